@@ -1,5 +1,9 @@
 use noria::DataType;
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{self, Serialize, Serializer};
+use std::fmt;
 use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
 
 /// A record is a single positive or negative data record with an associated time stamp.
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -67,7 +71,10 @@ impl From<(Vec<DataType>, bool)> for Record {
 
 impl Into<Vec<Record>> for Records {
     fn into(self) -> Vec<Record> {
-        self.0
+        match Arc::try_unwrap(self.0) {
+            Ok(shared) => shared.rows,
+            Err(shared) => shared.rows.clone(),
+        }
     }
 }
 
@@ -77,7 +84,7 @@ impl FromIterator<Record> for Records {
     where
         I: IntoIterator<Item = Record>,
     {
-        Records(iter.into_iter().collect())
+        Records::from(iter.into_iter().collect::<Vec<_>>())
     }
 }
 impl FromIterator<Vec<DataType>> for Records {
@@ -85,7 +92,7 @@ impl FromIterator<Vec<DataType>> for Records {
     where
         I: IntoIterator<Item = Vec<DataType>>,
     {
-        Records(iter.into_iter().map(Record::Positive).collect())
+        Records::from(iter.into_iter().map(Record::Positive).collect::<Vec<_>>())
     }
 }
 
@@ -93,53 +100,215 @@ impl IntoIterator for Records {
     type Item = Record;
     type IntoIter = ::std::vec::IntoIter<Record>;
     fn into_iter(self) -> Self::IntoIter {
-        self.0.into_iter()
+        Into::<Vec<Record>>::into(self).into_iter()
     }
 }
 impl<'a> IntoIterator for &'a Records {
     type Item = &'a Record;
     type IntoIter = ::std::slice::Iter<'a, Record>;
     fn into_iter(self) -> Self::IntoIter {
-        self.0.iter()
+        self.0.rows.iter()
     }
 }
 
-#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
-pub struct Records(Vec<Record>);
+/// A batch of records.
+///
+/// Copies of a batch share its records until one of them is changed, so that a batch that fans out
+/// to many children is not copied for each of them. They also share its encoding, so that it is
+/// only serialized once however many of the children are on other workers.
+#[derive(Clone, Default)]
+pub struct Records(Arc<Shared>);
+
+#[derive(Default)]
+struct Shared {
+    rows: Vec<Record>,
+    /// The records as they were last serialized, if they have not changed since.
+    encoded: Mutex<Option<Vec<u8>>>,
+}
+
+impl Clone for Shared {
+    fn clone(&self) -> Self {
+        Shared {
+            rows: self.rows.clone(),
+            encoded: Mutex::new(None),
+        }
+    }
+}
+
+impl PartialEq for Records {
+    fn eq(&self, other: &Records) -> bool {
+        self.0.rows == other.0.rows
+    }
+}
+
+impl fmt::Debug for Records {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Records").field(&self.0.rows).finish()
+    }
+}
+
+impl Serialize for Records {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut encoded = self.0.encoded.lock().unwrap();
+        if encoded.is_none() {
+            *encoded = Some(bincode::serialize(&self.0.rows).map_err(ser::Error::custom)?);
+        }
+        serializer.serialize_bytes(encoded.as_ref().unwrap())
+    }
+}
+
+impl<'de> Deserialize<'de> for Records {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Encoded;
+        impl<'de> Visitor<'de> for Encoded {
+            type Value = Vec<u8>;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("encoded records")
+            }
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                Ok(v.to_vec())
+            }
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(v)
+            }
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut v = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(b) = seq.next_element()? {
+                    v.push(b);
+                }
+                Ok(v)
+            }
+        }
+
+        let encoded = deserializer.deserialize_byte_buf(Encoded)?;
+        let rows: Vec<Record> = bincode::deserialize(&encoded).map_err(de::Error::custom)?;
+        Ok(Records::from(rows))
+    }
+}
+
+impl Records {
+    /// Cancel out each positive record against a negative record for the same row, and drop both.
+    ///
+    /// A burst of updates to the same row leaves behind pairs that would each have every node
+    /// downstream undo and redo the same work. The records that are kept stay in order.
+    pub fn compact(&mut self) {
+        use std::collections::HashMap;
+
+        if self.iter().all(Record::is_positive) || !self.iter().any(Record::is_positive) {
+            return;
+        }
+
+        let mut cancelled = vec![false; self.len()];
+        {
+            // the records of each row that are yet to be cancelled out, positive and negative
+            let mut open: HashMap<&[DataType], (Vec<usize>, Vec<usize>)> = HashMap::new();
+            for (i, r) in self.iter().enumerate() {
+                let (positive, negative) = open.entry(r.rec()).or_default();
+                let (mine, theirs) = if r.is_positive() {
+                    (positive, negative)
+                } else {
+                    (negative, positive)
+                };
+                match theirs.pop() {
+                    Some(j) => {
+                        cancelled[i] = true;
+                        cancelled[j] = true;
+                    }
+                    None => mine.push(i),
+                }
+            }
+        }
+
+        let mut i = 0;
+        self.retain(|_| {
+            i += 1;
+            !cancelled[i - 1]
+        });
+    }
+}
 
 impl Deref for Records {
     type Target = Vec<Record>;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.0.rows
     }
 }
 
 impl DerefMut for Records {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        // copies that share the records keep them as they were
+        let shared = Arc::make_mut(&mut self.0);
+        *shared.encoded.get_mut().unwrap() = None;
+        &mut shared.rows
     }
 }
 
-impl Into<Records> for Record {
-    fn into(self) -> Records {
-        Records(vec![self])
+impl From<Vec<Record>> for Records {
+    fn from(rows: Vec<Record>) -> Self {
+        Records(Arc::new(Shared {
+            rows,
+            encoded: Mutex::new(None),
+        }))
     }
 }
 
-impl Into<Records> for Vec<Record> {
+impl Into<Records> for Record {
     fn into(self) -> Records {
-        Records(self)
+        Records::from(vec![self])
     }
 }
 
 impl Into<Records> for Vec<Vec<DataType>> {
     fn into(self) -> Records {
-        Records(self.into_iter().map(Into::into).collect())
+        self.into_iter().collect()
     }
 }
 
 impl Into<Records> for Vec<(Vec<DataType>, bool)> {
     fn into(self) -> Records {
-        Records(self.into_iter().map(Into::into).collect())
+        self.into_iter().map(Record::from).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compacting_cancels_out_pairs() {
+        let row = |v: i32| vec![DataType::from(v)];
+        let mut rs: Records = vec![
+            (row(1), false),
+            (row(2), true),
+            (row(1), true),
+            (row(3), true),
+            (row(3), false),
+            (row(3), true),
+            (row(4), false),
+        ]
+        .into();
+        rs.compact();
+        let expected: Records = vec![(row(2), true), (row(3), true), (row(4), false)].into();
+        assert_eq!(rs, expected);
+
+        // records of the same sign are all kept
+        let mut rs: Records = vec![row(1), row(1)].into();
+        rs.compact();
+        assert_eq!(rs.len(), 2);
+    }
+
+    #[test]
+    fn copies_share_records_until_changed() {
+        let rs: Records = vec![vec![DataType::from(1)]].into();
+        let mut copy = rs.clone();
+        let encoded = bincode::serialize(&rs).unwrap();
+        assert_eq!(bincode::serialize(&copy).unwrap(), encoded);
+
+        copy.push(vec![DataType::from(2)].into());
+        assert_eq!(rs.len(), 1);
+        let decoded: Records = bincode::deserialize(&bincode::serialize(&copy).unwrap()).unwrap();
+        assert_eq!(decoded, copy);
+        let decoded: Records = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded, rs);
     }
 }
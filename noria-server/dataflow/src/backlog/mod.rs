@@ -1,10 +1,57 @@
 use common::SizeOf;
 use fnv::FnvBuildHasher;
+use nom_sql::OrderType;
+use ops::project::{self, ColumnMask};
 use prelude::*;
 use std::borrow::Cow;
+use std::cmp;
 
 use rand::{Rng, ThreadRng};
-use std::sync::Arc;
+use payload::BarrierKind;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time;
+
+/// When each base table processed the newest write from it that a reader reflects.
+type Freshness = HashMap<NodeIndex, time::SystemTime>;
+
+/// How many recently filled keys a reader remembers.
+const FILLED_KEYS: usize = 1024;
+
+/// How many exports a reader keeps before the oldest is dropped, in case it is never collected.
+const EXPORTS: usize = 8;
+
+/// How long a write group may hold back the writes readers see before a reader gives up on it, in
+/// case a copy of its end never arrives.
+const GROUP_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// How many of the latest snapshots a reader keeps the rows of, for reads at them.
+const SNAPSHOTS: usize = 16;
+
+/// How many keys a reader keeps the rows of across all of the snapshots it keeps, before it lets
+/// go of the oldest snapshot.
+const PINNED_KEYS: usize = 65_536;
+
+/// The rows that keys had at each of the latest snapshots a reader reached, oldest first, for the
+/// keys that have changed since. Keys that were holes at a snapshot are kept as `None`.
+type Pinned = VecDeque<(u64, HashMap<Vec<DataType>, Option<Vec<Vec<DataType>>>>)>;
+
+/// The rows readers held at each export barrier that has not been collected yet, oldest first.
+type Exports = VecDeque<(u64, Vec<Vec<DataType>>)>;
+
+/// The keys that rows were recently added for, so that clients that remember which keys had no
+/// rows can tell which of them may now have some.
+#[derive(Default)]
+struct Filled {
+    /// How many swaps have made rows added for some key visible.
+    generation: u64,
+    /// The keys rows were added for, along with the generation that made the rows visible.
+    keys: VecDeque<(u64, Vec<DataType>)>,
+    /// Keys filled in generations up to this one may have been forgotten.
+    complete: u64,
+}
 
 /// Allocate a new end-user facing result table.
 crate fn new(cols: usize, key: &[usize]) -> (SingleReadHandle, WriteHandle) {
@@ -60,6 +107,11 @@ fn new_inner(
         _ => make!(Many),
     };
 
+    let freshness = Arc::new(RwLock::new(Freshness::default()));
+    let snapshot = Arc::new(AtomicU64::new(0));
+    let filled = Arc::new(RwLock::new(Filled::default()));
+    let exports = Arc::new(Mutex::new(Exports::new()));
+    let pinned = Arc::new(RwLock::new(Pinned::new()));
     let w = WriteHandle {
         partial: trigger.is_some(),
         handle: w,
@@ -67,11 +119,31 @@ fn new_inner(
         cols,
         contiguous,
         mem_size: 0,
+        fresh: None,
+        freshness: freshness.clone(),
+        barriers: HashMap::new(),
+        groups: HashMap::new(),
+        reached: None,
+        snapshot: snapshot.clone(),
+        counts: None,
+        filling: Some(Vec::new()),
+        filled: filled.clone(),
+        exports: exports.clone(),
+        pinned: pinned.clone(),
     };
     let r = SingleReadHandle {
         handle: r,
         trigger,
         key: Vec::from(key),
+        view: Arc::from(""),
+        universe: None,
+        order: Arc::from(Vec::new()),
+        masks: Arc::from(Vec::new()),
+        freshness,
+        snapshot,
+        filled,
+        exports,
+        pinned,
     };
 
     (r, w)
@@ -108,6 +180,46 @@ crate struct WriteHandle {
     key: Vec<usize>,
     contiguous: bool,
     mem_size: usize,
+    /// The freshness readers will see after the next swap, if it has changed since the last one.
+    fresh: Option<Freshness>,
+    freshness: Arc<RwLock<Freshness>>,
+    /// How many copies of each barrier that is still on its way have arrived.
+    barriers: HashMap<(u64, BarrierKind), usize>,
+    /// The write groups that have started, but not yet ended, and when they started.
+    groups: HashMap<u64, time::Instant>,
+    /// The snapshot readers will reflect after the next swap, if it is newer than the last one.
+    reached: Option<u64>,
+    /// The newest snapshot that readers reflect.
+    snapshot: Arc<AtomicU64>,
+    /// How many copies of each row there are, if only one of each is kept.
+    counts: Option<HashMap<Vec<DataType>, usize>>,
+    /// The keys rows were added for since the last swap, or `None` if there were too many.
+    filling: Option<Vec<Vec<DataType>>>,
+    filled: Arc<RwLock<Filled>>,
+    exports: Arc<Mutex<Exports>>,
+    pinned: Arc<RwLock<Pinned>>,
+}
+
+/// Count the copy of a row that `r` adds or removes, and return whether it changes which rows
+/// there are.
+fn count(counts: &mut HashMap<Vec<DataType>, usize>, r: &Record) -> bool {
+    match *r {
+        Record::Positive(ref row) => {
+            let n = counts.entry(row.clone()).or_insert(0);
+            *n += 1;
+            *n == 1
+        }
+        Record::Negative(ref row) => match counts.get_mut(row) {
+            Some(n) if *n > 1 => {
+                *n -= 1;
+                false
+            }
+            _ => {
+                counts.remove(row);
+                true
+            }
+        },
+    }
 }
 
 type Key<'a> = Cow<'a, [DataType]>;
@@ -134,6 +246,15 @@ impl<'a> MutWriteHandleEntry<'a> {
     }
 
     crate fn mark_hole(self) {
+        if let Some(ref mut counts) = self.handle.counts {
+            self.handle
+                .handle
+                .meta_get_and(Cow::Borrowed(&*self.key), |rs| {
+                    for r in rs {
+                        counts.remove(r);
+                    }
+                });
+        }
         let size = self
             .handle
             .handle
@@ -226,7 +347,170 @@ impl WriteHandle {
     }
 
     crate fn swap(&mut self) {
+        // no write in a group may become visible until all of them can, unless the group has
+        // been open for so long that its end must have been lost
+        if !self.groups.is_empty() {
+            let now = time::Instant::now();
+            let abandoned: Vec<_> = self
+                .groups
+                .iter()
+                .filter(|&(_, &started)| now.duration_since(started) > GROUP_TIMEOUT)
+                .map(|(&id, _)| id)
+                .collect();
+            for id in abandoned {
+                self.groups.remove(&id);
+                self.barriers.remove(&(id, BarrierKind::GroupStart));
+                self.barriers.remove(&(id, BarrierKind::GroupEnd));
+                self.barriers.remove(&(id, BarrierKind::GroupAbort));
+            }
+            if !self.groups.is_empty() {
+                return;
+            }
+        }
+
         self.handle.refresh();
+
+        // only once the writes are visible may readers be told that they are
+        if let Some(fresh) = self.fresh.take() {
+            *self.freshness.write().unwrap() = fresh;
+        }
+        if let Some(id) = self.reached.take() {
+            // readers can see the rows as they were at the snapshot, so the rows of the keys that
+            // change from here on are kept for reads at it
+            let mut pinned = self.pinned.write().unwrap();
+            pinned.push_back((id, HashMap::new()));
+            while pinned.len() > SNAPSHOTS {
+                pinned.pop_front();
+            }
+            self.snapshot.store(id, Ordering::Release);
+        }
+
+        let filling = mem::replace(&mut self.filling, Some(Vec::new()));
+        if filling.as_ref().map(|keys| keys.is_empty()) == Some(true) {
+            return;
+        }
+        let mut filled = self.filled.write().unwrap();
+        filled.generation += 1;
+        let generation = filled.generation;
+        match filling {
+            Some(keys) => {
+                let keys = keys.into_iter().map(|k| (generation, k));
+                filled.keys.extend(keys)
+            }
+            None => {
+                filled.keys.clear();
+                filled.complete = generation;
+            }
+        }
+        while filled.keys.len() > FILLED_KEYS {
+            let (forgotten, _) = filled.keys.pop_front().unwrap();
+            filled.complete = cmp::max(filled.complete, forgotten);
+        }
+    }
+
+    /// Note that the records added since the last swap include a write that `base` processed at
+    /// `at`.
+    crate fn reflect(&mut self, base: NodeIndex, at: time::SystemTime) {
+        let freshness = &self.freshness;
+        let fresh = self
+            .fresh
+            .get_or_insert_with(|| freshness.read().unwrap().clone());
+        let newest = fresh.entry(base).or_insert(at);
+        if at > *newest {
+            *newest = at;
+        }
+    }
+
+    /// Note that a copy of barrier `id` has arrived, out of the `expected` copies that will.
+    ///
+    /// Once all copies of a snapshot barrier have arrived, every write that came before it is made
+    /// visible to readers, and readers are told that they reflect the snapshot. From when the
+    /// first copy of the start of a write group arrives until the last copy of its end has, no
+    /// writes are made visible, so that readers see all of the group or none of it. A group whose
+    /// end has not arrived within `GROUP_TIMEOUT` is given up on, so that a lost copy of the end
+    /// doesn't hold back every later write for good. Once all copies of an export barrier have
+    /// arrived, the rows readers can see are kept until a client collects them.
+    crate fn reach_barrier(&mut self, id: u64, kind: BarrierKind, expected: usize) {
+        let arrived = {
+            let arrived = self.barriers.entry((id, kind)).or_insert(0);
+            *arrived += 1;
+            *arrived
+        };
+        if kind == BarrierKind::GroupStart && arrived == 1 {
+            self.groups.insert(id, time::Instant::now());
+        }
+        if arrived < expected {
+            return;
+        }
+
+        // every copy of a barrier that was sent ahead of this one has arrived by now, if it ever
+        // will, so the arrivals of any that are still counted can be forgotten
+        self.barriers.retain(|&(other, _), _| other >= id);
+        self.barriers.remove(&(id, kind));
+        match kind {
+            BarrierKind::Snapshot => {
+                // a barrier can't make it through before the ones that were sent ahead of it
+                let newest = self
+                    .reached
+                    .unwrap_or_else(|| self.snapshot.load(Ordering::Acquire));
+                if id > newest {
+                    self.reached = Some(id);
+                }
+            }
+            BarrierKind::GroupStart => return,
+            BarrierKind::GroupEnd | BarrierKind::GroupAbort => {
+                self.groups.remove(&id);
+            }
+            BarrierKind::Export => {
+                // an open write group holds back the writes since it started on every shard
+                // alike, so the rows kept are still a consistent cut
+                self.swap();
+                self.export(id);
+                return;
+            }
+        }
+        self.swap();
+    }
+
+    /// Keep the rows readers can currently see as export `id`.
+    fn export(&mut self, id: u64) {
+        let mut rows = Vec::new();
+        self.handle.for_each(|rs| rows.extend(rs.iter().cloned()));
+        let mut exports = self.exports.lock().unwrap();
+        exports.push_back((id, rows));
+        while exports.len() > EXPORTS {
+            exports.pop_front();
+        }
+    }
+
+    /// When each base table processed the newest write from it that readers can currently see.
+    crate fn freshness(&self) -> Freshness {
+        self.freshness.read().unwrap().clone()
+    }
+
+    /// Keep the rows that readers can currently see for the keys that `rs` change, for every
+    /// snapshot that is kept and has not kept those keys already.
+    fn pin(&self, rs: &[Record]) {
+        let mut pinned = self.pinned.write().unwrap();
+        for r in rs {
+            let key = key_from_record(&self.key[..], self.contiguous, &r[..]);
+            for &mut (_, ref mut keys) in pinned.iter_mut() {
+                if keys.contains_key(&key[..]) {
+                    continue;
+                }
+                let rows = self
+                    .handle
+                    .meta_get_and(Cow::Borrowed(&key[..]), |rs| rs.to_vec())
+                    .and_then(|(rows, _)| rows)
+                    .or_else(|| if self.partial { None } else { Some(Vec::new()) });
+                keys.insert(key.to_vec(), rows);
+            }
+        }
+        // the oldest snapshots are let go of once too many keys are kept
+        let mut kept: usize = pinned.iter().map(|&(_, ref keys)| keys.len()).sum();
+        while kept > PINNED_KEYS {
+            kept -= pinned.pop_front().map(|(_, keys)| keys.len()).unwrap_or(0);
+        }
     }
 
     /// Add a new set of records to the backlog.
@@ -236,7 +520,34 @@ impl WriteHandle {
     where
         I: IntoIterator<Item = Record>,
     {
-        let mem_delta = self.handle.add(&self.key[..], self.cols, rs);
+        let rs: Vec<_> = rs.into_iter().collect();
+        if !self.pinned.read().unwrap().is_empty() {
+            self.pin(&rs);
+        }
+
+        let key = &self.key[..];
+        let contiguous = self.contiguous;
+        let filling = &mut self.filling;
+        let note = |r: &Record| {
+            if let Record::Positive(ref row) = *r {
+                let full = filling.as_ref().map(|keys| keys.len() >= FILLED_KEYS);
+                if full == Some(true) {
+                    *filling = None;
+                } else if let Some(ref mut keys) = *filling {
+                    keys.push(key_from_record(key, contiguous, &row[..]).into_owned());
+                }
+            }
+        };
+        let mem_delta = match self.counts {
+            Some(ref mut counts) => {
+                let rs = rs.into_iter().filter(|r| count(counts, r)).inspect(note);
+                self.handle.add(key, self.cols, rs)
+            }
+            None => {
+                let rs = rs.into_iter().inspect(note);
+                self.handle.add(key, self.cols, rs)
+            }
+        };
         if mem_delta > 0 {
             self.mem_size += mem_delta as usize;
         } else if mem_delta < 0 {
@@ -251,6 +562,11 @@ impl WriteHandle {
         self.partial
     }
 
+    /// Keep only one copy of each row that is added, until every copy has been removed again.
+    crate fn deduplicate(&mut self) {
+        self.counts = Some(HashMap::new());
+    }
+
     /// Evict `count` randomly selected keys from state and return them along with the number of
     /// bytes that will be freed once the underlying `evmap` applies the operation.
     crate fn evict_random_key(&mut self, rng: &mut ThreadRng) -> u64 {
@@ -265,6 +581,11 @@ impl WriteHandle {
                 Some(vs) => {
                     let size: u64 = vs.iter().map(|r| r.deep_size_of() as u64).sum();
                     bytes_to_be_freed += size;
+                    if let Some(ref mut counts) = self.counts {
+                        for r in vs.iter() {
+                            counts.remove(r);
+                        }
+                    }
                 }
             }
             self.mem_size = self
@@ -294,6 +615,15 @@ pub struct SingleReadHandle {
     handle: multir::Handle,
     trigger: Option<Arc<Fn(&[DataType]) -> bool + Send + Sync>>,
     key: Vec<usize>,
+    view: Arc<str>,
+    universe: Option<Arc<str>>,
+    order: Arc<[(usize, OrderType)]>,
+    masks: Arc<[(usize, ColumnMask)]>,
+    freshness: Arc<RwLock<Freshness>>,
+    snapshot: Arc<AtomicU64>,
+    filled: Arc<RwLock<Filled>>,
+    exports: Arc<Mutex<Exports>>,
+    pinned: Arc<RwLock<Pinned>>,
 }
 
 impl SingleReadHandle {
@@ -335,9 +665,140 @@ impl SingleReadHandle {
         self.handle.len()
     }
 
+    /// Record which view this handle reads from, and the security universe that view is in.
+    crate fn set_view(&mut self, view: &str, universe: Option<&str>) {
+        self.view = Arc::from(view);
+        self.universe = universe.map(Arc::from);
+    }
+
+    /// Have `sort` put rows in `order`.
+    crate fn set_order(&mut self, order: &[(usize, OrderType)]) {
+        self.order = Arc::from(order);
+    }
+
+    /// Have `mask` redact `masks` in the rows read from this handle.
+    crate fn set_masks(&mut self, masks: &[(usize, ColumnMask)]) {
+        self.masks = Arc::from(masks);
+    }
+
+    /// Whether rows read from this handle must be masked before they are returned.
+    pub fn is_masked(&self) -> bool {
+        !self.masks.is_empty()
+    }
+
+    /// Redact the columns of `rows` that the view they came from masks, if any.
+    pub fn mask(&self, rows: &mut [Vec<DataType>]) {
+        for r in rows {
+            project::apply_masks(&self.masks, r);
+        }
+    }
+
+    /// Put `rows` read from this handle in the order of the view they came from, if it has one.
+    pub fn sort<R: AsRef<[DataType]>>(&self, rows: &mut [R]) {
+        if self.order.is_empty() {
+            return;
+        }
+        rows.sort_by(|a, b| self.compare(a.as_ref(), b.as_ref()));
+    }
+
+    /// Compare two rows read from this handle by the order of the view they came from.
+    ///
+    /// Rows are equal if the view has no order.
+    pub fn compare(&self, a: &[DataType], b: &[DataType]) -> cmp::Ordering {
+        self.order
+            .iter()
+            .map(|&(c, ref ot)| match *ot {
+                OrderType::OrderAscending => a[c].cmp(&b[c]),
+                OrderType::OrderDescending => b[c].cmp(&a[c]),
+            })
+            .find(|&o| o != cmp::Ordering::Equal)
+            .unwrap_or(cmp::Ordering::Equal)
+    }
+
+    /// The name of the view this handle reads from.
+    pub fn view(&self) -> &str {
+        &self.view
+    }
+
+    /// The security universe of the view this handle reads from, if it is not global.
+    pub fn universe(&self) -> Option<&str> {
+        self.universe.as_ref().map(|u| &u[..])
+    }
+
     pub fn is_empty(&self) -> bool {
         self.handle.len() == 0
     }
+
+    /// Whether the writer has published at least once, so that lookups can be served.
+    pub fn is_ready(&self) -> bool {
+        let key = vec![DataType::None; self.key.len()];
+        self.handle.meta_get_and(&key, |_| ()).is_some()
+    }
+
+    /// When each base table processed the newest write from it that this handle reflects.
+    pub fn freshness(&self) -> HashMap<NodeIndex, time::SystemTime> {
+        self.freshness.read().unwrap().clone()
+    }
+
+    /// The number of swaps that have made rows for some key visible, along with the keys that rows
+    /// became visible for after generation `since`.
+    ///
+    /// The keys are `None` if too many have been filled since then to keep track of.
+    pub fn filled_since(&self, since: u64) -> (u64, Option<Vec<Vec<DataType>>>) {
+        let filled = self.filled.read().unwrap();
+        if since < filled.complete {
+            return (filled.generation, None);
+        }
+        let keys = filled
+            .keys
+            .iter()
+            .filter(|&&(generation, _)| generation > since)
+            .map(|(_, key)| key.clone())
+            .collect();
+        (filled.generation, Some(keys))
+    }
+
+    /// Whether this handle reflects every write that came before snapshot barrier `snapshot`.
+    pub fn reflects(&self, snapshot: u64) -> bool {
+        self.snapshot.load(Ordering::Acquire) >= snapshot
+    }
+
+    /// Find the rows that `key` had at `snapshot`, which the reader must reflect, and pass them to
+    /// `then`, like `try_find_and` does with the rows it has now.
+    ///
+    /// Returns `Err(())` if the reader no longer keeps the rows of that snapshot. Keys that were
+    /// holes at the snapshot, or have been evicted since, are found as they are now.
+    pub fn try_find_at_and<F, T>(
+        &self,
+        key: &[DataType],
+        snapshot: u64,
+        mut then: F,
+    ) -> Result<(Option<T>, i64), ()>
+    where
+        F: FnMut(&[Vec<DataType>]) -> T,
+    {
+        // the rows are read before the kept ones are looked at, so that a key that has changed
+        // since the snapshot is always among those kept
+        let now = self.try_find_and(key, &mut then)?;
+        let pinned = self.pinned.read().unwrap();
+        let keys = match pinned.iter().find(|&&(id, _)| id == snapshot) {
+            Some(&(_, ref keys)) => keys,
+            None => return Err(()),
+        };
+        match keys.get(key) {
+            Some(&Some(ref rows)) => Ok((Some(then(rows)), now.1)),
+            _ => Ok(now),
+        }
+    }
+
+    /// Take the rows that were kept when export barrier `id` arrived, or `None` if it hasn't yet.
+    pub fn take_export(&self, id: u64) -> Option<Vec<Vec<DataType>>> {
+        let mut exports = self.exports.lock().unwrap();
+        let at = exports.iter().position(|&(export, _)| export == id)?;
+        let (_, mut rows) = exports.remove(at)?;
+        self.mask(&mut rows);
+        Some(rows)
+    }
 }
 
 #[cfg(test)]
@@ -352,11 +813,13 @@ mod tests {
 
         // initially, store is uninitialized
         assert_eq!(r.try_find_and(&a[0..1], |rs| rs.len()), Err(()));
+        assert!(!r.is_ready());
 
         w.swap();
 
         // after first swap, it is empty, but ready
         assert_eq!(r.try_find_and(&a[0..1], |rs| rs.len()), Ok((Some(0), -1)));
+        assert!(r.is_ready());
 
         w.add(vec![Record::Positive(a.clone())]);
 
@@ -376,6 +839,30 @@ mod tests {
             .unwrap());
     }
 
+    #[test]
+    fn filled_keys_are_remembered() {
+        let (r, mut w) = new(2, &[0]);
+        w.swap();
+        assert_eq!(r.filled_since(0), (0, Some(vec![])));
+
+        w.add(vec![Record::Positive(vec![1.into(), "a".into()])]);
+        assert_eq!(r.filled_since(0), (0, Some(vec![])));
+        w.swap();
+        assert_eq!(r.filled_since(0), (1, Some(vec![vec![1.into()]])));
+        assert_eq!(r.filled_since(1), (1, Some(vec![])));
+
+        // removals fill nothing
+        w.add(vec![Record::Negative(vec![1.into(), "a".into()])]);
+        w.swap();
+        assert_eq!(r.filled_since(1), (1, Some(vec![])));
+
+        // too many keys at once to remember
+        w.add((0..FILLED_KEYS + 1).map(|i| Record::Positive(vec![i.into(), "b".into()])));
+        w.swap();
+        assert_eq!(r.filled_since(1), (2, None));
+        assert_eq!(r.filled_since(2), (2, Some(vec![])));
+    }
+
     #[test]
     fn busybusybusy() {
         use std::thread;
@@ -422,6 +909,28 @@ mod tests {
             .unwrap());
     }
 
+    #[test]
+    fn deduplicated_rows_stay_until_every_copy_is_gone() {
+        let a = vec![1.into(), "a".into()];
+
+        let (r, mut w) = new(2, &[0]);
+        w.deduplicate();
+        w.add(vec![
+            Record::Positive(a.clone()),
+            Record::Positive(a.clone()),
+        ]);
+        w.swap();
+        assert_eq!(r.try_find_and(&a[0..1], |rs| rs.len()).unwrap().0, Some(1));
+
+        w.add(vec![Record::Negative(a.clone())]);
+        w.swap();
+        assert_eq!(r.try_find_and(&a[0..1], |rs| rs.len()).unwrap().0, Some(1));
+
+        w.add(vec![Record::Negative(a.clone())]);
+        w.swap();
+        assert_eq!(r.try_find_and(&a[0..1], |rs| rs.len()).unwrap().0, Some(0));
+    }
+
     #[test]
     fn non_minimal_query() {
         let a = vec![1.into(), "a".into()];
@@ -539,4 +1048,107 @@ mod tests {
             .0
             .unwrap());
     }
+
+    #[test]
+    fn freshness_follows_swaps() {
+        let (r, mut w) = new(1, &[0]);
+        w.swap();
+        assert!(r.freshness().is_empty());
+
+        let base = NodeIndex::new(3);
+        let earlier = time::SystemTime::now();
+        let later = earlier + time::Duration::from_secs(1);
+        w.add(vec![Record::Positive(vec![1.into()])]);
+        w.reflect(base, later);
+        w.reflect(base, earlier);
+
+        // the write isn't visible yet, so neither is its freshness
+        assert!(r.freshness().is_empty());
+
+        w.swap();
+        assert_eq!(r.freshness()[&base], later);
+        assert_eq!(w.freshness()[&base], later);
+    }
+
+    #[test]
+    fn snapshot_waits_for_every_barrier() {
+        let (r, mut w) = new(1, &[0]);
+        w.swap();
+        assert!(!r.reflects(1));
+
+        let a = vec![1.into()];
+        w.add(vec![Record::Positive(a.clone())]);
+        w.reach_barrier(1, BarrierKind::Snapshot, 2);
+        assert!(!r.reflects(1));
+
+        // the write ahead of the barrier becomes visible with it
+        w.reach_barrier(1, BarrierKind::Snapshot, 2);
+        assert!(r.reflects(1));
+        assert!(!r.reflects(2));
+        assert_eq!(r.try_find_and(&a, |rs| rs.len()).unwrap().0, Some(1));
+    }
+
+    #[test]
+    fn reads_at_a_snapshot_miss_later_writes() {
+        let (r, mut w) = new(1, &[0]);
+        w.swap();
+
+        let a = vec![1.into()];
+        let b = vec![2.into()];
+        w.add(vec![Record::Positive(a.clone())]);
+        w.reach_barrier(1, BarrierKind::Snapshot, 1);
+        w.add(vec![
+            Record::Positive(a.clone()),
+            Record::Positive(b.clone()),
+        ]);
+        w.swap();
+
+        assert_eq!(r.try_find_and(&a, |rs| rs.len()).unwrap().0, Some(2));
+        assert_eq!(r.try_find_at_and(&a, 1, |rs| rs.len()).unwrap().0, Some(1));
+        assert_eq!(r.try_find_at_and(&b, 1, |rs| rs.len()).unwrap().0, Some(0));
+
+        // only the snapshots the reader has reached are kept
+        assert!(r.try_find_at_and(&a, 2, |rs| rs.len()).is_err());
+    }
+
+    #[test]
+    fn groups_become_visible_all_at_once() {
+        let (r, mut w) = new(1, &[0]);
+        w.swap();
+
+        let a = vec![1.into()];
+        let b = vec![2.into()];
+        w.reach_barrier(1, BarrierKind::GroupStart, 2);
+        w.add(vec![Record::Positive(a.clone())]);
+        w.swap();
+        w.reach_barrier(1, BarrierKind::GroupStart, 2);
+        w.reach_barrier(1, BarrierKind::GroupEnd, 2);
+        w.add(vec![Record::Positive(b.clone())]);
+        w.swap();
+        assert_eq!(r.try_find_and(&a, |rs| rs.len()).unwrap().0, Some(0));
+
+        w.reach_barrier(1, BarrierKind::GroupEnd, 2);
+        assert_eq!(r.try_find_and(&a, |rs| rs.len()).unwrap().0, Some(1));
+        assert_eq!(r.try_find_and(&b, |rs| rs.len()).unwrap().0, Some(1));
+    }
+
+    #[test]
+    fn exports_keep_rows_at_the_barrier() {
+        let (r, mut w) = new(1, &[0]);
+        w.swap();
+
+        let a = vec![1.into()];
+        let b = vec![2.into()];
+        w.add(vec![Record::Positive(a.clone())]);
+        w.reach_barrier(1, BarrierKind::Export, 2);
+        assert_eq!(r.take_export(1), None);
+
+        // writes after the last copy of the barrier are not part of the export
+        w.reach_barrier(1, BarrierKind::Export, 2);
+        w.add(vec![Record::Positive(b.clone())]);
+        w.swap();
+        assert_eq!(r.take_export(1), Some(vec![a]));
+        assert_eq!(r.take_export(1), None);
+        assert_eq!(r.try_find_and(&b, |rs| rs.len()).unwrap().0, Some(1));
+    }
 }
@@ -110,6 +110,18 @@ impl Handle {
         }
     }
 
+    /// Call `f` with the rows of every key that readers can currently see.
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&[Vec<DataType>]),
+    {
+        match *self {
+            Handle::Single(ref h) => h.for_each(|_, rs| f(rs)),
+            Handle::Double(ref h) => h.for_each(|_, rs| f(rs)),
+            Handle::Many(ref h) => h.for_each(|_, rs| f(rs)),
+        }
+    }
+
     pub fn add<I>(&mut self, key: &[usize], cols: usize, rs: I) -> isize
     where
         I: IntoIterator<Item = Record>,
@@ -0,0 +1,71 @@
+//! Where domains get the current time from.
+//!
+//! Domains never read the wall clock directly when deciding whether a timer has fired. Instead,
+//! they ask the `Clock` they were built with, so that tests and simulations can move time forward
+//! on their own schedule, and timer-driven behavior plays out the same way on every run.
+
+use std::sync::{Arc, Mutex};
+use std::time;
+
+/// A source of the current time.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> time::Instant;
+}
+
+/// The wall clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> time::Instant {
+        time::Instant::now()
+    }
+}
+
+/// A clock that only moves when it is told to.
+///
+/// Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct VirtualClock(Arc<Mutex<time::Instant>>);
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        VirtualClock::new()
+    }
+}
+
+impl VirtualClock {
+    /// A clock that starts out at the current wall clock time.
+    pub fn new() -> Self {
+        VirtualClock(Arc::new(Mutex::new(time::Instant::now())))
+    }
+
+    /// Move the clock forward by `by`.
+    pub fn advance(&self, by: time::Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> time::Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_clock_only_moves_when_advanced() {
+        let clock = VirtualClock::new();
+        let shared = clock.clone();
+        let start = clock.now();
+        ::std::thread::sleep(time::Duration::from_millis(10));
+        assert_eq!(clock.now(), start);
+
+        shared.advance(time::Duration::from_secs(60));
+        assert_eq!(clock.now(), start + time::Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,127 @@
+//! A record of the last packets a domain handled, for working out after the fact how its state
+//! came to be wrong.
+
+use prelude::*;
+use serde_json;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time;
+
+/// How many of the records a packet carries to keep.
+const SAMPLE: usize = 4;
+
+/// A summary of a packet that a domain handled.
+#[derive(Debug, Serialize)]
+struct Captured {
+    /// When the domain started handling the packet.
+    at: time::SystemTime,
+    /// What kind of packet it was.
+    packet: String,
+    /// The node the packet was headed for, if it carried records.
+    node: Option<NodeIndex>,
+    /// How many records the packet carried.
+    records: usize,
+    /// The first few records the packet carried.
+    sample: Vec<String>,
+}
+
+/// The last packets a domain handled, oldest first.
+pub(super) struct Capture {
+    packets: VecDeque<Captured>,
+    capacity: usize,
+    path: PathBuf,
+}
+
+impl Capture {
+    /// Keep the last `capacity` packets, for dumping to the file at `path`.
+    pub(super) fn new(capacity: usize, path: PathBuf) -> Self {
+        Capture {
+            packets: VecDeque::with_capacity(capacity),
+            capacity,
+            path,
+        }
+    }
+
+    /// Note that the domain is about to handle `m`.
+    pub(super) fn record(&mut self, m: &Packet, nodes: &DomainNodes) {
+        let (node, records, sample) = match *m {
+            Packet::Input { ref inner, .. } => {
+                let input = unsafe { inner.deref() };
+                let sample = input
+                    .data
+                    .iter()
+                    .take(SAMPLE)
+                    .map(|op| format!("{:?}", op))
+                    .collect();
+                (Some(input.dst), input.data.len(), sample)
+            }
+            Packet::Message { ref data, .. } | Packet::ReplayPiece { ref data, .. } => {
+                let sample = data
+                    .iter()
+                    .take(SAMPLE)
+                    .map(|r| format!("{:?}", r))
+                    .collect();
+                (Some(m.dst()), data.len(), sample)
+            }
+            _ => (None, 0, Vec::new()),
+        };
+
+        if self.packets.len() == self.capacity {
+            self.packets.pop_front();
+        }
+        self.packets.push_back(Captured {
+            at: time::SystemTime::now(),
+            packet: format!("{:?}", m),
+            node: node
+                .and_then(|n| nodes.get(n))
+                .map(|n| n.borrow().global_addr()),
+            records,
+            sample,
+        });
+    }
+
+    /// Write the captured packets to disk as JSON lines, and return the file they were written to.
+    pub(super) fn dump(&self) -> io::Result<&Path> {
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        for p in &self.packets {
+            serde_json::to_writer(&mut out, p)?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        Ok(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_keeps_the_last_packets() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let path = dir.path().join("packets.json");
+        let mut capture = Capture::new(2, path.clone());
+        let nodes = DomainNodes::default();
+        for _ in 0..3 {
+            capture.record(&Packet::Spin, &nodes);
+        }
+        let n = unsafe { LocalNodeIndex::make(0) };
+        capture.record(
+            &Packet::Message {
+                link: Link::new(n, n),
+                data: vec![vec![1.into()], vec![2.into()]].into(),
+                tracer: None,
+                origin: None,
+            },
+            &nodes,
+        );
+
+        assert_eq!(capture.dump().unwrap(), &*path);
+        let dumped = ::std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = dumped.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].contains("\"records\":2"));
+    }
+}
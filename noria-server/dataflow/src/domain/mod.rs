@@ -5,23 +5,33 @@ use std::cmp;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::mem;
 use std::net::SocketAddr;
+use std::ops::Range;
+use std::panic;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time;
 
+use clock::Clock;
+use common::SizeOf;
 use futures;
 use group_commit::GroupCommitQueueSet;
-use noria::channel::{self, TcpSender};
+use noria::channel;
 pub use noria::internal::DomainIndex as Index;
-use payload::{ControlReplyPacket, ReplayPieceContext};
+use noria::TableOperation;
+use payload::{BarrierKind, ControlReplyPacket, ReplayPieceContext};
 use prelude::*;
 use slog::Logger;
+use state::BackgroundSync;
 use stream_cancel::Valve;
 
 use timekeeper::{RealTime, SimpleTracker, ThreadTime, Timer, TimerSet};
 use tokio::{self, prelude::*};
 use Readers;
 
+mod capture;
+use self::capture::Capture;
+
 #[derive(Debug)]
 pub enum PollEvent {
     ResumePolling,
@@ -40,6 +50,44 @@ pub enum ProcessResult {
 pub struct Config {
     pub concurrent_replays: usize,
     pub replay_batch_timeout: time::Duration,
+    /// How many threads to chunk a full replay on. With 0, the domain chunks it inline.
+    pub full_replay_threads: usize,
+    /// How many of the last packets each domain handled to keep for dumping to disk, if any.
+    pub capture_packets: usize,
+    /// How many records an operator may emit for each record it is given before it is suspended,
+    /// if there is a limit.
+    pub max_fanout: Option<usize>,
+    /// How many packets may wait to be sent to downstream domains before `overflow` applies, if
+    /// there is a limit.
+    #[serde(default)]
+    pub max_queue: Option<usize>,
+    /// What to do once more packets wait to be sent to downstream domains than `max_queue` allows.
+    #[serde(default)]
+    pub overflow: Overflow,
+    /// How many bytes of memory the full state of each input to a join may take up before the
+    /// parts of it that were used least recently are moved to disk, if there is a limit.
+    #[serde(default)]
+    pub max_join_state: Option<u64>,
+}
+
+/// What a domain does once more packets wait to be sent to downstream domains than it allows.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Overflow {
+    /// Stop reading input, so that upstream domains and clients have to wait.
+    ///
+    /// This also holds back replay requests, so a limit that is too small can stall a partial
+    /// replay that has to pass back through this domain.
+    Block,
+    /// Refuse writes from clients, but keep processing packets from other domains.
+    Shed,
+    /// Keep the packets that do not fit on disk until downstream domains catch up.
+    Spill,
+}
+
+impl Default for Overflow {
+    fn default() -> Self {
+        Overflow::Block
+    }
 }
 
 const BATCH_SIZE: usize = 256;
@@ -108,6 +156,20 @@ struct Waiting {
     redos: HashMap<Hole, HashSet<Redo>>,
 }
 
+/// How much partial state a domain holds, as the worker that runs it last heard.
+///
+/// The worker evicts from the domain with the most `evictable` bytes once it is over its memory
+/// limit, and the domain then evicts from the node that has the most bytes relative to its
+/// eviction weight. Views that are given a higher weight thus keep more of their state.
+#[derive(Debug, Default)]
+pub struct StateSize {
+    /// The bytes of partial state that the domain holds.
+    pub bytes: AtomicUsize,
+    /// The bytes of partial state of the node that should be evicted from first, divided by the
+    /// eviction weight of that node.
+    pub evictable: AtomicUsize,
+}
+
 /// Struct sent to a worker to start a domain.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DomainBuilder {
@@ -129,14 +191,18 @@ unsafe impl Send for DomainBuilder {}
 
 impl DomainBuilder {
     /// Starts up the domain represented by this `DomainBuilder`.
+    ///
+    /// Replies to the controller are sent on `control_reply_tx`.
     pub fn build(
         self,
         log: Logger,
         readers: Readers,
         channel_coordinator: Arc<ChannelCoordinator>,
-        control_addr: SocketAddr,
+        control_reply_tx: Box<dyn channel::Sender<Item = ControlReplyPacket> + Send>,
         shutdown_valve: &Valve,
-        state_size: Arc<AtomicUsize>,
+        state_size: Arc<StateSize>,
+        clock: Arc<Clock>,
+        syncer: Option<BackgroundSync>,
     ) -> Domain {
         // initially, all nodes are not ready
         let not_ready = self
@@ -146,8 +212,25 @@ impl DomainBuilder {
             .collect();
 
         let log = log.new(o!("domain" => self.index.index(), "shard" => self.shard.unwrap_or(0)));
-        let control_reply_tx = TcpSender::connect(&control_addr).unwrap();
-        let group_commit_queues = GroupCommitQueueSet::new(&self.persistence_parameters);
+        let group_commit_queues =
+            GroupCommitQueueSet::new(&self.persistence_parameters, clock.clone());
+        let capture = if self.config.capture_packets > 0 {
+            let params = &self.persistence_parameters;
+            let path = params
+                .log_dir
+                .as_ref()
+                .map(PathBuf::as_path)
+                .unwrap_or_else(|| Path::new("."))
+                .join(format!(
+                    "{}-domain{}.{}-packets.json",
+                    params.log_prefix,
+                    self.index.index(),
+                    self.shard.unwrap_or(0)
+                ));
+            Some(Capture::new(self.config.capture_packets, path))
+        } else {
+            None
+        };
 
         Domain {
             index: self.index,
@@ -175,15 +258,33 @@ impl DomainBuilder {
             buffered_replay_requests: Default::default(),
             replay_batch_timeout: self.config.replay_batch_timeout,
             timed_purges: Default::default(),
+            probes: Default::default(),
+            capture,
+            clock,
+            syncer,
 
             concurrent_replays: 0,
             max_concurrent_replays: self.config.concurrent_replays,
+            full_replay_threads: self.config.full_replay_threads,
             replay_request_queue: Default::default(),
+            max_fanout: self.config.max_fanout,
+            max_queue: self.config.max_queue,
+            overflow: self.config.overflow,
+            max_join_state: self.config.max_join_state,
+            queue_depth: 0,
+            shed: 0,
+            suspended: Default::default(),
+            sent_bytes: Default::default(),
+            groups: Default::default(),
+            ungrouped: Default::default(),
             delayed_for_self: Default::default(),
 
             group_commit_queues,
 
             state_size,
+            eviction_weights: Default::default(),
+            publish_every: Default::default(),
+            unpublished: Default::default(),
             total_time: Timer::new(),
             total_ptime: Timer::new(),
             wait_time: Timer::new(),
@@ -193,6 +294,44 @@ impl DomainBuilder {
     }
 }
 
+/// The bytes of the records in `m` if `n` sends them on to other domains, or 0 if it does not.
+fn sent_bytes(n: &Node, m: &Packet) -> u64 {
+    if !n.is_egress() && !n.is_sharder() {
+        return 0;
+    }
+    match *m {
+        Packet::Message { ref data, .. } | Packet::ReplayPiece { ref data, .. } => {
+            data.iter().map(|r| r.deep_size_of()).sum()
+        }
+        _ => 0,
+    }
+}
+
+/// Drop the operations in `data` that `keep` says not to keep, and shift the ranges of operations
+/// that each of `senders` sent to match.
+fn retain_writes(
+    data: &mut Vec<TableOperation>,
+    senders: &mut [(SourceChannelIdentifier, Range<usize>)],
+    keep: &[bool],
+) {
+    // how many of the operations before each one are kept
+    let mut kept = Vec::with_capacity(keep.len() + 1);
+    kept.push(0);
+    for &k in keep {
+        let before = kept[kept.len() - 1];
+        kept.push(if k { before + 1 } else { before });
+    }
+    for &mut (_, ref mut ops) in senders.iter_mut() {
+        *ops = kept[ops.start]..kept[ops.end];
+    }
+
+    let mut i = 0;
+    data.retain(|_| {
+        i += 1;
+        keep[i - 1]
+    });
+}
+
 #[derive(Clone, Debug)]
 struct TimedPurge {
     time: time::Instant,
@@ -221,25 +360,61 @@ pub struct Domain {
     replay_paths: HashMap<Tag, ReplayPath>,
     reader_triggered: Map<HashSet<Vec<DataType>>>,
     timed_purges: VecDeque<TimedPurge>,
+    /// The last latency probe to reach each reader, how many copies of it have arrived, and how
+    /// long the slowest of them took to get there.
+    probes: Map<(u64, usize, time::Duration)>,
+    capture: Option<Capture>,
 
     replay_paths_by_dst: Map<HashMap<Vec<usize>, Vec<Tag>>>,
 
     concurrent_replays: usize,
     max_concurrent_replays: usize,
+    full_replay_threads: usize,
     replay_request_queue: VecDeque<(Tag, Vec<DataType>)>,
 
+    max_fanout: Option<usize>,
+    max_queue: Option<usize>,
+    overflow: Overflow,
+    max_join_state: Option<u64>,
+    /// How many packets wait to be sent to downstream domains, and how many client writes were
+    /// refused because too many did, as last reported by whoever runs the domain.
+    queue_depth: u64,
+    shed: u64,
+    /// Operators that emitted too much for what they were given, and no longer process updates.
+    suspended: HashSet<LocalNodeIndex>,
+    /// The bytes of the records that each node has sent on to other domains.
+    sent_bytes: HashMap<LocalNodeIndex, u64>,
+    /// The write groups that have started at each base table, but not yet ended, along with the
+    /// writes in them that are held until they do.
+    groups: HashMap<(LocalNodeIndex, u64), Input>,
+    /// Writes that clients sent as part of write groups that have yet to start at the base table
+    /// they are for.
+    #[allow(clippy::vec_box)]
+    ungrouped: HashMap<(LocalNodeIndex, u64), Vec<Box<Packet>>>,
+
     shutdown_valve: Valve,
     readers: Readers,
-    control_reply_tx: TcpSender<ControlReplyPacket>,
+    control_reply_tx: Box<dyn channel::Sender<Item = ControlReplyPacket> + Send>,
     channel_coordinator: Arc<ChannelCoordinator>,
 
     buffered_replay_requests: HashMap<Tag, (time::Instant, HashSet<Vec<DataType>>)>,
     replay_batch_timeout: time::Duration,
+    /// Where timers get the current time from.
+    clock: Arc<Clock>,
+    /// What syncs writes to base tables on disk in the background, if they are not synced as they
+    /// are made.
+    syncer: Option<BackgroundSync>,
     delayed_for_self: VecDeque<Box<Packet>>,
 
     group_commit_queues: GroupCommitQueueSet,
 
-    state_size: Arc<AtomicUsize>,
+    state_size: Arc<StateSize>,
+    /// The eviction weight of each node that does not have the default weight of 1.
+    eviction_weights: HashMap<LocalNodeIndex, u32>,
+    /// How long each reader that does not make writes visible right away holds on to them.
+    publish_every: HashMap<LocalNodeIndex, time::Duration>,
+    /// When the writes that each reader holds on to are to be made visible.
+    unpublished: HashMap<LocalNodeIndex, time::Instant>,
     total_time: Timer<SimpleTracker, RealTime>,
     total_ptime: Timer<SimpleTracker, ThreadTime>,
     wait_time: Timer<SimpleTracker, RealTime>,
@@ -459,20 +634,8 @@ impl Domain {
                 "#done" => requests_satisfied,
                 "ongoing" => self.concurrent_replays,
                 );
-                debug_assert!(self.concurrent_replays < self.max_concurrent_replays);
-                while self.concurrent_replays < self.max_concurrent_replays {
-                    if let Some((tag, key)) = self.replay_request_queue.pop_front() {
-                        trace!(self.log, "releasing replay request";
-                        "tag" => ?tag,
-                        "key" => ?key,
-                        "left" => self.replay_request_queue.len(),
-                        "ongoing" => self.concurrent_replays,
-                        );
-                        self.send_partial_replay_request(tag, key);
-                    } else {
-                        return;
-                    }
-                }
+                // NOTE: we may still be at the limit if it was lowered while replays were ongoing
+                self.release_replay_requests();
             }
             TriggerEndpoint::Local(..) => {
                 // didn't count against our quote, so we're also not decementing
@@ -483,6 +646,134 @@ impl Domain {
         }
     }
 
+    /// Send queued up replay requests for as long as we are below the concurrent replay limit.
+    fn release_replay_requests(&mut self) {
+        while self.concurrent_replays < self.max_concurrent_replays {
+            if let Some((tag, key)) = self.replay_request_queue.pop_front() {
+                trace!(self.log, "releasing replay request";
+                "tag" => ?tag,
+                "key" => ?key,
+                "left" => self.replay_request_queue.len(),
+                "ongoing" => self.concurrent_replays,
+                );
+                self.send_partial_replay_request(tag, key);
+            } else {
+                return;
+            }
+        }
+    }
+
+    /// Hold the writes in `m` to the references between the base tables in this domain.
+    ///
+    /// Returns the operations in `m` that must be refused, since they would leave a reference from
+    /// the base table they are for dangling, or delete rows that other base tables refer to and
+    /// may not lose. Operations that set a reference to a missing row to NULL instead are changed
+    /// in place. Also returns the writes to the tables that refer to the rows `m` deletes that
+    /// those deletes call for, which must be applied along with `m`. Deletes from base tables that
+    /// keep deleted rows only mark the rows, and refer to nothing.
+    #[allow(clippy::type_complexity, clippy::vec_box)]
+    fn enforce_references(
+        &mut self,
+        m: Box<Packet>,
+    ) -> (Box<Packet>, Vec<(usize, ConstraintViolation)>, Vec<Box<Packet>>) {
+        let me = m.dst();
+        let (inner, src, senders) = match *m {
+            Packet::Input {
+                inner,
+                src,
+                senders,
+            } => (inner, src, senders),
+            _ => unreachable!(),
+        };
+        let mut input = unsafe { inner.take() };
+
+        let mut refused: Vec<_> = {
+            let n = self.nodes[me].borrow();
+            let b = n.get_base().unwrap();
+            input
+                .data
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(i, op)| {
+                    b.soften_delete(op);
+                    b.check_references(op, &self.state).map(|v| (i, v))
+                })
+                .collect()
+        };
+
+        let deletes = input.data.iter().any(|op| match *op {
+            TableOperation::Delete { .. } => true,
+            _ => false,
+        });
+        let mut follow = Vec::new();
+        if deletes {
+            let referring: Vec<_> = self
+                .nodes
+                .iter()
+                .flat_map(|(ni, n)| {
+                    n.borrow()
+                        .get_base()
+                        .map(|b| b.references().to_vec())
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter(|r| *r.table == me)
+                        .map(move |r| (ni, r))
+                })
+                .collect();
+
+            let mut writes: HashMap<LocalNodeIndex, Vec<TableOperation>> = HashMap::new();
+            'ops: for (i, op) in input.data.iter().enumerate() {
+                let key = match *op {
+                    TableOperation::Delete { ref key } => &key[0],
+                    _ => continue,
+                };
+                if refused.iter().any(|&(j, _)| j == i) {
+                    continue;
+                }
+                let mut these = Vec::with_capacity(referring.len());
+                for &(ni, ref r) in &referring {
+                    let n = self.nodes[ni].borrow();
+                    let b = n.get_base().unwrap();
+                    match b.on_referenced_delete(ni, r, key, &self.state) {
+                        Ok(ops) => these.push((ni, ops)),
+                        Err(v) => {
+                            refused.push((i, v));
+                            continue 'ops;
+                        }
+                    }
+                }
+                for (ni, ops) in these {
+                    writes.entry(ni).or_default().extend(ops);
+                }
+            }
+
+            for (ni, data) in writes {
+                if data.is_empty() {
+                    continue;
+                }
+                follow.push(box Packet::Input {
+                    inner: LocalOrNot::new(Input {
+                        dst: ni,
+                        data,
+                        tracer: None,
+                        durable: input.durable,
+                        group: None,
+                    }),
+                    src: None,
+                    senders: vec![],
+                });
+            }
+        }
+
+        refused.sort_by_key(|&(i, _)| i);
+        let m = box Packet::Input {
+            inner: LocalOrNot::new(input),
+            src,
+            senders,
+        };
+        (m, refused, follow)
+    }
+
     fn dispatch(&mut self, m: Box<Packet>, sends: &mut EnqueuedSends, executor: &mut Executor) {
         let src = m.src();
         let me = m.dst();
@@ -503,21 +794,55 @@ impl Domain {
         if !self.not_ready.is_empty() && self.not_ready.contains(&me) {
             return;
         }
+        if !self.suspended.is_empty() && self.suspended.contains(&me) {
+            return;
+        }
+
+        let (m, refused) = if let Packet::Input { .. } = *m {
+            // the writes that deletes call for in the tables that refer to the deleted rows are
+            // applied first, so that no reference dangles even for a moment
+            let (m, refused, follow) = self.enforce_references(m);
+            for f in follow {
+                self.dispatch(f, sends, executor);
+            }
+            (m, refused)
+        } else {
+            (m, Vec::new())
+        };
+        let given = match *m {
+            Packet::Message { ref data, .. } => Some(data.len()),
+            _ => None,
+        };
 
         let (mut m, evictions) = {
             let mut n = self.nodes[me].borrow_mut();
+            let sent = sent_bytes(&n, &m);
+            if sent != 0 {
+                *self.sent_bytes.entry(me).or_insert(0) += sent;
+            }
             self.process_times.start(me);
             self.process_ptimes.start(me);
             let mut m = Some(m);
+            // readers that hold on to writes for a while make them visible later, all at once
+            let publish = match self.publish_every.get(&me) {
+                Some(&every) => {
+                    let now = self.clock.now();
+                    self.unpublished.entry(me).or_insert(now + every);
+                    false
+                }
+                None => true,
+            };
             let (misses, _, captured) = n.process(
                 &mut m,
+                refused,
                 None,
                 &mut self.state,
                 &self.nodes,
                 self.shard,
-                true,
+                publish,
                 sends,
                 executor,
+                &self.log,
             );
             assert_eq!(captured.len(), 0);
             self.process_ptimes.stop();
@@ -528,6 +853,22 @@ impl Domain {
                 return;
             }
 
+            // an operator whose output explodes, such as a join that is missing a join condition,
+            // would soon bring down the whole deployment. stop it instead, and let the operator of
+            // the deployment find out through the statistics.
+            if let (Some(max), Some(given)) = (self.max_fanout, given) {
+                let emitted = m.as_ref().unwrap().data().len();
+                if n.is_internal() && emitted > max.saturating_mul(given.max(1)) {
+                    error!(self.log, "suspending operator whose output exploded";
+                           "local" => me.id(),
+                           "node" => n.global_addr().index(),
+                           "given" => given,
+                           "emitted" => emitted);
+                    self.suspended.insert(me);
+                    return;
+                }
+            }
+
             // normally, we ignore misses during regular forwarding.
             // however, we have to be a little careful in the case of joins.
             let evictions = if n.is_internal() && n.is_join() && !misses.is_empty() {
@@ -684,11 +1025,16 @@ impl Domain {
     ) {
         self.wait_time.stop();
         m.trace(PacketEvent::Handle);
+        if let Some(ref mut capture) = self.capture {
+            capture.record(&m, &self.nodes);
+        }
 
         match *m {
             Packet::Message { .. } | Packet::Input { .. } => {
                 // WO for https://github.com/rust-lang/rfcs/issues/1403
-                self.dispatch(m, sends, executor);
+                if let Some(m) = self.await_group(m, executor) {
+                    self.dispatch(m, sends, executor);
+                }
             }
             Packet::ReplayPiece { .. } => {
                 self.handle_replay(m, sends, executor);
@@ -696,6 +1042,9 @@ impl Domain {
             Packet::Evict { .. } | Packet::EvictKeys { .. } => {
                 self.handle_eviction(m, sends);
             }
+            Packet::Probe { .. } | Packet::Barrier { .. } => {
+                self.handle_probe(m, sends);
+            }
             consumed => {
                 match consumed {
                     // workaround #16223
@@ -715,8 +1064,37 @@ impl Domain {
                     }
                     Packet::RemoveNodes { nodes } => {
                         for &node in &nodes {
-                            self.nodes[node].borrow_mut().remove();
-                            self.state.remove(node);
+                            let mut n = self.nodes[node].borrow_mut();
+                            if n.is_reader() {
+                                // clients read the reader's state through this map, so it is only
+                                // freed once it is gone from there too
+                                let gid = n.global_addr();
+                                let shard = *self.shard.as_ref().unwrap_or(&0);
+                                self.readers.lock().unwrap().remove(&(gid, shard));
+                            }
+                            // a dropped base takes the rows it has persisted with it
+                            let persisted = if n.is_base()
+                                && self.persistence_parameters.mode == DurabilityMode::Permanent
+                            {
+                                Some(self.base_state_name(n.name()))
+                            } else {
+                                None
+                            };
+                            n.remove();
+                            drop(n);
+                            if self.state.remove(node).is_some() {
+                                if let Some(name) = persisted {
+                                    if let Err(e) = PersistentState::destroy(&name) {
+                                        error!(self.log, "failed to delete base state";
+                                               "name" => name, "err" => ?e);
+                                    }
+                                }
+                            }
+                            self.eviction_weights.remove(&node);
+                            self.publish_every.remove(&node);
+                            self.unpublished.remove(&node);
+                            self.suspended.remove(&node);
+                            self.sent_bytes.remove(&node);
                             trace!(self.log, "node removed"; "local" => node.id());
                         }
 
@@ -759,6 +1137,87 @@ impl Domain {
                             .send(ControlReplyPacket::ack())
                             .unwrap();
                     }
+                    Packet::RenameColumn { node, column, name } => {
+                        self.nodes[node].borrow_mut().rename_column(column, name);
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::AlterColumnType {
+                        node,
+                        column,
+                        ty,
+                        cast,
+                    } => {
+                        self.nodes[node]
+                            .borrow_mut()
+                            .get_base_mut()
+                            .expect("told to change the column type of non-base node")
+                            .alter_column_type(column, ty.clone(), &cast);
+
+                        // convert the rows the base has, and pass on the rows that changed like
+                        // any other write
+                        let mut changed = Records::default();
+                        if let Some(s) = self.state.get_mut(node) {
+                            for row in s.cloned_records() {
+                                if column >= row.len() {
+                                    // the column was added later, so the default applies
+                                    continue;
+                                }
+                                let v = cast.apply(&row[column], &ty);
+                                if v != row[column] {
+                                    let mut new = row.clone();
+                                    new[column] = v;
+                                    changed.push(Record::Negative(row));
+                                    changed.push(Record::Positive(new));
+                                }
+                            }
+                            s.process_records(&mut changed, None);
+                            s.sync();
+                        }
+                        if !changed.is_empty() {
+                            for &child in self.nodes[node].borrow().children() {
+                                self.delayed_for_self.push_back(box Packet::Message {
+                                    link: Link::new(node, child),
+                                    data: changed.clone(),
+                                    tracer: None,
+                                    origin: None,
+                                });
+                            }
+                        }
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::RenameBase { node, name } => {
+                        let old = self.nodes[node].borrow().name().to_owned();
+                        self.nodes[node].borrow_mut().rename(name.clone());
+                        if self.persistence_parameters.mode == DurabilityMode::Permanent {
+                            // the state has to be closed before its files can move
+                            if let Some(s) = self.state.remove(node) {
+                                let keys = s.keys();
+                                drop(s);
+                                let (from, to) =
+                                    (self.base_state_name(&old), self.base_state_name(&name));
+                                PersistentState::rename(&from, &to).unwrap();
+
+                                let n = self.nodes[node].borrow();
+                                let key = n.get_base().and_then(|b| b.key());
+                                let mut s = self.persistent_state(to, key);
+                                for k in keys {
+                                    s.add_key(&k[..], None);
+                                }
+                                self.state.insert(node, box s);
+                            }
+                        }
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::RemoveEgressTx { node, target } => {
+                        let mut n = self.nodes[node].borrow_mut();
+                        n.with_egress_mut(|e| e.remove_tx(target));
+                    }
                     Packet::UpdateEgress {
                         node,
                         new_tx,
@@ -787,7 +1246,13 @@ impl Domain {
                     }
                     Packet::StateSizeProbe { node } => {
                         let row_count = self.state.get(node).map(|r| r.rows()).unwrap_or(0);
-                        let mem_size = self.state.get(node).map(|s| s.deep_size_of()).unwrap_or(0);
+                        let mut mem_size =
+                            self.state.get(node).map(|s| s.deep_size_of()).unwrap_or(0);
+                        // readers keep their state to themselves, and only know its size
+                        self.nodes[node]
+                            .borrow()
+                            .with_reader(|r| mem_size = r.state_size().unwrap_or(0))
+                            .unwrap_or(());
                         self.control_reply_tx
                             .send(ControlReplyPacket::StateSize(row_count, mem_size))
                             .unwrap();
@@ -809,7 +1274,8 @@ impl Domain {
                             }
                             InitialState::IndexedLocal(index) => {
                                 if !self.state.contains_key(node) {
-                                    self.state.insert(node, box MemoryState::default());
+                                    let s = self.full_state(node);
+                                    self.state.insert(node, s);
                                 }
                                 let state = self.state.get_mut(node).unwrap();
                                 for idx in index {
@@ -859,7 +1325,7 @@ impl Domain {
                                         tx
                                     })
                                     .collect::<Vec<_>>();
-                                let (r_part, w_part) =
+                                let (mut r_part, w_part) =
                                     backlog::new_partial(cols, &k[..], move |miss| {
                                         let n = txs.len();
                                         let tx = if n == 1 {
@@ -873,7 +1339,15 @@ impl Domain {
                                     });
 
                                 let mut n = self.nodes[node].borrow_mut();
+                                let view = n.name().to_owned();
                                 n.with_reader_mut(|r| {
+                                    r_part.set_view(&view, r.universe());
+                                    if let Some(order) = r.order() {
+                                        r_part.set_order(order);
+                                    }
+                                    if let Some(masks) = r.masks() {
+                                        r_part.set_masks(masks);
+                                    }
                                     assert!(self
                                         .readers
                                         .lock()
@@ -888,10 +1362,18 @@ impl Domain {
                             }
                             InitialState::Global { gid, cols, key } => {
                                 use backlog;
-                                let (r_part, w_part) = backlog::new(cols, &key[..]);
+                                let (mut r_part, w_part) = backlog::new(cols, &key[..]);
 
                                 let mut n = self.nodes[node].borrow_mut();
+                                let view = n.name().to_owned();
                                 n.with_reader_mut(|r| {
+                                    r_part.set_view(&view, r.universe());
+                                    if let Some(order) = r.order() {
+                                        r_part.set_order(order);
+                                    }
+                                    if let Some(masks) = r.masks() {
+                                        r_part.set_masks(masks);
+                                    }
                                     assert!(self
                                         .readers
                                         .lock()
@@ -1093,24 +1575,48 @@ impl Domain {
                                 r
                             };
 
-                            let replay_tx_desc = self
-                                .channel_coordinator
-                                .builder_for(&(self.index, self.shard.unwrap_or(0)))
-                                .unwrap();
+                            // we split the snapshot into a number of partitions, and spawn off
+                            // a separate chunker for each one so that large states are fed into
+                            // the replay path concurrently. there is no point in having more
+                            // partitions than there are batches.
+                            let nbatches = (state.len() + BATCH_SIZE - 1) / BATCH_SIZE;
+                            let nthreads = self.full_replay_threads;
+                            let nparts = cmp::max(1, cmp::min(nthreads, nbatches));
+                            let per_part = (state.len() + nparts - 1) / nparts;
+                            let mut state = state;
+                            let mut partitions = Vec::with_capacity(nparts);
+                            while state.len() > per_part {
+                                let at = state.len() - per_part;
+                                partitions.push(state.split_off(at));
+                            }
+                            partitions.push(state);
+
+                            // the chunkers all send to our own (local) input channel, so the
+                            // batches of any one chunker are received in the order they were
+                            // sent. the chunker that finishes last is responsible for sending the
+                            // final (empty) piece that tells the target that the replay is done.
+                            // since every other chunker has sent all its batches by the time it
+                            // decrements the counter, that piece is guaranteed to arrive last.
+                            let pending = Arc::new(AtomicUsize::new(partitions.len()));
+                            let domain = self
+                                .nodes
+                                .values()
+                                .next()
+                                .unwrap()
+                                .borrow()
+                                .domain()
+                                .index();
+
+                            for (pi, partition) in partitions.into_iter().enumerate() {
+                                let log = log.new(o!("partition" => pi));
+                                let fix = fix.clone();
+                                let pending = pending.clone();
+                                let replay_tx_desc = self
+                                    .channel_coordinator
+                                    .builder_for(&(self.index, self.shard.unwrap_or(0)))
+                                    .unwrap();
 
-                            thread::Builder::new()
-                                .name(format!(
-                                    "replay{}.{}",
-                                    self.nodes
-                                        .values()
-                                        .next()
-                                        .unwrap()
-                                        .borrow()
-                                        .domain()
-                                        .index(),
-                                    link.src
-                                ))
-                                .spawn(move || {
+                                let chunker = move || {
                                     use itertools::Itertools;
 
                                     // TODO: make async
@@ -1118,29 +1624,31 @@ impl Domain {
                                         replay_tx_desc.build_sync().unwrap();
 
                                     let start = time::Instant::now();
-                                    debug!(log, "starting state chunker"; "node" => %link.dst);
+                                    debug!(log,
+                                       "starting state chunker";
+                                       "node" => %link.dst,
+                                       "rows" => partition.len()
+                                    );
 
-                                    let iter = state.into_iter().chunks(BATCH_SIZE);
-                                    let mut iter = iter.into_iter().enumerate().peekable();
+                                    let iter = partition.into_iter().chunks(BATCH_SIZE);
 
                                     // process all records in state to completion within domain
                                     // and then forward on tx (if there is one)
-                                    while let Some((i, chunk)) = iter.next() {
+                                    for (i, chunk) in iter.into_iter().enumerate() {
                                         use std::iter::FromIterator;
                                         let chunk = Records::from_iter(chunk.map(&fix));
                                         let len = chunk.len();
-                                        let last = iter.peek().is_none();
                                         let p = box Packet::ReplayPiece {
                                             tag,
                                             link, // to is overwritten by receiver
-                                            context: ReplayPieceContext::Regular { last },
+                                            context: ReplayPieceContext::Regular { last: false },
                                             data: chunk,
                                         };
 
                                         trace!(log, "sending batch"; "#" => i, "[]" => len);
                                         if chunked_replay_tx.send(p).is_err() {
                                             warn!(log, "replayer noticed domain shutdown");
-                                            break;
+                                            return;
                                         }
                                     }
 
@@ -1149,8 +1657,32 @@ impl Domain {
                                        "node" => %link.dst,
                                        "μs" => start.elapsed().as_micros()
                                     );
-                                })
-                                .unwrap();
+
+                                    if pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+                                        let p = box Packet::ReplayPiece {
+                                            tag,
+                                            link,
+                                            context: ReplayPieceContext::Regular { last: true },
+                                            data: Vec::<Record>::new().into(),
+                                        };
+
+                                        if chunked_replay_tx.send(p).is_err() {
+                                            warn!(log, "replayer noticed domain shutdown");
+                                        }
+                                    }
+                                };
+
+                                // without replay threads, the chunker feeds the whole partition
+                                // into our input channel before we process anything else.
+                                if nthreads == 0 {
+                                    chunker();
+                                } else {
+                                    thread::Builder::new()
+                                        .name(format!("replay{}.{}.{}", domain, link.src, pi))
+                                        .spawn(chunker)
+                                        .unwrap();
+                                }
+                            }
                         }
 
                         self.handle_replay(p, sends, executor);
@@ -1170,16 +1702,10 @@ impl Domain {
                                 match (n.get_base(), &params.mode) {
                                     (Some(base), &DurabilityMode::DeleteOnExit)
                                     | (Some(base), &DurabilityMode::Permanent) => {
-                                        let base_name = format!(
-                                            "{}-{}-{}",
-                                            params.log_prefix,
-                                            n.name(),
-                                            self.shard.unwrap_or(0),
-                                        );
-
-                                        box PersistentState::new(base_name, base.key(), &params)
+                                        let base_name = self.base_state_name(n.name());
+                                        box self.persistent_state(base_name, base.key())
                                     }
-                                    _ => box MemoryState::default(),
+                                    _ => self.full_state(node),
                                 }
                             };
                             for idx in index {
@@ -1195,7 +1721,9 @@ impl Domain {
                             trace!(self.log, "readying empty node"; "local" => node.id());
                         }
 
-                        // swap replayed reader nodes to expose new state
+                        // swap replayed reader nodes to expose new state. this is what makes a
+                        // new view servable, so it must happen before the migration completes,
+                        // regardless of how often the reader otherwise publishes.
                         {
                             let mut n = self.nodes[node].borrow_mut();
                             if n.is_reader() {
@@ -1207,6 +1735,7 @@ impl Domain {
                                     }
                                 })
                                 .unwrap();
+                                self.unpublished.remove(&node);
                             }
                         }
 
@@ -1219,6 +1748,8 @@ impl Domain {
                             total_time: self.total_time.num_nanoseconds(),
                             total_ptime: self.total_ptime.num_nanoseconds(),
                             wait_time: self.wait_time.num_nanoseconds(),
+                            queue_depth: self.queue_depth,
+                            shed: self.shed,
                         };
 
                         let node_stats = self
@@ -1269,15 +1800,37 @@ impl Domain {
                                     .unwrap()
                                 };
 
-                                if time.is_some() && ptime.is_some() {
+                                let probe =
+                                    self.probes.get(local_index).map(|&(id, copies, latency)| {
+                                        (id, copies, latency.as_nanos() as u64)
+                                    });
+                                let freshness = n
+                                    .with_reader(|r| r.freshness().unwrap_or_default())
+                                    .unwrap_or_default();
+                                let suspended = self.suspended.contains(&local_index);
+                                let sent_bytes =
+                                    self.sent_bytes.get(&local_index).cloned().unwrap_or(0);
+
+                                if (time.is_some() && ptime.is_some())
+                                    || probe.is_some()
+                                    || !freshness.is_empty()
+                                    || suspended
+                                {
                                     Some((
                                         node_index,
                                         noria::debug::stats::NodeStats {
                                             desc: format!("{:?}", n),
-                                            process_time: time.unwrap(),
-                                            process_ptime: ptime.unwrap(),
+                                            process_time: time.unwrap_or(0),
+                                            process_ptime: ptime.unwrap_or(0),
                                             mem_size,
                                             materialized: mat_state,
+                                            probe,
+                                            freshness,
+                                            suspended,
+                                            sent_bytes,
+                                            // the controller knows which queries a node is for
+                                            owners: Vec::new(),
+                                            tags: Vec::new(),
                                         },
                                     ))
                                 } else {
@@ -1293,6 +1846,69 @@ impl Domain {
                     Packet::UpdateStateSize => {
                         self.update_state_sizes();
                     }
+                    Packet::DumpCapture => {
+                        self.dump_capture();
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::UpdateConfig {
+                        concurrent_replays,
+                        replay_batch_timeout,
+                    } => {
+                        info!(self.log, "updating configuration";
+                              "concurrent_replays" => ?concurrent_replays,
+                              "replay_batch_timeout" => ?replay_batch_timeout);
+                        if let Some(timeout) = replay_batch_timeout {
+                            self.replay_batch_timeout = timeout;
+                        }
+                        if let Some(n) = concurrent_replays {
+                            self.max_concurrent_replays = n;
+                            // a higher limit lets requests that were held back go right away
+                            self.release_replay_requests();
+                        }
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::UpdateEvictionWeight { node, weight } => {
+                        info!(self.log, "updating eviction weight";
+                              "node" => node.id(),
+                              "weight" => weight);
+                        if weight == 1 {
+                            self.eviction_weights.remove(&node);
+                        } else {
+                            self.eviction_weights.insert(node, weight);
+                        }
+                        self.update_state_sizes();
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::UpdatePublishInterval { node, every } => {
+                        info!(self.log, "updating reader publish interval";
+                              "node" => node.id(),
+                              "every" => ?every);
+                        match every {
+                            Some(every) => {
+                                self.publish_every.insert(node, every);
+                                // writes that are already held on to need not wait any longer
+                                let due = self.clock.now() + every;
+                                if let Some(at) = self.unpublished.get_mut(&node) {
+                                    *at = cmp::min(*at, due);
+                                }
+                            }
+                            None => {
+                                self.publish_every.remove(&node);
+                                if self.unpublished.remove(&node).is_some() {
+                                    self.publish(node);
+                                }
+                            }
+                        }
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
                     Packet::Quit => unreachable!("Quit messages are handled by event loop"),
                     Packet::Spin => {
                         // spinning as instructed
@@ -1303,7 +1919,7 @@ impl Domain {
         }
 
         if !self.buffered_replay_requests.is_empty() {
-            let now = time::Instant::now();
+            let now = self.clock.now();
             let to = self.replay_batch_timeout;
             let elapsed_replays: Vec<_> = {
                 self.buffered_replay_requests
@@ -1327,7 +1943,7 @@ impl Domain {
 
         let mut swap = HashSet::new();
         while let Some(tp) = self.timed_purges.front() {
-            let now = time::Instant::now();
+            let now = self.clock.now();
             if tp.time <= now {
                 let tp = self.timed_purges.pop_front().unwrap();
                 let mut node = self.nodes[tp.view].borrow_mut();
@@ -1346,14 +1962,21 @@ impl Domain {
             }
         }
         for n in swap {
-            self.nodes[n]
-                .borrow_mut()
-                .with_reader_mut(|r| {
-                    if let Some(wh) = r.writer_mut() {
-                        wh.swap();
-                    }
-                })
-                .unwrap();
+            self.publish(n);
+        }
+
+        if !self.unpublished.is_empty() {
+            let now = self.clock.now();
+            let due: Vec<_> = self
+                .unpublished
+                .iter()
+                .filter(|&(_, &at)| at <= now)
+                .map(|(&n, _)| n)
+                .collect();
+            for n in due {
+                self.unpublished.remove(&n);
+                self.publish(n);
+            }
         }
 
         if top {
@@ -1370,6 +1993,50 @@ impl Domain {
         self.wait_time.start();
     }
 
+    /// The name that the persistent state of the base node `name` goes by.
+    fn base_state_name(&self, name: &str) -> String {
+        format!(
+            "{}-{}-{}",
+            self.persistence_parameters.log_prefix,
+            name,
+            self.shard.unwrap_or(0),
+        )
+    }
+
+    /// Open the persistent state called `name` of a base node keyed by `key`.
+    fn persistent_state(&self, name: String, key: Option<&[usize]>) -> PersistentState {
+        let mut s = PersistentState::new(name, key, &self.persistence_parameters);
+        if let Some(ref syncer) = self.syncer {
+            s.sync_in_background(syncer);
+        }
+        s
+    }
+
+    /// Make a new in-memory state for `node`, which moves to disk in part if a join looks things
+    /// up in it and the state of joins is limited.
+    fn full_state(&self, node: LocalNodeIndex) -> Box<State> {
+        let joined = self.nodes[node].borrow().children().iter().any(|&c| {
+            let c = self.nodes[c].borrow();
+            c.is_internal() && c.is_join()
+        });
+        match self.max_join_state {
+            Some(budget) if joined => box SpillingState::new(budget),
+            _ => box MemoryState::default(),
+        }
+    }
+
+    /// Make the writes that the reader `node` has applied visible to its readers.
+    fn publish(&mut self, node: LocalNodeIndex) {
+        self.nodes[node]
+            .borrow_mut()
+            .with_reader_mut(|r| {
+                if let Some(wh) = r.writer_mut() {
+                    wh.swap();
+                }
+            })
+            .unwrap();
+    }
+
     fn seed_row<'a>(&self, source: LocalNodeIndex, row: Cow<'a, [DataType]>) -> Record {
         if let Some(&(start, ref defaults)) = self.ingress_inject.get(source) {
             let mut v = Vec::with_capacity(start + defaults.len());
@@ -1509,7 +2176,7 @@ impl Domain {
                 Entry::Vacant(v) => {
                     let mut ks = HashSet::new();
                     ks.insert(key);
-                    v.insert((time::Instant::now(), ks));
+                    v.insert((self.clock.now(), ks));
                 }
             }
 
@@ -1761,9 +2428,15 @@ impl Domain {
                             }
                         }
 
+                        let sent = sent_bytes(&n, m.as_ref().unwrap());
+                        if sent != 0 {
+                            *self.sent_bytes.entry(segment.node).or_insert(0) += sent;
+                        }
+
                         // process the current message in this node
                         let (mut misses, lookups, captured) = n.process(
                             &mut m,
+                            Vec::new(),
                             segment.partial_key.as_ref(),
                             &mut self.state,
                             &self.nodes,
@@ -1771,6 +2444,7 @@ impl Domain {
                             false,
                             sends,
                             ex,
+                            &self.log,
                         );
 
                         // ignore duplicate misses
@@ -2154,8 +2828,7 @@ impl Domain {
                                 if self.nodes[dst].borrow().beyond_mat_frontier() {
                                     // make sure we eventually evict these from here
                                     self.timed_purges.push_back(TimedPurge {
-                                        time: time::Instant::now()
-                                            + time::Duration::from_millis(50),
+                                        time: self.clock.now() + time::Duration::from_millis(50),
                                         keys: for_keys,
                                         view: dst,
                                         tag,
@@ -2373,6 +3046,171 @@ impl Domain {
         }
     }
 
+    /// Hold on to `m` if it is a write in a write group, and return it otherwise.
+    ///
+    /// Writes sent ahead of the start of their group wait for it to reach the base table. Once it
+    /// has, each write is checked against the rows of the base table and the writes of the group
+    /// held before it, and its sender is told right away whether the base table would refuse any
+    /// of it. The writes that it wouldn't are held until the group ends, and dropped if the group
+    /// is aborted. Writes outside the group that are applied in the meantime can still make the
+    /// base table refuse some of the held writes once they are applied.
+    fn await_group(&mut self, m: Box<Packet>, executor: &mut Executor) -> Option<Box<Packet>> {
+        let group = match *m {
+            Packet::Input { ref inner, .. } => unsafe { inner.deref() }.group,
+            _ => None,
+        };
+        let me = m.dst();
+        let id = match group {
+            Some(id) => id,
+            None => return Some(m),
+        };
+        if !self.groups.contains_key(&(me, id)) {
+            self.ungrouped.entry((me, id)).or_default().push(m);
+            return None;
+        }
+
+        let (inner, src, mut senders) = match *m {
+            Packet::Input {
+                inner,
+                src,
+                senders,
+            } => (inner, src, senders),
+            _ => unreachable!(),
+        };
+        let mut input = unsafe { inner.take() };
+        if let Some(src) = src {
+            senders.push((src, 0..input.data.len()));
+        }
+
+        let held = self.groups.get_mut(&(me, id)).unwrap();
+        let refused = {
+            let n = self.nodes[me].borrow();
+            let b = n.get_base().unwrap();
+            b.refusals(me, &held.data, &input.data, &self.state)
+        };
+        let mut keep = vec![true; input.data.len()];
+        for (src, ops) in senders.iter().cloned() {
+            let violations: Vec<_> = refused
+                .iter()
+                .filter(|&&(i, _)| ops.contains(&i))
+                .map(|&(i, ref v)| (i - ops.start, v.clone()))
+                .collect();
+            if violations.is_empty() {
+                executor.ack(src);
+            } else {
+                // none of what a client sent may be applied if any of it is refused
+                for k in &mut keep[ops] {
+                    *k = false;
+                }
+                executor.reject(src, violations);
+            }
+        }
+        retain_writes(&mut input.data, &mut senders, &keep);
+        held.data.extend(input.data);
+        held.durable |= input.durable;
+        None
+    }
+
+    /// Forward a latency probe or a snapshot barrier to every node below the one it was sent to,
+    /// and note its arrival at any readers among them.
+    fn handle_probe(&mut self, m: Box<Packet>, sends: &mut EnqueuedSends) {
+        let me = m.dst();
+        let mut n = self.nodes[me].borrow_mut();
+        if n.is_dropped() {
+            return;
+        }
+
+        if n.is_base() {
+            let group = match *m {
+                Packet::Barrier { id, kind, .. } => Some((id, kind)),
+                _ => None,
+            };
+            match group {
+                Some((id, BarrierKind::GroupStart)) => {
+                    // the writes of a group that clients sent ahead of its start follow it
+                    let held = Input {
+                        dst: me,
+                        data: Vec::new(),
+                        tracer: None,
+                        durable: false,
+                        group: None,
+                    };
+                    self.groups.insert((me, id), held);
+                    if let Some(ms) = self.ungrouped.remove(&(me, id)) {
+                        self.delayed_for_self.extend(ms);
+                    }
+                }
+                Some((id, BarrierKind::GroupEnd)) => {
+                    // the writes the group held are applied just ahead of its end
+                    if let Some(held) = self.groups.remove(&(me, id)) {
+                        if !held.data.is_empty() {
+                            self.delayed_for_self.push_back(box Packet::Input {
+                                inner: LocalOrNot::new(held),
+                                src: None,
+                                senders: vec![],
+                            });
+                            self.delayed_for_self.push_back(m);
+                            return;
+                        }
+                    }
+                }
+                Some((id, BarrierKind::GroupAbort)) => {
+                    self.groups.remove(&(me, id));
+                    self.ungrouped.remove(&(me, id));
+                }
+                _ => {}
+            }
+        }
+
+        if n.is_reader() {
+            let (id, sent) = match *m {
+                Packet::Probe { id, sent, .. } => (id, sent),
+                Packet::Barrier {
+                    id,
+                    kind,
+                    ref expected,
+                    ..
+                } => {
+                    if let Some(&expected) = expected.get(&n.global_addr()) {
+                        n.with_reader_mut(|r| r.reach_barrier(id, kind, expected))
+                            .unwrap();
+                    }
+                    return;
+                }
+                _ => unreachable!(),
+            };
+            let latency = time::SystemTime::now()
+                .duration_since(sent)
+                .unwrap_or_else(|_| time::Duration::from_millis(0));
+
+            // a probe may reach a reader along several paths, and is not through until it has
+            // arrived along all of them. the controller knows how many there are, so we just count.
+            let probe = self.probes.entry(me).or_insert((id, 0, latency));
+            if probe.0 != id {
+                *probe = (id, 0, latency);
+            }
+            probe.1 += 1;
+            probe.2 = probe.2.max(latency);
+        } else if n.is_sender() {
+            n.process_probe(m, self.shard, sends);
+        } else {
+            let children = n.children().to_vec();
+            drop(n);
+            let mut m = Some(m);
+            for (i, &child) in children.iter().enumerate() {
+                // avoid cloning if we can
+                let mut m = if i == children.len() - 1 {
+                    m.take().unwrap()
+                } else {
+                    m.as_ref().map(|m| box m.clone_data()).unwrap()
+                };
+                m.link_mut().src = me;
+                m.link_mut().dst = child;
+                self.handle_probe(m, sends);
+            }
+        }
+    }
+
     pub fn handle_eviction(&mut self, m: Box<Packet>, sends: &mut EnqueuedSends) {
         #[allow(clippy::too_many_arguments)]
         fn trigger_downstream_evictions(
@@ -2462,34 +3300,10 @@ impl Domain {
         match (*m,) {
             (Packet::Evict { node, num_bytes },) => {
                 let node = node.map(|n| (n, num_bytes)).or_else(|| {
-                    self.nodes
-                        .values()
-                        .filter_map(|nd| {
-                            let n = &*nd.borrow();
-                            let local_index = n.local_addr();
-
-                            if n.is_reader() {
-                                let mut size = 0;
-                                n.with_reader(|r| {
-                                    if r.is_partial() {
-                                        size = r.state_size().unwrap_or(0)
-                                    }
-                                })
-                                .unwrap();
-                                Some((local_index, size))
-                            } else {
-                                self.state
-                                    .get(local_index)
-                                    .filter(|state| state.is_partial())
-                                    .map(|state| (local_index, state.deep_size_of()))
-                            }
-                        })
-                        .filter(|&(_, s)| s > 0)
-                        .max_by_key(|&(_, s)| s)
-                        .map(|(n, s)| {
-                            trace!(self.log, "chose to evict from node {:?} with size {}", n, s);
-                            (n, cmp::min(num_bytes, s as usize))
-                        })
+                    self.evictable_node().map(|(n, s)| {
+                        trace!(self.log, "chose to evict from node {:?} with size {}", n, s);
+                        (n, cmp::min(num_bytes, s as usize))
+                    })
                 });
 
                 if let Some((node, num_bytes)) = node {
@@ -2603,6 +3417,18 @@ impl Domain {
         (self.index, self.shard.unwrap_or(0))
     }
 
+    /// How many packets may wait to be sent to downstream domains, and what to do with more.
+    pub fn queue_limit(&self) -> Option<(usize, Overflow)> {
+        self.max_queue.map(|max| (max, self.overflow))
+    }
+
+    /// Note how many packets wait to be sent to downstream domains, and how many client writes
+    /// have been refused because too many did, so that they show in statistics.
+    pub fn note_queue(&mut self, depth: u64, shed: u64) {
+        self.queue_depth = depth;
+        self.shed = shed;
+    }
+
     pub fn booted(&mut self, addr: SocketAddr) {
         info!(self.log, "booted domain"; "nodes" => self.nodes.len());
         self.control_reply_tx
@@ -2611,15 +3437,15 @@ impl Domain {
         self.wait_time.start();
     }
 
-    pub fn update_state_sizes(&mut self) {
-        let total: u64 = self
-            .nodes
+    /// The bytes of partial state that each node in this domain holds.
+    fn partial_state_sizes(&self) -> Vec<(LocalNodeIndex, u64)> {
+        self.nodes
             .values()
             .map(|nd| {
                 let n = &*nd.borrow();
                 let local_index = n.local_addr();
 
-                if n.is_reader() {
+                let size = if n.is_reader() {
                     // We are a reader, which has its own kind of state
                     let mut size = 0;
                     n.with_reader(|r| {
@@ -2636,12 +3462,55 @@ impl Domain {
                         .filter(|state| state.is_partial())
                         .map(|s| s.deep_size_of())
                         .unwrap_or(0)
-                }
+                };
+                (local_index, size)
             })
-            .sum();
+            .collect()
+    }
 
-        self.state_size.store(total as usize, Ordering::Relaxed);
-        // no response sent, as worker will read the atomic
+    /// The node to evict from when told to evict without being told from where, along with the
+    /// bytes of partial state it holds.
+    ///
+    /// This is the node with the most bytes relative to its eviction weight.
+    fn evictable_node(&self) -> Option<(LocalNodeIndex, u64)> {
+        self.partial_state_sizes()
+            .into_iter()
+            .filter(|&(_, s)| s > 0)
+            .max_by_key(|&(n, s)| s / self.eviction_weight(n))
+    }
+
+    fn eviction_weight(&self, node: LocalNodeIndex) -> u64 {
+        self.eviction_weights.get(&node).cloned().unwrap_or(1) as u64
+    }
+
+    pub fn update_state_sizes(&mut self) {
+        let total: u64 = self.partial_state_sizes().iter().map(|&(_, s)| s).sum();
+        let evictable = self
+            .evictable_node()
+            .map(|(n, s)| s / self.eviction_weight(n))
+            .unwrap_or(0);
+
+        self.state_size
+            .bytes
+            .store(total as usize, Ordering::Relaxed);
+        self.state_size
+            .evictable
+            .store(evictable as usize, Ordering::Relaxed);
+        // no response sent, as worker will read the atomics
+    }
+
+    /// Write the packets this domain last handled to disk, if it keeps them.
+    fn dump_capture(&self) {
+        if let Some(ref capture) = self.capture {
+            match capture.dump() {
+                Ok(path) => {
+                    warn!(self.log, "dumped captured packets"; "path" => %path.display());
+                }
+                Err(e) => {
+                    error!(self.log, "failed to dump captured packets"; "err" => %e);
+                }
+            }
+        }
     }
 
     pub fn on_event(
@@ -2649,6 +3518,29 @@ impl Domain {
         executor: &mut Executor,
         event: PollEvent,
         sends: &mut EnqueuedSends,
+    ) -> ProcessResult {
+        if self.capture.is_none() {
+            return self.process_event(executor, event, sends);
+        }
+
+        // if we're about to go down, leave behind the packets that led up to it
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            self.process_event(executor, event, sends)
+        }));
+        match res {
+            Ok(res) => res,
+            Err(e) => {
+                self.dump_capture();
+                panic::resume_unwind(e)
+            }
+        }
+    }
+
+    fn process_event(
+        &mut self,
+        executor: &mut Executor,
+        event: PollEvent,
+        sends: &mut EnqueuedSends,
     ) -> ProcessResult {
         self.wait_time.stop();
         //self.total_time.start();
@@ -2656,7 +3548,7 @@ impl Domain {
         let res = match event {
             PollEvent::ResumePolling => {
                 // when do we need to be woken up again?
-                let now = time::Instant::now();
+                let now = self.clock.now();
                 let opt1 = self
                     .buffered_replay_requests
                     .iter()
@@ -2676,13 +3568,24 @@ impl Domain {
                     }
                 });
 
-                let mut timeout = opt1.or(opt2).or(opt3);
+                let opt4 = self.unpublished.values().min().map(|&at| {
+                    if at > now {
+                        at - now
+                    } else {
+                        time::Duration::from_millis(0)
+                    }
+                });
+
+                let mut timeout = opt1.or(opt2).or(opt3).or(opt4);
                 if let Some(opt2) = opt2 {
                     timeout = Some(std::cmp::min(timeout.unwrap(), opt2));
                 }
                 if let Some(opt3) = opt3 {
                     timeout = Some(std::cmp::min(timeout.unwrap(), opt3));
                 }
+                if let Some(opt4) = opt4 {
+                    timeout = Some(std::cmp::min(timeout.unwrap(), opt4));
+                }
                 ProcessResult::KeepPolling(timeout)
             }
             PollEvent::Process(packet) => {
@@ -2698,6 +3601,13 @@ impl Domain {
                         self.handle(packet, sends, executor, true);
                     }
                 } else {
+                    if let Packet::Barrier { .. } | Packet::Input { .. } = *packet {
+                        // a barrier, or a write in a write group, must not overtake the writes
+                        // queued up ahead of it
+                        if let Some(m) = self.group_commit_queues.flush(packet.dst()) {
+                            self.handle(m, sends, executor, true);
+                        }
+                    }
                     self.handle(packet, sends, executor, true);
                 }
 
@@ -2712,7 +3622,10 @@ impl Domain {
                     self.handle(m, sends, executor, true);
                 }
 
-                if !self.buffered_replay_requests.is_empty() || !self.timed_purges.is_empty() {
+                if !self.buffered_replay_requests.is_empty()
+                    || !self.timed_purges.is_empty()
+                    || !self.unpublished.is_empty()
+                {
                     self.handle(box Packet::Spin, sends, executor, true);
                 }
 
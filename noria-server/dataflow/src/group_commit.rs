@@ -1,5 +1,7 @@
+use clock::Clock;
 use noria::internal::LocalOrNot;
 use prelude::*;
+use std::sync::Arc;
 use std::time;
 
 pub struct GroupCommitQueueSet {
@@ -7,22 +9,27 @@ pub struct GroupCommitQueueSet {
     #[allow(clippy::vec_box)]
     pending_packets: Map<(time::Instant, Vec<Box<Packet>>)>,
     params: PersistenceParameters,
+    clock: Arc<Clock>,
 }
 
 impl GroupCommitQueueSet {
     /// Create a new `GroupCommitQueue`.
-    pub fn new(params: &PersistenceParameters) -> Self {
+    pub fn new(params: &PersistenceParameters, clock: Arc<Clock>) -> Self {
         Self {
             pending_packets: Map::default(),
             params: params.clone(),
+            clock,
         }
     }
 
     /// Returns whether the given packet should be persisted.
+    ///
+    /// Writes that are part of a write group are never merged with others, since they may have to
+    /// wait for the group to start.
     pub fn should_append(&self, p: &Packet, nodes: &DomainNodes) -> bool {
-        if let Packet::Input { .. } = *p {
+        if let Packet::Input { ref inner, .. } = *p {
             assert!(nodes[p.dst()].borrow().is_base());
-            true
+            unsafe { inner.deref() }.group.is_none()
         } else {
             false
         }
@@ -30,7 +37,7 @@ impl GroupCommitQueueSet {
 
     /// Find the first queue that has timed out waiting for more packets, and flush it to disk.
     pub fn flush_if_necessary(&mut self) -> Option<Box<Packet>> {
-        let now = time::Instant::now();
+        let now = self.clock.now();
         let to = self.params.flush_timeout;
         let node = self
             .pending_packets
@@ -45,6 +52,14 @@ impl GroupCommitQueueSet {
         }
     }
 
+    /// Merge any packets that are pending for `node`, regardless of how long they have waited.
+    pub fn flush(&mut self, node: LocalNodeIndex) -> Option<Box<Packet>> {
+        match self.pending_packets.get_mut(node) {
+            Some(&mut (_, ref mut ps)) => Self::merge_packets(ps),
+            None => None,
+        }
+    }
+
     /// Merge any pending packets.
     fn flush_internal(&mut self, node: LocalNodeIndex) -> Option<Box<Packet>> {
         Self::merge_packets(&mut self.pending_packets[node].1)
@@ -54,17 +69,18 @@ impl GroupCommitQueueSet {
     /// packets that were written.
     pub fn append(&mut self, p: Box<Packet>) -> Option<Box<Packet>> {
         let node = p.dst();
+        let now = self.clock.now();
         let pp = self
             .pending_packets
             .entry(node)
-            .or_insert_with(|| (time::Instant::now(), Vec::new()));
+            .or_insert_with(|| (now, Vec::new()));
 
         if pp.1.is_empty() {
-            pp.0 = time::Instant::now();
+            pp.0 = now;
         }
 
         pp.1.push(p);
-        if pp.0.elapsed() >= self.params.flush_timeout {
+        if now.duration_since(pp.0) >= self.params.flush_timeout {
             self.flush_internal(node)
         } else {
             None
@@ -73,13 +89,14 @@ impl GroupCommitQueueSet {
 
     /// Returns how long until a flush should occur.
     pub fn duration_until_flush(&self) -> Option<time::Duration> {
+        let now = self.clock.now();
         self.pending_packets
             .values()
             .filter(|(_, ps)| !ps.is_empty())
             .map(|p| {
                 self.params
                     .flush_timeout
-                    .checked_sub(p.0.elapsed())
+                    .checked_sub(now.duration_since(p.0))
                     .unwrap_or(time::Duration::from_millis(0))
             })
             .min()
@@ -92,6 +109,7 @@ impl GroupCommitQueueSet {
         let mut packets = packets.peekable();
         let merged_dst = packets.peek().as_mut().unwrap().dst();
         let mut merged_tracer: Tracer = None;
+        let mut merged_durable = false;
 
         let mut all_senders = vec![];
         let merged_data = packets.fold(Vec::new(), |mut acc, p| {
@@ -101,14 +119,22 @@ impl GroupCommitQueueSet {
                     src,
                     senders,
                 } => {
-                    let Input { dst, data, tracer } = unsafe { inner.take() };
+                    let Input {
+                        dst,
+                        data,
+                        tracer,
+                        durable,
+                        ..
+                    } = unsafe { inner.take() };
 
                     assert_eq!(senders.len(), 0);
                     assert_eq!(merged_dst, dst);
+                    let start = acc.len();
                     acc.extend(data);
+                    merged_durable |= durable;
 
                     if let Some(src) = src {
-                        all_senders.push(src);
+                        all_senders.push((src, start..acc.len()));
                     }
 
                     match (&merged_tracer, tracer) {
@@ -137,6 +163,8 @@ impl GroupCommitQueueSet {
                 dst: merged_dst,
                 data: merged_data,
                 tracer: merged_tracer,
+                durable: merged_durable,
+                group: None,
             }),
             src: None,
             senders: all_senders,
@@ -153,3 +181,57 @@ impl GroupCommitQueueSet {
         Self::merge_committed_packets(packets.drain(..))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::VirtualClock;
+    use noria::TableOperation;
+
+    fn input(dst: LocalNodeIndex, x: i32) -> Box<Packet> {
+        Box::new(Packet::Input {
+            inner: LocalOrNot::new(Input {
+                dst,
+                data: vec![TableOperation::Insert(vec![x.into()])],
+                tracer: None,
+                durable: false,
+                group: None,
+            }),
+            src: None,
+            senders: vec![],
+        })
+    }
+
+    #[test]
+    fn it_flushes_once_the_clock_passes_the_timeout() {
+        let clock = VirtualClock::new();
+        let mut params = PersistenceParameters::default();
+        params.flush_timeout = time::Duration::from_secs(1);
+        let mut queues = GroupCommitQueueSet::new(&params, Arc::new(clock.clone()));
+
+        let dst = unsafe { LocalNodeIndex::make(0) };
+        assert!(queues.append(input(dst, 1)).is_none());
+        assert!(queues.append(input(dst, 2)).is_none());
+        assert_eq!(
+            queues.duration_until_flush(),
+            Some(time::Duration::from_secs(1))
+        );
+        assert!(queues.flush_if_necessary().is_none());
+
+        clock.advance(time::Duration::from_millis(600));
+        assert_eq!(
+            queues.duration_until_flush(),
+            Some(time::Duration::from_millis(400))
+        );
+        assert!(queues.flush_if_necessary().is_none());
+
+        clock.advance(time::Duration::from_millis(400));
+        match queues.flush_if_necessary().map(|p| *p) {
+            Some(Packet::Input { inner, .. }) => {
+                assert_eq!(unsafe { inner.take() }.data.len(), 2);
+            }
+            p => panic!("expected a merged input packet, got {:?}", p),
+        }
+        assert_eq!(queues.duration_until_flush(), None);
+    }
+}
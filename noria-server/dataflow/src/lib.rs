@@ -22,10 +22,12 @@ extern crate petgraph;
 extern crate rahashmap;
 extern crate rand;
 extern crate regex;
+extern crate ring;
 extern crate rocksdb;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 #[macro_use]
 extern crate slog;
 extern crate stream_cancel;
@@ -35,6 +37,7 @@ extern crate tokio;
 extern crate vec_map;
 
 crate mod backlog;
+pub mod clock;
 pub mod node;
 pub mod ops;
 pub mod payload; // it makes me _really_ sad that this has to be pub
@@ -51,12 +54,14 @@ use std::sync::{Arc, Mutex};
 use std::time;
 
 pub use backlog::SingleReadHandle;
+pub use clock::{Clock, SystemClock, VirtualClock};
 pub type Readers =
     Arc<Mutex<HashMap<(petgraph::graph::NodeIndex, usize), backlog::SingleReadHandle>>>;
 pub type DomainConfig = domain::Config;
 
-pub use domain::{Domain, DomainBuilder, Index, PollEvent, ProcessResult};
+pub use domain::{Domain, DomainBuilder, Index, Overflow, PollEvent, ProcessResult, StateSize};
 pub use payload::Packet;
+pub use state::BackgroundSync;
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Sharding {
@@ -106,6 +111,10 @@ pub struct PersistenceParameters {
     pub log_dir: Option<PathBuf>,
     /// Number of background threads PersistentState can use (shared acrosss all worker threads).
     pub persistence_threads: i32,
+    /// If set, writes to base tables are not synced to disk as they are processed. Instead, a
+    /// dedicated thread per worker syncs all outstanding writes this often. Writes made since the
+    /// last sync may be lost on a crash.
+    pub background_sync: Option<time::Duration>,
 }
 
 impl Default for PersistenceParameters {
@@ -116,6 +125,7 @@ impl Default for PersistenceParameters {
             log_prefix: String::from("soup"),
             log_dir: None,
             persistence_threads: 1,
+            background_sync: None,
         }
     }
 }
@@ -107,8 +107,10 @@ impl Node {
     pub fn on_commit(&mut self, remap: &HashMap<NodeIndex, IndexPair>) {
         // this is *only* overwritten for these asserts.
         assert!(!self.taken);
-        if let NodeType::Internal(ref mut i) = self.inner {
-            i.on_commit(self.index.unwrap().as_global(), remap)
+        match self.inner {
+            NodeType::Internal(ref mut i) => i.on_commit(self.index.unwrap().as_global(), remap),
+            NodeType::Base(ref mut b) => b.on_commit(remap),
+            _ => {}
         }
     }
 
@@ -211,6 +213,10 @@ impl Node {
     pub fn remove(&mut self) {
         self.inner = NodeType::Dropped;
     }
+
+    pub fn rename(&mut self, name: String) {
+        self.name = name;
+    }
 }
 
 // derefs
@@ -341,6 +347,10 @@ impl Node {
         self.fields.len() - 1
     }
 
+    pub fn rename_column(&mut self, column: usize, field: String) {
+        self.fields[column] = field;
+    }
+
     pub fn has_domain(&self) -> bool {
         self.domain.is_some()
     }
@@ -403,6 +413,11 @@ impl Node {
         }
     }
 
+    /// Whether this node has been handed to a domain.
+    pub fn is_taken(&self) -> bool {
+        self.taken
+    }
+
     pub fn is_egress(&self) -> bool {
         if let NodeType::Egress { .. } = self.inner {
             true
@@ -2,14 +2,19 @@ use fnv::FnvHashMap;
 use node::NodeType;
 use payload;
 use prelude::*;
+use slog::Logger;
 use std::collections::{HashSet, VecDeque};
 use std::mem;
+use std::time;
 
 impl Node {
+    /// Process `m` at this node. If `m` is a write to a base table, `refused` holds the operations
+    /// in it that the domain has already refused, by index.
     #[allow(clippy::too_many_arguments)]
     crate fn process(
         &mut self,
         m: &mut Option<Box<Packet>>,
+        refused: Vec<(usize, ConstraintViolation)>,
         keyed_by: Option<&Vec<usize>>,
         state: &mut StateMap,
         nodes: &DomainNodes,
@@ -17,6 +22,7 @@ impl Node {
         swap: bool,
         output: &mut EnqueuedSends,
         ex: &mut Executor,
+        log: &Logger,
     ) -> (Vec<Miss>, Vec<Lookup>, HashSet<Vec<DataType>>) {
         m.as_mut().unwrap().trace(PacketEvent::Process);
 
@@ -30,13 +36,20 @@ impl Node {
                 });
             }
             NodeType::Base(ref mut b) => {
+                let gaddr = self.index.unwrap().as_global();
                 // NOTE: bases only accept BaseOperations
                 match m.take() {
                     Some(box Packet::Input {
                         inner, mut senders, ..
                     }) => {
-                        let Input { dst, data, tracer } = unsafe { inner.take() };
-                        let mut rs = b.process(addr, data, &*state);
+                        let Input {
+                            dst,
+                            data,
+                            tracer,
+                            durable,
+                            ..
+                        } = unsafe { inner.take() };
+                        let (mut rs, refused) = b.process(addr, data, refused, &*state, log);
 
                         // When a replay originates at a base node, we replay the data *through* that
                         // same base node because its column set may have changed. However, this replay
@@ -47,16 +60,34 @@ impl Node {
                         // So: only materialize if the message we're processing is not a replay!
                         if keyed_by.is_none() {
                             materialize(&mut rs, None, state.get_mut(addr));
+                            if durable {
+                                if let Some(s) = state.get_mut(addr) {
+                                    s.sync();
+                                }
+                            }
                         }
 
                         // Send write-ACKs to all the clients with updates that made
-                        // it into this merged packet:
-                        senders.drain(..).for_each(|src| ex.ack(src));
+                        // it into this merged packet, and tell those whose updates were
+                        // refused why:
+                        for (src, ops) in senders.drain(..) {
+                            let violations: Vec<_> = refused
+                                .iter()
+                                .filter(|&&(i, _)| ops.contains(&i))
+                                .map(|&(i, ref v)| (i - ops.start, v.clone()))
+                                .collect();
+                            if violations.is_empty() {
+                                ex.ack(src);
+                            } else {
+                                ex.reject(src, violations);
+                            }
+                        }
 
                         *m = Some(Box::new(Packet::Message {
                             link: Link::new(dst, dst),
                             data: rs,
                             tracer,
+                            origin: Some((gaddr, time::SystemTime::now())),
                         }));
                     }
                     Some(ref p) => {
@@ -118,7 +149,11 @@ impl Node {
 
                         match i.on_input_raw(ex, from, old_data, &mut tracer, &replay, nodes, state)
                         {
-                            RawProcessingResult::Regular(m) => {
+                            RawProcessingResult::Regular(mut m) => {
+                                if let ReplayContext::None = replay {
+                                    // a burst of writes to the same row need not go any further
+                                    m.results.compact();
+                                }
                                 mem::replace(data, m.results);
                                 lookups = m.lookups;
                                 misses = m.misses;
@@ -224,6 +259,24 @@ impl Node {
         Default::default()
     }
 
+    crate fn process_probe(
+        &mut self,
+        m: Box<Packet>,
+        on_shard: Option<usize>,
+        output: &mut FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
+    ) {
+        let addr = self.local_addr();
+        match self.inner {
+            NodeType::Egress(Some(ref mut e)) => {
+                e.process(&mut Some(m), on_shard.unwrap_or(0), output);
+            }
+            NodeType::Sharder(ref mut s) => {
+                s.process_probe(m, addr, output);
+            }
+            _ => unreachable!(),
+        }
+    }
+
     crate fn process_eviction(
         &mut self,
         from: LocalNodeIndex,
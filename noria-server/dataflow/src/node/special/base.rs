@@ -1,15 +1,154 @@
+use nom_sql::{Literal, SqlType};
 use noria::{Modification, Operation, TableOperation};
+use ops::filter::FilterCondition;
+use ops::project::ProjectExpression;
 use prelude::*;
+use slog::Logger;
 use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use vec_map::VecMap;
 
+/// What a base table does about writes that would leave a reference to another base table
+/// dangling.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReferenceAction {
+    /// Refuse writes that refer to a missing row, and deletes of rows that are still referred to.
+    Reject,
+    /// Set references to missing rows to NULL, including those to rows that are deleted.
+    Nullify,
+    /// Refuse writes that refer to a missing row, and delete the rows that refer to a deleted row.
+    Cascade,
+}
+
+/// A column of a base table that holds the primary key of a row in another base table.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Reference {
+    /// The column that holds the key.
+    pub column: usize,
+    /// The base table that is referred to. It must be in the same domain.
+    pub table: IndexPair,
+    /// The primary key column of the base table that is referred to.
+    pub key: usize,
+    /// What to do about writes that would leave the reference dangling.
+    pub action: ReferenceAction,
+}
+
+/// A condition that every row of a base table must meet.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Check {
+    /// The constraint, as it was declared.
+    pub text: String,
+    /// The column the condition is on.
+    pub column: usize,
+    /// The condition. Rows that are NULL in `column` meet it, like they do in SQL.
+    pub condition: FilterCondition,
+}
+
+/// What a base table does about writes with values that do not have the types its columns are
+/// declared with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TypeMismatch {
+    /// Refuse the operations with such values.
+    Reject,
+    /// Convert the values to the declared types, losing precision if need be, or to NULL if they
+    /// cannot be converted at all.
+    Coerce,
+    /// Accept the values as they are, but log them.
+    Log,
+}
+
+/// The columns of a base table that say where in a stream each row came from, so that events the
+/// stream delivers again, such as after a crash, are not applied twice.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dedup {
+    /// The columns that events are in order within, such as the key or the partition of a
+    /// message.
+    pub key: Vec<usize>,
+    /// The column that holds the offset of each event within its key.
+    pub offset: usize,
+    /// How many of the latest offsets of each key are remembered. Events with offsets older than
+    /// all of those are taken to have been applied already.
+    pub window: usize,
+}
+
+/// How the values already in a column are converted when the type of the column changes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Cast {
+    /// Convert the values to the new type, losing precision if need be, or to NULL if they cannot
+    /// be converted at all.
+    Coerce,
+    /// Like `Coerce`, but values that cannot be converted become the given value instead.
+    CoerceOr(DataType),
+}
+
+impl Cast {
+    /// Convert `v` into a value of type `ty`.
+    pub fn apply(&self, v: &DataType, ty: &SqlType) -> DataType {
+        let cast = v.coerce_to(ty);
+        match *self {
+            Cast::CoerceOr(ref or) if cast.is_none() && !v.is_none() => or.clone(),
+            _ => cast,
+        }
+    }
+}
+
+/// The values that `set` sets columns to, along with the columns.
+fn set_values<'a>(set: &'a mut [Modification]) -> impl Iterator<Item = (usize, &'a mut DataType)> {
+    set.iter_mut().enumerate().filter_map(|(col, m)| match *m {
+        Modification::Set(ref mut v) => Some((col, v)),
+        _ => None,
+    })
+}
+
+/// A value that a base table computes for a column of each row that is inserted without one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DefaultExpression {
+    /// The time of the insert.
+    Now,
+    /// A random UUID, as text.
+    Uuid,
+    /// The next value of a sequence that starts after the largest value already in the column.
+    Sequence,
+}
+
+impl DefaultExpression {
+    fn eval(self, next: &mut i64) -> DataType {
+        match self {
+            DefaultExpression::Now => DataType::from(&Literal::CurrentTimestamp),
+            DefaultExpression::Uuid => {
+                let mut b: [u8; 16] = rand::random();
+                // a version 4 UUID, of the variant in RFC 4122
+                b[6] = (b[6] & 0x0f) | 0x40;
+                b[8] = (b[8] & 0x3f) | 0x80;
+                let hex: String = b.iter().map(|b| format!("{:02x}", b)).collect();
+                format!(
+                    "{}-{}-{}-{}-{}",
+                    &hex[..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..]
+                )
+                .into()
+            }
+            DefaultExpression::Sequence => {
+                *next += 1;
+                DataType::from(*next - 1)
+            }
+        }
+    }
+}
+
 /// Base is used to represent the root nodes of the Noria data flow graph.
 ///
 /// These nodes perform no computation, and their job is merely to persist all received updates and
 /// forward them to interested downstream operators. A base node should only be sent updates of the
 /// type corresponding to the node's type.
+///
+/// A base node with a primary key compares what each batch of writes leaves every row it touches
+/// as with what the row was before, and forwards nothing for rows that end up as they were. Writes
+/// that set a row to the values it already has thus never reach the rest of the graph.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Base {
     primary_key: Option<Vec<usize>>,
@@ -17,6 +156,22 @@ pub struct Base {
     defaults: Vec<DataType>,
     dropped: Vec<usize>,
     unmodified: bool,
+
+    references: Vec<Reference>,
+    unique: Vec<Vec<usize>>,
+    checks: Vec<Check>,
+    generated: Vec<(usize, ProjectExpression)>,
+    default_exprs: Vec<(usize, DefaultExpression)>,
+    soft_delete: Option<usize>,
+    types: Option<(Vec<SqlType>, TypeMismatch)>,
+    dedup: Option<Dedup>,
+
+    /// The next value of each sequence, once it is known.
+    #[serde(skip)]
+    sequences: HashMap<usize, i64>,
+    /// The latest offsets applied for each key of `dedup`, once they are known.
+    #[serde(skip)]
+    applied: Option<HashMap<Vec<DataType>, BTreeSet<i64>>>,
 }
 
 impl Base {
@@ -65,6 +220,19 @@ impl Base {
         self.dropped.push(column);
     }
 
+    /// Change the type of `column` to `ty`, converting its default value with `cast`.
+    ///
+    /// The rows already in the table are the domain's to convert.
+    pub fn alter_column_type(&mut self, column: usize, ty: SqlType, cast: &Cast) {
+        assert!(column < self.defaults.len());
+        self.defaults[column] = cast.apply(&self.defaults[column], &ty);
+        if let Some((ref mut types, _)) = self.types {
+            if column < types.len() {
+                types[column] = ty;
+            }
+        }
+    }
+
     pub fn get_dropped(&self) -> VecMap<DataType> {
         self.dropped
             .iter()
@@ -72,6 +240,408 @@ impl Base {
             .collect()
     }
 
+    /// Refer to another base table from one of this one's columns.
+    pub fn add_reference(&mut self, reference: Reference) {
+        self.references.push(reference);
+    }
+
+    /// The references this base table has to other base tables.
+    pub fn references(&self) -> &[Reference] {
+        &self.references
+    }
+
+    /// Require that no two rows of this base table have the same values in `columns`. Rows with
+    /// NULL in any of them are exempt.
+    pub fn add_unique(&mut self, columns: Vec<usize>) {
+        self.unique.push(columns);
+    }
+
+    /// The sets of columns that no two rows of this base table may share values in.
+    pub fn unique(&self) -> &[Vec<usize>] {
+        &self.unique
+    }
+
+    /// Require that every row of this base table meets `check`.
+    pub fn add_check(&mut self, check: Check) {
+        self.checks.push(check);
+    }
+
+    /// The conditions that every row of this base table must meet.
+    pub fn checks(&self) -> &[Check] {
+        &self.checks
+    }
+
+    /// Compute `column` from the other columns of each row that is written. Whatever value the
+    /// write gave the column is replaced.
+    pub fn add_generated(&mut self, column: usize, expression: ProjectExpression) {
+        self.generated.push((column, expression));
+    }
+
+    /// The columns of this base table that are computed from the others, and how.
+    pub fn generated(&self) -> &[(usize, ProjectExpression)] {
+        &self.generated
+    }
+
+    /// Compute `column` by `expression` for rows that are inserted with NULL in it.
+    pub fn add_default_expression(&mut self, column: usize, expression: DefaultExpression) {
+        self.default_exprs.push((column, expression));
+    }
+
+    /// The columns of this base table whose defaults are computed when rows are inserted, and how.
+    pub fn default_expressions(&self) -> &[(usize, DefaultExpression)] {
+        &self.default_exprs
+    }
+
+    /// Whether any column of this base table takes its default from a sequence.
+    pub fn has_sequences(&self) -> bool {
+        self.default_exprs
+            .iter()
+            .any(|&(_, e)| e == DefaultExpression::Sequence)
+    }
+
+    /// Mark rows as deleted by setting `column` to the time they were deleted, instead of removing
+    /// them.
+    pub fn set_soft_delete(&mut self, column: usize) {
+        self.soft_delete = Some(column);
+    }
+
+    /// The column that rows of this base table are marked as deleted in, if they are kept.
+    pub fn soft_delete(&self) -> Option<usize> {
+        self.soft_delete
+    }
+
+    /// Hold the values written to each column to the type it is declared with, and do `action`
+    /// about values of other types. Columns added later may hold any type.
+    pub fn set_column_types(&mut self, types: Vec<SqlType>, action: TypeMismatch) {
+        self.types = Some((types, action));
+    }
+
+    /// The types the columns of this base table are held to, and what is done about values of
+    /// other types, if they are.
+    pub fn column_types(&self) -> Option<(&[SqlType], TypeMismatch)> {
+        self.types
+            .as_ref()
+            .map(|&(ref types, action)| (&types[..], action))
+    }
+
+    /// Drop the rows written to this base table whose events, as `dedup` identifies them, have
+    /// been applied already.
+    ///
+    /// Since the offsets are kept in the rows, they are as durable as the rows are, and the
+    /// offsets that were applied are found in the table's state again after a restart.
+    pub fn set_dedup(&mut self, dedup: Dedup) {
+        assert_ne!(dedup.window, 0);
+        self.dedup = Some(dedup);
+        self.applied = None;
+    }
+
+    /// How this base table tells events it has already applied apart, if it does.
+    pub fn dedup(&self) -> Option<&Dedup> {
+        self.dedup.as_ref()
+    }
+
+    /// Turn `op` into an update that marks the row as deleted if it is a delete, and this base
+    /// table keeps deleted rows.
+    crate fn soften_delete(&self, op: &mut TableOperation) {
+        let col = match self.soft_delete {
+            Some(col) => col,
+            None => return,
+        };
+        *op = match *op {
+            TableOperation::Delete { ref key } => {
+                let mut set = vec![Modification::None; self.defaults.len()];
+                set[col] = Modification::Set(DataType::from(&Literal::CurrentTimestamp));
+                TableOperation::Update {
+                    key: key.clone(),
+                    set,
+                }
+            }
+            _ => return,
+        };
+    }
+
+    /// Compute the defaults of the columns of `row` that were inserted as NULL.
+    fn fill_defaults(&mut self, us: LocalNodeIndex, row: &mut Vec<DataType>, state: &StateMap) {
+        self.fix(row);
+        let Base {
+            ref default_exprs,
+            ref mut sequences,
+            ..
+        } = *self;
+        for &(col, e) in default_exprs {
+            if !row[col].is_none() {
+                continue;
+            }
+            let next = sequences.entry(col).or_insert_with(|| {
+                // the sequence picks up where the rows already in the table leave off
+                let max = state
+                    .get(us)
+                    .map(|db| {
+                        db.cloned_records()
+                            .iter()
+                            .filter_map(|r| match r[col] {
+                                DataType::Int(i) => Some(i64::from(i)),
+                                DataType::BigInt(i) => Some(i),
+                                _ => None,
+                            })
+                            .max()
+                            .unwrap_or(0)
+                    })
+                    .unwrap_or(0);
+                max + 1
+            });
+            row[col] = e.eval(next);
+        }
+    }
+
+    /// Whether the row `row` is for an event that has been applied already. If not, the event is
+    /// remembered as applied.
+    fn is_duplicate(&mut self, us: LocalNodeIndex, row: &[DataType], state: &StateMap) -> bool {
+        let dedup = match self.dedup {
+            Some(ref dedup) => dedup,
+            None => return false,
+        };
+        let offset = |row: &[DataType]| match row.get(dedup.offset) {
+            Some(&DataType::Int(i)) => Some(i64::from(i)),
+            Some(&DataType::BigInt(i)) => Some(i),
+            _ => None,
+        };
+        let key = |row: &[DataType]| -> Vec<DataType> {
+            dedup
+                .key
+                .iter()
+                .map(|&c| row.get(c).cloned().unwrap_or(DataType::None))
+                .collect()
+        };
+        let remember = |offsets: &mut BTreeSet<i64>, offset: i64| {
+            offsets.insert(offset);
+            while offsets.len() > dedup.window {
+                let oldest = *offsets.iter().next().unwrap();
+                offsets.remove(&oldest);
+            }
+        };
+
+        let applied = self.applied.get_or_insert_with(|| {
+            // the rows already in the table are for the events that were applied before
+            let mut applied = HashMap::new();
+            if let Some(db) = state.get(us) {
+                for r in db.cloned_records() {
+                    if let Some(o) = offset(&r) {
+                        remember(applied.entry(key(&r)).or_insert_with(BTreeSet::new), o);
+                    }
+                }
+            }
+            applied
+        });
+
+        let o = match offset(row) {
+            Some(o) => o,
+            None => return false,
+        };
+        let offsets = applied.entry(key(row)).or_insert_with(BTreeSet::new);
+        let too_old = offsets.len() == dedup.window && o < *offsets.iter().next().unwrap();
+        if too_old || offsets.contains(&o) {
+            return true;
+        }
+        remember(offsets, o);
+        false
+    }
+
+    /// Compute the generated columns of `row`, in the order they were added.
+    fn generate(&self, row: &mut [DataType]) {
+        for &(col, ref e) in &self.generated {
+            let v = e.eval(row);
+            row[col] = v;
+        }
+    }
+
+    /// The first of this base table's checks that `row` fails, if any.
+    fn check(&self, row: &[DataType]) -> Option<ConstraintViolation> {
+        self.checks
+            .iter()
+            .find(|c| {
+                // columns added after the row was written have their default value
+                let v = row.get(c.column).unwrap_or(&self.defaults[c.column]);
+                !v.is_none() && !c.condition.matches(v, row)
+            })
+            .map(|c| {
+                ConstraintViolation::Check(CheckViolation {
+                    check: c.text.clone(),
+                    row: row.to_vec(),
+                })
+            })
+    }
+
+    /// Hold the values in `op` to the types of their columns, and return the first one that does
+    /// not have its column's type if `op` should be refused for it.
+    fn check_types(&self, op: &mut TableOperation, log: &Logger) -> Option<ConstraintViolation> {
+        let (types, action) = match self.types {
+            Some((ref types, action)) => (types, action),
+            None => return None,
+        };
+        let pk = self.primary_key.as_ref().map(|k| &k[..]).unwrap_or(&[]);
+        let values: Vec<_> = match *op {
+            TableOperation::Insert(ref mut row) => row.iter_mut().enumerate().collect(),
+            TableOperation::Delete { ref mut key } => pk.iter().cloned().zip(key).collect(),
+            TableOperation::InsertOrUpdate {
+                ref mut row,
+                ref mut update,
+            } => row
+                .iter_mut()
+                .enumerate()
+                .chain(set_values(update))
+                .collect(),
+            TableOperation::Update {
+                ref mut set,
+                ref mut key,
+            } => pk.iter().cloned().zip(key).chain(set_values(set)).collect(),
+        };
+
+        for (col, v) in values {
+            let ty = match types.get(col) {
+                Some(ty) if !v.fits(ty) => ty,
+                _ => continue,
+            };
+            match action {
+                TypeMismatch::Reject => {
+                    return Some(ConstraintViolation::Type(TypeViolation {
+                        column: col,
+                        expected: ty.clone(),
+                        value: v.clone(),
+                    }));
+                }
+                TypeMismatch::Coerce => *v = v.coerce_to(ty),
+                TypeMismatch::Log => {
+                    warn!(log, "base accepting value of the wrong type";
+                          "value" => ?v, "column" => col, "type" => ?ty)
+                }
+            }
+        }
+        None
+    }
+
+    crate fn on_commit(&mut self, remap: &HashMap<NodeIndex, IndexPair>) {
+        for r in &mut self.references {
+            r.table = remap[&r.table.as_global()];
+        }
+    }
+
+    /// Change `op` if it would leave one of this base table's references dangling, and return why
+    /// it must be refused if it can't be kept at all.
+    crate fn check_references(
+        &self,
+        op: &mut TableOperation,
+        state: &StateMap,
+    ) -> Option<ConstraintViolation> {
+        if self.references.is_empty() {
+            return None;
+        }
+
+        let dangles = |r: &Reference, v: &DataType| {
+            if let DataType::None = *v {
+                return false;
+            }
+            let db = state
+                .get(*r.table)
+                .expect("referenced base must be materialized");
+            match db.lookup(&[r.key], &KeyType::Single(v)) {
+                LookupResult::Some(rows) => rows.is_empty(),
+                LookupResult::Missing => false,
+            }
+        };
+
+        for r in &self.references {
+            let v = match *op {
+                TableOperation::Insert(ref mut row)
+                | TableOperation::InsertOrUpdate { ref mut row, .. } => &mut row[r.column],
+                TableOperation::Update { ref mut set, .. } => match set[r.column] {
+                    Modification::Set(ref mut v) => v,
+                    _ => continue,
+                },
+                TableOperation::Delete { .. } => continue,
+            };
+            if dangles(r, &*v) {
+                match r.action {
+                    ReferenceAction::Nullify => *v = DataType::None,
+                    ReferenceAction::Reject | ReferenceAction::Cascade => {
+                        return Some(ConstraintViolation::Reference(
+                            ReferenceViolation::Missing {
+                                column: r.column,
+                                value: v.clone(),
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// The writes to this base table, `us`, that deleting the row with primary key `key` from the
+    /// base table that `r` refers to calls for, or why the delete must be refused.
+    crate fn on_referenced_delete(
+        &self,
+        us: LocalNodeIndex,
+        r: &Reference,
+        key: &DataType,
+        state: &StateMap,
+    ) -> Result<Vec<TableOperation>, ConstraintViolation> {
+        let db = state.get(us).expect("base must be materialized");
+        let rows = match db.lookup(&[r.column], &KeyType::Single(key)) {
+            LookupResult::Some(rows) => rows,
+            LookupResult::Missing => unreachable!(),
+        };
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let key_cols = match (r.action, self.primary_key.as_ref()) {
+            (ReferenceAction::Reject, _) | (_, None) => {
+                return Err(ConstraintViolation::Reference(
+                    ReferenceViolation::Referenced { key: key.clone() },
+                ));
+            }
+            (_, Some(cols)) => cols,
+        };
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let key = key_cols.iter().map(|&c| row[c].clone()).collect();
+                if r.action == ReferenceAction::Cascade {
+                    return TableOperation::Delete { key };
+                }
+                let mut set = vec![Modification::None; row.len()];
+                set[r.column] = Modification::Set(DataType::None);
+                TableOperation::Update { key, set }
+            })
+            .collect())
+    }
+
+    /// The operations among `ops` that this base table, `us`, would refuse if they were applied
+    /// right after `before`, without applying either.
+    crate fn refusals(
+        &self,
+        us: LocalNodeIndex,
+        before: &[TableOperation],
+        ops: &[TableOperation],
+        state: &StateMap,
+    ) -> Vec<(usize, ConstraintViolation)> {
+        let mut all: Vec<_> = before.iter().chain(ops).cloned().collect();
+        let dangling = all
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, op)| self.check_references(op, state).map(|v| (i, v)))
+            .collect();
+        // the values of the wrong type are logged when the writes are applied
+        let log = Logger::root(slog::Discard, o!());
+        let (_, refused) = self.clone().process(us, all, dangling, state, &log);
+        refused
+            .into_iter()
+            .filter(|&(i, _)| i >= before.len())
+            .map(|(i, v)| (i - before.len(), v))
+            .collect()
+    }
+
     crate fn fix(&self, row: &mut Vec<DataType>) {
         if self.unmodified {
             return;
@@ -95,6 +665,18 @@ impl Clone for Base {
             defaults: self.defaults.clone(),
             dropped: self.dropped.clone(),
             unmodified: self.unmodified,
+
+            references: self.references.clone(),
+            unique: self.unique.clone(),
+            checks: self.checks.clone(),
+            generated: self.generated.clone(),
+            default_exprs: self.default_exprs.clone(),
+            soft_delete: self.soft_delete,
+            types: self.types.clone(),
+            dedup: self.dedup.clone(),
+
+            sequences: HashMap::new(),
+            applied: None,
         }
     }
 }
@@ -107,6 +689,18 @@ impl Default for Base {
             defaults: Vec::new(),
             dropped: Vec::new(),
             unmodified: true,
+
+            references: Vec::new(),
+            unique: Vec::new(),
+            checks: Vec::new(),
+            generated: Vec::new(),
+            default_exprs: Vec::new(),
+            soft_delete: None,
+            types: None,
+            dedup: None,
+
+            sequences: HashMap::new(),
+            applied: None,
         }
     }
 }
@@ -132,31 +726,81 @@ impl Base {
         Clone::clone(self)
     }
 
+    /// Apply `ops` to this base table, `us`, other than those that `refused` already says are
+    /// refused, and return the records to send on along with every operation that was refused.
     pub(in crate::node) fn process(
         &mut self,
         us: LocalNodeIndex,
-        mut ops: Vec<TableOperation>,
+        ops: Vec<TableOperation>,
+        mut refused: Vec<(usize, ConstraintViolation)>,
         state: &StateMap,
-    ) -> Records {
+        log: &Logger,
+    ) -> (Records, Vec<(usize, ConstraintViolation)>) {
+        let mut ops: Vec<_> = ops
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, mut op)| {
+                if refused.iter().any(|&(j, _)| j == i) {
+                    return None;
+                }
+                match self.check_types(&mut op, log) {
+                    Some(v) => {
+                        refused.push((i, v));
+                        None
+                    }
+                    None => Some((i, op)),
+                }
+            })
+            .collect();
+
+        if self.dedup.is_some() {
+            // events that are delivered again are acknowledged, but change nothing
+            ops.retain(|&(_, ref op)| match *op {
+                TableOperation::Insert(ref row)
+                | TableOperation::InsertOrUpdate { ref row, .. } => {
+                    !self.is_duplicate(us, row, state)
+                }
+                _ => true,
+            });
+        }
+
+        if !self.default_exprs.is_empty() {
+            for &mut (_, ref mut op) in &mut ops {
+                match *op {
+                    TableOperation::Insert(ref mut row)
+                    | TableOperation::InsertOrUpdate { ref mut row, .. } => {
+                        self.fill_defaults(us, row, state)
+                    }
+                    _ => {}
+                }
+            }
+        }
+
         if self.primary_key.is_none() || ops.is_empty() {
-            return ops
+            let rs = ops
                 .into_iter()
-                .map(|r| {
+                .filter_map(|(i, r)| {
                     if let TableOperation::Insert(mut r) = r {
                         self.fix(&mut r);
-                        Record::Positive(r)
+                        self.generate(&mut r);
+                        if let Some(v) = self.check(&r) {
+                            refused.push((i, v));
+                            return None;
+                        }
+                        Some(Record::Positive(r))
                     } else {
                         unreachable!("unkeyed base got non-insert operation {:?}", r);
                     }
                 })
                 .collect();
+            return (rs, refused);
         }
 
         let key_cols = &self.primary_key.as_ref().unwrap()[..];
-        ops.sort_by(|a, b| key_of(key_cols, a).cmp(key_of(key_cols, b)));
+        ops.sort_by(|&(_, ref a), &(_, ref b)| key_of(key_cols, a).cmp(key_of(key_cols, b)));
 
         // starting key
-        let mut this_key: Vec<_> = key_of(key_cols, &ops[0]).cloned().collect();
+        let mut this_key: Vec<_> = key_of(key_cols, &ops[0].1).cloned().collect();
 
         // starting record state
         let db = state
@@ -182,22 +826,22 @@ impl Base {
         let mut current = get_current(&this_key);
         let mut was = current.clone();
 
-        let mut results = Vec::with_capacity(ops.len());
-        for op in ops {
+        // the operations on each key that changed its row, and how. if the change is refused,
+        // all of them are, since they only changed the row together.
+        let mut changes = Vec::with_capacity(ops.len());
+        let mut on_key = Vec::new();
+        for (i, op) in ops {
             if this_key.iter().cmp(key_of(key_cols, &op)) != Ordering::Equal {
                 if current != was {
-                    if let Some(was) = was {
-                        results.push(Record::Negative(was.into_owned()));
-                    }
-                    if let Some(current) = current {
-                        results.push(Record::Positive(current.into_owned()));
-                    }
+                    changes.push((on_key, was, current));
                 }
 
                 this_key = key_of(key_cols, &op).cloned().collect();
                 current = get_current(&this_key);
                 was = current.clone();
+                on_key = Vec::new();
             }
+            on_key.push(i);
 
             let update = match op {
                 TableOperation::Insert(row) => {
@@ -255,6 +899,86 @@ impl Base {
 
         // we may have changed things in the last iteration of the loop above
         if current != was {
+            changes.push((on_key, was, current));
+        }
+
+        if !self.generated.is_empty() {
+            for &mut (_, _, ref mut current) in &mut changes {
+                if let Some(ref mut row) = *current {
+                    self.generate(row.to_mut());
+                }
+            }
+            // a write to only generated columns changes nothing
+            changes.retain(|&(_, ref was, ref current)| current != was);
+        }
+
+        if !self.checks.is_empty() {
+            changes.retain(|&(ref on_key, _, ref current)| {
+                match current.as_ref().and_then(|row| self.check(row)) {
+                    Some(v) => {
+                        refused.extend(on_key.iter().map(|&i| (i, v.clone())));
+                        false
+                    }
+                    None => true,
+                }
+            });
+        }
+
+        if !self.unique.is_empty() {
+            // later writes must lose to earlier ones
+            changes.sort_by_key(|&(ref on_key, _, _)| on_key[on_key.len() - 1]);
+
+            // how many more rows have each unique value than before this batch
+            let mut added: HashMap<(usize, Vec<DataType>), isize> = HashMap::new();
+            let value = |row: &Option<Cow<'_, [DataType]>>, cols: &[usize]| {
+                let row = row.as_ref()?;
+                let v: Vec<_> = cols.iter().map(|&c| row[c].clone()).collect();
+                if v.iter().any(DataType::is_none) {
+                    None
+                } else {
+                    Some(v)
+                }
+            };
+            let unique = &self.unique;
+            changes.retain(|&(ref on_key, ref was, ref current)| {
+                let mut moves = Vec::with_capacity(unique.len());
+                for (u, cols) in unique.iter().enumerate() {
+                    let (old, new) = (value(was, cols), value(current, cols));
+                    if old == new {
+                        continue;
+                    }
+                    if let Some(ref new) = new {
+                        let existing = match db.lookup(cols, &KeyType::from(new)) {
+                            LookupResult::Some(rows) => rows.len() as isize,
+                            LookupResult::Missing => unreachable!(),
+                        };
+                        let batch = added.get(&(u, new.clone())).cloned().unwrap_or(0);
+                        if existing + batch > 0 {
+                            let v = ConstraintViolation::Unique(UniqueViolation {
+                                columns: cols.clone(),
+                                value: new.clone(),
+                            });
+                            refused.extend(on_key.iter().map(|&i| (i, v.clone())));
+                            return false;
+                        }
+                    }
+                    moves.push((u, old, new));
+                }
+
+                for (u, old, new) in moves {
+                    if let Some(old) = old {
+                        *added.entry((u, old)).or_insert(0) -= 1;
+                    }
+                    if let Some(new) = new {
+                        *added.entry((u, new)).or_insert(0) += 1;
+                    }
+                }
+                true
+            });
+        }
+
+        let mut results = Vec::with_capacity(2 * changes.len());
+        for (_, was, current) in changes {
             if let Some(was) = was {
                 results.push(Record::Negative(was.into_owned()));
             }
@@ -267,7 +991,7 @@ impl Base {
             self.fix(r);
         }
 
-        results.into()
+        (results.into(), refused)
     }
 
     pub(in crate::node) fn suggest_indexes(&self, n: NodeIndex) -> HashMap<NodeIndex, Vec<usize>> {
@@ -307,7 +1031,58 @@ mod tests {
         assert_eq!(b.unmodified, true);
     }
 
-    fn test_lots_of_changes_in_same_batch(mut state: Box<State>) {
+    #[test]
+    fn it_holds_values_to_column_types() {
+        let mut b = Base::new(vec![]).with_key(vec![0]);
+        let types = vec![SqlType::Int(32), SqlType::Text];
+        b.set_column_types(types.clone(), TypeMismatch::Coerce);
+        let mut op = TableOperation::Insert(vec!["1".into(), 2.into()]);
+        let log = Logger::root(slog::Discard, o!());
+        assert_eq!(b.check_types(&mut op, &log), None);
+        assert_eq!(op, TableOperation::Insert(vec![1.into(), "2".into()]));
+
+        b.set_column_types(types, TypeMismatch::Reject);
+        let mut op = TableOperation::Delete {
+            key: vec!["1".into()],
+        };
+        match b.check_types(&mut op, &log) {
+            Some(ConstraintViolation::Type(ref v)) => assert_eq!(v.column, 0),
+            v => panic!("mistyped key was not refused: {:?}", v),
+        }
+    }
+
+    #[test]
+    fn it_casts_columns_to_new_types() {
+        let mut b = Base::new(vec![DataType::None, "7".into()]);
+        b.set_column_types(vec![SqlType::Int(32), SqlType::Text], TypeMismatch::Reject);
+        b.alter_column_type(1, SqlType::Bigint(64), &Cast::Coerce);
+        assert_eq!(b.defaults[1], 7.into());
+        assert_eq!(b.column_types().unwrap().0[1], SqlType::Bigint(64));
+
+        let cast = Cast::CoerceOr(0.into());
+        assert_eq!(cast.apply(&"x".into(), &SqlType::Int(32)), 0.into());
+        assert_eq!(
+            cast.apply(&DataType::None, &SqlType::Int(32)),
+            DataType::None
+        );
+        assert_eq!(
+            Cast::Coerce.apply(&"x".into(), &SqlType::Int(32)),
+            DataType::None
+        );
+    }
+
+    /// Set up `b` as a base with columns `x`, `y`, and `z` and state `state`, and return a
+    /// function that has it process a batch of writes.
+    fn setup(b: Base, state: Box<State>) -> impl FnMut(Vec<TableOperation>) -> Records {
+        let mut one = setup_refusing(b, state);
+        move |u: Vec<TableOperation>| one(u).0
+    }
+
+    /// Like `setup`, but the function also returns the writes that `b` refused.
+    fn setup_refusing(
+        b: Base,
+        mut state: Box<State>,
+    ) -> impl FnMut(Vec<TableOperation>) -> (Records, Vec<usize>) {
         use node;
         use prelude::*;
 
@@ -319,7 +1094,6 @@ mod tests {
             node::NodeType::Source,
         ));
 
-        let b = Base::new(vec![]).with_key(vec![0, 2]);
         let global = graph.add_node(Node::new("b", &["x", "y", "z"], b));
         graph.add_edge(source, global, ());
         let local = unsafe { LocalNodeIndex::make(0 as u32) };
@@ -343,13 +1117,22 @@ mod tests {
         states.insert(local, state);
         let n = graph[global].take();
         let mut n = n.finalize(&graph);
+        let log = Logger::root(slog::Discard, o!());
 
-        let mut one = move |u: Vec<TableOperation>| {
-            let mut m = n.get_base_mut().unwrap().process(local, u, &states);
+        move |u: Vec<TableOperation>| {
+            let (mut m, refused) =
+                n.get_base_mut()
+                    .unwrap()
+                    .process(local, u, Vec::new(), &states, &log);
             node::materialize(&mut m, None, states.get_mut(local));
-            m
-        };
+            let mut refused: Vec<_> = refused.into_iter().map(|(i, _)| i).collect();
+            refused.sort();
+            (m, refused)
+        }
+    }
 
+    fn test_lots_of_changes_in_same_batch(state: Box<State>) {
+        let mut one = setup(Base::new(vec![]).with_key(vec![0, 2]), state);
         assert_eq!(
             one(vec![
                 TableOperation::Insert(vec![1.into(), "a".into(), 1.into()]),
@@ -417,4 +1200,102 @@ mod tests {
 
         test_lots_of_changes_in_same_batch(box state);
     }
+
+    #[test]
+    fn it_drops_writes_that_change_nothing() {
+        let mut one = setup(Base::new(vec![]).with_key(vec![0]), box MemoryState::default());
+        let row = vec![1.into(), "a".into(), 1.into()];
+        assert_eq!(
+            one(vec![TableOperation::Insert(row.clone())]),
+            vec![row.clone()].into()
+        );
+
+        // setting a row to what it already is does not reach the rest of the graph
+        let same = |v: &str, z: i32| TableOperation::Update {
+            key: vec![1.into()],
+            set: vec![
+                Modification::None,
+                Modification::Set(v.into()),
+                Modification::Set(z.into()),
+            ],
+        };
+        assert_eq!(one(vec![same("a", 1)]), Records::default());
+        assert_eq!(
+            one(vec![TableOperation::InsertOrUpdate {
+                row: row.clone(),
+                update: row.iter().cloned().map(Modification::Set).collect(),
+            }]),
+            Records::default()
+        );
+        // nor do changes that are undone within the same batch
+        assert_eq!(one(vec![same("b", 2), same("a", 1)]), Records::default());
+        assert_eq!(
+            one(vec![same("b", 1)]),
+            vec![(row, false), (vec![1.into(), "b".into(), 1.into()], true)].into()
+        );
+    }
+
+    #[test]
+    fn it_refuses_every_write_to_a_refused_row() {
+        let mut b = Base::new(vec![]).with_key(vec![0]);
+        b.add_unique(vec![1]);
+        let mut state: Box<State> = box MemoryState::default();
+        state.add_key(&[1], None);
+        let mut one = setup_refusing(b, state);
+        let row = |x: i32, y: &str| vec![x.into(), y.into(), 1.into()];
+        assert_eq!(
+            one(vec![TableOperation::Insert(row(1, "a"))]),
+            (vec![row(1, "a")].into(), vec![])
+        );
+
+        // neither write alone makes row 2 clash with row 1, but together they do
+        let (rs, refused) = one(vec![
+            TableOperation::Insert(row(2, "b")),
+            TableOperation::Insert(row(3, "c")),
+            TableOperation::Update {
+                key: vec![2.into()],
+                set: vec![
+                    Modification::None,
+                    Modification::Set("a".into()),
+                    Modification::None,
+                ],
+            },
+        ]);
+        assert_eq!(rs, vec![row(3, "c")].into());
+        assert_eq!(refused, vec![0, 2]);
+    }
+
+    #[test]
+    fn it_drops_events_it_has_applied() {
+        let mut b = Base::new(vec![]).with_key(vec![0]);
+        b.set_dedup(Dedup {
+            key: vec![1],
+            offset: 2,
+            window: 2,
+        });
+        let mut one = setup(b, box MemoryState::default());
+        let upsert = |id: i32, offset: i32| {
+            let row: Vec<DataType> = vec![id.into(), "p".into(), offset.into()];
+            TableOperation::InsertOrUpdate {
+                row: row.clone(),
+                update: row.into_iter().map(Modification::Set).collect(),
+            }
+        };
+
+        assert_eq!(one(vec![upsert(1, 1), upsert(2, 2)]).len(), 2);
+        assert_eq!(one(vec![upsert(1, 4)]).len(), 2);
+        // delivered again
+        assert_eq!(one(vec![upsert(1, 4)]), Records::default());
+        // older than anything in the window
+        assert_eq!(one(vec![upsert(1, 1)]), Records::default());
+        // late, but still in the window
+        assert_eq!(
+            one(vec![upsert(2, 3)]),
+            vec![
+                (vec![2.into(), "p".into(), 2.into()], false),
+                (vec![2.into(), "p".into(), 3.into()], true)
+            ]
+            .into()
+        );
+    }
 }
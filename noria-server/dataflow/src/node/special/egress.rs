@@ -13,6 +13,14 @@ struct EgressTx {
 pub struct Egress {
     txs: Vec<EgressTx>,
     tags: HashMap<Tag, NodeIndex>,
+
+    /// Memoized mapping from replay tag to the index of the tx that tag's replays go out on.
+    ///
+    /// This saves us from having to re-discover the target child for every replay piece (and
+    /// every eviction) that passes through this egress. It is cleared whenever the set of txs or
+    /// tags changes.
+    #[serde(skip)]
+    replay_targets: FnvHashMap<Tag, Option<usize>>,
 }
 
 impl Clone for Egress {
@@ -22,6 +30,7 @@ impl Clone for Egress {
         Self {
             txs: Vec::new(),
             tags: self.tags.clone(),
+            replay_targets: Default::default(),
         }
     }
 }
@@ -31,6 +40,7 @@ impl Default for Egress {
         Self {
             tags: Default::default(),
             txs: Default::default(),
+            replay_targets: Default::default(),
         }
     }
 }
@@ -42,10 +52,20 @@ impl Egress {
             local: dst_l,
             dest: addr,
         });
+        self.replay_targets.clear();
+    }
+
+    /// Stop sending to `dst_g`, which has been removed.
+    ///
+    /// Replays along paths to `dst_g` are dropped from then on.
+    pub fn remove_tx(&mut self, dst_g: NodeIndex) {
+        self.txs.retain(|tx| tx.node != dst_g);
+        self.replay_targets.clear();
     }
 
     pub fn add_tag(&mut self, tag: Tag, dst: NodeIndex) {
         self.tags.insert(tag, dst);
+        self.replay_targets.remove(&tag);
     }
 
     pub fn process(
@@ -55,52 +75,153 @@ impl Egress {
         output: &mut FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
     ) {
         let &mut Self {
-            ref mut txs,
+            ref txs,
             ref tags,
+            ref mut replay_targets,
         } = self;
 
         // send any queued updates to all external children
         assert!(!txs.is_empty());
-        let txn = txs.len() - 1;
 
         // we need to find the ingress node following this egress according to the path
         // with replay.tag, and then forward this message only on the channel corresponding
         // to that ingress node.
-        let replay_to = m.as_ref().unwrap().tag().map(|tag| {
-            tags.get(&tag)
-                .cloned()
-                .expect("egress node told about replay message, but not on replay path")
-        });
+        if let Some(tag) = m.as_ref().unwrap().tag() {
+            let txi = *replay_targets.entry(tag).or_insert_with(|| {
+                let replay_to = tags
+                    .get(&tag)
+                    .expect("egress node told about replay message, but not on replay path");
+                txs.iter().position(|tx| tx.node == *replay_to)
+            });
 
-        for (txi, ref mut tx) in txs.iter_mut().enumerate() {
-            let mut take = txi == txn;
-            if let Some(replay_to) = replay_to.as_ref() {
-                if *replay_to == tx.node {
-                    take = true;
-                } else {
-                    continue;
-                }
+            if let Some(txi) = txi {
+                Self::send(&txs[txi], m.take().unwrap(), shard, output);
             }
+            return;
+        }
 
-            // Avoid cloning if this is last send
-            let mut m = if take {
+        // this is a regular update, so it goes to every child. the copies share its records, and
+        // their encoding, until a child changes them. we avoid copying for the last send.
+        let txn = txs.len() - 1;
+        for (txi, tx) in txs.iter().enumerate() {
+            let m = if txi == txn {
                 m.take().unwrap()
             } else {
-                // we know this is a data (not a replay)
-                // because, a replay will force a take
                 m.as_ref().map(|m| box m.clone_data()).unwrap()
             };
 
-            // src is usually ignored and overwritten by ingress
-            // *except* if the ingress is marked as a shard merger
-            // in which case it wants to know about the shard
-            m.link_mut().src = unsafe { LocalNodeIndex::make(shard as u32) };
-            m.link_mut().dst = tx.local;
+            Self::send(tx, m, shard, output);
+        }
+    }
 
-            output.entry(tx.dest).or_default().push_back(m);
-            if take {
-                break;
-            }
+    fn send(
+        tx: &EgressTx,
+        mut m: Box<Packet>,
+        shard: usize,
+        output: &mut FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
+    ) {
+        // src is usually ignored and overwritten by ingress
+        // *except* if the ingress is marked as a shard merger
+        // in which case it wants to know about the shard
+        m.link_mut().src = unsafe { LocalNodeIndex::make(shard as u32) };
+        m.link_mut().dst = tx.local;
+
+        output.entry(tx.dest).or_default().push_back(m);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lni(i: usize) -> LocalNodeIndex {
+        unsafe { LocalNodeIndex::make(i as u32) }
+    }
+
+    fn egress(n: usize) -> Egress {
+        let mut e = Egress::default();
+        for i in 0..n {
+            e.add_tx(NodeIndex::new(i), lni(i), (DomainIndex::from(i), 0));
+        }
+        e
+    }
+
+    fn evict(tag: Tag) -> Option<Box<Packet>> {
+        Some(box Packet::EvictKeys {
+            link: Link::new(lni(0), lni(0)),
+            tag,
+            keys: vec![vec![1.into()]],
+        })
+    }
+
+    #[test]
+    fn regular_goes_to_all() {
+        let mut e = egress(3);
+        let mut output = FnvHashMap::default();
+        let mut m = Some(box Packet::Message {
+            link: Link::new(lni(0), lni(0)),
+            data: vec![vec![DataType::from(1)]].into(),
+            tracer: None,
+            origin: None,
+        });
+        e.process(&mut m, 0, &mut output);
+        assert!(m.is_none());
+        assert_eq!(output.len(), 3);
+        for i in 0..3 {
+            let ms = &output[&(DomainIndex::from(i), 0)];
+            assert_eq!(ms.len(), 1);
+            assert_eq!(ms[0].data().len(), 1);
+            assert_eq!(ms[0].dst(), lni(i));
+        }
+    }
+
+    #[test]
+    fn replay_goes_to_target() {
+        let mut e = egress(3);
+        e.add_tag(Tag(1), NodeIndex::new(1));
+        e.add_tag(Tag(2), NodeIndex::new(2));
+
+        let mut output = FnvHashMap::default();
+        for &tag in &[Tag(1), Tag(2), Tag(1)] {
+            let mut m = evict(tag);
+            e.process(&mut m, 0, &mut output);
+            assert!(m.is_none());
         }
+
+        assert!(!output.contains_key(&(DomainIndex::from(0), 0)));
+        assert_eq!(output[&(DomainIndex::from(1), 0)].len(), 2);
+        assert_eq!(output[&(DomainIndex::from(2), 0)].len(), 1);
+
+        // the memoized target must not outlive a change to the tag's path
+        e.add_tx(NodeIndex::new(3), lni(3), (DomainIndex::from(3), 0));
+        e.add_tag(Tag(1), NodeIndex::new(3));
+        e.process(&mut evict(Tag(1)), 0, &mut output);
+        assert_eq!(output[&(DomainIndex::from(1), 0)].len(), 2);
+        assert_eq!(output[&(DomainIndex::from(3), 0)].len(), 1);
+    }
+
+    #[test]
+    fn removed_children_get_nothing() {
+        let mut e = egress(2);
+        e.add_tag(Tag(1), NodeIndex::new(1));
+        let mut output = FnvHashMap::default();
+        e.process(&mut evict(Tag(1)), 0, &mut output);
+        assert_eq!(output[&(DomainIndex::from(1), 0)].len(), 1);
+
+        e.remove_tx(NodeIndex::new(1));
+        let mut output = FnvHashMap::default();
+        let mut m = evict(Tag(1));
+        e.process(&mut m, 0, &mut output);
+        assert!(output.is_empty());
+
+        let mut m = Some(box Packet::Message {
+            link: Link::new(lni(0), lni(0)),
+            data: vec![vec![DataType::from(1)]].into(),
+            tracer: None,
+            origin: None,
+        });
+        e.process(&mut m, 0, &mut output);
+        assert_eq!(output.len(), 1);
+        assert!(output.contains_key(&(DomainIndex::from(0), 0)));
     }
 }
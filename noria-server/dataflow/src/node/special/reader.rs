@@ -1,6 +1,11 @@
 use backlog;
+use nom_sql::OrderType;
 use noria::channel;
+use ops::project::{self, ColumnMask};
+use payload::BarrierKind;
 use prelude::*;
+use std::collections::HashMap;
+use std::time;
 
 /// A StreamUpdate reflects the addition or deletion of a row from a reader node.
 #[derive(Clone, Debug, PartialEq)]
@@ -36,6 +41,14 @@ pub struct Reader {
 
     for_node: NodeIndex,
     state: Option<Vec<usize>>,
+    /// The order to return the rows for each key in, if any.
+    order: Option<Vec<(usize, OrderType)>>,
+    /// Whether to keep only one copy of each row.
+    distinct: bool,
+    /// Columns to redact in the rows that are read, which the reader is still keyed by unredacted.
+    masks: Option<Vec<(usize, ColumnMask)>>,
+    /// The security universe this reader belongs to, if it is not global.
+    universe: Option<String>,
 }
 
 impl Clone for Reader {
@@ -45,7 +58,11 @@ impl Clone for Reader {
             writer: None,
             streamers: self.streamers.clone(),
             state: self.state.clone(),
+            order: self.order.clone(),
+            distinct: self.distinct,
+            masks: self.masks.clone(),
             for_node: self.for_node,
+            universe: self.universe.clone(),
         }
     }
 }
@@ -56,7 +73,11 @@ impl Reader {
             writer: None,
             streamers: Vec::new(),
             state: None,
+            order: None,
+            distinct: false,
+            masks: None,
             for_node,
+            universe: None,
         }
     }
 
@@ -66,6 +87,14 @@ impl Reader {
         self.for_node
     }
 
+    pub fn universe(&self) -> Option<&str> {
+        self.universe.as_ref().map(String::as_str)
+    }
+
+    pub fn set_universe(&mut self, universe: String) {
+        self.universe = Some(universe);
+    }
+
     #[allow(dead_code)]
     fn writer(&self) -> Option<&backlog::WriteHandle> {
         self.writer.as_ref()
@@ -81,7 +110,11 @@ impl Reader {
             writer: self.writer.take(),
             streamers: mem::replace(&mut self.streamers, Vec::new()),
             state: self.state.clone(),
+            order: self.order.clone(),
+            distinct: self.distinct,
+            masks: self.masks.clone(),
             for_node: self.for_node,
+            universe: self.universe.clone(),
         }
     }
 
@@ -104,8 +137,11 @@ impl Reader {
         }
     }
 
-    crate fn set_write_handle(&mut self, wh: backlog::WriteHandle) {
+    crate fn set_write_handle(&mut self, mut wh: backlog::WriteHandle) {
         assert!(self.writer.is_none());
+        if self.distinct {
+            wh.deduplicate();
+        }
         self.writer = Some(wh);
     }
 
@@ -113,6 +149,36 @@ impl Reader {
         self.state.as_ref().map(|s| &s[..])
     }
 
+    /// Return the rows for each key sorted by `order`.
+    pub fn set_order(&mut self, order: Vec<(usize, OrderType)>) {
+        self.order = Some(order);
+    }
+
+    pub fn order(&self) -> Option<&[(usize, OrderType)]> {
+        self.order.as_ref().map(|o| &o[..])
+    }
+
+    /// Keep only one copy of each row, however many times it is produced.
+    pub fn set_distinct(&mut self) {
+        self.distinct = true;
+    }
+
+    pub fn is_distinct(&self) -> bool {
+        self.distinct
+    }
+
+    /// Redact the given columns of every row that is read or streamed from this reader.
+    ///
+    /// Unlike masks in a `Project`, these leave the rows the reader keeps intact, so they can be
+    /// used on the columns that the reader is keyed by.
+    pub fn set_masks(&mut self, masks: Vec<(usize, ColumnMask)>) {
+        self.masks = if masks.is_empty() { None } else { Some(masks) };
+    }
+
+    pub fn masks(&self) -> Option<&[(usize, ColumnMask)]> {
+        self.masks.as_ref().map(|m| &m[..])
+    }
+
     pub fn set_key(&mut self, key: &[usize]) {
         if let Some(ref skey) = self.state {
             assert_eq!(&skey[..], key);
@@ -125,6 +191,19 @@ impl Reader {
         self.writer.as_ref().map(SizeOf::deep_size_of)
     }
 
+    /// Note that a copy of barrier `id` has arrived, out of the `expected` copies that will.
+    crate fn reach_barrier(&mut self, id: u64, kind: BarrierKind, expected: usize) {
+        // NOTE: *could* be None if reader has been created but its state hasn't been built yet
+        if let Some(w) = self.writer.as_mut() {
+            w.reach_barrier(id, kind, expected);
+        }
+    }
+
+    /// When each base table processed the newest write from it that readers can currently see.
+    crate fn freshness(&self) -> Option<HashMap<NodeIndex, time::SystemTime>> {
+        self.writer.as_ref().map(backlog::WriteHandle::freshness)
+    }
+
     /// Evict a randomly selected key, returning the number of bytes evicted.
     /// Note that due to how `evmap` applies the evictions asynchronously, we can only evict a
     /// single key at a time here.
@@ -200,6 +279,10 @@ impl Reader {
                 });
             }
 
+            if let Some((base, at)) = m.origin() {
+                state.reflect(base, at);
+            }
+
             if self.streamers.is_empty() {
                 state.add(m.take_data());
             } else {
@@ -217,7 +300,13 @@ impl Reader {
         m.as_mut().unwrap().trace(PacketEvent::ReachedReader);
 
         if !self.streamers.is_empty() {
-            let mut data = Some(m.take().unwrap().take_data()); // so we can .take() for last tx
+            let mut data = m.take().unwrap().take_data();
+            if let Some(ref masks) = self.masks {
+                for r in data.iter_mut() {
+                    project::apply_masks(&masks[..], &mut r[..]);
+                }
+            }
+            let mut data = Some(data); // so we can .take() for last tx
             let mut left = self.streamers.len();
 
             // remove any channels where the receiver has hung up
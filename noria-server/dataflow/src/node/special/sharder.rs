@@ -129,6 +129,21 @@ impl Sharder {
         }
     }
 
+    /// Send a latency probe or a snapshot barrier on to every shard.
+    pub fn process_probe(
+        &mut self,
+        m: Box<Packet>,
+        index: LocalNodeIndex,
+        output: &mut FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
+    ) {
+        for &(dst, addr) in &self.txs {
+            let mut m = box m.clone_data();
+            m.link_mut().src = index;
+            m.link_mut().dst = dst;
+            output.entry(addr).or_default().push_back(m);
+        }
+    }
+
     pub fn process_eviction(
         &mut self,
         key_columns: &[usize],
@@ -40,6 +40,31 @@ pub enum FilterCondition {
     In(Vec<DataType>),
 }
 
+impl FilterCondition {
+    /// Whether the value `d` in the record `r` meets this condition.
+    pub fn matches(&self, d: &DataType, r: &[DataType]) -> bool {
+        match *self {
+            FilterCondition::Comparison(ref op, ref f) => {
+                let v = match *f {
+                    Value::Constant(ref dt) => dt,
+                    Value::Column(c) => &r[c],
+                };
+                match *op {
+                    Operator::Equal => d == v,
+                    Operator::NotEqual => d != v,
+                    Operator::Greater => d > v,
+                    Operator::GreaterOrEqual => d >= v,
+                    Operator::Less => d < v,
+                    Operator::LessOrEqual => d <= v,
+                    Operator::In => unreachable!(),
+                    _ => unimplemented!(),
+                }
+            }
+            FilterCondition::In(ref fs) => fs.contains(d),
+        }
+    }
+}
+
 impl Filter {
     /// Construct a new filter operator. The `filter` vector must have as many elements as the
     /// `src` node has columns. Each column that is set to `None` matches any value, while columns
@@ -85,27 +110,8 @@ impl Ingredient for Filter {
         rs.retain(|r| {
             self.filter.iter().enumerate().all(|(i, fi)| {
                 // check if this filter matches
-                let d = &r[i];
                 if let Some(ref cond) = *fi {
-                    match *cond {
-                        FilterCondition::Comparison(ref op, ref f) => {
-                            let v = match *f {
-                                Value::Constant(ref dt) => dt,
-                                Value::Column(c) => &r[c],
-                            };
-                            match *op {
-                                Operator::Equal => d == v,
-                                Operator::NotEqual => d != v,
-                                Operator::Greater => d > v,
-                                Operator::GreaterOrEqual => d >= v,
-                                Operator::Less => d < v,
-                                Operator::LessOrEqual => d <= v,
-                                Operator::In => unreachable!(),
-                                _ => unimplemented!(),
-                            }
-                        }
-                        FilterCondition::In(ref fs) => fs.contains(d),
-                    }
+                    cond.matches(&r[i], r)
                 } else {
                     // everything matches no condition
                     true
@@ -0,0 +1,496 @@
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+use prelude::*;
+
+/// The number of bits of a value's hash that pick its register.
+const PRECISION: u32 = 10;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// A HyperLogLog sketch of the distinct values in a multiset, which can also have values taken
+/// out of it.
+///
+/// Rather than the highest rank that each register has seen, the sketch counts how many values
+/// it holds with each rank in each register, so that a register falls back to the next highest
+/// rank once all the values with its highest rank are retracted. Two sketches are merged by
+/// adding up their counts, which is how the partial sketches made in the shards of an input are
+/// put together. A sketch of 1024 registers estimates the number of distinct values to within a
+/// few percent.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HyperLogLog {
+    counts: BTreeMap<(u16, u8), i64>,
+}
+
+impl HyperLogLog {
+    /// Add or retract a copy of `value`. `NULL` is never counted.
+    pub fn insert(&mut self, value: &DataType, positive: bool) {
+        if let DataType::None = *value {
+            return;
+        }
+
+        let mut h = DefaultHasher::new();
+        value.hash(&mut h);
+        let hash = h.finish();
+        let register = (hash >> (64 - PRECISION)) as u16;
+        let rank = ((hash << PRECISION).leading_zeros().min(64 - PRECISION) + 1) as u8;
+        self.add((register, rank), if positive { 1 } else { -1 });
+    }
+
+    /// Add the counts of `other` to this sketch, or take them away from it.
+    pub fn merge(&mut self, other: &HyperLogLog, positive: bool) {
+        for (&slot, &count) in &other.counts {
+            self.add(slot, if positive { count } else { -count });
+        }
+    }
+
+    fn add(&mut self, slot: (u16, u8), delta: i64) {
+        let count = {
+            let count = self.counts.entry(slot).or_insert(0);
+            *count += delta;
+            *count
+        };
+        if count == 0 {
+            self.counts.remove(&slot);
+        }
+    }
+
+    /// An estimate of the number of distinct values in the sketch.
+    pub fn estimate(&self) -> u64 {
+        let mut registers = [0u8; REGISTERS];
+        for (&(register, rank), &count) in &self.counts {
+            let register = &mut registers[register as usize];
+            if count > 0 && rank > *register {
+                *register = rank;
+            }
+        }
+
+        let m = REGISTERS as f64;
+        let sum: f64 = registers.iter().map(|&r| 2f64.powi(-i32::from(r))).sum();
+        let zeros = registers.iter().filter(|&&r| r == 0).count();
+        let raw = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let estimate = if raw <= 2.5 * m && zeros > 0 {
+            // few registers are set, so count those that are not instead
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        };
+        estimate.round() as u64
+    }
+}
+
+impl<'a> From<&'a DataType> for HyperLogLog {
+    fn from(value: &'a DataType) -> Self {
+        let mut sketch = HyperLogLog::default();
+        if let DataType::None = *value {
+            return sketch;
+        }
+
+        let text: Cow<str> = value.into();
+        for slot in text.split(',').filter(|s| !s.is_empty()) {
+            let mut parts = slot.split('.');
+            let mut next = || {
+                parts
+                    .next()
+                    .and_then(|p| p.parse::<i64>().ok())
+                    .unwrap_or_else(|| panic!("malformed sketch: {}", text))
+            };
+            let (register, rank, count) = (next(), next(), next());
+            sketch.add((register as u16, rank as u8), count);
+        }
+        sketch
+    }
+}
+
+impl<'a> From<&'a HyperLogLog> for DataType {
+    fn from(sketch: &'a HyperLogLog) -> Self {
+        sketch
+            .counts
+            .iter()
+            .map(|(&(register, rank), count)| format!("{}.{}.{}", register, rank, count))
+            .collect::<Vec<_>>()
+            .join(",")
+            .into()
+    }
+}
+
+/// Estimates the number of distinct values of the `over` column in each group, as with
+/// `APPROX_COUNT_DISTINCT(over)`.
+///
+/// Instead of every distinct value, the operator keeps a `HyperLogLog` sketch for each group, and
+/// emits the group along with the estimate whenever the estimate changes. The sketches are
+/// rebuilt by the full replay that fills the operator's state, so it must be fully materialized.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ApproxCountDistinct {
+    src: IndexPair,
+    over: usize,
+    group: Vec<usize>,
+    /// Whether the `over` column holds the sketches made by a `PartialSketch` rather than values.
+    merge: bool,
+
+    #[serde(skip)]
+    sketches: HashMap<Vec<DataType>, HyperLogLog>,
+}
+
+impl ApproxCountDistinct {
+    /// Construct a new operator that estimates the distinct values of column `over` of `src` in
+    /// each group of the columns in `group_by`, which should not include `over`.
+    pub fn over(src: NodeIndex, over: usize, group_by: &[usize]) -> Self {
+        assert!(
+            !group_by.iter().any(|&i| i == over),
+            "cannot group by aggregation column"
+        );
+        let mut group = group_by.to_vec();
+        group.sort();
+        ApproxCountDistinct {
+            src: src.into(),
+            over,
+            group,
+            merge: false,
+            sketches: HashMap::new(),
+        }
+    }
+
+    /// Split this operator into a `PartialSketch`, which sketches the values of each group in a
+    /// batch, and this operator, which then only merges those sketches.
+    pub fn split_partial(&mut self) -> PartialSketch {
+        self.merge = true;
+        PartialSketch {
+            src: self.src,
+            over: self.over,
+            group: self.group.clone(),
+        }
+    }
+}
+
+impl Ingredient for ApproxCountDistinct {
+    fn take(&mut self) -> NodeOperator {
+        Clone::clone(self).into()
+    }
+
+    fn ancestors(&self) -> Vec<NodeIndex> {
+        vec![self.src.as_global()]
+    }
+
+    fn on_connected(&mut self, g: &Graph) {
+        assert!(
+            self.over < g[self.src.as_global()].fields().len(),
+            "cannot aggregate over non-existing column"
+        );
+    }
+
+    fn on_commit(&mut self, _: NodeIndex, remap: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(remap);
+    }
+
+    fn on_input(
+        &mut self,
+        _: &mut Executor,
+        from: LocalNodeIndex,
+        rs: Records,
+        _: &mut Tracer,
+        _: Option<&[usize]>,
+        _: &DomainNodes,
+        _: &StateMap,
+    ) -> ProcessingResult {
+        debug_assert_eq!(from, *self.src);
+
+        // the groups that changed, in the order they first showed up, with their old estimates
+        let mut changed = Vec::new();
+        let mut seen = HashSet::new();
+        for r in rs {
+            let (row, positive) = r.extract();
+            let group: Vec<_> = self.group.iter().map(|&c| row[c].clone()).collect();
+            if seen.insert(group.clone()) {
+                let before = self.sketches.get(&group).map(HyperLogLog::estimate);
+                changed.push((group.clone(), before));
+            }
+
+            let sketch = self.sketches.entry(group).or_insert_with(Default::default);
+            if self.merge {
+                sketch.merge(&HyperLogLog::from(&row[self.over]), positive);
+            } else {
+                sketch.insert(&row[self.over], positive);
+            }
+        }
+
+        // groups that are seen for the first time are emitted even if they are empty, as with
+        // counts
+        let mut out = Vec::new();
+        for (group, before) in changed {
+            let after = self.sketches[&group].estimate();
+            if before == Some(after) {
+                continue;
+            }
+            if let Some(before) = before {
+                let mut row = group.clone();
+                row.push((before as i64).into());
+                out.push((row, false));
+            }
+            let mut row = group;
+            row.push((after as i64).into());
+            out.push((row, true));
+        }
+
+        ProcessingResult {
+            results: out.into(),
+            ..Default::default()
+        }
+    }
+
+    fn suggest_indexes(&self, this: NodeIndex) -> HashMap<NodeIndex, Vec<usize>> {
+        // index by our primary key
+        Some((this, (0..self.group.len()).collect()))
+            .into_iter()
+            .collect()
+    }
+
+    fn resolve(&self, col: usize) -> Option<Vec<(NodeIndex, usize)>> {
+        self.group
+            .get(col)
+            .map(|&c| vec![(self.src.as_global(), c)])
+    }
+
+    fn description(&self, detailed: bool) -> String {
+        if !detailed {
+            return String::from("≈|δ|");
+        }
+
+        let group_cols = self
+            .group
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("≈|δ|({}) γ[{}]", self.over, group_cols)
+    }
+
+    fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
+        vec![(self.src.as_global(), self.group.get(column).cloned())]
+    }
+
+    fn requires_full_materialization(&self) -> bool {
+        true
+    }
+
+    fn is_selective(&self) -> bool {
+        true
+    }
+}
+
+/// Sketches the values of each group of an `ApproxCountDistinct` within every batch of records,
+/// so that only one record per group is sent on to the operator itself.
+///
+/// Each output record is the first record of its group in the batch, with the `over` column
+/// replaced by a sketch of the values that the batch added to and retracted from the group. The
+/// operator must then merge those sketches (see `ApproxCountDistinct::split_partial`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartialSketch {
+    src: IndexPair,
+    over: usize,
+    group: Vec<usize>,
+}
+
+impl PartialSketch {
+    /// The columns that records are grouped by.
+    pub fn group_by(&self) -> &[usize] {
+        &self.group[..]
+    }
+}
+
+impl Ingredient for PartialSketch {
+    fn take(&mut self) -> NodeOperator {
+        Clone::clone(self).into()
+    }
+
+    fn ancestors(&self) -> Vec<NodeIndex> {
+        vec![self.src.as_global()]
+    }
+
+    fn on_connected(&mut self, _: &Graph) {}
+
+    fn on_commit(&mut self, _: NodeIndex, remap: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(remap);
+    }
+
+    fn on_input(
+        &mut self,
+        _: &mut Executor,
+        _: LocalNodeIndex,
+        rs: Records,
+        _: &mut Tracer,
+        _: Option<&[usize]>,
+        _: &DomainNodes,
+        _: &StateMap,
+    ) -> ProcessingResult {
+        let group = &self.group;
+        let mut rs: Vec<_> = rs.into();
+        rs.sort_by(|a, b| {
+            group
+                .iter()
+                .map(|&c| &a[c])
+                .cmp(group.iter().map(|&c| &b[c]))
+        });
+
+        let mut rows: Vec<Vec<DataType>> = Vec::new();
+        let mut sketches: Vec<HyperLogLog> = Vec::new();
+        for r in rs {
+            let same = rows
+                .last()
+                .map_or(false, |row| group.iter().all(|&c| row[c] == r[c]));
+            if !same {
+                sketches.push(HyperLogLog::default());
+            }
+            sketches
+                .last_mut()
+                .unwrap()
+                .insert(&r[self.over], r.is_positive());
+            if !same {
+                rows.push(r.extract().0);
+            }
+        }
+
+        let over = self.over;
+        let results: Vec<_> = rows
+            .into_iter()
+            .zip(sketches)
+            .map(|(mut row, sketch)| {
+                row[over] = (&sketch).into();
+                Record::Positive(row)
+            })
+            .collect();
+        ProcessingResult {
+            results: results.into(),
+            ..Default::default()
+        }
+    }
+
+    fn suggest_indexes(&self, _: NodeIndex) -> HashMap<NodeIndex, Vec<usize>> {
+        HashMap::new()
+    }
+
+    fn resolve(&self, col: usize) -> Option<Vec<(NodeIndex, usize)>> {
+        if !self.group.contains(&col) {
+            return None;
+        }
+        Some(vec![(self.src.as_global(), col)])
+    }
+
+    fn description(&self, _: bool) -> String {
+        format!("∂≈|δ|({})", self.over)
+    }
+
+    fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
+        if !self.group.contains(&column) {
+            return vec![(self.src.as_global(), None)];
+        }
+        vec![(self.src.as_global(), Some(column))]
+    }
+
+    fn is_selective(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ops;
+
+    fn setup() -> ops::test::MockGraph {
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["x", "y"]);
+        g.set_op(
+            "approx",
+            &["x", "ys"],
+            ApproxCountDistinct::over(s.as_global(), 1, &[0]),
+            true,
+        );
+        g
+    }
+
+    fn sketch_of(values: ::std::ops::Range<i32>) -> HyperLogLog {
+        let mut sketch = HyperLogLog::default();
+        for v in values {
+            sketch.insert(&v.into(), true);
+        }
+        sketch
+    }
+
+    #[test]
+    fn it_estimates_distinct_values() {
+        let mut sketch = sketch_of(0..10_000);
+        // copies of values that are already there change nothing
+        let before = sketch.estimate();
+        sketch.merge(&sketch_of(0..5_000), true);
+        assert_eq!(sketch.estimate(), before);
+        assert!(
+            (before as i64 - 10_000).abs() < 1_000,
+            "estimated {}",
+            before
+        );
+
+        // taking all copies of half the values out halves the estimate
+        sketch.merge(&sketch_of(0..5_000), false);
+        sketch.merge(&sketch_of(0..5_000), false);
+        let after = sketch.estimate();
+        assert!((after as i64 - 5_000).abs() < 500, "estimated {}", after);
+        assert_eq!(sketch, sketch_of(5_000..10_000));
+
+        // small counts are exact enough to compare
+        assert_eq!(sketch_of(0..3).estimate(), 3);
+        assert_eq!(HyperLogLog::default().estimate(), 0);
+
+        let text: DataType = (&sketch).into();
+        assert_eq!(HyperLogLog::from(&text), sketch);
+        assert_eq!(HyperLogLog::from(&DataType::None), HyperLogLog::default());
+    }
+
+    #[test]
+    fn it_forwards_estimates() {
+        let mut g = setup();
+        let row = |x: i32, y: i32| vec![DataType::from(x), y.into()];
+        let out = |x: i32, n: i64| vec![DataType::from(x), n.into()];
+
+        assert_eq!(g.narrow_one_row(row(1, 1), true), vec![out(1, 1)].into());
+        assert!(g.narrow_one_row(row(1, 1), true).is_empty());
+        assert_eq!(
+            g.narrow_one_row(row(1, 2), true),
+            vec![(out(1, 1), false), (out(1, 2), true)].into()
+        );
+        // one copy of 1 is left
+        assert!(g.narrow_one_row((row(1, 1), false), true).is_empty());
+        assert_eq!(
+            g.narrow_one(vec![(row(1, 1), false), (row(2, 1), true)], true),
+            vec![(out(1, 2), false), (out(1, 1), true), (out(2, 1), true)].into()
+        );
+    }
+
+    #[test]
+    fn it_merges_partial_sketches() {
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["x", "y"]);
+        let mut approx = ApproxCountDistinct::over(s.as_global(), 1, &[0]);
+        let partial = approx.split_partial();
+        g.set_op("approx", &["x", "ys"], approx, true);
+
+        let sketch = |values| DataType::from(&sketch_of(values));
+        assert_eq!(
+            g.narrow_one_row(vec![1.into(), sketch(0..3)], true),
+            vec![vec![DataType::from(1), 3.into()]].into()
+        );
+        // sketches from other shards overlap
+        assert_eq!(
+            g.narrow_one_row(vec![1.into(), sketch(2..4)], true),
+            vec![
+                (vec![DataType::from(1), 3.into()], false),
+                (vec![DataType::from(1), 4.into()], true),
+            ]
+            .into()
+        );
+        assert_eq!(partial.group_by(), &[0]);
+        assert_eq!(partial.description(true), "∂≈|δ|(1)");
+    }
+}
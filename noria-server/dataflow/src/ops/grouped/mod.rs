@@ -3,12 +3,14 @@ use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
 
+use ops::project::ProjectExpression;
 use prelude::*;
 
 // pub mod latest;
 pub mod aggregate;
 pub mod concat;
 pub mod extremum;
+pub mod hll;
 
 /// Trait for implementing operations that collapse a group of records into a single record.
 ///
@@ -71,6 +73,9 @@ pub struct GroupedOperator<T: GroupedOperation> {
     us: Option<IndexPair>,
     cols: usize,
 
+    // computed columns that records are also grouped by, which come after the grouped columns
+    key_exprs: Vec<ProjectExpression>,
+
     // precomputed datastructures
     group_by: Vec<usize>,
     out_key: Vec<usize>,
@@ -85,12 +90,22 @@ impl<T: GroupedOperation> GroupedOperator<T> {
 
             us: None,
             cols: 0,
+            key_exprs: Vec::new(),
             group_by: Vec::new(),
             out_key: Vec::new(),
             colfix: Vec::new(),
         }
     }
 
+    /// Also group records by the values of `exprs`, such as a timestamp rounded down to the day.
+    ///
+    /// The values are emitted after the grouped columns, and before the computed value. Since they
+    /// cannot be traced back to a column of the parent, the operator is always fully materialized.
+    pub fn with_key_expressions(mut self, exprs: Vec<ProjectExpression>) -> Self {
+        self.key_exprs = exprs;
+        self
+    }
+
     pub fn over_columns(&self) -> Vec<usize> {
         self.inner.over_columns()
     }
@@ -119,7 +134,7 @@ where
         self.group_by.extend(self.inner.group_by().iter().cloned());
         self.group_by.sort();
         // cache the range of our output keys
-        self.out_key = (0..self.group_by.len()).collect();
+        self.out_key = (0..self.group_by.len() + self.key_exprs.len()).collect();
 
         // build a translation mechanism for going from output columns to input columns
         let colfix: Vec<_> = (0..self.cols)
@@ -161,11 +176,18 @@ where
         }
 
         let group_by = &self.group_by;
+        let key_exprs = &self.key_exprs;
         let cmp = |a: &Record, b: &Record| {
             group_by
                 .iter()
                 .map(|&col| &a[col])
                 .cmp(group_by.iter().map(|&col| &b[col]))
+                .then_with(|| {
+                    key_exprs
+                        .iter()
+                        .map(|e| e.eval(a))
+                        .cmp(key_exprs.iter().map(|e| e.eval(b)))
+                })
         };
 
         // First, we want to be smart about multiple added/removed rows with same group.
@@ -191,19 +213,11 @@ where
                  mut diffs: ::std::vec::Drain<_>| {
                     let mut group_rs = group_rs.peekable();
 
-                    let mut group = Vec::with_capacity(group_by.len() + 1);
+                    let mut group = Vec::with_capacity(out_key.len() + 1);
                     {
                         let group_r = group_rs.peek().unwrap();
-                        let mut group_by_i = 0;
-                        for (col, v) in group_r.iter().enumerate() {
-                            if col == group_by[group_by_i] {
-                                group.push(v.clone());
-                                group_by_i += 1;
-                                if group_by_i == group_by.len() {
-                                    break;
-                                }
-                            }
-                        }
+                        group.extend(group_by.iter().map(|&col| group_r[col].clone()));
+                        group.extend(key_exprs.iter().map(|e| e.eval(group_r)));
                     }
 
                     let rs = {
@@ -289,23 +303,39 @@ where
     }
 
     fn resolve(&self, col: usize) -> Option<Vec<(NodeIndex, usize)>> {
-        if col == self.colfix.len() {
+        if col >= self.colfix.len() {
             return None;
         }
         Some(vec![(self.src.as_global(), self.colfix[col])])
     }
 
     fn description(&self, detailed: bool) -> String {
-        self.inner.description(detailed)
+        let description = self.inner.description(detailed);
+        if !detailed || self.key_exprs.is_empty() {
+            return description;
+        }
+
+        let exprs = self
+            .key_exprs
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("{} γ[{}]", description, exprs)
     }
 
     fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
-        if column == self.colfix.len() {
+        if column >= self.colfix.len() {
             return vec![(self.src.as_global(), None)];
         }
         vec![(self.src.as_global(), Some(self.colfix[column]))]
     }
 
+    fn requires_full_materialization(&self) -> bool {
+        // partial replays are keyed by columns of the parent, which computed keys do not have
+        !self.key_exprs.is_empty()
+    }
+
     fn is_selective(&self) -> bool {
         true
     }
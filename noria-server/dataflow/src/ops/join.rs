@@ -615,6 +615,10 @@ mod tests {
     use ops;
 
     fn setup() -> (ops::test::MockGraph, IndexPair, IndexPair) {
+        setup_kind(JoinType::Left)
+    }
+
+    fn setup_kind(kind: JoinType) -> (ops::test::MockGraph, IndexPair, IndexPair) {
         let mut g = ops::test::MockGraph::new();
         let l = g.add_base("left", &["l0", "l1"]);
         let r = g.add_base("right", &["r0", "r1"]);
@@ -623,7 +627,7 @@ mod tests {
         let j = Join::new(
             l.as_global(),
             r.as_global(),
-            JoinType::Left,
+            kind,
             vec![B(0, 0), L(1), R(1)],
         );
 
@@ -743,6 +747,17 @@ mod tests {
         assert_eq!(rs.len(), 0);
     }
 
+    #[test]
+    fn it_agrees_with_recomputation() {
+        for kind in vec![JoinType::Left, JoinType::Inner] {
+            ops::prop::Check::new(move || {
+                let (g, l, r) = setup_kind(kind.clone());
+                (g, vec![l, r])
+            })
+            .run(ops::prop::ints(vec![2, 2], 3));
+        }
+    }
+
     #[test]
     fn it_suggests_indices() {
         use std::collections::HashMap;
@@ -9,8 +9,11 @@ pub mod grouped;
 pub mod identity;
 pub mod join;
 pub mod latest;
+pub mod positional;
 pub mod project;
+pub mod recursive;
 pub mod rewrite;
+pub mod set;
 pub mod topk;
 pub mod trigger;
 pub mod union;
@@ -19,10 +22,14 @@ pub mod union;
 #[allow(clippy::large_enum_variant)]
 pub enum NodeOperator {
     Sum(grouped::GroupedOperator<grouped::aggregate::Aggregator>),
+    PartialSum(grouped::aggregate::PartialAggregator),
     Extremum(grouped::GroupedOperator<grouped::extremum::ExtremumOperator>),
     Concat(grouped::GroupedOperator<grouped::concat::GroupConcat>),
+    ApproxCountDistinct(grouped::hll::ApproxCountDistinct),
+    PartialSketch(grouped::hll::PartialSketch),
     Join(join::Join),
     Latest(latest::Latest),
+    Positional(positional::PositionalValue),
     Project(project::Project),
     Union(union::Union),
     Identity(identity::Identity),
@@ -31,6 +38,8 @@ pub enum NodeOperator {
     Trigger(trigger::Trigger),
     Rewrite(rewrite::Rewrite),
     Distinct(distinct::Distinct),
+    SetOp(set::SetOp),
+    Recursive(recursive::Recursive),
 }
 
 macro_rules! nodeop_from_impl {
@@ -47,6 +56,10 @@ nodeop_from_impl!(
     NodeOperator::Sum,
     grouped::GroupedOperator<grouped::aggregate::Aggregator>
 );
+nodeop_from_impl!(
+    NodeOperator::PartialSum,
+    grouped::aggregate::PartialAggregator
+);
 nodeop_from_impl!(
     NodeOperator::Extremum,
     grouped::GroupedOperator<grouped::extremum::ExtremumOperator>
@@ -55,8 +68,14 @@ nodeop_from_impl!(
     NodeOperator::Concat,
     grouped::GroupedOperator<grouped::concat::GroupConcat>
 );
+nodeop_from_impl!(
+    NodeOperator::ApproxCountDistinct,
+    grouped::hll::ApproxCountDistinct
+);
+nodeop_from_impl!(NodeOperator::PartialSketch, grouped::hll::PartialSketch);
 nodeop_from_impl!(NodeOperator::Join, join::Join);
 nodeop_from_impl!(NodeOperator::Latest, latest::Latest);
+nodeop_from_impl!(NodeOperator::Positional, positional::PositionalValue);
 nodeop_from_impl!(NodeOperator::Project, project::Project);
 nodeop_from_impl!(NodeOperator::Union, union::Union);
 nodeop_from_impl!(NodeOperator::Identity, identity::Identity);
@@ -65,15 +84,21 @@ nodeop_from_impl!(NodeOperator::TopK, topk::TopK);
 nodeop_from_impl!(NodeOperator::Trigger, trigger::Trigger);
 nodeop_from_impl!(NodeOperator::Rewrite, rewrite::Rewrite);
 nodeop_from_impl!(NodeOperator::Distinct, distinct::Distinct);
+nodeop_from_impl!(NodeOperator::SetOp, set::SetOp);
+nodeop_from_impl!(NodeOperator::Recursive, recursive::Recursive);
 
 macro_rules! impl_ingredient_fn_mut {
     ($self:ident, $fn:ident, $( $arg:ident ),* ) => {
         match *$self {
             NodeOperator::Sum(ref mut i) => i.$fn($($arg),*),
+            NodeOperator::PartialSum(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Extremum(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Concat(ref mut i) => i.$fn($($arg),*),
+            NodeOperator::ApproxCountDistinct(ref mut i) => i.$fn($($arg),*),
+            NodeOperator::PartialSketch(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Join(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Latest(ref mut i) => i.$fn($($arg),*),
+            NodeOperator::Positional(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Project(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Union(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Identity(ref mut i) => i.$fn($($arg),*),
@@ -82,6 +107,8 @@ macro_rules! impl_ingredient_fn_mut {
             NodeOperator::Trigger(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Rewrite(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Distinct(ref mut i) => i.$fn($($arg),*),
+            NodeOperator::SetOp(ref mut i) => i.$fn($($arg),*),
+            NodeOperator::Recursive(ref mut i) => i.$fn($($arg),*),
         }
     }
 }
@@ -90,10 +117,14 @@ macro_rules! impl_ingredient_fn_ref {
     ($self:ident, $fn:ident, $( $arg:ident ),* ) => {
         match *$self {
             NodeOperator::Sum(ref i) => i.$fn($($arg),*),
+            NodeOperator::PartialSum(ref i) => i.$fn($($arg),*),
             NodeOperator::Extremum(ref i) => i.$fn($($arg),*),
             NodeOperator::Concat(ref i) => i.$fn($($arg),*),
+            NodeOperator::ApproxCountDistinct(ref i) => i.$fn($($arg),*),
+            NodeOperator::PartialSketch(ref i) => i.$fn($($arg),*),
             NodeOperator::Join(ref i) => i.$fn($($arg),*),
             NodeOperator::Latest(ref i) => i.$fn($($arg),*),
+            NodeOperator::Positional(ref i) => i.$fn($($arg),*),
             NodeOperator::Project(ref i) => i.$fn($($arg),*),
             NodeOperator::Union(ref i) => i.$fn($($arg),*),
             NodeOperator::Identity(ref i) => i.$fn($($arg),*),
@@ -102,6 +133,8 @@ macro_rules! impl_ingredient_fn_ref {
             NodeOperator::Trigger(ref i) => i.$fn($($arg),*),
             NodeOperator::Rewrite(ref i) => i.$fn($($arg),*),
             NodeOperator::Distinct(ref i) => i.$fn($($arg),*),
+            NodeOperator::SetOp(ref i) => i.$fn($($arg),*),
+            NodeOperator::Recursive(ref i) => i.$fn($($arg),*),
         }
     }
 }
@@ -221,6 +254,9 @@ impl Ingredient for NodeOperator {
     }
 }
 
+#[cfg(test)]
+pub mod prop;
+
 #[cfg(test)]
 pub mod test {
     use std::cell;
@@ -416,6 +452,12 @@ pub mod test {
 
             impl Executor for Ex {
                 fn ack(&mut self, _: SourceChannelIdentifier) {}
+                fn reject(
+                    &mut self,
+                    _: SourceChannelIdentifier,
+                    _: Vec<(usize, ConstraintViolation)>,
+                ) {
+                }
                 fn create_universe(&mut self, _: HashMap<String, DataType>) {}
             }
 
@@ -461,6 +503,10 @@ pub mod test {
             self.narrow_one::<Record>(d.into(), remember)
         }
 
+        pub fn is_materialized(&self) -> bool {
+            self.states.contains_key(*self.nut.unwrap())
+        }
+
         pub fn node(&self) -> cell::Ref<Node> {
             self.nodes[*self.nut.unwrap()].borrow()
         }
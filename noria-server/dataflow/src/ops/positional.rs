@@ -0,0 +1,316 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use prelude::*;
+
+/// Supported kinds of positional value operators.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+pub enum Position {
+    /// The value of the `over` column in the record of each group that comes first by the `order`
+    /// column, as with `FIRST_VALUE(over ORDER BY order)`.
+    FIRST,
+    /// The value of the `over` column in the record of each group that comes last by the `order`
+    /// column, as with `LAST_VALUE(over ORDER BY order)`.
+    LAST,
+}
+
+impl Position {
+    /// Construct a new `PositionalValue` that picks the value at this position.
+    ///
+    /// The value is taken from column number `over` of the inputs (i.e., from the `src` node in the
+    /// graph), records are ordered by column number `order`, and the columns in the `group_by`
+    /// array are used as a group identifier. Records that tie on `order` are ordered by `over`.
+    pub fn of(
+        self,
+        src: NodeIndex,
+        over: usize,
+        order: usize,
+        group_by: &[usize],
+    ) -> PositionalValue {
+        assert!(
+            !group_by.iter().any(|&i| i == over || i == order),
+            "cannot group by value or ordering column"
+        );
+        let mut group_by = Vec::from(group_by);
+        group_by.sort();
+
+        PositionalValue {
+            src: src.into(),
+            us: None,
+            position: self,
+            over,
+            order,
+            out_key: (0..group_by.len()).collect(),
+            group_by,
+        }
+    }
+}
+
+/// `PositionalValue` maintains the first or last value of a column in each group, by the order of
+/// another column, such as the latest status of each entity.
+///
+/// Unlike `Latest`, records may also be removed. Whenever records for a group arrive, the value
+/// for that group is picked anew from all the records of the group in the parent, so the parent
+/// must be materialized, with an index on the group columns. The output record is the columns
+/// identifying the group, followed by the value, and groups that have no records left are
+/// removed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PositionalValue {
+    src: IndexPair,
+    us: Option<IndexPair>,
+
+    position: Position,
+    over: usize,
+    order: usize,
+    group_by: Vec<usize>,
+    out_key: Vec<usize>,
+}
+
+impl PositionalValue {
+    fn cmp(&self, a: &[DataType], b: &[DataType]) -> Ordering {
+        (&a[self.order], &a[self.over]).cmp(&(&b[self.order], &b[self.over]))
+    }
+}
+
+impl Ingredient for PositionalValue {
+    fn take(&mut self) -> NodeOperator {
+        Clone::clone(self).into()
+    }
+
+    fn ancestors(&self) -> Vec<NodeIndex> {
+        vec![self.src.as_global()]
+    }
+
+    fn on_connected(&mut self, g: &Graph) {
+        let srcn = &g[self.src.as_global()];
+        assert!(
+            self.over < srcn.fields().len() && self.order < srcn.fields().len(),
+            "cannot pick a value by non-existing columns"
+        );
+    }
+
+    fn on_commit(&mut self, us: NodeIndex, remap: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(remap);
+        self.us = Some(remap[&us]);
+    }
+
+    fn on_input(
+        &mut self,
+        _: &mut Executor,
+        from: LocalNodeIndex,
+        rs: Records,
+        _: &mut Tracer,
+        _: Option<&[usize]>,
+        nodes: &DomainNodes,
+        state: &StateMap,
+    ) -> ProcessingResult {
+        debug_assert_eq!(from, *self.src);
+
+        // the parent already reflects the records, so all we need is the groups they touch
+        let mut groups: Vec<Vec<DataType>> = rs
+            .iter()
+            .map(|r| self.group_by.iter().map(|&c| r[c].clone()).collect())
+            .collect();
+        groups.sort();
+        groups.dedup();
+
+        let us = self.us.unwrap();
+        let db = state
+            .get(*us)
+            .expect("positional operators must have their own state materialized");
+
+        let mut out = Vec::new();
+        for group in groups {
+            let key = KeyType::from(&group[..]);
+            let current = match db.lookup(&self.out_key[..], &key) {
+                LookupResult::Some(rs) => {
+                    debug_assert!(rs.len() <= 1, "a group had more than 1 result");
+                    rs.into_iter().next().map(Cow::into_owned)
+                }
+                LookupResult::Missing => unreachable!("positional operators are never partial"),
+            };
+
+            let rows = self
+                .lookup(*self.src, &self.group_by[..], &key, nodes, state)
+                .expect("positional operators must have their parent materialized")
+                .expect("positional operators must have their parent fully materialized");
+            let picked = match self.position {
+                Position::FIRST => rows.min_by(|a, b| self.cmp(a, b)),
+                Position::LAST => rows.max_by(|a, b| self.cmp(a, b)),
+            };
+            let new = picked.map(|r| {
+                let mut row = group.clone();
+                row.push(r[self.over].clone());
+                row
+            });
+
+            if current != new {
+                out.extend(current.map(Record::Negative));
+                out.extend(new.map(Record::Positive));
+            }
+        }
+
+        ProcessingResult {
+            results: out.into(),
+            ..Default::default()
+        }
+    }
+
+    fn suggest_indexes(&self, this: NodeIndex) -> HashMap<NodeIndex, Vec<usize>> {
+        vec![
+            (this, self.out_key.clone()),
+            (self.src.as_global(), self.group_by.clone()),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn resolve(&self, col: usize) -> Option<Vec<(NodeIndex, usize)>> {
+        if col == self.group_by.len() {
+            return None;
+        }
+        Some(vec![(self.src.as_global(), self.group_by[col])])
+    }
+
+    fn description(&self, detailed: bool) -> String {
+        let op = match self.position {
+            Position::FIRST => "first",
+            Position::LAST => "last",
+        };
+        if !detailed {
+            return String::from(op);
+        }
+
+        let group_cols = self
+            .group_by
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("{}({} by {}) γ[{}]", op, self.over, self.order, group_cols)
+    }
+
+    fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
+        if column == self.group_by.len() {
+            return vec![(self.src.as_global(), None)];
+        }
+        vec![(self.src.as_global(), Some(self.group_by[column]))]
+    }
+
+    fn requires_full_materialization(&self) -> bool {
+        // a group's value is picked from all of its records in the parent
+        true
+    }
+
+    fn is_selective(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ops;
+
+    fn setup(position: Position) -> (ops::test::MockGraph, IndexPair) {
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["entity", "ts", "status"]);
+        g.set_op(
+            "positional",
+            &["entity", "status"],
+            position.of(s.as_global(), 2, 1, &[0]),
+            true,
+        );
+        (g, s)
+    }
+
+    /// Feed `row` to the node under test, after applying it to the parent as the domain would.
+    fn one(g: &mut ops::test::MockGraph, s: IndexPair, row: Vec<DataType>, pos: bool) -> Records {
+        let mut rs: Records = vec![(row.clone(), pos)].into();
+        g.states.get_mut(*s).unwrap().process_records(&mut rs, None);
+        g.narrow_one_row((row, pos), true)
+    }
+
+    #[test]
+    fn it_describes() {
+        let (g, _) = setup(Position::LAST);
+        assert_eq!(g.node().description(true), "last(2 by 1) γ[0]");
+    }
+
+    #[test]
+    fn it_keeps_the_last_value() {
+        let (mut g, s) = setup(Position::LAST);
+        let row = |ts: i32, status: &str| vec![1.into(), ts.into(), status.into()];
+
+        let rs = one(&mut g, s, row(1, "new"), true);
+        assert_eq!(rs, vec![vec![1.into(), "new".into()]].into());
+
+        let rs = one(&mut g, s, row(3, "closed"), true);
+        assert_eq!(
+            rs,
+            vec![
+                (vec![1.into(), "new".into()], false),
+                (vec![1.into(), "closed".into()], true),
+            ]
+            .into()
+        );
+
+        // records that arrive late but are not the last change nothing
+        let rs = one(&mut g, s, row(2, "open"), true);
+        assert!(rs.is_empty());
+
+        // retracting the last value falls back to the one before it
+        let rs = one(&mut g, s, row(3, "closed"), false);
+        assert_eq!(
+            rs,
+            vec![
+                (vec![1.into(), "closed".into()], false),
+                (vec![1.into(), "open".into()], true),
+            ]
+            .into()
+        );
+
+        // and the group goes away once all of its records have
+        one(&mut g, s, row(1, "new"), false);
+        let rs = one(&mut g, s, row(2, "open"), false);
+        assert_eq!(rs, vec![(vec![1.into(), "open".into()], false)].into());
+    }
+
+    #[test]
+    fn it_keeps_the_first_value() {
+        let (mut g, s) = setup(Position::FIRST);
+        let row = |ts: i32, status: &str| vec![1.into(), ts.into(), status.into()];
+
+        one(&mut g, s, row(2, "open"), true);
+        let rs = one(&mut g, s, row(1, "new"), true);
+        assert_eq!(
+            rs,
+            vec![
+                (vec![1.into(), "open".into()], false),
+                (vec![1.into(), "new".into()], true),
+            ]
+            .into()
+        );
+        assert!(one(&mut g, s, row(3, "closed"), true).is_empty());
+    }
+
+    #[test]
+    fn it_suggests_indices() {
+        let me = 2.into();
+        let (g, s) = setup(Position::LAST);
+        let idx = g.node().suggest_indexes(me);
+
+        assert_eq!(idx.len(), 2);
+        assert_eq!(idx[&me], vec![0]);
+        assert_eq!(idx[&s.as_global()], vec![0]);
+    }
+
+    #[test]
+    fn it_resolves() {
+        let (g, s) = setup(Position::LAST);
+        assert_eq!(g.node().resolve(0), Some(vec![(s.as_global(), 0)]));
+        assert_eq!(g.node().resolve(1), None);
+    }
+}
@@ -1,8 +1,11 @@
 use nom_sql::ArithmeticOperator;
 
+use ring::{digest, hmac};
+
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 
 use prelude::*;
 
@@ -27,6 +30,26 @@ impl ProjectExpression {
     ) -> ProjectExpression {
         ProjectExpression { op, left, right }
     }
+
+    /// The value of this expression for `record`.
+    pub fn eval(&self, record: &[DataType]) -> DataType {
+        let left = match self.left {
+            ProjectExpressionBase::Column(i) => &record[i],
+            ProjectExpressionBase::Literal(ref data) => data,
+        };
+
+        let right = match self.right {
+            ProjectExpressionBase::Column(i) => &record[i],
+            ProjectExpressionBase::Literal(ref data) => data,
+        };
+
+        match self.op {
+            ArithmeticOperator::Add => left + right,
+            ArithmeticOperator::Subtract => left - right,
+            ArithmeticOperator::Multiply => left * right,
+            ArithmeticOperator::Divide => left / right,
+        }
+    }
 }
 
 impl fmt::Display for ProjectExpressionBase {
@@ -51,6 +74,83 @@ impl fmt::Display for ProjectExpression {
     }
 }
 
+/// Redacts the value of an output column.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ColumnMask {
+    /// Replace the value with `NULL`.
+    Null,
+    /// Replace the value with an HMAC of it under the given key, so that equal values can still be
+    /// matched up, but values cannot be recovered by hashing guesses without the key.
+    Hash(String),
+    /// Keep only the given number of leading characters of textual values.
+    Truncate(usize),
+}
+
+/// Feeds the bytes that `DataType` hashes into an HMAC, so that values that compare equal (such
+/// as `Text` and `TinyText`) are masked the same way.
+struct Keyed(hmac::SigningContext);
+
+impl Hasher for Keyed {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        unreachable!("keyed hashes are finished by signing them")
+    }
+}
+
+impl ColumnMask {
+    pub fn apply(&self, value: &DataType) -> DataType {
+        if let DataType::None = *value {
+            return DataType::None;
+        }
+
+        match *self {
+            ColumnMask::Null => DataType::None,
+            ColumnMask::Hash(ref key) => {
+                let key = hmac::SigningKey::new(&digest::SHA256, key.as_bytes());
+                let mut h = Keyed(hmac::SigningContext::with_key(&key));
+                value.hash(&mut h);
+                let tag = h.0.sign();
+                tag.as_ref()[..16]
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()
+                    .into()
+            }
+            ColumnMask::Truncate(n) => match *value {
+                DataType::Text(..) | DataType::TinyText(..) => {
+                    let text: Cow<str> = value.into();
+                    text.chars().take(n).collect::<String>().into()
+                }
+                _ => value.clone(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ColumnMask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ColumnMask::Null => write!(f, "null"),
+            ColumnMask::Hash(_) => write!(f, "hash"),
+            ColumnMask::Truncate(n) => write!(f, "truncate({})", n),
+        }
+    }
+}
+
+// the key of a hash mask is left out, so that it does not end up in logs
+impl fmt::Debug for ColumnMask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ColumnMask::Null => write!(f, "Null"),
+            ColumnMask::Hash(_) => write!(f, "Hash(..)"),
+            ColumnMask::Truncate(n) => write!(f, "Truncate({})", n),
+        }
+    }
+}
+
 /// Permutes or omits columns from its source node, or adds additional literal value columns.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Project {
@@ -58,6 +158,7 @@ pub struct Project {
     emit: Option<Vec<usize>>,
     additional: Option<Vec<DataType>>,
     expressions: Option<Vec<ProjectExpression>>,
+    masks: Option<Vec<(usize, ColumnMask)>>,
     src: IndexPair,
     cols: usize,
 }
@@ -74,12 +175,28 @@ impl Project {
             emit: Some(emit.into()),
             additional,
             expressions,
+            masks: None,
             src: src.into(),
             cols: 0,
             us: None,
         }
     }
 
+    /// Redact the given output columns before they are emitted.
+    ///
+    /// Masked columns no longer resolve to their source column, so they should not be used as
+    /// keys downstream.
+    pub fn with_masks(mut self, masks: Vec<(usize, ColumnMask)>) -> Project {
+        self.masks = if masks.is_empty() { None } else { Some(masks) };
+        self
+    }
+
+    fn is_masked(&self, col: usize) -> bool {
+        self.masks
+            .as_ref()
+            .map_or(false, |masks| masks.iter().any(|&(c, _)| c == col))
+    }
+
     fn resolve_col(&self, col: usize) -> usize {
         if self.emit.is_some() && col >= self.emit.as_ref().unwrap().len() {
             panic!(
@@ -100,22 +217,10 @@ impl Project {
     }
 }
 
-fn eval_expression(expression: &ProjectExpression, record: &[DataType]) -> DataType {
-    let left = match expression.left {
-        ProjectExpressionBase::Column(i) => &record[i],
-        ProjectExpressionBase::Literal(ref data) => data,
-    };
-
-    let right = match expression.right {
-        ProjectExpressionBase::Column(i) => &record[i],
-        ProjectExpressionBase::Literal(ref data) => data,
-    };
-
-    match expression.op {
-        ArithmeticOperator::Add => left + right,
-        ArithmeticOperator::Subtract => left - right,
-        ArithmeticOperator::Multiply => left * right,
-        ArithmeticOperator::Divide => left / right,
+/// Redact the given columns of `record`.
+crate fn apply_masks(masks: &[(usize, ColumnMask)], record: &mut [DataType]) {
+    for &(col, ref mask) in masks {
+        record[col] = mask.apply(&record[col]);
     }
 }
 
@@ -143,6 +248,7 @@ impl Ingredient for Project {
         let emit = self.emit.clone();
         let additional = self.additional.clone();
         let expressions = self.expressions.clone();
+        let masks = self.masks.clone();
 
         // translate output columns to input columns
         let mut in_cols = Cow::Borrowed(columns);
@@ -168,7 +274,7 @@ impl Ingredient for Project {
                         Some(emit) => Box::new(rs.map(move |r| {
                             let mut new_r = Vec::with_capacity(r.len());
                             let mut expr: Vec<DataType> = if let Some(ref e) = expressions {
-                                e.iter().map(|i| eval_expression(i, &r[..])).collect()
+                                e.iter().map(|i| i.eval(&r[..])).collect()
                             } else {
                                 vec![]
                             };
@@ -190,6 +296,14 @@ impl Ingredient for Project {
                         })) as Box<_>,
                         None => Box::new(rs) as Box<_>,
                     };
+                    let r = match masks {
+                        Some(masks) => Box::new(r.map(move |r| {
+                            let mut r = r.into_owned();
+                            apply_masks(&masks[..], &mut r[..]);
+                            Cow::from(r)
+                        })) as Box<_>,
+                        None => r,
+                    };
 
                     Some(Some(r))
                 }
@@ -240,7 +354,7 @@ impl Ingredient for Project {
                 }
 
                 if let Some(ref e) = self.expressions {
-                    new_r.extend(e.iter().map(|i| eval_expression(i, &r[..])));
+                    new_r.extend(e.iter().map(|i| i.eval(&r[..])));
                 }
 
                 if let Some(ref a) = self.additional {
@@ -251,6 +365,12 @@ impl Ingredient for Project {
             }
         }
 
+        if let Some(ref masks) = self.masks {
+            for r in &mut *rs {
+                apply_masks(&masks[..], &mut r[..]);
+            }
+        }
+
         ProcessingResult {
             results: rs,
             ..Default::default()
@@ -262,6 +382,9 @@ impl Ingredient for Project {
     }
 
     fn resolve(&self, col: usize) -> Option<Vec<(NodeIndex, usize)>> {
+        if self.is_masked(col) {
+            return None;
+        }
         Some(vec![(self.src.as_global(), self.resolve_col(col))])
     }
 
@@ -294,11 +417,16 @@ impl Ingredient for Project {
                 }
             }
         };
+        if let Some(ref masks) = self.masks {
+            emit_cols.extend(masks.iter().map(|&(c, ref m)| format!("{}: {}", c, m)));
+        }
         format!("π[{}]", emit_cols.join(", "))
     }
 
     fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
-        let result = if self.emit.is_some() && column >= self.emit.as_ref().unwrap().len() {
+        let result = if self.is_masked(column)
+            || self.emit.is_some() && column >= self.emit.as_ref().unwrap().len()
+        {
             None
         } else {
             Some(self.resolve_col(column))
@@ -650,6 +778,44 @@ mod tests {
         assert_query_through(p, 0, 2.into(), states, expected);
     }
 
+    #[test]
+    fn it_masks() {
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["x", "y", "z"]);
+        g.set_op(
+            "mask",
+            &["x", "y", "z"],
+            Project::new(s.as_global(), &[0, 1, 2], None, None).with_masks(vec![
+                (0, ColumnMask::Null),
+                (1, ColumnMask::Hash("key".to_owned())),
+                (2, ColumnMask::Truncate(2)),
+            ]),
+            false,
+        );
+        assert_eq!(
+            g.node().description(true),
+            "π[*, 0: null, 1: hash, 2: truncate(2)]"
+        );
+
+        let rec = vec!["a".into(), "b".into(), "cde".into()];
+        let out = g.narrow_one_row(rec.clone(), false);
+        let other = g.narrow_one_row(rec, false);
+        assert_eq!(out, other);
+
+        let out = out.into_iter().next().unwrap();
+        assert_eq!(out[0], DataType::None);
+        assert_ne!(out[1], "b".into());
+        assert_eq!(out[2], "cd".into());
+
+        // hashes depend on the key
+        let other = ColumnMask::Hash("other".to_owned());
+        assert_ne!(other.apply(&"b".into()), out[1]);
+
+        // masked columns aren't the source column anymore
+        assert_eq!(g.node().resolve(0), None);
+        assert_eq!(g.node().resolve(2), None);
+    }
+
     #[test]
     fn it_suggests_indices() {
         let me = 1.into();
@@ -0,0 +1,161 @@
+//! Property-based testing of operators.
+//!
+//! A [`Check`] feeds random batches of inserts and deletes through an operator set up in a
+//! [`MockGraph`]. After every batch, it checks that the deltas the operator has emitted so far add
+//! up to what a fresh instance of the operator produces when given the current contents of its
+//! base tables all at once.
+
+use std::collections::HashMap;
+
+use ops::test::MockGraph;
+use prelude::*;
+
+use rand::rngs::StdRng;
+use rand::{self, Rng, SeedableRng};
+
+/// A multiset of rows.
+type Bag = HashMap<Vec<DataType>, isize>;
+
+fn add(bag: &mut Bag, rs: Records) {
+    for r in rs {
+        let (r, positive) = r.extract();
+        *bag.entry(r).or_insert(0) += if positive { 1 } else { -1 };
+    }
+    bag.retain(|_, n| *n != 0);
+}
+
+/// Rows whose `i`th base table has `columns[i]` columns, each holding an integer in `0..max`.
+///
+/// Keeping `max` small makes it likely that rows share join keys and groups.
+pub fn ints(columns: Vec<usize>, max: i32) -> impl FnMut(usize, &mut StdRng) -> Vec<DataType> {
+    move |base, rng| {
+        (0..columns[base])
+            .map(|_| rng.gen_range(0, max).into())
+            .collect()
+    }
+}
+
+/// A randomized check of an operator.
+pub struct Check<F> {
+    setup: F,
+    runs: usize,
+    steps: usize,
+    deletes: bool,
+    ignore: Option<Box<Fn(&[DataType]) -> bool>>,
+}
+
+impl<F> Check<F>
+where
+    F: Fn() -> (MockGraph, Vec<IndexPair>),
+{
+    /// Check the operator that `setup` creates, given the base tables that it should be fed from.
+    pub fn new(setup: F) -> Self {
+        Check {
+            setup,
+            runs: 50,
+            steps: 20,
+            deletes: true,
+            ignore: None,
+        }
+    }
+
+    /// Only insert rows, for operators that do not support arbitrary deletions.
+    pub fn without_deletes(mut self) -> Self {
+        self.deletes = false;
+        self
+    }
+
+    /// Leave rows for which `ignore` returns true out of the comparison, for operators that
+    /// deliberately emit rows that recomputation would not produce.
+    pub fn ignore<P>(mut self, ignore: P) -> Self
+    where
+        P: Fn(&[DataType]) -> bool + 'static,
+    {
+        self.ignore = Some(Box::new(ignore));
+        self
+    }
+
+    /// Run the check, generating rows for the `i`th base table with `rows(i, rng)`.
+    ///
+    /// Panics with the seed of the failing run, and the batches it issued, if the operator's
+    /// output diverges.
+    pub fn run<G>(&self, mut rows: G)
+    where
+        G: FnMut(usize, &mut StdRng) -> Vec<DataType>,
+    {
+        for _ in 0..self.runs {
+            let seed: u64 = rand::thread_rng().gen();
+            self.run_seeded(seed, &mut rows);
+        }
+    }
+
+    /// Run the check once, with random choices made by an RNG seeded with `seed`.
+    pub fn run_seeded<G>(&self, seed: u64, rows: &mut G)
+    where
+        G: FnMut(usize, &mut StdRng) -> Vec<DataType>,
+    {
+        let mut s = [0u8; 32];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = (seed >> (8 * (i % 8))) as u8;
+        }
+        let mut rng = StdRng::from_seed(s);
+
+        let (mut g, bases) = (self.setup)();
+        let remember = g.is_materialized();
+        let mut contents = vec![Vec::new(); bases.len()];
+        let mut output = Bag::new();
+        let mut batches = Vec::new();
+        for _ in 0..self.steps {
+            let b = rng.gen_range(0, bases.len());
+            let mut batch = Vec::new();
+            for _ in 0..rng.gen_range(1, 4) {
+                if self.deletes && !contents[b].is_empty() && rng.gen_bool(0.3) {
+                    let i = rng.gen_range(0, contents[b].len());
+                    batch.push(Record::Negative(contents[b].swap_remove(i)));
+                } else {
+                    let r = rows(b, &mut rng);
+                    contents[b].push(r.clone());
+                    batch.push(Record::Positive(r));
+                }
+            }
+            batches.push((b, batch.clone()));
+
+            // bases absorb writes before forwarding them, and operators rely on that when they
+            // look into their parents' state.
+            if let Some(state) = g.states.get_mut(*bases[b]) {
+                state.process_records(&mut batch.clone().into(), None);
+            }
+            add(&mut output, g.one(bases[b], batch, remember));
+
+            let mut expected = self.recompute(&contents);
+            let mut actual = output.clone();
+            if let Some(ref ignore) = self.ignore {
+                expected.retain(|r, _| !ignore(r));
+                actual.retain(|r, _| !ignore(r));
+            }
+            assert_eq!(
+                actual, expected,
+                "operator output diverged from recomputation (seed {}) after {:?}",
+                seed, batches
+            );
+        }
+    }
+
+    /// The result of feeding `contents` through a fresh instance of the operator.
+    fn recompute(&self, contents: &[Vec<Vec<DataType>>]) -> Bag {
+        let (mut g, bases) = (self.setup)();
+        let remember = g.is_materialized();
+        let mut result = Bag::new();
+        for (&base, rows) in bases.iter().zip(contents) {
+            if rows.is_empty() {
+                continue;
+            }
+            let rs: Records = rows.clone().into();
+            if let Some(state) = g.states.get_mut(*base) {
+                state.process_records(&mut rs.clone(), None);
+            }
+            add(&mut result, g.one(base, rs, remember));
+        }
+        result
+    }
+}
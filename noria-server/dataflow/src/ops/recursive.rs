@@ -0,0 +1,305 @@
+use std::collections::{HashMap, HashSet};
+
+use prelude::*;
+
+/// An operator that follows the edges its parent holds for up to `depth` steps.
+///
+/// Each row of the parent is an edge from its `from` column to its `to` column. For every node
+/// that edges start from, the operator emits a row `(from, to, steps)` for each node it reaches in
+/// at most `depth` steps, along with the fewest steps it takes to get there. This is the fixpoint
+/// that a bounded `WITH RECURSIVE` query reaches by feeding the paths it has found back in, one
+/// edge longer each time. When edges change, what every node whose paths may run through them
+/// reaches is worked out anew, and only the rows that differ are emitted. The edges are kept by the
+/// operator and rebuilt by the full replay that fills its state, so it must be fully materialized.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Recursive {
+    src: IndexPair,
+    from: usize,
+    to: usize,
+    depth: usize,
+
+    /// How many copies of each edge there are, by the node it starts from.
+    #[serde(skip)]
+    edges: HashMap<DataType, HashMap<DataType, usize>>,
+    /// The nodes that each node has edges from.
+    #[serde(skip)]
+    reverse: HashMap<DataType, HashSet<DataType>>,
+    /// The nodes that each node reaches, with the fewest steps it takes.
+    #[serde(skip)]
+    reached: HashMap<DataType, HashMap<DataType, usize>>,
+}
+
+impl Recursive {
+    /// Construct a new operator that follows the edges from column `from` to column `to` of
+    /// `src` for up to `depth` steps.
+    pub fn new(src: NodeIndex, from: usize, to: usize, depth: usize) -> Self {
+        assert!(depth > 0);
+        Recursive {
+            src: src.into(),
+            from,
+            to,
+            depth,
+            edges: HashMap::new(),
+            reverse: HashMap::new(),
+            reached: HashMap::new(),
+        }
+    }
+
+    /// The nodes that `start` reaches, with the fewest steps it takes.
+    fn reach(&self, start: &DataType) -> HashMap<DataType, usize> {
+        let mut reached = HashMap::new();
+        let mut frontier = vec![start.clone()];
+        for steps in 1..=self.depth {
+            let mut next = Vec::new();
+            for node in &frontier {
+                for to in self.edges.get(node).into_iter().flat_map(HashMap::keys) {
+                    if !reached.contains_key(to) {
+                        reached.insert(to.clone(), steps);
+                        next.push(to.clone());
+                    }
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            frontier = next;
+        }
+        reached
+    }
+
+    /// Add `delta` copies of the edge from `from` to `to`, and return whether the edge appeared
+    /// or disappeared.
+    fn add_edge(&mut self, from: &DataType, to: &DataType, delta: isize) -> bool {
+        let tos = self.edges.entry(from.clone()).or_insert_with(HashMap::new);
+        let before = tos.get(to).cloned().unwrap_or(0);
+        let after = (before as isize + delta).max(0) as usize;
+        if after == 0 {
+            tos.remove(to);
+            if tos.is_empty() {
+                self.edges.remove(from);
+            }
+        } else {
+            tos.insert(to.clone(), after);
+        }
+
+        if (before == 0) == (after == 0) {
+            return false;
+        }
+        if after == 0 {
+            let froms = self.reverse.get_mut(to).unwrap();
+            froms.remove(from);
+            if froms.is_empty() {
+                self.reverse.remove(to);
+            }
+        } else {
+            self.reverse
+                .entry(to.clone())
+                .or_insert_with(HashSet::new)
+                .insert(from.clone());
+        }
+        true
+    }
+}
+
+impl Ingredient for Recursive {
+    fn take(&mut self) -> NodeOperator {
+        Clone::clone(self).into()
+    }
+
+    fn ancestors(&self) -> Vec<NodeIndex> {
+        vec![self.src.as_global()]
+    }
+
+    fn on_connected(&mut self, g: &Graph) {
+        let fields = g[self.src.as_global()].fields().len();
+        assert!(self.from < fields && self.to < fields);
+    }
+
+    fn on_commit(&mut self, _: NodeIndex, remap: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(remap);
+    }
+
+    fn on_input(
+        &mut self,
+        _: &mut Executor,
+        from: LocalNodeIndex,
+        rs: Records,
+        _: &mut Tracer,
+        _: Option<&[usize]>,
+        _: &DomainNodes,
+        _: &StateMap,
+    ) -> ProcessingResult {
+        debug_assert_eq!(from, *self.src);
+
+        // the nodes that changed edges start from
+        let mut changed = HashSet::new();
+        for r in rs {
+            let (edge_from, edge_to) = (r[self.from].clone(), r[self.to].clone());
+            let delta = if r.is_positive() { 1 } else { -1 };
+            if self.add_edge(&edge_from, &edge_to, delta) {
+                changed.insert(edge_from);
+            }
+        }
+        if changed.is_empty() {
+            return ProcessingResult::default();
+        }
+
+        // the paths of a node may run through a changed edge if the node reached where the edge
+        // starts with steps to spare before the change, or does so after it
+        let mut affected: HashSet<_> = self
+            .reached
+            .iter()
+            .filter(|&(_, reached)| {
+                changed
+                    .iter()
+                    .any(|n| reached.get(n).map(|&s| s < self.depth).unwrap_or(false))
+            })
+            .map(|(n, _)| n.clone())
+            .collect();
+        let mut seen = HashSet::new();
+        let mut frontier: Vec<_> = changed.into_iter().collect();
+        for steps in 0..self.depth {
+            let mut next = Vec::new();
+            for node in frontier {
+                if seen.insert(node.clone()) && steps + 1 < self.depth {
+                    next.extend(self.reverse.get(&node).into_iter().flatten().cloned());
+                }
+            }
+            frontier = next;
+        }
+        affected.extend(seen);
+
+        let mut affected: Vec<_> = affected.into_iter().collect();
+        affected.sort();
+        let mut out = Vec::new();
+        for start in affected {
+            let before = self.reached.remove(&start).unwrap_or_default();
+            let after = self.reach(&start);
+
+            let mut gone: Vec<_> = before
+                .iter()
+                .filter(|&(to, steps)| after.get(to) != Some(steps))
+                .collect();
+            gone.sort();
+            let mut new: Vec<_> = after
+                .iter()
+                .filter(|&(to, steps)| before.get(to) != Some(steps))
+                .collect();
+            new.sort();
+            for (positive, rows) in vec![(false, gone), (true, new)] {
+                for (to, &steps) in rows {
+                    out.push((vec![start.clone(), to.clone(), steps.into()], positive));
+                }
+            }
+
+            if !after.is_empty() {
+                self.reached.insert(start, after);
+            }
+        }
+
+        ProcessingResult {
+            results: out.into(),
+            ..Default::default()
+        }
+    }
+
+    fn suggest_indexes(&self, this: NodeIndex) -> HashMap<NodeIndex, Vec<usize>> {
+        // the operator has to be materialized so that other views are not replayed through it
+        vec![(this, vec![0])].into_iter().collect()
+    }
+
+    fn resolve(&self, _: usize) -> Option<Vec<(NodeIndex, usize)>> {
+        None
+    }
+
+    fn description(&self, _: bool) -> String {
+        format!("↻ [{} → {}, ≤ {}]", self.from, self.to, self.depth)
+    }
+
+    fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
+        let parent = match column {
+            0 => Some(self.from),
+            1 => Some(self.to),
+            _ => None,
+        };
+        vec![(self.src.as_global(), parent)]
+    }
+
+    fn requires_full_materialization(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ops;
+
+    fn setup(depth: usize) -> ops::test::MockGraph {
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("edges", &["from", "to"]);
+        g.set_op(
+            "reach",
+            &["from", "to", "steps"],
+            Recursive::new(s.as_global(), 0, 1, depth),
+            true,
+        );
+        g
+    }
+
+    fn edge(from: i32, to: i32) -> Vec<DataType> {
+        vec![from.into(), to.into()]
+    }
+
+    fn reached(from: i32, to: i32, steps: usize) -> Vec<DataType> {
+        vec![from.into(), to.into(), steps.into()]
+    }
+
+    #[test]
+    fn it_follows_edges_up_to_depth() {
+        let mut g = setup(2);
+        assert_eq!(
+            g.narrow_one_row(edge(1, 2), true),
+            vec![reached(1, 2, 1)].into()
+        );
+        assert_eq!(
+            g.narrow_one_row(edge(2, 3), true),
+            vec![reached(1, 3, 2), reached(2, 3, 1)].into()
+        );
+        // 1 would take three steps to reach 4
+        assert_eq!(
+            g.narrow_one_row(edge(3, 4), true),
+            vec![reached(2, 4, 2), reached(3, 4, 1)].into()
+        );
+        // a cycle leads back to where it starts
+        assert_eq!(
+            g.narrow_one_row(edge(2, 1), true),
+            vec![reached(1, 1, 2), reached(2, 1, 1), reached(2, 2, 2)].into()
+        );
+    }
+
+    #[test]
+    fn it_maintains_reachability_as_edges_change() {
+        let mut g = setup(3);
+        g.narrow_one(vec![edge(1, 2), edge(2, 3)], true);
+
+        // a shortcut makes 3 one step away from 1
+        assert_eq!(
+            g.narrow_one_row(edge(1, 3), true),
+            vec![(reached(1, 3, 2), false), (reached(1, 3, 1), true)].into()
+        );
+        // a second copy of an edge changes nothing, and neither does removing it again
+        assert!(g.narrow_one_row(edge(1, 3), true).is_empty());
+        assert!(g.narrow_one_row((edge(1, 3), false), true).is_empty());
+        // without the first edge, 1 only reaches 3
+        assert_eq!(
+            g.narrow_one_row((edge(1, 2), false), true),
+            vec![(reached(1, 2, 1), false)].into()
+        );
+        assert_eq!(
+            g.narrow_one_row((edge(1, 3), false), true),
+            vec![(reached(1, 3, 1), false)].into()
+        );
+    }
+}
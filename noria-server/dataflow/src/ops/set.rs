@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use prelude::*;
+
+/// Which rows a set operator keeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SetOpKind {
+    /// The rows that are in both inputs.
+    Intersect,
+    /// The rows that are in the first input but not in the second.
+    Except,
+}
+
+/// An operator that computes the INTERSECT or EXCEPT of two inputs.
+///
+/// The two inputs reach the operator through a single parent, usually a union, whose column
+/// `side` is 0 for the rows of the first input and 1 for those of the second. The operator counts
+/// how many copies of each row either input holds, so that duplicates and retractions are
+/// accounted for, and emits each row once, without the `side` column, for as long as the counts
+/// put it in the result. The counts are rebuilt by the full replay that fills the operator's
+/// state, so it must be fully materialized.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SetOp {
+    src: IndexPair,
+    kind: SetOpKind,
+    side: usize,
+
+    #[serde(skip)]
+    counts: HashMap<Vec<DataType>, [i64; 2]>,
+}
+
+impl SetOp {
+    /// Construct a new set operator over `src`, whose last column, `side`, tells the inputs apart.
+    pub fn new(src: NodeIndex, kind: SetOpKind, side: usize) -> Self {
+        SetOp {
+            src: src.into(),
+            kind,
+            side,
+            counts: HashMap::new(),
+        }
+    }
+
+    fn keeps(&self, counts: [i64; 2]) -> bool {
+        match self.kind {
+            SetOpKind::Intersect => counts[0] > 0 && counts[1] > 0,
+            SetOpKind::Except => counts[0] > 0 && counts[1] == 0,
+        }
+    }
+}
+
+impl Ingredient for SetOp {
+    fn take(&mut self) -> NodeOperator {
+        Clone::clone(self).into()
+    }
+
+    fn ancestors(&self) -> Vec<NodeIndex> {
+        vec![self.src.as_global()]
+    }
+
+    fn on_connected(&mut self, g: &Graph) {
+        assert_eq!(self.side, g[self.src.as_global()].fields().len() - 1);
+    }
+
+    fn on_commit(&mut self, _: NodeIndex, remap: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(remap);
+    }
+
+    fn on_input(
+        &mut self,
+        _: &mut Executor,
+        from: LocalNodeIndex,
+        rs: Records,
+        _: &mut Tracer,
+        _: Option<&[usize]>,
+        _: &DomainNodes,
+        _: &StateMap,
+    ) -> ProcessingResult {
+        debug_assert_eq!(from, *self.src);
+
+        // net change to the counts of each row, in the order the rows first showed up
+        let mut order = Vec::new();
+        let mut deltas: HashMap<Vec<DataType>, [i64; 2]> = HashMap::new();
+        for r in rs {
+            let (mut row, positive) = r.extract();
+            let side = if row.remove(self.side) == DataType::from(0) {
+                0
+            } else {
+                1
+            };
+            let delta = deltas.entry(row.clone()).or_insert_with(|| {
+                order.push(row);
+                [0, 0]
+            });
+            delta[side] += if positive { 1 } else { -1 };
+        }
+
+        let mut out = Vec::new();
+        for row in order {
+            let delta = deltas[&row];
+            let before = self.counts.get(&row).cloned().unwrap_or([0, 0]);
+            let after = [before[0] + delta[0], before[1] + delta[1]];
+            let (was, is) = (self.keeps(before), self.keeps(after));
+            if after == [0, 0] {
+                self.counts.remove(&row);
+            } else {
+                self.counts.insert(row.clone(), after);
+            }
+            if was != is {
+                out.push((row, is));
+            }
+        }
+
+        ProcessingResult {
+            results: out.into(),
+            ..Default::default()
+        }
+    }
+
+    fn suggest_indexes(&self, this: NodeIndex) -> HashMap<NodeIndex, Vec<usize>> {
+        // the operator has to be materialized so that other views are not replayed through it
+        vec![(this, vec![0])].into_iter().collect()
+    }
+
+    fn resolve(&self, _: usize) -> Option<Vec<(NodeIndex, usize)>> {
+        None
+    }
+
+    fn description(&self, _: bool) -> String {
+        match self.kind {
+            SetOpKind::Intersect => "∩".into(),
+            SetOpKind::Except => "∖".into(),
+        }
+    }
+
+    fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
+        vec![(self.src.as_global(), Some(column))]
+    }
+
+    fn requires_full_materialization(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ops;
+
+    fn setup(kind: SetOpKind) -> ops::test::MockGraph {
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["x", "y", "side"]);
+        g.set_op("set", &["x", "y"], SetOp::new(s.as_global(), kind, 2), true);
+        g
+    }
+
+    fn row(x: i32, side: i32) -> Vec<DataType> {
+        vec![x.into(), "a".into(), side.into()]
+    }
+
+    #[test]
+    fn it_intersects() {
+        let mut g = setup(SetOpKind::Intersect);
+        let out = |x: i32| vec![DataType::from(x), "a".into()];
+
+        assert!(g.narrow_one_row(row(1, 0), true).is_empty());
+        assert_eq!(g.narrow_one_row(row(1, 1), true), vec![out(1)].into());
+        // more copies on either side change nothing
+        assert!(g.narrow_one_row(row(1, 0), true).is_empty());
+        assert!(g.narrow_one_row((row(1, 0), false), true).is_empty());
+        assert_eq!(
+            g.narrow_one_row((row(1, 0), false), true),
+            vec![(out(1), false)].into()
+        );
+        // a row that shows up on both sides in one batch
+        assert_eq!(
+            g.narrow_one(vec![(row(2, 1), true), (row(2, 0), true)], true),
+            vec![out(2)].into()
+        );
+    }
+
+    #[test]
+    fn it_subtracts() {
+        let mut g = setup(SetOpKind::Except);
+        let out = |x: i32| vec![DataType::from(x), "a".into()];
+
+        assert_eq!(g.narrow_one_row(row(1, 0), true), vec![out(1)].into());
+        assert!(g.narrow_one_row(row(1, 0), true).is_empty());
+        assert_eq!(
+            g.narrow_one_row(row(1, 1), true),
+            vec![(out(1), false)].into()
+        );
+        assert_eq!(
+            g.narrow_one_row((row(1, 1), false), true),
+            vec![out(1)].into()
+        );
+        assert!(g.narrow_one_row(row(2, 1), true).is_empty());
+        // a retraction and an insertion that cancel out
+        assert!(g
+            .narrow_one(vec![(row(1, 0), false), (row(1, 0), true)], true)
+            .is_empty());
+    }
+}
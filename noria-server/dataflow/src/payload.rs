@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 #[cfg(debug_assertions)]
 use backtrace::Backtrace;
 use domain;
+use nom_sql::SqlType;
 use node;
 use noria;
 use noria::channel;
@@ -13,6 +14,7 @@ use prelude::*;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::net::SocketAddr;
+use std::ops::Range;
 use std::time;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -71,6 +73,21 @@ pub enum ReplayPieceContext {
     },
 }
 
+/// What a barrier marks in the stream of writes to the base tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BarrierKind {
+    /// The point at which a snapshot was taken.
+    Snapshot,
+    /// The start of a write group.
+    GroupStart,
+    /// The end of a write group.
+    GroupEnd,
+    /// The end of a write group that is to be dropped, since some of its writes were refused.
+    GroupAbort,
+    /// The point at which readers that expect the barrier keep a copy of what they hold.
+    Export,
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct SourceChannelIdentifier {
     pub token: usize,
@@ -85,7 +102,9 @@ pub enum Packet {
     Input {
         inner: LocalOrNot<Input>,
         src: Option<SourceChannelIdentifier>,
-        senders: Vec<SourceChannelIdentifier>,
+        /// The clients whose writes were merged into this one, and which of the operations each
+        /// of them sent.
+        senders: Vec<(SourceChannelIdentifier, Range<usize>)>,
     },
 
     /// Regular data-flow update.
@@ -93,6 +112,8 @@ pub enum Packet {
         link: Link,
         data: Records,
         tracer: Tracer,
+        /// The base table the update started out at, and when that base table processed it.
+        origin: Option<(NodeIndex, time::SystemTime)>,
     },
 
     /// Update that is part of a tagged data-flow replay path.
@@ -117,6 +138,26 @@ pub enum Packet {
         keys: Vec<Vec<DataType>>,
     },
 
+    /// A latency probe, which is forwarded to every reader below the node it is sent to.
+    Probe {
+        link: Link,
+        id: u64,
+        /// When the probe was injected, according to the controller's clock.
+        sent: time::SystemTime,
+    },
+
+    /// A barrier, which is forwarded to every reader below the node it is sent to.
+    ///
+    /// Every write a base table processed before the barrier has reached a reader once the
+    /// barrier has reached it along every path from the base tables.
+    Barrier {
+        link: Link,
+        id: u64,
+        kind: BarrierKind,
+        /// How many copies of the barrier each shard of each reader will receive.
+        expected: HashMap<NodeIndex, usize>,
+    },
+
     //
     // Internal control
     //
@@ -148,6 +189,33 @@ pub enum Packet {
         column: usize,
     },
 
+    /// Rename a column of an existing node, after the base column it passes on was renamed.
+    RenameColumn {
+        node: LocalNodeIndex,
+        column: usize,
+        name: String,
+    },
+
+    /// Change the type of a column of an existing `Base` node, converting the rows it has.
+    AlterColumnType {
+        node: LocalNodeIndex,
+        column: usize,
+        ty: SqlType,
+        cast: node::special::Cast,
+    },
+
+    /// Rename an existing `Base` node, moving any state it has persisted along with it.
+    RenameBase {
+        node: LocalNodeIndex,
+        name: String,
+    },
+
+    /// Stop an Egress node from sending to the ingress node `target`, which has been removed.
+    RemoveEgressTx {
+        node: LocalNodeIndex,
+        target: NodeIndex,
+    },
+
     /// Update Egress node.
     UpdateEgress {
         node: LocalNodeIndex,
@@ -230,6 +298,34 @@ pub enum Packet {
 
     /// Ask domain to log its state size
     UpdateStateSize,
+
+    /// Ask domain to write the packets it last handled to disk, and ack on the control reply
+    /// channel when it has.
+    DumpCapture,
+
+    /// Change how a domain limits and batches the replays it asks for, and ack on the control
+    /// reply channel when it has.
+    UpdateConfig {
+        concurrent_replays: Option<usize>,
+        replay_batch_timeout: Option<time::Duration>,
+    },
+
+    /// Change how reluctant a domain is to evict from a node when it is told to evict without
+    /// being told from where, and ack on the control reply channel when it has.
+    ///
+    /// A node with weight `w` is evicted from as if it held `1/w` of the state it does.
+    UpdateEvictionWeight {
+        node: LocalNodeIndex,
+        weight: u32,
+    },
+
+    /// Have a reader hold on to the writes it applies for up to the given time before it makes
+    /// them visible, or make them visible right away if there is none, and ack on the control
+    /// reply channel when it has.
+    UpdatePublishInterval {
+        node: LocalNodeIndex,
+        every: Option<time::Duration>,
+    },
 }
 
 impl Packet {
@@ -241,6 +337,7 @@ impl Packet {
             }
             Packet::Message { ref link, .. } => link.src,
             Packet::ReplayPiece { ref link, .. } => link.src,
+            Packet::Probe { ref link, .. } | Packet::Barrier { ref link, .. } => link.src,
             _ => unreachable!(),
         }
     }
@@ -250,6 +347,7 @@ impl Packet {
             Packet::Input { ref inner, .. } => unsafe { inner.deref() }.dst,
             Packet::Message { ref link, .. } => link.dst,
             Packet::ReplayPiece { ref link, .. } => link.dst,
+            Packet::Probe { ref link, .. } | Packet::Barrier { ref link, .. } => link.dst,
             _ => unreachable!(),
         }
     }
@@ -259,6 +357,7 @@ impl Packet {
             Packet::Message { ref mut link, .. } => link,
             Packet::ReplayPiece { ref mut link, .. } => link,
             Packet::EvictKeys { ref mut link, .. } => link,
+            Packet::Probe { ref mut link, .. } | Packet::Barrier { ref mut link, .. } => link,
             _ => unreachable!(),
         }
     }
@@ -318,16 +417,20 @@ impl Packet {
         mem::replace(inner, Records::default())
     }
 
-    crate fn clone_data(&self) -> Self {
+    /// Copy a data-carrying packet, such as a `Message` or a `ReplayPiece`, or a `Probe` or a
+    /// `Barrier`.
+    pub fn clone_data(&self) -> Self {
         match *self {
             Packet::Message {
                 link,
                 ref data,
                 ref tracer,
+                origin,
             } => Packet::Message {
                 link,
                 data: data.clone(),
                 tracer: tracer.clone(),
+                origin,
             },
             Packet::ReplayPiece {
                 link,
@@ -340,6 +443,18 @@ impl Packet {
                 data: data.clone(),
                 context: context.clone(),
             },
+            Packet::Probe { link, id, sent } => Packet::Probe { link, id, sent },
+            Packet::Barrier {
+                link,
+                id,
+                kind,
+                ref expected,
+            } => Packet::Barrier {
+                link,
+                id,
+                kind,
+                expected: expected.clone(),
+            },
             _ => unreachable!(),
         }
     }
@@ -360,6 +475,14 @@ impl Packet {
         }
     }
 
+    /// The base table a regular update started out at, and when that base table processed it.
+    crate fn origin(&self) -> Option<(NodeIndex, time::SystemTime)> {
+        match *self {
+            Packet::Message { origin, .. } => origin,
+            _ => None,
+        }
+    }
+
     crate fn tracer(&mut self) -> Option<&mut Tracer> {
         match *self {
             Packet::Message { ref mut tracer, .. } => Some(tracer),
@@ -373,6 +496,10 @@ impl fmt::Debug for Packet {
         match *self {
             Packet::Input { .. } => write!(f, "Packet::Input"),
             Packet::Message { ref link, .. } => write!(f, "Packet::Message({:?})", link),
+            Packet::Probe { ref link, id, .. } => write!(f, "Packet::Probe({:?}, {})", link, id),
+            Packet::Barrier {
+                ref link, id, kind, ..
+            } => write!(f, "Packet::Barrier({:?}, {:?} {})", link, kind, id),
             Packet::RequestReaderReplay { ref key, .. } => {
                 write!(f, "Packet::RequestReaderReplay({:?})", key)
             }
@@ -14,11 +14,16 @@ crate type Edge = ();
 
 // dataflow types
 crate use noria::debug::trace::{PacketEvent, Tracer};
+crate use noria::error::{
+    CheckViolation, ConstraintViolation, ReferenceViolation, TypeViolation, UniqueViolation,
+};
 crate use noria::Input;
 crate use payload::{ReplayPathSegment, SourceChannelIdentifier};
 
 // domain local state
-crate use state::{LookupResult, MemoryState, PersistentState, RecordResult, Row, State};
+crate use state::{
+    LookupResult, MemoryState, PersistentState, RecordResult, Row, SpillingState, State,
+};
 crate type StateMap = Map<Box<State>>;
 crate type DomainNodes = Map<cell::RefCell<Node>>;
 crate type ReplicaAddr = (DomainIndex, usize);
@@ -43,5 +48,11 @@ pub use PersistenceParameters;
 pub type ChannelCoordinator = noria::channel::ChannelCoordinator<(DomainIndex, usize), Box<Packet>>;
 pub trait Executor {
     fn ack(&mut self, tag: SourceChannelIdentifier);
+    /// Tell the sender of a write that the operations at the given indices in it were refused.
+    fn reject(
+        &mut self,
+        tag: SourceChannelIdentifier,
+        violations: Vec<(usize, ConstraintViolation)>,
+    );
     fn create_universe(&mut self, req: HashMap<String, DataType>);
 }
@@ -0,0 +1,193 @@
+//! The format that node state is written out in.
+//!
+//! State that outlives the process that wrote it starts with a header that says what kind of state
+//! it is and which version of its layout it was written in. A newer build reads state that an
+//! older one wrote through the shim that upgrades that version to the current layout, and refuses
+//! state that a newer build wrote rather than misread it, so that rolling upgrades and restores can
+//! cross changes to the layout. State that was written before there were headers is version 0.
+//!
+//! Aggregations and readers keep their state in memory only, and rebuild it through replays, so
+//! their kinds are reserved for when their state is written out too.
+
+use bincode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error::Error;
+use std::io;
+
+/// What every header starts with. Nothing that was written before there were headers does.
+const MAGIC: &[u8; 4] = b"NSTF";
+
+/// How many bytes a header takes up: the magic, the kind, and the version.
+crate const HEADER_LEN: usize = 7;
+
+/// The kinds of node state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+crate enum StateKind {
+    /// The rows and indices of a base table.
+    Base,
+    /// The rows of one side of a join.
+    Join,
+    /// The groups of an aggregation.
+    Aggregation,
+    /// The rows that a reader serves lookups from.
+    Reader,
+}
+
+impl StateKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            StateKind::Base => 1,
+            StateKind::Join => 2,
+            StateKind::Aggregation => 3,
+            StateKind::Reader => 4,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            1 => Some(StateKind::Base),
+            2 => Some(StateKind::Join),
+            3 => Some(StateKind::Aggregation),
+            4 => Some(StateKind::Reader),
+            _ => None,
+        }
+    }
+}
+
+/// Node state that is written out in a versioned layout.
+crate trait Versioned: Serialize + DeserializeOwned {
+    /// The kind of state this is.
+    const KIND: StateKind;
+    /// The version of the layout that the state is written out in now.
+    const VERSION: u16;
+
+    /// Read `payload`, which was written out in the older layout `version`.
+    fn upgrade(version: u16, payload: &[u8]) -> io::Result<Self>;
+}
+
+/// State that cannot be read.
+crate fn invalid<E: Into<Box<dyn Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// The header of state of `kind` written out in layout `version`.
+crate fn header(kind: StateKind, version: u16) -> [u8; HEADER_LEN] {
+    let v = version.to_le_bytes();
+    [
+        MAGIC[0],
+        MAGIC[1],
+        MAGIC[2],
+        MAGIC[3],
+        kind.to_byte(),
+        v[0],
+        v[1],
+    ]
+}
+
+/// Split `bytes` into the version of the layout of the state of `kind` that they hold, and the
+/// state itself.
+///
+/// Bytes that do not start with a header are taken to be state of `kind` written in version 0.
+crate fn split(kind: StateKind, bytes: &[u8]) -> io::Result<(u16, &[u8])> {
+    if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC[..] {
+        return Ok((0, bytes));
+    }
+    let found = bytes[MAGIC.len()];
+    match StateKind::from_byte(found) {
+        Some(k) if k == kind => {}
+        Some(k) => return Err(invalid(format!("expected {:?} state, found {:?}", kind, k))),
+        None => return Err(invalid(format!("unknown kind of state {}", found))),
+    }
+    let version = u16::from_le_bytes([bytes[MAGIC.len() + 1], bytes[MAGIC.len() + 2]]);
+    Ok((version, &bytes[HEADER_LEN..]))
+}
+
+/// Check that state of `kind` in layout `version` can be read by a build that writes `current`.
+crate fn check(kind: StateKind, version: u16, current: u16) -> io::Result<()> {
+    if version > current {
+        return Err(invalid(format!(
+            "{:?} state is in version {} of its layout, but only versions up to {} can be read",
+            kind, version, current
+        )));
+    }
+    Ok(())
+}
+
+/// Write out `value` with a header.
+crate fn encode<T: Versioned>(value: &T) -> Vec<u8> {
+    let mut bytes = header(T::KIND, T::VERSION).to_vec();
+    bytes.extend(bincode::serialize(value).unwrap());
+    bytes
+}
+
+/// Read state that `encode`, or an older build, wrote out, upgrading it if it was written in an
+/// older layout.
+crate fn decode<T: Versioned>(bytes: &[u8]) -> io::Result<T> {
+    let (version, payload) = split(T::KIND, bytes)?;
+    check(T::KIND, version, T::VERSION)?;
+    if version == T::VERSION {
+        bincode::deserialize(payload).map_err(invalid)
+    } else {
+        T::upgrade(version, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Counts {
+        counts: Vec<u64>,
+        total: u64,
+    }
+
+    impl Versioned for Counts {
+        const KIND: StateKind = StateKind::Aggregation;
+        const VERSION: u16 = 2;
+
+        fn upgrade(version: u16, payload: &[u8]) -> io::Result<Self> {
+            match version {
+                // the total used to be worked out when the state was read
+                0 | 1 => {
+                    let counts: Vec<u64> = bincode::deserialize(payload).map_err(invalid)?;
+                    let total = counts.iter().sum();
+                    Ok(Counts { counts, total })
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn it_upgrades_older_layouts() {
+        let counts = Counts {
+            counts: vec![1, 2],
+            total: 3,
+        };
+        assert_eq!(decode::<Counts>(&encode(&counts)).unwrap(), counts);
+
+        // from before there were headers, and from a version with one
+        let old = bincode::serialize(&vec![1u64, 2]).unwrap();
+        assert_eq!(decode::<Counts>(&old).unwrap(), counts);
+        let mut v1 = header(StateKind::Aggregation, 1).to_vec();
+        v1.extend(&old);
+        assert_eq!(decode::<Counts>(&v1).unwrap(), counts);
+    }
+
+    #[test]
+    fn it_refuses_what_it_cannot_read() {
+        let counts = Counts {
+            counts: vec![],
+            total: 0,
+        };
+        let mut newer = header(StateKind::Aggregation, 3).to_vec();
+        newer.extend(bincode::serialize(&counts).unwrap());
+        assert!(decode::<Counts>(&newer).is_err());
+
+        let mut other = header(StateKind::Reader, 2).to_vec();
+        other.extend(bincode::serialize(&counts).unwrap());
+        assert!(decode::<Counts>(&other).is_err());
+    }
+}
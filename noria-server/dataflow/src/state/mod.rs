@@ -1,7 +1,9 @@
+mod format;
 mod keyed_state;
 mod memory_state;
 mod persistent_state;
 mod single_state;
+mod spilling_state;
 
 use std::borrow::Cow;
 use std::ops::Deref;
@@ -13,6 +15,8 @@ use prelude::*;
 
 crate use self::memory_state::MemoryState;
 crate use self::persistent_state::PersistentState;
+pub use self::persistent_state::BackgroundSync;
+crate use self::spilling_state::SpillingState;
 
 crate trait State: SizeOf + Send {
     /// Add an index keyed by the given columns and replayed to by the given partial tags.
@@ -50,6 +54,9 @@ crate trait State: SizeOf + Send {
     fn evict_keys(&mut self, tag: Tag, keys: &[Vec<DataType>]) -> Option<(&[usize], u64)>;
 
     fn clear(&mut self);
+
+    /// Make sure that the records processed so far survive a crash, if this state is on disk.
+    fn sync(&mut self) {}
 }
 
 #[derive(Clone, Debug)]
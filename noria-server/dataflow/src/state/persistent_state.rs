@@ -2,10 +2,16 @@ use bincode;
 use itertools::Itertools;
 use rocksdb::{self, ColumnFamily, SliceTransform, SliceTransformFns, WriteBatch};
 use serde;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::{thread, time};
 use tempfile::{tempdir, TempDir};
 
 use common::SizeOf;
 use prelude::*;
+use state::format::{self, StateKind, Versioned};
 use state::{RecordResult, State};
 
 // Incremented on each PersistentState initialization so that IndexSeq
@@ -32,6 +38,20 @@ struct PersistentMeta {
     epoch: IndexEpoch,
 }
 
+// The version of the meta's layout also covers the layout of the rows and keys of the table.
+impl Versioned for PersistentMeta {
+    const KIND: StateKind = StateKind::Base;
+    const VERSION: u16 = 1;
+
+    fn upgrade(version: u16, payload: &[u8]) -> io::Result<Self> {
+        match version {
+            // the layout has not changed since before there were headers
+            0 => bincode::deserialize(payload).map_err(format::invalid),
+            _ => unreachable!("no version between 0 and {}", Self::VERSION),
+        }
+    }
+}
+
 #[derive(Clone)]
 struct PersistentIndex {
     column_family: ColumnFamily,
@@ -47,13 +67,124 @@ impl PersistentIndex {
     }
 }
 
+/// Syncs the RocksDB write-ahead logs of base tables to disk at a fixed interval from a dedicated
+/// thread, which all the base tables of a worker share.
+///
+/// This lets writes to a base table be applied without waiting for an fsync on the domain thread;
+/// all writes that were made before a sync are made durable by that sync (group commit). The
+/// thread stops once every copy of the `BackgroundSync` is dropped.
+#[derive(Clone)]
+pub struct BackgroundSync(Arc<Syncer>);
+
+struct Syncer {
+    // Held while syncing, so that a table that is removed is never synced after.
+    dbs: Arc<Mutex<Vec<Weak<rocksdb::DB>>>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl BackgroundSync {
+    /// Start syncing every `every`.
+    pub fn start(every: time::Duration) -> Self {
+        let dbs: Arc<Mutex<Vec<Weak<rocksdb::DB>>>> = Default::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let dbs = dbs.clone();
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("sync".to_owned())
+                .spawn(move || {
+                    let mut opts = rocksdb::WriteOptions::default();
+                    opts.set_sync(true);
+                    loop {
+                        thread::park_timeout(every);
+                        // an empty, synced write forces all earlier writes in the WAL to disk.
+                        dbs.lock().unwrap().retain(|db| match db.upgrade() {
+                            Some(db) => {
+                                db.write_opt(WriteBatch::default(), &opts).unwrap();
+                                true
+                            }
+                            None => false,
+                        });
+                        if stop.load(Ordering::SeqCst) {
+                            break;
+                        }
+                    }
+                })
+                .unwrap()
+        };
+
+        BackgroundSync(Arc::new(Syncer {
+            dbs,
+            stop,
+            handle: Some(handle),
+        }))
+    }
+
+    fn add(&self, db: &Arc<rocksdb::DB>) {
+        self.0.dbs.lock().unwrap().push(Arc::downgrade(db));
+    }
+
+    /// Stop syncing `db`. Once this returns, the syncer no longer holds a handle to it.
+    fn remove(&self, db: &Arc<rocksdb::DB>) {
+        self.0
+            .dbs
+            .lock()
+            .unwrap()
+            .retain(|d| d.upgrade().map(|d| !Arc::ptr_eq(&d, db)).unwrap_or(false));
+    }
+}
+
+/// The registration of a DB with a `BackgroundSync`.
+///
+/// Dropping it syncs what the syncer has yet to, and makes the syncer let go of the DB so that
+/// the DB is closed once its other handles are dropped.
+struct Registration {
+    syncer: BackgroundSync,
+    db: Weak<rocksdb::DB>,
+}
+
+impl Registration {
+    fn new(syncer: &BackgroundSync, db: &Arc<rocksdb::DB>) -> Self {
+        syncer.add(db);
+        Registration {
+            syncer: syncer.clone(),
+            db: Arc::downgrade(db),
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.upgrade() {
+            self.syncer.remove(&db);
+            let mut opts = rocksdb::WriteOptions::default();
+            opts.set_sync(true);
+            db.write_opt(WriteBatch::default(), &opts).unwrap();
+        }
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            handle.join().unwrap();
+        }
+    }
+}
+
 /// PersistentState stores data in RocksDB.
 pub struct PersistentState {
+    // The registration with the syncer that syncs writes to the DB in the background, if they are
+    // not synced as they are made. It must be dropped before `db`, so that it can do a final sync.
+    syncer: Option<Registration>,
     db_opts: rocksdb::Options,
     // We don't really want DB to be an option, but doing so lets us drop it manually in
     // PersistenState's Drop by setting `self.db = None` - after which we can then discard the
     // persisted files if we want to.
-    db: Option<rocksdb::DB>,
+    db: Option<Arc<rocksdb::DB>>,
     // The first element is always considered the primary index, where the actual data is stored.
     // Subsequent indices maintain pointers to the data in the first index, and cause an additional
     // read during lookups. When `self.has_unique_index` is true the first index is a primary key,
@@ -155,12 +286,22 @@ impl State for PersistentState {
             }
         }
 
-        // Sync the writes to RocksDB's WAL:
+        // Sync the writes to RocksDB's WAL, unless that's done for us in the background:
         let mut opts = rocksdb::WriteOptions::default();
-        opts.set_sync(true);
+        opts.set_sync(self.syncer.is_none());
         self.db.as_ref().unwrap().write_opt(batch, &opts).unwrap();
     }
 
+    fn sync(&mut self) {
+        if self.syncer.is_some() {
+            // as in the background, an empty, synced write forces earlier writes to disk
+            let mut opts = rocksdb::WriteOptions::default();
+            opts.set_sync(true);
+            let db = self.db.as_ref().unwrap();
+            db.write_opt(WriteBatch::default(), &opts).unwrap();
+        }
+    }
+
     fn lookup(&self, columns: &[usize], key: &KeyType) -> LookupResult {
         let db = self.db.as_ref().unwrap();
         let index_id = self
@@ -206,9 +347,13 @@ impl State for PersistentState {
         // We'll store all the pointers (or values if this is index 0) for
         // this index in its own column family:
         let index_id = self.indices.len().to_string();
-        let column_family = self
-            .db
-            .as_mut()
+        //
+        // The background syncer holds a handle to the DB while it syncs, so we take the DB away
+        // from it while we need exclusive access.
+        if let Some(ref registration) = self.syncer {
+            registration.syncer.remove(self.db.as_ref().unwrap());
+        }
+        let column_family = Arc::get_mut(self.db.as_mut().unwrap())
             .unwrap()
             .create_cf(&index_id, &self.db_opts)
             .unwrap();
@@ -234,6 +379,9 @@ impl State for PersistentState {
         });
 
         self.persist_meta();
+        if let Some(ref registration) = self.syncer {
+            registration.syncer.add(self.db.as_ref().unwrap());
+        }
     }
 
     fn keys(&self) -> Vec<Vec<usize>> {
@@ -333,7 +481,8 @@ impl PersistentState {
             db = DB::open_cf_descriptors(&opts, &full_name, make_cfs());
         }
         let mut db = db.unwrap();
-        let meta = Self::retrieve_and_update_meta(&db);
+        let meta = Self::retrieve_and_update_meta(&db)
+            .unwrap_or_else(|e| panic!("cannot read base table {}: {}", name, e));
         let indices: Vec<PersistentIndex> = meta
             .indices
             .into_iter()
@@ -356,17 +505,16 @@ impl PersistentState {
             indices,
             has_unique_index: primary_key.is_some(),
             epoch: meta.epoch,
+            syncer: None,
             db_opts: opts,
-            db: Some(db),
+            db: Some(Arc::new(db)),
             _directory: directory,
         };
 
         if primary_key.is_some() && state.indices.is_empty() {
             // This is the first time we're initializing this PersistentState,
             // so persist the primary key index right away.
-            let cf = state
-                .db
-                .as_mut()
+            let cf = Arc::get_mut(state.db.as_mut().unwrap())
                 .unwrap()
                 .create_cf("0", &state.db_opts)
                 .unwrap();
@@ -379,6 +527,25 @@ impl PersistentState {
         state
     }
 
+    /// Delete the files of the permanent state called `name`, which must no longer be open.
+    pub fn destroy(name: &str) -> io::Result<()> {
+        fs::remove_dir_all(format!("{}.db", name))
+    }
+
+    /// Move the files of the permanent state called `from`, which must no longer be open, to
+    /// where a state called `to` keeps them.
+    pub fn rename(from: &str, to: &str) -> io::Result<()> {
+        fs::rename(format!("{}.db", from), format!("{}.db", to))
+    }
+
+    /// Have `syncer` sync writes to this state to disk, rather than syncing each write as it is
+    /// made.
+    pub fn sync_in_background(&mut self, syncer: &BackgroundSync) {
+        // an earlier registration must be dropped first, as it deregisters the DB
+        self.syncer = None;
+        self.syncer = Some(Registration::new(syncer, self.db.as_ref().unwrap()));
+    }
+
     fn build_options(name: &str, params: &PersistenceParameters) -> rocksdb::Options {
         let mut opts = rocksdb::Options::default();
         opts.set_compression_type(rocksdb::DBCompressionType::Lz4);
@@ -442,17 +609,17 @@ impl PersistentState {
         KeyType::from(columns.iter().map(|i| &row[*i]))
     }
 
-    fn retrieve_and_update_meta(db: &rocksdb::DB) -> PersistentMeta {
+    fn retrieve_and_update_meta(db: &rocksdb::DB) -> io::Result<PersistentMeta> {
         let indices = db.get(META_KEY).unwrap();
         let mut meta = match indices {
-            Some(data) => bincode::deserialize(&*data).unwrap(),
+            Some(data) => format::decode(&*data)?,
             None => PersistentMeta::default(),
         };
 
+        // the meta is written back in the current layout, whichever one it was read in
         meta.epoch += 1;
-        let data = bincode::serialize(&meta).unwrap();
-        db.put(META_KEY, &data).unwrap();
-        meta
+        db.put(META_KEY, &format::encode(&meta)).unwrap();
+        Ok(meta)
     }
 
     fn persist_meta(&mut self) {
@@ -464,8 +631,7 @@ impl PersistentState {
             epoch: self.epoch,
         };
 
-        let data = bincode::serialize(&meta).unwrap();
-        db.put(META_KEY, &data).unwrap();
+        db.put(META_KEY, &format::encode(&meta)).unwrap();
     }
 
     // Our RocksDB keys come in three forms, and are encoded as follows:
@@ -873,6 +1039,63 @@ mod tests {
         }
     }
 
+    #[test]
+    fn persistent_state_rename_and_destroy() {
+        let (_dir, name) = get_tmp_path();
+        let renamed = format!("{}-renamed", name);
+        let mut params = PersistenceParameters::default();
+        params.mode = DurabilityMode::Permanent;
+        let syncer = BackgroundSync::start(::std::time::Duration::from_millis(1));
+        let row: Vec<DataType> = vec![10.into(), "Cat".into()];
+        {
+            let mut state = PersistentState::new(name.clone(), Some(&[0]), &params);
+            state.sync_in_background(&syncer);
+            state.process_records(&mut vec![row.clone()].into(), None);
+        }
+
+        PersistentState::rename(&name, &renamed).unwrap();
+        {
+            let state = PersistentState::new(renamed.clone(), Some(&[0]), &params);
+            match state.lookup(&[0], &KeyType::Single(&10.into())) {
+                LookupResult::Some(RecordResult::Owned(rows)) => assert_eq!(rows, vec![row]),
+                _ => unreachable!(),
+            }
+        }
+
+        PersistentState::destroy(&renamed).unwrap();
+        assert!(!PathBuf::from(format!("{}.db", renamed)).exists());
+        assert!(!PathBuf::from(format!("{}.db", name)).exists());
+    }
+
+    #[test]
+    fn persistent_state_recover_background_sync() {
+        let (_dir, name) = get_tmp_path();
+        let mut params = PersistenceParameters::default();
+        params.mode = DurabilityMode::Permanent;
+        let syncer = BackgroundSync::start(::std::time::Duration::from_millis(1));
+        let first: Vec<DataType> = vec![10.into(), "Cat".into()];
+        let second: Vec<DataType> = vec![20.into(), "Bob".into()];
+        {
+            let mut state = PersistentState::new(name.clone(), None, &params);
+            state.sync_in_background(&syncer);
+            state.add_key(&[0], None);
+            state.process_records(&mut vec![first.clone()].into(), None);
+            // adding an index must work while the syncer is running
+            state.add_key(&[1], None);
+            state.process_records(&mut vec![second.clone()].into(), None);
+            assert!(state.syncer.is_some());
+        }
+
+        let state = PersistentState::new(name, None, &params);
+        match state.lookup(&[1], &KeyType::Single(&"Bob".into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => {
+                assert_eq!(rows.len(), 1);
+                assert_eq!(&rows[0], &second);
+            }
+            _ => unreachable!(),
+        }
+    }
+
     #[test]
     fn persistent_state_recover_unique_key() {
         let (_dir, name) = get_tmp_path();
@@ -1036,6 +1259,43 @@ mod tests {
         };
     }
 
+    #[test]
+    fn persistent_state_reads_meta_written_before_headers() {
+        let (_dir, name) = get_tmp_path();
+        let mut params = PersistenceParameters::default();
+        params.mode = DurabilityMode::Permanent;
+
+        let epoch = {
+            let mut state = PersistentState::new(name.clone(), Some(&[0]), &params);
+            state.add_key(&[1], None);
+            insert(&mut state, vec![1.into(), 2.into()]);
+
+            // the meta as it was written before it had a header
+            let meta = PersistentMeta {
+                indices: vec![vec![0], vec![1]],
+                epoch: state.epoch,
+            };
+            let data = bincode::serialize(&meta).unwrap();
+            state.db.as_ref().unwrap().put(META_KEY, &data).unwrap();
+            state.epoch
+        };
+
+        let state = PersistentState::new(name, Some(&[0]), &params);
+        assert_eq!(state.epoch, epoch + 1);
+        assert_eq!(state.indices.len(), 2);
+        match state.lookup(&[1], &KeyType::Single(&2.into())) {
+            LookupResult::Some(RecordResult::Owned(rs)) => assert_eq!(rs.len(), 1),
+            _ => unreachable!(),
+        }
+
+        // the meta has been written back with a header
+        let data = state.db.as_ref().unwrap().get(META_KEY).unwrap().unwrap();
+        assert_eq!(
+            &data[..format::HEADER_LEN],
+            &format::header(StateKind::Base, PersistentMeta::VERSION)[..]
+        );
+    }
+
     #[test]
     fn persistent_state_all_rows() {
         let mut state = setup_persistent("persistent_state_all_rows");
@@ -0,0 +1,576 @@
+use bincode;
+use std::cell::Cell;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::iter;
+use tempfile;
+
+use common::SizeOf;
+use prelude::*;
+use state::format::{self, StateKind};
+
+/// The number of partitions that the rows are spread across.
+const PARTITIONS: usize = 64;
+
+/// The version of the layout of the logs of partitions that were moved to disk.
+const LOG_VERSION: u16 = 1;
+
+/// How many records the log of a partition on disk may hold before it is compacted, as long as it
+/// also holds more than twice as many records as the partition has rows.
+const COMPACT_AFTER: usize = 1024;
+
+/// The rows of a partition that were moved to disk, as a log of the records written to it since.
+struct Cold {
+    file: File,
+    /// How many bytes of the file belong to the log. A write that fails part of the way through
+    /// leaves the rest of the file behind, to be overwritten by the next write.
+    len: u64,
+    /// The number of records in the log.
+    records: usize,
+    /// Records that could not be added to the log, which are kept in memory until they can.
+    pending: Vec<(bool, Vec<DataType>)>,
+    rows: usize,
+    /// Roughly how many bytes the rows would take up in memory again.
+    bytes: u64,
+}
+
+enum Partition {
+    Hot(MemoryState),
+    Cold(Cold),
+}
+
+/// A fully materialized state for the inputs of joins, which moves the parts of itself that were
+/// used least recently to disk once its rows take up more memory than it is allowed.
+///
+/// Rows are spread across partitions by the hash of their key in the first index. A lookup on
+/// that index only looks at the one partition the key belongs in, and reads it from disk if it
+/// has been moved there. Lookups on other indices look at every partition. Reading a partition
+/// from disk takes time in proportion to its rows, since its log is compacted whenever it grows
+/// much longer than that.
+///
+/// Partitions that can't be written to disk stay in memory, and records that can't be added to a
+/// partition's log are held in memory until the next write to it. Rows that are on disk but can't
+/// be read back are lost, and fail the domain.
+pub struct SpillingState {
+    keys: Vec<Vec<usize>>,
+    partitions: Vec<Partition>,
+    /// When each partition was last used, by the ticks of `clock`.
+    used: Vec<Cell<u64>>,
+    clock: Cell<u64>,
+    budget: u64,
+}
+
+impl SizeOf for SpillingState {
+    fn size_of(&self) -> u64 {
+        use std::mem::size_of;
+
+        size_of::<Self>() as u64
+    }
+
+    fn deep_size_of(&self) -> u64 {
+        self.partitions
+            .iter()
+            .map(|p| match *p {
+                Partition::Hot(ref s) => s.deep_size_of(),
+                Partition::Cold(ref c) => c
+                    .pending
+                    .iter()
+                    .map(|&(_, ref row)| row.deep_size_of())
+                    .sum(),
+            })
+            .sum()
+    }
+}
+
+impl State for SpillingState {
+    fn add_key(&mut self, columns: &[usize], partial: Option<Vec<Tag>>) {
+        assert!(partial.is_none(), "SpillingState can't be partial");
+        if self.keys.iter().any(|k| &k[..] == columns) {
+            return;
+        }
+
+        // cold partitions are scanned in full, so they need no indices
+        self.keys.push(columns.to_vec());
+        for p in &mut self.partitions {
+            if let Partition::Hot(ref mut s) = *p {
+                s.add_key(columns, None);
+            }
+        }
+    }
+
+    fn is_useful(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    fn is_partial(&self) -> bool {
+        false
+    }
+
+    fn process_records(&mut self, records: &mut Records, _: Option<Tag>) {
+        let mut batches = vec![Vec::new(); PARTITIONS];
+        for r in records.iter() {
+            batches[self.partition_of(r)].push(r.clone());
+        }
+
+        for (i, batch) in batches.into_iter().enumerate() {
+            if batch.is_empty() {
+                continue;
+            }
+            self.touch(i);
+            match self.partitions[i] {
+                Partition::Hot(ref mut s) => s.process_records(&mut batch.into(), None),
+                Partition::Cold(ref mut c) => {
+                    c.append(batch.into_iter().map(|r| {
+                        let (row, positive) = r.extract();
+                        (positive, row)
+                    }));
+                    c.compact();
+                }
+            }
+        }
+
+        self.rebalance();
+    }
+
+    fn mark_hole(&mut self, _: &[DataType], _: Tag) {
+        unreachable!("SpillingState can't be partial")
+    }
+
+    fn mark_filled(&mut self, _: Vec<DataType>, _: Tag) {
+        unreachable!("SpillingState can't be partial")
+    }
+
+    fn lookup<'a>(&'a self, columns: &[usize], key: &KeyType) -> LookupResult<'a> {
+        let values = key_values(key);
+        let matches = |row: &[DataType]| columns.iter().zip(&values).all(|(&c, &v)| row[c] == *v);
+
+        if columns == &self.keys[0][..] {
+            let i = partition(values.iter().cloned());
+            self.touch(i);
+            return match self.partitions[i] {
+                Partition::Hot(ref s) => s.lookup(columns, key),
+                Partition::Cold(ref c) => {
+                    let mut rows = c.read_or_fail();
+                    rows.retain(|r| matches(r));
+                    LookupResult::Some(RecordResult::Owned(rows))
+                }
+            };
+        }
+
+        let mut rows = Vec::new();
+        for p in &self.partitions {
+            match *p {
+                Partition::Hot(ref s) => {
+                    if let LookupResult::Some(rs) = s.lookup(columns, key) {
+                        rows.extend(rs.into_iter().map(|r| r.into_owned()));
+                    }
+                }
+                Partition::Cold(ref c) => {
+                    rows.extend(c.read_or_fail().into_iter().filter(|r| matches(r)));
+                }
+            }
+        }
+        LookupResult::Some(RecordResult::Owned(rows))
+    }
+
+    fn rows(&self) -> usize {
+        self.partitions
+            .iter()
+            .map(|p| match *p {
+                Partition::Hot(ref s) => s.rows(),
+                Partition::Cold(ref c) => c.rows,
+            })
+            .sum()
+    }
+
+    fn keys(&self) -> Vec<Vec<usize>> {
+        self.keys.clone()
+    }
+
+    fn cloned_records(&self) -> Vec<Vec<DataType>> {
+        let mut rows = Vec::new();
+        for p in &self.partitions {
+            match *p {
+                Partition::Hot(ref s) => rows.extend(s.cloned_records()),
+                Partition::Cold(ref c) => rows.extend(c.read_or_fail()),
+            }
+        }
+        rows
+    }
+
+    fn evict_random_keys(&mut self, _: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
+        unreachable!("can't evict keys from SpillingState")
+    }
+
+    fn evict_keys(&mut self, _: Tag, _: &[Vec<DataType>]) -> Option<(&[usize], u64)> {
+        unreachable!("can't evict keys from SpillingState")
+    }
+
+    fn clear(&mut self) {
+        for p in &mut self.partitions {
+            *p = Partition::Hot(MemoryState::default());
+            if let Partition::Hot(ref mut s) = *p {
+                for key in &self.keys {
+                    s.add_key(key, None);
+                }
+            }
+        }
+    }
+}
+
+impl SpillingState {
+    /// Make a state whose rows may take up `budget` bytes of memory.
+    pub fn new(budget: u64) -> Self {
+        SpillingState {
+            keys: Vec::new(),
+            partitions: (0..PARTITIONS)
+                .map(|_| Partition::Hot(MemoryState::default()))
+                .collect(),
+            used: (0..PARTITIONS).map(|_| Cell::new(0)).collect(),
+            clock: Cell::new(0),
+            budget,
+        }
+    }
+
+    /// The number of partitions that are on disk.
+    pub fn spilled(&self) -> usize {
+        self.partitions
+            .iter()
+            .filter(|p| match **p {
+                Partition::Cold(_) => true,
+                Partition::Hot(_) => false,
+            })
+            .count()
+    }
+
+    fn partition_of(&self, row: &[DataType]) -> usize {
+        partition(self.keys[0].iter().map(|&c| &row[c]))
+    }
+
+    fn touch(&self, i: usize) {
+        let now = self.clock.get() + 1;
+        self.clock.set(now);
+        self.used[i].set(now);
+    }
+
+    /// Move the partitions used least recently to disk until the rest fit in the budget, and
+    /// bring back the ones used most recently while they still fit.
+    ///
+    /// Partitions that can't be moved either way are left where they are until the next time.
+    fn rebalance(&mut self) {
+        let mut in_memory = self.deep_size_of();
+        while in_memory > self.budget {
+            let coldest = (0..PARTITIONS)
+                .filter(|&i| match self.partitions[i] {
+                    Partition::Hot(ref s) => s.rows() != 0,
+                    Partition::Cold(_) => false,
+                })
+                .min_by_key(|&i| self.used[i].get());
+            match coldest.map(|i| self.spill(i)) {
+                Some(Ok(bytes)) => in_memory -= bytes,
+                Some(Err(_)) | None => break,
+            }
+        }
+
+        loop {
+            let warmest = (0..PARTITIONS)
+                .filter_map(|i| match self.partitions[i] {
+                    Partition::Cold(ref c) => Some((i, c.bytes)),
+                    Partition::Hot(_) => None,
+                })
+                .max_by_key(|&(i, _)| self.used[i].get());
+            match warmest {
+                Some((i, bytes)) if in_memory + bytes <= self.budget => match self.unspill(i) {
+                    Ok(bytes) => in_memory += bytes,
+                    Err(_) => break,
+                },
+                _ => break,
+            }
+        }
+    }
+
+    /// Move partition `i` to disk, and return how many bytes of memory that freed.
+    fn spill(&mut self, i: usize) -> io::Result<u64> {
+        let cold = match self.partitions[i] {
+            Partition::Hot(ref s) => Cold::create(&s.cloned_records(), s.deep_size_of())?,
+            Partition::Cold(_) => unreachable!(),
+        };
+        let bytes = cold.bytes;
+        self.partitions[i] = Partition::Cold(cold);
+        Ok(bytes)
+    }
+
+    /// Bring partition `i` back into memory, and return how many bytes of memory it now takes up.
+    fn unspill(&mut self, i: usize) -> io::Result<u64> {
+        let rows = match self.partitions[i] {
+            Partition::Cold(ref c) => c.read()?,
+            Partition::Hot(_) => unreachable!(),
+        };
+        let mut s = MemoryState::default();
+        for key in &self.keys {
+            s.add_key(key, None);
+        }
+        s.process_records(&mut rows.into(), None);
+        let bytes = s.deep_size_of();
+        self.partitions[i] = Partition::Hot(s);
+        Ok(bytes)
+    }
+}
+
+impl Cold {
+    /// Write `rows`, which take up `bytes` bytes of memory, to a new log.
+    fn create(rows: &[Vec<DataType>], bytes: u64) -> io::Result<Self> {
+        let mut log = format::header(StateKind::Join, LOG_VERSION).to_vec();
+        for row in rows {
+            encode(&mut log, true, row)?;
+        }
+        let mut file = tempfile::tempfile()?;
+        file.write_all(&log)?;
+        Ok(Cold {
+            file,
+            len: log.len() as u64,
+            records: rows.len(),
+            pending: Vec::new(),
+            rows: rows.len(),
+            bytes,
+        })
+    }
+
+    /// Add `records` to the end of the log, or hold on to them if that fails.
+    fn append<I: IntoIterator<Item = (bool, Vec<DataType>)>>(&mut self, records: I) {
+        for (positive, row) in records {
+            if positive {
+                self.rows += 1;
+                self.bytes += row.deep_size_of();
+            } else {
+                self.rows = self.rows.saturating_sub(1);
+                self.bytes = self.bytes.saturating_sub(row.deep_size_of());
+            }
+            self.pending.push((positive, row));
+        }
+
+        if let Ok(written) = self.write_pending() {
+            self.len += written;
+            self.records += self.pending.len();
+            self.pending.clear();
+        }
+    }
+
+    /// Write the records held back from the log to its end, and return how many bytes they took.
+    fn write_pending(&self) -> io::Result<u64> {
+        let mut log = Vec::new();
+        for &(positive, ref row) in &self.pending {
+            encode(&mut log, positive, row)?;
+        }
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.len))?;
+        file.write_all(&log)?;
+        Ok(log.len() as u64)
+    }
+
+    /// Rewrite the log to hold only the partition's rows, once it has grown much longer than that.
+    fn compact(&mut self) {
+        if self.records < COMPACT_AFTER || self.records <= 2 * self.rows {
+            return;
+        }
+        // a log that can't be rewritten is simply kept
+        if let Ok(compacted) = self.read().and_then(|rows| Cold::create(&rows, self.bytes)) {
+            *self = compacted;
+        }
+    }
+
+    /// The rows of the partition, as its log and the records held back from it leave them.
+    fn read(&self) -> io::Result<Vec<Vec<DataType>>> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        let mut file = BufReader::new(file.take(self.len));
+        let mut header = [0; format::HEADER_LEN];
+        file.read_exact(&mut header)?;
+        match format::split(StateKind::Join, &header)? {
+            (LOG_VERSION, _) => {}
+            (version, _) => {
+                return Err(format::invalid(format!(
+                    "spilled rows are in version {} of their layout, not {}",
+                    version, LOG_VERSION
+                )));
+            }
+        }
+
+        // each row is counted, so that a negative takes back one copy of it without a search
+        let mut rows: HashMap<Vec<DataType>, usize> = HashMap::new();
+        {
+            let mut apply = |positive: bool, row: Vec<DataType>| match rows.entry(row) {
+                Entry::Occupied(mut e) => {
+                    if positive {
+                        *e.get_mut() += 1;
+                    } else if *e.get() == 1 {
+                        e.remove();
+                    } else {
+                        *e.get_mut() -= 1;
+                    }
+                }
+                Entry::Vacant(e) => {
+                    if positive {
+                        e.insert(1);
+                    }
+                }
+            };
+            loop {
+                let mut len = [0; 8];
+                match file.read_exact(&mut len) {
+                    Ok(()) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+                let mut bytes = vec![0; u64::from_le_bytes(len) as usize];
+                file.read_exact(&mut bytes)?;
+                let (positive, row): (bool, Vec<DataType>) = bincode::deserialize(&bytes)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                apply(positive, row);
+            }
+            for &(positive, ref row) in &self.pending {
+                apply(positive, row.clone());
+            }
+        }
+
+        Ok(rows
+            .into_iter()
+            .flat_map(|(row, n)| iter::repeat(row).take(n))
+            .collect())
+    }
+
+    /// The rows of the partition, which can't be done without if they can't be read back.
+    fn read_or_fail(&self) -> Vec<Vec<DataType>> {
+        self.read()
+            .unwrap_or_else(|e| panic!("lost the spilled rows of a join's state: {}", e))
+    }
+}
+
+fn partition<'a, I: Iterator<Item = &'a DataType>>(key: I) -> usize {
+    let mut h = DefaultHasher::new();
+    for v in key {
+        v.hash(&mut h);
+    }
+    (h.finish() % PARTITIONS as u64) as usize
+}
+
+fn key_values<'a>(key: &'a KeyType) -> Vec<&'a DataType> {
+    match *key {
+        KeyType::Single(a) => vec![a],
+        KeyType::Double((ref a, ref b)) => vec![a, b],
+        KeyType::Tri((ref a, ref b, ref c)) => vec![a, b, c],
+        KeyType::Quad((ref a, ref b, ref c, ref d)) => vec![a, b, c, d],
+        KeyType::Quin((ref a, ref b, ref c, ref d, ref e)) => vec![a, b, c, d, e],
+        KeyType::Sex((ref a, ref b, ref c, ref d, ref e, ref f)) => vec![a, b, c, d, e, f],
+    }
+}
+
+/// Add a record to the end of `log`.
+fn encode(log: &mut Vec<u8>, positive: bool, row: &[DataType]) -> io::Result<()> {
+    let bytes = bincode::serialize(&(positive, row))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    log.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    log.extend_from_slice(&bytes);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert<S: State>(state: &mut S, rows: Vec<Vec<DataType>>) {
+        state.process_records(&mut rows.into(), None);
+    }
+
+    fn lookup(state: &SpillingState, columns: &[usize], key: i32) -> Vec<Vec<DataType>> {
+        let key = DataType::from(key);
+        match state.lookup(columns, &KeyType::Single(&key)) {
+            LookupResult::Some(rs) => {
+                let mut rows: Vec<_> = rs.into_iter().map(|r| r.into_owned()).collect();
+                rows.sort();
+                rows
+            }
+            LookupResult::Missing => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn it_spills_and_still_finds_rows() {
+        let mut state = SpillingState::new(2048);
+        state.add_key(&[0], None);
+        state.add_key(&[1], None);
+        insert(
+            &mut state,
+            (0..200).map(|i| vec![i.into(), (i % 10).into()]).collect(),
+        );
+        assert!(state.spilled() > 0);
+        assert!(state.deep_size_of() <= 2048);
+        assert_eq!(state.rows(), 200);
+
+        for i in 0..200 {
+            assert_eq!(
+                lookup(&state, &[0], i),
+                vec![vec![i.into(), (i % 10).into()]]
+            );
+        }
+        assert_eq!(lookup(&state, &[1], 3).len(), 20);
+
+        // deletes reach rows on disk too
+        state.process_records(
+            &mut (0..200)
+                .filter(|i| i % 2 == 0)
+                .map(|i| (vec![i.into(), (i % 10).into()], false))
+                .collect::<Vec<(Vec<DataType>, bool)>>()
+                .into(),
+            None,
+        );
+        assert_eq!(state.rows(), 100);
+        assert!(lookup(&state, &[0], 4).is_empty());
+        assert_eq!(lookup(&state, &[0], 5), vec![vec![5.into(), 5.into()]]);
+        assert_eq!(state.cloned_records().len(), 100);
+    }
+
+    #[test]
+    fn it_brings_partitions_back_once_there_is_room() {
+        let mut state = SpillingState::new(4096);
+        state.add_key(&[0], None);
+        let rows: Vec<Vec<DataType>> = (0..400).map(|i| vec![i.into()]).collect();
+        insert(&mut state, rows.clone());
+        assert!(state.spilled() > 0);
+
+        state.process_records(
+            &mut rows
+                .into_iter()
+                .skip(10)
+                .map(|r| (r, false))
+                .collect::<Vec<(Vec<DataType>, bool)>>()
+                .into(),
+            None,
+        );
+        assert_eq!(state.spilled(), 0);
+        assert_eq!(state.rows(), 10);
+        assert_eq!(lookup(&state, &[0], 9), vec![vec![9.into()]]);
+    }
+
+    #[test]
+    fn it_compacts_the_logs_of_spilled_partitions() {
+        let mut state = SpillingState::new(0);
+        state.add_key(&[0], None);
+        insert(&mut state, vec![vec![1.into()]]);
+        assert_eq!(state.spilled(), 1);
+
+        for _ in 0..COMPACT_AFTER {
+            insert(&mut state, vec![vec![1.into()]]);
+            state.process_records(&mut vec![(vec![1.into()], false)].into(), None);
+        }
+        let i = partition(iter::once(&DataType::from(1)));
+        match state.partitions[i] {
+            Partition::Cold(ref c) => assert!(c.records <= COMPACT_AFTER),
+            Partition::Hot(_) => unreachable!(),
+        }
+        assert_eq!(lookup(&state, &[0], 1), vec![vec![1.into()]]);
+    }
+}
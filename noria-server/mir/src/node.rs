@@ -10,6 +10,8 @@ use dataflow::ops;
 use dataflow::ops::filter::FilterCondition;
 use dataflow::ops::grouped::aggregate::Aggregation as AggregationKind;
 use dataflow::ops::grouped::extremum::Extremum as ExtremumKind;
+use dataflow::ops::project::ColumnMask;
+use dataflow::ops::set::SetOpKind;
 use std::collections::HashMap;
 use {FlowNode, MirNodeRef};
 
@@ -18,6 +20,7 @@ pub enum GroupedNodeType {
     Aggregation(ops::grouped::aggregate::Aggregation),
     Extremum(ops::grouped::extremum::Extremum),
     GroupConcat(String),
+    ApproxCountDistinct,
 }
 
 pub struct MirNode {
@@ -185,7 +188,7 @@ impl MirNode {
     pub fn add_column(&mut self, c: Column) {
         match self.inner {
             // the aggregation column must always be the last column
-            MirNodeType::Aggregation { .. } => {
+            MirNodeType::Aggregation { .. } | MirNodeType::ApproxCountDistinct { .. } => {
                 let pos = self.columns.len() - 1;
                 self.columns.insert(pos, c.clone());
             }
@@ -330,13 +333,14 @@ impl MirNode {
         match self.inner {
             MirNodeType::Aggregation { ref on, .. }
             | MirNodeType::Extremum { ref on, .. }
-            | MirNodeType::GroupConcat { ref on, .. } => {
+            | MirNodeType::GroupConcat { ref on, .. }
+            | MirNodeType::ApproxCountDistinct { ref on, .. } => {
                 // need the "over" column
                 if !columns.contains(on) {
                     columns.push(on.clone());
                 }
             }
-            MirNodeType::Filter { .. } => {
+            MirNodeType::Filter { .. } | MirNodeType::SetOp { .. } => {
                 let parent = self.ancestors.iter().next().unwrap();
                 // need all parent columns
                 for c in parent.borrow().columns() {
@@ -352,6 +356,16 @@ impl MirNode {
                     }
                 }
             }
+            MirNodeType::Recursive {
+                ref from, ref to, ..
+            } => {
+                // need the columns that edges run between
+                for c in &[from, to] {
+                    if !columns.contains(c) {
+                        columns.push((*c).clone());
+                    }
+                }
+            }
             _ => (),
         }
         columns
@@ -375,12 +389,14 @@ impl MirNode {
 
 /// Specifies the adapatation of an existing base node by column addition/removal.
 /// `over` is a `MirNode` of type `Base`.
+#[derive(Clone)]
 pub struct BaseNodeAdaptation {
     pub over: MirNodeRef,
     pub columns_added: Vec<ColumnSpecification>,
     pub columns_removed: Vec<ColumnSpecification>,
 }
 
+#[derive(Clone)]
 pub enum MirNodeType {
     /// over column, group_by columns
     Aggregation {
@@ -388,6 +404,11 @@ pub enum MirNodeType {
         group_by: Vec<Column>,
         kind: AggregationKind,
     },
+    /// over column, group_by columns, estimating the number of distinct values
+    ApproxCountDistinct {
+        on: Column,
+        group_by: Vec<Column>,
+    },
     /// column specifications, keys (non-compound), tx flag, adapted base
     Base {
         column_specs: Vec<(ColumnSpecification, Option<usize>)>,
@@ -434,6 +455,8 @@ pub enum MirNodeType {
         emit: Vec<Column>,
         arithmetic: Vec<(String, ArithmeticExpression)>,
         literals: Vec<(String, DataType)>,
+        /// output columns (by index) to redact, and how
+        masks: Vec<(usize, ColumnMask)>,
     },
     /// emit columns
     Union {
@@ -450,14 +473,28 @@ pub enum MirNodeType {
     Distinct {
         group_by: Vec<Column>,
     },
+    /// INTERSECT or EXCEPT of the rows that the last parent column tags as from either input
+    SetOp {
+        kind: SetOpKind,
+    },
+    /// nodes reached by following the edges from column to column in up to depth steps
+    Recursive {
+        from: Column,
+        to: Column,
+        depth: usize,
+    },
     /// reuse another node
     Reuse {
         node: MirNodeRef,
     },
-    /// leaf (reader) node, keys
+    /// leaf (reader) node, keys, order to return rows in, whether to drop duplicate rows
     Leaf {
         node: MirNodeRef,
         keys: Vec<Column>,
+        order: Option<Vec<(Column, OrderType)>>,
+        distinct: bool,
+        /// key columns to redact in the rows that are read, and how
+        masks: Vec<(Column, ColumnMask)>,
     },
     /// Rewrite node
     Rewrite {
@@ -479,6 +516,11 @@ impl MirNodeType {
             } => {
                 group_by.push(c);
             }
+            MirNodeType::ApproxCountDistinct {
+                ref mut group_by, ..
+            } => {
+                group_by.push(c);
+            }
             MirNodeType::Base { .. } => panic!("can't add columns to base nodes!"),
             MirNodeType::Extremum {
                 ref mut group_by, ..
@@ -590,6 +632,16 @@ impl MirNodeType {
                     _ => false,
                 }
             }
+            MirNodeType::ApproxCountDistinct {
+                on: ref our_on,
+                group_by: ref our_group_by,
+            } => match *other {
+                MirNodeType::ApproxCountDistinct {
+                    ref on,
+                    ref group_by,
+                } => our_on == on && our_group_by == group_by,
+                _ => false,
+            },
             MirNodeType::Extremum {
                 on: ref our_on,
                 group_by: ref our_group_by,
@@ -648,12 +700,19 @@ impl MirNodeType {
                 emit: ref our_emit,
                 literals: ref our_literals,
                 arithmetic: ref our_arithmetic,
+                masks: ref our_masks,
             } => match *other {
                 MirNodeType::Project {
                     ref emit,
                     ref literals,
                     ref arithmetic,
-                } => our_emit == emit && our_literals == literals && our_arithmetic == arithmetic,
+                    ref masks,
+                } => {
+                    our_emit == emit
+                        && our_literals == literals
+                        && our_arithmetic == arithmetic
+                        && our_masks == masks
+                }
                 _ => false,
             },
             MirNodeType::Distinct {
@@ -662,6 +721,22 @@ impl MirNodeType {
                 MirNodeType::Distinct { ref group_by } => group_by == our_group_by,
                 _ => false,
             },
+            MirNodeType::SetOp { kind: our_kind } => match *other {
+                MirNodeType::SetOp { kind } => kind == our_kind,
+                _ => false,
+            },
+            MirNodeType::Recursive {
+                from: ref our_from,
+                to: ref our_to,
+                depth: our_depth,
+            } => match *other {
+                MirNodeType::Recursive {
+                    ref from,
+                    ref to,
+                    depth,
+                } => from == our_from && to == our_to && depth == our_depth,
+                _ => false,
+            },
             MirNodeType::Reuse { node: ref us } => {
                 match *other {
                     // both nodes are `Reuse` nodes, so we simply compare the both sides' reuse
@@ -694,9 +769,24 @@ impl MirNodeType {
                 _ => false,
             },
             MirNodeType::Leaf {
-                keys: ref our_keys, ..
+                keys: ref our_keys,
+                order: ref our_order,
+                distinct: our_distinct,
+                masks: ref our_masks,
+                ..
             } => match *other {
-                MirNodeType::Leaf { ref keys, .. } => keys == our_keys,
+                MirNodeType::Leaf {
+                    ref keys,
+                    ref order,
+                    distinct,
+                    ref masks,
+                    ..
+                } => {
+                    keys == our_keys
+                        && order == our_order
+                        && distinct == our_distinct
+                        && masks == our_masks
+                }
                 _ => false,
             },
             MirNodeType::Union { emit: ref our_emit } => match *other {
@@ -767,6 +857,17 @@ impl Debug for MirNodeType {
                     .join(", ");
                 write!(f, "{} γ[{}]", op_string, group_cols)
             }
+            MirNodeType::ApproxCountDistinct {
+                ref on,
+                ref group_by,
+            } => {
+                let group_cols = group_by
+                    .iter()
+                    .map(|c| c.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "≈|δ|({}) γ[{}]", on.name.as_str(), group_cols)
+            }
             MirNodeType::Base {
                 ref column_specs,
                 ref keys,
@@ -905,6 +1006,7 @@ impl Debug for MirNodeType {
                 ref emit,
                 ref literals,
                 ref arithmetic,
+                ..
             } => write!(
                 f,
                 "π [{}{}{}]",
@@ -951,6 +1053,12 @@ impl Debug for MirNodeType {
                     .join(", ");
                 write!(f, "Distinct [γ: {}]", key_cols)
             }
+            MirNodeType::SetOp { kind } => write!(f, "{:?}", kind),
+            MirNodeType::Recursive {
+                ref from,
+                ref to,
+                depth,
+            } => write!(f, "↻ [{} → {}, ≤ {}]", from.name, to.name, depth),
             MirNodeType::TopK {
                 ref order, ref k, ..
             } => write!(f, "TopK [k: {}, {:?}]", k, order),
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::fmt::{Display, Error, Formatter};
 
+use node::{MirNode, MirNodeType};
 use petgraph::graph::NodeIndex;
 use MirNodeRef;
 
@@ -81,6 +82,25 @@ impl MirQuery {
         self
     }
 
+    /// Copy this query so that it can be added to the graph once more without planning it anew.
+    ///
+    /// The copy reads from `roots` in place of the roots of this query, in the same order. All
+    /// its other nodes are new, and are tagged with schema version `v`.
+    pub fn replan(&self, roots: Vec<MirNodeRef>, v: usize) -> MirQuery {
+        assert_eq!(roots.len(), self.roots.len());
+        let mut copies: HashMap<_, _> = self
+            .roots
+            .iter()
+            .map(|r| r.borrow().versioned_name())
+            .zip(roots.iter().cloned())
+            .collect();
+        MirQuery {
+            name: self.name.clone(),
+            roots,
+            leaf: copy_node(&self.leaf, v, &mut copies),
+        }
+    }
+
     pub fn make_universe_naming_consistent(
         mut self,
         table_mapping: &HashMap<(String, Option<String>), String>,
@@ -91,6 +111,39 @@ impl MirQuery {
     }
 }
 
+/// Copy `node` and all of its ancestors that are not in `copies` already.
+fn copy_node(node: &MirNodeRef, v: usize, copies: &mut HashMap<String, MirNodeRef>) -> MirNodeRef {
+    let n = node.borrow();
+    if let Some(copy) = copies.get(&n.versioned_name()) {
+        return copy.clone();
+    }
+
+    let ancestors: Vec<_> = n
+        .ancestors
+        .iter()
+        .map(|a| copy_node(a, v, copies))
+        .collect();
+    let inner = match n.inner {
+        MirNodeType::Leaf {
+            node: ref parent,
+            ref keys,
+            ref order,
+            distinct,
+            ref masks,
+        } => MirNodeType::Leaf {
+            node: copy_node(parent, v, copies),
+            keys: keys.clone(),
+            order: order.clone(),
+            distinct,
+            masks: masks.clone(),
+        },
+        ref inner => inner.clone(),
+    };
+    let copy = MirNode::new(&n.name, v, n.columns.clone(), inner, ancestors, vec![]);
+    copies.insert(n.versioned_name(), copy.clone());
+    copy
+}
+
 impl Display for MirQuery {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         use std::collections::VecDeque;
@@ -128,3 +181,78 @@ impl Display for MirQuery {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use column::Column;
+    use std::rc::Rc;
+
+    fn make_query() -> (MirNodeRef, MirQuery) {
+        let cols = vec![Column::from("aa"), Column::from("ab")];
+        let a = MirNode::new(
+            "a",
+            0,
+            cols.clone(),
+            MirNodeType::Base {
+                column_specs: vec![],
+                keys: vec![Column::from("aa")],
+                adapted_over: None,
+            },
+            vec![],
+            vec![],
+        );
+        let reuse_a = MirNode::reuse(a.clone(), 0);
+        let p = MirNode::new(
+            "q_n0",
+            0,
+            cols.clone(),
+            MirNodeType::Identity,
+            vec![reuse_a.clone()],
+            vec![],
+        );
+        let leaf = MirNode::new(
+            "q",
+            0,
+            cols,
+            MirNodeType::Leaf {
+                node: p.clone(),
+                keys: vec![Column::from("aa")],
+                order: None,
+                distinct: false,
+                masks: vec![],
+            },
+            vec![p],
+            vec![],
+        );
+        let mq = MirQuery {
+            name: String::from("q"),
+            roots: vec![reuse_a],
+            leaf,
+        };
+        (a, mq)
+    }
+
+    #[test]
+    fn it_replans() {
+        let (a, mq) = make_query();
+        let root = MirNode::reuse(a, 1);
+        let copy = mq.replan(vec![root.clone()], 1);
+
+        assert!(Rc::ptr_eq(&copy.roots[0], &root));
+        let nodes = copy.topo_nodes();
+        assert_eq!(nodes.len(), 3);
+        assert!(nodes.iter().all(|n| n.borrow().from_version == 1));
+        assert_eq!(copy.leaf.borrow().versioned_name(), "q_v1");
+
+        // the copy hangs off the new root, and the leaf refers to its copied parent
+        let p = copy.leaf.borrow().ancestors()[0].clone();
+        assert!(Rc::ptr_eq(&root.borrow().children()[0], &p));
+        match copy.leaf.borrow().inner {
+            MirNodeType::Leaf { ref node, .. } => assert!(Rc::ptr_eq(node, &p)),
+            _ => unreachable!(),
+        }
+        assert!(!Rc::ptr_eq(&mq.leaf, &copy.leaf));
+        assert_eq!(mq.topo_nodes().len(), 3);
+    }
+}
@@ -277,6 +277,9 @@ mod tests {
             MirNodeType::Leaf {
                 node: c.clone(),
                 keys: vec![Column::from("ba")],
+                order: None,
+                distinct: false,
+                masks: vec![],
             },
             vec![],
             vec![],
@@ -326,6 +329,7 @@ mod tests {
                 emit: vec![Column::from("aa")],
                 arithmetic: vec![],
                 literals: vec![],
+                masks: vec![],
             },
             vec![c.clone()],
             vec![d.clone()],
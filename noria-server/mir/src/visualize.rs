@@ -114,6 +114,17 @@ impl GraphViz for MirNodeType {
                     .join(", ");
                 write!(out, "{} | γ: {}", op_string, group_cols)?;
             }
+            MirNodeType::ApproxCountDistinct {
+                ref on,
+                ref group_by,
+            } => {
+                let group_cols = group_by
+                    .iter()
+                    .map(|c| print_col(c))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(out, "≈\\|δ\\|({}) | γ: {}", print_col(on), group_cols)?;
+            }
             MirNodeType::Base {
                 ref column_specs,
                 ref keys,
@@ -240,6 +251,7 @@ impl GraphViz for MirNodeType {
                 ref emit,
                 ref literals,
                 ref arithmetic,
+                ..
             } => {
                 write!(
                     out,
@@ -285,6 +297,22 @@ impl GraphViz for MirNodeType {
                     .join(", ");
                 write!(out, "Distinct | γ: {}", key_cols)?;
             }
+            MirNodeType::SetOp { kind } => {
+                write!(out, "{:?}", kind)?;
+            }
+            MirNodeType::Recursive {
+                ref from,
+                ref to,
+                depth,
+            } => {
+                write!(
+                    out,
+                    "↻ | {} → {}, ≤ {}",
+                    print_col(from),
+                    print_col(to),
+                    depth
+                )?;
+            }
             MirNodeType::TopK {
                 ref order, ref k, ..
             } => {
@@ -0,0 +1,219 @@
+use hyper::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// The privileges granted to the holder of an API token.
+///
+/// Roles are ordered, and each role includes the privileges of the roles before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Role {
+    /// May inspect the dataflow graph and read from views.
+    Read,
+    /// May additionally write to base tables.
+    Write,
+    /// May additionally change the dataflow, such as by installing or extending recipes.
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Role::Read),
+            "write" => Ok(Role::Write),
+            "admin" => Ok(Role::Admin),
+            r => bail!("unknown role {}", r),
+        }
+    }
+}
+
+/// Maps API tokens to the roles they have been granted.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+crate struct AuthConfig {
+    tokens: HashMap<String, Role>,
+}
+
+impl AuthConfig {
+    /// Parse a list of tokens given as `ROLE:TOKEN`, separated by commas or whitespace.
+    crate fn parse(s: &str) -> Result<Self, failure::Error> {
+        let mut auth = AuthConfig::default();
+        for t in s.split(|c: char| c == ',' || c.is_whitespace()) {
+            if t.is_empty() {
+                continue;
+            }
+            let mut parts = t.splitn(2, ':');
+            let role = parts.next().unwrap().parse()?;
+            let token = parts
+                .next()
+                .ok_or_else(|| format_err!("api token must be given as ROLE:TOKEN"))?;
+            auth.add_token(token.to_owned(), role);
+        }
+        Ok(auth)
+    }
+
+    crate fn add_token(&mut self, token: String, role: Role) {
+        self.tokens.insert(token, role);
+    }
+
+    /// Also accept all the tokens in `other`.
+    crate fn extend(&mut self, other: AuthConfig) {
+        self.tokens.extend(other.tokens);
+    }
+
+    /// Any token that has been granted the `Admin` role.
+    crate fn admin_token(&self) -> Option<&str> {
+        self.tokens
+            .iter()
+            .find(|&(_, &role)| role == Role::Admin)
+            .map(|(token, _)| &token[..])
+    }
+
+    /// Check that a request with the given headers may access the external API at `path`.
+    ///
+    /// Requests without a known token are rejected with `UNAUTHORIZED`, and requests whose token
+    /// does not have the required role are rejected with `FORBIDDEN`.
+    crate fn authorize(&self, headers: &HeaderMap, path: &str) -> Result<Role, StatusCode> {
+        let role = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                if v.starts_with("Bearer ") {
+                    Some(&v["Bearer ".len()..])
+                } else {
+                    None
+                }
+            })
+            .and_then(|token| self.tokens.get(token))
+            .cloned()
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        if role >= required_role(path) {
+            Ok(role)
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+/// The role needed to access the external API endpoint at `path`.
+///
+/// Endpoints that are not explicitly listed here require the `Admin` role, so that new endpoints
+/// are not accidentally exposed to less privileged clients.
+fn required_role(path: &str) -> Role {
+    match path {
+        "/graph.html" | "/graph" | "/simple_graph" | "/graphviz" | "/simple_graphviz"
+        | "/get_statistics" | "/inputs" | "/outputs" | "/instances" | "/nodes"
+        | "/view_builder" | "/workers" | "/domains" | "/catalog" | "/snapshot" => Role::Read,
+        "/migrations" | "/migration_progress" | "/export_view" => Role::Read,
+        "/table_builder" | "/propagation" | "/open_write_group" | "/close_write_group" => {
+            Role::Write
+        }
+        // "/prepare" may add a query to the recipe, so it is left to admins
+        _ => Role::Admin,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(token: &str) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        h.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        h
+    }
+
+    #[test]
+    fn roles_are_enforced() {
+        let mut auth = AuthConfig::default();
+        auth.add_token("r".to_owned(), Role::Read);
+        auth.add_token("w".to_owned(), Role::Write);
+        auth.add_token("a".to_owned(), Role::Admin);
+
+        assert_eq!(
+            auth.authorize(&HeaderMap::new(), "/view_builder"),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            auth.authorize(&headers("x"), "/view_builder"),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        assert_eq!(
+            auth.authorize(&headers("r"), "/view_builder"),
+            Ok(Role::Read)
+        );
+        assert_eq!(
+            auth.authorize(&headers("r"), "/table_builder"),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            auth.authorize(&headers("w"), "/table_builder"),
+            Ok(Role::Write)
+        );
+        assert_eq!(
+            auth.authorize(&headers("w"), "/propagation"),
+            Ok(Role::Write)
+        );
+        assert_eq!(
+            auth.authorize(&headers("w"), "/open_write_group"),
+            Ok(Role::Write)
+        );
+        assert_eq!(
+            auth.authorize(&headers("r"), "/close_write_group"),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            auth.authorize(&headers("w"), "/extend_recipe"),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            auth.authorize(&headers("a"), "/extend_recipe"),
+            Ok(Role::Admin)
+        );
+
+        assert_eq!(auth.authorize(&headers("r"), "/snapshot"), Ok(Role::Read));
+        assert_eq!(
+            auth.authorize(&headers("r"), "/export_view"),
+            Ok(Role::Read)
+        );
+
+        // cluster state can be read by anyone, but only changed by admins
+        assert_eq!(auth.authorize(&headers("r"), "/workers"), Ok(Role::Read));
+        assert_eq!(
+            auth.authorize(&headers("w"), "/drain_worker"),
+            Err(StatusCode::FORBIDDEN)
+        );
+
+        assert_eq!(
+            auth.authorize(&headers("w"), "/prepare"),
+            Err(StatusCode::FORBIDDEN)
+        );
+
+        // unknown endpoints require the most privileged role
+        assert_eq!(
+            auth.authorize(&headers("w"), "/zookeeper/state"),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(auth.admin_token(), Some("a"));
+    }
+
+    #[test]
+    fn tokens_are_parsed() {
+        let auth = AuthConfig::parse("read:r, write:w\nadmin:a:b").unwrap();
+        assert_eq!(
+            auth.authorize(&headers("r"), "/view_builder"),
+            Ok(Role::Read)
+        );
+        assert_eq!(
+            auth.authorize(&headers("w"), "/table_builder"),
+            Ok(Role::Write)
+        );
+        assert_eq!(auth.admin_token(), Some("a:b"));
+
+        assert!(AuthConfig::parse("root:x").is_err());
+        assert!(AuthConfig::parse("read").is_err());
+        assert_eq!(AuthConfig::parse("").unwrap(), AuthConfig::default());
+    }
+}
@@ -0,0 +1,331 @@
+extern crate clap;
+extern crate hdrhistogram;
+extern crate noria;
+extern crate rand;
+extern crate tokio;
+extern crate zipf;
+
+use clap::{value_t_or_exit, App, Arg};
+use hdrhistogram::Histogram;
+use noria::{DataType, SyncControllerHandle, ZookeeperAuthority};
+use rand::distributions::{Distribution, Uniform};
+use rand::Rng;
+use std::time::{Duration, Instant};
+use std::{process, thread};
+
+/// The latencies of one kind of operation.
+struct Latencies {
+    /// Time from when each operation should have been issued until it completed.
+    sojourn: Histogram<u64>,
+    /// Time from when each operation was actually issued until it completed.
+    remote: Histogram<u64>,
+    errors: u64,
+}
+
+impl Latencies {
+    fn new() -> Self {
+        Latencies {
+            sojourn: Histogram::new_with_bounds(10, 60_000_000, 4).unwrap(),
+            remote: Histogram::new_with_bounds(10, 60_000_000, 4).unwrap(),
+            errors: 0,
+        }
+    }
+
+    fn record(&mut self, scheduled: Instant, issued: Instant, ok: bool) {
+        if !ok {
+            self.errors += 1;
+            return;
+        }
+        let done = Instant::now();
+        self.sojourn
+            .saturating_record((done - scheduled).as_micros() as u64);
+        self.remote
+            .saturating_record((done - issued).as_micros() as u64);
+    }
+
+    fn add(&mut self, other: &Latencies) {
+        self.sojourn.add(&other.sojourn).unwrap();
+        self.remote.add(&other.remote).unwrap();
+        self.errors += other.errors;
+    }
+
+    fn report(&self, op: &str, runtime: Duration) {
+        let secs = runtime.as_secs() as f64 + f64::from(runtime.subsec_nanos()) * 1e-9;
+        println!(
+            "# {} ops/s: {:.2} ({} errors)",
+            op,
+            self.remote.len() as f64 / secs,
+            self.errors
+        );
+        for &q in &[0.5, 0.95, 0.99] {
+            println!(
+                "{}\t{}\t{:.2}\t{:.2}\t(all µs)",
+                op,
+                (q * 100.0) as u64,
+                self.sojourn.value_at_quantile(q),
+                self.remote.value_at_quantile(q)
+            );
+        }
+        println!(
+            "{}\t100\t{:.2}\t{:.2}\t(all µs)",
+            op,
+            self.sojourn.max(),
+            self.remote.max()
+        );
+    }
+}
+
+/// The workload that each load generator thread issues.
+#[derive(Clone)]
+struct Workload {
+    table: String,
+    view: String,
+    keys: usize,
+    skew: Option<f64>,
+    read_ratio: f64,
+    /// Time between operations, if they are issued at a fixed rate.
+    interval: Option<Duration>,
+    warmup: Duration,
+    runtime: Duration,
+}
+
+fn generate<E>(
+    mut ch: SyncControllerHandle<ZookeeperAuthority, E>,
+    workload: Workload,
+) -> (Latencies, Latencies)
+where
+    E: tokio::executor::Executor,
+{
+    let mut table = ch.table(&workload.table).unwrap().into_sync();
+    let mut view = ch.view(&workload.view).unwrap().into_sync();
+    let columns = table.columns().len();
+
+    let uniform = Uniform::new_inclusive(1, workload.keys);
+    let zipf = workload
+        .skew
+        .map(|s| zipf::ZipfDistribution::new(workload.keys, s).unwrap());
+    let mut rng = rand::thread_rng();
+
+    let mut reads = Latencies::new();
+    let mut writes = Latencies::new();
+    let start = Instant::now();
+    let measure_from = start + workload.warmup;
+    let mut next = start;
+    while start.elapsed() < workload.warmup + workload.runtime {
+        // with a target rate, operations are scheduled independently of how long earlier ones
+        // took, so that a slow deployment shows up as queueing delay in the sojourn times.
+        let scheduled = match workload.interval {
+            Some(interval) => {
+                let now = Instant::now();
+                if next > now {
+                    thread::sleep(next - now);
+                }
+                next += interval;
+                next - interval
+            }
+            None => Instant::now(),
+        };
+
+        let key = match zipf {
+            Some(ref zipf) => zipf.sample(&mut rng),
+            None => uniform.sample(&mut rng),
+        };
+        let key = DataType::from(key as i64);
+
+        let issued = Instant::now();
+        if rng.gen_bool(workload.read_ratio) {
+            let ok = view.lookup(&[key], true).is_ok();
+            if scheduled >= measure_from {
+                reads.record(scheduled, issued, ok);
+            }
+        } else {
+            let mut row = vec![key];
+            row.extend((1..columns).map(|_| DataType::from(rng.gen::<i32>())));
+            let ok = table.insert(row).is_ok();
+            if scheduled >= measure_from {
+                writes.record(scheduled, issued, ok);
+            }
+        }
+    }
+
+    (reads, writes)
+}
+
+fn main() {
+    let args = App::new("noria-load")
+        .version("0.0.1")
+        .about("Generates a read/write workload against a Noria deployment and reports latencies.")
+        .after_help(
+            "Writes insert rows into the table whose first column is the key, and whose other \
+             columns are random integers. Reads look up a key in the view.",
+        )
+        .arg(
+            Arg::with_name("zookeeper")
+                .short("z")
+                .long("zookeeper")
+                .takes_value(true)
+                .default_value("127.0.0.1:2181")
+                .help("Zookeeper connection info."),
+        )
+        .arg(
+            Arg::with_name("deployment")
+                .long("deployment")
+                .short("d")
+                .required(true)
+                .takes_value(true)
+                .help("Noria deployment ID to generate load against."),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .takes_value(true)
+                .env("NORIA_API_TOKEN")
+                .help("API token to authenticate with, if the controller requires one."),
+        )
+        .arg(
+            Arg::with_name("table")
+                .long("table")
+                .required(true)
+                .takes_value(true)
+                .help("Base table to write to."),
+        )
+        .arg(
+            Arg::with_name("view")
+                .long("view")
+                .required(true)
+                .takes_value(true)
+                .help("View to read from."),
+        )
+        .arg(
+            Arg::with_name("keys")
+                .long("keys")
+                .takes_value(true)
+                .default_value("100000")
+                .help("Number of distinct keys to read and write."),
+        )
+        .arg(
+            Arg::with_name("distribution")
+                .long("distribution")
+                .takes_value(true)
+                .default_value("zipf:1.08")
+                .help("Key distribution: uniform, or zipf:<skew>."),
+        )
+        .arg(
+            Arg::with_name("read-ratio")
+                .long("read-ratio")
+                .takes_value(true)
+                .default_value("0.95")
+                .help("Fraction of operations that are reads."),
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .takes_value(true)
+                .default_value("0")
+                .help("Target operations per second across all threads [0 = as fast as possible]."),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .default_value("4")
+                .help("Number of load generator threads."),
+        )
+        .arg(
+            Arg::with_name("warmup")
+                .long("warmup")
+                .takes_value(true)
+                .default_value("10")
+                .help("Time to generate load for before measuring, in seconds."),
+        )
+        .arg(
+            Arg::with_name("runtime")
+                .short("r")
+                .long("runtime")
+                .takes_value(true)
+                .default_value("30")
+                .help("Time to measure for, in seconds."),
+        )
+        .get_matches();
+
+    let threads = value_t_or_exit!(args, "threads", usize);
+    let ops = value_t_or_exit!(args, "target", f64);
+    let keys = value_t_or_exit!(args, "keys", usize);
+    if keys == 0 {
+        eprintln!("there must be at least one key");
+        process::exit(1);
+    }
+    let read_ratio = value_t_or_exit!(args, "read-ratio", f64);
+    if read_ratio < 0.0 || read_ratio > 1.0 {
+        eprintln!("read ratio must be between 0 and 1");
+        process::exit(1);
+    }
+    let skew = match args.value_of("distribution").unwrap() {
+        "uniform" => None,
+        d if d.starts_with("zipf:") => match d["zipf:".len()..].parse::<f64>() {
+            Ok(skew) => Some(skew),
+            Err(_) => {
+                eprintln!("invalid zipf skew: {}", d);
+                process::exit(1);
+            }
+        },
+        d => {
+            eprintln!("unknown key distribution: {}", d);
+            process::exit(1);
+        }
+    };
+
+    let runtime = Duration::from_secs(value_t_or_exit!(args, "runtime", u64));
+    let workload = Workload {
+        table: args.value_of("table").unwrap().to_owned(),
+        view: args.value_of("view").unwrap().to_owned(),
+        keys,
+        skew,
+        read_ratio,
+        interval: if ops > 0.0 {
+            Some(Duration::from_nanos((1e9 * threads as f64 / ops) as u64))
+        } else {
+            None
+        },
+        warmup: Duration::from_secs(value_t_or_exit!(args, "warmup", u64)),
+        runtime,
+    };
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let authority = ZookeeperAuthority::for_deployment(
+        args.value_of("zookeeper").unwrap(),
+        args.value_of("deployment").unwrap(),
+    )
+    .unwrap();
+    let ch = match args.value_of("token") {
+        Some(token) => SyncControllerHandle::new_with_token(authority, token, rt.executor()),
+        None => SyncControllerHandle::new(authority, rt.executor()),
+    }
+    .unwrap_or_else(|e| {
+        eprintln!("could not connect to deployment: {:?}", e);
+        process::exit(1);
+    });
+
+    let generators: Vec<_> = (0..threads)
+        .map(|i| {
+            let ch = ch.clone();
+            let workload = workload.clone();
+            thread::Builder::new()
+                .name(format!("load-gen{}", i))
+                .spawn(move || generate(ch, workload))
+                .unwrap()
+        })
+        .collect();
+
+    let mut reads = Latencies::new();
+    let mut writes = Latencies::new();
+    for g in generators {
+        let (r, w) = g.join().unwrap();
+        reads.add(&r);
+        writes.add(&w);
+    }
+
+    println!("# op\tpct\tsojourn\tremote");
+    writes.report("write", runtime);
+    reads.report("read", runtime);
+}
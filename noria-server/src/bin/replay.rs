@@ -0,0 +1,83 @@
+extern crate clap;
+extern crate noria;
+extern crate tokio;
+
+use noria::recording;
+use noria::{SyncControllerHandle, ZookeeperAuthority};
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+
+fn main() {
+    use clap::{App, Arg};
+    let matches = App::new("noria-replay")
+        .version("0.0.1")
+        .about("Replays a recorded client workload against a Noria deployment.")
+        .arg(
+            Arg::with_name("zookeeper")
+                .short("z")
+                .long("zookeeper")
+                .takes_value(true)
+                .default_value("127.0.0.1:2181")
+                .help("Zookeeper connection info."),
+        )
+        .arg(
+            Arg::with_name("deployment")
+                .long("deployment")
+                .short("d")
+                .required(true)
+                .takes_value(true)
+                .help("Noria deployment ID to replay against."),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .takes_value(true)
+                .env("NORIA_API_TOKEN")
+                .help("API token to authenticate with, if the controller requires one."),
+        )
+        .arg(
+            Arg::with_name("speed")
+                .long("speed")
+                .takes_value(true)
+                .help("Speed relative to the recording [default: as fast as possible]."),
+        )
+        .arg(
+            Arg::with_name("recording")
+                .required(true)
+                .index(1)
+                .help("Recording to replay."),
+        )
+        .get_matches();
+
+    let deployment = matches.value_of("deployment").unwrap();
+    let zookeeper_addr = matches.value_of("zookeeper").unwrap();
+    let speed = matches
+        .value_of("speed")
+        .map(|s| s.parse::<f64>().expect("speed must be a number"));
+    let recording = File::open(matches.value_of("recording").unwrap()).unwrap_or_else(|e| {
+        eprintln!("could not open recording: {}", e);
+        process::exit(1);
+    });
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let authority = ZookeeperAuthority::for_deployment(zookeeper_addr, deployment).unwrap();
+    let mut ch = match matches.value_of("token") {
+        Some(token) => SyncControllerHandle::new_with_token(authority, token, rt.executor()),
+        None => SyncControllerHandle::new(authority, rt.executor()),
+    }
+    .unwrap();
+
+    match recording::replay(&mut ch, BufReader::new(recording), speed) {
+        Ok(replayed) => {
+            println!(
+                "replayed {} writes and {} lookups; {} failed, at most {:?} behind schedule",
+                replayed.writes, replayed.lookups, replayed.failed, replayed.max_lag
+            );
+        }
+        Err(e) => {
+            eprintln!("replay failed: {:?}", e);
+            process::exit(1);
+        }
+    }
+}
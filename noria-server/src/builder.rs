@@ -1,12 +1,22 @@
+use crate::coordination::Capacity;
 use crate::handle::{Handle, SyncHandle};
+use crate::optimizer::QueryOptimizer;
+use crate::secrets::{Secrets, SecretsProvider};
+use crate::sim::Simulation;
+use crate::tls::TlsConfig;
+use crate::AuditConfig;
 use crate::Config;
 use crate::FrontierStrategy;
+use crate::RateLimits;
 use crate::ReuseConfigType;
-use dataflow::PersistenceParameters;
+use crate::Role;
+use dataflow::{DurabilityMode, Overflow, PersistenceParameters};
 use failure;
 use noria::consensus::{Authority, LocalAuthority};
 use slog;
+use std::collections::HashMap;
 use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time;
 use tokio::prelude::*;
@@ -16,7 +26,12 @@ pub struct Builder {
     config: Config,
     memory_limit: Option<usize>,
     memory_check_frequency: Option<time::Duration>,
+    labels: HashMap<String, String>,
+    capacity: Option<Capacity>,
+    reader_only: bool,
     listen_addr: IpAddr,
+    secrets: Option<Secrets>,
+    optimizer: Option<Arc<dyn QueryOptimizer>>,
     log: slog::Logger,
 }
 impl Default for Builder {
@@ -27,6 +42,11 @@ impl Default for Builder {
             log: slog::Logger::root(slog::Discard, o!()),
             memory_limit: None,
             memory_check_frequency: None,
+            labels: HashMap::new(),
+            capacity: None,
+            reader_only: false,
+            secrets: None,
+            optimizer: None,
         }
     }
 }
@@ -45,6 +65,53 @@ impl Builder {
         self.config.domain_config.replay_batch_timeout = t;
     }
 
+    /// Set how many threads a domain may use to concurrently feed a snapshot of its state into a
+    /// full (non-partial) replay path, such as when a new fully materialized view is added.
+    pub fn set_full_replay_threads(&mut self, n: usize) {
+        assert_ne!(n, 0);
+        self.config.domain_config.full_replay_threads = n;
+    }
+
+    /// Have every domain keep the last `n` packets it handled, and write them to disk if it
+    /// panics, or when asked to with `ControllerHandle::dump_packets`.
+    ///
+    /// The packets end up in the persistence log directory, one file per domain shard.
+    pub fn set_packet_capture(&mut self, n: usize) {
+        self.config.domain_config.capture_packets = n;
+    }
+
+    /// Suspend any operator that emits more than `n` records for each record it is given, so that
+    /// a query whose maintenance cost explodes cannot take down the deployment.
+    ///
+    /// A suspended operator drops the updates it is given, so the views below it stop changing.
+    /// They are listed by `ControllerHandle::suspended_views`, and stay suspended until the
+    /// queries they belong to are removed.
+    pub fn set_max_fanout(&mut self, n: usize) {
+        assert_ne!(n, 0);
+        self.config.domain_config.max_fanout = Some(n);
+    }
+
+    /// Let at most `n` packets wait in each domain to be sent to downstream domains, and deal with
+    /// any more as `overflow` says.
+    ///
+    /// How many packets wait, and how many writes were refused, is listed in each domain's
+    /// statistics.
+    pub fn set_queue_limit(&mut self, n: usize, overflow: Overflow) {
+        assert_ne!(n, 0);
+        self.config.domain_config.max_queue = Some(n);
+        self.config.domain_config.overflow = overflow;
+    }
+
+    /// Let the full state kept for each input to a join take up at most `bytes` bytes of memory,
+    /// and keep the rest of it on disk.
+    ///
+    /// The state is split into partitions by join key, and the partitions that were used least
+    /// recently are the ones moved to disk. They are moved back once there is room for them again.
+    pub fn set_join_state_limit(&mut self, bytes: u64) {
+        assert_ne!(bytes, 0);
+        self.config.domain_config.max_join_state = Some(bytes);
+    }
+
     /// Set the persistence parameters used by the system.
     pub fn set_persistence(&mut self, p: PersistenceParameters) {
         self.config.persistence = p;
@@ -80,6 +147,32 @@ impl Builder {
         self.memory_check_frequency = Some(check_freq);
     }
 
+    /// Label the worker with `value` for `label`, such as `zone=us-east-1a` or `disk=ssd`.
+    ///
+    /// Tables and queries in the recipe can ask to only be placed on workers with certain labels,
+    /// or to be spread across the values of a label.
+    pub fn add_label(&mut self, label: &str, value: &str) {
+        self.labels.insert(label.to_owned(), value.to_owned());
+    }
+
+    /// Tell the controller that the worker has `cores` cores and `memory` bytes of memory, so that
+    /// it is given a share of the domains that is in proportion to what the other workers have.
+    ///
+    /// Workers have a single core by default, and as much memory as their memory limit.
+    pub fn set_capacity(&mut self, cores: usize, memory: usize) {
+        assert_ne!(cores, 0);
+        self.capacity = Some(Capacity { cores, memory });
+    }
+
+    /// Only run readers on this worker, and none of the base tables or operators that feed them.
+    ///
+    /// Once any such worker has joined, each new reader is put in a domain of its own, and those
+    /// domains are placed on reader-only workers, while all other domains are kept off them. This
+    /// lets read capacity be added without moving any of the state that writes go through.
+    pub fn set_reader_only(&mut self) {
+        self.reader_only = true;
+    }
+
     /// Set the IP address that the worker should use for listening.
     pub fn set_listen_addr(&mut self, listen_addr: IpAddr) {
         self.listen_addr = listen_addr;
@@ -100,6 +193,158 @@ impl Builder {
         self.config.threads = Some(threads);
     }
 
+    /// Require clients of the controller's external API to authenticate with an API token, and
+    /// grant the given token the given role.
+    ///
+    /// Once a token has been added, requests that do not carry a known token are rejected. The
+    /// handle returned when starting the server uses an `Admin` token if one has been added.
+    pub fn add_api_token(&mut self, token: &str, role: Role) {
+        self.config
+            .auth
+            .get_or_insert_with(Default::default)
+            .add_token(token.to_owned(), role);
+    }
+
+    /// Encrypt connections to and within the deployment with TLS, using the PEM-encoded
+    /// certificate chain and private key in the given files.
+    ///
+    /// This covers the controller's external API, clients' connections to views and base tables,
+    /// and the connections between workers, the controller, and domains. Since workers are
+    /// addressed by IP, the certificate must be issued for `name`, which those connections check
+    /// it against instead. Every server in the deployment should use the same name.
+    ///
+    /// The files are re-read when they change, so certificates can be rotated in place; existing
+    /// connections keep the certificate they were set up with. If the certificate is not signed by
+    /// a well-known authority, `ca` should point to the certificate that signed it, so that
+    /// servers, and the handle returned when starting the server, can verify it.
+    pub fn set_tls(&mut self, name: &str, cert: &Path, key: &Path, ca: Option<&Path>) {
+        self.config.tls = Some(TlsConfig {
+            name: name.to_owned(),
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+            ca: ca.map(PathBuf::from),
+        });
+    }
+
+    /// Limit the resources each security universe may use.
+    ///
+    /// Universes may install at most `max_queries` queries. Universes whose own nodes hold more
+    /// than `max_memory` bytes of state have their partially materialized state evicted first.
+    pub fn set_universe_quota(&mut self, max_queries: Option<usize>, max_memory: Option<u64>) {
+        self.config.universe_quota.max_queries = max_queries;
+        self.config.universe_quota.max_memory = max_memory;
+    }
+
+    /// Abort migrations that take longer than `timeout`.
+    ///
+    /// A migration that has not finished when the timeout runs out, for example because a worker
+    /// that it replays through died, is undone like one that fails: the domains it booted are shut
+    /// down, the nodes it added are removed, and the change that started it fails with an error.
+    /// Booting domains is not cut short, and changes already made to the columns of existing base
+    /// tables are not undone.
+    pub fn set_migration_timeout(&mut self, timeout: time::Duration) {
+        self.config.migration_timeout = Some(timeout);
+    }
+
+    /// Limit the load that each client may place on the workers.
+    ///
+    /// Reads and writes beyond the given per-second rates, and blocking reads beyond the given
+    /// number of concurrent replays, are refused with a `RateLimited` error. Clients are
+    /// identified by their IP address.
+    pub fn set_rate_limits(
+        &mut self,
+        reads_per_sec: Option<u32>,
+        writes_per_sec: Option<u32>,
+        concurrent_replays: Option<usize>,
+    ) {
+        self.config.rate_limits = RateLimits {
+            reads_per_sec,
+            writes_per_sec,
+            concurrent_replays,
+        };
+    }
+
+    /// Require connections between domains, the controller, and workers to authenticate with the
+    /// given shared secret.
+    ///
+    /// Every worker in the deployment must be given the same secret. Both ends of a connection
+    /// prove that they know it by answering a random challenge from the other, so the secret
+    /// itself is never sent over the network. Clients' connections to views and base tables must
+    /// also authenticate, with keys derived from the secret that the controller hands out along
+    /// with the views and tables.
+    ///
+    /// Every message sent after that carries an HMAC under a key derived from the handshake, so
+    /// messages that are forged, altered, or replayed by an attacker on the network path are
+    /// rejected, and the connection is dropped. Use `set_tls` to also encrypt them.
+    pub fn set_domain_secret(&mut self, secret: &str) {
+        self.config.domain_secret = Some(secret.to_owned());
+    }
+
+    /// Record reads from views in an audit trail.
+    ///
+    /// Each worker appends a JSON object per recorded read to the file at `path`, or writes it to
+    /// standard output if `path` is `-`. Records name the client, the view and its security
+    /// universe, and the keys that were read. Only a `sample` fraction of reads is recorded.
+    pub fn set_read_audit(&mut self, path: &Path, sample: f64) {
+        assert!(sample > 0.0 && sample <= 1.0);
+        self.config.audit = Some(AuditConfig {
+            path: path.to_path_buf(),
+            sample,
+        });
+    }
+
+    /// Simulate a deployment with this configuration, seeded with `seed`, instead of starting it.
+    ///
+    /// The controller, the workers added to the simulation, and the network between them all run
+    /// on the calling thread, and whenever more than one of them can make progress, the seed
+    /// decides which one goes next. Timers fire when nothing else can run, rather than after
+    /// wall-clock time has passed. Base tables are kept in memory.
+    ///
+    /// This is meant for tests that need to reproduce the order in which the parts of a deployment
+    /// interleave, including through migrations and failures.
+    pub fn simulate(&self, seed: u64) -> Simulation {
+        let mut config = self.config.clone();
+        // nothing may run on threads of its own, or the seed would not decide what runs when
+        config.domain_config.full_replay_threads = 0;
+        config.persistence.mode = DurabilityMode::MemoryOnly;
+        config.persistence.background_sync = None;
+        Simulation::new(config, seed, self.log.clone())
+    }
+
+    /// Declare the base tables that installed queries read from, but that the recipe does not.
+    ///
+    /// Such tables are given the columns that the queries refer to them by, with the types of any
+    /// literals the columns are compared with. The declarations are added to the recipe, so they
+    /// show up in it and outlive the controller. This is meant for trying out queries before a
+    /// schema has been settled on.
+    pub fn enable_table_inference(&mut self) {
+        self.config.infer_tables = true;
+    }
+
+    /// Fetch API tokens and the domain secret from `provider` when the server starts.
+    ///
+    /// API tokens from the provider are accepted in addition to any added with `add_api_token`,
+    /// and a domain secret from the provider takes precedence over one set with
+    /// `set_domain_secret`. If `refresh_every` is given, API tokens are fetched again at that
+    /// interval so that they can be rotated without restarting the server. Secrets are never
+    /// written to the authority.
+    pub fn set_secrets_provider(
+        &mut self,
+        provider: Arc<dyn SecretsProvider>,
+        refresh_every: Option<time::Duration>,
+    ) {
+        self.secrets = Some(Secrets::new(provider, refresh_every));
+    }
+
+    /// Let `optimizer` rewrite the queries that the controller adds, and the plans it makes for
+    /// them, before they are turned into dataflow nodes.
+    ///
+    /// The optimizer is not written to the authority, so each server that may become the
+    /// controller must be given it.
+    pub fn set_query_optimizer(&mut self, optimizer: Arc<dyn QueryOptimizer>) {
+        self.optimizer = Some(optimizer);
+    }
+
     /// Start a server instance and return a handle to it.
     #[must_use]
     pub fn start<A: Authority + 'static>(
@@ -111,10 +356,22 @@ impl Builder {
             ref config,
             memory_limit,
             memory_check_frequency,
+            ref labels,
+            capacity,
+            reader_only,
+            ref secrets,
+            ref optimizer,
             ref log,
         } = *self;
 
         let config = config.clone();
+        let labels = labels.clone();
+        let capacity = capacity.unwrap_or_else(|| Capacity {
+            memory: memory_limit.unwrap_or(0),
+            ..Capacity::default()
+        });
+        let secrets = secrets.clone();
+        let optimizer = optimizer.clone();
         let log = log.clone();
         future::lazy(move || {
             crate::startup::start_instance(
@@ -123,6 +380,11 @@ impl Builder {
                 config,
                 memory_limit,
                 memory_check_frequency,
+                labels,
+                capacity,
+                reader_only,
+                secrets,
+                optimizer,
                 log,
             )
         })
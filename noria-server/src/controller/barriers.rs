@@ -0,0 +1,55 @@
+//! How snapshot barriers spread through the data-flow graph.
+//!
+//! A barrier is injected at every shard of every base table, and copied at each node to all of its
+//! children, so a reader sees one copy of it for every path that leads to it from a base table.
+//! Paths multiply where every shard of one domain sends to a single shard of another, so readers
+//! must be told how many copies to wait for before they know that every write ahead of the barrier
+//! has reached them.
+
+use dataflow::prelude::*;
+use std::collections::HashMap;
+
+/// How many copies of a barrier injected at every base table each shard of each reader receives.
+pub(super) fn arrivals(
+    graph: &Graph,
+    source: NodeIndex,
+    shards: &HashMap<DomainIndex, usize>,
+) -> HashMap<NodeIndex, usize> {
+    let mut copies: HashMap<NodeIndex, usize> = HashMap::new();
+    let mut topo = petgraph::visit::Topo::new(graph);
+    while let Some(ni) = topo.next(graph) {
+        let n = &graph[ni];
+        if ni == source || n.is_dropped() || !n.has_domain() {
+            continue;
+        }
+
+        let mut c = 0;
+        for pi in graph.neighbors_directed(ni, petgraph::EdgeDirection::Incoming) {
+            let p = &graph[pi];
+            if pi == source {
+                // every shard of a base table gets a copy of its own
+                c += 1;
+                continue;
+            }
+            let from = match copies.get(&pi) {
+                Some(&from) => from,
+                None => continue,
+            };
+
+            // each shard of a sharder sends to every shard below it, and each shard of an egress
+            // sends either to the same shard below it, or to the only one.
+            let fanin = if p.domain() != n.domain()
+                && (p.is_sharder() || shards.get(&n.domain()) == Some(&1))
+            {
+                shards.get(&p.domain()).cloned().unwrap_or(1)
+            } else {
+                1
+            };
+            c += from * fanin;
+        }
+        copies.insert(ni, c);
+    }
+
+    copies.retain(|&ni, _| graph[ni].is_reader());
+    copies
+}
@@ -1,28 +1,52 @@
+use crate::controller::barriers;
 use crate::controller::domain_handle::{DomainHandle, DomainShardHandle};
+use crate::controller::invariants;
+use crate::controller::journal::{self, Change};
+use crate::controller::links::Subscription;
 use crate::controller::migrate::materialization::Materializations;
+use crate::controller::migrate::Checkpoint;
+use crate::controller::placement::{self, Candidate, Placement};
+use crate::controller::prepared;
+use crate::controller::progress::{self, MigrationTracker};
 use crate::controller::recipe::Schema;
+use crate::controller::scheduler::MigrationQueue;
 use crate::controller::schema;
+use crate::controller::shadow::{self, Shadow};
+use crate::controller::sql::SqlIncorporator;
 use crate::controller::{ControllerState, Migration, Recipe};
-use crate::controller::{Worker, WorkerIdentifier};
-use crate::coordination::{CoordinationMessage, CoordinationPayload, DomainDescriptor};
+use crate::controller::{Environment, GroupMembershipUpdate, MembershipWrite};
+use crate::controller::{Worker, WorkerIdentifier, WorkerSender};
+use crate::coordination::{Capacity, CoordinationMessage, CoordinationPayload, DomainDescriptor};
+use crate::optimizer::QueryOptimizer;
+use crate::UniverseQuota;
+use dataflow::payload::{BarrierKind, ControlReplyPacket};
 use dataflow::prelude::*;
-use dataflow::{node, payload::ControlReplyPacket, prelude::Packet, DomainBuilder, DomainConfig};
+use dataflow::{node, prelude::Packet, Clock, DomainBuilder, DomainConfig};
 use hyper::{self, Method, StatusCode};
 use mio::net::TcpListener;
-use nom_sql::ColumnSpecification;
+use nom_sql::parser as sql_parser;
+use nom_sql::{ColumnSpecification, SqlQuery};
 use noria::builders::*;
-use noria::channel::tcp::{SendError, TcpSender};
+use noria::channel::auth;
+use noria::channel::tcp::SendError;
+use noria::cluster::{Catalog, CatalogEntry, DomainInfo, MigrationPlan, MigrationStatus};
+use noria::cluster::{MigrationProgress, MigrationResult, MigrationStep, SubmittedMigration};
+use noria::cluster::{MoveReport, PlacementPlan, PlannedDomain, PlannedWorker, ReplayPath};
+use noria::cluster::{WorkerChange, WorkerInfo};
 use noria::consensus::{Authority, Epoch, STATE_KEY};
-use noria::debug::stats::{DomainStats, GraphStats, NodeStats};
-use noria::ActivationResult;
+use noria::debug::invariants::Violation;
+use noria::debug::stats::{DomainStats, GraphStats, NodeStats, QueryUsage};
+use noria::prepared::StatementPlan;
+use noria::{ActivationResult, ConfigUpdate, ShadowReport};
 use petgraph::visit::Bfs;
 use slog::Logger;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
 use std::mem;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use std::{cell, io, thread, time};
+use std::{cell, io, time};
 use tokio::prelude::*;
 
 /// `Controller` is the core component of the alternate Soup implementation.
@@ -38,6 +62,9 @@ pub(super) struct ControllerInner {
     pub(super) sharding: Option<usize>,
 
     pub(super) domain_config: DomainConfig,
+    /// The memory limit that workers were last told to enforce, or 0 for no limit, if it has been
+    /// changed since they started.
+    memory_limit: Option<usize>,
 
     /// Parameters for persistence code.
     pub(super) persistence: PersistenceParameters,
@@ -50,19 +77,42 @@ pub(super) struct ControllerInner {
     pub(in crate::controller) domain_nodes: HashMap<DomainIndex, Vec<NodeIndex>>,
     pub(super) channel_coordinator: Arc<ChannelCoordinator>,
     pub(super) debug_channel: Option<SocketAddr>,
+    /// How to connect to workers that register.
+    connect: Box<dyn Fn(&SocketAddr) -> io::Result<(WorkerSender, SocketAddr)> + Send>,
+    /// What the controller tells the time by.
+    pub(super) clock: Arc<dyn Clock>,
 
     /// Map from worker address to the address the worker is listening on for reads.
     read_addrs: HashMap<WorkerIdentifier, SocketAddr>,
     pub(super) workers: HashMap<WorkerIdentifier, Worker>,
 
+    /// Constraints on where the domains that nodes are in may be placed.
+    pub(super) placements: HashMap<NodeIndex, Placement>,
+
     /// State between migrations
     pub(super) remap: HashMap<DomainIndex, HashMap<NodeIndex, IndexPair>>,
 
     pub(super) epoch: Epoch,
 
-    pending_recovery: Option<(Vec<String>, usize)>,
+    /// The recipes to apply, the queries to remove in between, and the version the last of them
+    /// should end up at, once enough workers have registered after this controller took over.
+    pending_recovery: Option<(Vec<String>, Vec<(usize, String)>, usize)>,
+    /// The number of recorded recipe changes to make again instead, if every change was recorded
+    /// in the journal.
+    pending_journal: Option<usize>,
+    /// The number of entries in the journal, if changes are journalled.
+    journal: Option<usize>,
+    /// The recipe version that each of the persisted recipes brings the recipe up to.
+    persisted_versions: Vec<usize>,
+    /// What the migrations that were committed since the recipe last changed did.
+    pub(super) committed: Vec<journal::Committed>,
 
     quorum: usize,
+    /// How long a migration may take before it is aborted, if there is a limit.
+    pub(super) migration_timeout: Option<Duration>,
+    /// Whether the next migration should fail, and if so whether by panicking.
+    #[cfg(test)]
+    pub(super) fail_next_migration: Option<bool>,
     heartbeat_every: Duration,
     healthcheck_every: Duration,
     last_checked_workers: Instant,
@@ -70,26 +120,105 @@ pub(super) struct ControllerInner {
     log: slog::Logger,
 
     pub(in crate::controller) replies: DomainReplies,
+
+    /// The nodes added by each security universe's migrations, which its quota applies to.
+    pub(super) universe_nodes: HashMap<String, Vec<NodeIndex>>,
+    universe_quota: UniverseQuota,
+    /// Whether universe quotas are due to be enforced.
+    quotas_due: bool,
+    /// Whether queries may read from base tables that the recipe does not declare.
+    infer_tables: bool,
+
+    /// The proposed recipe that is currently running in the shadow of the live one, if any.
+    shadow: Option<Shadow>,
+
+    /// The identifier of the last snapshot barrier that was injected.
+    last_snapshot: u64,
+
+    /// The running links that feed base tables from views in other deployments, by table.
+    links: HashMap<String, Subscription>,
+
+    /// The eviction weight of each view that does not have the default weight of 1.
+    eviction_weights: HashMap<String, u32>,
+    /// How long the reader of each view that does not make writes visible right away holds on to
+    /// them.
+    publish_intervals: HashMap<String, Duration>,
+
+    /// The recipe extensions that clients have submitted to be applied in the background.
+    migrations: MigrationQueue,
+    /// How far the migration that is running, and those that ran before it, have got.
+    pub(super) progress: MigrationTracker,
 }
 
-pub(in crate::controller) struct DomainReplies(
-    futures::sync::mpsc::UnboundedReceiver<ControlReplyPacket>,
-);
+pub(in crate::controller) struct DomainReplies {
+    rx: futures::sync::mpsc::UnboundedReceiver<ControlReplyPacket>,
+    /// When waiting for domains to acknowledge gives up, if it does.
+    deadline: Option<Instant>,
+    /// How many replies that were given up on each domain may still send.
+    late: HashMap<DomainIndex, usize>,
+    clock: Arc<dyn Clock>,
+    /// What to do while there are no replies to read.
+    idle: Box<dyn FnMut() + Send>,
+}
 
 impl DomainReplies {
-    fn read_n_domain_replies(&mut self, n: usize) -> Vec<ControlReplyPacket> {
+    fn new(
+        rx: futures::sync::mpsc::UnboundedReceiver<ControlReplyPacket>,
+        clock: Arc<dyn Clock>,
+        idle: Box<dyn FnMut() + Send>,
+    ) -> Self {
+        DomainReplies {
+            rx,
+            deadline: None,
+            late: HashMap::new(),
+            clock,
+            idle,
+        }
+    }
+
+    /// Give up waiting for domains to acknowledge at `deadline`, or never if it is `None`.
+    pub(in crate::controller) fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Stop expecting the replies given up on from domain `d`, which has been shut down.
+    pub(in crate::controller) fn forget(&mut self, d: DomainIndex) {
+        self.late.remove(&d);
+    }
+
+    /// Read `n` replies, or, if `deadline` passes first, return how many are missing.
+    fn read_n_domain_replies(
+        &mut self,
+        n: usize,
+        deadline: Option<Instant>,
+    ) -> Result<Vec<ControlReplyPacket>, usize> {
         let mut crps = Vec::with_capacity(n);
 
         // TODO
         // TODO: it's so stupid to spin here now...
         // TODO
         loop {
-            match self.0.poll() {
-                Ok(Async::NotReady) => thread::yield_now(),
+            match self.rx.poll() {
+                Ok(Async::NotReady) => {
+                    if deadline.map(|d| self.clock.now() >= d).unwrap_or(false) {
+                        return Err(n - crps.len());
+                    }
+                    (self.idle)()
+                }
                 Ok(Async::Ready(Some(crp))) => {
+                    // replies that were given up on are told apart only by when they arrive
+                    if let Some(d) = self.late.keys().next().cloned() {
+                        let late = self.late.get_mut(&d).unwrap();
+                        *late -= 1;
+                        if *late == 0 {
+                            self.late.remove(&d);
+                        }
+                        continue;
+                    }
+
                     crps.push(crp);
                     if crps.len() == n {
-                        return crps;
+                        return Ok(crps);
                     }
                 }
                 Ok(Async::Ready(None)) => {
@@ -102,13 +231,46 @@ impl DomainReplies {
         }
     }
 
-    pub(in crate::controller) fn wait_for_acks(&mut self, d: &DomainHandle) {
-        for r in self.read_n_domain_replies(d.shards()) {
+    /// Read a reply from each shard of `d`, giving up at the deadline.
+    fn wait_for(&mut self, d: &DomainHandle) -> Result<Vec<ControlReplyPacket>, String> {
+        let deadline = self.deadline;
+        self.read_n_domain_replies(d.shards(), deadline)
+            .map_err(|missing| {
+                *self.late.entry(d.index()).or_insert(0) += missing;
+                format!(
+                    "timed out waiting for {} shard(s) of domain {} to reply",
+                    missing,
+                    d.index().index()
+                )
+            })
+    }
+
+    pub(in crate::controller) fn wait_for_acks(&mut self, d: &DomainHandle) -> Result<(), String> {
+        for r in self.wait_for(d)? {
             match r {
                 ControlReplyPacket::Ack(_) => {}
                 r => unreachable!("got unexpected non-ack control reply: {:?}", r),
             }
         }
+        Ok(())
+    }
+
+    /// Sum up the sizes of the state of a node that each shard of `d` reports.
+    pub(in crate::controller) fn wait_for_state_size(
+        &mut self,
+        d: &DomainHandle,
+    ) -> Result<(usize, u64), String> {
+        let mut size = (0, 0);
+        for r in self.wait_for(d)? {
+            match r {
+                ControlReplyPacket::StateSize(rows, bytes) => {
+                    size.0 += rows;
+                    size.1 += bytes;
+                }
+                r => unreachable!("got unexpected non-size control reply: {:?}", r),
+            }
+        }
+        Ok(size)
     }
 
     fn wait_for_statistics(
@@ -116,7 +278,8 @@ impl DomainReplies {
         d: &DomainHandle,
     ) -> Vec<(DomainStats, HashMap<NodeIndex, NodeStats>)> {
         let mut stats = Vec::with_capacity(d.shards());
-        for r in self.read_n_domain_replies(d.shards()) {
+        // statistics are not asked for while a migration runs, so this never gives up
+        for r in self.read_n_domain_replies(d.shards(), None).unwrap() {
             match r {
                 ControlReplyPacket::Statistics(d, s) => stats.push((d, s)),
                 r => unreachable!("got unexpected non-stats control reply: {:?}", r),
@@ -130,6 +293,16 @@ pub(super) fn graphviz(
     graph: &Graph,
     detailed: bool,
     materializations: &Materializations,
+) -> String {
+    annotated_graphviz(graph, detailed, materializations, &[])
+}
+
+/// Like `graphviz`, but with each of `notes` attached to its node.
+fn annotated_graphviz(
+    graph: &Graph,
+    detailed: bool,
+    materializations: &Materializations,
+    notes: &[(NodeIndex, String)],
 ) -> String {
     let mut s = String::new();
 
@@ -175,6 +348,22 @@ pub(super) fn graphviz(
         s.push_str("\n");
     }
 
+    // notes.
+    for (i, &(index, ref note)) in notes.iter().enumerate() {
+        indentln(&mut s);
+        s.push_str(&format!(
+            "note{} [ shape=note, label=\"{}\" ]\n",
+            i,
+            note.replace('"', "\\\"")
+        ));
+        indentln(&mut s);
+        s.push_str(&format!(
+            "note{} -> n{} [ style=dashed, arrowhead=none ]\n",
+            i,
+            index.index()
+        ));
+    }
+
     // footer.
     s.push_str("}}");
 
@@ -225,6 +414,17 @@ impl ControllerInner {
             (&Method::POST, "/get_statistics") => {
                 return Ok(Ok(json::to_string(&self.get_statistics()).unwrap()));
             }
+            (&Method::GET, "/workers") | (&Method::POST, "/workers") => {
+                return Ok(Ok(json::to_string(&self.list_workers()).unwrap()));
+            }
+            (&Method::GET, "/migrations") | (&Method::POST, "/migrations") => {
+                return Ok(Ok(json::to_string(&self.migration_status()).unwrap()));
+            }
+            (&Method::POST, "/migration_progress") => {
+                return json::from_slice(&body)
+                    .map_err(|_| StatusCode::BAD_REQUEST)
+                    .map(|ticket| Ok(json::to_string(&self.migration_progress(ticket)).unwrap()));
+            }
             _ => {}
         }
 
@@ -236,9 +436,67 @@ impl ControllerInner {
             (Method::GET, "/flush_partial") => {
                 Ok(Ok(json::to_string(&self.flush_partial()).unwrap()))
             }
+            (Method::POST, "/universe_usage") => {
+                Ok(Ok(json::to_string(&self.universe_usage()).unwrap()))
+            }
+            (Method::POST, "/snapshot") => {
+                Ok(self.snapshot().map(|id| json::to_string(&id).unwrap()))
+            }
+            (Method::POST, "/export_view") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|name: String| {
+                    self.export_view(&name)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/propagation") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| self.propagation(args).map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/open_write_group") => Ok(self
+                .open_write_group()
+                .map(|id| json::to_string(&id).unwrap())),
+            (Method::POST, "/close_write_group") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(id, abort)| {
+                    self.close_write_group(id, abort)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/probe") => Ok(self.probe().map(|id| json::to_string(&id).unwrap())),
+            (Method::POST, "/probe_latencies") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|id| Ok(json::to_string(&self.probe_latencies(id)).unwrap())),
+            (Method::POST, "/check_invariants") => {
+                Ok(Ok(json::to_string(&self.check_invariants()).unwrap()))
+            }
+            (Method::POST, "/dump_packets") => {
+                Ok(Ok(json::to_string(&self.dump_packets()).unwrap()))
+            }
             (Method::POST, "/inputs") => Ok(Ok(json::to_string(&self.inputs()).unwrap())),
             (Method::POST, "/outputs") => Ok(Ok(json::to_string(&self.outputs()).unwrap())),
             (Method::GET, "/instances") => Ok(Ok(json::to_string(&self.get_instances()).unwrap())),
+            (Method::GET, "/domains") | (Method::POST, "/domains") => {
+                Ok(Ok(json::to_string(&self.list_domains()).unwrap()))
+            }
+            (Method::GET, "/catalog") | (Method::POST, "/catalog") => {
+                Ok(Ok(json::to_string(&self.catalog()).unwrap()))
+            }
+            (Method::GET, "/suspended_views") | (Method::POST, "/suspended_views") => {
+                Ok(Ok(json::to_string(&self.suspended_views()).unwrap()))
+            }
+            (Method::POST, "/drain_worker") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|worker| {
+                    self.drain_worker(worker)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/rebalance") => {
+                Ok(self.rebalance().map(|r| json::to_string(&r).unwrap()))
+            }
+            (Method::POST, "/plan_workers") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|change| {
+                    self.plan_workers(change)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
             (Method::GET, "/nodes") => {
                 // TODO(malte): this is a pretty yucky hack, but hyper doesn't provide easy access
                 // to individual query variables unfortunately. We'll probably want to factor this
@@ -279,18 +537,75 @@ impl ControllerInner {
             (Method::POST, "/view_builder") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| Ok(json::to_string(&self.view_builder(args)).unwrap())),
+            (Method::POST, "/replay_paths") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|name: String| {
+                    self.replay_paths(&name)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
             (Method::POST, "/extend_recipe") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
                     self.extend_recipe(authority, args)
                         .map(|r| json::to_string(&r).unwrap())
                 }),
+            (Method::POST, "/submit_migration") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.migrations
+                        .submit(args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/submitted_migration") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|ticket| {
+                    self.migrations
+                        .status(ticket)
+                        .ok_or_else(|| format!("no migration was submitted as {}", ticket))
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/plan_recipe") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| self.plan_recipe(args).map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/rollback_migration") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|version| {
+                    self.rollback_migration(authority, version)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/remove_query") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|name| {
+                    self.remove_query(authority, name)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/prepare") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|sql| {
+                    self.prepare(authority, sql)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
             (Method::POST, "/install_recipe") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
                     self.install_recipe(authority, args)
                         .map(|r| json::to_string(&r).unwrap())
                 }),
+            (Method::POST, "/shadow_recipe") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.shadow_recipe(args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/shadow_report") => {
+                Ok(self.shadow_report().map(|r| json::to_string(&r).unwrap()))
+            }
+            (Method::POST, "/promote_shadow") => Ok(self
+                .promote_shadow(authority)
+                .map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/discard_shadow") => {
+                Ok(self.discard_shadow().map(|r| json::to_string(&r).unwrap()))
+            }
             (Method::POST, "/set_security_config") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
@@ -303,12 +618,60 @@ impl ControllerInner {
                     self.create_universe(args)
                         .map(|r| json::to_string(&r).unwrap())
                 }),
+            (Method::POST, "/remove_universe") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.remove_universe(args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/membership_write") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.membership_write(args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/group_membership") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.update_group_membership(args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
             (Method::POST, "/remove_node") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
                     self.remove_nodes(vec![args].as_slice())
                         .map(|r| json::to_string(&r).unwrap())
                 }),
+            (Method::POST, "/update_config") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.update_config(authority, args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/set_eviction_weight") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(view, weight)| {
+                    self.set_eviction_weight(authority, view, weight)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/set_publish_interval") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(view, every)| {
+                    self.set_publish_interval(authority, view, every)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            #[cfg(feature = "fault-injection")]
+            (Method::POST, "/inject_fault") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|fault| {
+                    self.broadcast(CoordinationPayload::InjectFault(fault));
+                    Ok(json::to_string(&()).unwrap())
+                }),
+            #[cfg(feature = "fault-injection")]
+            (Method::POST, "/clear_faults") => {
+                self.broadcast(CoordinationPayload::ClearFaults);
+                Ok(Ok(json::to_string(&()).unwrap()))
+            }
             _ => Err(StatusCode::NOT_FOUND),
         }
     }
@@ -318,31 +681,84 @@ impl ControllerInner {
         msg: &CoordinationMessage,
         remote: &SocketAddr,
         read_listen_addr: SocketAddr,
+        labels: HashMap<String, String>,
+        capacity: Capacity,
+        reader_only: bool,
     ) -> Result<(), io::Error> {
         info!(
             self.log,
-            "new worker registered from {:?}, which listens on {:?}", msg.source, remote
+            "new worker registered from {:?}, which listens on {:?}", msg.source, remote;
+            "labels" => ?labels,
+            "capacity" => ?capacity,
+            "reader_only" => reader_only
         );
 
-        let sender = TcpSender::connect(remote)?;
-        let ws = Worker::new(sender);
+        let sender = (self.connect)(remote)?;
+        let mut ws = Worker::new(sender, self.clock.now(), labels, capacity, reader_only);
+        if let Some(memory_limit) = self.memory_limit {
+            // the worker was started with the limit it was configured with, which may be stale
+            ws.sender
+                .send(CoordinationMessage {
+                    epoch: self.epoch,
+                    source: ws.source,
+                    payload: CoordinationPayload::UpdateConfig { memory_limit },
+                })
+                .unwrap();
+        }
         self.workers.insert(msg.source, ws);
         self.read_addrs.insert(msg.source, read_listen_addr);
 
+        Ok(())
+    }
+
+    /// Restore the graph that the previous controller had, once enough workers have registered.
+    ///
+    /// Fails if the graph that the recorded recipe changes make does not come out the way it did
+    /// the first time, in which case this controller must not take over.
+    pub(super) fn recover<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+    ) -> Result<(), String> {
         if self.workers.len() >= self.quorum {
-            if let Some((recipes, recipe_version)) = self.pending_recovery.take() {
+            if let Some(len) = self.pending_journal.take() {
+                assert_eq!(self.workers.len(), self.quorum);
+                assert_eq!(self.recipe.version(), 0);
+
+                info!(self.log, "Replaying recipe journal"; "changes" => len);
+                for i in 0..len {
+                    let entry = journal::read(&**authority, i)?;
+                    self.committed.clear();
+                    if let Err(e) = self.replay(&entry.change) {
+                        crit!(self.log, "recorded recipe change failed: {}", e);
+                        return Err(format!("recorded recipe change {} failed: {}", i, e));
+                    }
+                    if let Some(d) = journal::divergence(&entry.migrations, &self.committed) {
+                        crit!(self.log, "recovered graph differs from the recorded one";
+                              "change" => ?entry.change, "difference" => %d);
+                        return Err(format!("recovered graph differs at change {}: {}", i, d));
+                    }
+                }
+                self.committed.clear();
+            }
+            if let Some((recipes, removals, recipe_version)) = self.pending_recovery.take() {
                 assert_eq!(self.workers.len(), self.quorum);
                 assert_eq!(self.recipe.version(), 0);
-                assert!(recipe_version + 1 >= recipes.len());
+                assert!(recipe_version + 1 >= recipes.len() + removals.len());
 
                 info!(self.log, "Restoring graph configuration");
                 self.recipe = Recipe::with_version(
-                    recipe_version + 1 - recipes.len(),
+                    recipe_version + 1 - recipes.len() - removals.len(),
                     Some(self.log.clone()),
                 );
-                for r in recipes {
+                for (i, r) in recipes.into_iter().enumerate() {
                     self.apply_recipe(self.recipe.clone().extend(&r).unwrap())
                         .unwrap();
+                    self.persisted_versions.push(self.recipe.version());
+
+                    for (_, name) in removals.iter().filter(|&&(after, _)| after == i + 1) {
+                        let without = self.recipe.clone().without(&[name.clone()]);
+                        self.apply_recipe(without).unwrap();
+                    }
                 }
             }
         }
@@ -354,13 +770,18 @@ impl ControllerInner {
         let mut any_failed = false;
 
         // check if there are any newly failed workers
-        if self.last_checked_workers.elapsed() > self.healthcheck_every {
+        let now = self.clock.now();
+        if now.duration_since(self.last_checked_workers) > self.healthcheck_every {
             for (_addr, ws) in self.workers.iter() {
-                if ws.healthy && ws.last_heartbeat.elapsed() > self.heartbeat_every * 4 {
+                if ws.healthy && now.duration_since(ws.last_heartbeat) > self.heartbeat_every * 4 {
                     any_failed = true;
                 }
             }
-            self.last_checked_workers = Instant::now();
+            self.last_checked_workers = now;
+
+            // quotas are checked as often as workers are, but only after any failures are handled
+            self.quotas_due =
+                self.universe_quota.max_memory.is_some() && !self.universe_nodes.is_empty();
         }
 
         // if we have newly failed workers, iterate again to find all workers that have missed >= 3
@@ -369,7 +790,7 @@ impl ControllerInner {
         if any_failed {
             let mut failed = Vec::new();
             for (addr, ws) in self.workers.iter_mut() {
-                if ws.healthy && ws.last_heartbeat.elapsed() > self.heartbeat_every * 3 {
+                if ws.healthy && now.duration_since(ws.last_heartbeat) > self.heartbeat_every * 3 {
                     error!(self.log, "worker at {:?} has failed!", addr);
                     ws.healthy = false;
                     failed.push(addr.clone());
@@ -387,14 +808,26 @@ impl ControllerInner {
             affected_nodes.extend(self.get_failed_nodes(&wi));
         }
 
-        // then, figure out which queries are affected (and thus must be removed and added again in
-        // a migration)
-        let affected_queries = self.recipe.queries_for_nodes(affected_nodes);
-        let (recovery, mut original) = self.recipe.make_recovery(affected_queries);
+        self.rebuild_queries(affected_nodes)
+            .expect("failed to recover from worker failure");
+    }
+
+    /// Remove the queries that `nodes` belong to and add them again, so that their domains are
+    /// placed anew.
+    fn rebuild_queries(&mut self, nodes: Vec<NodeIndex>) -> Result<Vec<String>, String> {
+        // figure out which queries are affected (and thus must be removed and added again in a
+        // migration)
+        let mut affected_queries = self.recipe.queries_for_nodes(nodes);
+        affected_queries.sort();
+        affected_queries.dedup();
+        if affected_queries.is_empty() {
+            return Ok(affected_queries);
+        }
+        let (recovery, mut original) = self.recipe.make_recovery(affected_queries.clone());
 
         // activate recipe
-        self.apply_recipe(recovery.clone())
-            .expect("failed to apply recovery recipe");
+        self.apply_recipe(recovery)
+            .map_err(|e| format!("failed to apply recovery recipe: {}", e))?;
 
         // we must do this *after* the migration, since the migration itself modifies the recipe in
         // `recovery`, and we currently need to clone it here.
@@ -405,10 +838,12 @@ impl ControllerInner {
 
         // back to original recipe, which should add the query again
         self.apply_recipe(original)
-            .expect("failed to activate original recipe");
+            .map_err(|e| format!("failed to activate original recipe: {}", e))?;
+        Ok(affected_queries)
     }
 
     pub(super) fn handle_heartbeat(&mut self, msg: &CoordinationMessage) -> Result<(), io::Error> {
+        let now = self.clock.now();
         match self.workers.get_mut(&msg.source) {
             None => crit!(
                 self.log,
@@ -416,7 +851,7 @@ impl ControllerInner {
                 msg.source
             ),
             Some(ref mut ws) => {
-                ws.last_heartbeat = Instant::now();
+                ws.last_heartbeat = now;
             }
         }
 
@@ -428,7 +863,10 @@ impl ControllerInner {
     pub(super) fn new(
         log: slog::Logger,
         state: ControllerState,
+        env: Environment,
         drx: futures::sync::mpsc::UnboundedReceiver<ControlReplyPacket>,
+        optimizer: Option<Arc<dyn QueryOptimizer>>,
+        progress: MigrationTracker,
     ) -> Self {
         let mut g = petgraph::Graph::new();
         let source = g.add_node(node::Node::new(
@@ -443,17 +881,29 @@ impl ControllerInner {
         }
         materializations.set_frontier_strategy(state.config.frontier_strategy);
 
-        let cc = Arc::new(ChannelCoordinator::new());
         assert_ne!(state.config.quorum, 0);
 
-        let pending_recovery = if !state.recipes.is_empty() {
-            Some((state.recipes, state.recipe_version))
+        let pending_journal = if state.journal != 0 {
+            Some(state.journal)
+        } else {
+            None
+        };
+        // a journal that starts part of the way through could not be replayed
+        let journal = if state.journal == 0 && !state.recipes.is_empty() {
+            None
+        } else {
+            Some(state.journal)
+        };
+        let pending_recovery = if pending_journal.is_none() && !state.recipes.is_empty() {
+            Some((state.recipes, state.removals, state.recipe_version))
         } else {
             None
         };
 
         let mut recipe = Recipe::blank(Some(log.clone()));
         recipe.enable_reuse(state.config.reuse);
+        recipe.set_universe_query_limit(state.config.universe_quota.max_queries);
+        recipe.set_optimizer(optimizer);
 
         ControllerInner {
             ingredients: g,
@@ -463,28 +913,56 @@ impl ControllerInner {
             materializations,
             sharding: state.config.sharding,
             domain_config: state.config.domain_config,
+            memory_limit: None,
             persistence: state.config.persistence,
             heartbeat_every: state.config.heartbeat_every,
             healthcheck_every: state.config.healthcheck_every,
             recipe,
             quorum: state.config.quorum,
+            migration_timeout: state.config.migration_timeout,
+            #[cfg(test)]
+            fail_next_migration: None,
             log,
 
             domains: Default::default(),
             domain_nodes: Default::default(),
-            channel_coordinator: cc,
+            channel_coordinator: env.coordinator,
             debug_channel: None,
+            connect: env.connect,
+            clock: env.clock.clone(),
             epoch: state.epoch,
 
             remap: HashMap::default(),
+            placements: HashMap::default(),
 
             read_addrs: HashMap::default(),
             workers: HashMap::default(),
 
             pending_recovery,
-            last_checked_workers: Instant::now(),
+            pending_journal,
+            journal,
+            persisted_versions: Vec::new(),
+            committed: Vec::new(),
+            last_checked_workers: env.clock.now(),
 
-            replies: DomainReplies(drx),
+            replies: DomainReplies::new(drx, env.clock, env.idle),
+
+            universe_nodes: HashMap::default(),
+            universe_quota: state.config.universe_quota,
+            quotas_due: false,
+            infer_tables: state.config.infer_tables,
+
+            shadow: None,
+
+            last_snapshot: 0,
+
+            links: HashMap::default(),
+
+            eviction_weights: state.eviction_weights,
+            publish_intervals: state.publish_intervals,
+
+            migrations: MigrationQueue::default(),
+            progress,
         }
     }
 
@@ -523,6 +1001,47 @@ impl ControllerInner {
         self.persistence = params;
     }
 
+    /// The placement that a domain that holds `nodes` asks for, and the workers out of `workers`
+    /// that it may be placed on, given the number of shards each of them runs already.
+    ///
+    /// The workers that meet the constraints of the domain's nodes each get a share of the shards
+    /// in proportion to the resources they have.
+    fn candidates<'a, W: Copy + Eq + Hash>(
+        &self,
+        nodes: &[NodeIndex],
+        workers: &[(W, &'a HashMap<String, String>, Capacity, bool)],
+        running: &HashMap<W, usize>,
+    ) -> (Placement, Vec<Candidate<'a, W>>) {
+        let mut placement = Placement::default();
+        for ni in nodes {
+            if let Some(p) = self.placements.get(ni) {
+                placement.merge(p);
+            }
+        }
+        // domains that hold nothing but readers go on reader-only workers if there are any, and
+        // no other domains go on those
+        let readers_only = nodes
+            .iter()
+            .all(|&ni| self.ingredients[ni].is_reader() || self.ingredients[ni].is_ingress());
+        let on_readers = readers_only && workers.iter().any(|&(_, _, _, r)| r);
+        let eligible: Vec<_> = workers
+            .iter()
+            .filter(|&&(_, _, _, r)| r == on_readers)
+            .collect();
+        let weights = placement::weights(&eligible.iter().map(|w| w.2).collect::<Vec<_>>());
+        let candidates = eligible
+            .into_iter()
+            .zip(weights)
+            .map(|(&(worker, labels, _, _), weight)| Candidate {
+                worker,
+                labels,
+                weight,
+                shards: running.get(&worker).cloned().unwrap_or(0),
+            })
+            .collect();
+        (placement, candidates)
+    }
+
     pub(in crate::controller) fn place_domain(
         &mut self,
         idx: DomainIndex,
@@ -530,8 +1049,35 @@ impl ControllerInner {
         log: &Logger,
         nodes: Vec<(NodeIndex, bool)>,
     ) -> DomainHandle {
+        let running = self.running_shards();
+        let mut workers: Vec<_> = self
+            .workers
+            .iter()
+            .filter(|(_, w)| w.healthy && !w.draining)
+            .map(|(&wi, w)| (wi, &w.labels, w.capacity, w.reader_only))
+            .collect();
+        // so that the same workers get the same domains however the map happens to be ordered
+        workers.sort_by_key(|&(wi, ..)| wi);
+        let members: Vec<_> = nodes.iter().map(|&(ni, _)| ni).collect();
+        let (placement, healthy) = self.candidates(&members, &workers, &running);
+        let assignments = match placement.choose(&healthy, num_shards.unwrap_or(1)) {
+            Some(assignments) => assignments,
+            None => {
+                // the constraints were checked when they were added, but nodes that are placed
+                // together may not agree, or the workers that met them may have failed since
+                error!(
+                    log,
+                    "no worker meets the placement of domain {}: {}",
+                    idx.index(),
+                    placement
+                );
+                Placement::default()
+                    .choose(&healthy, num_shards.unwrap_or(1))
+                    .expect("no healthy workers that can run domain")
+            }
+        };
+
         // TODO: can we just redirect all domain traffic through the worker's connection?
-        let mut assignments = Vec::new();
         let mut nodes = Some(
             nodes
                 .into_iter()
@@ -543,9 +1089,6 @@ impl ControllerInner {
                 .collect(),
         );
 
-        // TODO(malte): simple round-robin placement for the moment
-        let mut wi = self.workers.iter_mut();
-
         // Send `AssignDomain` to each shard of the given domain
         for i in 0..num_shards.unwrap_or(1) {
             let nodes = if i == num_shards.unwrap_or(1) - 1 {
@@ -563,15 +1106,7 @@ impl ControllerInner {
                 persistence_parameters: self.persistence.clone(),
             };
 
-            let (identifier, w) = loop {
-                if let Some((i, w)) = wi.next() {
-                    if w.healthy {
-                        break (*i, w);
-                    }
-                } else {
-                    wi = self.workers.iter_mut();
-                }
-            };
+            let w = self.workers.get_mut(&assignments[i]).unwrap();
 
             // send domain to worker
             info!(
@@ -579,9 +1114,9 @@ impl ControllerInner {
                 "sending domain {}.{} to worker {:?}",
                 domain.index.index(),
                 domain.shard.unwrap_or(0),
-                w.sender.peer_addr()
+                assignments[i]
             );
-            let src = w.sender.local_addr().unwrap();
+            let src = w.source;
             w.sender
                 .send(CoordinationMessage {
                     epoch: self.epoch,
@@ -589,14 +1124,16 @@ impl ControllerInner {
                     payload: CoordinationPayload::AssignDomain(domain),
                 })
                 .unwrap();
-
-            assignments.push(identifier);
         }
 
         // Wait for all the domains to acknowledge.
         let mut txs = HashMap::new();
         let mut announce = Vec::new();
-        let replies = self.replies.read_n_domain_replies(num_shards.unwrap_or(1));
+        // booting is not given up on, since the shards that do boot could not be shut down again
+        let replies = self
+            .replies
+            .read_n_domain_replies(num_shards.unwrap_or(1), None)
+            .unwrap();
         for r in replies {
             match r {
                 ControlReplyPacket::Booted(shard, addr) => {
@@ -636,7 +1173,7 @@ impl ControllerInner {
                     .sender
                     .send(CoordinationMessage {
                         epoch: self.epoch,
-                        source: endpoint.sender.local_addr().unwrap(),
+                        source: endpoint.source,
                         payload: CoordinationPayload::DomainBooted(dd),
                     })
                     .unwrap();
@@ -670,46 +1207,126 @@ impl ControllerInner {
 
     /// Adds a new user universe.
     /// User universes automatically enforce security policies.
-    fn add_universe<F, T>(&mut self, context: HashMap<String, DataType>, f: F) -> T
+    fn add_universe<F, T>(&mut self, context: HashMap<String, DataType>, f: F) -> Result<T, String>
     where
         F: FnOnce(&mut Migration) -> T,
     {
         info!(self.log, "starting migration: new soup universe");
         let miglog = self.log.new(o!());
+        let checkpoint = Checkpoint::take(self);
+        let start = self.clock.now();
         let mut m = Migration {
             mainline: self,
             added: Default::default(),
             columns: Default::default(),
             readers: Default::default(),
+            indices: Default::default(),
+            unsharded: Default::default(),
+            removed: Default::default(),
+            dropped: Default::default(),
+            renamed: Default::default(),
             context,
-            start: time::Instant::now(),
+            checkpoint,
+            start,
             log: miglog,
         };
         let r = f(&mut m);
-        m.commit();
-        r
+        m.commit()?;
+        Ok(r)
     }
 
     /// Perform a new query schema migration.
+    ///
+    /// Panics if the migration times out; see `try_migrate`.
     // crate viz for tests
     crate fn migrate<F, T>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Migration) -> T,
+    {
+        self.try_migrate(f)
+            .unwrap_or_else(|e| panic!("migration failed: {}", e))
+    }
+
+    /// Perform a new query schema migration, or return an error if it timed out and was undone.
+    crate fn try_migrate<F, T>(&mut self, f: F) -> Result<T, String>
+    where
+        F: FnOnce(&mut Migration) -> T,
+    {
+        self.migrate_with_result(f).map(|(r, _)| r)
+    }
+
+    /// Perform a new query schema migration, and return what it changed in the running graph
+    /// along with what `f` returned. See `try_migrate`.
+    fn migrate_with_result<F, T>(&mut self, f: F) -> Result<(T, MigrationResult), String>
     where
         F: FnOnce(&mut Migration) -> T,
     {
         info!(self.log, "starting migration");
         let miglog = self.log.new(o!());
+        let checkpoint = Checkpoint::take(self);
+        let start = self.clock.now();
         let mut m = Migration {
             mainline: self,
             added: Default::default(),
             columns: Default::default(),
             readers: Default::default(),
+            indices: Default::default(),
+            unsharded: Default::default(),
+            removed: Default::default(),
+            dropped: Default::default(),
+            renamed: Default::default(),
             context: Default::default(),
-            start: time::Instant::now(),
+            checkpoint,
+            start,
             log: miglog,
         };
         let r = f(&mut m);
-        m.commit();
-        r
+        let result = m.commit()?;
+        Ok((r, result))
+    }
+
+    /// Make a migration without committing it, and return what committing it would change in the
+    /// running graph. The graph is left as it was.
+    crate fn plan_migration<F, T>(&mut self, f: F) -> (T, MigrationPlan)
+    where
+        F: FnOnce(&mut Migration) -> T,
+    {
+        info!(self.log, "starting migration plan");
+        let miglog = self.log.new(o!());
+        let checkpoint = Checkpoint::take(self);
+        let start = self.clock.now();
+        let mut m = Migration {
+            mainline: self,
+            added: Default::default(),
+            columns: Default::default(),
+            readers: Default::default(),
+            indices: Default::default(),
+            unsharded: Default::default(),
+            removed: Default::default(),
+            dropped: Default::default(),
+            renamed: Default::default(),
+            context: Default::default(),
+            checkpoint,
+            start,
+            log: miglog,
+        };
+        let r = f(&mut m);
+        let mut plan = m.plan();
+
+        let mut state_bytes = HashMap::new();
+        for (_, (_, nodes)) in self.get_statistics().domains {
+            for (ni, ns) in nodes {
+                *state_bytes.entry(ni).or_insert(0) += ns.mem_size;
+            }
+        }
+        plan.replayed_bytes = plan
+            .replay_paths
+            .iter()
+            .filter(|p| !p.partial)
+            .filter_map(|p| p.segments.first().and_then(|s| s.nodes.first()))
+            .map(|ni| state_bytes.get(ni).cloned().unwrap_or(0))
+            .sum();
+        (r, plan)
     }
 
     #[cfg(test)]
@@ -721,7 +1338,7 @@ impl ControllerInner {
     ///
     /// Input nodes are here all nodes of type `Table`. The addresses returned by this function will
     /// all have been returned as a key in the map from `commit` at some point in the past.
-    fn inputs(&self) -> BTreeMap<String, NodeIndex> {
+    pub(super) fn inputs(&self) -> BTreeMap<String, NodeIndex> {
         self.ingredients
             .neighbors_directed(self.source, petgraph::EdgeDirection::Outgoing)
             .map(|n| {
@@ -739,6 +1356,7 @@ impl ControllerInner {
     fn outputs(&self) -> BTreeMap<String, NodeIndex> {
         self.ingredients
             .externals(petgraph::EdgeDirection::Outgoing)
+            .filter(|&n| !shadow::is_shadow(self.ingredients[n].name()))
             .filter_map(|n| {
                 let name = self.ingredients[n].name().to_owned();
                 self.ingredients[n]
@@ -785,21 +1403,78 @@ impl ControllerInner {
             }
         };
 
-        self.find_view_for(node, name).map(|r| {
-            let domain = self.ingredients[r].domain();
-            let columns = self.ingredients[r].fields().to_vec();
-            let schema = self.view_schema(r);
-            let shards = (0..self.domains[&domain].shards())
-                .map(|i| self.read_addrs[&self.domains[&domain].assignment(i)])
-                .collect();
+        self.find_view_for(node, name)
+            .map(|r| self.reader_builder(r))
+    }
 
-            ViewBuilder {
-                node: r,
-                columns,
-                schema,
-                shards,
+    /// The reader node of the view `name`.
+    fn find_reader(&self, name: &str) -> Option<NodeIndex> {
+        let node = match self.recipe.node_addr_for(name) {
+            Ok(ni) => Some(ni),
+            Err(_) => self.outputs().get(name).cloned(),
+        };
+        node.and_then(|ni| self.find_view_for(ni, name))
+    }
+
+    /// The replay paths that fill the state of the view `name`, and of the nodes it reads from.
+    fn replay_paths(&self, name: &str) -> Result<Vec<ReplayPath>, String> {
+        let reader = self
+            .find_reader(name)
+            .ok_or_else(|| format!("no view named {}", name))?;
+
+        let mut upstream = HashSet::new();
+        let mut next = vec![reader];
+        while let Some(ni) = next.pop() {
+            if upstream.insert(ni) {
+                next.extend(
+                    self.ingredients
+                        .neighbors_directed(ni, petgraph::EdgeDirection::Incoming),
+                );
             }
-        })
+        }
+        Ok(self.materializations.replay_paths(&upstream))
+    }
+
+    /// Obtain a `ViewBuilder` for the reader node `r`.
+    fn reader_builder(&self, r: NodeIndex) -> ViewBuilder {
+        let domain = self.ingredients[r].domain();
+        let columns = self.ingredients[r].fields().to_vec();
+        let schema = self.view_schema(r);
+        let shards = (0..self.domains[&domain].shards())
+            .map(|i| self.read_addrs[&self.domains[&domain].assignment(i)])
+            .collect();
+
+        ViewBuilder {
+            node: r,
+            columns,
+            schema,
+            shards,
+            secret: self
+                .channel_coordinator
+                .secret()
+                .map(|s| auth::derive(s, auth::READ)),
+            tls: self.channel_coordinator.tls().map(|t| t.name().to_owned()),
+        }
+    }
+
+    /// Take a snapshot, and return it along with the readers of every view computed from the base
+    /// table `base`, so that a client can wait for all of them to reflect its writes.
+    fn propagation(&mut self, base: &str) -> Result<Option<(u64, Vec<ViewBuilder>)>, String> {
+        let ni = match self.recipe.node_addr_for(base) {
+            Ok(ni) => ni,
+            Err(_) => match self.inputs().get(base) {
+                Some(&ni) => ni,
+                None => return Ok(None),
+            },
+        };
+        let mut readers = Vec::new();
+        let mut bfs = Bfs::new(&self.ingredients, ni);
+        while let Some(n) = bfs.next(&self.ingredients) {
+            if self.ingredients[n].is_reader() && !self.ingredients[n].is_dropped() {
+                readers.push(self.reader_builder(n));
+            }
+        }
+        Ok(Some((self.snapshot()?, readers)))
     }
 
     fn view_schema(&self, view_ni: NodeIndex) -> Option<Vec<ColumnSpecification>> {
@@ -875,17 +1550,23 @@ impl ControllerInner {
             table_name: node.name().to_owned(),
             columns,
             schema,
+            secret: self
+                .channel_coordinator
+                .secret()
+                .map(|s| auth::derive(s, auth::WRITE)),
+            tls: self.channel_coordinator.tls().map(|t| t.name().to_owned()),
         })
     }
 
     /// Get statistics about the time spent processing different parts of the graph.
     fn get_statistics(&mut self) -> GraphStats {
         trace!(self.log, "asked to get statistics");
+        let owned = self.owners_and_tags();
         let log = &self.log;
         let workers = &self.workers;
         let replies = &mut self.replies;
         // TODO: request stats from domains in parallel.
-        let domains = self
+        let mut domains: HashMap<_, _> = self
             .domains
             .iter_mut()
             .flat_map(|(&di, s)| {
@@ -900,23 +1581,874 @@ impl ControllerInner {
             })
             .collect();
 
-        GraphStats { domains }
+        for &mut (_, ref mut nodes) in domains.values_mut() {
+            for (ni, stats) in nodes.iter_mut() {
+                if let Some(&(ref owners, ref tags)) = owned.get(ni) {
+                    stats.owners = owners.clone();
+                    stats.tags = tags.clone();
+                }
+            }
+        }
+        let queries = self.query_usage(&domains);
+        GraphStats { domains, queries }
     }
 
-    fn get_instances(&self) -> Vec<(WorkerIdentifier, bool, Duration)> {
-        self.workers
-            .iter()
-            .map(|(&id, ref status)| (id, status.healthy, status.last_heartbeat.elapsed()))
-            .collect()
+    /// The nodes that the reader `reader` reads from, including itself.
+    fn upstream_of(&self, reader: NodeIndex) -> HashSet<NodeIndex> {
+        let mut upstream = HashSet::new();
+        let mut next = vec![reader];
+        while let Some(ni) = next.pop() {
+            if ni == self.source || !upstream.insert(ni) {
+                continue;
+            }
+            next.extend(
+                self.ingredients
+                    .neighbors_directed(ni, petgraph::EdgeDirection::Incoming),
+            );
+        }
+        upstream
     }
 
-    fn flush_partial(&mut self) -> u64 {
-        // get statistics for current domain sizes
-        // and evict all state from partial nodes
-        let workers = &self.workers;
-        let replies = &mut self.replies;
-        let to_evict: Vec<_> = self
-            .domains
+    /// The resources that each query has used, going by the statistics of the nodes in `domains`.
+    fn query_usage(
+        &self,
+        domains: &HashMap<(DomainIndex, usize), (DomainStats, HashMap<NodeIndex, NodeStats>)>,
+    ) -> HashMap<String, QueryUsage> {
+        let mut queries: HashMap<NodeIndex, Vec<String>> = HashMap::new();
+        for name in self.outputs().keys() {
+            if let Some(reader) = self.find_reader(name) {
+                for ni in self.upstream_of(reader) {
+                    queries.entry(ni).or_default().push(name.clone());
+                }
+            }
+        }
+
+        let mut usage: HashMap<String, QueryUsage> = HashMap::new();
+        for &(_, ref nodes) in domains.values() {
+            for (ni, stats) in nodes {
+                let names = match queries.get(ni) {
+                    Some(names) => names,
+                    None => continue,
+                };
+                // what can't be split evenly goes to the queries that come first
+                let share = |total: u64, i: usize| {
+                    let n = names.len() as u64;
+                    total / n + if (i as u64) < total % n { 1 } else { 0 }
+                };
+                for (i, name) in names.iter().enumerate() {
+                    let u = usage.entry(name.clone()).or_default();
+                    u.cpu_time += share(stats.process_ptime, i);
+                    u.state_bytes += share(stats.mem_size, i);
+                    u.network_bytes += share(stats.sent_bytes, i);
+                }
+            }
+        }
+        usage
+    }
+
+    /// The owners and tags of the named queries that each node computes a part of.
+    fn owners_and_tags(&self) -> HashMap<NodeIndex, (Vec<String>, Vec<String>)> {
+        let mut owned: HashMap<_, (Vec<String>, Vec<String>)> = HashMap::new();
+        for m in self.recipe.metadata() {
+            let reader = match self.find_reader(&m.name) {
+                Some(reader) => reader,
+                None => continue,
+            };
+            for ni in self.upstream_of(reader) {
+                let &mut (ref mut owners, ref mut tags) = owned.entry(ni).or_default();
+                if let Some(ref owner) = m.owner {
+                    if !owners.contains(owner) {
+                        owners.push(owner.clone());
+                    }
+                }
+                for tag in &m.tags {
+                    if !tags.contains(tag) {
+                        tags.push(tag.clone());
+                    }
+                }
+            }
+        }
+        owned
+    }
+
+    /// Pick the identifier of the next barrier.
+    fn next_barrier(&mut self) -> u64 {
+        // readers only remember the newest snapshot they reflect, so identifiers must keep
+        // increasing even across a change of controller.
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        let id = ::std::cmp::max(self.last_snapshot + 1, now);
+        self.last_snapshot = id;
+        id
+    }
+
+    /// Inject a snapshot barrier at every base table, and return its identifier.
+    fn snapshot(&mut self) -> Result<u64, String> {
+        let id = self.next_barrier();
+        self.inject_barrier(id, BarrierKind::Snapshot)?;
+        Ok(id)
+    }
+
+    /// Inject an export barrier that only the reader of the view `name` heeds, and return its
+    /// identifier along with the view, from whose shards the rows they keep can be collected.
+    fn export_view(&mut self, name: &str) -> Result<(u64, ViewBuilder), String> {
+        let reader = self
+            .find_reader(name)
+            .ok_or_else(|| format!("no view named {}", name))?;
+        let status = self
+            .materializations
+            .get_status(reader, &self.ingredients[reader]);
+        if let MaterializationStatus::Partial { .. } = status {
+            return Err(format!("view {} is only partially materialized", name));
+        }
+
+        let mut expected = self.barrier_arrivals();
+        expected.retain(|&ni, _| ni == reader);
+        if expected.get(&reader).cloned().unwrap_or(0) == 0 {
+            return Err(format!("view {} is not computed from any base table", name));
+        }
+        let id = self.next_barrier();
+        self.send_barrier(id, BarrierKind::Export, expected)?;
+        Ok((id, self.reader_builder(reader)))
+    }
+
+    /// Start a write group at every base table, and return its identifier.
+    ///
+    /// Clients send the writes in the group straight to the base tables, which hold on to them
+    /// until the group ends.
+    fn open_write_group(&mut self) -> Result<u64, String> {
+        let id = self.next_barrier();
+        self.inject_barrier(id, BarrierKind::GroupStart)?;
+        Ok(id)
+    }
+
+    /// End write group `id` at every base table, once they hold all of its writes.
+    ///
+    /// The base tables apply the writes they hold for the group as it ends, or drop them if
+    /// `abort` is set.
+    fn close_write_group(&mut self, id: u64, abort: bool) -> Result<(), String> {
+        let kind = if abort {
+            BarrierKind::GroupAbort
+        } else {
+            BarrierKind::GroupEnd
+        };
+        self.inject_barrier(id, kind)
+    }
+
+    /// Inject barrier `id` at every shard of every base table.
+    fn inject_barrier(&mut self, id: u64, kind: BarrierKind) -> Result<(), String> {
+        let expected = self.barrier_arrivals();
+        self.send_barrier(id, kind, expected)
+    }
+
+    /// How many copies of a barrier each reader should wait for.
+    fn barrier_arrivals(&self) -> HashMap<NodeIndex, usize> {
+        let shards = self
+            .domains
+            .iter()
+            .map(|(&di, d)| (di, d.shards()))
+            .collect();
+        barriers::arrivals(&self.ingredients, self.source, &shards)
+    }
+
+    /// Send barrier `id` to every shard of every base table, to be heeded by the readers in
+    /// `expected`.
+    fn send_barrier(
+        &mut self,
+        id: u64,
+        kind: BarrierKind,
+        expected: HashMap<NodeIndex, usize>,
+    ) -> Result<(), String> {
+        let bases: Vec<_> = self
+            .ingredients
+            .neighbors_directed(self.source, petgraph::EdgeDirection::Outgoing)
+            .filter(|&ni| !self.ingredients[ni].is_dropped())
+            .collect();
+        for ni in bases {
+            let local = self.ingredients[ni].local_addr();
+            let p = box Packet::Barrier {
+                link: Link::new(local, local),
+                id,
+                kind,
+                expected: expected.clone(),
+            };
+            self.domains
+                .get_mut(&self.ingredients[ni].domain())
+                .unwrap()
+                .send_to_healthy(p, &self.workers)
+                .map_err(|e| {
+                    format!(
+                        "failed to send barrier to {}: {:?}",
+                        self.ingredients[ni].name(),
+                        e
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Inject a latency probe at every base table, and return its identifier.
+    fn probe(&mut self) -> Result<u64, String> {
+        let id = rand::random();
+        let sent = time::SystemTime::now();
+        let bases: Vec<_> = self
+            .ingredients
+            .neighbors_directed(self.source, petgraph::EdgeDirection::Outgoing)
+            .filter(|&ni| !self.ingredients[ni].is_dropped())
+            .collect();
+        for ni in bases {
+            let local = self.ingredients[ni].local_addr();
+            let p = box Packet::Probe {
+                link: Link::new(local, local),
+                id,
+                sent,
+            };
+            self.domains
+                .get_mut(&self.ingredients[ni].domain())
+                .unwrap()
+                .send_to_healthy(p, &self.workers)
+                .map_err(|e| {
+                    format!(
+                        "failed to send probe to {}: {:?}",
+                        self.ingredients[ni].name(),
+                        e
+                    )
+                })?;
+        }
+        Ok(id)
+    }
+
+    /// How long the latency probe `id` took to reach each view that it has reached every shard of.
+    ///
+    /// A probe reaches a reader shard once along every path from every base table, and is only
+    /// counted once all of those copies have arrived.
+    fn probe_latencies(&mut self, id: u64) -> HashMap<String, Duration> {
+        let expected = self.barrier_arrivals();
+        let mut reached: HashMap<NodeIndex, (usize, Duration)> = HashMap::new();
+        for (_, (_, node_stats)) in self.get_statistics().domains {
+            for (ni, ns) in node_stats {
+                match ns.probe {
+                    Some((probe, copies, nanos))
+                        if probe == id && Some(&copies) == expected.get(&ni) =>
+                    {
+                        let r = reached.entry(ni).or_insert((0, Duration::from_millis(0)));
+                        r.0 += 1;
+                        r.1 = r.1.max(Duration::from_nanos(nanos));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        reached
+            .into_iter()
+            .filter(|&(ni, (shards, _))| {
+                shards == self.domains[&self.ingredients[ni].domain()].shards()
+            })
+            .map(|(ni, (_, latency))| (self.ingredients[ni].name().to_owned(), latency))
+            .collect()
+    }
+
+    /// The views that have stopped changing because an operator they read from emitted far more
+    /// records than it was given, and was suspended.
+    fn suspended_views(&mut self) -> Vec<String> {
+        let suspended: HashSet<_> = self
+            .get_statistics()
+            .domains
+            .into_iter()
+            .flat_map(|(_, (_, node_stats))| node_stats)
+            .filter(|&(_, ref ns)| ns.suspended)
+            .map(|(ni, _)| ni)
+            .collect();
+
+        let mut views = Vec::new();
+        for &ni in &suspended {
+            let mut bfs = Bfs::new(&self.ingredients, ni);
+            while let Some(child) = bfs.next(&self.ingredients) {
+                let n = &self.ingredients[child];
+                if n.is_reader() && !shadow::is_shadow(n.name()) {
+                    views.push(n.name().to_owned());
+                }
+            }
+        }
+        views.sort();
+        views.dedup();
+        if !views.is_empty() {
+            warn!(self.log, "views have been suspended"; "views" => ?views);
+        }
+        views
+    }
+
+    fn check_invariants(&self) -> Vec<Violation> {
+        let shards = self
+            .domains
+            .iter()
+            .map(|(&di, d)| (di, d.shards()))
+            .collect();
+        let violations = invariants::check(&self.ingredients, &shards, &self.remap);
+        for v in &violations {
+            warn!(self.log, "graph invariant violated: {}", v);
+        }
+        violations
+    }
+
+    /// Have every domain write the packets it last handled to disk.
+    fn dump_packets(&mut self) {
+        let workers = &self.workers;
+        let replies = &mut self.replies;
+        for d in self.domains.values_mut() {
+            d.send_to_healthy(box Packet::DumpCapture, workers)
+                .expect("failed to ask domain to dump captured packets");
+            replies
+                .wait_for_acks(d)
+                .expect("no deadline is set outside of migrations");
+        }
+    }
+
+    /// The number of bytes of state held by each node, summed across shards.
+    fn node_sizes(&mut self) -> HashMap<NodeIndex, (u64, bool)> {
+        let mut sizes = HashMap::new();
+        for (_, node_stats) in self.get_statistics().domains.into_iter().map(|(_, s)| s) {
+            for (ni, ns) in node_stats {
+                let partial = match ns.materialized {
+                    MaterializationStatus::Partial { .. } => true,
+                    _ => false,
+                };
+                sizes.entry(ni).or_insert((0, partial)).0 += ns.mem_size;
+            }
+        }
+        sizes
+    }
+
+    /// The number of nodes, and bytes of state in those nodes, that belong to each universe.
+    fn universe_usage(&mut self) -> HashMap<String, (usize, u64)> {
+        let sizes = self.node_sizes();
+        self.universe_nodes
+            .iter()
+            .map(|(id, nodes)| {
+                let bytes = nodes
+                    .iter()
+                    .filter_map(|ni| sizes.get(ni))
+                    .map(|&(bytes, _)| bytes)
+                    .sum();
+                (id.clone(), (nodes.len(), bytes))
+            })
+            .collect()
+    }
+
+    /// Evict partially materialized state from universes that hold more state than their quota
+    /// allows, so that they do not crowd out other universes' state.
+    ///
+    /// Nothing is done while a worker has missed heartbeats, since its domains may never reply
+    /// with their statistics. Quotas are enforced again once it has recovered or been replaced.
+    pub(super) fn enforce_universe_quotas(&mut self) {
+        let max = match self.universe_quota.max_memory {
+            Some(max) => max,
+            None => return,
+        };
+        let heartbeat_every = self.heartbeat_every;
+        let now = self.clock.now();
+        if self
+            .workers
+            .values()
+            .any(|ws| ws.healthy && now.duration_since(ws.last_heartbeat) > heartbeat_every * 2)
+        {
+            return;
+        }
+        let sizes = self.node_sizes();
+
+        let mut evictions = Vec::new();
+        for (id, nodes) in &self.universe_nodes {
+            let mut nodes: Vec<_> = nodes
+                .iter()
+                .filter_map(|ni| sizes.get(ni).map(|&size| (*ni, size)))
+                .collect();
+            let total: u64 = nodes.iter().map(|&(_, (bytes, _))| bytes).sum();
+            if total <= max {
+                continue;
+            }
+
+            // evict from the largest partial nodes first
+            let mut excess = total - max;
+            nodes.sort_by_key(|&(_, (bytes, _))| ::std::cmp::Reverse(bytes));
+            for (ni, (bytes, partial)) in nodes {
+                if excess == 0 {
+                    break;
+                }
+                if partial && bytes > 0 {
+                    let evict = ::std::cmp::min(bytes, excess);
+                    evictions.push((ni, evict));
+                    excess -= evict;
+                }
+            }
+
+            if excess > 0 {
+                warn!(self.log, "universe exceeds its memory quota, but holds no more evictable state";
+                      "universe" => id, "excess" => excess);
+            }
+        }
+
+        let workers = &self.workers;
+        for (ni, bytes) in evictions {
+            let n = &self.ingredients[ni];
+            self.domains
+                .get_mut(&n.domain())
+                .unwrap()
+                .send_to_healthy(
+                    box Packet::Evict {
+                        node: Some(n.local_addr()),
+                        num_bytes: bytes as usize,
+                    },
+                    workers,
+                )
+                .expect("failed to send domain eviction message");
+        }
+    }
+
+    fn get_instances(&self) -> Vec<(WorkerIdentifier, bool, Duration)> {
+        let now = self.clock.now();
+        self.workers
+            .iter()
+            .map(|(&id, ref status)| {
+                let since = now.duration_since(status.last_heartbeat);
+                (id, status.healthy, since)
+            })
+            .collect()
+    }
+
+    /// Describe the workers that have registered, along with how many domain shards each runs.
+    fn list_workers(&self) -> Vec<WorkerInfo> {
+        let running = self.running_shards();
+        let now = self.clock.now();
+        let mut workers: Vec<_> = self
+            .workers
+            .iter()
+            .map(|(&addr, w)| WorkerInfo {
+                addr,
+                healthy: w.healthy,
+                last_heartbeat: now.duration_since(w.last_heartbeat),
+                labels: w.labels.clone(),
+                cores: w.capacity.cores,
+                memory: w.capacity.memory,
+                reader_only: w.reader_only,
+                draining: w.draining,
+                shards: running.get(&addr).cloned().unwrap_or(0),
+            })
+            .collect();
+        workers.sort_by_key(|w| w.addr);
+        workers
+    }
+
+    /// The nodes of domain `di` that have not been removed.
+    fn live_nodes(&self, di: DomainIndex) -> Vec<NodeIndex> {
+        self.domain_nodes
+            .get(&di)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .filter(|&ni| !self.ingredients[ni].is_dropped())
+            .collect()
+    }
+
+    /// The number of shards of domains that still hold nodes that each worker runs.
+    fn running_shards(&self) -> HashMap<WorkerIdentifier, usize> {
+        let mut running = HashMap::new();
+        for (&di, d) in &self.domains {
+            if self.live_nodes(di).is_empty() {
+                continue;
+            }
+            for shard in 0..d.shards() {
+                *running.entry(d.assignment(shard)).or_insert(0) += 1;
+            }
+        }
+        running
+    }
+
+    /// Describe the domains that still hold nodes, along with the workers their shards run on.
+    fn list_domains(&self) -> Vec<DomainInfo> {
+        let mut domains: Vec<_> = self
+            .domains
+            .iter()
+            .map(|(&di, d)| DomainInfo {
+                domain: di.index(),
+                shards: (0..d.shards()).map(|i| d.assignment(i)).collect(),
+                nodes: self.live_nodes(di),
+            })
+            .filter(|d| !d.nodes.is_empty())
+            .collect();
+        domains.sort_by_key(|d| d.domain);
+        domains
+    }
+
+    /// List the base tables and views, along with their columns and the metadata of their queries.
+    fn catalog(&self) -> Catalog {
+        let metadata = self.recipe.metadata();
+        let entry = |node: NodeIndex| CatalogEntry {
+            node,
+            columns: self.ingredients[node].fields().to_vec(),
+            owner: None,
+            tags: Vec::new(),
+        };
+        Catalog {
+            tables: self
+                .inputs()
+                .into_iter()
+                .map(|(name, ni)| (name, entry(ni)))
+                .collect(),
+            views: self
+                .outputs()
+                .into_iter()
+                .map(|(name, ni)| {
+                    // the reader knows which columns are visible to clients
+                    let reader = self.find_view_for(ni, &name).unwrap_or(ni);
+                    let mut e = entry(reader);
+                    if let Some(m) = metadata.iter().find(|m| m.name == name) {
+                        e.owner = m.owner.clone();
+                        e.tags = m.tags.clone();
+                    }
+                    (name, e)
+                })
+                .collect(),
+        }
+    }
+
+    /// Describe the migrations that have yet to finish.
+    fn migration_status(&mut self) -> MigrationStatus {
+        MigrationStatus {
+            recipe_version: self.recipe.version(),
+            recovering: self
+                .pending_recovery
+                .as_ref()
+                .map(|&(ref recipes, ..)| recipes.len()),
+            shadow: self.shadow_report().ok(),
+            queued: self.migrations.tickets(),
+        }
+    }
+
+    /// How far a migration has got. See `MigrationTracker::get`, which also answers this while a
+    /// migration is running.
+    fn migration_progress(&self, ticket: Option<u64>) -> Option<MigrationProgress> {
+        self.progress
+            .get(ticket)
+            .or_else(|| match self.migrations.status(ticket?)? {
+                SubmittedMigration::Queued(ahead) => {
+                    Some(progress::not_started(MigrationStep::Queued(ahead)))
+                }
+                // applied, or failed, without a migration of the graph
+                _ => Some(progress::not_started(MigrationStep::Done)),
+            })
+    }
+
+    /// Whether universe quotas are due to be enforced, which the controller has not already been
+    /// asked to.
+    pub(super) fn wake_quotas(&mut self) -> bool {
+        mem::replace(&mut self.quotas_due, false)
+    }
+
+    /// Whether there are submitted migrations that the controller should get to, which it has
+    /// not already been asked to.
+    pub(super) fn wake_migrations(&mut self) -> bool {
+        self.pending_recovery.is_none() && self.shadow.is_none() && self.migrations.wake()
+    }
+
+    /// Apply the submitted migrations, in batches of those that do not conflict.
+    pub(super) fn run_queued_migrations<A: Authority + 'static>(&mut self, authority: &Arc<A>) {
+        if self.pending_recovery.is_some() || self.shadow.is_some() {
+            // picked up again after the next request
+            self.migrations.defer();
+            return;
+        }
+
+        loop {
+            let batch = self.migrations.next_batch();
+            if batch.is_empty() {
+                return;
+            }
+
+            let tickets: Vec<_> = batch.iter().map(|q| q.ticket).collect();
+            info!(self.log, "applying submitted migrations"; "tickets" => ?tickets);
+            let add_txt = batch
+                .iter()
+                .map(|q| q.text.as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            let progress = self.progress.clone();
+            let r = progress.applying(tickets.clone(), || self.extend_recipe(authority, add_txt));
+            match r {
+                Err(ref e) if batch.len() > 1 => {
+                    // the migrations do not conflict, so they can be applied one at a time instead,
+                    // and only those that fail on their own are reported as failed
+                    warn!(self.log, "submitted migrations failed together: {}", e;
+                          "tickets" => ?tickets);
+                    for q in batch.chunks(1) {
+                        let text = q[0].text.clone();
+                        let r = progress
+                            .applying(vec![q[0].ticket], || self.extend_recipe(authority, text));
+                        if let Err(ref e) = r {
+                            warn!(self.log, "submitted migration failed: {}", e;
+                                  "ticket" => q[0].ticket);
+                        }
+                        self.migrations.finish(q, &r);
+                    }
+                }
+                _ => {
+                    if let Err(ref e) = r {
+                        warn!(self.log, "submitted migrations failed: {}", e; "tickets" => ?tickets);
+                    }
+                    self.migrations.finish(&batch, &r);
+                }
+            }
+        }
+    }
+
+    /// Start links for the base tables that the recipe feeds from views in other deployments, and
+    /// stop those that it no longer does.
+    pub(super) fn sync_links<A: Authority + 'static>(&mut self, authority: &Arc<A>) {
+        if self.pending_recovery.is_some() {
+            return;
+        }
+
+        let wanted: HashMap<_, _> = self
+            .recipe
+            .links()
+            .iter()
+            .map(|l| (l.table.clone(), l.clone()))
+            .collect();
+        self.links
+            .retain(|table, s| wanted.get(table) == Some(s.link()));
+        for (table, link) in wanted {
+            if !self.links.contains_key(&table) {
+                info!(self.log, "feeding table from upstream view";
+                      "table" => &table,
+                      "view" => &link.view,
+                      "upstream" => &link.upstream);
+                let s = Subscription::start(link, authority.clone(), self.log.clone());
+                self.links.insert(table, s);
+            }
+        }
+    }
+
+    /// Stop placing domains on `worker`, and move the queries that run there elsewhere.
+    fn drain_worker(&mut self, worker: WorkerIdentifier) -> Result<MoveReport, String> {
+        let reader_only = match self.workers.get(&worker) {
+            Some(w) => w.reader_only,
+            None => return Err(format!("no worker at {}", worker)),
+        };
+        // domains that hold more than readers cannot go on reader-only workers
+        let takers = self.workers.iter().any(|(&wi, w)| {
+            wi != worker && w.healthy && !w.draining && (reader_only || !w.reader_only)
+        });
+        if !takers {
+            return Err(format!("no other worker can take over from {}", worker));
+        }
+
+        info!(self.log, "draining worker {:?}", worker);
+        self.workers.get_mut(&worker).unwrap().draining = true;
+        self.move_queries_off(&[worker])
+    }
+
+    /// Move the queries that run on workers with more than their share of domain shards, so that
+    /// their domains are placed in proportion to the capacity of each worker again.
+    fn rebalance(&mut self) -> Result<MoveReport, String> {
+        let running = self.running_shards();
+        let mut overloaded = Vec::new();
+        // reader-only workers run different domains from the rest, so they are balanced apart
+        for &reader_only in &[false, true] {
+            let eligible: Vec<_> = self
+                .workers
+                .iter()
+                .filter(|(_, w)| w.healthy && !w.draining && w.reader_only == reader_only)
+                .map(|(&wi, w)| (wi, w.capacity, running.get(&wi).cloned().unwrap_or(0)))
+                .collect();
+            let total: usize = eligible.iter().map(|&(_, _, shards)| shards).sum();
+            let weights =
+                placement::weights(&eligible.iter().map(|&(_, c, _)| c).collect::<Vec<_>>());
+            for ((wi, _, shards), weight) in eligible.into_iter().zip(weights) {
+                if shards as f64 > (total as f64 * weight).ceil() {
+                    overloaded.push(wi);
+                }
+            }
+        }
+
+        if overloaded.is_empty() {
+            return Ok(MoveReport::default());
+        }
+        info!(self.log, "rebalancing domains"; "overloaded" => ?overloaded);
+        self.move_queries_off(&overloaded)
+    }
+
+    /// Work out where domains would be placed if the workers changed as `change` says, and how much
+    /// state would move, without changing anything.
+    ///
+    /// Domains move as draining the removed workers, and then rebalancing, would move them, except
+    /// that each domain is placed anew on its own rather than along with the rest of the queries
+    /// it is part of.
+    fn plan_workers(&mut self, change: WorkerChange) -> Result<PlacementPlan, String> {
+        for w in &change.remove {
+            if !self.workers.contains_key(w) {
+                return Err(format!("no worker at {}", w));
+            }
+        }
+
+        let mut state_bytes = HashMap::new();
+        for ((di, shard), (_, nodes)) in self.get_statistics().domains {
+            state_bytes.insert(
+                (di, shard),
+                nodes.values().map(|ns| ns.mem_size).sum::<u64>(),
+            );
+        }
+
+        let added = change.add.iter().enumerate().map(|(i, w)| {
+            let capacity = Capacity {
+                cores: w.cores,
+                memory: w.memory,
+            };
+            (PlannedWorker::New(i), &w.labels, capacity, w.reader_only)
+        });
+        let workers: Vec<_> = self
+            .workers
+            .iter()
+            .filter(|(wi, w)| w.healthy && !w.draining && !change.remove.contains(*wi))
+            .map(|(&wi, w)| {
+                let worker = PlannedWorker::Existing(wi);
+                (worker, &w.labels, w.capacity, w.reader_only)
+            })
+            .chain(added)
+            .collect();
+        if workers.is_empty() {
+            return Err("no workers would be left to run domains".to_owned());
+        }
+
+        let mut domains: Vec<_> = self
+            .domains
+            .iter()
+            .filter(|&(&di, _)| !self.live_nodes(di).is_empty())
+            .map(|(&di, d)| {
+                let shards: Vec<_> = (0..d.shards())
+                    .map(|i| PlannedWorker::Existing(d.assignment(i)))
+                    .collect();
+                (di, shards)
+            })
+            .collect();
+        domains.sort_by_key(|&(di, _)| di.index());
+        let mut running = HashMap::new();
+        for &w in domains.iter().flat_map(|(_, shards)| shards) {
+            *running.entry(w).or_insert(0) += 1;
+        }
+
+        // place the domains with shards on any of `off` anew, and return those that hold base
+        // tables, which stay where they are
+        let move_off = |off: &[PlannedWorker],
+                        domains: &mut Vec<(DomainIndex, Vec<PlannedWorker>)>,
+                        running: &mut HashMap<PlannedWorker, usize>|
+         -> Result<Vec<usize>, String> {
+            let mut stuck = Vec::new();
+            for (di, shards) in domains.iter_mut() {
+                if !shards.iter().any(|w| off.contains(w)) {
+                    continue;
+                }
+                let nodes = self.live_nodes(*di);
+                if nodes.iter().any(|&ni| self.ingredients[ni].is_base()) {
+                    stuck.push(di.index());
+                    continue;
+                }
+
+                for w in shards.iter() {
+                    *running.get_mut(w).unwrap() -= 1;
+                }
+                let (placement, candidates) = self.candidates(&nodes, &workers, running);
+                *shards = placement
+                    .choose(&candidates, shards.len())
+                    .or_else(|| Placement::default().choose(&candidates, shards.len()))
+                    .ok_or_else(|| format!("no worker could run domain {}", di.index()))?;
+                for &w in shards.iter() {
+                    *running.entry(w).or_insert(0) += 1;
+                }
+            }
+            Ok(stuck)
+        };
+
+        let removed: Vec<_> = change
+            .remove
+            .iter()
+            .map(|&w| PlannedWorker::Existing(w))
+            .collect();
+        let stuck = move_off(&removed, &mut domains, &mut running)?;
+        if change.rebalance {
+            let mut overloaded = Vec::new();
+            // reader-only workers run different domains from the rest, so they are balanced apart
+            for &reader_only in &[false, true] {
+                let eligible: Vec<_> = workers.iter().filter(|w| w.3 == reader_only).collect();
+                let shards = |w: PlannedWorker| running.get(&w).cloned().unwrap_or(0);
+                let total: usize = eligible.iter().map(|w| shards(w.0)).sum();
+                let weights = placement::weights(&eligible.iter().map(|w| w.2).collect::<Vec<_>>());
+                for (w, weight) in eligible.into_iter().zip(weights) {
+                    if shards(w.0) as f64 > (total as f64 * weight).ceil() {
+                        overloaded.push(w.0);
+                    }
+                }
+            }
+            // as with rebalancing, domains that hold base tables are left where they are
+            move_off(&overloaded, &mut domains, &mut running)?;
+        }
+
+        let mut moved_bytes = 0;
+        let domains = domains
+            .into_iter()
+            .map(|(di, shards)| {
+                let d = &self.domains[&di];
+                let bytes = |i| state_bytes.get(&(di, i)).cloned().unwrap_or(0);
+                let moved: Vec<_> = (0..shards.len())
+                    .filter(|&i| shards[i] != PlannedWorker::Existing(d.assignment(i)))
+                    .collect();
+                moved_bytes += moved.iter().map(|&i| bytes(i)).sum::<u64>();
+                PlannedDomain {
+                    domain: di.index(),
+                    shards,
+                    moved,
+                    state_bytes: (0..d.shards()).map(bytes).sum(),
+                }
+            })
+            .collect();
+        Ok(PlacementPlan {
+            domains,
+            moved_bytes,
+            stuck,
+        })
+    }
+
+    /// Rebuild the queries in domains on any of `workers`, so that the domains are placed anew.
+    ///
+    /// Domains that hold base tables are left where they are, since their state cannot be moved.
+    fn move_queries_off(&mut self, workers: &[WorkerIdentifier]) -> Result<MoveReport, String> {
+        let mut nodes = Vec::new();
+        let mut remaining = Vec::new();
+        for (&di, d) in &self.domains {
+            if !workers.iter().any(|w| d.assigned_to_worker(w)) {
+                continue;
+            }
+            let live = self.live_nodes(di);
+            if live.iter().any(|&ni| self.ingredients[ni].is_base()) {
+                remaining.push(di.index());
+            } else {
+                nodes.extend(live);
+            }
+        }
+        remaining.sort();
+
+        let moved = self.rebuild_queries(nodes)?;
+        Ok(MoveReport { moved, remaining })
+    }
+
+    fn flush_partial(&mut self) -> u64 {
+        // get statistics for current domain sizes
+        // and evict all state from partial nodes
+        let workers = &self.workers;
+        let replies = &mut self.replies;
+        let to_evict: Vec<_> = self
+            .domains
             .iter_mut()
             .map(|(di, s)| {
                 s.send_to_healthy(box Packet::GetStatistics, workers)
@@ -937,31 +2469,233 @@ impl ControllerInner {
             })
             .collect();
 
-        let mut total_evicted = 0;
-        for (di, nodes) in to_evict {
-            for (ni, bytes) in nodes {
-                let na = self.ingredients[ni].local_addr();
-                self.domains
-                    .get_mut(&di)
-                    .unwrap()
-                    .send_to_healthy(
-                        box Packet::Evict {
-                            node: Some(na),
-                            num_bytes: bytes as usize,
-                        },
-                        workers,
-                    )
-                    .expect("failed to send domain flush message");
-                total_evicted += bytes;
+        let mut total_evicted = 0;
+        for (di, nodes) in to_evict {
+            for (ni, bytes) in nodes {
+                let na = self.ingredients[ni].local_addr();
+                self.domains
+                    .get_mut(&di)
+                    .unwrap()
+                    .send_to_healthy(
+                        box Packet::Evict {
+                            node: Some(na),
+                            num_bytes: bytes as usize,
+                        },
+                        workers,
+                    )
+                    .expect("failed to send domain flush message");
+                total_evicted += bytes;
+            }
+        }
+
+        warn!(
+            self.log,
+            "flushed {} bytes of partial domain state", total_evicted
+        );
+
+        total_evicted
+    }
+
+    /// Apply the settings in `update` to the running deployment.
+    ///
+    /// All of the settings are checked before any of them are applied.
+    fn update_config<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        update: ConfigUpdate,
+    ) -> Result<(), String> {
+        if update.concurrent_replays == Some(0) {
+            return Err("domains must be allowed at least one concurrent replay".to_owned());
+        }
+
+        let mut domain_config = self.domain_config.clone();
+        if let Some(n) = update.concurrent_replays {
+            domain_config.concurrent_replays = n;
+        }
+        if let Some(t) = update.replay_batch_timeout {
+            domain_config.replay_batch_timeout = t;
+        }
+        if domain_config != self.domain_config {
+            // a controller that takes over should hand the same settings to new domains
+            if authority
+                .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                    None => unreachable!(),
+                    Some(ref state) if state.epoch > self.epoch => Err(()),
+                    Some(mut state) => {
+                        state.config.domain_config = domain_config.clone();
+                        Ok(state)
+                    }
+                })
+                .is_err()
+            {
+                return Err("Failed to persist configuration update".to_owned());
+            }
+
+            let workers = &self.workers;
+            let replies = &mut self.replies;
+            for d in self.domains.values_mut() {
+                d.send_to_healthy(
+                    box Packet::UpdateConfig {
+                        concurrent_replays: update.concurrent_replays,
+                        replay_batch_timeout: update.replay_batch_timeout,
+                    },
+                    workers,
+                )
+                .map_err(|e| format!("failed to update domain configuration: {}", e))?;
+                replies.wait_for_acks(d)?;
+            }
+            self.domain_config = domain_config;
+        }
+
+        if let Some(memory_limit) = update.memory_limit {
+            self.broadcast(CoordinationPayload::UpdateConfig { memory_limit });
+            self.memory_limit = Some(memory_limit);
+        }
+
+        info!(self.log, "updated configuration"; "update" => ?update);
+        Ok(())
+    }
+
+    /// Make the memory manager of each worker keep `weight` times as much of the state of the view
+    /// `view` as it does of a view with the default weight of 1 before it evicts from it.
+    fn set_eviction_weight<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        view: String,
+        weight: u32,
+    ) -> Result<(), String> {
+        if weight == 0 {
+            return Err("eviction weights must be at least 1".to_owned());
+        }
+        if self.find_reader(&view).is_none() {
+            return Err(format!("no view named {}", view));
+        }
+
+        // a controller that takes over should weigh the view the same way
+        self.persist_view_settings(authority, |state| {
+            if weight == 1 {
+                state.eviction_weights.remove(&view);
+            } else {
+                state.eviction_weights.insert(view.clone(), weight);
             }
+        })?;
+
+        self.send_to_reader(&view, |node| Packet::UpdateEvictionWeight { node, weight })?;
+        if weight == 1 {
+            self.eviction_weights.remove(&view);
+        } else {
+            self.eviction_weights.insert(view.clone(), weight);
         }
+        info!(self.log, "updated eviction weight"; "view" => view, "weight" => weight);
+        Ok(())
+    }
 
-        warn!(
-            self.log,
-            "flushed {} bytes of partial domain state", total_evicted
-        );
+    /// Have the reader of the view `view` hold on to the writes it applies for up to `every`
+    /// before it makes them visible, or make them visible right away if `every` is `None`.
+    fn set_publish_interval<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        view: String,
+        every: Option<Duration>,
+    ) -> Result<(), String> {
+        if self.find_reader(&view).is_none() {
+            return Err(format!("no view named {}", view));
+        }
 
-        total_evicted
+        // a controller that takes over should have the view publish the same way
+        self.persist_view_settings(authority, |state| match every {
+            Some(every) => {
+                state.publish_intervals.insert(view.clone(), every);
+            }
+            None => {
+                state.publish_intervals.remove(&view);
+            }
+        })?;
+
+        self.send_to_reader(&view, |node| Packet::UpdatePublishInterval { node, every })?;
+        match every {
+            Some(every) => self.publish_intervals.insert(view.clone(), every),
+            None => self.publish_intervals.remove(&view),
+        };
+        info!(self.log, "updated publish interval"; "view" => view, "every" => ?every);
+        Ok(())
+    }
+
+    /// Change the settings of views that the controller keeps in the authority.
+    fn persist_view_settings<A, F>(&self, authority: &Arc<A>, change: F) -> Result<(), String>
+    where
+        A: Authority + 'static,
+        F: Fn(&mut ControllerState),
+    {
+        authority
+            .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                None => unreachable!(),
+                Some(ref state) if state.epoch > self.epoch => Err(()),
+                Some(mut state) => {
+                    change(&mut state);
+                    Ok(state)
+                }
+            })
+            .map(|_| ())
+            .map_err(|_| "Failed to persist view settings".to_owned())
+    }
+
+    /// Send the packet that `make` makes for the reader of `view` to the domain that holds it, and
+    /// wait for it to ack, if the view exists.
+    fn send_to_reader<F>(&mut self, view: &str, make: F) -> Result<(), String>
+    where
+        F: FnOnce(LocalNodeIndex) -> Packet,
+    {
+        let reader = match self.find_reader(view) {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let node = self.ingredients[reader].local_addr();
+        let domain = self.ingredients[reader].domain();
+        let workers = &self.workers;
+        let d = self.domains.get_mut(&domain).unwrap();
+        d.send_to_healthy(box make(node), workers)
+            .map_err(|e| format!("failed to update view {}: {}", view, e))?;
+        self.replies.wait_for_acks(d)?;
+        Ok(())
+    }
+
+    /// Hand the settings that views have been given to their readers, which may be new.
+    fn send_view_settings(&mut self) -> Result<(), String> {
+        let weights: Vec<_> = self
+            .eviction_weights
+            .iter()
+            .map(|(v, &w)| (v.clone(), w))
+            .collect();
+        for (view, weight) in weights {
+            self.send_to_reader(&view, |node| Packet::UpdateEvictionWeight { node, weight })?;
+        }
+        let intervals: Vec<_> = self
+            .publish_intervals
+            .iter()
+            .map(|(v, &every)| (v.clone(), every))
+            .collect();
+        for (view, every) in intervals {
+            self.send_to_reader(&view, |node| Packet::UpdatePublishInterval {
+                node,
+                every: Some(every),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Send `payload` to every worker.
+    fn broadcast(&mut self, payload: CoordinationPayload) {
+        for endpoint in self.workers.values_mut() {
+            endpoint
+                .sender
+                .send(CoordinationMessage {
+                    epoch: self.epoch,
+                    source: endpoint.source,
+                    payload: payload.clone(),
+                })
+                .unwrap();
+        }
     }
 
     pub(super) fn create_universe(
@@ -1009,13 +2743,82 @@ impl ControllerInner {
                 }
                 Err(e) => {
                     crit!(log, "failed to create universe: {:?}", e);
-                    Err("failed to create universe".to_owned())
+                    Err(format!("failed to create universe: {}", e))
                 }
             }
-            .unwrap();
-        });
+        })??;
+
+        self.recipe = r;
+        Ok(())
+    }
 
+    /// Remove the security universe with the given context, along with the queries installed in
+    /// it, so that later universes neither reuse nor count them.
+    pub(super) fn remove_universe(
+        &mut self,
+        context: HashMap<String, DataType>,
+    ) -> Result<(), String> {
+        if context.get("id").is_none() {
+            return Err("universe context must have id".to_owned());
+        }
+        let mut r = self.recipe.clone();
+        let leaves = self.add_universe(context, |mig| {
+            r.next();
+            r.remove_universe(mig)
+        })?;
         self.recipe = r;
+        self.remove_leaves(&leaves)
+    }
+
+    /// The write to a security group's membership base table that makes `update`.
+    ///
+    /// Once the write has been applied, the group's membership view and the group universes that
+    /// depend on it update incrementally, and `update_group_membership` should be called.
+    fn membership_write(&self, update: GroupMembershipUpdate) -> Result<MembershipWrite, String> {
+        let table = self.recipe.membership_table(&update.group)?;
+        let builder = self
+            .table_builder(&table.table)
+            .ok_or_else(|| format!("membership table {} does not exist", table.table))?;
+
+        let mut row = vec![DataType::None; builder.columns.len()];
+        let values = vec![(&table.uid, &update.uid), (&table.gid, &update.gid)]
+            .into_iter()
+            .chain(table.fixed.iter().map(|&(ref c, ref v)| (c, v)));
+        for (column, value) in values {
+            let i = builder
+                .columns
+                .iter()
+                .position(|c| c == column)
+                .ok_or_else(|| {
+                    format!("membership table {} has no column {}", table.table, column)
+                })?;
+            row[i] = value.clone();
+        }
+
+        let key: Vec<_> = builder.key.iter().map(|&i| row[i].clone()).collect();
+        if !update.member && (!builder.key_is_primary || key.contains(&DataType::None)) {
+            return Err(format!(
+                "cannot remove members of group {}: the key of {} is not determined by the \
+                 membership query",
+                update.group, table.table
+            ));
+        }
+
+        Ok(MembershipWrite {
+            table: table.table,
+            row,
+            key,
+            fields: table.fields,
+        })
+    }
+
+    /// Record a change to the members of a security group, once it has been written to the
+    /// group's membership base table, so that queries installed in the user's universe from now
+    /// on see the new membership.
+    fn update_group_membership(&mut self, update: GroupMembershipUpdate) -> Result<(), String> {
+        self.recipe.membership_table(&update.group)?;
+        self.recipe
+            .update_group_membership(&update.group, &update.uid, &update.gid, update.member);
         Ok(())
     }
 
@@ -1025,10 +2828,25 @@ impl ControllerInner {
     }
 
     fn apply_recipe(&mut self, mut new: Recipe) -> Result<ActivationResult, String> {
-        let r = self.migrate(|mig| {
+        // a migration that fails is undone, and so must be what it did to the queries
+        let (saved, inc) = (new.save_mir(), new.sql_inc().clone());
+        let r = match self.migrate_with_result(|mig| {
             new.activate(mig)
                 .map_err(|e| format!("failed to activate recipe: {}", e))
-        });
+        }) {
+            Ok((r, result)) => r.map(|mut ra| {
+                ra.migration = Some(result);
+                ra
+            }),
+            Err(e) => {
+                crit!(self.log, "failed to apply recipe: {}", e);
+                SqlIncorporator::restore_mir(saved);
+                let mut old = new.revert();
+                old.set_sql_inc(inc);
+                self.recipe = old;
+                return Err(format!("failed to apply recipe: {}", e));
+            }
+        };
 
         match r {
             Ok(ref ra) => {
@@ -1039,18 +2857,7 @@ impl ControllerInner {
                     .partition(|ni| self.ingredients[*ni].is_base());
 
                 // first remove query nodes in reverse topological order
-                let mut topo_removals = Vec::with_capacity(removed_other.len());
-                let mut topo = petgraph::visit::Topo::new(&self.ingredients);
-                while let Some(node) = topo.next(&self.ingredients) {
-                    if removed_other.contains(&node) {
-                        topo_removals.push(node);
-                    }
-                }
-                topo_removals.reverse();
-
-                for leaf in topo_removals {
-                    self.remove_leaf(leaf)?;
-                }
+                self.remove_leaves(&removed_other)?;
 
                 // now remove bases
                 for base in removed_bases {
@@ -1073,6 +2880,9 @@ impl ControllerInner {
                 }
 
                 self.recipe = new;
+
+                // views may have been given new readers, which start out with default settings
+                self.send_view_settings()?;
             }
             Err(ref e) => {
                 crit!(self.log, "failed to apply recipe: {}", e);
@@ -1090,16 +2900,33 @@ impl ControllerInner {
         authority: &Arc<A>,
         add_txt: String,
     ) -> Result<ActivationResult, String> {
+        if self.shadow.is_some() {
+            return Err("cannot extend recipe during a shadow migration".to_owned());
+        }
+
+        // the declarations are kept in the recipe, so recovery does not infer them again
+        let add_txt = if self.infer_tables {
+            self.recipe.with_inferred_tables(&add_txt, false)?
+        } else {
+            add_txt
+        };
+
         // needed because self.apply_recipe needs to mutate self.recipe, so can't have it borrowed
         let new = mem::replace(&mut self.recipe, Recipe::blank(None));
         match new.extend(&add_txt) {
             Ok(new) => {
-                let activation_result = self.apply_recipe(new);
+                // a recipe that failed to apply is not recovered either
+                self.committed.clear();
+                let activation_result = self.apply_recipe(new)?;
+                let committed = mem::replace(&mut self.committed, Vec::new());
+                let change = Change::Extend(add_txt.clone());
+                let journaled = self.write_journal(authority, change, &committed)?;
                 if authority
                     .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
                         None => unreachable!(),
                         Some(ref state) if state.epoch > self.epoch => Err(()),
                         Some(mut state) => {
+                            journal::record(&mut state, journaled);
                             state.recipe_version = self.recipe.version();
                             state.recipes.push(add_txt.clone());
                             Ok(state)
@@ -1109,8 +2936,10 @@ impl ControllerInner {
                 {
                     return Err("Failed to persist recipe extension".to_owned());
                 }
+                self.journal = journaled;
+                self.persisted_versions.push(self.recipe.version());
 
-                activation_result
+                Ok(activation_result)
             }
             Err((old, e)) => {
                 // need to restore the old recipe
@@ -1121,23 +2950,279 @@ impl ControllerInner {
         }
     }
 
+    /// Write `change` and what the migrations it `committed` did to the journal, if changes are
+    /// journalled, and return the length that the journal has with it.
+    fn write_journal<A: Authority + 'static>(
+        &self,
+        authority: &Arc<A>,
+        change: Change,
+        committed: &[journal::Committed],
+    ) -> Result<Option<usize>, String> {
+        match self.journal {
+            Some(len) => journal::write(&**authority, len, change, committed).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Make the recorded recipe change `change` again, as a controller that takes over does.
+    fn replay(&mut self, change: &Change) -> Result<ActivationResult, String> {
+        match *change {
+            Change::Install(ref r_txt) => {
+                let r = Recipe::from_str(r_txt, Some(self.log.clone()))?;
+                let activation_result = self.apply_recipe(self.recipe.clone().replace(r).unwrap());
+                self.persisted_versions = vec![self.recipe.version()];
+                activation_result
+            }
+            Change::Extend(ref add_txt) => {
+                let new = self.recipe.clone().extend(add_txt).map_err(|(_, e)| e)?;
+                let activation_result = self.apply_recipe(new);
+                self.persisted_versions.push(self.recipe.version());
+                activation_result
+            }
+            Change::Remove(ref name) => {
+                let without = self.recipe.clone().without(&[name.clone()]);
+                self.apply_recipe(without)
+            }
+            Change::Rollback(version) => {
+                let kept = self
+                    .persisted_versions
+                    .iter()
+                    .position(|&v| v == version)
+                    .ok_or_else(|| format!("recipe version {} was not persisted", version))?
+                    + 1;
+                let target = self
+                    .recipe
+                    .prior_at(version)
+                    .cloned()
+                    .ok_or_else(|| format!("no recipe with version {}", version))?;
+                let new = self.recipe.clone().replace(target).unwrap();
+                let activation_result = self.apply_recipe(new);
+                self.persisted_versions.truncate(kept);
+                self.persisted_versions[kept - 1] = self.recipe.version();
+                activation_result
+            }
+        }
+    }
+
+    /// Work out what extending the recipe with `add_txt` would change in the running graph,
+    /// without changing it.
+    fn plan_recipe(&mut self, add_txt: String) -> Result<MigrationPlan, String> {
+        if self.shadow.is_some() {
+            return Err("cannot plan recipe during a shadow migration".to_owned());
+        }
+
+        let add_txt = if self.infer_tables {
+            self.recipe.with_inferred_tables(&add_txt, false)?
+        } else {
+            add_txt
+        };
+
+        // the recipe is planned on a clone, which shares the MIR of the queries it already has
+        let saved = self.recipe.save_mir();
+        let mut new = self
+            .recipe
+            .clone()
+            .extend(&add_txt)
+            .map_err(|(_, e)| format!("failed to extend recipe: {:?}", e))?;
+        let (r, plan) = self.plan_migration(|mig| new.activate(mig));
+        SqlIncorporator::restore_mir(saved);
+        r.map_err(|e| format!("failed to activate recipe: {}", e))?;
+        Ok(plan)
+    }
+
+    /// Undo the migrations made since the recipe was at `version`, by removing the queries and
+    /// tables they added and adding back the ones they removed.
+    ///
+    /// Only versions that were persisted since the recipe was last installed can be rolled back
+    /// to. The recipe moves on to a new version with the same contents as `version`.
+    fn rollback_migration<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        version: usize,
+    ) -> Result<ActivationResult, String> {
+        if self.shadow.is_some() {
+            return Err("cannot roll back during a shadow migration".to_owned());
+        }
+        if version >= self.recipe.version() {
+            return Err(format!(
+                "cannot roll back to version {} from version {}",
+                version,
+                self.recipe.version()
+            ));
+        }
+        let kept = match self.persisted_versions.iter().position(|&v| v == version) {
+            Some(i) => i + 1,
+            None => return Err(format!("recipe version {} was not persisted", version)),
+        };
+
+        let target = match self.recipe.prior_at(version) {
+            Some(r) => r.clone(),
+            None => return Err(format!("no recipe with version {}", version)),
+        };
+
+        self.committed.clear();
+        let old = mem::replace(&mut self.recipe, Recipe::blank(None));
+        let new = old.replace(target).unwrap();
+        let activation_result = self.apply_recipe(new)?;
+        let committed = mem::replace(&mut self.committed, Vec::new());
+        let journaled = self.write_journal(authority, Change::Rollback(version), &committed)?;
+        if authority
+            .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                None => unreachable!(),
+                Some(ref state) if state.epoch > self.epoch => Err(()),
+                Some(mut state) => {
+                    journal::record(&mut state, journaled);
+                    state.recipe_version = self.recipe.version();
+                    state.recipes.truncate(kept);
+                    // queries removed since are back
+                    state.removals.retain(|&(after, _)| after < kept);
+                    Ok(state)
+                }
+            })
+            .is_err()
+        {
+            return Err("Failed to persist recipe rollback".to_owned());
+        }
+        self.journal = journaled;
+        // the persisted recipes now bring the recipe up to the new version instead
+        self.persisted_versions.truncate(kept);
+        self.persisted_versions[kept - 1] = self.recipe.version();
+        Ok(activation_result)
+    }
+
+    /// Remove the query `name` from the recipe, along with any nodes that only it used.
+    fn remove_query<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        name: String,
+    ) -> Result<ActivationResult, String> {
+        if self.shadow.is_some() {
+            return Err("cannot remove a query during a shadow migration".to_owned());
+        }
+        let ni = self.recipe.node_addr_for(&name)?;
+        if self.ingredients[ni].is_base() {
+            return Err(format!("{} is a table, not a query", name));
+        }
+
+        self.committed.clear();
+        let old = mem::replace(&mut self.recipe, Recipe::blank(None));
+        let activation_result = self.apply_recipe(old.without(&[name.clone()]))?;
+        let committed = mem::replace(&mut self.committed, Vec::new());
+        let journaled = self.write_journal(authority, Change::Remove(name.clone()), &committed)?;
+        if authority
+            .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                None => unreachable!(),
+                Some(ref state) if state.epoch > self.epoch => Err(()),
+                Some(mut state) => {
+                    journal::record(&mut state, journaled);
+                    state.recipe_version = self.recipe.version();
+                    state.removals.push((state.recipes.len(), name.clone()));
+                    Ok(state)
+                }
+            })
+            .is_err()
+        {
+            return Err("Failed to persist query removal".to_owned());
+        }
+        self.journal = journaled;
+
+        Ok(activation_result)
+    }
+
+    /// Plan the statement `sql` for a client that will execute it many times, and add its query
+    /// to the recipe if it is a `SELECT` that the recipe does not have yet.
+    fn prepare<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        sql: String,
+    ) -> Result<StatementPlan, String> {
+        let q = sql_parser::parse_query(&sql).map_err(|_| format!("failed to parse {}", sql))?;
+        let sq = match q {
+            SqlQuery::Select(ref sq) => sq,
+            SqlQuery::Insert(ref iq) => return self.plan_write(&q, &iq.table.name),
+            SqlQuery::Update(ref uq) => return self.plan_write(&q, &uq.table.name),
+            SqlQuery::Delete(ref dq) => return self.plan_write(&q, &dq.table.name),
+            _ => return Err("only SELECT, INSERT, UPDATE and DELETE can be prepared".to_owned()),
+        };
+
+        let name = match self.recipe.name_of(&q).map(str::to_owned) {
+            Some(name) => name,
+            None if self.recipe.contains(&q) => {
+                return Err(format!("{} is in the recipe, but not under a name", sql));
+            }
+            None => {
+                let name = prepared::query_name(&q);
+                let query = format!("QUERY {}: {};", name, sql.trim().trim_end_matches(';'));
+                self.extend_recipe(authority, query)?;
+                name
+            }
+        };
+
+        let reader = self
+            .find_reader(&name)
+            .ok_or_else(|| format!("no view named {}", name))?;
+        let key = match self.ingredients[reader].with_reader(|r| r.key().map(Vec::from)) {
+            Ok(Some(key)) => key,
+            _ => return Err(format!("view {} is not keyed", name)),
+        };
+        let key: Vec<_> = key
+            .into_iter()
+            .map(|c| {
+                let column = self.ingredients[reader].fields()[c].clone();
+                let cs =
+                    schema::column_schema(&self.ingredients, reader, &self.recipe, c, &self.log);
+                (column, cs.map(|cs| cs.sql_type))
+            })
+            .collect();
+        let mut params = Vec::new();
+        if let Some(ref cond) = sq.where_clause {
+            prepared::placeholders(cond, &mut params);
+        }
+        prepared::plan_lookup(&name, &key, &params)
+    }
+
+    /// Plan the write `q` to the base table `table`.
+    fn plan_write(&self, q: &SqlQuery, table: &str) -> Result<StatementPlan, String> {
+        match self.recipe.schema_for(table) {
+            Some(Schema::Table(ref schema)) => prepared::plan_write(q, schema),
+            _ => Err(format!("no base table named {}", table)),
+        }
+    }
+
     fn install_recipe<A: Authority + 'static>(
         &mut self,
         authority: &Arc<A>,
         r_txt: String,
     ) -> Result<ActivationResult, String> {
+        if self.shadow.is_some() {
+            return Err("cannot install recipe during a shadow migration".to_owned());
+        }
+
+        let r_txt = if self.infer_tables {
+            self.recipe.with_inferred_tables(&r_txt, true)?
+        } else {
+            r_txt
+        };
+
         match Recipe::from_str(&r_txt, Some(self.log.clone())) {
             Ok(r) => {
                 let old = mem::replace(&mut self.recipe, Recipe::blank(None));
                 let new = old.replace(r).unwrap();
-                let activation_result = self.apply_recipe(new);
+                // a recipe that failed to apply is not recovered either
+                self.committed.clear();
+                let activation_result = self.apply_recipe(new)?;
+                let committed = mem::replace(&mut self.committed, Vec::new());
+                let change = Change::Install(r_txt.clone());
+                let journaled = self.write_journal(authority, change, &committed)?;
                 if authority
                     .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
                         None => unreachable!(),
                         Some(ref state) if state.epoch > self.epoch => Err(()),
                         Some(mut state) => {
+                            journal::record(&mut state, journaled);
                             state.recipe_version = self.recipe.version();
                             state.recipes = vec![r_txt.clone()];
+                            state.removals.clear();
                             Ok(state)
                         }
                     })
@@ -1145,7 +3230,9 @@ impl ControllerInner {
                 {
                     return Err("Failed to persist recipe installation".to_owned());
                 }
-                activation_result
+                self.journal = journaled;
+                self.persisted_versions = vec![self.recipe.version()];
+                Ok(activation_result)
             }
             Err(e) => {
                 crit!(self.log, "failed to parse recipe: {:?}", e);
@@ -1154,12 +3241,161 @@ impl ControllerInner {
         }
     }
 
+    /// Install the queries that the recipe `r_txt` adds or changes next to the live ones, so that
+    /// they can be evaluated on live writes for `soak` before `r_txt` is installed.
+    fn shadow_recipe(
+        &mut self,
+        (r_txt, soak): (String, Duration),
+    ) -> Result<ActivationResult, String> {
+        if self.shadow.is_some() {
+            return Err("a shadow migration is already in progress".to_owned());
+        }
+
+        let proposed = Recipe::from_str(&r_txt, None)?;
+        let (additions, queries) = shadow::shadow_queries(&self.recipe, &proposed)?;
+        let mut added: Vec<_> = queries
+            .keys()
+            .filter(|q| self.recipe.node_addr_for(q).is_err())
+            .cloned()
+            .collect();
+        added.sort();
+
+        // nodes are never removed from the graph, so the shadow's nodes are all those added by
+        // its migration
+        let before = self.ingredients.node_count();
+        let old = mem::replace(&mut self.recipe, Recipe::blank(None));
+        let new = match old.extend(&additions) {
+            Ok(new) => new,
+            Err((old, e)) => {
+                crit!(self.log, "failed to shadow recipe: {:?}", e);
+                self.recipe = old;
+                return Err("failed to shadow recipe".to_owned());
+            }
+        };
+        let activation_result = self.apply_recipe(new)?;
+
+        info!(self.log, "started shadow migration";
+              "queries" => queries.len(),
+              "soak" => ?soak);
+        self.shadow = Some(Shadow {
+            recipe: r_txt,
+            queries,
+            added,
+            nodes: (before..self.ingredients.node_count())
+                .map(NodeIndex::new)
+                .collect(),
+            started: Instant::now(),
+            soak,
+        });
+        Ok(activation_result)
+    }
+
+    /// Report on the cost of the shadow migration so far.
+    fn shadow_report(&mut self) -> Result<ShadowReport, String> {
+        let nodes = match self.shadow {
+            Some(ref shadow) => shadow.nodes.clone(),
+            None => return Err("no shadow migration is in progress".to_owned()),
+        };
+
+        let mut state_bytes = 0;
+        let mut process_time = 0;
+        for (_, node_stats) in self.get_statistics().domains.into_iter().map(|(_, s)| s) {
+            for (ni, ns) in node_stats {
+                if nodes.contains(&ni) {
+                    state_bytes += ns.mem_size;
+                    process_time += ns.process_time;
+                }
+            }
+        }
+
+        let shadow = self.shadow.as_ref().unwrap();
+        Ok(ShadowReport {
+            queries: shadow.queries.clone(),
+            added: shadow.added.clone(),
+            soaked: shadow.started.elapsed(),
+            ready: shadow.ready(),
+            nodes: nodes.len(),
+            state_bytes,
+            process_time,
+        })
+    }
+
+    /// Install the recipe that is running in the shadow, once it has soaked for long enough.
+    fn promote_shadow<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+    ) -> Result<ActivationResult, String> {
+        match self.shadow {
+            None => return Err("no shadow migration is in progress".to_owned()),
+            Some(ref shadow) if !shadow.ready() => {
+                return Err("shadow migration has not soaked for long enough".to_owned());
+            }
+            Some(_) => {}
+        }
+
+        // the proposed recipe does not contain the shadow queries, so installing it also removes
+        // them. any nodes that the new live queries reuse from the shadow are kept.
+        let shadow = self.shadow.take().unwrap();
+        let r = self.install_recipe(authority, shadow.recipe.clone());
+        if r.is_err() {
+            self.shadow = Some(shadow);
+        }
+        r
+    }
+
+    /// Remove the shadow queries, leaving the live recipe as it was.
+    fn discard_shadow(&mut self) -> Result<ActivationResult, String> {
+        let shadow = match self.shadow.take() {
+            Some(shadow) => shadow,
+            None => return Err("no shadow migration is in progress".to_owned()),
+        };
+
+        let queries: Vec<_> = shadow.queries.values().cloned().collect();
+        let old = mem::replace(&mut self.recipe, Recipe::blank(None));
+        self.apply_recipe(old.without(&queries))
+    }
+
     fn graphviz(&self, detailed: bool) -> String {
-        graphviz(&self.ingredients, detailed, &self.materializations)
+        // show who owns each named query next to the node that computes it
+        let notes: Vec<_> = self
+            .recipe
+            .metadata()
+            .into_iter()
+            .filter_map(|m| {
+                let leaf = self.recipe.node_addr_for(&m.name).ok()?;
+                let mut note = format!("{}\\n", m.name);
+                if let Some(ref owner) = m.owner {
+                    note.push_str(&format!("owner: {}\\n", owner));
+                }
+                if !m.tags.is_empty() {
+                    note.push_str(&format!("tags: {}\\n", m.tags.join(", ")));
+                }
+                Some((leaf, note))
+            })
+            .collect();
+        annotated_graphviz(&self.ingredients, detailed, &self.materializations, &notes)
+    }
+
+    /// Remove the query leaves `leaves`, children before their parents.
+    fn remove_leaves(&mut self, leaves: &[NodeIndex]) -> Result<(), String> {
+        let mut topo_removals = Vec::with_capacity(leaves.len());
+        let mut topo = petgraph::visit::Topo::new(&self.ingredients);
+        while let Some(node) = topo.next(&self.ingredients) {
+            if leaves.contains(&node) {
+                topo_removals.push(node);
+            }
+        }
+        topo_removals.reverse();
+
+        for leaf in topo_removals {
+            self.remove_leaf(leaf)?;
+        }
+        Ok(())
     }
 
-    fn remove_leaf(&mut self, mut leaf: NodeIndex) -> Result<(), String> {
+    pub(super) fn remove_leaf(&mut self, mut leaf: NodeIndex) -> Result<(), String> {
         let mut removals = vec![];
+        let mut detached = vec![];
         let start = leaf;
         assert!(!self.ingredients[leaf].is_source());
 
@@ -1199,19 +3435,20 @@ impl ControllerInner {
                 );
                 unreachable!();
             }
-            // nodes can have only one reader attached
-            assert!(readers.len() <= 1);
             debug!(
                 self.log,
                 "Removing query leaf \"{}\"", self.ingredients[leaf].name();
                 "node" => leaf.index(),
             );
-            if !readers.is_empty() {
-                removals.push(readers[0]);
-                leaf = readers[0];
-            } else {
-                unreachable!();
+            // a node has a reader for each of the keys that it is looked up by
+            let (&first, rest) = readers.split_first().unwrap();
+            for &reader in rest {
+                let edge = self.ingredients.find_edge(leaf, reader).unwrap();
+                self.ingredients.remove_edge(edge);
+                removals.push(reader);
             }
+            removals.push(first);
+            leaf = first;
         }
 
         // `node` now does not have any children any more
@@ -1242,26 +3479,49 @@ impl ControllerInner {
                         .count() == 0
                 {
                     nodes.push(parent);
+                } else if self.ingredients[parent].is_egress() {
+                    detached.push((parent, node));
                 }
             }
 
             removals.push(node);
         }
 
+        // egress nodes that are kept because they still feed other domains must stop sending to
+        // the ingress nodes that are removed
+        for (egress, ingress) in detached {
+            let domain = self.ingredients[egress].domain();
+            let m = box Packet::RemoveEgressTx {
+                node: self.ingredients[egress].local_addr(),
+                target: ingress,
+            };
+            self.domains
+                .get_mut(&domain)
+                .unwrap()
+                .send_to_healthy(m, &self.workers)
+                .map_err(|e| format!("failed to detach removed node: {:?}", e))?;
+        }
+
         self.remove_nodes(removals.as_slice())
     }
 
-    fn remove_nodes(&mut self, removals: &[NodeIndex]) -> Result<(), String> {
+    pub(super) fn remove_nodes(&mut self, removals: &[NodeIndex]) -> Result<(), String> {
         // Remove node from controller local state
         let mut domain_removals: HashMap<DomainIndex, Vec<LocalNodeIndex>> = HashMap::default();
         for ni in removals {
             self.ingredients[*ni].remove();
+            self.materializations.forget(*ni);
+            self.placements.remove(ni);
             debug!(self.log, "Removed node {}", ni.index());
             domain_removals
                 .entry(self.ingredients[*ni].domain())
                 .or_insert_with(Vec::new)
                 .push(self.ingredients[*ni].local_addr())
         }
+        for nodes in self.universe_nodes.values_mut() {
+            nodes.retain(|ni| !removals.contains(ni));
+        }
+        self.universe_nodes.retain(|_, nodes| !nodes.is_empty());
 
         // Send messages to domains
         for (domain, nodes) in domain_removals {
@@ -1271,12 +3531,12 @@ impl ControllerInner {
                 domain.index(),
             );
 
-            match self
-                .domains
-                .get_mut(&domain)
-                .unwrap()
-                .send_to_healthy(box Packet::RemoveNodes { nodes }, &self.workers)
-            {
+            // an aborted migration shuts down the domains it booted before removing their nodes
+            let d = match self.domains.get_mut(&domain) {
+                Some(d) => d,
+                None => continue,
+            };
+            match d.send_to_healthy(box Packet::RemoveNodes { nodes }, &self.workers) {
                 Ok(_) => (),
                 Err(e) => match e {
                     SendError::IoError(ref ioe) => {
@@ -0,0 +1,75 @@
+//! Checks of the global invariants that migrations are meant to maintain in the data-flow graph.
+//!
+//! Migrations only ever check the parts of the graph that they touch, so a bug in how an earlier
+//! migration hooked up domains may not surface until much later. These checks instead look at the
+//! whole graph as the controller sees it, and are cheap enough to run after every migration in
+//! tests.
+
+use dataflow::prelude::*;
+use noria::debug::invariants::Violation;
+use std::collections::{HashMap, HashSet};
+
+/// Check `graph` against the number of shards of each booted domain, and the local index of each
+/// node in its domain as recorded in `remap`.
+pub(super) fn check(
+    graph: &Graph,
+    shards: &HashMap<DomainIndex, usize>,
+    remap: &HashMap<DomainIndex, HashMap<NodeIndex, IndexPair>>,
+) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let mut locals = HashSet::new();
+    for ni in graph.node_indices() {
+        let n = &graph[ni];
+        if n.is_source() || !n.has_domain() {
+            continue;
+        }
+        let domain = n.domain();
+
+        // even dropped nodes keep their index in the domain
+        let remapped = remap.get(&domain).and_then(|r| r.get(&ni));
+        if remapped.map(|ip| **ip) != Some(n.local_addr())
+            || !locals.insert((domain, n.local_addr()))
+        {
+            violations.push(Violation::RemapMismatch { node: ni, domain });
+        }
+
+        if n.is_dropped() {
+            continue;
+        }
+
+        // every edge that crosses domains must go from a sender into an ingress
+        let mut fed = false;
+        for pi in graph.neighbors_directed(ni, petgraph::EdgeDirection::Incoming) {
+            let p = &graph[pi];
+            if p.is_source() || p.is_dropped() {
+                continue;
+            }
+            if p.is_sender() {
+                fed = true;
+            }
+            if p.domain() != domain && !(p.is_sender() && n.is_ingress()) {
+                violations.push(Violation::UnroutedEdge { from: pi, to: ni });
+            }
+        }
+        if n.is_ingress() && !fed {
+            violations.push(Violation::OrphanedIngress(ni));
+        }
+
+        if let Some(&domain_shards) = shards.get(&domain) {
+            let node_shards = n.sharded_by().shards().unwrap_or(1);
+            if node_shards != domain_shards {
+                violations.push(Violation::ShardingMismatch {
+                    node: ni,
+                    domain,
+                    shards: node_shards,
+                    domain_shards,
+                });
+            }
+        }
+
+        if n.with_reader(|r| r.key().is_none()).unwrap_or(false) {
+            violations.push(Violation::UnkeyedReader(ni));
+        }
+    }
+    violations
+}
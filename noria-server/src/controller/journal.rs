@@ -0,0 +1,236 @@
+//! A record of the recipe changes the controller has made, and of what each migration they
+//! committed did to the graph.
+//!
+//! The journal is kept in the authority next to the recipes. A controller that takes over makes
+//! the recorded changes again, one by one and in the order they were first made, rather than
+//! applying the recipes that are left once rollbacks and removals are accounted for. That hands
+//! out node indices, domains, and local addresses the same way they were handed out the first
+//! time, and every migration that recovery commits is checked against the one that was recorded.
+//! A controller whose graph comes out differently does not take over.
+//!
+//! Since the graph that a change makes depends on every change made before it, the journal can't
+//! be cut short. It is kept compact instead: each entry is kept under a key of its own rather
+//! than in the controller's state, and each migration is only recorded as a digest of what it
+//! did, which is enough to tell whether recovery committed the same one.
+
+use crate::controller::migrate::ColumnChange;
+use crate::controller::ControllerState;
+use dataflow::prelude::*;
+use noria::consensus::Authority;
+use serde_json;
+use std::collections::HashSet;
+
+/// The prefix of the keys that the entries of the journal are kept under in the authority.
+const JOURNAL_KEY: &str = "/journal";
+
+/// A change to the recipe.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(super) enum Change {
+    /// The recipe was replaced by this one.
+    Install(String),
+    /// The recipe was extended with this.
+    Extend(String),
+    /// The query with this name was removed from the recipe.
+    Remove(String),
+    /// The recipe was rolled back to this version.
+    Rollback(usize),
+}
+
+/// A node that a migration added.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(super) struct Added {
+    pub(super) index: usize,
+    pub(super) name: String,
+    pub(super) domain: usize,
+    pub(super) local: usize,
+}
+
+/// What a committed migration did to the graph.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(super) struct Committed {
+    /// The nodes that were added, in the order of their indices.
+    pub(super) nodes: Vec<Added>,
+    /// The changes to the columns of base tables, by the index of the base table.
+    pub(super) columns: Vec<(usize, ColumnChange)>,
+    /// The key of each reader that was added, by the index of the reader.
+    pub(super) readers: Vec<(usize, Vec<usize>)>,
+}
+
+impl Committed {
+    /// Record the nodes in `new`, as they were committed in `graph`, along with `columns`.
+    pub(super) fn of(
+        graph: &Graph,
+        new: &HashSet<NodeIndex>,
+        columns: Vec<(usize, ColumnChange)>,
+    ) -> Self {
+        let mut new: Vec<_> = new
+            .iter()
+            .cloned()
+            .filter(|&ni| !graph[ni].is_dropped() && !graph[ni].is_source())
+            .collect();
+        new.sort();
+
+        let nodes = new
+            .iter()
+            .map(|&ni| Added {
+                index: ni.index(),
+                name: graph[ni].name().to_owned(),
+                domain: graph[ni].domain().index(),
+                local: graph[ni].local_addr().id(),
+            })
+            .collect();
+        let readers = new
+            .iter()
+            .filter_map(|&ni| {
+                let key = graph[ni].with_reader(|r| r.key().map(Vec::from)).ok()?;
+                Some((ni.index(), key.unwrap_or_default()))
+            })
+            .collect();
+        Committed {
+            nodes,
+            columns,
+            readers,
+        }
+    }
+}
+
+/// What is recorded of a committed migration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Digest {
+    /// The number of nodes that the migration added.
+    pub(super) nodes: usize,
+    /// A hash of everything the migration did, which does not depend on the process it is
+    /// computed in.
+    pub(super) hash: u64,
+}
+
+impl<'a> From<&'a Committed> for Digest {
+    fn from(c: &'a Committed) -> Self {
+        // FNV-1a, over the same encoding that the journal used to hold in full
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for b in serde_json::to_vec(c).unwrap() {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        Digest {
+            nodes: c.nodes.len(),
+            hash,
+        }
+    }
+}
+
+/// A change to the recipe, and the migrations that making it committed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(super) struct Entry {
+    pub(super) change: Change,
+    pub(super) migrations: Vec<Digest>,
+}
+
+fn key(i: usize) -> String {
+    format!("{}-{}", JOURNAL_KEY, i)
+}
+
+/// Write `change` and the `migrations` it committed to the authority as the entry that follows
+/// the `len` entries that the journal has, and return the length of the journal with it.
+///
+/// The entry only becomes part of the journal once the length is persisted in the controller's
+/// state, along with the recipes that the change leads to. An entry that was written for a change
+/// that was never persisted is written over by the next one.
+pub(super) fn write<A: Authority>(
+    authority: &A,
+    len: usize,
+    change: Change,
+    migrations: &[Committed],
+) -> Result<usize, String> {
+    let entry = Entry {
+        change,
+        migrations: migrations.iter().map(Digest::from).collect(),
+    };
+    match authority.read_modify_write(&key(len), |_: Option<Entry>| Ok::<_, ()>(entry.clone())) {
+        Ok(Ok(_)) => Ok(len + 1),
+        _ => Err("failed to persist recipe journal".to_owned()),
+    }
+}
+
+/// Read the `i`th entry of the journal.
+pub(super) fn read<A: Authority>(authority: &A, i: usize) -> Result<Entry, String> {
+    match authority.try_read(&key(i)) {
+        Ok(Some(data)) => serde_json::from_slice(&data)
+            .map_err(|e| format!("journal entry {} is corrupt: {}", i, e)),
+        _ => Err(format!("journal entry {} is missing", i)),
+    }
+}
+
+/// Make the journal in `state` include the entries written so far, if changes are journalled, so
+/// that they are persisted along with the recipes in `state`.
+pub(super) fn record(state: &mut ControllerState, len: Option<usize>) {
+    if let Some(len) = len {
+        state.journal = len;
+    }
+}
+
+/// Describe how the migrations that were `replayed` differ from the ones that were `recorded`, if
+/// they do.
+pub(super) fn divergence(recorded: &[Digest], replayed: &[Committed]) -> Option<String> {
+    if recorded.len() != replayed.len() {
+        return Some(format!(
+            "recorded {} migrations, but committed {}",
+            recorded.len(),
+            replayed.len()
+        ));
+    }
+    recorded
+        .iter()
+        .zip(replayed.iter().map(Digest::from))
+        .enumerate()
+        .find(|&(_, (r, ref p))| r != p)
+        .map(|(i, (r, p))| {
+            if r.nodes != p.nodes {
+                format!(
+                    "migration {} added {} nodes, but {} were recorded",
+                    i, p.nodes, r.nodes
+                )
+            } else {
+                format!("migration {} added different nodes than were recorded", i)
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn added(index: usize, domain: usize) -> Added {
+        Added {
+            index,
+            name: format!("n{}", index),
+            domain,
+            local: 0,
+        }
+    }
+
+    #[test]
+    fn it_finds_where_replays_diverge() {
+        let committed = vec![Committed {
+            nodes: vec![added(1, 0), added(2, 1)],
+            columns: vec![(1, ColumnChange::Drop(0))],
+            readers: vec![(2, vec![0])],
+        }];
+        let recorded: Vec<_> = committed.iter().map(Digest::from).collect();
+        assert_eq!(divergence(&recorded, &committed.clone()), None);
+        assert_eq!(
+            divergence(&recorded, &[]).unwrap(),
+            "recorded 1 migrations, but committed 0"
+        );
+
+        let mut replayed = committed.clone();
+        replayed[0].nodes[1].domain = 2;
+        let d = divergence(&recorded, &replayed).unwrap();
+        assert_eq!(d, "migration 0 added different nodes than were recorded");
+
+        let mut replayed = committed.clone();
+        replayed[0].nodes.pop();
+        let d = divergence(&recorded, &replayed).unwrap();
+        assert_eq!(d, "migration 0 added 1 nodes, but 2 were recorded");
+    }
+}
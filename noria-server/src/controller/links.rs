@@ -0,0 +1,184 @@
+//! Keeping base tables in step with views in other deployments.
+//!
+//! Readers do not expose the changes they apply, so a link reads the whole upstream view every so
+//! often, and writes the difference from what it read the time before to the table: rows that
+//! have appeared or changed are upserted by the table's primary key, and rows that have gone are
+//! deleted. The view must not take parameters, and the table must have a primary key. A link that
+//! starts afresh, such as after the controller fails over, upserts every row of the view once, but
+//! cannot tell which rows went away while no link was running.
+
+use noria::consensus::Authority;
+use noria::{ControllerHandle, DataType, Modification, TableOperation};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::prelude::*;
+
+/// How often links read the views they feed from.
+const POLL_EVERY: Duration = Duration::from_millis(500);
+
+/// How many rows of a view links read at a time.
+const CHUNK: usize = 1024;
+
+/// A base table that is fed from a view in another deployment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+crate struct ViewLink {
+    /// The table that is fed.
+    crate table: String,
+    /// The view that it is fed from.
+    crate view: String,
+    /// The ZooKeeper address of the deployment that the view is in.
+    crate upstream: String,
+}
+
+/// A running link, which stops once it is dropped.
+crate struct Subscription {
+    link: ViewLink,
+    stop: Arc<AtomicBool>,
+}
+
+impl Subscription {
+    /// Start feeding the table of `link`, in the deployment that `authority` leads to, from its
+    /// view. Failures are logged, and the link starts over.
+    crate fn start<A: Authority + 'static>(
+        link: ViewLink,
+        authority: Arc<A>,
+        log: slog::Logger,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let l = link.clone();
+        thread::Builder::new()
+            .name(format!("link-{}", link.table))
+            .spawn(move || {
+                let mut rt = tokio::runtime::Runtime::new().unwrap();
+                while !stopped.load(Ordering::SeqCst) {
+                    if let Err(e) = follow(&l, &authority, &mut rt, &stopped) {
+                        warn!(log, "link to upstream view failed";
+                              "table" => &l.table,
+                              "view" => &l.view,
+                              "error" => %e);
+                        thread::sleep(POLL_EVERY);
+                    }
+                }
+            })
+            .unwrap();
+        Subscription { link, stop }
+    }
+
+    /// The link this subscription runs.
+    crate fn link(&self) -> &ViewLink {
+        &self.link
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Feed the table of `link` from its view until `stop` is set, or something fails.
+fn follow<A: Authority + 'static>(
+    link: &ViewLink,
+    authority: &Arc<A>,
+    rt: &mut tokio::runtime::Runtime,
+    stop: &AtomicBool,
+) -> Result<(), failure::Error> {
+    let name = link.view.clone();
+    let upstream = ControllerHandle::from_zk(&link.upstream);
+    let view = rt.block_on(upstream.and_then(move |mut ch| ch.view(&name)))?;
+    let view = view.into_sync();
+    let name = link.table.clone();
+    let local = ControllerHandle::make(authority.clone());
+    let table = rt.block_on(local.and_then(move |mut ch| ch.table(&name)))?;
+    let mut table = table.into_sync();
+
+    let key = match table.primary_key() {
+        Some(key) => key.to_vec(),
+        None => bail!("table {} has no primary key", link.table),
+    };
+    let width = table.columns().len();
+    let mut last = HashMap::new();
+    while !stop.load(Ordering::SeqCst) {
+        let mut rows = HashMap::new();
+        for row in view.lookup_stream(&[0.into()], CHUNK, true) {
+            let mut row = row.map_err(|e| format_err!("failed to read {}: {:?}", link.view, e))?;
+            if row.len() < width {
+                bail!("view {} has too few columns for {}", link.view, link.table);
+            }
+            // views may carry hidden columns, such as their key, after the query's columns
+            row.truncate(width);
+            rows.insert(key.iter().map(|&c| row[c].clone()).collect(), row);
+        }
+
+        let ops = changes(&last, &rows);
+        if !ops.is_empty() {
+            table
+                .perform_all(ops)
+                .map_err(|e| format_err!("failed to write to {}: {:?}", link.table, e))?;
+        }
+        last = rows;
+        thread::sleep(POLL_EVERY);
+    }
+    Ok(())
+}
+
+/// The writes that bring a table that holds the rows `old` up to date with the rows `new`, both
+/// by primary key.
+fn changes(
+    old: &HashMap<Vec<DataType>, Vec<DataType>>,
+    new: &HashMap<Vec<DataType>, Vec<DataType>>,
+) -> Vec<TableOperation> {
+    let mut ops: Vec<_> = old
+        .keys()
+        .filter(|key| !new.contains_key(*key))
+        .map(|key| TableOperation::Delete { key: key.clone() })
+        .collect();
+    ops.extend(
+        new.iter()
+            .filter(|&(key, row)| old.get(key) != Some(row))
+            .map(|(_, row)| TableOperation::InsertOrUpdate {
+                row: row.clone(),
+                update: row.iter().cloned().map(Modification::Set).collect(),
+            }),
+    );
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_writes_the_difference() {
+        let row = |id: i32, v: &str| (vec![id.into()], vec![id.into(), v.into()]);
+        let old: HashMap<_, _> = vec![row(1, "a"), row(2, "b"), row(3, "c")]
+            .into_iter()
+            .collect();
+        let new: HashMap<_, _> = vec![row(1, "a"), row(2, "x"), row(4, "d")]
+            .into_iter()
+            .collect();
+
+        let upsert = |id: i32, v: &str| TableOperation::InsertOrUpdate {
+            row: vec![id.into(), v.into()],
+            update: vec![Modification::Set(id.into()), Modification::Set(v.into())],
+        };
+        let mut ops = changes(&old, &new);
+        ops.sort_by_key(|op| format!("{:?}", op));
+        let mut expected = vec![
+            TableOperation::Delete {
+                key: vec![3.into()],
+            },
+            upsert(2, "x"),
+            upsert(4, "d"),
+        ];
+        expected.sort_by_key(|op| format!("{:?}", op));
+        assert_eq!(ops, expected);
+
+        assert!(changes(&new, &new).is_empty());
+        assert_eq!(changes(&HashMap::new(), &new).len(), 3);
+    }
+}
@@ -4,13 +4,21 @@ use dataflow::prelude::*;
 use petgraph;
 use slog::Logger;
 
-pub fn assign(log: &Logger, graph: &mut Graph, topo_list: &[NodeIndex], ndomains: &mut usize) {
+pub fn assign(
+    log: &Logger,
+    graph: &mut Graph,
+    topo_list: &[NodeIndex],
+    ndomains: &mut usize,
+    separate_readers: bool,
+) {
     // we need to walk the data flow graph and assign domains to all new nodes.
     // we generally want as few domains as possible, but in *some* cases we must make new ones.
     // specifically:
     //
     //  - the child of a Sharder is always in a different domain from the sharder
     //  - shard merge nodes are never in the same domain as their sharded ancestors
+    //  - if `separate_readers` is set, readers are in domains of their own, so that they can be
+    //    placed on workers that only run readers
 
     let mut next_domain = || {
         *ndomains += 1;
@@ -30,7 +38,18 @@ pub fn assign(log: &Logger, graph: &mut Graph, topo_list: &[NodeIndex], ndomains
                 return next_domain();
             }
 
+            if separate_readers && n.is_reader() {
+                return next_domain();
+            }
+
             if n.is_base() {
+                // bases that refer to one another check the references against each other's
+                // state, so they must share a domain
+                let related = super::related_bases(graph, node);
+                if let Some(&r) = related.iter().find(|&&r| graph[r].has_domain()) {
+                    return graph[r].domain().index();
+                }
+
                 // bases are in a little bit of an awkward position becuase they can't just blindly
                 // join in domains of other bases in the face of sharding. consider the case of two
                 // bases, A and B, where A is sharded by A[0] and B by B[0]. Can they share a
@@ -153,7 +172,7 @@ pub fn assign(log: &Logger, graph: &mut Graph, topo_list: &[NodeIndex], ndomains
                         .neighbors_directed(pni, petgraph::EdgeDirection::Outgoing)
                         .map(|ni| &graph[ni]);
                     for s in siblings {
-                        if !s.has_domain() {
+                        if !s.has_domain() || (separate_readers && s.is_reader()) {
                             continue;
                         }
                         if s.sharded_by().is_none() != n.sharded_by().is_none() {
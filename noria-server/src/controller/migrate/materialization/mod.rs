@@ -6,12 +6,14 @@
 //! module).
 
 use crate::controller::domain_handle::DomainHandle;
+use crate::controller::progress::MigrationTracker;
 use crate::controller::{
     inner::{graphviz, DomainReplies},
     keys,
 };
 use crate::controller::{Worker, WorkerIdentifier};
 use dataflow::prelude::*;
+use noria::cluster::{self, MigrationStep, ReplayPath};
 use petgraph;
 use petgraph::graph::NodeIndex;
 use slog::Logger;
@@ -40,12 +42,31 @@ pub enum FrontierStrategy {
     Match(String),
 }
 
+/// How a node was asked to be materialized, whatever the planner would otherwise pick.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(in crate::controller) enum MaterializationHint {
+    /// Materialize the node fully.
+    Full,
+    /// Materialize the node partially if it can be, even if partial materialization is disabled.
+    Partial,
+}
+
 impl Default for FrontierStrategy {
     fn default() -> Self {
         FrontierStrategy::None
     }
 }
 
+/// The materializations that had been decided on at some point, to go back to if a migration
+/// fails.
+pub(in crate::controller) struct Saved {
+    have: HashMap<NodeIndex, Indices>,
+    added: HashMap<NodeIndex, Indices>,
+    partial: HashSet<NodeIndex>,
+    hints: HashMap<NodeIndex, MaterializationHint>,
+    paths: Vec<ReplayPath>,
+}
+
 pub(in crate::controller) struct Materializations {
     log: Logger,
 
@@ -55,8 +76,12 @@ pub(in crate::controller) struct Materializations {
     partial: HashSet<NodeIndex>,
     partial_enabled: bool,
     frontier_strategy: FrontierStrategy,
+    hints: HashMap<NodeIndex, MaterializationHint>,
 
     tag_generator: AtomicUsize,
+
+    /// The replay paths that have been set up, in the order they were set up in.
+    paths: Vec<ReplayPath>,
 }
 
 impl Materializations {
@@ -71,8 +96,11 @@ impl Materializations {
             partial: HashSet::default(),
             partial_enabled: true,
             frontier_strategy: FrontierStrategy::None,
+            hints: HashMap::default(),
 
             tag_generator: AtomicUsize::default(),
+
+            paths: Vec::new(),
         }
     }
 
@@ -90,6 +118,43 @@ impl Materializations {
     pub(in crate::controller) fn set_frontier_strategy(&mut self, f: FrontierStrategy) {
         self.frontier_strategy = f;
     }
+
+    /// Materialize `ni` as `hint` says if it is materialized when it is first added.
+    pub(in crate::controller) fn hint(&mut self, ni: NodeIndex, hint: MaterializationHint) {
+        self.hints.insert(ni, hint);
+    }
+
+    /// Remember the materializations decided on so far.
+    pub(in crate::controller) fn save(&self) -> Saved {
+        Saved {
+            have: self.have.clone(),
+            added: self.added.clone(),
+            partial: self.partial.clone(),
+            hints: self.hints.clone(),
+            paths: self.paths.clone(),
+        }
+    }
+
+    /// Go back to the materializations in `saved`.
+    ///
+    /// Tags keep counting up from where they got to, since domains may already have been told
+    /// about replay paths that used them.
+    pub(in crate::controller) fn restore(&mut self, saved: Saved) {
+        self.have = saved.have;
+        self.added = saved.added;
+        self.partial = saved.partial;
+        self.hints = saved.hints;
+        self.paths = saved.paths;
+    }
+
+    /// Forget everything about `ni`, which has been removed from the graph.
+    pub(in crate::controller) fn forget(&mut self, ni: NodeIndex) {
+        self.have.remove(&ni);
+        self.added.remove(&ni);
+        self.partial.remove(&ni);
+        self.hints.remove(&ni);
+        self.paths.retain(|p| p.target != ni);
+    }
 }
 
 impl Materializations {
@@ -186,6 +251,32 @@ impl Materializations {
                         .insert(cols);
                 }
             }
+
+            // a base that refers to other bases must find the rows that refer to a deleted row
+            if let Some(b) = n.get_base() {
+                for r in b.references() {
+                    lookup_obligations
+                        .entry(ni)
+                        .or_insert_with(HashSet::new)
+                        .insert(vec![r.column]);
+                }
+                // and one with unique columns must find rows that already have a value
+                for cols in b.unique() {
+                    lookup_obligations
+                        .entry(ni)
+                        .or_insert_with(HashSet::new)
+                        .insert(cols.clone());
+                }
+                // and one with sequences must know where they leave off
+                for &(col, e) in b.default_expressions() {
+                    if e == dataflow::node::special::DefaultExpression::Sequence {
+                        lookup_obligations
+                            .entry(ni)
+                            .or_insert_with(HashSet::new)
+                            .insert(vec![col]);
+                    }
+                }
+            }
         }
 
         // map all the indices to the corresponding columns in the parent
@@ -322,7 +413,14 @@ impl Materializations {
             // be the case, we need to keep moving up the ancestor tree of `ni`, and check at each
             // stage that we can trace the key column back into each of our nearest
             // materializations.
-            let mut able = self.partial_enabled;
+            let mut able = match self.hints.get(&ni) {
+                Some(MaterializationHint::Full) => {
+                    warn!(self.log, "full because hinted"; "node" => ni.index());
+                    false
+                }
+                Some(MaterializationHint::Partial) => true,
+                None => self.partial_enabled,
+            };
             let mut add = HashMap::new();
 
             // bases can't be partial
@@ -454,6 +552,88 @@ impl Materializations {
 
     /// Retrieves the materialization status of a given node, or None
     /// if the node isn't materialized.
+    /// The replay paths that fill the state of any of `nodes`.
+    pub(in crate::controller) fn replay_paths(
+        &self,
+        nodes: &HashSet<NodeIndex>,
+    ) -> Vec<ReplayPath> {
+        self.paths
+            .iter()
+            .filter(|p| nodes.contains(&p.target))
+            .cloned()
+            .collect()
+    }
+
+    /// The replay paths that committing the new nodes `new` would set up, worked out without
+    /// changing these materializations or telling any domain about them.
+    pub(in crate::controller) fn plan(
+        &self,
+        graph: &Graph,
+        new: &HashSet<NodeIndex>,
+    ) -> Vec<ReplayPath> {
+        let mut m = Materializations {
+            log: self.log.clone(),
+            have: self.have.clone(),
+            added: self.added.clone(),
+            partial: self.partial.clone(),
+            partial_enabled: self.partial_enabled,
+            frontier_strategy: self.frontier_strategy.clone(),
+            hints: self.hints.clone(),
+            tag_generator: AtomicUsize::new(self.tag_generator.load(Ordering::SeqCst)),
+            paths: Vec::new(),
+        };
+        m.extend(graph, new);
+
+        let mut fill: Vec<_> = m
+            .added
+            .drain()
+            .map(|(ni, indices)| (ni, indices.into_iter().collect::<Vec<_>>()))
+            .collect();
+        // new readers are filled on the key they are looked up by
+        for &ni in new {
+            if fill.iter().any(|&(n, _)| n == ni) {
+                continue;
+            }
+            if let Ok(Some(key)) = graph[ni].with_reader(|r| r.key().map(Vec::from)) {
+                fill.push((ni, vec![key]));
+            }
+        }
+        fill.sort();
+
+        let mut paths = Vec::new();
+        for (ni, mut indices) in fill {
+            let partial = m.partial.contains(&ni);
+            indices.sort();
+            if !partial {
+                // full materializations are only ever filled once
+                indices.truncate(1);
+            }
+            for index in indices {
+                for path in plan::paths(&m, graph, ni, partial, &index) {
+                    let mut segments: Vec<cluster::ReplayPathSegment> = Vec::new();
+                    for (node, _) in path {
+                        let domain = graph[node].domain().index();
+                        if segments.last().map_or(true, |s| s.domain != domain) {
+                            segments.push(cluster::ReplayPathSegment {
+                                domain,
+                                nodes: Vec::new(),
+                            });
+                        }
+                        segments.last_mut().unwrap().nodes.push(node);
+                    }
+                    paths.push(ReplayPath {
+                        tag: m.next_tag().id(),
+                        target: ni,
+                        index: index.clone(),
+                        partial,
+                        segments,
+                    });
+                }
+            }
+        }
+        paths
+    }
+
     pub(in crate::controller) fn get_status(
         &self,
         index: NodeIndex,
@@ -485,7 +665,8 @@ impl Materializations {
         domains: &mut HashMap<DomainIndex, DomainHandle>,
         workers: &HashMap<WorkerIdentifier, Worker>,
         replies: &mut DomainReplies,
-    ) {
+        progress: &MigrationTracker,
+    ) -> Result<(), String> {
         self.extend(graph, new);
 
         // check that we don't have fully materialized nodes downstream of partially materialized
@@ -752,6 +933,11 @@ impl Materializations {
             }
         }
 
+        progress.update(|p| {
+            p.step = MigrationStep::Replaying;
+            p.nodes_to_ready = reindex.len() + make.len();
+        });
+
         // first, we add any new indices to existing nodes
         for node in reindex {
             let mut index_on = self.added.remove(&node).unwrap();
@@ -804,8 +990,17 @@ impl Materializations {
                 info!(self.log, "adding partial index to existing {:?}", n);
                 let log = self.log.new(o!("node" => node.index()));
                 let log = mem::replace(&mut self.log, log);
-                self.setup(node, &mut index_on, graph, domains, workers, replies);
+                let set_up = self.setup(
+                    node,
+                    &mut index_on,
+                    graph,
+                    domains,
+                    workers,
+                    replies,
+                    progress,
+                );
                 mem::replace(&mut self.log, log);
+                set_up?;
                 index_on.clear();
             } else if !n.sharded_by().is_none() {
                 // what do we even do here?!
@@ -828,6 +1023,7 @@ impl Materializations {
                     )
                     .unwrap();
             }
+            progress.update(|p| p.nodes_ready += 1);
         }
 
         // then, we start prepping new nodes
@@ -843,7 +1039,15 @@ impl Materializations {
                 .unwrap_or_else(HashSet::new);
 
             let start = ::std::time::Instant::now();
-            self.ready_one(ni, &mut index_on, graph, domains, workers, replies);
+            self.ready_one(
+                ni,
+                &mut index_on,
+                graph,
+                domains,
+                workers,
+                replies,
+                progress,
+            )?;
             let reconstructed = index_on.is_empty();
 
             // communicate to the domain in charge of a particular node that it should start
@@ -863,8 +1067,9 @@ impl Materializations {
                     workers,
                 )
                 .unwrap();
-            replies.wait_for_acks(&domain);
+            replies.wait_for_acks(&domain)?;
             trace!(self.log, "node ready"; "node" => ni.index());
+            progress.update(|p| p.nodes_ready += 1);
 
             if reconstructed {
                 info!(self.log, "reconstruction completed";
@@ -875,6 +1080,7 @@ impl Materializations {
         }
 
         self.added.clear();
+        Ok(())
     }
 
     /// Perform all operations necessary to bring any materializations for the given node up, and
@@ -887,7 +1093,8 @@ impl Materializations {
         domains: &mut HashMap<DomainIndex, DomainHandle>,
         workers: &HashMap<WorkerIdentifier, Worker>,
         replies: &mut DomainReplies,
-    ) {
+        progress: &MigrationTracker,
+    ) -> Result<(), String> {
         let n = &graph[ni];
         let mut has_state = !index_on.is_empty();
 
@@ -905,7 +1112,7 @@ impl Materializations {
             // a new base must be empty, so we can materialize it immediately
             info!(self.log, "no need to replay empty new base"; "node" => ni.index());
             assert!(!self.partial.contains(&ni));
-            return;
+            return Ok(());
         }
 
         // if this node doesn't need to be materialized, then we're done.
@@ -919,21 +1126,22 @@ impl Materializations {
 
         if !has_state {
             debug!(self.log, "no need to replay non-materialized view"; "node" => ni.index());
-            return;
+            return Ok(());
         }
 
         // we have a parent that has data, so we need to replay and reconstruct
         info!(self.log, "beginning reconstruction of {:?}", n);
         let log = self.log.new(o!("node" => ni.index()));
         let log = mem::replace(&mut self.log, log);
-        self.setup(ni, index_on, graph, domains, workers, replies);
+        let set_up = self.setup(ni, index_on, graph, domains, workers, replies, progress);
         mem::replace(&mut self.log, log);
+        set_up?;
 
         // NOTE: the state has already been marked ready by the replay completing, but we want to
         // wait for the domain to finish replay, which the ready executed by the outer commit()
         // loop does.
         index_on.clear();
-        return;
+        Ok(())
     }
 
     /// Reconstruct the materialized state required by the given (new) node through replay.
@@ -945,7 +1153,8 @@ impl Materializations {
         domains: &mut HashMap<DomainIndex, DomainHandle>,
         workers: &HashMap<WorkerIdentifier, Worker>,
         replies: &mut DomainReplies,
-    ) {
+        progress: &MigrationTracker,
+    ) -> Result<(), String> {
         if index_on.is_empty() {
             // we must be reconstructing a Reader.
             // figure out what key that Reader is using
@@ -963,7 +1172,7 @@ impl Materializations {
         let pending = {
             let mut plan = plan::Plan::new(self, graph, ni, domains, workers);
             for index in index_on.drain() {
-                plan.add(index, replies);
+                plan.add(index, replies)?;
             }
             plan.finalize()
         };
@@ -997,7 +1206,24 @@ impl Materializations {
                "domain" => target.index(),
             );
 
-            replies.wait_for_acks(&domains[&target]);
+            replies.wait_for_acks(&domains[&target])?;
+
+            // see how much state the replays filled, for the progress of the migration
+            let domain = domains.get_mut(&target).unwrap();
+            domain
+                .send_to_healthy(
+                    box Packet::StateSizeProbe {
+                        node: graph[ni].local_addr(),
+                    },
+                    workers,
+                )
+                .unwrap();
+            let (rows, bytes) = replies.wait_for_state_size(domain)?;
+            progress.update(|p| {
+                p.rows_replayed += rows;
+                p.bytes_replayed += bytes;
+            });
         }
+        Ok(())
     }
 }
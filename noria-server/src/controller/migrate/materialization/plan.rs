@@ -4,6 +4,7 @@ use crate::controller::keys;
 use crate::controller::{Worker, WorkerIdentifier};
 use dataflow::payload::{ReplayPathSegment, SourceSelection, TriggerEndpoint};
 use dataflow::prelude::*;
+use noria::cluster::{self, ReplayPath};
 use std::collections::{HashMap, HashSet};
 
 pub(super) struct Plan<'a> {
@@ -52,67 +53,24 @@ impl<'a> Plan<'a> {
     }
 
     fn paths(&mut self, columns: &[usize]) -> Vec<Vec<(NodeIndex, Vec<Option<usize>>)>> {
-        let graph = self.graph;
-        let ni = self.node;
-        let paths = keys::provenance_of(graph, ni, &columns[..], Self::on_join(&self.graph));
-
-        // cut paths so they only reach to the the closest materialized node
-        let mut paths: Vec<_> = paths
-            .into_iter()
-            .map(|path| -> Vec<_> {
-                let mut found = false;
-                let mut path: Vec<_> = path
-                    .into_iter()
-                    .enumerate()
-                    .take_while(|&(i, (node, _))| {
-                        // remember, the paths are "backwards", so the first node is target node
-                        if i == 0 {
-                            return true;
-                        }
-
-                        // keep taking until we get our first materialized node
-                        // (`found` helps us emulate `take_while_inclusive`)
-                        if found {
-                            // we've already found a materialized node
-                            return false;
-                        }
-
-                        if self.m.have.contains_key(&node) {
-                            // we want to take this node, but not any later ones
-                            found = true;
-                        }
-                        true
-                    })
-                    .map(|(_, segment)| segment)
-                    .collect();
-                path.reverse();
-                path
-            })
-            .collect();
-
-        // since we cut off part of each path, we *may* now have multiple paths that are the same
-        // (i.e., if there was a union above the nearest materialization). this would be bad, as it
-        // would cause a domain to request replays *twice* for a key from one view!
-        paths.sort();
-        paths.dedup();
-
-        // all columns better resolve if we're doing partial
-        assert!(!self.partial || paths.iter().all(|p| p[0].1.iter().all(Option::is_some)));
-
-        paths
+        paths(self.m, self.graph, self.node, self.partial, columns)
     }
 
     /// Finds the appropriate replay paths for the given index, and inform all domains on those
     /// paths about them. It also notes if any data backfills will need to be run, which is
     /// eventually reported back by `finalize`.
     #[allow(clippy::cognitive_complexity)]
-    pub(super) fn add(&mut self, index_on: Vec<usize>, replies: &mut DomainReplies) {
+    pub(super) fn add(
+        &mut self,
+        index_on: Vec<usize>,
+        replies: &mut DomainReplies,
+    ) -> Result<(), String> {
         if !self.partial && !self.paths.is_empty() {
             // non-partial views should not have one replay path per index. that would cause us to
             // replay several times, even though one full replay should always be sufficient.
             // we do need to keep track of the fact that there should be an index here though.
             self.tags.entry(index_on).or_default();
-            return;
+            return Ok(());
         }
 
         // inform domains about replay paths
@@ -154,6 +112,19 @@ impl<'a> Plan<'a> {
             }
 
             info!(self.m.log, "domain replay path is {:?}", segments; "tag" => tag.id());
+            self.m.paths.push(ReplayPath {
+                tag: tag.id(),
+                target: self.node,
+                index: index_on.clone(),
+                partial: self.partial,
+                segments: segments
+                    .iter()
+                    .map(|&(domain, ref nodes)| cluster::ReplayPathSegment {
+                        domain: domain.index(),
+                        nodes: nodes.iter().map(|&(ni, _)| ni).collect(),
+                    })
+                    .collect(),
+            });
 
             // tell all the domains about their segment of this replay path
             let mut pending = None;
@@ -333,7 +304,7 @@ impl<'a> Plan<'a> {
                 trace!(self.m.log, "telling domain about replay path"; "domain" => domain.index());
                 let ctx = self.domains.get_mut(&domain).unwrap();
                 ctx.send_to_healthy(setup, self.workers).unwrap();
-                replies.wait_for_acks(&ctx);
+                replies.wait_for_acks(&ctx)?;
             }
 
             if !self.partial {
@@ -345,6 +316,7 @@ impl<'a> Plan<'a> {
         }
 
         self.tags.entry(index_on).or_default().extend(tags);
+        Ok(())
     }
 
     /// Instructs the target node to set up appropriate state for any new indices that have been
@@ -511,3 +483,60 @@ impl<'a> Plan<'a> {
         }
     }
 }
+
+/// The paths along which the index `columns` of `node` is filled from the closest materializations
+/// upstream of it, starting at those materializations.
+pub(super) fn paths(
+    m: &super::Materializations,
+    graph: &Graph,
+    node: NodeIndex,
+    partial: bool,
+    columns: &[usize],
+) -> Vec<Vec<(NodeIndex, Vec<Option<usize>>)>> {
+    let paths = keys::provenance_of(graph, node, &columns[..], Plan::on_join(graph));
+
+    // cut paths so they only reach to the the closest materialized node
+    let mut paths: Vec<_> = paths
+        .into_iter()
+        .map(|path| -> Vec<_> {
+            let mut found = false;
+            let mut path: Vec<_> = path
+                .into_iter()
+                .enumerate()
+                .take_while(|&(i, (node, _))| {
+                    // remember, the paths are "backwards", so the first node is target node
+                    if i == 0 {
+                        return true;
+                    }
+
+                    // keep taking until we get our first materialized node
+                    // (`found` helps us emulate `take_while_inclusive`)
+                    if found {
+                        // we've already found a materialized node
+                        return false;
+                    }
+
+                    if m.have.contains_key(&node) {
+                        // we want to take this node, but not any later ones
+                        found = true;
+                    }
+                    true
+                })
+                .map(|(_, segment)| segment)
+                .collect();
+            path.reverse();
+            path
+        })
+        .collect();
+
+    // since we cut off part of each path, we *may* now have multiple paths that are the same
+    // (i.e., if there was a union above the nearest materialization). this would be bad, as it
+    // would cause a domain to request replays *twice* for a key from one view!
+    paths.sort();
+    paths.dedup();
+
+    // all columns better resolve if we're doing partial
+    assert!(!partial || paths.iter().all(|p| p[0].1.iter().all(Option::is_some)));
+
+    paths
+}
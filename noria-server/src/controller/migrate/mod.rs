@@ -20,10 +20,21 @@
 //!
 //! Beware, Here be dragons™
 
+use crate::controller::journal;
+use crate::controller::migrate::materialization::MaterializationHint;
+use crate::controller::placement::Placement;
+use crate::controller::sql::security::universe_name;
 use crate::controller::ControllerInner;
+use dataflow::ops::project::{ColumnMask, ProjectExpression};
 use dataflow::prelude::*;
 use dataflow::{node, prelude::Packet};
-use std::collections::{HashMap, HashSet};
+use nom_sql::{OrderType, SqlType};
+use noria::cluster::{
+    AddedReader, MigrationPlan, MigrationResult, MigrationStep, PlannedNode, SwappedParent,
+};
+use std::any::Any;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::panic;
 use std::time::Instant;
 
 use petgraph;
@@ -35,10 +46,99 @@ crate mod materialization;
 mod routing;
 mod sharding;
 
-#[derive(Clone)]
+/// The nodes of `new` that were added to `graph`, in the order of their indices.
+fn added_nodes(graph: &Graph, source: NodeIndex, new: &HashSet<NodeIndex>) -> Vec<PlannedNode> {
+    let mut nodes: Vec<_> = new
+        .iter()
+        .filter(|&&ni| ni != source && !graph[ni].is_dropped())
+        .map(|&ni| PlannedNode {
+            node: ni,
+            name: graph[ni].name().to_owned(),
+            desc: format!("{:?}", graph[ni]),
+            domain: graph[ni].domain().index(),
+        })
+        .collect();
+    nodes.sort_by_key(|n| n.node);
+    nodes
+}
+
+/// The base nodes that base node `node` refers to, or that refer to it.
+fn related_bases(graph: &Graph, node: NodeIndex) -> Vec<NodeIndex> {
+    let refers = |ni: NodeIndex| graph[ni].get_base().map(|b| b.references()).unwrap_or(&[]);
+    let mut related: Vec<_> = refers(node).iter().map(|r| r.table.as_global()).collect();
+    related.extend(
+        graph
+            .node_indices()
+            .filter(|&ni| refers(ni).iter().any(|r| r.table.as_global() == node)),
+    );
+    related
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub(super) enum ColumnChange {
     Add(String, DataType),
     Drop(usize),
+    Rename(usize, String),
+    AlterType(usize, SqlType, node::special::Cast),
+}
+
+/// The controller's view of the graph as it was before a migration started, so that it can be put
+/// back if the migration fails part of the way through.
+///
+/// Every migration takes one, which copies the whole graph along with the controller's records of
+/// where its nodes live. That costs time and memory in proportion to the number of nodes and
+/// replay paths, but not to the amount of data: the rows of materialized nodes are kept by the
+/// domains, and the controller's copy of a node holds only its operator and schema.
+pub(super) struct Checkpoint {
+    ingredients: Graph,
+    ndomains: usize,
+    domain_nodes: HashMap<DomainIndex, Vec<NodeIndex>>,
+    remap: HashMap<DomainIndex, HashMap<NodeIndex, IndexPair>>,
+    placements: HashMap<NodeIndex, Placement>,
+    universe_nodes: HashMap<String, Vec<NodeIndex>>,
+    materializations: materialization::Saved,
+}
+
+impl Checkpoint {
+    /// Copy what a migration may change about the controller's view of the graph.
+    pub(super) fn take(mainline: &ControllerInner) -> Self {
+        Checkpoint {
+            ingredients: mainline.ingredients.clone(),
+            ndomains: mainline.ndomains,
+            domain_nodes: mainline.domain_nodes.clone(),
+            remap: mainline.remap.clone(),
+            placements: mainline.placements.clone(),
+            universe_nodes: mainline.universe_nodes.clone(),
+            materializations: mainline.materializations.save(),
+        }
+    }
+
+    /// Put the controller's view of the graph back to how it was, except for the nodes in `keep`,
+    /// whose changes the domains have already applied. The domains themselves are left alone.
+    fn restore(self, mainline: &mut ControllerInner, keep: &[NodeIndex]) {
+        let mut ingredients = self.ingredients;
+        for &ni in keep {
+            ingredients[ni] = mainline.ingredients[ni].clone();
+        }
+        mainline.ingredients = ingredients;
+        mainline.ndomains = self.ndomains;
+        mainline.domain_nodes = self.domain_nodes;
+        mainline.remap = self.remap;
+        mainline.placements = self.placements;
+        mainline.universe_nodes = self.universe_nodes;
+        mainline.materializations.restore(self.materializations);
+    }
+}
+
+/// The message that a panic was raised with.
+fn panic_message(e: Box<dyn Any + Send>) -> String {
+    match e.downcast::<String>() {
+        Ok(message) => *message,
+        Err(e) => match e.downcast_ref::<&str>() {
+            Some(message) => (*message).to_owned(),
+            None => "panicked".to_owned(),
+        },
+    }
 }
 
 /// A `Migration` encapsulates a number of changes to the Soup data flow graph.
@@ -51,7 +151,19 @@ crate struct Migration<'a> {
     pub(super) added: HashSet<NodeIndex>,
     pub(super) columns: Vec<(NodeIndex, ColumnChange)>,
     pub(super) readers: HashMap<NodeIndex, NodeIndex>,
-
+    /// The further readers that `maintain_also` set up for each node, each with its own key.
+    pub(super) indices: HashMap<NodeIndex, Vec<NodeIndex>>,
+    /// Nodes that were asked not to be sharded.
+    pub(super) unsharded: HashSet<NodeIndex>,
+    /// Existing views to remove once the new nodes are in place.
+    pub(super) removed: Vec<NodeIndex>,
+    /// Existing base nodes to remove after the views are gone.
+    pub(super) dropped: Vec<NodeIndex>,
+    /// Existing base nodes whose domains need to learn their new names.
+    pub(super) renamed: Vec<NodeIndex>,
+
+    /// The graph as it was before the migration started.
+    pub(super) checkpoint: Checkpoint,
     pub(super) start: Instant,
     pub(super) log: slog::Logger,
 
@@ -88,6 +200,7 @@ impl<'a> Migration<'a> {
 
         // keep track of the fact that it's new
         self.added.insert(ni);
+        self.attribute(ni);
         // insert it into the graph
         for parent in parents {
             self.mainline.ingredients.add_edge(parent, ni, ());
@@ -123,6 +236,7 @@ impl<'a> Migration<'a> {
 
         // keep track of the fact that it's new
         self.added.insert(ni);
+        self.attribute(ni);
         // insert it into the graph
         self.mainline
             .ingredients
@@ -153,6 +267,19 @@ impl<'a> Migration<'a> {
         }
     }
 
+    /// Attribute a newly added node to the universe this migration operates in, if any, so that
+    /// its state counts towards that universe's quota.
+    fn attribute(&mut self, ni: NodeIndex) {
+        if self.context.get("id").is_some() {
+            let (id, group) = self.universe();
+            self.mainline
+                .universe_nodes
+                .entry(universe_name(&id, &group))
+                .or_insert_with(Vec::new)
+                .push(ni);
+        }
+    }
+
     /// Returns the context of this migration
     pub(super) fn context(&self) -> &HashMap<String, DataType> {
         &self.context
@@ -225,29 +352,363 @@ impl<'a> Migration<'a> {
         self.columns.push((node, ColumnChange::Drop(column)));
     }
 
+    /// Remove the view `node`, along with its reader and any operators above it that no other
+    /// view uses, once the migration is committed.
+    ///
+    /// Only views that nothing but a reader reads from can be removed. The views of recipe queries
+    /// should be removed from the recipe instead, so that the recipe forgets them too.
+    // crate viz for tests
+    crate fn remove_node(&mut self, node: NodeIndex) {
+        assert!(!self.added.contains(&node));
+
+        let graph = &self.mainline.ingredients;
+        assert!(!graph[node].is_source() && !graph[node].is_base());
+        assert!(graph
+            .neighbors_directed(node, petgraph::EdgeDirection::Outgoing)
+            .all(|c| graph[c].is_reader() || graph[c].is_dropped()));
+        self.removed.push(node);
+    }
+
+    /// Drop the base node `node`, along with its state and anything it has persisted, once the
+    /// migration is committed.
+    ///
+    /// Every view that reads from the base must be removed first, in this migration or an
+    /// earlier one. Tables in the recipe should be dropped from the recipe instead.
+    // crate viz for tests
+    crate fn drop_base(&mut self, node: NodeIndex) {
+        assert!(!self.added.contains(&node));
+        assert!(self.mainline.ingredients[node].is_base());
+        assert!(
+            related_bases(&self.mainline.ingredients, node).is_empty(),
+            "cannot drop a base that takes part in references"
+        );
+        self.dropped.push(node);
+    }
+
+    /// Give the base node `node` the new name `name`, which is what clients then look it up by.
+    ///
+    /// Anything the base has persisted moves along with it. Tables in the recipe should be
+    /// renamed in the recipe instead.
+    // crate viz for tests
+    crate fn rename_base<S: ToString>(&mut self, node: NodeIndex, name: S) {
+        let name = name.to_string();
+        assert!(!self.added.contains(&node));
+        assert!(self.mainline.ingredients[node].is_base());
+        assert!(
+            !self.mainline.inputs().contains_key(&name),
+            "there already is a base named {}",
+            name
+        );
+
+        // like with columns, the controller's copy changes right away, and the domain's on commit
+        self.mainline.ingredients[node].rename(name);
+        if !self.renamed.contains(&node) {
+            self.renamed.push(node);
+        }
+    }
+
+    /// Rename column `column` of the base node `node` to `name`, keeping its rows as they are.
+    ///
+    /// The views below the base that pass the column on under its old name follow along, and so
+    /// does the recipe's schema for the table if the base is one of the recipe's tables.
+    // crate viz for tests
+    crate fn rename_column<S: ToString>(&mut self, node: NodeIndex, column: usize, name: S) {
+        // not allowed to rename columns of new nodes
+        assert!(!self.added.contains(&node));
+
+        let name = name.to_string();
+        let graph = &mut self.mainline.ingredients;
+        assert!(graph[node].is_base());
+        let old = graph[node].fields()[column].clone();
+
+        // follow the column down for as long as it keeps its name
+        let mut renamed = vec![(node, column)];
+        let mut next = 0;
+        while next < renamed.len() {
+            let (parent, pc) = renamed[next];
+            next += 1;
+            let mut children = graph
+                .neighbors_directed(parent, petgraph::EdgeDirection::Outgoing)
+                .detach();
+            while let Some(child) = children.next_node(graph) {
+                let n = &graph[child];
+                if n.is_dropped() {
+                    continue;
+                }
+                for c in 0..n.fields().len() {
+                    if n.fields()[c] != old || renamed.contains(&(child, c)) {
+                        continue;
+                    }
+                    let passed_on = if n.is_internal() {
+                        n.parent_columns(c).contains(&(parent, Some(pc)))
+                    } else {
+                        // the other nodes pass each column on where it was
+                        c == pc
+                    };
+                    if passed_on {
+                        renamed.push((child, c));
+                    }
+                }
+            }
+        }
+
+        for &(ni, c) in &renamed {
+            graph[ni].rename_column(c, name.clone());
+            self.columns
+                .push((ni, ColumnChange::Rename(c, name.clone())));
+        }
+
+        let table = graph[node].name().to_owned();
+        let recipe = &mut self.mainline.recipe;
+        if recipe.node_addr_for(&table) == Ok(node) {
+            recipe.rename_column(&table, &old, &name, &renamed);
+        }
+    }
+
+    /// Change the type of column `column` of the base node `node` to `ty`.
+    ///
+    /// The rows the base already has are converted with `cast`, and so is the column's default.
+    /// Rows that change are passed on to the views below the base like any other write. If the
+    /// base is one of the recipe's tables, the recipe's schema for it changes too.
+    // crate viz for tests
+    crate fn alter_column_type(
+        &mut self,
+        node: NodeIndex,
+        column: usize,
+        ty: SqlType,
+        cast: node::special::Cast,
+    ) {
+        // not allowed to change columns of new nodes
+        assert!(!self.added.contains(&node));
+
+        let base = &mut self.mainline.ingredients[node];
+        assert!(base.is_base());
+        base.get_base_mut()
+            .unwrap()
+            .alter_column_type(column, ty.clone(), &cast);
+        let table = base.name().to_owned();
+        let field = base.fields()[column].clone();
+
+        // also eventually propagate to domain clone
+        self.columns
+            .push((node, ColumnChange::AlterType(column, ty.clone(), cast)));
+
+        let recipe = &mut self.mainline.recipe;
+        if recipe.node_addr_for(&table) == Ok(node) {
+            recipe.alter_column_type(&table, &field, ty);
+        }
+    }
+
+    /// Have the new base node `node` refer to the row of base node `table` whose primary key,
+    /// `key`, is in its column `column`.
+    ///
+    /// Writes that would leave the reference dangling are handled as `action` says. The two base
+    /// nodes are placed in the same domain, and neither is sharded.
+    pub(super) fn add_reference(
+        &mut self,
+        node: NodeIndex,
+        table: NodeIndex,
+        column: usize,
+        key: usize,
+        action: node::special::ReferenceAction,
+    ) -> Result<(), String> {
+        assert!(self.added.contains(&node));
+
+        let graph = &self.mainline.ingredients;
+        let t = &graph[table];
+        match t.get_base() {
+            Some(b) if b.key() == Some(&[key][..]) => {}
+            _ => {
+                return Err(format!(
+                    "{} can only be referred to by its primary key",
+                    t.name()
+                ));
+            }
+        }
+        if t.has_domain() && !t.sharded_by().is_none() {
+            return Err(format!(
+                "{} is sharded, and cannot be referred to",
+                t.name()
+            ));
+        }
+
+        let b = graph[node].get_base().unwrap();
+        if action != node::special::ReferenceAction::Reject && b.key().is_none() {
+            return Err(format!(
+                "{} needs a primary key to change its rows when the rows they refer to are deleted",
+                graph[node].name()
+            ));
+        }
+        // the references are checked against the state of the other base, so they must all be in
+        // the same domain
+        for r in b.references() {
+            let other = &graph[r.table.as_global()];
+            if t.has_domain() && other.has_domain() && t.domain() != other.domain() {
+                return Err(format!(
+                    "{} cannot refer to both {} and {}",
+                    graph[node].name(),
+                    t.name(),
+                    other.name()
+                ));
+            }
+        }
+
+        self.mainline.ingredients[node]
+            .get_base_mut()
+            .unwrap()
+            .add_reference(node::special::Reference {
+                column,
+                table: table.into(),
+                key,
+                action,
+            });
+        Ok(())
+    }
+
+    /// Require that no two rows of the base table `node` share values in `columns`.
+    ///
+    /// Writes that would break this are refused, and the clients that sent them are told so. The
+    /// base table must have a primary key, and is not sharded.
+    pub(super) fn add_unique(
+        &mut self,
+        node: NodeIndex,
+        columns: Vec<usize>,
+    ) -> Result<(), String> {
+        assert!(self.added.contains(&node));
+
+        let n = &mut self.mainline.ingredients[node];
+        let name = n.name().to_owned();
+        let b = n.get_base_mut().unwrap();
+        if b.key().is_none() {
+            return Err(format!(
+                "{} needs a primary key to have unique columns",
+                name
+            ));
+        }
+        b.add_unique(columns);
+        Ok(())
+    }
+
+    /// Compute `column` of the base table `node` from its other columns whenever a row is written.
+    pub(super) fn add_generated(
+        &mut self,
+        node: NodeIndex,
+        column: usize,
+        expression: ProjectExpression,
+    ) -> Result<(), String> {
+        assert!(self.added.contains(&node));
+
+        let n = &mut self.mainline.ingredients[node];
+        let name = n.name().to_owned();
+        let b = n.get_base_mut().unwrap();
+        // rows are matched up with the rows they replace by key before the columns are computed
+        if b.key().map(|k| k.contains(&column)).unwrap_or(false) {
+            return Err(format!("the primary key of {} cannot be generated", name));
+        }
+        b.add_generated(column, expression);
+        Ok(())
+    }
+
+    /// Compute `column` of the base table `node` by `expression` for rows inserted without it.
+    pub(super) fn add_default_expression(
+        &mut self,
+        node: NodeIndex,
+        column: usize,
+        expression: node::special::DefaultExpression,
+    ) {
+        assert!(self.added.contains(&node));
+        self.mainline.ingredients[node]
+            .get_base_mut()
+            .unwrap()
+            .add_default_expression(column, expression);
+    }
+
+    /// Keep the rows that are deleted from the base table `node`, and set `column` to the time they
+    /// were deleted instead.
+    pub(super) fn set_soft_delete(&mut self, node: NodeIndex, column: usize) -> Result<(), String> {
+        assert!(self.added.contains(&node));
+
+        let n = &mut self.mainline.ingredients[node];
+        let name = n.name().to_owned();
+        let b = n.get_base_mut().unwrap();
+        match b.key() {
+            Some(key) if !key.contains(&column) => {}
+            Some(_) => return Err(format!("the primary key of {} cannot mark deletes", name)),
+            None => return Err(format!("{} needs a primary key to keep deleted rows", name)),
+        }
+        b.set_soft_delete(column);
+        Ok(())
+    }
+
+    /// Hold the values written to the base table `node` to the `types` its columns are declared
+    /// with, and do `action` about values of other types.
+    pub(super) fn set_column_types(
+        &mut self,
+        node: NodeIndex,
+        types: Vec<SqlType>,
+        action: node::special::TypeMismatch,
+    ) {
+        assert!(self.added.contains(&node));
+        self.mainline.ingredients[node]
+            .get_base_mut()
+            .unwrap()
+            .set_column_types(types, action);
+    }
+
+    /// Have the base table `node` drop the rows for stream events it has applied already, as
+    /// `dedup` tells them apart.
+    pub(super) fn set_dedup(&mut self, node: NodeIndex, dedup: node::special::Dedup) {
+        assert!(self.added.contains(&node));
+        self.mainline.ingredients[node]
+            .get_base_mut()
+            .unwrap()
+            .set_dedup(dedup);
+    }
+
+    /// Require that every row of the base table `node` meets `check`.
+    ///
+    /// Writes that would leave behind a row that does not are refused, and the clients that sent
+    /// them are told so.
+    pub(super) fn add_check(&mut self, node: NodeIndex, check: node::special::Check) {
+        assert!(self.added.contains(&node));
+        self.mainline.ingredients[node]
+            .get_base_mut()
+            .unwrap()
+            .add_check(check);
+    }
+
     #[cfg(test)]
     crate fn graph(&self) -> &Graph {
         self.mainline.graph()
     }
 
     fn ensure_reader_for(&mut self, n: NodeIndex, name: Option<String>) {
-        use std::collections::hash_map::Entry;
-        if let Entry::Vacant(e) = self.readers.entry(n) {
-            // make a reader
-            let r = node::special::Reader::new(n);
-            let mut r = if let Some(name) = name {
-                self.mainline.ingredients[n].named_mirror(r, name)
-            } else {
-                self.mainline.ingredients[n].mirror(r)
-            };
-            if r.name().starts_with("SHALLOW_") {
-                r.purge = true;
-            }
-            let r = self.mainline.ingredients.add_node(r);
-            self.mainline.ingredients.add_edge(n, r, ());
-            self.added.insert(r);
-            e.insert(r);
+        if !self.readers.contains_key(&n) {
+            let r = self.add_reader(n, name);
+            self.readers.insert(n, r);
+        }
+    }
+
+    /// Add a reader of `n`, without a key.
+    fn add_reader(&mut self, n: NodeIndex, name: Option<String>) -> NodeIndex {
+        let mut r = node::special::Reader::new(n);
+        if self.context.get("id").is_some() {
+            let (id, group) = self.universe();
+            r.set_universe(universe_name(&id, &group));
         }
+        let mut r = if let Some(name) = name {
+            self.mainline.ingredients[n].named_mirror(r, name)
+        } else {
+            self.mainline.ingredients[n].mirror(r)
+        };
+        if r.name().starts_with("SHALLOW_") {
+            r.purge = true;
+        }
+        let r = self.mainline.ingredients.add_node(r);
+        self.mainline.ingredients.add_edge(n, r, ());
+        self.added.insert(r);
+        self.attribute(r);
+        r
     }
 
     /// Set up the given node such that its output can be efficiently queried.
@@ -278,310 +739,715 @@ impl<'a> Migration<'a> {
             .unwrap();
     }
 
-    /// Commit the changes introduced by this `Migration` to the master `Soup`.
+    /// Also maintain the output of `n` as the view `name`, looked up by `key`.
     ///
-    /// This will spin up an execution thread for each new thread domain, and hook those new
-    /// domains into the larger Soup graph. The returned map contains entry points through which
-    /// new updates should be sent to introduce them into the Soup.
-    #[allow(clippy::cognitive_complexity)]
-    pub(super) fn commit(self) {
-        info!(self.log, "finalizing migration"; "#nodes" => self.added.len());
+    /// The view gets a reader of its own, which keeps its own index of the rows of `n`, so that
+    /// one query can be looked up in several ways without the nodes that compute it being added
+    /// again for each. Its rows are ordered and deduplicated like those of the reader that
+    /// `maintain` set up for `n`, which must have been set up first.
+    pub(super) fn maintain_also(&mut self, name: String, n: NodeIndex, key: &[usize]) {
+        let (order, distinct, masks) = match self.readers.get(&n) {
+            Some(&ri) => self.mainline.ingredients[ri]
+                .with_reader(|r| {
+                    (
+                        r.order().map(<[_]>::to_vec),
+                        r.is_distinct(),
+                        r.masks().map(<[_]>::to_vec),
+                    )
+                })
+                .unwrap(),
+            None => (None, false, None),
+        };
 
-        let log = self.log;
-        let start = self.start;
-        let mut mainline = self.mainline;
-        let mut new = self.added;
-        let mut topo = mainline.topo_order(&new);
+        let ri = self.add_reader(n, Some(name));
+        self.mainline.ingredients[ri]
+            .with_reader_mut(|r| {
+                r.set_key(key);
+                if let Some(order) = order {
+                    r.set_order(order);
+                }
+                if distinct {
+                    r.set_distinct();
+                }
+                if let Some(masks) = masks {
+                    r.set_masks(masks);
+                }
+            })
+            .unwrap();
+        self.indices.entry(n).or_default().push(ri);
+    }
 
-        // Shard the graph as desired
-        let mut swapped0 = if let Some(shards) = mainline.sharding {
-            let (t, swapped) =
-                sharding::shard(&log, &mut mainline.ingredients, &mut new, &topo, shards);
-            topo = t;
+    /// The names of the columns of `n`.
+    pub(super) fn fields(&self, n: NodeIndex) -> &[String] {
+        self.mainline.ingredients[n].fields()
+    }
 
-            swapped
-        } else {
-            HashMap::default()
-        };
+    /// The readers that `maintain` and `maintain_also` set up for `n`.
+    fn readers_of(&self, n: NodeIndex) -> Vec<NodeIndex> {
+        let indices = self.indices.get(&n).into_iter().flatten();
+        self.readers
+            .get(&n)
+            .into_iter()
+            .chain(indices)
+            .cloned()
+            .collect()
+    }
+
+    /// Have the reader that `maintain` set up for `n` return the rows for each key sorted by
+    /// `order`.
+    pub(super) fn order_reader(&mut self, n: NodeIndex, order: Vec<(usize, OrderType)>) {
+        let ri = self.readers[&n];
+        self.mainline.ingredients[ri]
+            .with_reader_mut(|r| r.set_order(order))
+            .unwrap();
+    }
 
-        // Assign domains
+    /// Have the reader that `maintain` set up for `n` redact `masks` in the rows it returns.
+    pub(super) fn mask_reader(&mut self, n: NodeIndex, masks: Vec<(usize, ColumnMask)>) {
+        let ri = self.readers[&n];
+        self.mainline.ingredients[ri]
+            .with_reader_mut(|r| r.set_masks(masks))
+            .unwrap();
+    }
+
+    /// Have the reader that `maintain` set up for `n` keep only one copy of each row.
+    pub(super) fn deduplicate_reader(&mut self, n: NodeIndex) {
+        let ri = self.readers[&n];
+        self.mainline.ingredients[ri]
+            .with_reader_mut(|r| r.set_distinct())
+            .unwrap();
+    }
+
+    /// Only place the domain that `n`, or the reader that `maintain` set up for it, ends up in on
+    /// workers that `placement` allows.
+    ///
+    /// This has no effect if that domain already exists.
+    pub(super) fn place(&mut self, n: NodeIndex, placement: Placement) -> Result<(), String> {
+        if !self
+            .mainline
+            .workers
+            .values()
+            .any(|w| w.healthy && !w.draining && placement.allows(&w.labels))
+        {
+            return Err(format!("no worker meets placement {}", placement));
+        }
+        let n = self.readers.get(&n).cloned().unwrap_or(n);
+        self.mainline
+            .placements
+            .entry(n)
+            .or_default()
+            .merge(&placement);
+        Ok(())
+    }
+
+    /// Materialize the nodes in `nodes`, and the readers that were set up for them, as `hint` says,
+    /// whatever the planner would otherwise pick.
+    pub(super) fn hint_materialization(&mut self, nodes: &[NodeIndex], hint: MaterializationHint) {
+        for n in nodes {
+            for n in self.readers_of(*n).into_iter().chain(Some(*n)) {
+                self.mainline.materializations.hint(n, hint);
+            }
+        }
+    }
+
+    /// Do not shard the nodes in `nodes`, or the readers that were set up for them.
+    pub(super) fn unshard(&mut self, nodes: &[NodeIndex]) {
+        for n in nodes {
+            let readers = self.readers_of(*n);
+            self.unsharded.extend(readers);
+            self.unsharded.insert(*n);
+        }
+    }
+
+    /// Work out what committing this `Migration` would change in the running graph, without
+    /// telling any domain about it.
+    ///
+    /// The new nodes are sharded, assigned to domains, and connected across domains in the graph
+    /// the migration was made on, which is then put back as it was before the migration started.
+    pub(super) fn plan(self) -> MigrationPlan {
+        info!(self.log, "planning migration"; "#nodes" => self.added.len());
+
+        let log = self.log;
+        let checkpoint = self.checkpoint;
+        let mainline = self.mainline;
+        let mut new = self.added;
+        let mut topo = mainline.topo_order(&new);
+        if let Some(shards) = mainline.sharding {
+            topo = sharding::shard(
+                &log,
+                &mut mainline.ingredients,
+                &mut new,
+                &topo,
+                shards,
+                &self.unsharded,
+            )
+            .0;
+        }
+        let separate_readers = mainline.workers.values().any(|w| w.reader_only);
         assignment::assign(
             &log,
             &mut mainline.ingredients,
             &topo,
             &mut mainline.ndomains,
+            separate_readers,
         );
-
-        // Set up ingress and egress nodes
-        let swapped1 = routing::add(
+        routing::add(
             &log,
             &mut mainline.ingredients,
             mainline.source,
             &mut new,
             &topo,
         );
-        topo = mainline.topo_order(&new);
-
-        // Merge the swap lists
-        for ((dst, src), instead) in swapped1 {
-            use std::collections::hash_map::Entry;
-            match swapped0.entry((dst, src)) {
-                Entry::Occupied(mut instead0) => {
-                    if &instead != instead0.get() {
-                        // This can happen if sharding decides to add a Sharder *under* a node,
-                        // and routing decides to add an ingress/egress pair between that node
-                        // and the Sharder. It's perfectly okay, but we should prefer the
-                        // "bottommost" swap to take place (i.e., the node that is *now*
-                        // closest to the dst node). This *should* be the sharding node, unless
-                        // routing added an ingress *under* the Sharder. We resolve the
-                        // collision by looking at which translation currently has an adge from
-                        // `src`, and then picking the *other*, since that must then be node
-                        // below.
-                        if mainline.ingredients.find_edge(src, instead).is_some() {
-                            // src -> instead -> instead0 -> [children]
-                            // from [children]'s perspective, we should use instead0 for from, so
-                            // we can just ignore the `instead` swap.
-                        } else {
-                            // src -> instead0 -> instead -> [children]
-                            // from [children]'s perspective, we should use instead for src, so we
-                            // need to prefer the `instead` swap.
-                            *instead0.get_mut() = instead;
-                        }
-                    }
-                }
-                Entry::Vacant(hole) => {
-                    hole.insert(instead);
-                }
-            }
-
-            // we may also already have swapped the parents of some node *to* `src`. in
-            // swapped0. we want to change that mapping as well, since lookups in swapped
-            // aren't recursive.
-            for (_, instead0) in swapped0.iter_mut() {
-                if *instead0 == src {
-                    *instead0 = instead;
-                }
-            }
-        }
-        let swapped = swapped0;
-        let mut sorted_new = new.iter().collect::<Vec<_>>();
-        sorted_new.sort();
 
-        // Find all nodes for domains that have changed
-        let changed_domains: HashSet<DomainIndex> = sorted_new
+        let graph = &mainline.ingredients;
+        let nodes = added_nodes(graph, mainline.source, &new);
+        let mut new_domains: Vec<_> = nodes
             .iter()
-            .filter(|&&&ni| !mainline.ingredients[ni].is_dropped())
-            .map(|&&ni| mainline.ingredients[ni].domain())
+            .map(|n| n.domain)
+            .filter(|&d| !mainline.domains.contains_key(&DomainIndex::from(d)))
             .collect();
+        new_domains.sort();
+        new_domains.dedup();
+
+        let plan = MigrationPlan {
+            nodes,
+            new_domains,
+            replay_paths: mainline.materializations.plan(graph, &new),
+            replayed_bytes: 0,
+        };
+        checkpoint.restore(mainline, &[]);
+        plan
+    }
 
-        let mut domain_new_nodes = sorted_new
+    /// Commit the changes introduced by this `Migration` to the master `Soup`.
+    ///
+    /// This will spin up an execution thread for each new thread domain, and hook those new
+    /// domains into the larger Soup graph. The returned map contains entry points through which
+    /// new updates should be sent to introduce them into the Soup.
+    ///
+    /// If any step up to setting up the new materializations fails or panics, or the controller
+    /// has a migration timeout and the domains have not all caught up by the time it runs out, the
+    /// migration is undone and an error is returned. Otherwise, what the migration changed in the
+    /// graph is returned.
+    #[allow(clippy::cognitive_complexity)]
+    pub(super) fn commit(self) -> Result<MigrationResult, String> {
+        info!(self.log, "finalizing migration"; "#nodes" => self.added.len());
+
+        let log = self.log;
+        let start = self.start;
+        let checkpoint = self.checkpoint;
+        let mut mainline = self.mainline;
+        let mut new = self.added;
+        let unsharded = self.unsharded;
+        let columns = self.columns;
+        let journaled: Vec<_> = columns
             .iter()
-            .filter(|&&&ni| ni != mainline.source)
-            .filter(|&&&ni| !mainline.ingredients[ni].is_dropped())
-            .map(|&&ni| (mainline.ingredients[ni].domain(), ni))
-            .fold(HashMap::new(), |mut dns, (d, ni)| {
-                dns.entry(d).or_insert_with(Vec::new).push(ni);
-                dns
-            });
+            .map(|&(ni, ref change)| (ni.index(), change.clone()))
+            .collect();
+        let renamed = self.renamed;
+        let removed = self.removed;
+        let dropped = self.dropped;
+        mainline.progress.start(new.len());
+        mainline
+            .replies
+            .set_deadline(mainline.migration_timeout.map(|t| start + t));
+
+        // nothing the migration did is kept unless every step up to the new materializations
+        // succeeds, and a step that panics fails the migration just like one that errors
+        let mut booted = Vec::new();
+        let mut applied = Vec::new();
+        let mut swaps = Vec::new();
+        let staged = panic::catch_unwind(panic::AssertUnwindSafe(|| -> Result<(), String> {
+            let mut topo = mainline.topo_order(&new);
+
+            // Shard the graph as desired
+            let mut swapped0 = if let Some(shards) = mainline.sharding {
+                let (t, swapped) = sharding::shard(
+                    &log,
+                    &mut mainline.ingredients,
+                    &mut new,
+                    &topo,
+                    shards,
+                    &unsharded,
+                );
+                topo = t;
+
+                swapped
+            } else {
+                HashMap::default()
+            };
+
+            // Assign domains
+            let separate_readers = mainline.workers.values().any(|w| w.reader_only);
+            assignment::assign(
+                &log,
+                &mut mainline.ingredients,
+                &topo,
+                &mut mainline.ndomains,
+                separate_readers,
+            );
 
-        // Assign local addresses to all new nodes, and initialize them
-        for (domain, nodes) in &mut domain_new_nodes {
-            // Number of pre-existing nodes
-            let mut nnodes = mainline.remap.get(domain).map(HashMap::len).unwrap_or(0);
+            // Set up ingress and egress nodes
+            let swapped1 = routing::add(
+                &log,
+                &mut mainline.ingredients,
+                mainline.source,
+                &mut new,
+                &topo,
+            );
+            topo = mainline.topo_order(&new);
+
+            // Merge the swap lists
+            for ((dst, src), instead) in swapped1 {
+                use std::collections::hash_map::Entry;
+                match swapped0.entry((dst, src)) {
+                    Entry::Occupied(mut instead0) => {
+                        if &instead != instead0.get() {
+                            // This can happen if sharding decides to add a Sharder *under* a node,
+                            // and routing decides to add an ingress/egress pair between that node
+                            // and the Sharder. It's perfectly okay, but we should prefer the
+                            // "bottommost" swap to take place (i.e., the node that is *now*
+                            // closest to the dst node). This *should* be the sharding node, unless
+                            // routing added an ingress *under* the Sharder. We resolve the
+                            // collision by looking at which translation currently has an adge from
+                            // `src`, and then picking the *other*, since that must then be node
+                            // below.
+                            if mainline.ingredients.find_edge(src, instead).is_some() {
+                                // src -> instead -> instead0 -> [children]
+                                // from [children]'s perspective, we should use instead0 for from,
+                                // so we can just ignore the `instead` swap.
+                            } else {
+                                // src -> instead0 -> instead -> [children]
+                                // from [children]'s perspective, we should use instead for src, so
+                                // we need to prefer the `instead` swap.
+                                *instead0.get_mut() = instead;
+                            }
+                        }
+                    }
+                    Entry::Vacant(hole) => {
+                        hole.insert(instead);
+                    }
+                }
 
-            if nodes.is_empty() {
-                // Nothing to do here
-                continue;
+                // we may also already have swapped the parents of some node *to* `src`. in
+                // swapped0. we want to change that mapping as well, since lookups in swapped
+                // aren't recursive.
+                for (_, instead0) in swapped0.iter_mut() {
+                    if *instead0 == src {
+                        *instead0 = instead;
+                    }
+                }
             }
+            let swapped = swapped0;
+            swaps = swapped
+                .iter()
+                .map(|(&(node, parent), &instead)| SwappedParent {
+                    node,
+                    parent,
+                    instead,
+                })
+                .collect();
+            swaps.sort_by_key(|s| (s.node, s.parent));
+            let mut sorted_new = new.iter().collect::<Vec<_>>();
+            sorted_new.sort();
+
+            // Find all nodes for domains that have changed, in the order new ones are booted in
+            let changed_domains: BTreeSet<DomainIndex> = sorted_new
+                .iter()
+                .filter(|&&&ni| !mainline.ingredients[ni].is_dropped())
+                .map(|&&ni| mainline.ingredients[ni].domain())
+                .collect();
+
+            let mut domain_new_nodes = sorted_new
+                .iter()
+                .filter(|&&&ni| ni != mainline.source)
+                .filter(|&&&ni| !mainline.ingredients[ni].is_dropped())
+                .map(|&&ni| (mainline.ingredients[ni].domain(), ni))
+                .fold(HashMap::new(), |mut dns, (d, ni)| {
+                    dns.entry(d).or_insert_with(Vec::new).push(ni);
+                    dns
+                });
+
+            // Assign local addresses to all new nodes, and initialize them
+            for (domain, nodes) in &mut domain_new_nodes {
+                // Number of pre-existing nodes
+                let mut nnodes = mainline.remap.get(domain).map(HashMap::len).unwrap_or(0);
+
+                if nodes.is_empty() {
+                    // Nothing to do here
+                    continue;
+                }
 
-            let log = log.new(o!("domain" => domain.index()));
+                let log = log.new(o!("domain" => domain.index()));
 
-            // Give local addresses to every (new) node
-            for &ni in nodes.iter() {
-                debug!(log,
-                       "assigning local index";
-                       "type" => format!("{:?}", mainline.ingredients[ni]),
-                       "node" => ni.index(),
-                       "local" => nnodes
-                );
+                // Give local addresses to every (new) node
+                for &ni in nodes.iter() {
+                    debug!(log,
+                           "assigning local index";
+                           "type" => format!("{:?}", mainline.ingredients[ni]),
+                           "node" => ni.index(),
+                           "local" => nnodes
+                    );
 
-                let mut ip: IndexPair = ni.into();
-                ip.set_local(unsafe { LocalNodeIndex::make(nnodes as u32) });
-                mainline.ingredients[ni].set_finalized_addr(ip);
-                mainline
-                    .remap
-                    .entry(*domain)
-                    .or_insert_with(HashMap::new)
-                    .insert(ni, ip);
-                nnodes += 1;
-            }
+                    let mut ip: IndexPair = ni.into();
+                    ip.set_local(unsafe { LocalNodeIndex::make(nnodes as u32) });
+                    mainline.ingredients[ni].set_finalized_addr(ip);
+                    mainline
+                        .remap
+                        .entry(*domain)
+                        .or_insert_with(HashMap::new)
+                        .insert(ni, ip);
+                    nnodes += 1;
+                }
 
-            // Initialize each new node
-            for &ni in nodes.iter() {
-                if mainline.ingredients[ni].is_internal() {
-                    // Figure out all the remappings that have happened
-                    // NOTE: this has to be *per node*, since a shared parent may be remapped
-                    // differently to different children (due to sharding for example). we just
-                    // allocate it once though.
-                    let mut remap = mainline.remap[domain].clone();
-
-                    // Parents in other domains have been swapped for ingress nodes.
-                    // Those ingress nodes' indices are now local.
-                    for (&(dst, src), &instead) in &swapped {
-                        if dst != ni {
-                            // ignore mappings for other nodes
-                            continue;
+                // Initialize each new node
+                for &ni in nodes.iter() {
+                    if mainline.ingredients[ni].is_base() {
+                        // bases only know of the other bases they refer to, which are in this
+                        // domain
+                        mainline
+                            .ingredients
+                            .node_weight_mut(ni)
+                            .unwrap()
+                            .on_commit(&mainline.remap[domain]);
+                    } else if mainline.ingredients[ni].is_internal() {
+                        // Figure out all the remappings that have happened
+                        // NOTE: this has to be *per node*, since a shared parent may be remapped
+                        // differently to different children (due to sharding for example). we just
+                        // allocate it once though.
+                        let mut remap = mainline.remap[domain].clone();
+
+                        // Parents in other domains have been swapped for ingress nodes.
+                        // Those ingress nodes' indices are now local.
+                        for (&(dst, src), &instead) in &swapped {
+                            if dst != ni {
+                                // ignore mappings for other nodes
+                                continue;
+                            }
+
+                            let old = remap.insert(src, mainline.remap[domain][&instead]);
+                            assert_eq!(old, None);
                         }
 
-                        let old = remap.insert(src, mainline.remap[domain][&instead]);
-                        assert_eq!(old, None);
+                        trace!(log, "initializing new node"; "node" => ni.index());
+                        mainline
+                            .ingredients
+                            .node_weight_mut(ni)
+                            .unwrap()
+                            .on_commit(&remap);
                     }
+                }
+            }
 
-                    trace!(log, "initializing new node"; "node" => ni.index());
+            if let Some(shards) = mainline.sharding {
+                sharding::validate(&log, &mainline.ingredients, &topo, shards)
+            };
+
+            // at this point, we've hooked up the graph such that, for any given domain, the graph
+            // looks like this:
+            //
+            //      o (egress)
+            //     +.\......................
+            //     :  o (ingress)
+            //     :  |
+            //     :  o-------------+
+            //     :  |             |
+            //     :  o             o
+            //     :  |             |
+            //     :  o (egress)    o (egress)
+            //     +..|...........+.|..........
+            //     :  o (ingress) : o (ingress)
+            //     :  |\          :  \
+            //     :  | \         :   o
+            //
+            // etc.
+            // println!("{}", mainline);
+
+            for &ni in &new {
+                let n = &mainline.ingredients[ni];
+                if ni != mainline.source && !n.is_dropped() {
+                    let di = n.domain();
                     mainline
-                        .ingredients
-                        .node_weight_mut(ni)
-                        .unwrap()
-                        .on_commit(&remap);
+                        .domain_nodes
+                        .entry(di)
+                        .or_insert_with(Vec::new)
+                        .push(ni);
                 }
             }
-        }
+            let mut uninformed_domain_nodes: HashMap<_, _> = changed_domains
+                .iter()
+                .map(|&di| {
+                    let mut m = mainline.domain_nodes[&di]
+                        .iter()
+                        .cloned()
+                        .map(|ni| (ni, new.contains(&ni)))
+                        .collect::<Vec<_>>();
+                    m.sort();
+                    (di, m)
+                })
+                .collect();
+
+            // Boot up new domains (they'll ignore all updates for now)
+            debug!(log, "booting new domains");
+            let to_boot = changed_domains
+                .iter()
+                .filter(|&d| !mainline.domains.contains_key(d))
+                .count();
+            mainline.progress.update(|p| {
+                p.step = MigrationStep::BootingDomains;
+                p.domains_to_boot = to_boot;
+            });
+            for domain in changed_domains {
+                if mainline.domains.contains_key(&domain) {
+                    // this is not a new domain
+                    continue;
+                }
 
-        if let Some(shards) = mainline.sharding {
-            sharding::validate(&log, &mainline.ingredients, &topo, shards)
-        };
+                let nodes = uninformed_domain_nodes.remove(&domain).unwrap();
+                let d = mainline.place_domain(
+                    domain,
+                    mainline.ingredients[nodes[0].0].sharded_by().shards(),
+                    &log,
+                    nodes,
+                );
+                mainline.domains.insert(domain, d);
+                booted.push(domain);
+                mainline.progress.update(|p| p.domains_booted += 1);
+            }
 
-        // at this point, we've hooked up the graph such that, for any given domain, the graph
-        // looks like this:
-        //
-        //      o (egress)
-        //     +.\......................
-        //     :  o (ingress)
-        //     :  |
-        //     :  o-------------+
-        //     :  |             |
-        //     :  o             o
-        //     :  |             |
-        //     :  o (egress)    o (egress)
-        //     +..|...........+.|..........
-        //     :  o (ingress) : o (ingress)
-        //     :  |\          :  \
-        //     :  | \         :   o
-        //
-        // etc.
-        // println!("{}", mainline);
-
-        for &ni in &new {
-            let n = &mainline.ingredients[ni];
-            if ni != mainline.source && !n.is_dropped() {
-                let di = n.domain();
-                mainline
-                    .domain_nodes
-                    .entry(di)
-                    .or_insert_with(Vec::new)
-                    .push(ni);
+            // Add any new nodes to existing domains (they'll also ignore all updates for now)
+            debug!(log, "mutating existing domains");
+            mainline
+                .progress
+                .update(|p| p.step = MigrationStep::Connecting);
+            augmentation::inform(&log, &mut mainline, uninformed_domain_nodes);
+
+            // Tell all base nodes and base ingress children about newly added columns
+            for (ni, change) in columns {
+                let mut inform = if let ColumnChange::Add(..) = change {
+                    // we need to inform all of the base's children too,
+                    // so that they know to add columns to existing records when replaying
+                    mainline
+                        .ingredients
+                        .neighbors_directed(ni, petgraph::EdgeDirection::Outgoing)
+                        .filter(|&eni| mainline.ingredients[eni].is_egress())
+                        .flat_map(|eni| {
+                            // find ingresses under this egress
+                            mainline
+                                .ingredients
+                                .neighbors_directed(eni, petgraph::EdgeDirection::Outgoing)
+                        })
+                        .collect()
+                } else {
+                    // ingress nodes don't need to know about deleted columns, because those are
+                    // only relevant when new writes enter the graph. renamed columns are already
+                    // listed for every node they were renamed in, and converted rows are sent on
+                    // as writes.
+                    Vec::new()
+                };
+                inform.push(ni);
+                // once the domains are told, the change stays even if the migration fails
+                applied.push(ni);
+
+                for ni in inform {
+                    let n = &mainline.ingredients[ni];
+                    let m = match change.clone() {
+                        ColumnChange::Add(field, default) => box Packet::AddBaseColumn {
+                            node: n.local_addr(),
+                            field,
+                            default,
+                        },
+                        ColumnChange::Drop(column) => box Packet::DropBaseColumn {
+                            node: n.local_addr(),
+                            column,
+                        },
+                        ColumnChange::Rename(column, name) => box Packet::RenameColumn {
+                            node: n.local_addr(),
+                            column,
+                            name,
+                        },
+                        ColumnChange::AlterType(column, ty, cast) => box Packet::AlterColumnType {
+                            node: n.local_addr(),
+                            column,
+                            ty,
+                            cast,
+                        },
+                    };
+
+                    let domain = mainline.domains.get_mut(&n.domain()).unwrap();
+
+                    domain.send_to_healthy(m, &mainline.workers).unwrap();
+                    mainline.replies.wait_for_acks(&domain)?;
+                }
             }
-        }
-        let mut uninformed_domain_nodes: HashMap<_, _> = changed_domains
-            .iter()
-            .map(|&di| {
-                let mut m = mainline.domain_nodes[&di]
-                    .iter()
-                    .cloned()
-                    .map(|ni| (ni, new.contains(&ni)))
-                    .collect::<Vec<_>>();
-                m.sort();
-                (di, m)
-            })
-            .collect();
 
-        // Boot up new domains (they'll ignore all updates for now)
-        debug!(log, "booting new domains");
-        for domain in changed_domains {
-            if mainline.domains.contains_key(&domain) {
-                // this is not a new domain
-                continue;
+            for ni in renamed {
+                applied.push(ni);
+                let n = &mainline.ingredients[ni];
+                let m = box Packet::RenameBase {
+                    node: n.local_addr(),
+                    name: n.name().to_owned(),
+                };
+                let domain = mainline.domains.get_mut(&n.domain()).unwrap();
+                domain.send_to_healthy(m, &mainline.workers).unwrap();
+                mainline.replies.wait_for_acks(&domain)?;
             }
 
-            let nodes = uninformed_domain_nodes.remove(&domain).unwrap();
-            let d = mainline.place_domain(
-                domain,
-                mainline.ingredients[nodes[0].0].sharded_by().shards(),
+            // Set up inter-domain connections
+            // NOTE: once we do this, we are making existing domains block on new domains!
+            info!(log, "bringing up inter-domain connections");
+            routing::connect(
                 &log,
-                nodes,
+                &mut mainline.ingredients,
+                &mut mainline.domains,
+                &mainline.workers,
+                &new,
             );
-            mainline.domains.insert(domain, d);
+
+            #[cfg(test)]
+            match mainline.fail_next_migration.take() {
+                Some(true) => panic!("migration was made to panic"),
+                Some(false) => return Err("migration was made to fail".to_owned()),
+                None => {}
+            }
+
+            // And now, the last piece of the puzzle -- set up materializations
+            info!(log, "initializing new materializations");
+            mainline.materializations.commit(
+                &mut mainline.ingredients,
+                &new,
+                &mut mainline.domains,
+                &mainline.workers,
+                &mut mainline.replies,
+                &mainline.progress,
+            )
+        }))
+        .unwrap_or_else(|e| Err(panic_message(e)));
+        if let Err(e) = staged {
+            abort(&log, mainline, &new, &booted, &e);
+            let ndomains = mainline.ndomains;
+            checkpoint.restore(mainline, &applied);
+            // the indices of domains that were booted are not handed out again, in case their
+            // workers have yet to shut them down
+            mainline.ndomains = ndomains;
+            return Err(e);
         }
+        mainline.replies.set_deadline(None);
+        let committed = journal::Committed::of(&mainline.ingredients, &new, journaled);
+        mainline.committed.push(committed);
 
-        // Add any new nodes to existing domains (they'll also ignore all updates for now)
-        debug!(log, "mutating existing domains");
-        augmentation::inform(&log, &mut mainline, uninformed_domain_nodes);
+        let graph = &mainline.ingredients;
+        let nodes = added_nodes(graph, mainline.source, &new);
+        let mut reused: Vec<_> = nodes
+            .iter()
+            .flat_map(|n| graph.neighbors_directed(n.node, petgraph::EdgeDirection::Incoming))
+            .filter(|&pi| pi != mainline.source && !new.contains(&pi))
+            .collect();
+        reused.sort();
+        reused.dedup();
+        let readers = nodes
+            .iter()
+            .filter_map(|n| {
+                let key = graph[n.node].with_reader(|r| r.key().map(Vec::from)).ok()?;
+                Some(AddedReader {
+                    node: n.node,
+                    name: n.name.clone(),
+                    key: key.unwrap_or_default(),
+                })
+            })
+            .collect();
+        booted.sort();
+        let mut removals: Vec<_> = removed.iter().chain(&dropped).cloned().collect();
+        removals.sort();
+
+        if !removed.is_empty() {
+            info!(log, "removing old views"; "#nodes" => removed.len());
+            for leaf in removed {
+                // an earlier removal may already have taken this one with it
+                if !mainline.ingredients[leaf].is_dropped() {
+                    mainline.remove_leaf(leaf).unwrap();
+                }
+            }
+        }
 
-        // Tell all base nodes and base ingress children about newly added columns
-        for (ni, change) in self.columns {
-            let mut inform = if let ColumnChange::Add(..) = change {
-                // we need to inform all of the base's children too,
-                // so that they know to add columns to existing records when replaying
+        for base in dropped {
+            assert_eq!(
                 mainline
                     .ingredients
-                    .neighbors_directed(ni, petgraph::EdgeDirection::Outgoing)
-                    .filter(|&eni| mainline.ingredients[eni].is_egress())
-                    .flat_map(|eni| {
-                        // find ingresses under this egress
-                        mainline
-                            .ingredients
-                            .neighbors_directed(eni, petgraph::EdgeDirection::Outgoing)
-                    })
-                    .collect()
-            } else {
-                // ingress nodes don't need to know about deleted columns, because those are only
-                // relevant when new writes enter the graph.
-                Vec::new()
-            };
-            inform.push(ni);
+                    .neighbors_directed(base, petgraph::EdgeDirection::Outgoing)
+                    .count(),
+                0,
+                "cannot drop base {} while views still read from it",
+                mainline.ingredients[base].name()
+            );
+            info!(log, "dropping base"; "node" => base.index());
+            mainline.remove_leaf(base).unwrap();
+        }
 
-            for ni in inform {
-                let n = &mainline.ingredients[ni];
-                let m = match change.clone() {
-                    ColumnChange::Add(field, default) => box Packet::AddBaseColumn {
-                        node: n.local_addr(),
-                        field,
-                        default,
-                    },
-                    ColumnChange::Drop(column) => box Packet::DropBaseColumn {
-                        node: n.local_addr(),
-                        column,
-                    },
-                };
+        mainline.progress.finish();
+        let took = mainline.clock.now().duration_since(start);
+        warn!(log, "migration completed"; "ms" => took.as_millis());
+        Ok(MigrationResult {
+            nodes,
+            reused,
+            new_domains: booted.into_iter().map(|d| d.index()).collect(),
+            readers,
+            swaps,
+            removed: removals,
+            progress: mainline.progress.get(None).unwrap(),
+        })
+    }
+}
 
-                let domain = mainline.domains.get_mut(&n.domain()).unwrap();
+/// Undo what a migration that failed part of the way through did to the domains: the domains it
+/// booted are shut down, and the nodes it handed to the other domains are taken out of them again.
+///
+/// The controller's own view of the graph is left for the migration's checkpoint to put back.
+fn abort(
+    log: &slog::Logger,
+    mainline: &mut ControllerInner,
+    new: &HashSet<NodeIndex>,
+    booted: &[DomainIndex],
+    error: &str,
+) {
+    crit!(log, "aborting migration: {}", error);
+    mainline.replies.set_deadline(None);
+
+    for di in booted {
+        if let Some(mut d) = mainline.domains.remove(di) {
+            // don't unwrap, since the domain may be what the migration was stuck on
+            drop(d.send_to_healthy(box Packet::Quit, &mainline.workers));
+        }
+        mainline.replies.forget(*di);
+    }
 
-                domain.send_to_healthy(m, &mainline.workers).unwrap();
-                mainline.replies.wait_for_acks(&domain);
+    // only nodes that were handed to a domain have anything to undo
+    let mut added: Vec<_> = new
+        .iter()
+        .cloned()
+        .filter(|&ni| ni != mainline.source)
+        .filter(|&ni| !mainline.ingredients[ni].is_dropped() && mainline.ingredients[ni].is_taken())
+        .collect();
+    added.sort();
+
+    // the nodes that were there before must stop sending to the new ones
+    let mut untx = Vec::new();
+    for &ni in &added {
+        for parent in mainline
+            .ingredients
+            .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+        {
+            let p = &mainline.ingredients[parent];
+            if !new.contains(&parent) && p.is_egress() {
+                untx.push((p.domain(), p.local_addr(), ni));
             }
         }
-
-        // Set up inter-domain connections
-        // NOTE: once we do this, we are making existing domains block on new domains!
-        info!(log, "bringing up inter-domain connections");
-        routing::connect(
-            &log,
-            &mut mainline.ingredients,
-            &mut mainline.domains,
-            &mainline.workers,
-            &new,
-        );
-
-        // And now, the last piece of the puzzle -- set up materializations
-        info!(log, "initializing new materializations");
-        mainline.materializations.commit(
-            &mut mainline.ingredients,
-            &new,
-            &mut mainline.domains,
-            &mainline.workers,
-            &mut mainline.replies,
-        );
-
-        warn!(log, "migration completed"; "ms" => start.elapsed().as_millis());
     }
+    for (domain, node, target) in untx {
+        if let Some(d) = mainline.domains.get_mut(&domain) {
+            let m = box Packet::RemoveEgressTx { node, target };
+            drop(d.send_to_healthy(m, &mainline.workers));
+        }
+    }
+    mainline.remove_nodes(&added).unwrap();
 }
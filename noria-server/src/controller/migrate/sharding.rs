@@ -13,6 +13,7 @@ pub fn shard(
     new: &mut HashSet<NodeIndex>,
     topo_list: &[NodeIndex],
     sharding_factor: usize,
+    unsharded: &HashSet<NodeIndex>,
 ) -> (Vec<NodeIndex>, HashMap<(NodeIndex, NodeIndex), NodeIndex>) {
     // we must keep track of changes we make to the parent of a node, since this remapping must be
     // communicated to the nodes so they know the true identifier of their parent in the graph.
@@ -26,6 +27,34 @@ pub fn shard(
             .map(|ni| (ni, graph[ni].sharded_by()))
             .collect();
 
+        let constrained = graph[node]
+            .get_base()
+            .map(|b| !b.unique().is_empty() || b.has_sequences() || b.dedup().is_some())
+            .unwrap_or(false);
+        if constrained || graph[node].is_base() && !super::related_bases(graph, node).is_empty() {
+            // references between bases are checked against the state of both in one domain, and
+            // unique columns against the state of the whole base. sequences and the offsets of
+            // stream events that were applied must also be shared by the whole base.
+            info!(log, "not sharding base with constraints"; "node" => ?node);
+            graph
+                .node_weight_mut(node)
+                .unwrap()
+                .shard_by(Sharding::None);
+            continue;
+        }
+
+        if unsharded.contains(&node) {
+            info!(log, "not sharding node as asked"; "node" => ?node);
+            for &ni in input_shardings.keys() {
+                reshard(log, new, &mut swaps, graph, ni, node, Sharding::ForcedNone);
+            }
+            graph
+                .node_weight_mut(node)
+                .unwrap()
+                .shard_by(Sharding::ForcedNone);
+            continue;
+        }
+
         let mut need_sharding = if graph[node].is_internal() || graph[node].is_base() {
             // suggest_indexes is okay because `node` *must* be new, and therefore will return
             // global node indices.
@@ -558,6 +587,23 @@ pub fn shard(
             .shard_by(Sharding::ForcedNone);
     }
 
+    // shuffling the input of a sharded aggregation sends every change from every input shard to
+    // the aggregation. counts and sums can instead be partially computed in each input shard
+    // first, so that only one record per group and batch crosses the shuffle. the same goes for
+    // the sketches of distinct values, which are then merged by the aggregation.
+    let aggregations: Vec<_> = new
+        .iter()
+        .filter(|&&n| graph[n].is_internal())
+        .filter(|&&n| match *graph[n] {
+            ops::NodeOperator::Sum(_) | ops::NodeOperator::ApproxCountDistinct(_) => true,
+            _ => false,
+        })
+        .cloned()
+        .collect();
+    for n in aggregations {
+        pre_aggregate(log, new, &mut swaps, graph, n);
+    }
+
     // check that we didn't mess anything up
     // topo list changed though, so re-compute it
     let mut topo_list = Vec::with_capacity(new.len());
@@ -639,6 +685,84 @@ fn reshard(
     );
 }
 
+/// Insert a `PartialAggregator` or a `PartialSketch` above the nodes that shuffle the input of the
+/// aggregation `agg`, if that input is sharded and the shuffled records are not used by anything
+/// else.
+fn pre_aggregate(
+    log: &Logger,
+    new: &mut HashSet<NodeIndex>,
+    swaps: &mut HashMap<(NodeIndex, NodeIndex), NodeIndex>,
+    graph: &mut Graph,
+    agg: NodeIndex,
+) {
+    let parent = |graph: &Graph, n: NodeIndex| {
+        let mut ps = graph.neighbors_directed(n, petgraph::EdgeDirection::Incoming);
+        let p = ps.next().unwrap();
+        assert_eq!(ps.count(), 0);
+        p
+    };
+
+    // walk up past the sharders and shard mergers added for the aggregation
+    let mut shuffle = None;
+    let mut src = parent(&*graph, agg);
+    while new.contains(&src) && (graph[src].is_sharder() || graph[src].is_shard_merger()) {
+        if graph
+            .neighbors_directed(src, petgraph::EdgeDirection::Outgoing)
+            .count()
+            != 1
+        {
+            return;
+        }
+        shuffle = Some(src);
+        src = parent(&*graph, src);
+    }
+    let shuffle = match shuffle {
+        Some(shuffle) => shuffle,
+        None => return,
+    };
+    let shards = match graph[src].sharded_by().shards() {
+        Some(shards) => shards,
+        None => return,
+    };
+
+    let (partial, group_by): (NodeOperator, Vec<usize>) =
+        match **graph.node_weight_mut(agg).unwrap() {
+            ops::NodeOperator::Sum(ref mut a) => match a.split_partial() {
+                Some(partial) => {
+                    let group_by = partial.group_by().to_vec();
+                    (partial.into(), group_by)
+                }
+                None => return,
+            },
+            ops::NodeOperator::ApproxCountDistinct(ref mut a) => {
+                let partial = a.split_partial();
+                let group_by = partial.group_by().to_vec();
+                (partial.into(), group_by)
+            }
+            _ => unreachable!(),
+        };
+
+    // the partial results stay in the shards of the input, but are only still sharded by its
+    // sharding column if they are grouped by it
+    let sharding = match graph[src].sharded_by() {
+        Sharding::ByColumn(c, _) if group_by.contains(&c) => Sharding::ByColumn(c, shards),
+        _ => Sharding::Random(shards),
+    };
+    let mut node = graph[src].mirror(partial);
+    node.shard_by(sharding);
+    let node = graph.add_node(node);
+    info!(log, "pre-aggregating before shuffle";
+          "aggregation" => ?agg,
+          "using" => ?node);
+
+    new.insert(node);
+    let old = graph.find_edge(src, shuffle).unwrap();
+    graph.remove_edge(old).unwrap();
+    graph.add_edge(src, node, ());
+    graph.add_edge(node, shuffle, ());
+    swaps.insert((shuffle, src), node);
+}
+
 pub fn validate(log: &Logger, graph: &Graph, topo_list: &[NodeIndex], sharding_factor: usize) {
     // ensure that each node matches the sharding of each of its ancestors, unless the ancestor is
     // a sharder or a shard merger
@@ -8,7 +8,8 @@ use common::DataType;
 use dataflow::ops::filter::FilterCondition;
 use dataflow::ops::join::{Join, JoinType};
 use dataflow::ops::latest::Latest;
-use dataflow::ops::project::{Project, ProjectExpression, ProjectExpressionBase};
+use dataflow::ops::project::{ColumnMask, Project, ProjectExpression, ProjectExpressionBase};
+use dataflow::ops::set::SetOpKind;
 use dataflow::{node, ops};
 use mir::node::{GroupedNodeType, MirNode, MirNodeType};
 use mir::query::{MirQuery, QueryFlowParts};
@@ -99,6 +100,23 @@ fn mir_node_to_flow_parts(
                         table_mapping,
                     )
                 }
+                MirNodeType::ApproxCountDistinct {
+                    ref on,
+                    ref group_by,
+                } => {
+                    assert_eq!(mir_node.ancestors.len(), 1);
+                    let parent = mir_node.ancestors[0].clone();
+                    make_grouped_node(
+                        &name,
+                        parent,
+                        mir_node.columns.as_slice(),
+                        on,
+                        group_by,
+                        GroupedNodeType::ApproxCountDistinct,
+                        mig,
+                        table_mapping,
+                    )
+                }
                 MirNodeType::Base {
                     ref mut column_specs,
                     ref keys,
@@ -184,10 +202,16 @@ fn mir_node_to_flow_parts(
                     let parent = mir_node.ancestors[0].clone();
                     make_latest_node(&name, parent, mir_node.columns.as_slice(), group_by, mig)
                 }
-                MirNodeType::Leaf { ref keys, .. } => {
+                MirNodeType::Leaf {
+                    ref keys,
+                    ref order,
+                    distinct,
+                    ref masks,
+                    ..
+                } => {
                     assert_eq!(mir_node.ancestors.len(), 1);
                     let parent = mir_node.ancestors[0].clone();
-                    materialize_leaf_node(&parent, name, keys, mig);
+                    materialize_leaf_node(&parent, name, keys, order, distinct, masks, mig);
                     // TODO(malte): below is yucky, but required to satisfy the type system:
                     // each match arm must return a `FlowNode`, so we use the parent's one
                     // here.
@@ -221,6 +245,7 @@ fn mir_node_to_flow_parts(
                     ref emit,
                     ref literals,
                     ref arithmetic,
+                    ref masks,
                 } => {
                     assert_eq!(mir_node.ancestors.len(), 1);
                     let parent = mir_node.ancestors[0].clone();
@@ -231,6 +256,7 @@ fn mir_node_to_flow_parts(
                         emit,
                         arithmetic,
                         literals,
+                        masks,
                         mig,
                         table_mapping,
                     )
@@ -264,6 +290,28 @@ fn mir_node_to_flow_parts(
                     let parent = mir_node.ancestors[0].clone();
                     make_distinct_node(&name, parent, mir_node.columns.as_slice(), group_by, mig)
                 }
+                MirNodeType::SetOp { kind } => {
+                    assert_eq!(mir_node.ancestors.len(), 1);
+                    let parent = mir_node.ancestors[0].clone();
+                    make_set_op_node(&name, parent, mir_node.columns.as_slice(), kind, mig)
+                }
+                MirNodeType::Recursive {
+                    ref from,
+                    ref to,
+                    depth,
+                } => {
+                    assert_eq!(mir_node.ancestors.len(), 1);
+                    let parent = mir_node.ancestors[0].clone();
+                    make_recursive_node(
+                        &name,
+                        parent,
+                        mir_node.columns.as_slice(),
+                        from,
+                        to,
+                        depth,
+                        mig,
+                    )
+                }
                 MirNodeType::TopK {
                     ref order,
                     ref group_by,
@@ -550,6 +598,12 @@ fn make_grouped_node(
             let gc = GroupConcat::new(parent_na, vec![TextComponent::Column(over_col_indx)], sep);
             mig.add_ingredient(String::from(name), column_names.as_slice(), gc)
         }
+        GroupedNodeType::ApproxCountDistinct => {
+            use dataflow::ops::grouped::hll::ApproxCountDistinct;
+            let approx =
+                ApproxCountDistinct::over(parent_na, over_col_indx, group_col_indx.as_slice());
+            mig.add_ingredient(String::from(name), column_names.as_slice(), approx)
+        }
     };
     FlowNode::New(na)
 }
@@ -740,6 +794,7 @@ fn make_project_node(
     emit: &[Column],
     arithmetic: &[(String, ArithmeticExpression)],
     literals: &[(String, DataType)],
+    masks: &[(usize, ColumnMask)],
     mig: &mut Migration,
     table_mapping: Option<&HashMap<(String, Option<String>), String>>,
 ) -> FlowNode {
@@ -772,11 +827,54 @@ fn make_project_node(
             projected_column_ids.as_slice(),
             Some(literal_values),
             Some(projected_arithmetic),
-        ),
+        )
+        .with_masks(masks.to_vec()),
     );
     FlowNode::New(n)
 }
 
+fn make_set_op_node(
+    name: &str,
+    parent: MirNodeRef,
+    columns: &[Column],
+    kind: SetOpKind,
+    mig: &mut Migration,
+) -> FlowNode {
+    let parent_na = parent.borrow().flow_node_addr().unwrap();
+    let column_names = column_names(columns);
+    // the parent has one more column, which tells the two inputs apart
+    let side = parent.borrow().columns().len() - 1;
+
+    let na = mig.add_ingredient(
+        String::from(name),
+        column_names.as_slice(),
+        ops::set::SetOp::new(parent_na, kind, side),
+    );
+    FlowNode::New(na)
+}
+
+fn make_recursive_node(
+    name: &str,
+    parent: MirNodeRef,
+    columns: &[Column],
+    from: &Column,
+    to: &Column,
+    depth: usize,
+    mig: &mut Migration,
+) -> FlowNode {
+    let parent_na = parent.borrow().flow_node_addr().unwrap();
+    let column_names = column_names(columns);
+    let from = parent.borrow().column_id_for_column(from, None);
+    let to = parent.borrow().column_id_for_column(to, None);
+
+    let na = mig.add_ingredient(
+        String::from(name),
+        column_names.as_slice(),
+        ops::recursive::Recursive::new(parent_na, from, to, depth),
+    );
+    FlowNode::New(na)
+}
+
 fn make_distinct_node(
     name: &str,
     parent: MirNodeRef,
@@ -870,6 +968,9 @@ fn materialize_leaf_node(
     parent: &MirNodeRef,
     name: String,
     key_cols: &[Column],
+    order: &Option<Vec<(Column, OrderType)>>,
+    distinct: bool,
+    masks: &[(Column, ColumnMask)],
     mig: &mut Migration,
 ) {
     let na = parent.borrow().flow_node_addr().unwrap();
@@ -891,4 +992,22 @@ fn materialize_leaf_node(
         // if no key specified, default to the first column
         mig.maintain(name, na, &[0]);
     }
+
+    if let Some(ref order) = *order {
+        let order = order
+            .iter()
+            .map(|&(ref c, ref ot)| (parent.borrow().column_id_for_column(c, None), ot.clone()))
+            .collect();
+        mig.order_reader(na, order);
+    }
+    if distinct {
+        mig.deduplicate_reader(na);
+    }
+    if !masks.is_empty() {
+        let masks = masks
+            .iter()
+            .map(|&(ref c, ref m)| (parent.borrow().column_id_for_column(c, None), m.clone()))
+            .collect();
+        mig.mask_reader(na, masks);
+    }
 }
@@ -1,37 +1,62 @@
 use crate::controller::inner::ControllerInner;
 use crate::controller::migrate::Migration;
+use crate::controller::progress::MigrationTracker;
 use crate::controller::recipe::Recipe;
 use crate::coordination::CoordinationMessage;
-use crate::coordination::CoordinationPayload;
+use crate::coordination::{Capacity, CoordinationPayload};
+use crate::optimizer::QueryOptimizer;
 use crate::startup::Event;
+use crate::tls::Certificates;
 use crate::Config;
 use async_bincode::AsyncBincodeReader;
 use dataflow::payload::ControlReplyPacket;
+use dataflow::prelude::ChannelCoordinator;
+use dataflow::{Clock, SystemClock};
+use futures::future::{self, Either};
 use futures::sync::mpsc::UnboundedSender;
-use futures::{self, Future, Sink, Stream};
+use futures::{self, Async, Future, Sink, Stream};
 use hyper::{self, StatusCode};
-use noria::channel::TcpSender;
+use noria::channel::{self, TcpSender};
 use noria::consensus::{Authority, Epoch, STATE_KEY};
-use noria::ControllerDescriptor;
+use noria::tls::Connector;
+use noria::{ControllerDescriptor, DataType};
 use serde_json;
 use slog;
+use std::collections::HashMap;
+use std::io;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use std::time;
 use stream_cancel::Valve;
 use tokio;
 
+mod barriers;
 mod domain_handle;
 mod inner;
+mod invariants;
+mod journal;
 mod keys;
+mod links;
 crate mod migrate; // crate viz for tests
 mod mir_to_flow;
+mod placement;
+mod prepared;
+crate mod progress; // crate viz for the external api
 crate mod recipe; // crate viz for tests
+mod scheduler;
 mod schema;
 mod security;
+mod shadow;
+crate mod sim; // crate viz for simulations
 crate mod sql; // crate viz for tests
 
+/// What the controller keeps in the authority, and what a controller that takes over starts from.
+///
+/// Only the recipes and the journal of how they changed are kept, not the graph they were turned
+/// into, so a controller that takes over re-derives the graph by making the changes anew (see
+/// `journal`).
 #[derive(Clone, Serialize, Deserialize)]
 crate struct ControllerState {
     crate config: Config,
@@ -39,39 +64,157 @@ crate struct ControllerState {
 
     recipe_version: usize,
     recipes: Vec<String>,
+    /// The queries that were removed from the recipe, each with how many of `recipes` had been
+    /// applied when it was.
+    #[serde(default)]
+    removals: Vec<(usize, String)>,
+
+    /// The eviction weight of each view that does not have the default weight of 1.
+    #[serde(default)]
+    eviction_weights: HashMap<String, u32>,
+    /// How long the reader of each view that does not make writes visible right away holds on to
+    /// them.
+    #[serde(default)]
+    publish_intervals: HashMap<String, time::Duration>,
+
+    /// The number of entries in the journal, which records every change made to the recipes and
+    /// what the migrations it committed did, if they were recorded from the first one on. The
+    /// entries are kept under keys of their own.
+    #[serde(default)]
+    journal: usize,
+}
+
+impl ControllerState {
+    /// The state of a deployment that has no recipe yet.
+    crate fn new(config: Config, epoch: Epoch) -> Self {
+        ControllerState {
+            config,
+            epoch,
+            recipe_version: 0,
+            recipes: vec![],
+            removals: vec![],
+            eviction_weights: HashMap::new(),
+            publish_intervals: HashMap::new(),
+            journal: 0,
+        }
+    }
+}
+
+/// A change to the members of a security group.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+crate struct GroupMembershipUpdate {
+    crate group: String,
+    crate uid: DataType,
+    crate gid: DataType,
+    /// Whether the user joins the group, or leaves it.
+    crate member: bool,
+}
+
+/// The write to a group's membership base table that applies a `GroupMembershipUpdate`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+crate struct MembershipWrite {
+    crate table: String,
+    crate row: Vec<DataType>,
+    crate key: Vec<DataType>,
+    /// Where the user and group ids are in the rows of the group's membership view.
+    crate fields: (usize, usize),
 }
 
 struct Worker {
     healthy: bool,
     last_heartbeat: time::Instant,
-    sender: TcpSender<CoordinationMessage>,
+    sender: WorkerSender,
+    /// The address that messages to the worker come from.
+    source: SocketAddr,
+    /// The labels the worker was started with.
+    labels: HashMap<String, String>,
+    /// The resources the worker has for running domains.
+    capacity: Capacity,
+    /// Whether the worker only runs domains that hold nothing but readers.
+    reader_only: bool,
+    /// Whether the worker is being drained, so that no new domains are placed on it.
+    draining: bool,
 }
 
 impl Worker {
-    fn new(sender: TcpSender<CoordinationMessage>) -> Self {
+    fn new(
+        (sender, source): (WorkerSender, SocketAddr),
+        now: time::Instant,
+        labels: HashMap<String, String>,
+        capacity: Capacity,
+        reader_only: bool,
+    ) -> Self {
         Worker {
             healthy: true,
-            last_heartbeat: time::Instant::now(),
+            last_heartbeat: now,
             sender,
+            source,
+            labels,
+            capacity,
+            reader_only,
+            draining: false,
         }
     }
 }
 
 type WorkerIdentifier = SocketAddr;
+type WorkerSender = Box<dyn channel::Sender<Item = CoordinationMessage> + Send>;
+
+/// How the controller reaches workers and domains, and tells the time.
+///
+/// A simulation replaces these with in-memory channels and a virtual clock.
+struct Environment {
+    coordinator: Arc<ChannelCoordinator>,
+    /// Connect to the worker listening on an address, and say where messages to it come from.
+    connect: Box<dyn Fn(&SocketAddr) -> io::Result<(WorkerSender, SocketAddr)> + Send>,
+    clock: Arc<dyn Clock>,
+    /// Called each time there is no reply from domains yet while waiting for one.
+    idle: Box<dyn FnMut() + Send>,
+}
+
+impl Environment {
+    /// Reach workers and domains over TCP, secured as `config` says, and use the system clock.
+    fn tcp(config: &Config, tls: Option<Connector>) -> Self {
+        let cc = Arc::new(ChannelCoordinator::secured(
+            config.domain_secret.clone().map(String::into_bytes),
+            tls,
+        ));
+        Environment {
+            coordinator: cc.clone(),
+            connect: Box::new(move |addr| {
+                let sender = TcpSender::connect_with(addr, cc.tls(), cc.secret())?;
+                let source = sender.local_addr()?;
+                Ok((Box::new(sender) as WorkerSender, source))
+            }),
+            clock: Arc::new(SystemClock),
+            idle: Box::new(thread::yield_now),
+        }
+    }
+}
 
 pub(super) fn main<A: Authority + 'static>(
     valve: &Valve,
     config: Config,
     descriptor: ControllerDescriptor,
-    ctrl_rx: futures::sync::mpsc::UnboundedReceiver<Event>,
+    mut ctrl_rx: futures::sync::mpsc::UnboundedReceiver<Event>,
     cport: tokio::net::tcp::TcpListener,
+    (certs, connector): (Option<Arc<Certificates>>, Option<Connector>),
     log: slog::Logger,
     authority: Arc<A>,
     tx: futures::sync::mpsc::UnboundedSender<Event>,
+    optimizer: Option<Arc<dyn QueryOptimizer>>,
+    progress: MigrationTracker,
 ) -> impl Future<Item = (), Error = ()> {
     let (dtx, drx) = futures::sync::mpsc::unbounded();
 
-    tokio::spawn(listen_domain_replies(valve, log.clone(), dtx, cport));
+    let domain_secret = config.domain_secret.clone().map(String::into_bytes);
+    tokio::spawn(listen_domain_replies(
+        valve,
+        log.clone(),
+        dtx,
+        cport,
+        (certs, domain_secret),
+    ));
 
     // note that we do not start up the data-flow until we find a controller!
 
@@ -86,86 +229,55 @@ pub(super) fn main<A: Authority + 'static>(
     // state that this instance will take if it becomes the controller
     let mut campaign = Some(campaign);
     let mut drx = Some(drx);
-    ctrl_rx
+
+    // submitted migrations are applied once there are no more requests waiting, so that those
+    // submitted together can be applied together
+    let migrations_waiting = Arc::new(AtomicBool::new(false));
+    let waiting = migrations_waiting.clone();
+    // likewise, quotas are only enforced when there is nothing else to do, since that has to ask
+    // every domain for its statistics
+    let quotas_waiting = Arc::new(AtomicBool::new(false));
+    let quotas = quotas_waiting.clone();
+    let events = futures::stream::poll_fn(move || match ctrl_rx.poll() {
+        Ok(Async::NotReady) if waiting.swap(false, Ordering::SeqCst) => {
+            Ok(Async::Ready(Some(Event::RunMigrations)))
+        }
+        Ok(Async::NotReady) if quotas.swap(false, Ordering::SeqCst) => {
+            Ok(Async::Ready(Some(Event::EnforceQuotas)))
+        }
+        r => r,
+    });
+    events
         .map_err(|_| unreachable!())
         .fold(None, move |mut controller: Option<ControllerInner>, e| {
             match e {
-                Event::InternalMessage(msg) => match msg.payload {
-                    CoordinationPayload::Deregister => {
-                        unimplemented!();
-                    }
-                    CoordinationPayload::CreateUniverse(universe) => {
-                        if let Some(ref mut ctrl) = controller {
-                            crate::block_on(|| ctrl.create_universe(universe).unwrap());
-                        }
-                    }
-                    CoordinationPayload::Register {
-                        ref addr,
-                        ref read_listen_addr,
-                        ..
-                    } => {
-                        if let Some(ref mut ctrl) = controller {
-                            crate::block_on(|| {
-                                ctrl.handle_register(&msg, addr, read_listen_addr.clone())
-                                    .unwrap()
-                            });
-                        }
-                    }
-                    CoordinationPayload::Heartbeat => {
-                        if let Some(ref mut ctrl) = controller {
-                            crate::block_on(|| ctrl.handle_heartbeat(&msg).unwrap());
-                        }
-                    }
-                    _ => unreachable!(),
-                },
-                Event::ExternalRequest(method, path, query, body, reply_tx) => {
-                    if let Some(ref mut ctrl) = controller {
-                        let authority = &authority;
-                        let reply = crate::block_on(|| {
-                            ctrl.external_request(method, path, query, body, &authority)
-                        });
-
-                        if reply_tx.send(reply).is_err() {
-                            warn!(log, "client hung up");
-                        }
-                    } else if reply_tx.send(Err(StatusCode::NOT_FOUND)).is_err() {
-                        warn!(log, "client hung up for 404");
-                    }
-                }
-                #[cfg(test)]
-                Event::ManualMigration { f, done } => {
-                    if let Some(ref mut ctrl) = controller {
-                        if !ctrl.workers.is_empty() {
-                            crate::block_on(|| {
-                                ctrl.migrate(move |m| f.call_box((m,)));
-                                done.send(()).unwrap();
-                            });
-                        }
-                    } else {
-                        unreachable!("got migration closure before becoming leader");
-                    }
-                }
-                #[cfg(test)]
-                Event::IsReady(reply) => {
-                    reply
-                        .send(
-                            controller
-                                .as_ref()
-                                .map(|ctrl| !ctrl.workers.is_empty())
-                                .unwrap_or(false),
-                        )
-                        .unwrap();
-                }
                 Event::WonLeaderElection(state) => {
                     let c = campaign.take().unwrap();
                     crate::block_on(move || c.join().unwrap());
                     let drx = drx.take().unwrap();
-                    controller = Some(ControllerInner::new(log.clone(), state.clone(), drx));
+                    controller = Some(ControllerInner::new(
+                        log.clone(),
+                        state.clone(),
+                        Environment::tcp(&state.config, connector.clone()),
+                        drx,
+                        optimizer.clone(),
+                        progress.clone(),
+                    ));
                 }
                 Event::CampaignError(e) => {
                     panic!("{:?}", e);
                 }
-                e => unreachable!("{:?} is not a controller event", e),
+                e => match controller.take() {
+                    Some(mut ctrl) => {
+                        let waiting = (&*migrations_waiting, &*quotas_waiting);
+                        if handle_event(&mut ctrl, e, &authority, waiting, &log) {
+                            controller = Some(ctrl);
+                        } else if let Err(e) = authority.surrender_leadership() {
+                            error!(log, "failed to surrender leadership: {:?}", e);
+                        }
+                    }
+                    None => not_leading(e, &log),
+                },
             }
             Ok(controller)
         })
@@ -182,29 +294,164 @@ pub(super) fn main<A: Authority + 'static>(
         .map_err(|e| panic!("{:?}", e))
 }
 
+/// Handle an event on the controller that is leading.
+///
+/// Returns `false` if the controller must step down.
+fn handle_event<A: Authority + 'static>(
+    ctrl: &mut ControllerInner,
+    e: Event,
+    authority: &Arc<A>,
+    (migrations_waiting, quotas_waiting): (&AtomicBool, &AtomicBool),
+    log: &slog::Logger,
+) -> bool {
+    match e {
+        Event::InternalMessage(msg) => match msg.payload {
+            CoordinationPayload::Deregister => {
+                unimplemented!();
+            }
+            CoordinationPayload::CreateUniverse(universe) => {
+                crate::block_on(|| ctrl.create_universe(universe).unwrap());
+            }
+            CoordinationPayload::Register {
+                ref addr,
+                ref read_listen_addr,
+                ref labels,
+                capacity,
+                reader_only,
+                ..
+            } => {
+                let recovered = crate::block_on(|| {
+                    ctrl.handle_register(
+                        &msg,
+                        addr,
+                        read_listen_addr.clone(),
+                        labels.clone(),
+                        capacity,
+                        reader_only,
+                    )
+                    .unwrap();
+                    ctrl.recover(authority)
+                });
+                if let Err(e) = recovered {
+                    // a controller with the wrong graph must not lead; another one may recover it
+                    crit!(log, "failed to recover the graph, stepping down: {}", e);
+                    return false;
+                }
+                ctrl.sync_links(authority);
+            }
+            CoordinationPayload::Heartbeat => {
+                crate::block_on(|| ctrl.handle_heartbeat(&msg).unwrap());
+                if ctrl.wake_quotas() {
+                    quotas_waiting.store(true, Ordering::SeqCst);
+                }
+            }
+            _ => unreachable!(),
+        },
+        Event::ExternalRequest(method, path, query, body, reply_tx) => {
+            let reply =
+                crate::block_on(|| ctrl.external_request(method, path, query, body, authority));
+            ctrl.sync_links(authority);
+            if ctrl.wake_migrations() {
+                migrations_waiting.store(true, Ordering::SeqCst);
+            }
+
+            if reply_tx.send(reply).is_err() {
+                warn!(log, "client hung up");
+            }
+        }
+        #[cfg(test)]
+        Event::ManualMigration { f, done } => {
+            if !ctrl.workers.is_empty() {
+                crate::block_on(|| {
+                    ctrl.migrate(move |m| f.call_box((m,)));
+                    done.send(()).unwrap();
+                });
+            }
+        }
+        #[cfg(test)]
+        Event::FailNextMigration(panic, done) => {
+            ctrl.fail_next_migration = Some(panic);
+            done.send(()).unwrap();
+        }
+        #[cfg(test)]
+        Event::IsReady(reply) => {
+            reply.send(!ctrl.workers.is_empty()).unwrap();
+        }
+        Event::RunMigrations => {
+            crate::block_on(|| ctrl.run_queued_migrations(authority));
+            ctrl.sync_links(authority);
+        }
+        Event::EnforceQuotas => {
+            crate::block_on(|| ctrl.enforce_universe_quotas());
+        }
+        e => unreachable!("{:?} is not a controller event", e),
+    }
+    true
+}
+
+/// Handle an event that is meant for the controller while this instance is not leading.
+fn not_leading(e: Event, log: &slog::Logger) {
+    match e {
+        Event::InternalMessage(..) | Event::RunMigrations | Event::EnforceQuotas => {}
+        Event::ExternalRequest(.., reply_tx) => {
+            if reply_tx.send(Err(StatusCode::NOT_FOUND)).is_err() {
+                warn!(log, "client hung up for 404");
+            }
+        }
+        #[cfg(test)]
+        Event::ManualMigration { .. } => {
+            unreachable!("got migration closure before becoming leader");
+        }
+        #[cfg(test)]
+        Event::FailNextMigration(_, done) => {
+            done.send(()).unwrap();
+        }
+        #[cfg(test)]
+        Event::IsReady(reply) => {
+            reply.send(false).unwrap();
+        }
+        e => unreachable!("{:?} is not a controller event", e),
+    }
+}
+
+/// Listen for replies from domains, on connections that are encrypted with `certs` and that know
+/// `secret`, if those are set.
 fn listen_domain_replies(
     valve: &Valve,
     log: slog::Logger,
     reply_tx: UnboundedSender<ControlReplyPacket>,
     on: tokio::net::TcpListener,
+    (certs, secret): (Option<Arc<Certificates>>, Option<Vec<u8>>),
 ) -> impl Future<Item = (), Error = ()> {
     let valve = valve.clone();
+    let conn_log = log.clone();
     valve
         .wrap(on.incoming())
         .map_err(failure::Error::from)
         .for_each(move |sock| {
-            tokio::spawn(
-                valve
-                    .wrap(AsyncBincodeReader::from(sock))
-                    .map_err(failure::Error::from)
-                    .forward(
-                        reply_tx
-                            .clone()
-                            .sink_map_err(|_| format_err!("main event loop went away")),
-                    )
-                    .map(|_| ())
-                    .map_err(|e| panic!("{:?}", e)),
+            let valve = valve.clone();
+            let reply_tx = reply_tx.clone();
+            let handshake = crate::secure(
+                certs.as_ref().map(|c| &**c),
+                secret.as_ref().map(Vec::as_slice),
+                sock,
+                &conn_log,
             );
+            tokio::spawn(handshake.and_then(move |sock| {
+                match sock {
+                    Some(sock) => Either::A(
+                        valve
+                            .wrap(AsyncBincodeReader::from(sock))
+                            .map_err(failure::Error::from)
+                            .forward(
+                                reply_tx.sink_map_err(|_| format_err!("main event loop went away")),
+                            )
+                            .map(|_| ())
+                            .map_err(|e| panic!("{:?}", e)),
+                    ),
+                    None => Either::B(future::ok(())),
+                }
+            }));
             Ok(())
         })
         .map_err(move |e| {
@@ -259,15 +506,11 @@ fn instance_campaign<A: Authority + 'static>(
             let state = authority.read_modify_write(
                 STATE_KEY,
                 |state: Option<ControllerState>| match state {
-                    None => Ok(ControllerState {
-                        config: config.clone(),
-                        epoch,
-                        recipe_version: 0,
-                        recipes: vec![],
-                    }),
+                    None => Ok(ControllerState::new(config.clone(), epoch)),
                     Some(ref state) if state.epoch > epoch => Err(()),
                     Some(mut state) => {
                         state.epoch = epoch;
+                        state.config.restore_secrets(&config);
                         if state.config != config {
                             panic!("Config in Zk does not match requested config!")
                         }
@@ -0,0 +1,212 @@
+//! Constraints on which workers the shards of a domain may be placed on.
+
+use crate::coordination::Capacity;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A worker that the shards of a domain may be placed on.
+crate struct Candidate<'a, W> {
+    crate worker: W,
+    crate labels: &'a HashMap<String, String>,
+    /// How large a share of all domain shards the worker should get.
+    crate weight: f64,
+    /// How many domain shards the worker already runs.
+    crate shards: usize,
+}
+
+/// How large a share of all domain shards workers with the given capacities should each get.
+///
+/// Cores and memory count equally, but memory only counts if every worker knows how much it has.
+crate fn weights(capacities: &[Capacity]) -> Vec<f64> {
+    let cores: usize = capacities.iter().map(|c| c.cores).sum();
+    let memory: usize = capacities.iter().map(|c| c.memory).sum();
+    let known = capacities.iter().all(|c| c.memory != 0);
+    capacities
+        .iter()
+        .map(|c| {
+            let share = c.cores as f64 / cores.max(1) as f64;
+            if known {
+                (share + c.memory as f64 / memory as f64) / 2.0
+            } else {
+                share
+            }
+        })
+        .collect()
+}
+
+/// Where the shards of a domain may go, given the labels that workers were started with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+crate struct Placement {
+    /// Labels that a worker must have, along with the value each must have.
+    crate labels: Vec<(String, String)>,
+    /// A label whose values the shards should be spread across.
+    crate spread: Option<String>,
+}
+
+impl Placement {
+    /// Whether a worker with `labels` meets this placement.
+    crate fn allows(&self, labels: &HashMap<String, String>) -> bool {
+        self.labels
+            .iter()
+            .all(|(label, value)| labels.get(label) == Some(value))
+    }
+
+    /// Add the constraints of `other` to this placement.
+    crate fn merge(&mut self, other: &Placement) {
+        for l in &other.labels {
+            if !self.labels.contains(l) {
+                self.labels.push(l.clone());
+            }
+        }
+        if self.spread.is_none() {
+            self.spread = other.spread.clone();
+        }
+    }
+
+    /// Pick a worker for each of `shards` shards out of `candidates`, among the workers that this
+    /// placement allows, so that each ends up with shards in proportion to its weight.
+    ///
+    /// Returns `None` if no worker is allowed.
+    crate fn choose<W: Copy>(&self, candidates: &[Candidate<W>], shards: usize) -> Option<Vec<W>> {
+        let allowed: Vec<_> = candidates.iter().filter(|c| self.allows(c.labels)).collect();
+        if allowed.is_empty() {
+            return None;
+        }
+
+        let value = |i: usize| {
+            self.spread
+                .as_ref()
+                .and_then(|label| allowed[i].labels.get(label))
+        };
+        let mut used = HashMap::new();
+        let mut running: Vec<_> = allowed.iter().map(|c| c.shards).collect();
+        let mut next = 0;
+        let chosen = (0..shards)
+            .map(|_| {
+                // the next worker in turn whose value for the spread label has been picked the
+                // fewest times so far, and which would run the fewest shards for its weight
+                let used_by = |i: usize| used.get(&value(i)).cloned().unwrap_or(0);
+                let load = |i: usize| (running[i] + 1) as f64 / allowed[i].weight;
+                let pick = (0..allowed.len())
+                    .map(|o| (next + o) % allowed.len())
+                    .min_by(|&a, &b| {
+                        used_by(a)
+                            .cmp(&used_by(b))
+                            .then(load(a).partial_cmp(&load(b)).unwrap_or(Ordering::Equal))
+                    })
+                    .unwrap();
+                *used.entry(value(pick)).or_insert(0) += 1;
+                running[pick] += 1;
+                next = pick + 1;
+                allowed[pick].worker
+            })
+            .collect();
+        Some(chosen)
+    }
+}
+
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let labels: Vec<_> = self
+            .labels
+            .iter()
+            .map(|(label, value)| format!("{}={}", label, value))
+            .collect();
+        write!(f, "[{}]", labels.join(", "))?;
+        if let Some(ref spread) = self.spread {
+            write!(f, " spread across {}", spread)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(ls: &[(&str, &str)]) -> HashMap<String, String> {
+        ls.iter()
+            .map(|&(l, v)| (l.to_owned(), v.to_owned()))
+            .collect()
+    }
+
+    fn candidates<'a>(labels: &[&'a HashMap<String, String>]) -> Vec<Candidate<'a, usize>> {
+        labels
+            .iter()
+            .enumerate()
+            .map(|(worker, &labels)| Candidate {
+                worker,
+                labels,
+                weight: 1.0,
+                shards: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_only_chooses_allowed_workers() {
+        let ssd = labels(&[("disk", "ssd"), ("zone", "a")]);
+        let hdd = labels(&[("disk", "hdd"), ("zone", "a")]);
+        let workers = candidates(&[&hdd, &ssd, &hdd]);
+
+        let placement = Placement {
+            labels: vec![("disk".to_owned(), "ssd".to_owned())],
+            spread: None,
+        };
+        assert_eq!(placement.choose(&workers, 2), Some(vec![1, 1]));
+        assert_eq!(
+            Placement::default().choose(&workers, 4),
+            Some(vec![0, 1, 2, 0])
+        );
+
+        let placement = Placement {
+            labels: vec![("disk".to_owned(), "tape".to_owned())],
+            spread: None,
+        };
+        assert_eq!(placement.choose(&workers, 1), None);
+    }
+
+    #[test]
+    fn it_spreads_shards_across_label_values() {
+        let a = labels(&[("zone", "a")]);
+        let b = labels(&[("zone", "b")]);
+        let workers = candidates(&[&a, &a, &a, &b]);
+
+        let placement = Placement {
+            labels: vec![],
+            spread: Some("zone".to_owned()),
+        };
+        assert_eq!(placement.choose(&workers, 2), Some(vec![0, 3]));
+        assert_eq!(placement.choose(&workers, 4), Some(vec![0, 3, 1, 3]));
+    }
+
+    #[test]
+    fn it_gives_bigger_workers_more_shards() {
+        let big = Capacity {
+            cores: 4,
+            memory: 0,
+        };
+        let small = Capacity {
+            cores: 2,
+            memory: 0,
+        };
+        assert_eq!(weights(&[big, small]), vec![4.0 / 6.0, 2.0 / 6.0]);
+        let small = Capacity {
+            cores: 2,
+            memory: 100,
+        };
+        assert_eq!(weights(&[big, small]), vec![4.0 / 6.0, 2.0 / 6.0]);
+
+        let none = HashMap::new();
+        let mut workers = candidates(&[&none, &none]);
+        workers[0].weight = 4.0 / 6.0;
+        workers[1].weight = 2.0 / 6.0;
+        let chosen = Placement::default().choose(&workers, 6).unwrap();
+        assert_eq!(chosen.iter().filter(|&&w| w == 0).count(), 4);
+
+        // shards that the workers already run count too
+        workers[0].shards = 4;
+        assert_eq!(Placement::default().choose(&workers, 1), Some(vec![1]));
+    }
+}
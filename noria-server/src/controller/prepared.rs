@@ -0,0 +1,346 @@
+//! Planning the statements that clients prepare once and then execute many times.
+//!
+//! A `SELECT` is executed as a lookup in the view of its query, so its `?` parameters must be
+//! compared with the columns that the view is keyed by. An `INSERT` of one row is executed as an
+//! insert, and an `UPDATE` or `DELETE` whose `WHERE` clause gives each column of the table's
+//! primary key as an update or delete of that one row. Only values may be `?` parameters.
+
+use crate::oracle::primary_key;
+use nom_sql::{
+    Column, ColumnConstraint, ConditionBase, ConditionExpression, CreateTableStatement,
+    FieldValueExpression, Literal, Operator, SqlQuery, SqlType,
+};
+use noria::prepared::{Parameter, Route, Slot, StatementPlan};
+use noria::DataType;
+
+/// The name that the query of a prepared `SELECT` is added to the recipe under.
+crate fn query_name(q: &SqlQuery) -> String {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut h = DefaultHasher::new();
+    q.hash(&mut h);
+    format!("prepared_{:x}", h.finish())
+}
+
+/// Add the columns that the `?` parameters in `cond` are compared with to `params`, in the order
+/// the parameters appear in.
+crate fn placeholders(cond: &ConditionExpression, params: &mut Vec<Column>) {
+    match *cond {
+        ConditionExpression::ComparisonOp(ref ct) => match (&*ct.left, &*ct.right) {
+            (
+                &ConditionExpression::Base(ConditionBase::Field(ref c)),
+                &ConditionExpression::Base(ConditionBase::Literal(Literal::Placeholder)),
+            )
+            | (
+                &ConditionExpression::Base(ConditionBase::Literal(Literal::Placeholder)),
+                &ConditionExpression::Base(ConditionBase::Field(ref c)),
+            ) => params.push(c.clone()),
+            _ => {}
+        },
+        ConditionExpression::LogicalOp(ref ct) => {
+            placeholders(&ct.left, params);
+            placeholders(&ct.right, params);
+        }
+        ConditionExpression::NegationOp(ref inner) | ConditionExpression::Bracketed(ref inner) => {
+            placeholders(inner, params)
+        }
+        _ => {}
+    }
+}
+
+/// Plan a lookup in `view`, which is keyed by the columns `key`, of the `SELECT` whose `?`
+/// parameters are compared with the columns `params`.
+crate fn plan_lookup(
+    view: &str,
+    key: &[(String, Option<SqlType>)],
+    params: &[Column],
+) -> Result<StatementPlan, String> {
+    let unkeyed = || format!("{} is not keyed by the parameters", view);
+    let parameters: Vec<_> = params
+        .iter()
+        .map(|c| Parameter {
+            column: c.name.clone(),
+            sql_type: key
+                .iter()
+                .find(|&&(ref name, _)| *name == c.name)
+                .and_then(|&(_, ref ty)| ty.clone()),
+        })
+        .collect();
+
+    let key = if params.is_empty() {
+        // views without parameters are keyed by a column that always holds 0
+        if key.len() != 1 {
+            return Err(unkeyed());
+        }
+        vec![Slot::Value(0.into())]
+    } else {
+        if key.len() != params.len() {
+            return Err(unkeyed());
+        }
+        let mut used = vec![false; params.len()];
+        let mut slots = Vec::new();
+        for &(ref name, _) in key {
+            let i = (0..params.len())
+                .find(|&i| !used[i] && params[i].name == *name)
+                .ok_or_else(unkeyed)?;
+            used[i] = true;
+            slots.push(Slot::Parameter(i));
+        }
+        slots
+    };
+
+    Ok(StatementPlan {
+        parameters,
+        route: Route::Lookup {
+            view: view.to_owned(),
+            key,
+        },
+    })
+}
+
+/// Plan the `INSERT`, `UPDATE`, or `DELETE` `q` of the table whose schema is `schema`.
+crate fn plan_write(q: &SqlQuery, schema: &CreateTableStatement) -> Result<StatementPlan, String> {
+    let table = schema.table.name.clone();
+    let mut parameters = Vec::new();
+    let route = match *q {
+        SqlQuery::Insert(ref iq) => {
+            if iq.data.len() != 1 {
+                return Err("only inserts of a single row can be prepared".to_owned());
+            }
+            let columns: Vec<_> = match iq.fields {
+                Some(ref fields) => fields.iter().map(|c| c.name.clone()).collect(),
+                None => schema
+                    .fields
+                    .iter()
+                    .map(|f| f.column.name.clone())
+                    .collect(),
+            };
+            if columns.len() != iq.data[0].len() {
+                return Err(format!("wrong number of values for {}", table));
+            }
+
+            // columns that are not given take their default
+            let mut row: Vec<_> = schema
+                .fields
+                .iter()
+                .map(|f| {
+                    let default = f.constraints.iter().find_map(|c| match *c {
+                        ColumnConstraint::DefaultValue(ref dv) => Some(DataType::from(dv)),
+                        _ => None,
+                    });
+                    Slot::Value(default.unwrap_or(DataType::None))
+                })
+                .collect();
+            for (column, value) in columns.iter().zip(&iq.data[0]) {
+                let i = column_index(schema, column)?;
+                row[i] = slot(schema, i, value, &mut parameters)?;
+            }
+            Route::Insert { table, row }
+        }
+        SqlQuery::Update(ref uq) => {
+            let mut set = vec![None; schema.fields.len()];
+            for &(ref column, ref value) in &uq.fields {
+                let i = column_index(schema, &column.name)?;
+                set[i] = Some(match *value {
+                    FieldValueExpression::Literal(ref l) => {
+                        slot(schema, i, &l.value, &mut parameters)?
+                    }
+                    FieldValueExpression::Arithmetic(_) => {
+                        return Err("only values can be set by prepared updates".to_owned());
+                    }
+                });
+            }
+            let key = primary_key_of(schema, uq.where_clause.as_ref(), &mut parameters)?;
+            Route::Update { table, set, key }
+        }
+        SqlQuery::Delete(ref dq) => {
+            let key = primary_key_of(schema, dq.where_clause.as_ref(), &mut parameters)?;
+            Route::Delete { table, key }
+        }
+        _ => unreachable!("only writes are planned as writes"),
+    };
+    Ok(StatementPlan { parameters, route })
+}
+
+/// The index of the column `column` of the table whose schema is `schema`.
+fn column_index(schema: &CreateTableStatement, column: &str) -> Result<usize, String> {
+    schema
+        .fields
+        .iter()
+        .position(|f| f.column.name == column)
+        .ok_or_else(|| format!("{} has no column {}", schema.table.name, column))
+}
+
+/// The slot for the value `value` of column `i`, which is a new parameter if it is `?`.
+fn slot(
+    schema: &CreateTableStatement,
+    i: usize,
+    value: &Literal,
+    parameters: &mut Vec<Parameter>,
+) -> Result<Slot, String> {
+    match *value {
+        Literal::Placeholder => {
+            let field = &schema.fields[i];
+            parameters.push(Parameter {
+                column: field.column.name.clone(),
+                sql_type: Some(field.sql_type.clone()),
+            });
+            Ok(Slot::Parameter(parameters.len() - 1))
+        }
+        Literal::Null | Literal::Integer(_) | Literal::String(_) | Literal::FixedPoint(_) => {
+            Ok(Slot::Value(value.into()))
+        }
+        _ => Err(format!("unsupported value: {}", value)),
+    }
+}
+
+/// The primary key of the row that `cond` picks out of the table whose schema is `schema`, which
+/// must compare each column of the key, and nothing else, for equality.
+fn primary_key_of(
+    schema: &CreateTableStatement,
+    cond: Option<&ConditionExpression>,
+    parameters: &mut Vec<Parameter>,
+) -> Result<Vec<Slot>, String> {
+    let unsupported = || {
+        format!(
+            "prepared writes to {} must pick out one row by its primary key",
+            schema.table.name
+        )
+    };
+    let key = primary_key(schema).ok_or_else(unsupported)?;
+
+    let mut equal = Vec::new();
+    let mut next = cond.into_iter().collect::<Vec<_>>();
+    while let Some(cond) = next.pop() {
+        match *cond {
+            ConditionExpression::LogicalOp(ref ct) if ct.operator == Operator::And => {
+                // look at the left side first, so that parameters are numbered in order
+                next.push(&ct.right);
+                next.push(&ct.left);
+            }
+            ConditionExpression::Bracketed(ref inner) => next.push(inner),
+            ConditionExpression::ComparisonOp(ref ct) if ct.operator == Operator::Equal => {
+                match (&*ct.left, &*ct.right) {
+                    (
+                        &ConditionExpression::Base(ConditionBase::Field(ref c)),
+                        &ConditionExpression::Base(ConditionBase::Literal(ref l)),
+                    ) => {
+                        let i = column_index(schema, &c.name)?;
+                        equal.push((c.name.clone(), slot(schema, i, l, parameters)?));
+                    }
+                    _ => return Err(unsupported()),
+                }
+            }
+            _ => return Err(unsupported()),
+        }
+    }
+
+    if equal.len() != key.len() {
+        return Err(unsupported());
+    }
+    key.iter()
+        .map(|k| {
+            equal
+                .iter()
+                .find(|&&(ref c, _)| c == k)
+                .map(|&(_, ref s)| s.clone())
+                .ok_or_else(unsupported)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom_sql::parser::parse_query;
+
+    fn posts() -> CreateTableStatement {
+        match parse_query(
+            "CREATE TABLE posts (id int, author int, title varchar(40) DEFAULT 'untitled', \
+             PRIMARY KEY(id));",
+        )
+        .unwrap()
+        {
+            SqlQuery::CreateTable(ct) => ct,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn it_plans_writes() {
+        let q = parse_query("INSERT INTO posts (author, id) VALUES (?, ?);").unwrap();
+        let plan = plan_write(&q, &posts()).unwrap();
+        let columns: Vec<_> = plan.parameters.iter().map(|p| &*p.column).collect();
+        assert_eq!(columns, vec!["author", "id"]);
+        assert_eq!(
+            plan.route,
+            Route::Insert {
+                table: "posts".to_owned(),
+                row: vec![
+                    Slot::Parameter(1),
+                    Slot::Parameter(0),
+                    Slot::Value("untitled".into()),
+                ],
+            }
+        );
+
+        let q = parse_query("UPDATE posts SET title = ? WHERE id = ?;").unwrap();
+        let plan = plan_write(&q, &posts()).unwrap();
+        assert_eq!(plan.parameters.len(), 2);
+        assert_eq!(
+            plan.route,
+            Route::Update {
+                table: "posts".to_owned(),
+                set: vec![None, None, Some(Slot::Parameter(0))],
+                key: vec![Slot::Parameter(1)],
+            }
+        );
+
+        let q = parse_query("DELETE FROM posts WHERE id = 3;").unwrap();
+        let plan = plan_write(&q, &posts()).unwrap();
+        assert!(plan.parameters.is_empty());
+        assert_eq!(
+            plan.route,
+            Route::Delete {
+                table: "posts".to_owned(),
+                key: vec![Slot::Value(3.into())],
+            }
+        );
+
+        // writes must pick out rows by their primary key
+        let q = parse_query("DELETE FROM posts WHERE author = ?;").unwrap();
+        assert!(plan_write(&q, &posts()).is_err());
+        let q = parse_query("DELETE FROM posts WHERE id = ? OR id = ?;").unwrap();
+        assert!(plan_write(&q, &posts()).is_err());
+    }
+
+    #[test]
+    fn it_plans_lookups() {
+        let q = parse_query("SELECT id FROM posts WHERE title = ? AND author = ?;").unwrap();
+        let mut params = Vec::new();
+        match q {
+            SqlQuery::Select(ref sq) => {
+                placeholders(sq.where_clause.as_ref().unwrap(), &mut params)
+            }
+            _ => unreachable!(),
+        }
+        let key = vec![
+            ("author".to_owned(), Some(SqlType::Int(32))),
+            ("title".to_owned(), None),
+        ];
+        let plan = plan_lookup("q", &key, &params).unwrap();
+        assert_eq!(plan.parameters[1].sql_type, Some(SqlType::Int(32)));
+        assert_eq!(
+            plan.route,
+            Route::Lookup {
+                view: "q".to_owned(),
+                key: vec![Slot::Parameter(1), Slot::Parameter(0)],
+            }
+        );
+
+        assert!(plan_lookup("q", &key[..1], &params).is_err());
+        let plan = plan_lookup("q", &[("bogokey".to_owned(), None)], &[]).unwrap();
+        assert!(plan.parameters.is_empty());
+    }
+}
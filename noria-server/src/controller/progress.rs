@@ -0,0 +1,141 @@
+//! How far the migrations that the controller applies have got.
+//!
+//! The controller applies a migration without handling any other requests in between, which can
+//! take a while when the state of new views has to be replayed. So that clients can follow a
+//! migration while it runs, its progress is kept where the external API can read it without
+//! waiting for the controller to get to the request.
+
+use noria::cluster::{MigrationProgress, MigrationStep};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct Tracked {
+    /// The migration that is running, or that ran last, and when it started.
+    current: Option<(MigrationProgress, Instant)>,
+    /// The tickets of the submitted migrations that the migration that runs next applies.
+    tickets: Vec<u64>,
+    /// The tickets of the submitted migrations that `current` applies.
+    current_tickets: Vec<u64>,
+    /// How far the migrations that applied submitted migrations got, by ticket.
+    finished: HashMap<u64, MigrationProgress>,
+}
+
+/// The progress of the migrations that the controller applies, shared with the external API.
+#[derive(Clone, Default)]
+crate struct MigrationTracker(Arc<Mutex<Tracked>>);
+
+/// The progress of a migration that has not started.
+crate fn not_started(step: MigrationStep) -> MigrationProgress {
+    MigrationProgress {
+        step,
+        nodes_added: 0,
+        domains_booted: 0,
+        domains_to_boot: 0,
+        nodes_ready: 0,
+        nodes_to_ready: 0,
+        rows_replayed: 0,
+        bytes_replayed: 0,
+        elapsed: Duration::from_secs(0),
+    }
+}
+
+impl MigrationTracker {
+    /// Run `f`, counting the migrations that it starts as applying the submitted migrations
+    /// `tickets`.
+    crate fn applying<F, T>(&self, tickets: Vec<u64>, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        self.0.lock().unwrap().tickets = tickets;
+        let r = f();
+        self.0.lock().unwrap().tickets.clear();
+        r
+    }
+
+    /// Note that a migration that adds `nodes` nodes to the graph has started.
+    crate fn start(&self, nodes: usize) {
+        let mut tracked = self.0.lock().unwrap();
+        let mut progress = not_started(MigrationStep::Planning);
+        progress.nodes_added = nodes;
+        tracked.current = Some((progress, Instant::now()));
+        tracked.current_tickets = tracked.tickets.clone();
+    }
+
+    /// Update the progress of the migration that is running.
+    crate fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut MigrationProgress),
+    {
+        if let Some((ref mut progress, _)) = self.0.lock().unwrap().current {
+            f(progress);
+        }
+    }
+
+    /// Note that the migration that is running has finished.
+    crate fn finish(&self) {
+        let mut tracked = self.0.lock().unwrap();
+        let tracked = &mut *tracked;
+        if let Some((ref mut progress, started)) = tracked.current {
+            progress.step = MigrationStep::Done;
+            progress.elapsed = started.elapsed();
+            for &ticket in &tracked.current_tickets {
+                tracked.finished.insert(ticket, progress.clone());
+            }
+        }
+    }
+
+    /// How far the migration that applies the submitted migration `ticket` has got, or, without a
+    /// ticket, the migration that is running or ran last. Returns `None` if that is not known.
+    crate fn get(&self, ticket: Option<u64>) -> Option<MigrationProgress> {
+        let tracked = self.0.lock().unwrap();
+        if let Some(ticket) = ticket {
+            if let Some(progress) = tracked.finished.get(&ticket) {
+                return Some(progress.clone());
+            }
+            if !tracked.current_tickets.contains(&ticket) {
+                return None;
+            }
+        }
+        tracked.current.as_ref().map(|&(ref progress, started)| {
+            let mut progress = progress.clone();
+            if progress.step != MigrationStep::Done {
+                progress.elapsed = started.elapsed();
+            }
+            progress
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_follows_submitted_migrations() {
+        let tracker = MigrationTracker::default();
+        assert_eq!(tracker.get(None), None);
+
+        tracker.applying(vec![3, 4], || {
+            tracker.start(5);
+            tracker.update(|p| {
+                p.step = MigrationStep::Replaying;
+                p.nodes_to_ready = 2;
+                p.nodes_ready = 1;
+            });
+            let p = tracker.get(Some(3)).unwrap();
+            assert_eq!(p.step, MigrationStep::Replaying);
+            assert_eq!(p.nodes_added, 5);
+            assert!(p.eta().is_some());
+            tracker.finish();
+        });
+        assert_eq!(tracker.get(Some(4)).unwrap().step, MigrationStep::Done);
+        assert_eq!(tracker.get(Some(5)), None);
+
+        // a migration that was not submitted does not count towards the tickets
+        tracker.start(1);
+        assert_eq!(tracker.get(None).unwrap().nodes_added, 1);
+        assert_eq!(tracker.get(Some(3)).unwrap().nodes_added, 5);
+    }
+}
@@ -0,0 +1,61 @@
+//! Named queries that estimate how many distinct values they count.
+//!
+//! A named query may use `APPROX_COUNT_DISTINCT(col)` where it would use `COUNT(DISTINCT col)` to
+//! have the count estimated from a HyperLogLog sketch of the values instead, which takes the same
+//! small amount of state per group no matter how many distinct values there are, and is merged
+//! across shards. nom-sql does not know about the function, so it is rewritten to
+//! `COUNT(DISTINCT col)` before the statement is parsed, and the query is noted as approximate.
+//! Such a query is never built on top of one that counts exactly, nor the other way around.
+
+use super::clause::{find_word, query_name};
+
+const FUNCTION: &str = "APPROX_COUNT_DISTINCT";
+
+/// Rewrite the `APPROX_COUNT_DISTINCT`s of `query` to `COUNT(DISTINCT ..)`, and return what is
+/// left of it along with the name of the query if it had any.
+pub(super) fn extract(query: &str) -> Result<(String, Option<String>), String> {
+    let at = match find_word(query, FUNCTION) {
+        Some(at) => at,
+        None => return Ok((query.to_owned(), None)),
+    };
+    let name = query_name(&query[..at])
+        .ok_or_else(|| format!("only named queries can approximate: {}", &query[..at]))?;
+
+    let mut query = query.to_owned();
+    while let Some(at) = find_word(&query, FUNCTION) {
+        let rest = query[at + FUNCTION.len()..].trim_start();
+        if !rest.starts_with('(') {
+            return Err(format!("expected {}(column) in {}", FUNCTION, name));
+        }
+        query = format!("{}COUNT(DISTINCT {}", &query[..at], rest[1..].trim_start());
+    }
+    Ok((query, Some(name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_rewrites_approximate_counts() {
+        let (q, name) = extract(
+            "QUERY visitors: SELECT page, APPROX_COUNT_DISTINCT(user) AS n FROM visits \
+             WHERE page = ? GROUP BY page;",
+        )
+        .unwrap();
+        assert_eq!(
+            q,
+            "QUERY visitors: SELECT page, COUNT(DISTINCT user) AS n FROM visits \
+             WHERE page = ? GROUP BY page;"
+        );
+        assert_eq!(name, Some("visitors".to_owned()));
+
+        let (q, _) = extract("VIEW v: SELECT approx_count_distinct (a) FROM t;").unwrap();
+        assert_eq!(q, "VIEW v: SELECT COUNT(DISTINCT a) FROM t;");
+
+        assert!(extract("SELECT APPROX_COUNT_DISTINCT(a) FROM t;").is_err());
+        assert!(extract("QUERY v: SELECT APPROX_COUNT_DISTINCT a FROM t;").is_err());
+        let q = "QUERY v: SELECT COUNT(DISTINCT a) FROM t;";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), None));
+    }
+}
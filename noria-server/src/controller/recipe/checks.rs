@@ -0,0 +1,131 @@
+//! `CHECK` constraints on the rows of base tables.
+//!
+//! Only conditions that compare one column to literal values are understood, such as `price > 0`
+//! or `status IN ('open', 'closed')`. They may be given after a column (`price int CHECK (price >
+//! 0)`), or on their own (`CONSTRAINT positive CHECK (price > 0)`).
+
+use super::clause::{self, parenthesized};
+use dataflow::ops::filter::{FilterCondition, Value};
+use dataflow::prelude::DataType;
+use nom_sql::parser as sql_parser;
+use nom_sql::{ConditionBase, ConditionExpression, Operator, SqlQuery};
+
+/// A condition that every row of a base table must meet.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct TableCheck {
+    /// The table whose rows must meet the condition.
+    pub(super) table: String,
+    /// The condition, as it was declared.
+    pub(super) text: String,
+    /// The column the condition is on.
+    pub(super) column: String,
+    /// The condition.
+    pub(super) condition: FilterCondition,
+}
+
+/// Parse the condition `text` of a `CHECK` on `table` into the column it is on, and what values
+/// of that column meet it.
+fn condition(table: &str, text: &str) -> Result<(String, FilterCondition), String> {
+    let unsupported = || format!("unsupported check: CHECK ({})", text);
+
+    // nom-sql can parse the condition as long as it is in a query
+    let q = sql_parser::parse_query(&format!("SELECT * FROM {} WHERE {};", table, text))
+        .map_err(|_| unsupported())?;
+    let ct = match q {
+        SqlQuery::Select(ref s) => match s.where_clause {
+            Some(ConditionExpression::ComparisonOp(ref ct)) => ct,
+            _ => return Err(unsupported()),
+        },
+        _ => return Err(unsupported()),
+    };
+
+    let column = match *ct.left {
+        ConditionExpression::Base(ConditionBase::Field(ref c)) => c.name.clone(),
+        _ => return Err(unsupported()),
+    };
+    let condition = match *ct.right {
+        ConditionExpression::Base(ConditionBase::LiteralList(ref ll))
+            if ct.operator == Operator::In =>
+        {
+            FilterCondition::In(ll.iter().map(DataType::from).collect())
+        }
+        ConditionExpression::Base(ConditionBase::Literal(ref l)) => match ct.operator {
+            Operator::Equal
+            | Operator::NotEqual
+            | Operator::Greater
+            | Operator::GreaterOrEqual
+            | Operator::Less
+            | Operator::LessOrEqual => {
+                FilterCondition::Comparison(ct.operator.clone(), Value::Constant(l.into()))
+            }
+            _ => return Err(unsupported()),
+        },
+        _ => return Err(unsupported()),
+    };
+    Ok((column, condition))
+}
+
+/// Cut the `CHECK` constraints out of `query` if it creates a table, and return what is left of it
+/// along with the constraints.
+pub(super) fn extract(query: &str) -> Result<(String, Vec<TableCheck>), String> {
+    let mut checks = Vec::new();
+    let query = clause::columns(query, "CHECK", |table, def, at| {
+        let (text, rest) = parenthesized(&def[at + "CHECK".len()..])
+            .ok_or_else(|| format!("unsupported check: {}", def.trim()))?;
+        let text = text.trim();
+        let (column, condition) = condition(table, text)?;
+        checks.push(TableCheck {
+            table: table.to_owned(),
+            text: text.to_owned(),
+            column,
+            condition,
+        });
+
+        let first = def.split_whitespace().next().unwrap_or("");
+        if first.eq_ignore_ascii_case("CHECK") || first.eq_ignore_ascii_case("CONSTRAINT") {
+            Ok(None)
+        } else {
+            // the check follows a column definition, which has to stay
+            Ok(Some(clause::cut(def, at, def.len() - rest.len() - at)))
+        }
+    })?;
+    Ok((query, checks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cuts_out_checks() {
+        let (q, checks) = extract(
+            "CREATE TABLE items (id int, price int CHECK (price > 0), status varchar(8), \
+             PRIMARY KEY(id), CONSTRAINT known CHECK (status IN ('open', 'closed')));",
+        )
+        .unwrap();
+        assert_eq!(
+            q,
+            "CREATE TABLE items (id int, price int, status varchar(8), PRIMARY KEY(id));"
+        );
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[0].table, "items");
+        assert_eq!(checks[0].text, "price > 0");
+        assert_eq!(checks[0].column, "price");
+        assert_eq!(
+            checks[0].condition,
+            FilterCondition::Comparison(Operator::Greater, Value::Constant(0.into()))
+        );
+        assert_eq!(checks[1].column, "status");
+        assert_eq!(
+            checks[1].condition,
+            FilterCondition::In(vec!["open".into(), "closed".into()])
+        );
+    }
+
+    #[test]
+    fn it_refuses_complicated_checks() {
+        assert!(extract("CREATE TABLE t (a int, b int, CHECK (a > 0 AND b > 0));").is_err());
+        let q = "CREATE TABLE checks (id int, PRIMARY KEY(id));";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), Vec::new()));
+    }
+}
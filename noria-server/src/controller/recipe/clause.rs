@@ -0,0 +1,306 @@
+//! The clauses that Noria adds to the SQL of a recipe.
+//!
+//! nom-sql does not know about them, so they are cut out of each statement before it is parsed,
+//! and what they declare is kept alongside the statement in the recipe. A clause either follows a
+//! column of a `CREATE TABLE` statement, like `REFERENCES`, or follows the whole statement, like
+//! `PLACE ON`, in which case it runs up to the next such clause or the end of the statement. This
+//! module finds the clauses and cuts them out; the module of each clause only parses what it says.
+
+/// The keywords that start the clauses that follow a whole statement.
+const TRAILING: &[&str] = &[
+    "ON TYPE MISMATCH",
+    "DEDUP ON",
+    "SOURCED FROM",
+    "WITH RECURSIVE",
+    "QUALIFY",
+    "WITH HINTS",
+    "WITH METADATA",
+    "PLACE ON",
+    "SPREAD ACROSS",
+];
+
+/// A clause that follows a whole statement.
+#[derive(Debug)]
+pub(super) struct Clause<'a> {
+    /// The statement up to the clause.
+    pub(super) head: &'a str,
+    /// What follows the keyword of the clause, up to the next clause or the end of the statement.
+    pub(super) body: &'a str,
+    /// The statement without the clause.
+    pub(super) rest: String,
+}
+
+pub(super) fn unquote(ident: &str) -> String {
+    ident.trim_matches(|c| c == '`' || c == '"').to_owned()
+}
+
+/// Where `word` is in `s` on its own, ignoring case.
+fn find_words(s: &str, word: &str) -> Vec<usize> {
+    let upper = s.to_ascii_uppercase();
+    let is_ident = |c: Option<char>| c.map(|c| c.is_alphanumeric() || c == '_').unwrap_or(false);
+    upper
+        .match_indices(word)
+        .map(|(i, _)| i)
+        .filter(|&i| {
+            !is_ident(upper[..i].chars().next_back())
+                && !is_ident(upper[i + word.len()..].chars().next())
+        })
+        .collect()
+}
+
+/// Find `word` in `s` on its own, ignoring case.
+pub(super) fn find_word(s: &str, word: &str) -> Option<usize> {
+    find_words(s, word).into_iter().next()
+}
+
+/// Whether `at` in `s` is outside of any parentheses or quotes.
+fn outside(s: &str, at: usize) -> bool {
+    let mut depth = 0;
+    let mut quoted = None;
+    for c in s[..at].chars() {
+        match (quoted, c) {
+            (Some(q), c) if c == q => quoted = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') | (None, '`') => quoted = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            _ => {}
+        }
+    }
+    depth == 0 && quoted.is_none()
+}
+
+/// Find `word` in `s` on its own, ignoring case, outside of any parentheses or quotes.
+fn find_keyword(s: &str, word: &str) -> Option<usize> {
+    find_words(s, word).into_iter().find(|&at| outside(s, at))
+}
+
+/// Split the column definitions and constraints of a `CREATE TABLE` statement on the commas
+/// between them.
+fn definitions(body: &str) -> Vec<&str> {
+    let mut defs = Vec::new();
+    let mut depth = 0;
+    let mut quoted = None;
+    let mut start = 0;
+    for (i, c) in body.char_indices() {
+        match (quoted, c) {
+            (Some(q), c) if c == q => quoted = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') | (None, '`') => quoted = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                defs.push(&body[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    defs.push(&body[start..]);
+    defs
+}
+
+/// Split `s` into what is between the parentheses it starts with, and what follows them.
+pub(super) fn parenthesized(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start();
+    if !s.starts_with('(') {
+        return None;
+    }
+    let mut depth = 0;
+    let mut quoted = None;
+    for (i, c) in s.char_indices() {
+        match (quoted, c) {
+            (Some(q), c) if c == q => quoted = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') | (None, '`') => quoted = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((&s[1..i], &s[i + 1..]));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// `def` without the `len` bytes starting at `at`.
+pub(super) fn cut(def: &str, at: usize, len: usize) -> String {
+    format!("{} {}", def[..at].trim_end(), def[at + len..].trim_start())
+        .trim_end()
+        .to_owned()
+}
+
+/// The name of the table that `query` creates, and where the parentheses around the definitions
+/// of its columns and constraints are, if `query` creates a table and mentions `word`.
+pub(super) fn create_table(
+    query: &str,
+    word: &str,
+) -> Result<Option<(String, usize, usize)>, String> {
+    let create = match find_word(query, "CREATE") {
+        Some(i) if find_word(query, word).is_some() => i,
+        _ => return Ok(None),
+    };
+    match query[create..].split_whitespace().nth(1) {
+        Some(w) if w.eq_ignore_ascii_case("TABLE") => {}
+        _ => return Ok(None),
+    }
+    let (open, close) = match (query.find('('), query.rfind(')')) {
+        (Some(open), Some(close)) if open < close => (open, close),
+        _ => return Ok(None),
+    };
+    let table = query[create..open]
+        .split_whitespace()
+        .nth(2)
+        .map(unquote)
+        .ok_or_else(|| format!("no table name in {}", query))?;
+    Ok(Some((table, open, close)))
+}
+
+/// The name of the query that `statement` names, if it is a named query.
+pub(super) fn query_name(statement: &str) -> Option<String> {
+    if find_word(statement, "CREATE").is_some() {
+        return None;
+    }
+    let colon = statement.find(':')?;
+    let prefix: Vec<_> = statement[..colon].split_whitespace().collect();
+    match prefix[..] {
+        [name] | [_, name]
+            if !name.eq_ignore_ascii_case("QUERY") && !name.eq_ignore_ascii_case("VIEW") =>
+        {
+            Some(name.to_owned())
+        }
+        _ => None,
+    }
+}
+
+/// Go through the column definitions and constraints of `query` if it creates a table, and put
+/// the table back together with what `f` leaves of those that mention `keyword`.
+///
+/// `f` is given the table, the definition, and where `keyword` is in it, and returns what is left
+/// of the definition, if anything.
+pub(super) fn columns<F>(query: &str, keyword: &str, mut f: F) -> Result<String, String>
+where
+    F: FnMut(&str, &str, usize) -> Result<Option<String>, String>,
+{
+    let (table, open, close) = match create_table(query, keyword)? {
+        Some(t) => t,
+        None => return Ok(query.to_owned()),
+    };
+
+    let mut kept = Vec::new();
+    for def in definitions(&query[open + 1..close]) {
+        match find_word(def, keyword) {
+            Some(at) => kept.extend(f(&table, def, at)?),
+            None => kept.push(def.to_owned()),
+        }
+    }
+    Ok(format!(
+        "{}({}){}",
+        &query[..open],
+        kept.join(","),
+        &query[close + 1..]
+    ))
+}
+
+/// Cut the clause that starts with `keyword` out of `query`, if it has one.
+pub(super) fn trailing<'a>(query: &'a str, keyword: &str) -> Option<Clause<'a>> {
+    let at = find_keyword(query, keyword)?;
+    let start = at + keyword.len();
+    let end = query.trim_end().trim_end_matches(';').len();
+    let end = TRAILING
+        .iter()
+        .filter_map(|k| find_keyword(&query[start..], k))
+        .map(|i| start + i)
+        .min()
+        .unwrap_or(end)
+        .max(start);
+
+    let head = &query[..at];
+    let tail = &query[end..];
+    let rest = if tail.is_empty() || tail.starts_with(';') {
+        format!("{}{}", head.trim_end(), tail)
+    } else {
+        format!("{} {}", head.trim_end(), tail)
+    };
+    Some(Clause {
+        head,
+        body: query[start..end].trim(),
+        rest,
+    })
+}
+
+/// Cut the clause that starts with `keyword` out of `query` if it creates a table, and return the
+/// table along with the clause, which must follow the columns of the table.
+pub(super) fn table_clause<'a>(
+    query: &'a str,
+    keyword: &str,
+) -> Result<Option<(String, Clause<'a>)>, String> {
+    let (table, _, close) = match create_table(query, keyword)? {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    match trailing(query, keyword).filter(|c| c.head.len() > close) {
+        Some(c) => Ok(Some((table, c))),
+        None => Err(format!("{} must follow the columns of {}", keyword, table)),
+    }
+}
+
+/// Cut the clause that starts with `keyword` out of `query`, and return the name of the query
+/// along with the clause. Only named queries may have such clauses.
+pub(super) fn query_clause<'a>(
+    query: &'a str,
+    keyword: &str,
+) -> Result<Option<(String, Clause<'a>)>, String> {
+    let clause = match trailing(query, keyword) {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+    let name = query_name(clause.head)
+        .ok_or_else(|| format!("only named queries can have {}: {}", keyword, clause.head))?;
+    Ok(Some((name, clause)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cuts_trailing_clauses_up_to_the_next() {
+        let c = trailing(
+            "QUERY q: SELECT a FROM t WHERE b IN ('place on') WITH HINTS sharding = none \
+             PLACE ON disk = ssd;",
+            "WITH HINTS",
+        )
+        .unwrap();
+        assert_eq!(c.head, "QUERY q: SELECT a FROM t WHERE b IN ('place on') ");
+        assert_eq!(c.body, "sharding = none");
+        assert_eq!(
+            c.rest,
+            "QUERY q: SELECT a FROM t WHERE b IN ('place on') PLACE ON disk = ssd;"
+        );
+
+        let c = trailing("QUERY q: SELECT a FROM t PLACE ON disk = ssd;", "PLACE ON").unwrap();
+        assert_eq!(c.body, "disk = ssd");
+        assert_eq!(c.rest, "QUERY q: SELECT a FROM t;");
+        assert!(trailing("QUERY q: SELECT a FROM t WHERE b = 'PLACE ON';", "PLACE ON").is_none());
+    }
+
+    #[test]
+    fn table_clauses_follow_the_columns() {
+        let (table, c) = table_clause(
+            "CREATE TABLE t (id int) DEDUP ON id BY o WINDOW 1;",
+            "DEDUP ON",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(table, "t");
+        assert_eq!(c.body, "id BY o WINDOW 1");
+        assert!(table_clause("CREATE TABLE t (id int DEDUP ON id);", "DEDUP ON").is_err());
+        assert!(table_clause("QUERY q: SELECT a FROM t;", "DEDUP ON")
+            .unwrap()
+            .is_none());
+    }
+}
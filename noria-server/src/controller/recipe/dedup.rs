@@ -0,0 +1,96 @@
+//! Base tables that ignore stream events they have already applied.
+//!
+//! A table that a stream is written to, such as by `noria::ingest`, may end its `CREATE TABLE`
+//! statement with `DEDUP ON partition BY offset WINDOW 1000` to keep, for each value of the
+//! columns after `ON`, the last 1000 values of the offset column that it has applied. Rows
+//! whose offsets it has applied, or that are older than all of those it remembers, are dropped, so
+//! a stream that is replayed after a crash is not applied twice.
+
+use super::clause::{self, find_word, unquote};
+
+/// How a base table tells the stream events it has already applied apart.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct TableDedup {
+    /// The table.
+    pub(super) table: String,
+    /// The columns that events are in order within.
+    pub(super) key: Vec<String>,
+    /// The column that holds the offset of each event.
+    pub(super) offset: String,
+    /// How many offsets are remembered for each key.
+    pub(super) window: usize,
+}
+
+/// Cut the `DEDUP ON` clause out of `query` if it creates a table, and return what is left of it
+/// along with how the table tells events apart.
+pub(super) fn extract(query: &str) -> Result<(String, Option<TableDedup>), String> {
+    let (table, clause) = match clause::table_clause(query, "DEDUP ON")? {
+        Some(c) => c,
+        None => return Ok((query.to_owned(), None)),
+    };
+    let malformed = || format!("expected DEDUP ON columns BY column WINDOW n for {}", table);
+
+    let by = find_word(clause.body, "BY").ok_or_else(malformed)?;
+    let key: Vec<_> = clause.body[..by]
+        .split(',')
+        .map(|c| unquote(c.trim()))
+        .filter(|c| !c.is_empty())
+        .collect();
+    let words: Vec<_> = clause.body[by + "BY".len()..].split_whitespace().collect();
+    let (offset, window) = match words[..] {
+        [offset, window, size] if window.eq_ignore_ascii_case("WINDOW") && !key.is_empty() => {
+            match size.parse::<usize>() {
+                Ok(w) if w > 0 => (unquote(offset), w),
+                _ => return Err(malformed()),
+            }
+        }
+        _ => return Err(malformed()),
+    };
+
+    Ok((
+        clause.rest,
+        Some(TableDedup {
+            table,
+            key,
+            offset,
+            window,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cuts_out_dedup() {
+        let (q, dedup) = extract(
+            "CREATE TABLE events (id int, part int, off bigint) DEDUP ON part BY off WINDOW 100;",
+        )
+        .unwrap();
+        assert_eq!(q, "CREATE TABLE events (id int, part int, off bigint);");
+        assert_eq!(
+            dedup,
+            Some(TableDedup {
+                table: "events".to_owned(),
+                key: vec!["part".to_owned()],
+                offset: "off".to_owned(),
+                window: 100,
+            })
+        );
+
+        let (q, _) =
+            extract("CREATE TABLE t (k int, o int) dedup on k by o window 5 PLACE ON disk = ssd;")
+                .unwrap();
+        assert_eq!(q, "CREATE TABLE t (k int, o int) PLACE ON disk = ssd;");
+
+        let (_, dedup) =
+            extract("CREATE TABLE t (a int, b int, o int) DEDUP ON a, `b` BY o WINDOW 5").unwrap();
+        assert_eq!(dedup.unwrap().key, vec!["a".to_owned(), "b".to_owned()]);
+
+        assert!(extract("CREATE TABLE t (k int, o int) DEDUP ON k BY o;").is_err());
+        assert!(extract("CREATE TABLE t (k int, o int) DEDUP ON k BY o WINDOW 0;").is_err());
+        let q = "CREATE TABLE t (id int);";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), None));
+    }
+}
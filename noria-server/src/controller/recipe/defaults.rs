@@ -0,0 +1,120 @@
+//! Defaults of base table columns that are computed when each row is inserted.
+//!
+//! nom-sql only knows about literal defaults, which are the same for every row. The defaults that
+//! are computed are `DEFAULT NOW()` (or `CURRENT_TIMESTAMP`), `DEFAULT UUID()`, and
+//! `AUTO_INCREMENT`, which takes the next value of a sequence. Since clients always give a value for
+//! every column, a column with a computed default gets it whenever a row is inserted with NULL in
+//! that column.
+
+use super::clause::{self, unquote};
+use dataflow::node::special::DefaultExpression;
+
+/// A column of a base table whose default is computed when a row is inserted.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct DefaultColumn {
+    /// The table the column is in.
+    pub(super) table: String,
+    /// The column with the default.
+    pub(super) column: String,
+    /// How the default is computed.
+    pub(super) expression: DefaultExpression,
+}
+
+/// The defaults that are understood after `DEFAULT`, longest first.
+const EXPRESSIONS: &[(&str, DefaultExpression)] = &[
+    ("CURRENT_TIMESTAMP()", DefaultExpression::Now),
+    ("CURRENT_TIMESTAMP", DefaultExpression::Now),
+    ("NOW()", DefaultExpression::Now),
+    ("UUID()", DefaultExpression::Uuid),
+];
+
+/// The column that `def` defines.
+fn column(def: &str) -> Result<String, String> {
+    def.split_whitespace()
+        .next()
+        .map(unquote)
+        .ok_or_else(|| format!("no column in {}", def))
+}
+
+/// Cut the computed defaults out of `query` if it creates a table, and return what is left of it
+/// along with the columns that have them.
+pub(super) fn extract(query: &str) -> Result<(String, Vec<DefaultColumn>), String> {
+    let mut defaults: Vec<DefaultColumn> = Vec::new();
+    let query = clause::columns(query, "AUTO_INCREMENT", |table, def, at| {
+        let def = clause::cut(def, at, "AUTO_INCREMENT".len());
+        defaults.push(DefaultColumn {
+            table: table.to_owned(),
+            column: column(&def)?,
+            expression: DefaultExpression::Sequence,
+        });
+        Ok(Some(def))
+    })?;
+    let query = clause::columns(&query, "DEFAULT", |table, def, at| {
+        let value = def[at + "DEFAULT".len()..].trim_start();
+        let upper = value.to_ascii_uppercase();
+        let found = EXPRESSIONS.iter().find(|&&(e, _)| {
+            upper.starts_with(e)
+                && upper[e.len()..]
+                    .chars()
+                    .next()
+                    .map_or(true, |c| !c.is_alphanumeric() && c != '_')
+        });
+        let (e, expression) = match found {
+            Some(&(e, expression)) => (e, expression),
+            None => return Ok(Some(def.to_owned())),
+        };
+
+        let def = clause::cut(def, at, def.len() - value.len() + e.len() - at);
+        let column = column(&def)?;
+        if defaults
+            .iter()
+            .any(|d| d.table == table && d.column == column)
+        {
+            return Err(format!("more than one default in {}", def.trim()));
+        }
+        defaults.push(DefaultColumn {
+            table: table.to_owned(),
+            column,
+            expression,
+        });
+        Ok(Some(def))
+    })?;
+    Ok((query, defaults))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cuts_out_computed_defaults() {
+        let (q, defaults) = extract(
+            "CREATE TABLE posts (id int AUTO_INCREMENT, token varchar(36) DEFAULT uuid(), \
+             at datetime DEFAULT CURRENT_TIMESTAMP, score int DEFAULT 0, PRIMARY KEY(id));",
+        )
+        .unwrap();
+        assert_eq!(
+            q,
+            "CREATE TABLE posts (id int, token varchar(36), at datetime, \
+             score int DEFAULT 0, PRIMARY KEY(id));"
+        );
+        assert_eq!(
+            defaults
+                .iter()
+                .map(|d| (&*d.column, d.expression))
+                .collect::<Vec<_>>(),
+            vec![
+                ("id", DefaultExpression::Sequence),
+                ("token", DefaultExpression::Uuid),
+                ("at", DefaultExpression::Now),
+            ]
+        );
+        assert!(defaults.iter().all(|d| d.table == "posts"));
+    }
+
+    #[test]
+    fn it_leaves_literal_defaults_alone() {
+        let q = "CREATE TABLE t (a int DEFAULT 1, b varchar(8) DEFAULT 'now()');";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), Vec::new()));
+    }
+}
@@ -0,0 +1,103 @@
+//! Columns of base tables that are computed from the other columns of each row.
+//!
+//! A column is declared as generated with `total int AS (price * qty)` or `total int GENERATED
+//! ALWAYS AS (price * qty) STORED`, and the expression may be anything that a query could project.
+//! Generated columns are always stored, since they are computed when rows are written.
+
+use super::clause::{self, find_word, parenthesized, unquote};
+use nom_sql::parser as sql_parser;
+use nom_sql::{ArithmeticExpression, FieldDefinitionExpression, FieldValueExpression, SqlQuery};
+
+/// A column of a base table that is computed from its other columns.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct GeneratedColumn {
+    /// The table the column is in.
+    pub(super) table: String,
+    /// The column that is computed.
+    pub(super) column: String,
+    /// How it is computed.
+    pub(super) expression: ArithmeticExpression,
+}
+
+/// Parse the expression `text` that a column of `table` is computed by.
+fn expression(table: &str, text: &str) -> Result<ArithmeticExpression, String> {
+    // nom-sql can parse the expression as long as it is in a query
+    match sql_parser::parse_query(&format!("SELECT {} FROM {};", text, table)) {
+        Ok(SqlQuery::Select(ref s)) if s.fields.len() == 1 => match s.fields[0] {
+            FieldDefinitionExpression::Value(FieldValueExpression::Arithmetic(ref a)) => {
+                Ok(a.clone())
+            }
+            _ => Err(format!("unsupported generated column: AS ({})", text)),
+        },
+        _ => Err(format!("unsupported generated column: AS ({})", text)),
+    }
+}
+
+/// Cut the expressions of generated columns out of `query` if it creates a table, and return what
+/// is left of it along with the columns.
+pub(super) fn extract(query: &str) -> Result<(String, Vec<GeneratedColumn>), String> {
+    let mut generated = Vec::new();
+    let query = clause::columns(query, "AS", |table, def, at| {
+        let text = match parenthesized(&def[at + "AS".len()..]) {
+            Some((text, rest))
+                if rest.trim().is_empty() || rest.trim().eq_ignore_ascii_case("STORED") =>
+            {
+                text
+            }
+            _ => return Err(format!("unsupported generated column: {}", def.trim())),
+        };
+
+        let mut head = def[..at].trim_end();
+        if let Some(g) = find_word(head, "GENERATED") {
+            head = head[..g].trim_end();
+        }
+        let column = head
+            .split_whitespace()
+            .next()
+            .map(unquote)
+            .ok_or_else(|| format!("no column in {}", def))?;
+        generated.push(GeneratedColumn {
+            table: table.to_owned(),
+            column,
+            expression: expression(table, text.trim())?,
+        });
+        Ok(Some(head.to_owned()))
+    })?;
+    Ok((query, generated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom_sql::{ArithmeticBase, ArithmeticOperator};
+
+    #[test]
+    fn it_cuts_out_generated_columns() {
+        let (q, generated) = extract(
+            "CREATE TABLE lines (id int, price int, qty int, total int AS (price * qty), \
+             taxed int GENERATED ALWAYS AS (total * 2) STORED, PRIMARY KEY(id));",
+        )
+        .unwrap();
+        assert_eq!(
+            q,
+            "CREATE TABLE lines (id int, price int, qty int, total int, taxed int, \
+             PRIMARY KEY(id));"
+        );
+        assert_eq!(generated.len(), 2);
+        assert_eq!(generated[0].table, "lines");
+        assert_eq!(generated[0].column, "total");
+        assert_eq!(generated[0].expression.op, ArithmeticOperator::Multiply);
+        match generated[0].expression.left {
+            ArithmeticBase::Column(ref c) => assert_eq!(c.name, "price"),
+            ref b => panic!("unexpected operand {:?}", b),
+        }
+        assert_eq!(generated[1].column, "taxed");
+    }
+
+    #[test]
+    fn it_refuses_virtual_columns() {
+        assert!(extract("CREATE TABLE t (a int, b int AS (a + 1) VIRTUAL);").is_err());
+        let q = "CREATE TABLE assets (id int, PRIMARY KEY(id));";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), Vec::new()));
+    }
+}
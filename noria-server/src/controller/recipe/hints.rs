@@ -0,0 +1,153 @@
+//! Hints that override the planner for a named query.
+//!
+//! A named query may end with `WITH HINTS join_order = a b c AND materialize = full AND
+//! sharding = none` to have its joins made in the order the tables are listed in, its state
+//! materialized fully or partially no matter what the planner would otherwise pick, and its nodes
+//! left unsharded. `index = ByDate author date` also makes the rows of the query a view named
+//! `ByDate` that is looked up by its `author` and `date` columns, with a reader of its own that
+//! shares the rest of the query's nodes, and may be given for as many views as the query is to be
+//! looked up by. Any subset of the hints may be given. The hints only apply to the nodes that the
+//! query adds, so a query that is hinted to join in some order is never built on top of another.
+
+use super::clause::{self, unquote};
+use crate::controller::migrate::materialization::MaterializationHint;
+
+/// The hints given for a named query.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(super) struct QueryHints {
+    /// The query that the hints are for.
+    pub(super) name: String,
+    /// The tables of the query, in the order they should be joined in.
+    pub(super) join_order: Option<Vec<String>>,
+    /// How the query's state should be materialized.
+    pub(super) materialize: Option<MaterializationHint>,
+    /// Whether the query's nodes should not be sharded.
+    pub(super) unsharded: bool,
+    /// The further views of the query's rows, and the columns that each is looked up by.
+    pub(super) indices: Vec<(String, Vec<String>)>,
+}
+
+/// Cut the `WITH HINTS` clause out of `query`, and return what is left of it along with the hints
+/// it gives.
+pub(super) fn extract(query: &str) -> Result<(String, Option<QueryHints>), String> {
+    let (name, clause) = match clause::query_clause(query, "WITH HINTS")? {
+        Some(c) => c,
+        None => return Ok((query.to_owned(), None)),
+    };
+    let unsupported = || format!("unsupported hint: {}", clause.body);
+
+    let mut hints = QueryHints {
+        name,
+        ..Default::default()
+    };
+    let spaced = clause.body.replace('=', " = ");
+    let words: Vec<_> = spaced.split_whitespace().collect();
+    for hint in words.split(|w| w.eq_ignore_ascii_case("AND")) {
+        match *hint {
+            [key, "=", ..] if key.eq_ignore_ascii_case("join_order") => {
+                let tables = &hint[2..];
+                if tables.len() < 2 {
+                    return Err(unsupported());
+                }
+                hints.join_order = Some(tables.iter().map(|t| unquote(t)).collect());
+            }
+            [key, "=", how] if key.eq_ignore_ascii_case("materialize") => {
+                hints.materialize = Some(match &*how.to_ascii_lowercase() {
+                    "full" => MaterializationHint::Full,
+                    "partial" => MaterializationHint::Partial,
+                    _ => return Err(unsupported()),
+                });
+            }
+            [key, "=", how]
+                if key.eq_ignore_ascii_case("sharding") && how.eq_ignore_ascii_case("none") =>
+            {
+                hints.unsharded = true;
+            }
+            [key, "=", ..] if key.eq_ignore_ascii_case("index") => {
+                // the name of the view, and then the columns it is looked up by
+                let index = &hint[2..];
+                if index.len() < 2 {
+                    return Err(unsupported());
+                }
+                let columns = index[1..].iter().map(|c| unquote(c)).collect();
+                hints.indices.push((unquote(index[0]), columns));
+            }
+            _ => return Err(unsupported()),
+        }
+    }
+
+    Ok((clause.rest, Some(hints)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cuts_out_hints() {
+        let (q, hints) = extract(
+            "QUERY Votes: SELECT * FROM votes, stories, users \
+             WHERE votes.story = stories.id AND votes.user = users.id \
+             WITH HINTS join_order = users votes stories AND materialize=full;",
+        )
+        .unwrap();
+        assert_eq!(
+            q,
+            "QUERY Votes: SELECT * FROM votes, stories, users \
+             WHERE votes.story = stories.id AND votes.user = users.id;"
+        );
+        assert_eq!(
+            hints,
+            Some(QueryHints {
+                name: "Votes".to_owned(),
+                join_order: Some(vec![
+                    "users".to_owned(),
+                    "votes".to_owned(),
+                    "stories".to_owned(),
+                ]),
+                materialize: Some(MaterializationHint::Full),
+                unsharded: false,
+                indices: vec![],
+            })
+        );
+
+        let (q, hints) = extract(
+            "QUERY Posts: SELECT id FROM posts WHERE id = ? \
+             WITH HINTS sharding = none PLACE ON disk = ssd;",
+        )
+        .unwrap();
+        assert_eq!(
+            q,
+            "QUERY Posts: SELECT id FROM posts WHERE id = ? PLACE ON disk = ssd;"
+        );
+        assert!(hints.unwrap().unsharded);
+
+        let (_, hints) = extract(
+            "QUERY Posts: SELECT id, author, date FROM posts WHERE id = ? \
+             WITH HINTS index = ByDate author date AND index = ByAuthor author;",
+        )
+        .unwrap();
+        assert_eq!(
+            hints.unwrap().indices,
+            vec![
+                (
+                    "ByDate".to_owned(),
+                    vec!["author".to_owned(), "date".to_owned()]
+                ),
+                ("ByAuthor".to_owned(), vec!["author".to_owned()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_refuses_bad_hints() {
+        assert!(extract("SELECT id FROM posts WITH HINTS sharding = none;").is_err());
+        assert!(extract("CREATE TABLE t (id int) WITH HINTS sharding = none;").is_err());
+        assert!(extract("QUERY p: SELECT id FROM posts WITH HINTS materialize = some;").is_err());
+        assert!(extract("QUERY p: SELECT id FROM posts WITH HINTS join_order = posts;").is_err());
+        assert!(extract("QUERY p: SELECT id FROM posts WITH HINTS sharding = none AND;").is_err());
+        assert!(extract("QUERY p: SELECT id FROM posts WITH HINTS index = ById;").is_err());
+        let q = "QUERY p: SELECT id FROM posts;";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), None));
+    }
+}
@@ -0,0 +1,262 @@
+//! Base tables declared from the queries that read them.
+//!
+//! When the controller is started with table inference enabled, queries may read from tables that
+//! the recipe does not declare. Each such table is declared with the columns that the queries refer
+//! to it by, in the order they are first referred to. A column that a query compares with a literal
+//! takes the type of that literal, and any other column is declared as `text`. Base tables
+//! accept values of any type, so a wrong guess only shows in the schema that clients are given.
+//! Unqualified columns are taken to belong to the one undeclared table that a query reads from.
+
+use nom_sql::{
+    ArithmeticBase, Column, ConditionBase, ConditionExpression, FieldDefinitionExpression,
+    FieldValueExpression, JoinConstraint, JoinRightSide, Literal, SelectStatement, SqlQuery,
+};
+use std::collections::HashMap;
+
+/// The columns of a table, along with the types they are known to have.
+type Columns = Vec<(String, Option<&'static str>)>;
+
+/// The `CREATE TABLE` statements for the tables that `queries` read from but that are not in
+/// `known`, which maps the tables and views that are known to their columns.
+pub(super) fn declarations(
+    known: &HashMap<String, Vec<String>>,
+    queries: &[&SqlQuery],
+) -> Result<Vec<String>, String> {
+    let mut tables: Vec<(String, Columns)> = Vec::new();
+    for q in queries {
+        match **q {
+            SqlQuery::Select(ref sq) => infer(known, sq, &mut tables)?,
+            SqlQuery::CompoundSelect(ref csq) => {
+                for &(_, ref sq) in &csq.selects {
+                    infer(known, sq, &mut tables)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    tables
+        .into_iter()
+        .map(|(table, columns)| {
+            if columns.is_empty() {
+                return Err(format!("cannot infer the columns of {}", table));
+            }
+            let columns: Vec<_> = columns
+                .into_iter()
+                .map(|(c, ty)| format!("{} {}", c, ty.unwrap_or("text")))
+                .collect();
+            Ok(format!("CREATE TABLE {} ({});", table, columns.join(", ")))
+        })
+        .collect()
+}
+
+/// Add the columns that `sq` refers to the tables that are not in `known` by to `tables`.
+fn infer(
+    known: &HashMap<String, Vec<String>>,
+    sq: &SelectStatement,
+    tables: &mut Vec<(String, Columns)>,
+) -> Result<(), String> {
+    let mut from: Vec<_> = sq.tables.iter().collect();
+    for jc in &sq.join {
+        match jc.right {
+            JoinRightSide::Table(ref t) => from.push(t),
+            JoinRightSide::Tables(ref ts) => from.extend(ts),
+            _ => {}
+        }
+    }
+    let undeclared: Vec<_> = from
+        .iter()
+        .filter(|t| !known.contains_key(&t.name))
+        .collect();
+    if undeclared.is_empty() {
+        return Ok(());
+    }
+    for t in &undeclared {
+        if !tables.iter().any(|&(ref name, _)| *name == t.name) {
+            tables.push((t.name.clone(), Vec::new()));
+        }
+    }
+
+    let mut columns = Vec::new();
+    let mut computed = Vec::new();
+    for field in &sq.fields {
+        match *field {
+            FieldDefinitionExpression::Col(ref c) => {
+                column(c, &mut columns);
+                computed.extend(c.alias.clone());
+            }
+            FieldDefinitionExpression::Value(FieldValueExpression::Arithmetic(ref e)) => {
+                for side in &[&e.left, &e.right] {
+                    if let ArithmeticBase::Column(ref c) = **side {
+                        column(c, &mut columns);
+                    }
+                }
+                computed.extend(e.alias.clone());
+            }
+            _ => {}
+        }
+    }
+    for jc in &sq.join {
+        match jc.constraint {
+            JoinConstraint::On(ref cond) => condition(cond, &mut columns),
+            JoinConstraint::Using(ref cs) => {
+                columns.extend(cs.iter().map(|c| (c.clone(), None)));
+            }
+        }
+    }
+    if let Some(ref cond) = sq.where_clause {
+        condition(cond, &mut columns);
+    }
+    if let Some(ref gb) = sq.group_by {
+        for c in &gb.columns {
+            column(c, &mut columns);
+        }
+        if let Some(ref cond) = gb.having {
+            condition(cond, &mut columns);
+        }
+    }
+    if let Some(ref order) = sq.order {
+        for &(ref c, _) in &order.columns {
+            column(c, &mut columns);
+        }
+    }
+
+    for (c, ty) in columns {
+        let table = match c.table {
+            Some(ref t) => from
+                .iter()
+                .find(|f| f.name == *t || f.alias.as_ref() == Some(t))
+                .map(|f| &f.name),
+            // computed columns may be referred to by their alias
+            None if computed.contains(&c.name) => None,
+            None if from
+                .iter()
+                .any(|f| known.get(&f.name).map_or(false, |cs| cs.contains(&c.name))) =>
+            {
+                None
+            }
+            None if undeclared.len() == 1 => Some(&undeclared[0].name),
+            None => {
+                return Err(format!(
+                    "cannot tell which undeclared table has column {}",
+                    c.name
+                ));
+            }
+        };
+        let declared = match table.and_then(|t| tables.iter_mut().find(|&&mut (ref n, _)| n == t)) {
+            Some(&mut (_, ref mut declared)) => declared,
+            None => continue,
+        };
+        match declared
+            .iter_mut()
+            .find(|&&mut (ref name, _)| *name == c.name)
+        {
+            Some(&mut (_, ref mut known_ty)) => {
+                // columns compared with literals of different types can only be text
+                *known_ty = match (*known_ty, ty) {
+                    (Some(a), Some(b)) if a != b => Some("text"),
+                    (a, b) => a.or(b),
+                };
+            }
+            None => declared.push((c.name.clone(), ty)),
+        }
+    }
+    Ok(())
+}
+
+/// Add the column that `c` reads to `columns`.
+fn column(c: &Column, columns: &mut Vec<(Column, Option<&'static str>)>) {
+    use nom_sql::FunctionExpression::*;
+    match c.function {
+        Some(ref f) => match **f {
+            Avg(ref fe, _)
+            | Count(ref fe, _)
+            | Sum(ref fe, _)
+            | Min(ref fe)
+            | Max(ref fe)
+            | GroupConcat(ref fe, _) => columns.push((fe.clone(), None)),
+            _ => {}
+        },
+        None => columns.push((c.clone(), None)),
+    }
+}
+
+/// Add the columns that `cond` reads to `columns`, along with the types of the literals they are
+/// compared with.
+fn condition(cond: &ConditionExpression, columns: &mut Vec<(Column, Option<&'static str>)>) {
+    match *cond {
+        ConditionExpression::ComparisonOp(ref ct) | ConditionExpression::LogicalOp(ref ct) => {
+            match (&*ct.left, &*ct.right) {
+                (
+                    &ConditionExpression::Base(ConditionBase::Field(ref c)),
+                    &ConditionExpression::Base(ConditionBase::Literal(ref l)),
+                )
+                | (
+                    &ConditionExpression::Base(ConditionBase::Literal(ref l)),
+                    &ConditionExpression::Base(ConditionBase::Field(ref c)),
+                ) => columns.push((c.clone(), literal_type(l))),
+                (
+                    &ConditionExpression::Base(ConditionBase::Field(ref c)),
+                    &ConditionExpression::Base(ConditionBase::LiteralList(ref ls)),
+                ) => columns.push((c.clone(), ls.first().and_then(literal_type))),
+                _ => {
+                    condition(&ct.left, columns);
+                    condition(&ct.right, columns);
+                }
+            }
+        }
+        ConditionExpression::NegationOp(ref inner) | ConditionExpression::Bracketed(ref inner) => {
+            condition(inner, columns)
+        }
+        ConditionExpression::Base(ConditionBase::Field(ref c)) => column(c, columns),
+        _ => {}
+    }
+}
+
+/// The type of the column that `l` is a value of, if `l` says.
+fn literal_type(l: &Literal) -> Option<&'static str> {
+    match *l {
+        Literal::Integer(_) => Some("int"),
+        Literal::FixedPoint(_) => Some("double"),
+        Literal::String(_) => Some("text"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom_sql::parser::parse_query;
+
+    #[test]
+    fn it_declares_undeclared_tables() {
+        let mut known = HashMap::new();
+        known.insert("users".to_owned(), vec!["id".to_owned(), "name".to_owned()]);
+        let q = parse_query(
+            "SELECT users.name, p.title, COUNT(votes.user) AS vc \
+             FROM users JOIN posts AS p ON (users.id = p.author) \
+             JOIN votes ON (p.id = votes.post) \
+             WHERE p.score > 1.5 AND votes.kind = 'up' GROUP BY users.name, p.title ORDER BY vc",
+        )
+        .unwrap();
+        assert_eq!(
+            declarations(&known, &[&q]).unwrap(),
+            vec![
+                "CREATE TABLE posts (title text, author text, id text, score double);",
+                "CREATE TABLE votes (user text, post text, kind text);",
+            ]
+        );
+
+        let q = parse_query("SELECT id FROM posts WHERE author = ? AND id = 3").unwrap();
+        assert_eq!(
+            declarations(&HashMap::new(), &[&q]).unwrap(),
+            vec!["CREATE TABLE posts (id int, author text);"]
+        );
+
+        // columns must say which table they are from when several are undeclared
+        let q = parse_query("SELECT title FROM posts, votes WHERE posts.id = votes.post").unwrap();
+        assert!(declarations(&HashMap::new(), &[&q]).is_err());
+        let q = parse_query("SELECT COUNT(*) FROM posts").unwrap();
+        assert!(declarations(&HashMap::new(), &[&q]).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! Base tables that are fed from views in other deployments.
+//!
+//! A `CREATE TABLE` statement may end with `SOURCED FROM view IN 'zookeeper:2181/deployment'` to
+//! have the controller keep the table in step with the view `view` of the deployment that the
+//! given ZooKeeper address leads to.
+
+use super::clause::{self, unquote};
+use crate::controller::links::ViewLink;
+
+/// Cut the `SOURCED FROM` clause out of `query` if it creates a table, and return what is left of
+/// it along with the view that the table is fed from.
+pub(super) fn extract(query: &str) -> Result<(String, Option<ViewLink>), String> {
+    let (table, clause) = match clause::table_clause(query, "SOURCED FROM")? {
+        Some(c) => c,
+        None => return Ok((query.to_owned(), None)),
+    };
+    let words: Vec<_> = clause.body.split_whitespace().collect();
+    let link = match words[..] {
+        [view, in_, upstream]
+            if in_.eq_ignore_ascii_case("IN")
+                && upstream.len() > 2
+                && upstream.starts_with('\'')
+                && upstream.ends_with('\'') =>
+        {
+            ViewLink {
+                table,
+                view: unquote(view),
+                upstream: upstream[1..upstream.len() - 1].to_owned(),
+            }
+        }
+        _ => return Err(format!("unsupported source: {}", clause.body)),
+    };
+    Ok((clause.rest, Some(link)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cuts_out_sources() {
+        let (q, link) = extract(
+            "CREATE TABLE posts (id int, PRIMARY KEY(id)) SOURCED FROM AllPosts IN 'zk:2181/main';",
+        )
+        .unwrap();
+        assert_eq!(q, "CREATE TABLE posts (id int, PRIMARY KEY(id));");
+        assert_eq!(
+            link,
+            Some(ViewLink {
+                table: "posts".to_owned(),
+                view: "AllPosts".to_owned(),
+                upstream: "zk:2181/main".to_owned(),
+            })
+        );
+
+        let (q, _) =
+            extract("CREATE TABLE t (id int) SOURCED FROM v IN 'zk:2181/a' PLACE ON disk = ssd;")
+                .unwrap();
+        assert_eq!(q, "CREATE TABLE t (id int) PLACE ON disk = ssd;");
+
+        assert!(extract("CREATE TABLE t (id int) SOURCED FROM v;").is_err());
+        assert!(extract("CREATE TABLE t (id int) SOURCED FROM v IN zk:2181;").is_err());
+        let q = "CREATE TABLE t (id int);";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), None));
+    }
+}
@@ -0,0 +1,84 @@
+//! Who owns a named query, and how it is tagged.
+//!
+//! A named query may end with `WITH METADATA owner = ads AND tags = billing hot` to say which team
+//! owns it and what it is for, so that the views that cost the most in a shared deployment can be
+//! traced back to the people who asked for them. The owner and tags are listed with the query's
+//! view in the catalog, and with every node that computes the view in statistics and graphs.
+//! Unlike hints, metadata does not change how the query is computed, so a query may be given new
+//! metadata by adding it again with different metadata, without the query being built anew.
+
+use super::clause::{self, unquote};
+
+/// The owner and tags of a named query.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(in crate::controller) struct QueryMetadata {
+    /// The query that the metadata is for.
+    pub(in crate::controller) name: String,
+    /// The owner of the query, such as the team that maintains it.
+    pub(in crate::controller) owner: Option<String>,
+    /// The tags of the query.
+    pub(in crate::controller) tags: Vec<String>,
+}
+
+/// Cut the `WITH METADATA` clause out of `query`, and return what is left of it along with the
+/// metadata it gives.
+pub(super) fn extract(query: &str) -> Result<(String, Option<QueryMetadata>), String> {
+    let (name, clause) = match clause::query_clause(query, "WITH METADATA")? {
+        Some(c) => c,
+        None => return Ok((query.to_owned(), None)),
+    };
+    let unsupported = || format!("unsupported metadata: {}", clause.body);
+
+    let mut metadata = QueryMetadata {
+        name,
+        ..Default::default()
+    };
+    let spaced = clause.body.replace('=', " = ");
+    let words: Vec<_> = spaced.split_whitespace().collect();
+    for item in words.split(|w| w.eq_ignore_ascii_case("AND")) {
+        match *item {
+            [key, "=", owner] if key.eq_ignore_ascii_case("owner") => {
+                metadata.owner = Some(unquote(owner).trim_matches('\'').to_owned());
+            }
+            [key, "=", ..] if key.eq_ignore_ascii_case("tags") && item.len() > 2 => {
+                metadata.tags = item[2..]
+                    .iter()
+                    .map(|t| unquote(t).trim_matches('\'').to_owned())
+                    .collect();
+            }
+            _ => return Err(unsupported()),
+        }
+    }
+
+    Ok((clause.rest, Some(metadata)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cuts_out_metadata() {
+        let (q, metadata) = extract(
+            "QUERY Votes: SELECT * FROM votes WHERE votes.story = ? \
+             WITH METADATA owner = 'ads' AND tags = billing hot WITH HINTS sharding = none;",
+        )
+        .unwrap();
+        assert_eq!(
+            q,
+            "QUERY Votes: SELECT * FROM votes WHERE votes.story = ? WITH HINTS sharding = none;"
+        );
+        assert_eq!(
+            metadata,
+            Some(QueryMetadata {
+                name: "Votes".to_owned(),
+                owner: Some("ads".to_owned()),
+                tags: vec!["billing".to_owned(), "hot".to_owned()],
+            })
+        );
+
+        assert!(extract("SELECT id FROM posts WITH METADATA owner = ads;").is_err());
+        assert!(extract("QUERY p: SELECT id FROM posts WITH METADATA tags =;").is_err());
+        assert!(extract("QUERY p: SELECT id FROM posts WITH METADATA cost = 3;").is_err());
+    }
+}
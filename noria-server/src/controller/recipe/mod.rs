@@ -1,22 +1,58 @@
+use crate::controller::links::ViewLink;
+use crate::controller::security::group::MembershipTable;
 use crate::controller::security::SecurityConfig;
-use crate::controller::sql::SqlIncorporator;
+use crate::controller::sql::{SavedMir, SqlIncorporator};
 use crate::controller::Migration;
+use crate::optimizer::QueryOptimizer;
 use crate::ReuseConfigType;
+use dataflow::node::special::{Check, Dedup, TypeMismatch};
+use dataflow::ops::project::{ProjectExpression, ProjectExpressionBase};
 use dataflow::ops::trigger::Trigger;
 use dataflow::ops::trigger::TriggerEvent;
 use dataflow::prelude::DataType;
 use nom_sql::parser as sql_parser;
-use nom_sql::SqlQuery;
+use nom_sql::{JoinRightSide, SelectStatement, SqlQuery, SqlType};
+use noria::cluster::QueryReuse;
 use noria::ActivationResult;
 use petgraph::graph::NodeIndex;
 
 use nom::{self, is_alphanumeric, multispace};
-use nom_sql::CreateTableStatement;
+use nom_sql::{ArithmeticBase, ColumnConstraint, CreateTableStatement, TableKey};
 use slog;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str;
+use std::sync::Arc;
 use std::vec::Vec;
 
+mod approximate;
+mod checks;
+mod clause;
+mod dedup;
+mod defaults;
+mod generated;
+mod hints;
+mod inference;
+mod links;
+mod metadata;
+mod placement;
+mod recursive;
+mod references;
+mod soft_delete;
+mod top_n;
+mod type_mismatch;
+use self::checks::TableCheck;
+use self::dedup::TableDedup;
+use self::defaults::DefaultColumn;
+use self::generated::GeneratedColumn;
+use self::hints::QueryHints;
+pub(super) use self::metadata::QueryMetadata;
+use self::placement::PlacementClause;
+use self::recursive::RecursiveQuery;
+use self::references::TableReference;
+use self::soft_delete::SoftDelete;
+use self::top_n::TopNQuery;
+use self::type_mismatch::TypePolicy;
+
 type QueryID = u64;
 
 /// Represents a Soup recipe.
@@ -31,6 +67,8 @@ crate struct Recipe {
     aliases: HashMap<String, QueryID>,
     /// Security configuration
     security_config: Option<SecurityConfig>,
+    /// What the statements of the recipe declare in clauses that nom-sql does not know about.
+    clauses: Clauses,
 
     /// Recipe revision.
     version: usize,
@@ -51,11 +89,99 @@ impl PartialEq for Recipe {
         self.expressions == other.expressions
             && self.expression_order == other.expression_order
             && self.aliases == other.aliases
+            && self.clauses == other.clauses
             && self.version == other.version
             && self.prior == other.prior
     }
 }
 
+/// What the statements of a recipe declare in the clauses that nom-sql does not know about.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Clauses {
+    /// References between base tables declared in `CREATE TABLE` statements.
+    references: Vec<TableReference>,
+    /// Conditions on the rows of base tables declared in `CREATE TABLE` statements.
+    checks: Vec<TableCheck>,
+    /// Columns of base tables declared in `CREATE TABLE` statements as computed from the others.
+    generated: Vec<GeneratedColumn>,
+    /// Columns of base tables declared in `CREATE TABLE` statements with computed defaults.
+    defaults: Vec<DefaultColumn>,
+    /// Columns of base tables declared in `CREATE TABLE` statements to mark deleted rows.
+    soft_deletes: Vec<SoftDelete>,
+    /// What base tables declared in `CREATE TABLE` statements do about values of the wrong type.
+    type_policies: Vec<TypePolicy>,
+    /// How base tables declared in `CREATE TABLE` statements tell stream events they have applied.
+    dedups: Vec<TableDedup>,
+    /// The workers that tables and named queries asked to be placed on.
+    placements: Vec<PlacementClause>,
+    /// The hints that named queries give the planner.
+    hints: Vec<QueryHints>,
+    /// The named queries that follow the edges they select.
+    recursions: Vec<RecursiveQuery>,
+    /// The named queries that estimate how many distinct values they count.
+    approximations: Vec<String>,
+    /// The named queries that keep only the first rows of each group.
+    top_n: Vec<TopNQuery>,
+    /// The owners and tags of named queries, with later entries taking precedence.
+    metadata: Vec<QueryMetadata>,
+    /// The views in other deployments that base tables declared in `CREATE TABLE` statements are
+    /// fed from.
+    links: Vec<ViewLink>,
+}
+
+impl Clauses {
+    /// Cut the clauses out of `query`, keep what they declare, and return what is left of it.
+    fn extract(&mut self, query: &str) -> Result<String, String> {
+        let (stripped, tp) = type_mismatch::extract(query)?;
+        let (stripped, dd) = dedup::extract(&stripped)?;
+        let (stripped, ls) = links::extract(&stripped)?;
+        let (stripped, rs) = recursive::extract(&stripped)?;
+        let (stripped, ax) = approximate::extract(&stripped)?;
+        let (stripped, tn) = top_n::extract(&stripped)?;
+        let (stripped, hs) = hints::extract(&stripped)?;
+        let (stripped, md) = metadata::extract(&stripped)?;
+        let (stripped, ps) = placement::extract(&stripped)?;
+        let (stripped, refs) = references::extract(&stripped)?;
+        let (stripped, cs) = checks::extract(&stripped)?;
+        let (stripped, gs) = generated::extract(&stripped)?;
+        let (stripped, ds) = defaults::extract(&stripped)?;
+        let (stripped, sd) = soft_delete::extract(&stripped)?;
+        self.references.extend(refs);
+        self.checks.extend(cs);
+        self.generated.extend(gs);
+        self.defaults.extend(ds);
+        self.soft_deletes.extend(sd);
+        self.type_policies.extend(tp);
+        self.dedups.extend(dd);
+        self.placements.extend(ps);
+        self.hints.extend(hs);
+        self.recursions.extend(rs);
+        self.approximations.extend(ax);
+        self.top_n.extend(tn);
+        self.metadata.extend(md);
+        self.links.extend(ls);
+        Ok(stripped)
+    }
+
+    /// Add what the statements of another recipe declare.
+    fn extend(&mut self, other: Clauses) {
+        self.references.extend(other.references);
+        self.checks.extend(other.checks);
+        self.generated.extend(other.generated);
+        self.defaults.extend(other.defaults);
+        self.soft_deletes.extend(other.soft_deletes);
+        self.type_policies.extend(other.type_policies);
+        self.dedups.extend(other.dedups);
+        self.placements.extend(other.placements);
+        self.hints.extend(other.hints);
+        self.recursions.extend(other.recursions);
+        self.approximations.extend(other.approximations);
+        self.top_n.extend(other.top_n);
+        self.metadata.extend(other.metadata);
+        self.links.extend(other.links);
+    }
+}
+
 #[derive(Debug)]
 pub(super) enum Schema {
     Table(CreateTableStatement),
@@ -106,6 +232,38 @@ impl Recipe {
         }
     }
 
+    /// Return where the membership of the given security group is stored.
+    pub(in crate::controller) fn membership_table(
+        &self,
+        group: &str,
+    ) -> Result<MembershipTable, String> {
+        match self.security_config {
+            Some(ref config) => match config.groups.get(group) {
+                Some(g) => g.membership_table(),
+                None => Err(format!("no such security group: {}", group)),
+            },
+            None => Err("no security configuration installed".to_owned()),
+        }
+    }
+
+    /// Record a change to the members of a security group.
+    pub(in crate::controller) fn update_group_membership(
+        &mut self,
+        group: &str,
+        uid: &DataType,
+        gid: &DataType,
+        member: bool,
+    ) {
+        if !self
+            .inc
+            .as_mut()
+            .unwrap()
+            .update_group_membership(group, uid, gid, member)
+        {
+            debug!(self.log, "membership changed for user without a universe"; "uid" => %uid);
+        }
+    }
+
     /// Return active aliases for expressions
     fn aliases(&self) -> Vec<&str> {
         self.aliases.keys().map(String::as_str).collect()
@@ -130,6 +288,7 @@ impl Recipe {
                 Some(log) => log,
             },
             security_config: None,
+            clauses: Clauses::default(),
         }
     }
 
@@ -158,6 +317,25 @@ impl Recipe {
         self.inc.as_mut().unwrap().enable_reuse(reuse_type)
     }
 
+    /// Limit the number of queries that may be installed in each security universe.
+    pub(super) fn set_universe_query_limit(&mut self, limit: Option<usize>) {
+        self.inc.as_mut().unwrap().set_universe_query_limit(limit)
+    }
+
+    /// Let `optimizer` rewrite the queries added from now on.
+    pub(super) fn set_optimizer(&mut self, optimizer: Option<Arc<dyn QueryOptimizer>>) {
+        self.inc.as_mut().unwrap().set_optimizer(optimizer)
+    }
+
+    /// Save the MIR nodes that the queries of this recipe were planned into, which clones of the
+    /// recipe share.
+    pub(super) fn save_mir(&self) -> SavedMir {
+        self.inc
+            .as_ref()
+            .map(SqlIncorporator::save_mir)
+            .unwrap_or_default()
+    }
+
     fn resolve_alias(&self, alias: &str) -> Option<&str> {
         self.aliases.get(alias).map(|ref qid| {
             let (ref internal_qn, _, _) = self.expressions[qid];
@@ -222,9 +400,11 @@ impl Recipe {
         let cleaned_recipe_text = lines.join("\n");
 
         // parse and compute differences to current recipe
-        let parsed_queries = Recipe::parse(&cleaned_recipe_text)?;
-
-        Ok(Recipe::from_queries(parsed_queries, log))
+        let (parsed_queries, clauses) = Recipe::parse(&cleaned_recipe_text)?;
+        Ok(Recipe {
+            clauses,
+            ..Recipe::from_queries(parsed_queries, log)
+        })
     }
 
     /// Creates a recipe from a set of pre-parsed `SqlQuery` structures.
@@ -278,6 +458,7 @@ impl Recipe {
             expression_order,
             aliases,
             security_config: None,
+            clauses: Clauses::default(),
             version: 0,
             prior: None,
             inc: Some(inc),
@@ -291,13 +472,15 @@ impl Recipe {
         mig: &mut Migration,
         universe_groups: HashMap<String, Vec<DataType>>,
     ) -> Result<ActivationResult, String> {
-        use crate::controller::sql::security::Multiverse;
+        use crate::controller::sql::security::{universe_query_name, Multiverse};
 
         let mut result = ActivationResult {
             new_nodes: HashMap::default(),
             removed_leaves: Vec::default(),
             expressions_added: 0,
             expressions_removed: 0,
+            migration: None,
+            reuse: HashMap::default(),
         };
 
         if self.security_config.is_some() {
@@ -318,19 +501,7 @@ impl Recipe {
             // add the universe-specific query
             // don't use query name to avoid conflict with global queries
             let (id, group) = mig.universe();
-            let new_name = if n.is_some() {
-                match group {
-                    Some(ref g) => Some(format!(
-                        "{}_{}{}",
-                        n.clone().unwrap(),
-                        g.to_string(),
-                        id.to_string()
-                    )),
-                    None => Some(format!("{}_u{}", n.clone().unwrap(), id.to_string())),
-                }
-            } else {
-                None
-            };
+            let new_name = n.as_ref().map(|n| universe_query_name(n, &id, &group));
 
             let is_leaf = if group.is_some() { false } else { is_leaf };
 
@@ -338,7 +509,7 @@ impl Recipe {
                 .inc
                 .as_mut()
                 .unwrap()
-                .add_parsed_query(q, new_name, is_leaf, mig)?;
+                .add_universe_query(q, new_name, is_leaf, mig)?;
 
             // If the user provided us with a query name, use that.
             // If not, use the name internally used by the QFP.
@@ -353,6 +524,11 @@ impl Recipe {
         Ok(result)
     }
 
+    /// Removes the security universe that `mig` operates in, and returns the leaves of its queries.
+    pub(in crate::controller) fn remove_universe(&mut self, mig: &Migration) -> Vec<NodeIndex> {
+        self.inc.as_mut().unwrap().remove_universe(mig)
+    }
+
     /// Activate the recipe by migrating the Soup data-flow graph wrapped in `mig` to the recipe.
     /// This causes all necessary changes to said graph to be applied; however, it is the caller's
     /// responsibility to call `mig.commit()` afterwards.
@@ -375,6 +551,9 @@ impl Recipe {
             removed_leaves: Vec::default(),
             expressions_added: added.len(),
             expressions_removed: removed.len(),
+            // filled in once the migration is committed
+            migration: None,
+            reuse: HashMap::default(),
         };
 
         // upgrade schema version *before* applying changes, so that new queries are correctly
@@ -424,8 +603,50 @@ impl Recipe {
         // add new queries to the Soup graph carried by `mig`, and reflect state in the
         // incorporator in `inc`. `NodeIndex`es for new nodes are collected in `new_nodes` to be
         // returned to the caller (who may use them to obtain mutators and getters)
-        for qid in added {
+        for &qid in &added {
             let (n, q, is_leaf) = self.expressions[&qid].clone();
+            let hints: Vec<_> = self
+                .clauses
+                .hints
+                .iter()
+                .filter(|h| Some(&h.name) == n.as_ref())
+                .cloned()
+                .collect();
+            for tables in hints.iter().filter_map(|h| h.join_order.clone()) {
+                self.inc
+                    .as_mut()
+                    .unwrap()
+                    .order_joins(n.as_ref().unwrap(), tables);
+            }
+            for r in self
+                .clauses
+                .recursions
+                .iter()
+                .filter(|r| Some(&r.name) == n.as_ref())
+            {
+                self.inc.as_mut().unwrap().recurse(&r.name, r.depth);
+            }
+            for name in self
+                .clauses
+                .approximations
+                .iter()
+                .filter(|&a| Some(a) == n.as_ref())
+            {
+                self.inc.as_mut().unwrap().approximate_distinct(name);
+            }
+            for t in self
+                .clauses
+                .top_n
+                .iter()
+                .filter(|t| Some(&t.name) == n.as_ref())
+            {
+                self.inc.as_mut().unwrap().keep_top_n(
+                    &t.name,
+                    t.partition.clone(),
+                    t.order.clone(),
+                    t.n,
+                );
+            }
 
             // add the query
             let qfp = self
@@ -434,6 +655,115 @@ impl Recipe {
                 .unwrap()
                 .add_parsed_query(q, n.clone(), is_leaf, mig)?;
 
+            if let SqlQuery::CreateTable(ref ctq) = self.expressions[&qid].1 {
+                for r in self
+                    .clauses
+                    .references
+                    .iter()
+                    .filter(|r| r.table == ctq.table.name)
+                {
+                    self.add_reference(mig, ctq, qfp.query_leaf, r)?;
+                }
+                Self::add_unique(mig, ctq, qfp.query_leaf)?;
+                for c in self
+                    .clauses
+                    .checks
+                    .iter()
+                    .filter(|c| c.table == ctq.table.name)
+                {
+                    Self::add_check(mig, ctq, qfp.query_leaf, c)?;
+                }
+                for g in self
+                    .clauses
+                    .generated
+                    .iter()
+                    .filter(|g| g.table == ctq.table.name)
+                {
+                    Self::add_generated(mig, ctq, qfp.query_leaf, g)?;
+                }
+                for d in self
+                    .clauses
+                    .defaults
+                    .iter()
+                    .filter(|d| d.table == ctq.table.name)
+                {
+                    let column = ctq
+                        .fields
+                        .iter()
+                        .position(|f| f.column.name == d.column)
+                        .ok_or_else(|| format!("{} has no column {}", d.table, d.column))?;
+                    mig.add_default_expression(qfp.query_leaf, column, d.expression);
+                }
+                for s in self
+                    .clauses
+                    .soft_deletes
+                    .iter()
+                    .filter(|s| s.table == ctq.table.name)
+                {
+                    let column = ctq
+                        .fields
+                        .iter()
+                        .position(|f| f.column.name == s.column)
+                        .ok_or_else(|| format!("{} has no column {}", s.table, s.column))?;
+                    mig.set_soft_delete(qfp.query_leaf, column)?;
+                    self.inc
+                        .as_mut()
+                        .unwrap()
+                        .hide_soft_deleted(&s.table, &s.column);
+                }
+                // tables refuse values of the wrong type unless they say otherwise
+                let action = self
+                    .clauses
+                    .type_policies
+                    .iter()
+                    .find(|t| t.table == ctq.table.name)
+                    .map(|t| t.action)
+                    .unwrap_or(TypeMismatch::Reject);
+                let types = ctq.fields.iter().map(|f| f.sql_type.clone()).collect();
+                mig.set_column_types(qfp.query_leaf, types, action);
+                for d in self
+                    .clauses
+                    .dedups
+                    .iter()
+                    .filter(|d| d.table == ctq.table.name)
+                {
+                    Self::add_dedup(mig, ctq, qfp.query_leaf, d)?;
+                }
+            }
+
+            let placed = match self.expressions[&qid].1 {
+                SqlQuery::CreateTable(ref ctq) => Some(&ctq.table.name),
+                _ => n.as_ref(),
+            };
+            for p in self
+                .clauses
+                .placements
+                .iter()
+                .filter(|p| Some(&p.name) == placed)
+            {
+                mig.place(qfp.query_leaf, p.placement.clone())?;
+            }
+            for (index, columns) in hints.iter().flat_map(|h| &h.indices) {
+                if self.aliases.contains_key(index) {
+                    return Err(format!("{} is already the name of a query", index));
+                }
+                let fields = mig.fields(qfp.query_leaf);
+                let missing = |c: &str| format!("{} has no column {}", n.as_ref().unwrap(), c);
+                let key = columns
+                    .iter()
+                    .map(|c| fields.iter().position(|f| f == c).ok_or_else(|| missing(c)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                mig.maintain_also(index.clone(), qfp.query_leaf, &key);
+            }
+            for h in &hints {
+                if let Some(hint) = h.materialize {
+                    mig.hint_materialization(&qfp.new_nodes, hint);
+                }
+                if h.unsharded {
+                    mig.unshard(&qfp.new_nodes);
+                }
+            }
+
             // If the user provided us with a query name, use that.
             // If not, use the name internally used by the QFP.
             let query_name = match n {
@@ -441,9 +771,30 @@ impl Recipe {
                 None => qfp.name.clone(),
             };
 
+            if let SqlQuery::Select(_) | SqlQuery::CompoundSelect(_) = self.expressions[&qid].1 {
+                let reuse = QueryReuse {
+                    reused: qfp.reused_nodes.clone(),
+                    added: qfp.new_nodes.clone(),
+                    rejected: self.inc.as_ref().unwrap().rejected_reuse().to_vec(),
+                };
+                result.reuse.insert(query_name.clone(), reuse);
+            }
             result.new_nodes.insert(query_name, qfp.query_leaf);
         }
 
+        // universes that already exist must also get the new queries, with their policies
+        // applied, just like they would have if the queries had been there when they were created.
+        if self.security_config.is_some() {
+            use crate::controller::sql::security::Multiverse;
+            for qid in added {
+                let (n, q, is_leaf) = self.expressions[&qid].clone();
+                self.inc
+                    .as_mut()
+                    .unwrap()
+                    .add_to_universes(n, q, is_leaf, mig)?;
+            }
+        }
+
         result.removed_leaves = removed
             .iter()
             .filter_map(|qid| {
@@ -479,6 +830,141 @@ impl Recipe {
         Ok(result)
     }
 
+    /// Have the base table `ni` created by `ctq` enforce the reference `r`.
+    fn add_reference(
+        &self,
+        mig: &mut Migration,
+        ctq: &CreateTableStatement,
+        ni: NodeIndex,
+        r: &TableReference,
+    ) -> Result<(), String> {
+        let column = ctq
+            .fields
+            .iter()
+            .position(|f| f.column.name == r.column)
+            .ok_or_else(|| format!("{} has no column {}", r.table, r.column))?;
+        let table = self.node_addr_for(&r.referenced)?;
+        let key = self
+            .inc
+            .as_ref()
+            .unwrap()
+            .get_base_schema(&r.referenced)
+            .and_then(|s| {
+                s.fields
+                    .iter()
+                    .position(|f| f.column.name == r.referenced_column)
+            })
+            .ok_or_else(|| format!("{} has no column {}", r.referenced, r.referenced_column))?;
+        mig.add_reference(ni, table, column, key, r.action)
+    }
+
+    /// Have the base table `ni` created by `ctq` enforce its `UNIQUE` columns.
+    fn add_unique(
+        mig: &mut Migration,
+        ctq: &CreateTableStatement,
+        ni: NodeIndex,
+    ) -> Result<(), String> {
+        let mut unique: Vec<Vec<_>> = ctq
+            .fields
+            .iter()
+            .filter(|f| f.constraints.contains(&ColumnConstraint::Unique))
+            .map(|f| vec![&f.column.name])
+            .collect();
+        for key in ctq.keys.iter().flatten() {
+            if let TableKey::UniqueKey(_, ref cols) = *key {
+                unique.push(cols.iter().map(|c| &c.name).collect());
+            }
+        }
+
+        for names in unique {
+            let cols = names
+                .into_iter()
+                .map(|name| {
+                    ctq.fields
+                        .iter()
+                        .position(|f| f.column.name == *name)
+                        .ok_or_else(|| format!("{} has no column {}", ctq.table.name, name))
+                })
+                .collect::<Result<_, _>>()?;
+            mig.add_unique(ni, cols)?;
+        }
+        Ok(())
+    }
+
+    /// Have the base table `ni` created by `ctq` enforce the check `c`.
+    fn add_check(
+        mig: &mut Migration,
+        ctq: &CreateTableStatement,
+        ni: NodeIndex,
+        c: &TableCheck,
+    ) -> Result<(), String> {
+        let column = ctq
+            .fields
+            .iter()
+            .position(|f| f.column.name == c.column)
+            .ok_or_else(|| format!("{} has no column {}", c.table, c.column))?;
+        mig.add_check(
+            ni,
+            Check {
+                text: c.text.clone(),
+                column,
+                condition: c.condition.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Have the base table `ni` created by `ctq` drop the stream events it has applied, as `d` tells
+    /// them apart.
+    fn add_dedup(
+        mig: &mut Migration,
+        ctq: &CreateTableStatement,
+        ni: NodeIndex,
+        d: &TableDedup,
+    ) -> Result<(), String> {
+        let column = |name: &str| {
+            ctq.fields
+                .iter()
+                .position(|f| f.column.name == name)
+                .ok_or_else(|| format!("{} has no column {}", d.table, name))
+        };
+        let key = d.key.iter().map(|k| column(k)).collect::<Result<_, _>>()?;
+        mig.set_dedup(
+            ni,
+            Dedup {
+                key,
+                offset: column(&d.offset)?,
+                window: d.window,
+            },
+        );
+        Ok(())
+    }
+
+    /// Have the base table `ni` created by `ctq` compute the generated column `g`.
+    fn add_generated(
+        mig: &mut Migration,
+        ctq: &CreateTableStatement,
+        ni: NodeIndex,
+        g: &GeneratedColumn,
+    ) -> Result<(), String> {
+        let column = |name: &str| {
+            ctq.fields
+                .iter()
+                .position(|f| f.column.name == name)
+                .ok_or_else(|| format!("{} has no column {}", g.table, name))
+        };
+        let operand = |b: &ArithmeticBase| match *b {
+            ArithmeticBase::Column(ref c) => column(&c.name).map(ProjectExpressionBase::Column),
+            ArithmeticBase::Scalar(ref l) => Ok(ProjectExpressionBase::Literal(l.into())),
+        };
+        let expression = ProjectExpression::new(
+            g.expression.op.clone(),
+            operand(&g.expression.left)?,
+            operand(&g.expression.right)?,
+        );
+        mig.add_generated(ni, column(&g.column)?, expression)
+    }
+
     /// Work out the delta between two recipes.
     /// Returns two sets of `QueryID` -> `SqlQuery` mappings:
     /// (1) those queries present in `self`, but not in `other`; and
@@ -509,6 +995,189 @@ impl Recipe {
             .collect()
     }
 
+    /// The names of the tables and queries that the recipe defines, and of those that its queries
+    /// and references read from.
+    pub(super) fn footprint(&self) -> (HashSet<String>, HashSet<String>) {
+        fn reads(sq: &SelectStatement, into: &mut HashSet<String>) {
+            into.extend(sq.tables.iter().map(|t| t.name.clone()));
+            for jc in &sq.join {
+                match jc.right {
+                    JoinRightSide::Table(ref t) => {
+                        into.insert(t.name.clone());
+                    }
+                    JoinRightSide::Tables(ref ts) => into.extend(ts.iter().map(|t| t.name.clone())),
+                    JoinRightSide::NestedSelect(ref sq, _) => reads(sq, into),
+                    JoinRightSide::NestedJoin(_) => {}
+                }
+            }
+        }
+
+        let mut defines = HashSet::new();
+        let mut read = HashSet::new();
+        for &(ref name, ref q, _) in self.expressions.values() {
+            match *q {
+                SqlQuery::CreateTable(ref ctq) => {
+                    defines.insert(ctq.table.name.clone());
+                }
+                SqlQuery::Select(ref sq) => reads(sq, &mut read),
+                SqlQuery::CompoundSelect(ref csq) => {
+                    for &(_, ref sq) in &csq.selects {
+                        reads(sq, &mut read);
+                    }
+                }
+                _ => {}
+            }
+            defines.extend(name.clone());
+        }
+        read.extend(self.clauses.references.iter().map(|r| r.referenced.clone()));
+        (defines, read)
+    }
+
+    /// The views in other deployments that base tables are fed from.
+    pub(super) fn links(&self) -> &[ViewLink] {
+        &self.clauses.links
+    }
+
+    /// The owners and tags of the named queries in the recipe.
+    pub(super) fn metadata(&self) -> Vec<&QueryMetadata> {
+        let mut seen = HashSet::new();
+        self.clauses
+            .metadata
+            .iter()
+            .rev()
+            .filter(|m| self.aliases.contains_key(&m.name) && seen.insert(&m.name))
+            .collect()
+    }
+
+    /// Rename the column `old` of the base table `table` to `new`, both in the table's declaration
+    /// and in the schemas of the table and of the nodes in `renamed` that pass the column on.
+    pub(super) fn rename_column(
+        &mut self,
+        table: &str,
+        old: &str,
+        new: &str,
+        renamed: &[(NodeIndex, usize)],
+    ) {
+        if let Some(qid) = self.aliases.get(table).cloned() {
+            let (name, mut q, is_leaf) = self.expressions.remove(&qid).unwrap();
+            if let SqlQuery::CreateTable(ref mut ctq) = q {
+                for cs in &mut ctq.fields {
+                    if cs.column.name == old {
+                        cs.column.name = new.to_owned();
+                    }
+                }
+            }
+
+            // expressions are filed under the hash of their text, which has now changed
+            let renamed_qid = hash_query(&q);
+            self.expressions.insert(renamed_qid, (name, q, is_leaf));
+            for id in self
+                .expression_order
+                .iter_mut()
+                .chain(self.aliases.values_mut())
+            {
+                if *id == qid {
+                    *id = renamed_qid;
+                }
+            }
+        }
+
+        if let Some(ref mut inc) = self.inc {
+            inc.rename_column(table, old, new, renamed);
+        }
+    }
+
+    /// Declare `column` of `table` with the type `ty` from now on.
+    pub(super) fn alter_column_type(&mut self, table: &str, column: &str, ty: SqlType) {
+        if let Some(qid) = self.aliases.get(table).cloned() {
+            let (name, mut q, is_leaf) = self.expressions.remove(&qid).unwrap();
+            if let SqlQuery::CreateTable(ref mut ctq) = q {
+                for cs in &mut ctq.fields {
+                    if cs.column.name == column {
+                        cs.sql_type = ty.clone();
+                    }
+                }
+            }
+
+            let altered_qid = hash_query(&q);
+            self.expressions.insert(altered_qid, (name, q, is_leaf));
+            for id in self
+                .expression_order
+                .iter_mut()
+                .chain(self.aliases.values_mut())
+            {
+                if *id == qid {
+                    *id = altered_qid;
+                }
+            }
+        }
+
+        if let Some(ref mut inc) = self.inc {
+            inc.alter_column_type(table, column, ty);
+        }
+    }
+
+    /// Whether the recipe contains `q`, under any name.
+    pub(super) fn contains(&self, q: &SqlQuery) -> bool {
+        self.expressions.contains_key(&hash_query(q))
+    }
+
+    /// The name that the recipe has `q` under, if it has `q` under a name.
+    pub(super) fn name_of(&self, q: &SqlQuery) -> Option<&str> {
+        self.expressions
+            .get(&hash_query(q))
+            .and_then(|&(ref n, _, _)| n.as_ref())
+            .map(String::as_str)
+    }
+
+    /// Declare the base tables that the queries in `text` read from, but that neither `text` nor,
+    /// unless `text` is to replace this recipe, this recipe declares, and return `text` with those
+    /// declarations added.
+    pub(super) fn with_inferred_tables(
+        &self,
+        text: &str,
+        replacing: bool,
+    ) -> Result<String, String> {
+        let added = Recipe::from_str(text, None)?;
+        let mut known = HashMap::new();
+        let recipes = if replacing {
+            vec![&added]
+        } else {
+            vec![&added, self]
+        };
+        for r in recipes {
+            for &(ref name, ref q, _) in r.expressions.values() {
+                if let SqlQuery::CreateTable(ref ct) = *q {
+                    let columns = ct.fields.iter().map(|f| f.column.name.clone()).collect();
+                    known.insert(ct.table.name.clone(), columns);
+                } else if let Some(ref name) = *name {
+                    known.entry(name.clone()).or_insert_with(Vec::new);
+                }
+            }
+            for name in r.aliases.keys() {
+                let columns = match r.schema_for(name) {
+                    Some(Schema::View(columns)) => columns,
+                    _ => Vec::new(),
+                };
+                known.entry(name.clone()).or_insert(columns);
+            }
+        }
+
+        let queries: Vec<_> = added
+            .expression_order
+            .iter()
+            .map(|qid| &added.expressions[qid].1)
+            .collect();
+        let declarations = inference::declarations(&known, &queries)?;
+        if declarations.is_empty() {
+            return Ok(text.to_owned());
+        }
+        for d in &declarations {
+            info!(self.log, "inferred base table"; "declaration" => d);
+        }
+        Ok(format!("{}\n{}", declarations.join("\n"), text))
+    }
+
     /// Append the queries in the `additions` argument to this recipe. This will attempt to parse
     /// `additions`, and if successful, will extend the recipe. No expressions are removed from the
     /// recipe; use `replace` if removal of unused expressions is desired.
@@ -534,6 +1203,7 @@ impl Recipe {
             inc: prior_inc,
             log: self.log.clone(),
             security_config: self.security_config.clone(),
+            clauses: self.clauses.clone(),
             // retain the old recipe for future reference
             prior: Some(Box::new(self)),
         };
@@ -553,11 +1223,39 @@ impl Recipe {
             );
         }
         new.aliases.extend(add_rp.aliases);
+        new.clauses.extend(add_rp.clauses);
 
         // return new recipe as replacement for self
         Ok(new)
     }
 
+    /// Remove the named queries from this recipe.
+    /// Consumes `self` and returns a replacement recipe.
+    pub(super) fn without(mut self, qnames: &[String]) -> Recipe {
+        let prior_inc = self.inc.take();
+        let mut new = Recipe {
+            expressions: self.expressions.clone(),
+            expression_order: self.expression_order.clone(),
+            aliases: self.aliases.clone(),
+            version: self.version + 1,
+            inc: prior_inc,
+            log: self.log.clone(),
+            security_config: self.security_config.clone(),
+            clauses: self.clauses.clone(),
+            prior: Some(Box::new(self)),
+        };
+
+        for qname in qnames {
+            if !new.remove_query(qname) {
+                warn!(
+                    new.log,
+                    "Call to Recipe::remove_query() failed for {}", qname
+                );
+            }
+        }
+        new
+    }
+
     /// Helper method to reparent a recipe. This is needed for the recovery logic to build
     /// recovery and original recipe (see `make_recovery`).
     pub(in crate::controller) fn set_prior(&mut self, new_prior: Recipe) {
@@ -574,7 +1272,9 @@ impl Recipe {
         self.inc = Some(new_inc);
     }
 
-    fn parse(recipe_text: &str) -> Result<Vec<(Option<String>, SqlQuery, bool)>, String> {
+    fn parse(
+        recipe_text: &str,
+    ) -> Result<(Vec<(Option<String>, SqlQuery, bool)>, Clauses), String> {
         let lines: Vec<&str> = recipe_text
             .lines()
             .filter(|l| !l.is_empty() && !l.starts_with('#'))
@@ -605,6 +1305,11 @@ impl Recipe {
             i += 1;
         }
 
+        let mut clauses = Clauses::default();
+        for q in &mut query_strings {
+            *q = clauses.extract(q)?;
+        }
+
         let parsed_queries = query_strings
             .iter()
             .map(|q| (q, query_expr(q.as_bytes())))
@@ -630,13 +1335,14 @@ impl Recipe {
             return Err("Failed to parse recipe!".to_string());
         }
 
-        Ok(parsed_queries
+        let queries = parsed_queries
             .into_iter()
             .map(|(_, t)| {
                 let pr = t.unwrap().1;
                 (pr.1, pr.2, pr.0)
             })
-            .collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        Ok((queries, clauses))
     }
 
     /// Returns the predecessor from which this `Recipe` was migrated to.
@@ -645,6 +1351,18 @@ impl Recipe {
         self.prior.as_ref().map(|p| &**p)
     }
 
+    /// Returns the predecessor of this `Recipe` that had version `version`.
+    pub(super) fn prior_at(&self, version: usize) -> Option<&Recipe> {
+        let mut prior = self.prior();
+        while let Some(r) = prior {
+            if r.version() == version {
+                break;
+            }
+            prior = r.prior();
+        }
+        prior
+    }
+
     fn remove_query(&mut self, qname: &str) -> bool {
         let qid = self.aliases.get(qname).cloned();
         if qid.is_none() {
@@ -0,0 +1,128 @@
+//! Constraints on the workers that tables and queries may be placed on.
+//!
+//! Workers are started with labels such as `zone=us-east-1a` or `disk=ssd`. A `CREATE TABLE`
+//! statement or a named query may end with `PLACE ON disk = ssd AND zone = us-east-1a` to only be
+//! placed on workers with those labels, and with `SPREAD ACROSS zone` to have its shards placed on
+//! workers with as many different values of the label as possible. The constraints apply to the
+//! domain that the table or the query's reader ends up in, when that domain is first created.
+
+use super::clause::{self, create_table, query_name, unquote};
+use crate::controller::placement::Placement;
+
+/// The placement asked for by a table or a named query.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct PlacementClause {
+    /// The table or query that is placed.
+    pub(super) name: String,
+    /// Where it may be placed.
+    pub(super) placement: Placement,
+}
+
+/// The name of the table or query that `statement` creates.
+fn statement_name(statement: &str) -> Result<String, String> {
+    if let Some((table, _, _)) = create_table(statement, "TABLE")? {
+        return Ok(table);
+    }
+    query_name(statement)
+        .ok_or_else(|| format!("only tables and named queries can be placed: {}", statement))
+}
+
+/// Parse the `label = value AND ...` that follows `PLACE ON`.
+fn labels(text: &str) -> Option<Vec<(String, String)>> {
+    let spaced = text.replace('=', " = ");
+    let words: Vec<_> = spaced.split_whitespace().collect();
+    let mut labels = Vec::new();
+    for requirement in words.split(|w| w.eq_ignore_ascii_case("AND")) {
+        match *requirement {
+            [label, "=", value] => {
+                labels.push((unquote(label), unquote(value).trim_matches('\'').to_owned()))
+            }
+            _ => return None,
+        }
+    }
+    Some(labels)
+}
+
+/// Cut the `PLACE ON` and `SPREAD ACROSS` clauses off the end of `query`, and return what is left
+/// of it along with the placement they ask for.
+pub(super) fn extract(query: &str) -> Result<(String, Option<PlacementClause>), String> {
+    let place = clause::trailing(query, "PLACE ON");
+    let rest = place.as_ref().map_or(query, |c| c.rest.as_str());
+    let spread = clause::trailing(rest, "SPREAD ACROSS");
+    if place.is_none() && spread.is_none() {
+        return Ok((query.to_owned(), None));
+    }
+
+    let mut placement = Placement::default();
+    if let Some(ref c) = place {
+        placement.labels =
+            labels(c.body).ok_or_else(|| format!("unsupported placement: PLACE ON {}", c.body))?;
+    }
+    if let Some(ref c) = spread {
+        let words: Vec<_> = c.body.split_whitespace().collect();
+        match words[..] {
+            [label] => placement.spread = Some(unquote(label)),
+            _ => return Err(format!("unsupported placement: SPREAD ACROSS {}", c.body)),
+        }
+    }
+
+    let query = spread.map_or_else(|| rest.to_owned(), |c| c.rest);
+    let clause = PlacementClause {
+        name: statement_name(&query)?,
+        placement,
+    };
+    Ok((query, Some(clause)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(l: &str, v: &str) -> (String, String) {
+        (l.to_owned(), v.to_owned())
+    }
+
+    #[test]
+    fn it_cuts_out_placements() {
+        let (q, clause) =
+            extract("CREATE TABLE posts (id int, PRIMARY KEY(id)) PLACE ON disk = ssd;").unwrap();
+        assert_eq!(q, "CREATE TABLE posts (id int, PRIMARY KEY(id));");
+        assert_eq!(
+            clause,
+            Some(PlacementClause {
+                name: "posts".to_owned(),
+                placement: Placement {
+                    labels: vec![label("disk", "ssd")],
+                    spread: None,
+                },
+            })
+        );
+
+        let (q, clause) = extract(
+            "QUERY Posts: SELECT id FROM posts WHERE id = ? \
+             SPREAD ACROSS zone PLACE ON disk=ssd AND tier = 'web';",
+        )
+        .unwrap();
+        assert_eq!(q, "QUERY Posts: SELECT id FROM posts WHERE id = ?;");
+        assert_eq!(
+            clause,
+            Some(PlacementClause {
+                name: "Posts".to_owned(),
+                placement: Placement {
+                    labels: vec![label("disk", "ssd"), label("tier", "web")],
+                    spread: Some("zone".to_owned()),
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn it_refuses_bad_placements() {
+        assert!(extract("SELECT id FROM posts PLACE ON disk = ssd;").is_err());
+        assert!(extract("QUERY p: SELECT id FROM posts PLACE ON disk;").is_err());
+        assert!(extract("QUERY p: SELECT id FROM posts PLACE ON disk = ssd AND;").is_err());
+        assert!(extract("QUERY p: SELECT id FROM posts SPREAD ACROSS zone disk;").is_err());
+        let q = "QUERY p: SELECT id FROM posts;";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), None));
+    }
+}
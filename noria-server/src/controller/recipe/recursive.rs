@@ -0,0 +1,72 @@
+//! Named queries that follow the edges they select for a bounded number of steps.
+//!
+//! A named query that selects two columns may end with `WITH RECURSIVE DEPTH 3` to treat each of
+//! its rows as an edge from the first column to the second, and to return, for every node that
+//! edges start from, each node it reaches in at most three steps, along with the fewest steps it
+//! takes in a third column, `depth`. This is what a `WITH RECURSIVE` query that feeds the paths it
+//! has found back in until no path gets longer than the limit computes, such as followers of
+//! followers.
+
+use super::clause;
+
+/// A named query that follows the edges it selects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct RecursiveQuery {
+    /// The query.
+    pub(super) name: String,
+    /// How many steps the edges are followed for.
+    pub(super) depth: usize,
+}
+
+/// Cut the `WITH RECURSIVE DEPTH` clause out of `query`, and return what is left of it along with
+/// how far the query follows its edges.
+pub(super) fn extract(query: &str) -> Result<(String, Option<RecursiveQuery>), String> {
+    let (name, clause) = match clause::query_clause(query, "WITH RECURSIVE")? {
+        Some(c) => c,
+        None => return Ok((query.to_owned(), None)),
+    };
+    let words: Vec<_> = clause.body.split_whitespace().collect();
+    let depth = match words[..] {
+        [keyword, depth] if keyword.eq_ignore_ascii_case("DEPTH") => depth.parse::<usize>().ok(),
+        _ => None,
+    };
+    match depth {
+        Some(depth) if depth > 0 => Ok((clause.rest, Some(RecursiveQuery { name, depth }))),
+        _ => Err(format!("expected WITH RECURSIVE DEPTH n for {}", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cuts_out_recursion() {
+        let (q, recursive) =
+            extract("VIEW fof: SELECT follower, followee FROM follows WITH RECURSIVE DEPTH 2;")
+                .unwrap();
+        assert_eq!(q, "VIEW fof: SELECT follower, followee FROM follows;");
+        assert_eq!(
+            recursive,
+            Some(RecursiveQuery {
+                name: "fof".to_owned(),
+                depth: 2,
+            })
+        );
+
+        let (q, _) = extract(
+            "QUERY reach: SELECT src, dst FROM links with recursive depth 4 PLACE ON disk = ssd;",
+        )
+        .unwrap();
+        assert_eq!(
+            q,
+            "QUERY reach: SELECT src, dst FROM links PLACE ON disk = ssd;"
+        );
+
+        assert!(extract("SELECT src, dst FROM links WITH RECURSIVE DEPTH 2;").is_err());
+        assert!(extract("QUERY r: SELECT src, dst FROM links WITH RECURSIVE DEPTH 0;").is_err());
+        assert!(extract("QUERY r: SELECT src, dst FROM links WITH RECURSIVE 2;").is_err());
+        let q = "QUERY r: SELECT src, dst FROM links;";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), None));
+    }
+}
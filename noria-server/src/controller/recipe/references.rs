@@ -0,0 +1,108 @@
+//! References from a column of one base table to the primary key of another.
+//!
+//! Both the column form (`user_id int REFERENCES users(id)`) and the table constraint form
+//! (`FOREIGN KEY (user_id) REFERENCES users(id)`) are understood, and either may end in `ON DELETE
+//! RESTRICT`, `ON DELETE SET NULL`, or `ON DELETE CASCADE`.
+
+use super::clause::{self, find_word, unquote};
+use dataflow::node::special::ReferenceAction;
+
+/// A column of a base table that refers to the primary key of another base table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct TableReference {
+    /// The table that refers to the other one.
+    pub(super) table: String,
+    /// The column that holds the key of the row it refers to.
+    pub(super) column: String,
+    /// The table that is referred to.
+    pub(super) referenced: String,
+    /// The primary key column of the table that is referred to.
+    pub(super) referenced_column: String,
+    /// What to do about writes that would leave the reference dangling.
+    pub(super) action: ReferenceAction,
+}
+
+/// Parse what follows `REFERENCES` in `clause`.
+fn target(clause: &str) -> Result<(String, String, ReferenceAction), String> {
+    let spaced = clause.replace('(', " ( ").replace(')', " ) ");
+    let words: Vec<_> = spaced.split_whitespace().collect();
+    let upper: Vec<_> = words.iter().map(|w| w.to_ascii_uppercase()).collect();
+    let upper: Vec<_> = upper.iter().map(String::as_str).collect();
+    let action = match &upper[..] {
+        [_, "(", _, ")"] | [_, "(", _, ")", "ON", "DELETE", "RESTRICT"] => ReferenceAction::Reject,
+        [_, "(", _, ")", "ON", "DELETE", "SET", "NULL"] => ReferenceAction::Nullify,
+        [_, "(", _, ")", "ON", "DELETE", "CASCADE"] => ReferenceAction::Cascade,
+        _ => return Err(format!("unsupported reference: REFERENCES{}", clause)),
+    };
+    Ok((unquote(words[0]), unquote(words[2]), action))
+}
+
+/// Cut the references out of `query` if it creates a table, and return what is left of it along
+/// with the references.
+pub(super) fn extract(query: &str) -> Result<(String, Vec<TableReference>), String> {
+    let mut references = Vec::new();
+    let query = clause::columns(query, "REFERENCES", |table, def, at| {
+        let (referenced, referenced_column, action) = target(&def[at + "REFERENCES".len()..])?;
+
+        let constraint = find_word(def, "FOREIGN") == Some(def.len() - def.trim_start().len());
+        let (column, kept) = if constraint {
+            // a table constraint, which goes away entirely
+            let column = def[..at]
+                .split(|c| c == '(' || c == ')')
+                .nth(1)
+                .map(|c| unquote(c.trim()))
+                .ok_or_else(|| format!("no column in {}", def))?;
+            (column, None)
+        } else {
+            let column = def
+                .split_whitespace()
+                .next()
+                .map(unquote)
+                .ok_or_else(|| format!("no column in {}", def))?;
+            (column, Some(def[..at].trim_end().to_owned()))
+        };
+        references.push(TableReference {
+            table: table.to_owned(),
+            column,
+            referenced,
+            referenced_column,
+            action,
+        });
+        Ok(kept)
+    })?;
+    Ok((query, references))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cuts_out_references() {
+        let (q, refs) = extract(
+            "CREATE TABLE orders (id int, user_id int REFERENCES users(id) ON DELETE CASCADE, \
+             item_id int, PRIMARY KEY(id), FOREIGN KEY (item_id) REFERENCES `items` (`id`));",
+        )
+        .unwrap();
+        assert_eq!(
+            q,
+            "CREATE TABLE orders (id int, user_id int, item_id int, PRIMARY KEY(id));"
+        );
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0].table, "orders");
+        assert_eq!(refs[0].column, "user_id");
+        assert_eq!(refs[0].referenced, "users");
+        assert_eq!(refs[0].referenced_column, "id");
+        assert_eq!(refs[0].action, ReferenceAction::Cascade);
+        assert_eq!(refs[1].column, "item_id");
+        assert_eq!(refs[1].referenced, "items");
+        assert_eq!(refs[1].action, ReferenceAction::Reject);
+    }
+
+    #[test]
+    fn it_leaves_other_queries_alone() {
+        let q = "SELECT * FROM references_users WHERE id = ?;";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), Vec::new()));
+        assert!(extract("CREATE TABLE t (a int REFERENCES u(id) ON UPDATE CASCADE);").is_err());
+    }
+}
@@ -0,0 +1,69 @@
+//! Base tables that keep the rows that are deleted from them.
+//!
+//! A column of a base table is declared to mark deleted rows with `deleted_at datetime SOFT
+//! DELETE`. Deleting a row from such a table sets the column to the time of the delete instead,
+//! and every query that reads from the table only sees the rows where the column is NULL, unless it
+//! has a condition on the column of its own.
+
+use super::clause::{self, unquote};
+
+/// A column of a base table that marks the rows that have been deleted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct SoftDelete {
+    /// The table that keeps deleted rows.
+    pub(super) table: String,
+    /// The column that marks them.
+    pub(super) column: String,
+}
+
+/// Cut the `SOFT DELETE` marker out of `query` if it creates a table, and return what is left of
+/// it along with the column that was marked.
+pub(super) fn extract(query: &str) -> Result<(String, Option<SoftDelete>), String> {
+    let mut soft_delete = None;
+    let query = clause::columns(query, "SOFT DELETE", |table, def, at| {
+        if soft_delete.is_some() {
+            return Err(format!("{} has more than one SOFT DELETE column", table));
+        }
+
+        let def = clause::cut(def, at, "SOFT DELETE".len());
+        let column = def
+            .split_whitespace()
+            .next()
+            .map(unquote)
+            .ok_or_else(|| format!("no column in {}", def))?;
+        soft_delete = Some(SoftDelete {
+            table: table.to_owned(),
+            column,
+        });
+        Ok(Some(def))
+    })?;
+    Ok((query, soft_delete))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cuts_out_soft_delete() {
+        let (q, soft_delete) = extract(
+            "CREATE TABLE posts (id int, deleted_at datetime SOFT DELETE, PRIMARY KEY(id));",
+        )
+        .unwrap();
+        assert_eq!(
+            q,
+            "CREATE TABLE posts (id int, deleted_at datetime, PRIMARY KEY(id));"
+        );
+        assert_eq!(
+            soft_delete,
+            Some(SoftDelete {
+                table: "posts".to_owned(),
+                column: "deleted_at".to_owned(),
+            })
+        );
+
+        assert!(extract("CREATE TABLE t (a int SOFT DELETE, b int SOFT DELETE);").is_err());
+        let q = "CREATE TABLE soft (id int, PRIMARY KEY(id));";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), None));
+    }
+}
@@ -0,0 +1,168 @@
+//! Named queries that keep only the first few rows of each group.
+//!
+//! A named query may end with
+//! `QUALIFY ROW_NUMBER() OVER (PARTITION BY story ORDER BY ts DESC) <= 3` to keep, for each value
+//! of the columns it partitions by, only the three rows that come first in the given order, such
+//! as the three most recent comments on every story. This is the filter on a window function that
+//! SQL builds feeds with, and it is answered by a single top-k operator that is grouped by the
+//! partition. The columns that the clause names must be selected by the query.
+
+use super::clause::{self, find_word};
+use nom_sql::OrderType;
+
+/// A named query that keeps the first rows of each group.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct TopNQuery {
+    /// The query.
+    pub(super) name: String,
+    /// The columns whose values tell the groups apart.
+    pub(super) partition: Vec<String>,
+    /// The columns that rows are ordered by within their group.
+    pub(super) order: Vec<(String, OrderType)>,
+    /// How many rows of each group are kept.
+    pub(super) n: usize,
+}
+
+/// The columns listed, separated by commas, in `s`.
+fn columns(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|c| c.trim().to_owned())
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+/// Cut the `QUALIFY ROW_NUMBER() OVER (..) <= n` clause out of `query`, and return what is left of
+/// it along with which rows of each group the query keeps.
+pub(super) fn extract(query: &str) -> Result<(String, Option<TopNQuery>), String> {
+    let (name, clause) = match clause::query_clause(query, "QUALIFY")? {
+        Some(c) => c,
+        None => return Ok((query.to_owned(), None)),
+    };
+    let malformed = || {
+        format!(
+            "expected QUALIFY ROW_NUMBER() OVER (PARTITION BY .. ORDER BY ..) <= n for {}",
+            name
+        )
+    };
+
+    // the tokens up to the window, which must come in this order
+    let mut rest = clause.body;
+    for token in &["ROW_NUMBER", "(", ")", "OVER", "("] {
+        rest = rest.trim_start();
+        match rest.get(..token.len()) {
+            Some(t) if t.eq_ignore_ascii_case(token) => rest = &rest[token.len()..],
+            _ => return Err(malformed()),
+        }
+    }
+    let end = rest.find(')').ok_or_else(malformed)?;
+    let (window, rest) = (&rest[..end], &rest[end + 1..]);
+
+    let partition = find_word(window, "PARTITION BY").ok_or_else(malformed)?;
+    let order = find_word(window, "ORDER BY").ok_or_else(malformed)?;
+    if order < partition {
+        return Err(malformed());
+    }
+    let partition = columns(&window[partition + "PARTITION BY".len()..order]);
+    let order = columns(&window[order + "ORDER BY".len()..])
+        .into_iter()
+        .map(|c| {
+            let mut words = c.split_whitespace();
+            let column = words.next().unwrap().to_owned();
+            match words.next().map(|w| w.to_ascii_uppercase()) {
+                None => Ok((column, OrderType::OrderAscending)),
+                Some(ref w) if w == "ASC" => Ok((column, OrderType::OrderAscending)),
+                Some(ref w) if w == "DESC" => Ok((column, OrderType::OrderDescending)),
+                Some(_) => Err(malformed()),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if partition.is_empty() || order.is_empty() {
+        return Err(malformed());
+    }
+
+    // `<= n` keeps n rows, and `< n` one fewer
+    let rest = rest.trim_start();
+    let (inclusive, rest) = if rest.starts_with("<=") {
+        (true, &rest[2..])
+    } else if rest.starts_with('<') {
+        (false, &rest[1..])
+    } else {
+        return Err(malformed());
+    };
+    let rest = rest.trim_start();
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| rest.len());
+    let n = match rest[..end].parse::<usize>() {
+        Ok(n) if inclusive && n > 0 && rest[end..].is_empty() => n,
+        Ok(n) if !inclusive && n > 1 && rest[end..].is_empty() => n - 1,
+        _ => return Err(malformed()),
+    };
+
+    Ok((
+        clause.rest,
+        Some(TopNQuery {
+            name,
+            partition,
+            order,
+            n,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cuts_out_row_limits() {
+        let (q, top) = extract(
+            "VIEW recent: SELECT story, id, ts FROM comments \
+             QUALIFY ROW_NUMBER() OVER (PARTITION BY story ORDER BY ts DESC, id) <= 3;",
+        )
+        .unwrap();
+        assert_eq!(q, "VIEW recent: SELECT story, id, ts FROM comments;");
+        assert_eq!(
+            top,
+            Some(TopNQuery {
+                name: "recent".to_owned(),
+                partition: vec!["story".to_owned()],
+                order: vec![
+                    ("ts".to_owned(), OrderType::OrderDescending),
+                    ("id".to_owned(), OrderType::OrderAscending),
+                ],
+                n: 3,
+            })
+        );
+
+        let (q, top) = extract(
+            "VIEW top: SELECT a, b, c FROM t qualify row_number() over \
+             (partition by a, b order by c asc) < 2 PLACE ON disk = ssd;",
+        )
+        .unwrap();
+        assert_eq!(q, "VIEW top: SELECT a, b, c FROM t PLACE ON disk = ssd;");
+        let top = top.unwrap();
+        assert_eq!(top.partition, vec!["a".to_owned(), "b".to_owned()]);
+        assert_eq!(top.n, 1);
+
+        assert!(extract("SELECT a FROM t QUALIFY ROW_NUMBER() OVER (ORDER BY a) <= 1;").is_err());
+        let window = "(PARTITION BY a ORDER BY b)";
+        for bad in &[
+            "QUALIFY RANK() OVER (PARTITION BY a ORDER BY b) <= 1",
+            "QUALIFY ROW_NUMBER() OVER (PARTITION BY a) <= 1",
+            "QUALIFY ROW_NUMBER() OVER (ORDER BY b) <= 1",
+            "QUALIFY ROW_NUMBER() OVER (PARTITION BY a ORDER BY b SIDEWAYS) <= 1",
+        ] {
+            assert!(extract(&format!("VIEW v: SELECT a, b FROM t {};", bad)).is_err());
+        }
+        for bad in &["<= 0", "< 1", "= 1", ">= 2"] {
+            let q = format!(
+                "VIEW v: SELECT a, b FROM t QUALIFY ROW_NUMBER() OVER {} {};",
+                window, bad
+            );
+            assert!(extract(&q).is_err());
+        }
+        let q = "VIEW v: SELECT a, b FROM t;";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), None));
+    }
+}
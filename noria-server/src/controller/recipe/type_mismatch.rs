@@ -0,0 +1,63 @@
+//! What base tables do about writes with values that do not have the types of their columns.
+//!
+//! Base tables refuse operations with such values by default, as they would with `ON TYPE MISMATCH
+//! REJECT` at the end of their `CREATE TABLE` statement. A table may end with `ON TYPE MISMATCH
+//! COERCE` instead to have it convert them to the declared types, or with `ON TYPE MISMATCH LOG`
+//! to have it take them as they are, but log each one.
+
+use super::clause;
+use dataflow::node::special::TypeMismatch;
+
+/// What a base table does about values of the wrong type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct TypePolicy {
+    /// The table.
+    pub(super) table: String,
+    /// What it does about them.
+    pub(super) action: TypeMismatch,
+}
+
+/// Cut the `ON TYPE MISMATCH` clause out of `query` if it creates a table, and return what is left
+/// of it along with what the table does about values of the wrong type.
+pub(super) fn extract(query: &str) -> Result<(String, Option<TypePolicy>), String> {
+    let (table, clause) = match clause::table_clause(query, "ON TYPE MISMATCH")? {
+        Some(c) => c,
+        None => return Ok((query.to_owned(), None)),
+    };
+    let action = match &*clause.body.to_ascii_uppercase() {
+        "REJECT" => TypeMismatch::Reject,
+        "COERCE" => TypeMismatch::Coerce,
+        "LOG" => TypeMismatch::Log,
+        _ => return Err(format!("unsupported type mismatch action: {}", clause.body)),
+    };
+    Ok((clause.rest, Some(TypePolicy { table, action })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cuts_out_type_mismatch() {
+        let (q, policy) =
+            extract("CREATE TABLE posts (id int, PRIMARY KEY(id)) ON TYPE MISMATCH coerce;")
+                .unwrap();
+        assert_eq!(q, "CREATE TABLE posts (id int, PRIMARY KEY(id));");
+        assert_eq!(
+            policy,
+            Some(TypePolicy {
+                table: "posts".to_owned(),
+                action: TypeMismatch::Coerce,
+            })
+        );
+
+        let (q, _) =
+            extract("CREATE TABLE t (id int) ON TYPE MISMATCH REJECT PLACE ON disk = ssd;")
+                .unwrap();
+        assert_eq!(q, "CREATE TABLE t (id int) PLACE ON disk = ssd;");
+
+        assert!(extract("CREATE TABLE t (id int) ON TYPE MISMATCH IGNORE;").is_err());
+        let q = "CREATE TABLE t (id int);";
+        assert_eq!(extract(q).unwrap(), (q.to_owned(), None));
+    }
+}
@@ -0,0 +1,168 @@
+//! Migrations that clients submit to be applied in the background.
+//!
+//! Submitted recipe extensions are queued, and whenever the controller gets to the queue it takes
+//! as many of them as it can apply in one go: the oldest one, and every later one that does not
+//! conflict with any migration that is queued ahead of it. Two migrations conflict if one
+//! defines a table or query that the other also defines or reads from, since then the order they
+//! are applied in matters. The migrations that are taken are applied as one extension of the
+//! recipe, so their new nodes are planned, placed, and set up with the domains together. If that
+//! fails, they are applied one at a time, so that one migration that fails does not fail the
+//! others.
+
+use crate::controller::recipe::Recipe;
+use noria::cluster::SubmittedMigration;
+use noria::ActivationResult;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// A migration that is waiting in the queue.
+pub(super) struct Queued {
+    pub(super) ticket: u64,
+    pub(super) text: String,
+    defines: HashSet<String>,
+    reads: HashSet<String>,
+}
+
+impl Queued {
+    fn conflicts_with(&self, other: &Queued) -> bool {
+        !self.defines.is_disjoint(&other.defines)
+            || !self.defines.is_disjoint(&other.reads)
+            || !self.reads.is_disjoint(&other.defines)
+    }
+}
+
+#[derive(Default)]
+pub(super) struct MigrationQueue {
+    next_ticket: u64,
+    queued: VecDeque<Queued>,
+    /// How the migrations that have left the queue fared, by ticket.
+    finished: HashMap<u64, SubmittedMigration>,
+    /// Whether the controller has already been asked to get to the queue.
+    woken: bool,
+}
+
+impl MigrationQueue {
+    /// Queue the recipe extension `text`, and return its ticket.
+    pub(super) fn submit(&mut self, text: String) -> Result<u64, String> {
+        let (defines, reads) = Recipe::from_str(&text, None)
+            .map_err(|e| format!("failed to parse migration: {}", e))?
+            .footprint();
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.queued.push_back(Queued {
+            ticket,
+            text,
+            defines,
+            reads,
+        });
+        Ok(ticket)
+    }
+
+    /// How far the migration with the given ticket has got.
+    pub(super) fn status(&self, ticket: u64) -> Option<SubmittedMigration> {
+        if let Some(i) = self.queued.iter().position(|q| q.ticket == ticket) {
+            return Some(SubmittedMigration::Queued(i));
+        }
+        self.finished.get(&ticket).cloned()
+    }
+
+    /// The tickets of the migrations that are still queued, oldest first.
+    pub(super) fn tickets(&self) -> Vec<u64> {
+        self.queued.iter().map(|q| q.ticket).collect()
+    }
+
+    /// Whether there are queued migrations that the controller has not yet been asked to get to.
+    /// Asking again is left to the caller.
+    pub(super) fn wake(&mut self) -> bool {
+        if self.queued.is_empty() || self.woken {
+            return false;
+        }
+        self.woken = true;
+        true
+    }
+
+    /// Leave the queue until the controller is next asked to get to it.
+    pub(super) fn defer(&mut self) {
+        self.woken = false;
+    }
+
+    /// Take the migrations that can be applied in one go next.
+    pub(super) fn next_batch(&mut self) -> Vec<Queued> {
+        self.woken = false;
+        let mut batch = Vec::new();
+        let mut rest = VecDeque::new();
+        for q in self.queued.drain(..) {
+            if batch
+                .iter()
+                .chain(&rest)
+                .all(|ahead| !q.conflicts_with(ahead))
+            {
+                batch.push(q);
+            } else {
+                rest.push_back(q);
+            }
+        }
+        self.queued = rest;
+        batch
+    }
+
+    /// Record how applying `batch` in one go fared.
+    pub(super) fn finish(&mut self, batch: &[Queued], result: &Result<ActivationResult, String>) {
+        for q in batch {
+            let status = match *result {
+                Ok(ref r) => SubmittedMigration::Done(
+                    r.clone(),
+                    batch
+                        .iter()
+                        .map(|other| other.ticket)
+                        .filter(|&t| t != q.ticket)
+                        .collect(),
+                ),
+                Err(ref e) => SubmittedMigration::Failed(e.clone()),
+            };
+            self.finished.insert(q.ticket, status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_batches_migrations_that_do_not_conflict() {
+        let mut queue = MigrationQueue::default();
+        let a = queue
+            .submit("CREATE TABLE a (x int, y int);".to_owned())
+            .unwrap();
+        let b = queue
+            .submit("CREATE TABLE b (x int, y int);".to_owned())
+            .unwrap();
+        let qa = queue
+            .submit("QUERY qa: SELECT x FROM a WHERE y = ?;".to_owned())
+            .unwrap();
+        let qb = queue
+            .submit("QUERY qb: SELECT x FROM b WHERE y = ?;".to_owned())
+            .unwrap();
+        assert!(queue.wake());
+        assert!(!queue.wake());
+
+        let batch: Vec<_> = queue.next_batch().iter().map(|q| q.ticket).collect();
+        assert_eq!(batch, vec![a, b]);
+        match queue.status(qb) {
+            Some(SubmittedMigration::Queued(1)) => {}
+            s => panic!("unexpected status {:?}", s),
+        }
+
+        let batch: Vec<_> = queue.next_batch().iter().map(|q| q.ticket).collect();
+        assert_eq!(batch, vec![qa, qb]);
+        assert!(queue.next_batch().is_empty());
+        assert!(!queue.wake());
+    }
+
+    #[test]
+    fn it_rejects_migrations_that_do_not_parse() {
+        let mut queue = MigrationQueue::default();
+        assert!(queue.submit("CREATE TABLE".to_owned()).is_err());
+        assert!(queue.tickets().is_empty());
+    }
+}
@@ -1,6 +1,9 @@
 use crate::controller::security::policy::Policy;
 use nom_sql::parser as sql_parser;
-use nom_sql::SqlQuery;
+use nom_sql::{
+    Column, ConditionBase, ConditionExpression, FieldDefinitionExpression, Operator, SqlQuery,
+};
+use noria::DataType;
 use serde_json;
 use serde_json::Value;
 
@@ -45,6 +48,117 @@ impl Group {
     pub fn policies(&self) -> &[Policy] {
         self.policies.as_slice()
     }
+
+    /// The base table that the group's membership is derived from, and how a membership row is
+    /// written to it.
+    ///
+    /// This only works for membership queries that select the user and group ids straight from a
+    /// single table, optionally filtered by equality with constants (as in `WHERE role = 1`). The
+    /// ids are the columns selected as `uid` and `gid`, or the first and second column if they
+    /// aren't named that way.
+    pub fn membership_table(&self) -> Result<MembershipTable, String> {
+        let unsupported = || {
+            format!(
+                "membership of group {} is not a simple selection from a single table",
+                self.name
+            )
+        };
+
+        let select = match self.membership {
+            SqlQuery::Select(ref s) if s.tables.len() == 1 && s.fields.len() == 2 => s,
+            _ => return Err(unsupported()),
+        };
+
+        // columns may name the table they come from either directly or by its alias
+        let table = &select.tables[0];
+        let ours = |c: &Column| match c.table {
+            None => true,
+            Some(ref t) => *t == table.name || Some(t) == table.alias.as_ref(),
+        };
+
+        let columns: Vec<_> = select
+            .fields
+            .iter()
+            .map(|f| match *f {
+                FieldDefinitionExpression::Col(ref c) if c.function.is_none() && ours(c) => Ok(c),
+                _ => Err(unsupported()),
+            })
+            .collect::<Result<_, _>>()?;
+        let named = |name: &str| {
+            columns
+                .iter()
+                .position(|c| c.alias.as_ref().unwrap_or(&c.name) == name)
+        };
+        let fields = match (named("uid"), named("gid")) {
+            (Some(uid), Some(gid)) => (uid, gid),
+            _ => (0, 1),
+        };
+
+        let mut conditions = Vec::new();
+        if let Some(ref ce) = select.where_clause {
+            equalities(ce, &mut conditions).ok_or_else(unsupported)?;
+        }
+        let fixed = conditions
+            .into_iter()
+            .map(|(c, v)| {
+                if ours(c) {
+                    Ok((c.name.clone(), v))
+                } else {
+                    Err(unsupported())
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(MembershipTable {
+            table: table.name.clone(),
+            uid: columns[fields.0].name.clone(),
+            gid: columns[fields.1].name.clone(),
+            fixed,
+            fields,
+        })
+    }
+}
+
+/// Where the members of a group are stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MembershipTable {
+    /// The base table that holds the membership.
+    pub table: String,
+    /// The column that holds the user id.
+    pub uid: String,
+    /// The column that holds the group id.
+    pub gid: String,
+    /// Columns whose value the membership query requires to be a particular constant.
+    pub fixed: Vec<(String, DataType)>,
+    /// Where the user and group ids are in the rows of the group's membership view.
+    pub fields: (usize, usize),
+}
+
+/// Collect the `column = constant` conjuncts of `ce`, or return `None` if it has any other form.
+fn equalities<'a>(
+    ce: &'a ConditionExpression,
+    out: &mut Vec<(&'a Column, DataType)>,
+) -> Option<()> {
+    match *ce {
+        ConditionExpression::LogicalOp(ref ct) if ct.operator == Operator::And => {
+            equalities(&ct.left, out)?;
+            equalities(&ct.right, out)
+        }
+        ConditionExpression::ComparisonOp(ref ct) if ct.operator == Operator::Equal => {
+            match (&*ct.left, &*ct.right) {
+                (
+                    &ConditionExpression::Base(ConditionBase::Field(ref c)),
+                    &ConditionExpression::Base(ConditionBase::Literal(ref l)),
+                ) => {
+                    out.push((c, DataType::from(l)));
+                    Some(())
+                }
+                _ => None,
+            }
+        }
+        ConditionExpression::Bracketed(ref ce) => equalities(ce, out),
+        _ => None,
+    }
 }
 
 mod tests {
@@ -73,4 +187,52 @@ mod tests {
             sql_parser::parse_query(membership).unwrap()
         );
     }
+
+    #[test]
+    fn it_finds_membership_tables() {
+        use super::*;
+
+        let group_text = r#"
+            [
+                {
+                    "name": "ta",
+                    "membership": "select r_uid as uid, r_cid as gid FROM Role WHERE r_role = 1;",
+                    "policies": []
+                },
+                {
+                    "name": "student",
+                    "membership": "select uid, count(cid) as gid FROM Role GROUP BY uid;",
+                    "policies": []
+                },
+                {
+                    "name": "tutor",
+                    "membership": "select r.r_cid as gid, r.r_uid as uid FROM Role AS r WHERE r.r_role = 2;",
+                    "policies": []
+                }
+            ]"#;
+
+        let groups = Group::parse(group_text);
+        assert_eq!(
+            groups[0].membership_table(),
+            Ok(MembershipTable {
+                table: "Role".to_owned(),
+                uid: "r_uid".to_owned(),
+                gid: "r_cid".to_owned(),
+                fixed: vec![("r_role".to_owned(), 1.into())],
+                fields: (0, 1),
+            })
+        );
+        assert!(groups[1].membership_table().is_err());
+        // columns are told apart by their aliases, and may name the table by its alias
+        assert_eq!(
+            groups[2].membership_table(),
+            Ok(MembershipTable {
+                table: "Role".to_owned(),
+                uid: "r_uid".to_owned(),
+                gid: "r_cid".to_owned(),
+                fixed: vec![("r_role".to_owned(), 2.into())],
+                fields: (1, 0),
+            })
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use dataflow::ops::project::ColumnMask;
 use nom_sql::parser as sql_parser;
 use nom_sql::SqlQuery;
 use serde_json;
@@ -15,6 +16,7 @@ pub enum Policy {
     Rewrite(RewritePolicy),
     Allow(RowPolicy),
     Deny(RowPolicy),
+    Mask(MaskPolicy),
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Serialize, Deserialize)]
@@ -34,12 +36,22 @@ pub struct RewritePolicy {
     pub rewrite_view: SqlQuery,
 }
 
+/// Redacts a column of a table in every query result that includes it.
+#[derive(Clone, Debug, Hash, PartialEq, Serialize, Deserialize)]
+pub struct MaskPolicy {
+    pub name: String,
+    pub table: String,
+    pub column: String,
+    pub mask: ColumnMask,
+}
+
 impl Policy {
     pub fn name(&self) -> String {
         match *self {
             Policy::Rewrite(ref p) => p.name.clone(),
             Policy::Allow(ref p) => p.name.clone(),
             Policy::Deny(ref p) => p.name.clone(),
+            Policy::Mask(ref p) => p.name.clone(),
         }
     }
 
@@ -48,6 +60,7 @@ impl Policy {
             Policy::Rewrite(ref p) => p.table.clone(),
             Policy::Allow(ref p) => p.table.clone(),
             Policy::Deny(ref p) => p.table.clone(),
+            Policy::Mask(ref p) => p.table.clone(),
         }
     }
 
@@ -56,6 +69,21 @@ impl Policy {
             Policy::Rewrite(_) => false,
             Policy::Allow(_) => true,
             Policy::Deny(_) => true,
+            Policy::Mask(_) => false,
+        }
+    }
+
+    pub fn is_mask_policy(&self) -> bool {
+        match *self {
+            Policy::Mask(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn mask(&self) -> ColumnMask {
+        match *self {
+            Policy::Mask(ref p) => p.mask.clone(),
+            _ => panic!("Only mask policies have a mask"),
         }
     }
 
@@ -64,6 +92,7 @@ impl Policy {
             Policy::Rewrite(ref p) => p.rewrite_view.clone(),
             Policy::Allow(ref p) => p.predicate.clone(),
             Policy::Deny(ref p) => p.predicate.clone(),
+            Policy::Mask(_) => panic!("Mask policy doesn't have predicate field"),
         }
     }
 
@@ -72,6 +101,7 @@ impl Policy {
             Policy::Rewrite(ref p) => p.value.clone(),
             Policy::Allow(_) => panic!("Row policy doesn't have value field"),
             Policy::Deny(_) => panic!("Row policy doesn't have value field"),
+            Policy::Mask(_) => panic!("Mask policy doesn't have value field"),
         }
     }
 
@@ -80,6 +110,7 @@ impl Policy {
             Policy::Rewrite(ref p) => p.column.clone(),
             Policy::Allow(_) => panic!("Row policy doesn't have column field"),
             Policy::Deny(_) => panic!("Row policy doesn't have column field"),
+            Policy::Mask(ref p) => p.column.clone(),
         }
     }
 
@@ -88,6 +119,7 @@ impl Policy {
             Policy::Rewrite(ref p) => p.key.clone(),
             Policy::Allow(_) => panic!("Row policy doesn't have key field"),
             Policy::Deny(_) => panic!("Row policy doesn't have key field"),
+            Policy::Mask(_) => panic!("Mask policy doesn't have key field"),
         }
     }
 
@@ -104,6 +136,7 @@ impl Policy {
                     Some("rewrite") => Policy::parse_rewrite_policy(p),
                     Some("allow") => Policy::parse_row_policy(p, Action::Allow),
                     Some("deny") => Policy::parse_row_policy(p, Action::Deny),
+                    Some("mask") => Policy::parse_mask_policy(p),
                     _ => panic!("Unsupported policy action {}", action),
                 },
                 None => Policy::parse_row_policy(p, Action::Allow),
@@ -134,6 +167,29 @@ impl Policy {
         }
     }
 
+    fn parse_mask_policy(p: &Value) -> Policy {
+        let name = match p.get("name") {
+            Some(n) => n.as_str().unwrap(),
+            None => "",
+        };
+
+        let table = p["table"].as_str().unwrap();
+        let column = p["column"].as_str().unwrap();
+        let mask = match p["mask"].as_str() {
+            Some("null") => ColumnMask::Null,
+            Some("hash") => ColumnMask::Hash(p["key"].as_str().unwrap().to_string()),
+            Some("truncate") => ColumnMask::Truncate(p["length"].as_u64().unwrap() as usize),
+            m => panic!("Unsupported column mask {:?}", m),
+        };
+
+        Policy::Mask(MaskPolicy {
+            name: name.to_string(),
+            table: table.to_string(),
+            column: column.to_string(),
+            mask,
+        })
+    }
+
     fn parse_rewrite_policy(p: &Value) -> Policy {
         let name = match p.get("name") {
             Some(n) => n.as_str().unwrap(),
@@ -181,4 +237,22 @@ mod tests {
             sql_parser::parse_query(p1).unwrap()
         );
     }
+
+    #[test]
+    fn it_parses_mask_policies() {
+        use super::*;
+        let policy_text = r#"[{ "action": "mask", "table": "user", "column": "email",
+                                "mask": "hash", "key": "k" },
+                              { "action": "mask", "table": "user", "column": "phone",
+                                "mask": "truncate", "length": 3 }]"#;
+
+        let policies = Policy::parse(policy_text);
+
+        assert_eq!(policies.len(), 2);
+        assert!(policies.iter().all(|p| p.is_mask_policy()));
+        assert_eq!(policies[0].column(), "email");
+        assert_eq!(policies[0].mask(), ColumnMask::Hash("k".to_owned()));
+        assert_eq!(policies[1].table(), "user");
+        assert_eq!(policies[1].mask(), ColumnMask::Truncate(3));
+    }
 }
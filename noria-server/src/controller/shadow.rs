@@ -0,0 +1,113 @@
+//! Shadow migrations, which try out a proposed recipe on live writes before it serves any reads.
+//!
+//! The queries that a proposed recipe adds or changes are installed under new names next to the
+//! live queries, so that they are fed by the same writes, but clients do not read from them. Once
+//! the shadow has soaked for a while, its results can be compared with those of the live queries,
+//! and the state and processing time it costs inspected, before the proposed recipe is installed
+//! for real or the shadow is discarded.
+//!
+//! Shadow queries read from the live tables and views, never from each other. They are also not
+//! persisted, so a shadow does not survive the controller failing over.
+
+use crate::controller::recipe::Recipe;
+use nom_sql::SqlQuery;
+use petgraph::graph::NodeIndex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Prefix given to the names of shadow queries.
+const PREFIX: &str = "shadow__";
+
+/// A proposed recipe that is running in the shadow of the live one.
+pub(super) struct Shadow {
+    /// The proposed recipe, as given by the operator.
+    pub(super) recipe: String,
+    /// The name of the shadow query for each query the proposed recipe adds or changes.
+    pub(super) queries: HashMap<String, String>,
+    /// The queries among `queries` that the proposed recipe adds rather than changes.
+    pub(super) added: Vec<String>,
+    /// The nodes that were added to the graph for the shadow queries.
+    pub(super) nodes: Vec<NodeIndex>,
+    pub(super) started: Instant,
+    pub(super) soak: Duration,
+}
+
+impl Shadow {
+    /// Whether the shadow has soaked for long enough to be promoted.
+    pub(super) fn ready(&self) -> bool {
+        self.started.elapsed() >= self.soak
+    }
+}
+
+/// Whether `name` is the name of a shadow query.
+pub(super) fn is_shadow(name: &str) -> bool {
+    name.starts_with(PREFIX)
+}
+
+/// Work out the recipe additions that shadow the queries `proposed` adds to or changes in
+/// `current`, and the name of the shadow query for each of them.
+pub(super) fn shadow_queries(
+    current: &Recipe,
+    proposed: &Recipe,
+) -> Result<(String, HashMap<String, String>), String> {
+    let mut additions = String::new();
+    let mut queries = HashMap::new();
+    for (name, q) in proposed.expressions() {
+        if current.contains(q) {
+            continue;
+        }
+
+        match *q {
+            SqlQuery::CreateTable(ref ctq) => {
+                return Err(format!("cannot shadow changes to table {}", ctq.table.name));
+            }
+            _ => {
+                let name = name.ok_or_else(|| format!("cannot shadow unnamed query {}", q))?;
+                let shadow = format!("{}{}", PREFIX, name);
+                additions.push_str(&format!("QUERY {}: {};\n", shadow, q));
+                queries.insert(name.clone(), shadow);
+            }
+        }
+    }
+    Ok((additions, queries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_shadows_new_and_changed_queries() {
+        let current = Recipe::from_str(
+            "CREATE TABLE b (a int, c int);
+             QUERY kept: SELECT a FROM b WHERE c = ?;
+             QUERY changed: SELECT a FROM b WHERE a = ?;",
+            None,
+        )
+        .unwrap();
+        let proposed = Recipe::from_str(
+            "CREATE TABLE b (a int, c int);
+             QUERY kept: SELECT a FROM b WHERE c = ?;
+             QUERY changed: SELECT a, c FROM b WHERE a = ?;
+             QUERY added: SELECT c FROM b WHERE a = ?;",
+            None,
+        )
+        .unwrap();
+
+        let (additions, queries) = shadow_queries(&current, &proposed).unwrap();
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries["changed"], "shadow__changed");
+        assert_eq!(queries["added"], "shadow__added");
+        assert!(queries.values().all(|q| is_shadow(q)));
+
+        let shadow = Recipe::from_str(&additions, None).unwrap();
+        assert_eq!(shadow.expressions().len(), 2);
+    }
+
+    #[test]
+    fn it_refuses_to_shadow_tables() {
+        let current = Recipe::from_str("CREATE TABLE b (a int, c int);", None).unwrap();
+        let proposed = Recipe::from_str("CREATE TABLE b (a int, c int, d int);", None).unwrap();
+        assert!(shadow_queries(&current, &proposed).is_err());
+    }
+}
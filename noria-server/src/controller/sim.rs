@@ -0,0 +1,106 @@
+//! The controller of a simulated deployment.
+
+use crate::controller::inner::ControllerInner;
+use crate::controller::progress::MigrationTracker;
+use crate::controller::{handle_event, ControllerState, Environment, WorkerSender};
+use crate::sim::{self, Network, Node, Scheduler};
+use crate::startup::Event;
+use crate::Config;
+use futures::sync::mpsc::UnboundedSender;
+use noria::consensus::{Authority, Epoch, LocalAuthority, STATE_KEY};
+use noria::ControllerDescriptor;
+use serde_json;
+use slog;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::prelude::*;
+
+/// Make a controller the leader of a simulated deployment, and run it on `scheduler`.
+///
+/// Returns where to send the controller events, and the epoch that it leads in.
+crate fn start(
+    scheduler: &Scheduler,
+    network: &Network,
+    config: Config,
+    log: slog::Logger,
+) -> (UnboundedSender<Event>, Epoch) {
+    let addr = sim::controller_addr();
+    let descriptor = ControllerDescriptor {
+        external_addr: addr,
+        worker_addr: addr,
+        domain_addr: addr,
+        nonce: 0,
+        tls: false,
+    };
+    let authority = Arc::new(LocalAuthority::new());
+    let epoch = authority
+        .become_leader(serde_json::to_vec(&descriptor).unwrap())
+        .unwrap()
+        .expect("simulated controller has no one to lose the election to");
+    let state = authority
+        .read_modify_write(STATE_KEY, |_: Option<ControllerState>| -> Result<_, ()> {
+            Ok(ControllerState::new(config.clone(), epoch))
+        })
+        .unwrap()
+        .unwrap();
+
+    let (tx, mut rx) = futures::sync::mpsc::unbounded();
+    let (dtx, drx) = futures::sync::mpsc::unbounded();
+    network.listen_controller(tx.clone(), dtx);
+
+    let workers = network.clone();
+    let waiting = scheduler.clone();
+    let env = Environment {
+        coordinator: network.coordinator(),
+        connect: Box::new(move |addr: &SocketAddr| {
+            let sender = workers.connect_worker(addr).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "no simulated worker there",
+                )
+            })?;
+            Ok((Box::new(sender) as WorkerSender, sim::controller_addr()))
+        }),
+        clock: Arc::new(scheduler.clock().clone()),
+        // the rest of the deployment runs while the controller waits for it
+        idle: Box::new(move || waiting.wait()),
+    };
+    let mut ctrl = ControllerInner::new(
+        log.clone(),
+        state,
+        env,
+        drx,
+        None,
+        MigrationTracker::default(),
+    );
+
+    let migrations_waiting = AtomicBool::new(false);
+    let quotas_waiting = AtomicBool::new(false);
+    let main = future::poll_fn(move || {
+        let e = match rx.poll()? {
+            Async::Ready(Some(e)) => e,
+            Async::Ready(None) => return Ok(Async::Ready(())),
+            Async::NotReady if migrations_waiting.swap(false, Ordering::SeqCst) => {
+                Event::RunMigrations
+            }
+            Async::NotReady if quotas_waiting.swap(false, Ordering::SeqCst) => Event::EnforceQuotas,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+
+        let waiting = (&migrations_waiting, &quotas_waiting);
+        if !handle_event(&mut ctrl, e, &authority, waiting, &log) {
+            // there is no other controller to take over
+            crit!(log, "simulated controller stepped down");
+            return Ok(Async::Ready(()));
+        }
+
+        // one event at a time, so that the rest of the deployment can run in between
+        task::current().notify();
+        Ok(Async::NotReady)
+    });
+    scheduler.spawn(Node::Controller, "main", main);
+
+    (tx, epoch)
+}
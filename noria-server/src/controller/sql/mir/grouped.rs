@@ -77,6 +77,7 @@ pub(super) fn make_grouped(
     node_count: usize,
     prev_node: &mut Option<MirNodeRef>,
     is_reconcile: bool,
+    approximate: bool,
 ) -> Vec<MirNodeRef> {
     let mut func_nodes: Vec<MirNodeRef> = Vec::new();
     let mut node_count = node_count;
@@ -241,6 +242,7 @@ pub(super) fn make_grouped(
                     &Column::from(computed_col),
                     group_cols.iter().collect(),
                     parent_node,
+                    approximate,
                 );
 
                 *prev_node = Some(nodes.last().unwrap().clone());
@@ -6,6 +6,8 @@ use petgraph::graph::NodeIndex;
 // TODO(malte): remove if possible
 use dataflow::ops::filter::FilterCondition;
 use dataflow::ops::join::JoinType;
+use dataflow::ops::project::ColumnMask;
+use dataflow::ops::set::SetOpKind;
 
 use crate::controller::sql::query_graph::{OutputColumn, QueryGraph};
 use crate::controller::sql::query_signature::Signature;
@@ -13,12 +15,13 @@ use nom_sql::{
     ArithmeticExpression, ColumnSpecification, CompoundSelectOperator, ConditionBase,
     ConditionExpression, ConditionTree, Literal, Operator, SqlQuery, TableKey,
 };
-use nom_sql::{LimitClause, OrderClause, SelectStatement};
+use nom_sql::{LimitClause, OrderClause, OrderType, SelectStatement};
 
 use slog;
 use std::collections::{HashMap, HashSet};
 
 use std::ops::Deref;
+use std::rc::Rc;
 use std::vec::Vec;
 
 use crate::controller::sql::security::Universe;
@@ -35,6 +38,27 @@ fn sanitize_leaf_column(c: &mut Column, view_name: &str) {
     c.aliases = vec![];
 }
 
+/// The order a reader whose parent has `columns` keeps its rows in, if there is an `order` and
+/// the parent has all the columns it orders by.
+fn reader_order(
+    order: &Option<OrderClause>,
+    columns: &[Column],
+) -> Option<Vec<(Column, OrderType)>> {
+    order
+        .as_ref()?
+        .columns
+        .iter()
+        .map(|&(ref c, ref ot)| {
+            let c = Column::from(c);
+            if columns.contains(&c) {
+                Some((c, ot.clone()))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
 /// Returns all collumns used in a predicate
 fn predicate_columns(ce: &ConditionExpression) -> HashSet<Column> {
     use nom_sql::ConditionExpression::*;
@@ -102,6 +126,9 @@ pub(super) struct SqlToMirConverter {
     nodes: HashMap<(String, usize), MirNodeRef>,
     schema_version: usize,
 
+    /// The named queries that estimate rather than count their distinct values.
+    approximated: HashSet<String>,
+
     /// Universe in which the conversion is happening
     universe: Universe,
 }
@@ -114,6 +141,7 @@ impl Default for SqlToMirConverter {
             log: slog::Logger::root(slog::Discard, o!()),
             nodes: HashMap::default(),
             schema_version: 0,
+            approximated: HashSet::default(),
             universe: Universe::default(),
         }
     }
@@ -140,6 +168,17 @@ impl SqlToMirConverter {
         self.universe = Universe::default();
     }
 
+    /// Have the named query `query` estimate its `COUNT(DISTINCT ..)`s with a sketch of the
+    /// distinct values rather than keep all of them.
+    pub(super) fn approximate_distinct(&mut self, query: &str) {
+        self.approximated.insert(query.to_owned());
+    }
+
+    /// Whether the named query `query` estimates its `COUNT(DISTINCT ..)`s.
+    pub(super) fn approximates(&self, query: &str) -> bool {
+        self.approximated.contains(query)
+    }
+
     fn get_view(&self, view_name: &str) -> Result<MirNodeRef, String> {
         self.current
             .get(view_name)
@@ -153,6 +192,37 @@ impl SqlToMirConverter {
             })
     }
 
+    /// The mask that the current universe's policies put on `c`, if any.
+    ///
+    /// An aliased column is masked like the column it is an alias of, and a column of a view like
+    /// the column of the table or view that the view took it from.
+    fn mask_for(&self, c: &Column) -> Option<ColumnMask> {
+        let c = c.aliases.first().unwrap_or(c);
+        let table = c.table.as_ref()?;
+        let masked = self
+            .universe
+            .mask_policies
+            .get(table)
+            .and_then(|masks| masks.iter().find(|&&(ref column, _)| *column == c.name));
+        if let Some(&(_, ref mask)) = masked {
+            return Some(mask.clone());
+        }
+
+        let v = self.current.get(table)?;
+        let node = self.nodes.get(&(table.clone(), *v))?.borrow();
+        let i = node.columns().iter().position(|vc| vc.name == c.name)?;
+        let source = match node.inner {
+            // a leaf's columns are renamed after its view, but are otherwise its parent's
+            MirNodeType::Leaf { .. } => node.ancestors()[0].borrow().columns()[i].clone(),
+            _ => node.columns()[i].clone(),
+        };
+        if source.table.as_ref() == Some(table) && source.aliases.is_empty() {
+            // the column belongs to a base table, and is not masked
+            return None;
+        }
+        self.mask_for(&source)
+    }
+
     /// Converts a condition tree stored in the `ConditionExpr` returned by the SQL parser
     /// and adds its to a vector of conditions.
     fn to_conditions(
@@ -252,6 +322,7 @@ impl SqlToMirConverter {
                     emit: columns.clone(),
                     literals: vec![],
                     arithmetic: vec![],
+                    masks: vec![],
                 },
                 vec![parent.clone()],
                 vec![],
@@ -282,6 +353,9 @@ impl SqlToMirConverter {
             MirNodeType::Leaf {
                 node: parent.clone(),
                 keys: Vec::from(params),
+                order: None,
+                distinct: false,
+                masks: vec![],
             },
             vec![n],
             vec![],
@@ -303,8 +377,7 @@ impl SqlToMirConverter {
     pub(super) fn compound_query_to_mir(
         &mut self,
         name: &str,
-        sqs: Vec<&MirQuery>,
-        op: CompoundSelectOperator,
+        sqs: Vec<(Option<CompoundSelectOperator>, &MirQuery)>,
         order: &Option<OrderClause>,
         limit: &Option<LimitClause>,
         has_leaf: bool,
@@ -314,12 +387,22 @@ impl SqlToMirConverter {
         } else {
             format!("{}_union", name)
         };
-        let mut final_node = match op {
-            CompoundSelectOperator::Union => self.make_union_node(
+        let unions_only = sqs.iter().all(|&(ref op, _)| match *op {
+            None | Some(CompoundSelectOperator::Union) => true,
+            Some(CompoundSelectOperator::DistinctUnion) => true,
+            Some(_) => false,
+        });
+        let mut final_node = if unions_only {
+            self.make_union_node(
                 &union_name,
-                &sqs.iter().map(|mq| mq.leaf.clone()).collect::<Vec<_>>()[..],
-            ),
-            _ => unimplemented!(),
+                &sqs.iter().map(|mq| mq.1.leaf.clone()).collect::<Vec<_>>()[..],
+            )
+        } else {
+            self.make_set_op_nodes(
+                &union_name,
+                sqs.iter()
+                    .map(|&(ref op, mq)| (op.clone(), mq.leaf.clone())),
+            )
         };
         let node_id = (union_name, self.schema_version);
         self.nodes
@@ -366,6 +449,9 @@ impl SqlToMirConverter {
                 MirNodeType::Leaf {
                     node: final_node.clone(),
                     keys: vec![],
+                    order: reader_order(order, &columns),
+                    distinct: false,
+                    masks: vec![],
                 },
                 vec![final_node.clone()],
                 vec![],
@@ -384,7 +470,7 @@ impl SqlToMirConverter {
 
         MirQuery {
             name: String::from(name),
-            roots: sqs.iter().fold(Vec::new(), |mut acc, mq| {
+            roots: sqs.iter().fold(Vec::new(), |mut acc, &(_, mq)| {
                 acc.extend(mq.roots.iter().cloned());
                 acc
             }),
@@ -392,6 +478,182 @@ impl SqlToMirConverter {
         }
     }
 
+    /// Follow the edges that the two columns of `edges` give for up to `depth` steps. The query
+    /// returns where each edge starts, where it leads, and in how many steps.
+    pub(super) fn recursive_query_to_mir(
+        &mut self,
+        name: &str,
+        edges: &MirQuery,
+        depth: usize,
+        has_leaf: bool,
+    ) -> MirQuery {
+        let recursive_name = if !has_leaf {
+            String::from(name)
+        } else {
+            format!("{}_closure", name)
+        };
+        let parent = edges.leaf.clone();
+        let (from, to) = {
+            let parent_columns = parent.borrow().columns().to_vec();
+            assert_eq!(parent_columns.len(), 2);
+            (parent_columns[0].clone(), parent_columns[1].clone())
+        };
+
+        let columns = vec![from.clone(), to.clone(), Column::new(None, "depth")];
+        let sanitized_columns: Vec<Column> = columns
+            .clone()
+            .into_iter()
+            .map(|mut c| {
+                sanitize_leaf_column(&mut c, name);
+                c
+            })
+            .collect();
+
+        let recursive_node = MirNode::new(
+            &recursive_name,
+            self.schema_version,
+            if has_leaf {
+                columns
+            } else {
+                sanitized_columns.clone()
+            },
+            MirNodeType::Recursive { from, to, depth },
+            vec![parent],
+            vec![],
+        );
+        self.nodes
+            .entry((recursive_name, self.schema_version))
+            .or_insert_with(|| recursive_node.clone());
+
+        let leaf_node = if has_leaf {
+            MirNode::new(
+                name,
+                self.schema_version,
+                sanitized_columns,
+                MirNodeType::Leaf {
+                    node: recursive_node.clone(),
+                    keys: vec![],
+                    order: None,
+                    distinct: false,
+                    masks: vec![],
+                },
+                vec![recursive_node],
+                vec![],
+            )
+        } else {
+            recursive_node
+        };
+
+        self.current
+            .insert(String::from(leaf_node.borrow().name()), self.schema_version);
+        let node_id = (String::from(name), self.schema_version);
+        self.nodes
+            .entry(node_id)
+            .or_insert_with(|| leaf_node.clone());
+
+        MirQuery {
+            name: String::from(name),
+            roots: edges.roots.clone(),
+            leaf: leaf_node,
+        }
+    }
+
+    /// Keep the first `n` rows of `rows` in `order` for each value of the `partition` columns.
+    pub(super) fn top_n_query_to_mir(
+        &mut self,
+        name: &str,
+        rows: &MirQuery,
+        partition: &[String],
+        order: &[(String, OrderType)],
+        n: usize,
+        has_leaf: bool,
+    ) -> Result<MirQuery, String> {
+        let top_name = if !has_leaf {
+            String::from(name)
+        } else {
+            format!("{}_top", name)
+        };
+        let parent = rows.leaf.clone();
+        let columns = parent.borrow().columns().to_vec();
+        let find = |column: &str| {
+            columns
+                .iter()
+                .find(|c| c.name == column)
+                .cloned()
+                .ok_or_else(|| format!("query {} does not select column {}", name, column))
+        };
+        let group_by = partition
+            .iter()
+            .map(|c| find(c))
+            .collect::<Result<Vec<_>, _>>()?;
+        let order = order
+            .iter()
+            .map(|&(ref c, ref o)| Ok((find(c)?, o.clone())))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let sanitized_columns: Vec<Column> = columns
+            .clone()
+            .into_iter()
+            .map(|mut c| {
+                sanitize_leaf_column(&mut c, name);
+                c
+            })
+            .collect();
+
+        let top_node = MirNode::new(
+            &top_name,
+            self.schema_version,
+            if has_leaf {
+                columns
+            } else {
+                sanitized_columns.clone()
+            },
+            MirNodeType::TopK {
+                order: Some(order),
+                group_by,
+                k: n,
+                offset: 0,
+            },
+            vec![parent],
+            vec![],
+        );
+        self.nodes
+            .entry((top_name, self.schema_version))
+            .or_insert_with(|| top_node.clone());
+
+        let leaf_node = if has_leaf {
+            MirNode::new(
+                name,
+                self.schema_version,
+                sanitized_columns,
+                MirNodeType::Leaf {
+                    node: top_node.clone(),
+                    keys: vec![],
+                    order: None,
+                    distinct: false,
+                    masks: vec![],
+                },
+                vec![top_node],
+                vec![],
+            )
+        } else {
+            top_node
+        };
+
+        self.current
+            .insert(String::from(leaf_node.borrow().name()), self.schema_version);
+        let node_id = (String::from(name), self.schema_version);
+        self.nodes
+            .entry(node_id)
+            .or_insert_with(|| leaf_node.clone());
+
+        Ok(MirQuery {
+            name: String::from(name),
+            roots: rows.roots.clone(),
+            leaf: leaf_node,
+        })
+    }
+
     // pub(super) viz for tests
     pub(super) fn get_flow_node_address(&self, name: &str, version: usize) -> Option<NodeIndex> {
         match self.nodes.get(&(name.to_string(), version)) {
@@ -527,6 +789,50 @@ impl SqlToMirConverter {
         ))
     }
 
+    /// Plan a query once more from `mq`, an earlier plan for it, if every view that it reads from
+    /// is still the one that it read from then, and every node of other queries that it reuses is
+    /// still there.
+    pub(super) fn replan(&mut self, mq: &MirQuery) -> Option<MirQuery> {
+        let mut stack = vec![mq.leaf.clone()];
+        while let Some(mn) = stack.pop() {
+            let mn = mn.borrow();
+            if let MirNodeType::Reuse { ref node } = mn.inner {
+                let n = node.borrow();
+                match self.nodes.get(&(n.name().to_owned(), n.from_version)) {
+                    Some(registered) if Rc::ptr_eq(registered, node) => {}
+                    _ => return None,
+                }
+            }
+            stack.extend(mn.ancestors().iter().cloned());
+        }
+
+        let mut roots = Vec::new();
+        for root in &mq.roots {
+            let root = root.borrow();
+            let node = match root.inner {
+                MirNodeType::Reuse { ref node } => node,
+                _ => return None,
+            };
+            let v = *self.current.get(root.name())?;
+            match self.nodes.get(&(root.name().to_owned(), v)) {
+                Some(current) if Rc::ptr_eq(current, node) => {}
+                _ => return None,
+            }
+            roots.push(MirNode::reuse(node.clone(), self.schema_version));
+        }
+
+        let mq = mq.replan(roots, self.schema_version);
+        let mut stack = vec![mq.leaf.clone()];
+        while let Some(mn) = stack.pop() {
+            let node_id = (String::from(mn.borrow().name()), self.schema_version);
+            self.nodes.entry(node_id).or_insert_with(|| mn.clone());
+            stack.extend(mn.borrow().ancestors().iter().cloned());
+        }
+        self.current
+            .insert(String::from(mq.leaf.borrow().name()), self.schema_version);
+        Some(mq)
+    }
+
     pub(super) fn upgrade_schema(&mut self, new_version: usize) {
         assert!(new_version > self.schema_version);
         self.schema_version = new_version;
@@ -779,6 +1085,80 @@ impl SqlToMirConverter {
         )
     }
 
+    /// Combine the results of compound SELECT queries, some of which are put together with
+    /// INTERSECT or EXCEPT, into a node called `name`.
+    ///
+    /// As in standard SQL, INTERSECT binds more tightly than UNION and EXCEPT, which are applied
+    /// from left to right.
+    fn make_set_op_nodes<I>(&self, name: &str, inputs: I) -> MirNodeRef
+    where
+        I: IntoIterator<Item = (Option<CompoundSelectOperator>, MirNodeRef)>,
+    {
+        let mut terms: Vec<(Option<CompoundSelectOperator>, MirNodeRef)> = Vec::new();
+        for (i, (op, input)) in inputs.into_iter().enumerate() {
+            if let Some(CompoundSelectOperator::Intersect) = op {
+                let (prev_op, prev) = terms.pop().unwrap();
+                let node_name = format!("{}_s{}", name, i);
+                let node = self.make_set_op_node(&node_name, SetOpKind::Intersect, prev, input);
+                terms.push((prev_op, node));
+            } else {
+                terms.push((op, input));
+            }
+        }
+
+        let mut terms = terms.into_iter().enumerate();
+        let (_, (_, mut node)) = terms.next().unwrap();
+        for (i, (op, input)) in terms {
+            let node_name = format!("{}_s{}", name, i);
+            node = match op {
+                Some(CompoundSelectOperator::Except) => {
+                    self.make_set_op_node(&node_name, SetOpKind::Except, node, input)
+                }
+                _ => self.make_union_node(&node_name, &[node, input]),
+            };
+        }
+        // the last node holds the results of the whole query
+        node.borrow_mut().name = String::from(name);
+        node
+    }
+
+    /// Make a node called `name` that keeps the rows of `left` that `kind` says to keep given
+    /// the rows of `right`.
+    ///
+    /// The rows of both inputs are tagged with the input they come from and unioned, and the set
+    /// operator below the union counts them by input.
+    fn make_set_op_node(
+        &self,
+        name: &str,
+        kind: SetOpKind,
+        left: MirNodeRef,
+        right: MirNodeRef,
+    ) -> MirNodeRef {
+        let columns = left.borrow().columns().to_vec();
+        let tag = |input: MirNodeRef, side: i32| {
+            let columns = input.borrow().columns().to_vec();
+            self.make_project_node(
+                &format!("{}_{}", name, side),
+                input,
+                columns.iter().collect(),
+                vec![],
+                vec![("set_side".to_owned(), side.into())],
+                false,
+            )
+        };
+        let tagged = vec![tag(left, 0), tag(right, 1)];
+        let union = self.make_union_node(&format!("{}_tagged", name), &tagged);
+
+        MirNode::new(
+            name,
+            self.schema_version,
+            columns,
+            MirNodeType::SetOp { kind },
+            vec![union],
+            vec![],
+        )
+    }
+
     // Creates union node for universe creation - returns the resulting node ref and a universe table mapping
     fn make_union_node_sec(
         &self,
@@ -934,6 +1314,7 @@ impl SqlToMirConverter {
         func_col: &Column,
         group_cols: Vec<&Column>,
         parent: MirNodeRef,
+        approximate: bool,
     ) -> Vec<MirNodeRef> {
         use dataflow::ops::grouped::aggregate::Aggregation;
         use dataflow::ops::grouped::extremum::Extremum;
@@ -976,6 +1357,11 @@ impl SqlToMirConverter {
                 GroupedNodeType::Aggregation(Aggregation::SUM),
                 distinct,
             ),
+            Count(ref col, true) if approximate => mknode(
+                &Column::from(col),
+                GroupedNodeType::ApproxCountDistinct,
+                false,
+            ),
             Count(ref col, distinct) => mknode(
                 &Column::from(col),
                 GroupedNodeType::Aggregation(Aggregation::COUNT),
@@ -1067,6 +1453,17 @@ impl SqlToMirConverter {
                 vec![parent_node.clone()],
                 vec![],
             ),
+            GroupedNodeType::ApproxCountDistinct => MirNode::new(
+                name,
+                self.schema_version,
+                combined_columns,
+                MirNodeType::ApproxCountDistinct {
+                    on: over_col.clone(),
+                    group_by: group_by.into_iter().cloned().collect(),
+                },
+                vec![parent_node.clone()],
+                vec![],
+            ),
         }
     }
 
@@ -1221,6 +1618,7 @@ impl SqlToMirConverter {
                 emit: emit_cols,
                 literals,
                 arithmetic,
+                masks: vec![],
             },
             vec![parent_node.clone()],
             vec![],
@@ -1624,6 +2022,7 @@ impl SqlToMirConverter {
                     new_node_count,
                     &mut prev_node,
                     false,
+                    self.approximated.contains(name),
                 );
 
                 new_node_count += func_nodes.len();
@@ -1892,6 +2291,26 @@ impl SqlToMirConverter {
                 !has_leaf,
             );
 
+            // redact the columns this universe isn't allowed to see. this happens right above the
+            // reader, so the unmasked values can still flow through operators shared with other
+            // universes. the reader is keyed on the query parameters, so those are instead masked
+            // by the reader as it returns rows.
+            let params: Vec<Column> = qg.parameters().into_iter().map(Column::from).collect();
+            if uid != "global".into() {
+                let masks = projected_columns
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, c)| !params.contains(c))
+                    .filter_map(|(i, c)| self.mask_for(c).map(|mask| (i, mask)))
+                    .collect();
+                if let MirNodeType::Project {
+                    masks: ref mut m, ..
+                } = leaf_project_node.borrow_mut().inner
+                {
+                    *m = masks;
+                }
+            }
+
             nodes_added.push(leaf_project_node.clone());
 
             if has_leaf {
@@ -1911,7 +2330,15 @@ impl SqlToMirConverter {
                 let query_params = if has_bogokey {
                     vec![Column::new(None, "bogokey")]
                 } else {
-                    qg.parameters().into_iter().map(Column::from).collect()
+                    params
+                };
+                let masks = if uid != "global".into() {
+                    query_params
+                        .iter()
+                        .filter_map(|c| self.mask_for(c).map(|mask| (c.clone(), mask)))
+                        .collect()
+                } else {
+                    vec![]
                 };
 
                 let leaf_node = MirNode::new(
@@ -1921,6 +2348,9 @@ impl SqlToMirConverter {
                     MirNodeType::Leaf {
                         node: leaf_project_node.clone(),
                         keys: query_params,
+                        order: reader_order(&st.order, leaf_project_node.borrow().columns()),
+                        distinct: st.distinct,
+                        masks,
                     },
                     vec![leaf_project_node.clone()],
                     vec![],
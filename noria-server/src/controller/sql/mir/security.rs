@@ -79,6 +79,7 @@ impl SecurityBoundary for SqlToMirConverter {
                     node_count,
                     &mut Some(node.clone()),
                     true,
+                    false,
                 );
 
                 nodes_added.extend(grouped);
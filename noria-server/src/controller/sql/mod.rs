@@ -10,26 +10,33 @@ use self::mir::SqlToMirConverter;
 use self::query_graph::{to_query_graph, QueryGraph};
 use self::query_signature::Signature;
 use self::reuse::ReuseConfig;
+use self::security::Universe;
 use super::mir_to_flow::mir_query_to_flow_parts;
 use crate::controller::Migration;
+use crate::optimizer::QueryOptimizer;
 use crate::ReuseConfigType;
 use ::mir::query::{MirQuery, QueryFlowParts};
 use ::mir::reuse as mir_reuse;
+use ::mir::node::MirNodeType;
 use ::mir::Column;
 use ::mir::MirNodeRef;
 use dataflow::prelude::DataType;
 use nom_sql::parser as sql_parser;
-use nom_sql::{ArithmeticBase, CreateTableStatement, SqlQuery};
-use nom_sql::{CompoundSelectOperator, CompoundSelectStatement, SelectStatement};
+use nom_sql::{ArithmeticBase, CreateTableStatement, OrderType, SqlQuery, SqlType};
+use nom_sql::{CompoundSelectStatement, SelectStatement};
 use petgraph::graph::NodeIndex;
 
 use slog;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str;
+use std::sync::Arc;
 use std::vec::Vec;
 
 type UniverseId = (DataType, Option<DataType>);
 
+/// The parts of each planned MIR node that planning further queries on top of it may change.
+crate type SavedMir = Vec<(MirNodeRef, Vec<Column>, MirNodeType, Vec<MirNodeRef>)>;
+
 #[derive(Clone, Debug)]
 enum QueryGraphReuse {
     ExactMatch(MirNodeRef),
@@ -53,11 +60,27 @@ crate struct SqlIncorporator {
     query_graphs: HashMap<u64, QueryGraph>,
     base_mir_queries: HashMap<String, MirQuery>,
     mir_queries: HashMap<(u64, UniverseId), MirQuery>,
+    /// The MIR planned for each query, by its name, a hash of the query as rewritten, and its
+    /// universe, so that it need not be planned again when it is added once more.
+    plans: HashMap<(String, u64, UniverseId), MirQuery>,
     num_queries: usize,
 
     base_schemas: HashMap<String, CreateTableStatement>,
     view_schemas: HashMap<String, Vec<String>>,
 
+    /// The column that marks deleted rows in each base table that keeps them.
+    soft_deleted: HashMap<String, String>,
+
+    /// The order that named queries asked to have their tables joined in.
+    join_orders: HashMap<String, Vec<String>>,
+
+    /// How many steps the edges of each recursive named query are followed for.
+    recursions: HashMap<String, usize>,
+
+    /// The columns that each named query that keeps the first rows of its groups partitions and
+    /// orders by, and how many rows of each group it keeps.
+    top_n: HashMap<String, (Vec<String>, Vec<(String, OrderType)>, usize)>,
+
     schema_version: usize,
 
     reuse_type: ReuseConfigType,
@@ -65,6 +88,21 @@ crate struct SqlIncorporator {
     /// Active universes mapped to the group they belong to.
     /// If an user universe, mapped to None.
     universes: HashMap<Option<DataType>, Vec<UniverseId>>,
+
+    /// Every universe prepared so far, along with the migration context it was created with, so
+    /// that queries added later can also be installed in it.
+    prepared_universes: Vec<(HashMap<String, DataType>, Universe)>,
+
+    /// The number of queries installed in each universe, and how many each may have.
+    universe_queries: HashMap<String, usize>,
+    max_universe_queries: Option<usize>,
+
+    /// The external optimizer that may rewrite queries and their MIR before they are added.
+    optimizer: Option<Arc<dyn QueryOptimizer>>,
+
+    /// Why the query that was added last, and the queries it is made of, were not built on
+    /// existing queries, or on more of them.
+    rejected_reuse: Vec<String>,
 }
 
 impl Default for SqlIncorporator {
@@ -78,15 +116,27 @@ impl Default for SqlIncorporator {
             query_graphs: HashMap::default(),
             base_mir_queries: HashMap::default(),
             mir_queries: HashMap::default(),
+            plans: HashMap::default(),
             num_queries: 0,
 
             base_schemas: HashMap::default(),
             view_schemas: HashMap::default(),
 
+            soft_deleted: HashMap::default(),
+
+            join_orders: HashMap::default(),
+            recursions: HashMap::default(),
+            top_n: HashMap::default(),
+
             schema_version: 0,
 
             reuse_type: ReuseConfigType::Finkelstein,
             universes: HashMap::default(),
+            prepared_universes: Vec::new(),
+            universe_queries: HashMap::default(),
+            max_universe_queries: None,
+            optimizer: None,
+            rejected_reuse: Vec::new(),
         }
     }
 }
@@ -102,6 +152,42 @@ impl SqlIncorporator {
         }
     }
 
+    /// Hide the rows of base table `table` that are marked as deleted in `column` from the queries
+    /// added from now on, unless they ask about the column themselves.
+    pub(super) fn hide_soft_deleted(&mut self, table: &str, column: &str) {
+        self.soft_deleted.insert(table.to_owned(), column.to_owned());
+    }
+
+    /// Join the tables of the named query `query` in the order they are listed in `tables` when it
+    /// is added, rather than building it on top of another query.
+    pub(super) fn order_joins(&mut self, query: &str, tables: Vec<String>) {
+        self.join_orders.insert(query.to_owned(), tables);
+    }
+
+    /// Treat the two columns that the named query `query` selects as edges, and have it return
+    /// every node reached by following them for up to `depth` steps when it is added.
+    pub(super) fn recurse(&mut self, query: &str, depth: usize) {
+        self.recursions.insert(query.to_owned(), depth);
+    }
+
+    /// Have the named query `query` keep only the first `n` of its rows in `order` for each value
+    /// of the `partition` columns when it is added.
+    pub(super) fn keep_top_n(
+        &mut self,
+        query: &str,
+        partition: Vec<String>,
+        order: Vec<(String, OrderType)>,
+        n: usize,
+    ) {
+        self.top_n.insert(query.to_owned(), (partition, order, n));
+    }
+
+    /// Have the named query `query` estimate its `COUNT(DISTINCT ..)`s with a sketch of the
+    /// distinct values when it is added, rather than building it on top of another query.
+    pub(super) fn approximate_distinct(&mut self, query: &str) {
+        self.mir_converter.approximate_distinct(query);
+    }
+
     /// Disable node reuse for future migrations.
     #[allow(unused)]
     pub(super) fn disable_reuse(&mut self) {
@@ -114,6 +200,51 @@ impl SqlIncorporator {
         self.reuse_type = reuse_type;
     }
 
+    /// Limit the number of queries that may be installed in each security universe.
+    pub(super) fn set_universe_query_limit(&mut self, limit: Option<usize>) {
+        self.max_universe_queries = limit;
+    }
+
+    /// Let `optimizer` rewrite the queries added from now on.
+    pub(super) fn set_optimizer(&mut self, optimizer: Option<Arc<dyn QueryOptimizer>>) {
+        self.optimizer = optimizer;
+    }
+
+    /// Save the MIR nodes planned so far, which clones of this incorporator share, so that what a
+    /// clone does to them can be undone with `restore_mir`.
+    pub(super) fn save_mir(&self) -> SavedMir {
+        let mut saved: SavedMir = Vec::new();
+        let mut seen = HashSet::new();
+        let queries = self
+            .mir_queries
+            .values()
+            .chain(self.base_mir_queries.values())
+            .chain(self.plans.values());
+        for mq in queries {
+            for n in mq.topo_nodes() {
+                if !seen.insert(n.as_ptr()) {
+                    continue;
+                }
+                let (columns, inner, children) = {
+                    let n = n.borrow();
+                    (n.columns.clone(), n.inner.clone(), n.children.clone())
+                };
+                saved.push((n, columns, inner, children));
+            }
+        }
+        saved
+    }
+
+    /// Put back the MIR nodes that `save_mir` saved.
+    pub(super) fn restore_mir(saved: SavedMir) {
+        for (n, columns, inner, children) in saved {
+            let mut n = n.borrow_mut();
+            n.columns = columns;
+            n.inner = inner;
+            n.children = children;
+        }
+    }
+
     /// Incorporates a single query into via the flow graph migration in `mig`. The `query`
     /// argument is a string that holds a parameterized SQL query, and the `name` argument supplies
     /// an optional name for the query. If no `name` is specified, the table name is used in the
@@ -146,12 +277,27 @@ impl SqlIncorporator {
         is_leaf: bool,
         mig: &mut Migration,
     ) -> Result<QueryFlowParts, String> {
+        self.rejected_reuse.clear();
         match name {
             None => self.nodes_for_query(query, is_leaf, mig),
             Some(n) => self.nodes_for_named_query(query, n, is_leaf, mig),
         }
     }
 
+    /// Why the query that was added last was not built on existing queries, or on more of them.
+    pub(super) fn rejected_reuse(&self) -> &[String] {
+        &self.rejected_reuse
+    }
+
+    /// Note why `query_name` is not built on existing queries, or on more of them.
+    fn reject_reuse(&mut self, query_name: &str, reason: String) {
+        info!(
+            self.log,
+            "Not reusing for query \"{}\": {}", query_name, reason
+        );
+        self.rejected_reuse.push(reason);
+    }
+
     pub(super) fn get_base_schema(&self, name: &str) -> Option<CreateTableStatement> {
         self.base_schemas.get(name).cloned()
     }
@@ -190,7 +336,7 @@ impl SqlIncorporator {
         query_name: &str,
         universe: UniverseId,
         st: &SelectStatement,
-    ) -> (QueryGraph, QueryGraphReuse) {
+    ) -> Result<(QueryGraph, QueryGraphReuse), String> {
         debug!(self.log, "Making QG for \"{}\"", query_name);
         trace!(self.log, "Query \"{}\": {:#?}", query_name, st);
 
@@ -201,14 +347,31 @@ impl SqlIncorporator {
 
         trace!(self.log, "QG for \"{}\": {:#?}", query_name, qg);
 
+        // reusing another query would also reuse the order it joins in
+        if let Some(tables) = self.join_orders.get(query_name) {
+            qg.order_joins(tables)?;
+            let reason = "it asks for its tables to be joined in a given order".to_owned();
+            self.reject_reuse(query_name, reason);
+            return Ok((qg, QueryGraphReuse::None));
+        }
+
+        // an estimate must not stand in for an exact count, nor the other way around
+        if self.mir_converter.approximates(query_name) {
+            let reason = "it estimates counts, which exact counts cannot stand in for".to_owned();
+            self.reject_reuse(query_name, reason);
+            return Ok((qg, QueryGraphReuse::None));
+        }
+
         // if reuse is disabled, we're done
         if self.reuse_type == ReuseConfigType::NoReuse {
-            return (qg, QueryGraphReuse::None);
+            self.reject_reuse(query_name, "reuse is disabled".to_owned());
+            return Ok((qg, QueryGraphReuse::None));
         }
 
         // Do we already have this exact query or a subset of it in the same universe?
         // TODO(malte): make this an O(1) lookup by QG signature
         let qg_hash = qg.signature().hash;
+        let mut rejection = None;
         match self.mir_queries.get(&(qg_hash, universe.clone())) {
             None => (),
             Some(ref mir_query) => {
@@ -221,6 +384,7 @@ impl SqlIncorporator {
                 if existing_qg.signature() == qg.signature()
                     && existing_qg.parameters() == qg.parameters()
                     && existing_qg.exact_hash() == qg.exact_hash()
+                    && !self.mir_converter.approximates(&mir_query.name)
                 {
                     // we already have this exact query, down to the exact same reader key columns
                     // in exactly the same order
@@ -238,7 +402,16 @@ impl SqlIncorporator {
                         existing_qg,
                     );
 
-                    return (qg, QueryGraphReuse::ExactMatch(mir_query.leaf.clone()));
+                    return Ok((qg, QueryGraphReuse::ExactMatch(mir_query.leaf.clone())));
+                } else if existing_qg.signature() == qg.signature()
+                    && existing_qg.parameters() == qg.parameters()
+                {
+                    let name = &mir_query.name;
+                    rejection = Some(if self.mir_converter.approximates(name) {
+                        format!("{} estimates counts that this query counts exactly", name)
+                    } else {
+                        format!("{} reads the same columns, but computes something else", name)
+                    });
                 } else if existing_qg.signature() == qg.signature()
                     && existing_qg.parameters() != qg.parameters()
                 {
@@ -331,15 +504,29 @@ impl SqlIncorporator {
                                     Some(project_columns)
                                 }
                             };
-                            return (
+                            return Ok((
                                 qg,
                                 QueryGraphReuse::ReaderOntoExisting(mn, project_columns, params),
-                            );
+                            ));
                         }
+                        rejection = Some(format!(
+                            "{} does not keep the columns that this query is looked up by",
+                            mir_query.name
+                        ));
+                    } else if !predicates_match {
+                        rejection = Some(format!("{} filters on other values", mir_query.name));
+                    } else {
+                        rejection = Some(format!(
+                            "{} is looked up by other columns, which this query groups by",
+                            mir_query.name
+                        ));
                     }
                 }
             }
         }
+        if let Some(reason) = rejection {
+            self.reject_reuse(query_name, reason);
+        }
 
         let reuse_config = ReuseConfig::new(self.reuse_type.clone());
 
@@ -372,12 +559,14 @@ impl SqlIncorporator {
                 mir_queries.extend(mqs);
             }
 
-            return (qg, QueryGraphReuse::ExtendExisting(mir_queries));
+            return Ok((qg, QueryGraphReuse::ExtendExisting(mir_queries)));
         } else {
             info!(self.log, "No reuse opportunity, adding fresh query");
+            let reason = "no existing query computes any part of it".to_owned();
+            self.reject_reuse(query_name, reason);
         }
 
-        (qg, QueryGraphReuse::None)
+        Ok((qg, QueryGraphReuse::None))
     }
 
     fn add_leaf_to_existing_query(
@@ -458,10 +647,15 @@ impl SqlIncorporator {
             })
             .collect();
 
+        let subqueries = subqueries?;
         let mut combined_mir_query = self.mir_converter.compound_query_to_mir(
             query_name,
-            subqueries?.iter().collect(),
-            CompoundSelectOperator::Union,
+            query
+                .selects
+                .iter()
+                .map(|&(ref op, _)| op.clone())
+                .zip(subqueries.iter())
+                .collect(),
             &query.order,
             &query.limit,
             is_leaf,
@@ -474,6 +668,71 @@ impl SqlIncorporator {
         Ok(qfp)
     }
 
+    fn add_recursive_query(
+        &mut self,
+        query_name: &str,
+        query: &SelectStatement,
+        depth: usize,
+        is_leaf: bool,
+        mut mig: &mut Migration,
+    ) -> Result<QueryFlowParts, String> {
+        if query.fields.len() != 2 {
+            return Err(format!(
+                "recursive query {} must select the two columns that its edges run between",
+                query_name
+            ));
+        }
+
+        // the edges are always planned anew, since their MIR is needed to follow them
+        let edges_name = format!("{}_edges", query_name);
+        let (qg, _) = self.consider_query_graph(&edges_name, mig.universe(), query)?;
+        if !qg.parameters().is_empty() {
+            return Err(format!(
+                "recursive query {} cannot have parameters; query the view it defines instead",
+                query_name
+            ));
+        }
+        let (_, edges) = self.add_query_via_mir(&edges_name, query, qg, false, mig)?;
+
+        let mut mir = self
+            .mir_converter
+            .recursive_query_to_mir(query_name, &edges, depth, is_leaf);
+        let qfp = mir_query_to_flow_parts(&mut mir, &mut mig, None);
+        self.register_query(query_name, None, &mir, mig.universe());
+
+        Ok(qfp)
+    }
+
+    fn add_top_n_query(
+        &mut self,
+        query_name: &str,
+        query: &SelectStatement,
+        top_n: (Vec<String>, Vec<(String, OrderType)>, usize),
+        is_leaf: bool,
+        mut mig: &mut Migration,
+    ) -> Result<QueryFlowParts, String> {
+        // the rows are always planned anew, since their MIR is needed to rank them
+        let rows_name = format!("{}_rows", query_name);
+        let (qg, _) = self.consider_query_graph(&rows_name, mig.universe(), query)?;
+        if !qg.parameters().is_empty() {
+            return Err(format!(
+                "query {} that keeps the first rows of each group cannot have parameters; \
+                 query the view it defines instead",
+                query_name
+            ));
+        }
+        let (_, rows) = self.add_query_via_mir(&rows_name, query, qg, false, mig)?;
+
+        let (partition, order, n) = top_n;
+        let mut mir =
+            self.mir_converter
+                .top_n_query_to_mir(query_name, &rows, &partition, &order, n, is_leaf)?;
+        let qfp = mir_query_to_flow_parts(&mut mir, &mut mig, None);
+        self.register_query(query_name, None, &mir, mig.universe());
+
+        Ok(qfp)
+    }
+
     /// Returns tuple of `QueryFlowParts` and an optional new `MirQuery`. The latter is only
     /// present if a new `MirQuery` was added.
     fn add_select_query(
@@ -483,7 +742,21 @@ impl SqlIncorporator {
         is_leaf: bool,
         mig: &mut Migration,
     ) -> Result<(QueryFlowParts, Option<MirQuery>), String> {
-        let (qg, reuse) = self.consider_query_graph(&query_name, mig.universe(), sq);
+        let rewritten = self
+            .optimizer
+            .as_ref()
+            .and_then(|o| o.rewrite_query(query_name, sq));
+        let sq = rewritten.as_ref().unwrap_or(sq);
+        let (qg, reuse) = self.consider_query_graph(&query_name, mig.universe(), sq)?;
+        match reuse {
+            QueryGraphReuse::ExactMatch(_) | QueryGraphReuse::ReaderOntoExisting(..) => {}
+            _ => {
+                if let Some((qfp, mir)) = self.add_planned_query(query_name, sq, &qg, is_leaf, mig)
+                {
+                    return Ok((qfp, Some(mir)));
+                }
+            }
+        }
         Ok(match reuse {
             QueryGraphReuse::ExactMatch(mn) => {
                 let flow_node = mn.borrow().flow_node.as_ref().unwrap().address();
@@ -511,6 +784,36 @@ impl SqlIncorporator {
         })
     }
 
+    /// Add `query` from the plan that was made for it when it was added before, if all the views
+    /// that plan reads from are still there.
+    fn add_planned_query(
+        &mut self,
+        query_name: &str,
+        query: &SelectStatement,
+        qg: &QueryGraph,
+        is_leaf: bool,
+        mut mig: &mut Migration,
+    ) -> Option<(QueryFlowParts, MirQuery)> {
+        let universe = mig.universe();
+        let plan = (
+            query_name.to_owned(),
+            plan_hash(query, qg, is_leaf),
+            universe.clone(),
+        );
+        let mut mir = match self.plans.get(&plan) {
+            Some(mir) => self.mir_converter.replan(mir)?,
+            None => return None,
+        };
+        info!(
+            self.log,
+            "Reusing the plan made before for query \"{}\"", query_name
+        );
+
+        let qfp = mir_query_to_flow_parts(&mut mir, &mut mig, None);
+        self.register_query(query_name, Some(qg.clone()), &mir, universe);
+        Some((qfp, mir))
+    }
+
     fn add_query_via_mir(
         &mut self,
         query_name: &str,
@@ -538,6 +841,15 @@ impl SqlIncorporator {
 
         trace!(self.log, "Optimized MIR:\n{}", mir.to_graphviz().unwrap());
 
+        if let Some(rewritten) = self
+            .optimizer
+            .as_ref()
+            .and_then(|o| o.rewrite_mir(query_name, &mir))
+        {
+            trace!(self.log, "Rewritten MIR:\n{}", rewritten.to_graphviz().unwrap());
+            mir = rewritten;
+        }
+
         if sec {
             match table_mapping {
                 Some(ref x) => {
@@ -551,6 +863,11 @@ impl SqlIncorporator {
 
         // push it into the flow graph using the migration in `mig`, and obtain `QueryFlowParts`
         let qfp = mir_query_to_flow_parts(&mut mir, &mut mig, None);
+        if !sec {
+            let plan = plan_hash(query, &qg, is_leaf);
+            self.plans
+                .insert((query_name.to_owned(), plan, universe.clone()), mir.clone());
+        }
 
         // register local state
         self.register_query(query_name, Some(qg), &mir, universe);
@@ -608,6 +925,92 @@ impl SqlIncorporator {
         }
     }
 
+    /// Rename the column `old` of base table `table` to `new`, in the table's schema and MIR, and
+    /// in the schemas of the queries whose leaves are among the nodes in `renamed`.
+    pub(super) fn rename_column(
+        &mut self,
+        table: &str,
+        old: &str,
+        new: &str,
+        renamed: &[(NodeIndex, usize)],
+    ) {
+        if let Some(ctq) = self.base_schemas.get_mut(table) {
+            for cs in &mut ctq.fields {
+                if cs.column.name == old {
+                    cs.column.name = new.to_owned();
+                }
+            }
+        }
+
+        // later versions of the table are compared against the column specifications of its
+        // MIR node, so those have to change too
+        if let Some(mir) = self.base_mir_queries.get(table) {
+            let mut base = mir.leaf.borrow_mut();
+            for c in &mut base.columns {
+                if c.name == old {
+                    c.name = new.to_owned();
+                }
+            }
+            if let MirNodeType::Base {
+                ref mut column_specs,
+                ..
+            } = base.inner
+            {
+                for &mut (ref mut cs, _) in column_specs.iter_mut() {
+                    if cs.column.name == old {
+                        cs.column.name = new.to_owned();
+                    }
+                }
+            }
+        }
+
+        let mut schemas = vec![(table.to_owned(), None)];
+        for (query, &leaf) in &self.leaf_addresses {
+            for &(ni, c) in renamed {
+                if ni == leaf {
+                    schemas.push((query.clone(), Some(c)));
+                }
+            }
+        }
+        for (name, column) in schemas {
+            if let Some(fields) = self.view_schemas.get_mut(&name) {
+                match column {
+                    Some(c) => fields[c] = new.to_owned(),
+                    None => {
+                        for f in fields.iter_mut().filter(|f| f.as_str() == old) {
+                            *f = new.to_owned();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub(super) fn alter_column_type(&mut self, table: &str, column: &str, ty: SqlType) {
+        if let Some(ctq) = self.base_schemas.get_mut(table) {
+            for cs in &mut ctq.fields {
+                if cs.column.name == column {
+                    cs.sql_type = ty.clone();
+                }
+            }
+        }
+
+        if let Some(mir) = self.base_mir_queries.get(table) {
+            let mut base = mir.leaf.borrow_mut();
+            if let MirNodeType::Base {
+                ref mut column_specs,
+                ..
+            } = base.inner
+            {
+                for &mut (ref mut cs, _) in column_specs.iter_mut() {
+                    if cs.column.name == column {
+                        cs.sql_type = ty.clone();
+                    }
+                }
+            }
+        }
+    }
+
     pub(super) fn remove_base(&mut self, name: &str) {
         info!(self.log, "Removing base {} from SqlIncorporator", name);
         if self.base_schemas.remove(name).is_none() {
@@ -734,11 +1137,22 @@ impl SqlIncorporator {
 
         let qfp =
             mir_query_to_flow_parts(&mut post_reuse_opt_mir, &mut mig, table_mapping.as_ref());
+        if !sec {
+            let plan = plan_hash(query, &qg, is_leaf);
+            self.plans.insert(
+                (query_name.to_owned(), plan, universe.clone()),
+                post_reuse_opt_mir.clone(),
+            );
+        }
 
         info!(
             self.log,
             "Reused {} nodes for {}", num_reused_nodes, query_name
         );
+        if num_reused_nodes == 0 {
+            let reason = "the queries it could extend share no nodes with it".to_owned();
+            self.reject_reuse(query_name, reason);
+        }
 
         // register local state
         self.register_query(query_name, Some(qg), &post_reuse_opt_mir, universe);
@@ -770,6 +1184,7 @@ impl SqlIncorporator {
         use passes::implied_tables::ImpliedTableExpansion;
         use passes::key_def_coalescing::KeyDefinitionCoalescing;
         use passes::negation_removal::NegationRemoval;
+        use passes::soft_delete::SoftDeleteFiltering;
         use passes::star_expansion::StarExpansion;
         use passes::subqueries::SubQueries;
         use query_utils::ReferredTables;
@@ -850,6 +1265,7 @@ impl SqlIncorporator {
             .coalesce_key_definitions()
             .expand_stars(&self.view_schemas)
             .expand_implied_tables(&self.view_schemas)
+            .hide_soft_deleted(&self.soft_deleted)
             .rewrite_count_star(&self.view_schemas))
     }
 
@@ -893,7 +1309,15 @@ impl SqlIncorporator {
                 self.add_compound_query(&query_name, &csq, is_leaf, mig)
                     .unwrap()
             }
-            SqlQuery::Select(sq) => self.add_select_query(&query_name, &sq, is_leaf, mig)?.0,
+            SqlQuery::Select(sq) => {
+                if let Some(depth) = self.recursions.get(&query_name).cloned() {
+                    self.add_recursive_query(&query_name, &sq, depth, is_leaf, mig)?
+                } else if let Some(top_n) = self.top_n.get(&query_name).cloned() {
+                    self.add_top_n_query(&query_name, &sq, top_n, is_leaf, mig)?
+                } else {
+                    self.add_select_query(&query_name, &sq, is_leaf, mig)?.0
+                }
+            }
             ref q @ SqlQuery::CreateTable { .. } => self.add_base_via_mir(&query_name, &q, mig),
             q => panic!("unhandled query type in recipe: {:?}", q),
         };
@@ -918,6 +1342,18 @@ impl SqlIncorporator {
     }
 }
 
+/// A hash of everything that goes into planning `query`, whose query graph is `qg`.
+fn plan_hash(query: &SelectStatement, qg: &QueryGraph, is_leaf: bool) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut h = DefaultHasher::new();
+    query.hash(&mut h);
+    qg.exact_hash().hash(&mut h);
+    is_leaf.hash(&mut h);
+    h.finish()
+}
+
 /// Enables incorporation of a textual SQL query into a Soup graph.
 trait ToFlowParts {
     /// Turn a SQL query into a set of nodes inserted into the Soup graph managed by
@@ -1247,6 +1683,32 @@ mod tests {
         });
     }
 
+    #[test]
+    fn it_reuses_plans_of_removed_queries() {
+        let mut g = integration::start_simple("it_reuses_plans_of_removed_queries");
+        g.migrate(|mig| {
+            let mut inc = SqlIncorporator::default();
+            assert!(inc
+                .add_query("CREATE TABLE users (id int, name varchar(40));", None, mig)
+                .is_ok());
+            let q = "SELECT id, name FROM users WHERE users.id = ?;";
+            let leaf = inc
+                .add_query(q, Some("byid".into()), mig)
+                .unwrap()
+                .query_leaf;
+            assert_eq!(inc.plans.len(), 1);
+            assert_eq!(inc.remove_query("byid", mig), Some(leaf));
+
+            // adding the query back uses the plan made for it before, but gets it new nodes
+            inc.upgrade_schema(1);
+            let qfp = inc.add_query(q, Some("byid".into()), mig).unwrap();
+            assert_eq!(inc.plans.len(), 1);
+            assert_ne!(qfp.query_leaf, leaf);
+            assert_eq!(inc.get_flow_node_address("byid", 1), Some(qfp.query_leaf));
+            assert_eq!(mig.graph()[qfp.query_leaf].fields(), &["id", "name"]);
+        });
+    }
+
     #[test]
     fn it_reuses_with_different_parameter() {
         // set up graph
@@ -1579,6 +2041,35 @@ mod tests {
         });
     }
 
+    #[test]
+    fn it_orders_joins_as_asked() {
+        let mut g = integration::start_simple("it_orders_joins_as_asked");
+        g.migrate(|mig| {
+            let mut inc = SqlIncorporator::default();
+            for q in &[
+                "CREATE TABLE users (id int, name varchar(40));",
+                "CREATE TABLE votes (aid int, uid int);",
+                "CREATE TABLE articles (aid int, title varchar(255), author int);",
+            ] {
+                assert!(inc.add_query(q, None, mig).is_ok());
+            }
+
+            let q = "SELECT users.name, articles.title, votes.uid \
+                     FROM articles, users, votes \
+                     WHERE users.id = articles.author AND votes.aid = articles.aid;";
+            let order = |tables: &[&str]| tables.iter().map(|&t| t.to_owned()).collect();
+            inc.order_joins("votes_first", order(&["votes", "articles", "users"]));
+            assert!(inc.add_query(q, Some("votes_first".into()), mig).is_ok());
+            let qg = &inc.query_graphs[&inc.named_queries["votes_first"]];
+            let first = &qg.join_order[0];
+            assert!(first.src == "votes" || first.dst == "votes");
+
+            // tables the query does not join cannot be ordered
+            inc.order_joins("bad", order(&["votes", "stories"]));
+            assert!(inc.add_query(q, Some("bad".into()), mig).is_err());
+        });
+    }
+
     #[test]
     #[allow_fail]
     fn it_incorporates_join_projecting_join_columns() {
@@ -1765,6 +2256,35 @@ mod tests {
         });
     }
 
+    #[test]
+    fn it_incorporates_compound_intersection() {
+        // set up graph
+        let mut g = integration::start_simple("it_incorporates_compound_intersection");
+        g.migrate(|mig| {
+            let mut inc = SqlIncorporator::default();
+            assert!(inc
+                .add_query("CREATE TABLE users (id int, name varchar(40));", None, mig)
+                .is_ok());
+
+            let res = inc.add_query(
+                "SELECT users.id, users.name FROM users \
+                 WHERE users.id = 32 \
+                 INTERSECT \
+                 SELECT users.id, users.name FROM users \
+                 WHERE users.name = 'bob';",
+                None,
+                mig,
+            );
+            assert!(res.is_ok());
+
+            // the leaf of this query is the set operator, which drops the column that tells the
+            // two inputs apart
+            let set_view = get_node(&inc, mig, &res.unwrap().name);
+            assert_eq!(set_view.fields(), &["id", "name"]);
+            assert_eq!(set_view.description(true), "∩");
+        });
+    }
+
     #[test]
     fn it_distinguishes_predicates() {
         // set up graph
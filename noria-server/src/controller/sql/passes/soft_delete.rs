@@ -0,0 +1,137 @@
+use nom_sql::{
+    Column, ConditionBase, ConditionExpression, ConditionTree, JoinOperator, JoinRightSide,
+    Literal, Operator, SelectStatement, SqlQuery, Table,
+};
+
+use std::collections::HashMap;
+
+pub trait SoftDeleteFiltering {
+    fn hide_soft_deleted(self, soft_deleted: &HashMap<String, String>) -> SqlQuery;
+}
+
+/// Whether `ce` says anything about `column` of `table`.
+fn mentions(ce: &ConditionExpression, table: &str, column: &str) -> bool {
+    match *ce {
+        ConditionExpression::LogicalOp(ConditionTree {
+            box ref left,
+            box ref right,
+            ..
+        })
+        | ConditionExpression::ComparisonOp(ConditionTree {
+            box ref left,
+            box ref right,
+            ..
+        }) => mentions(left, table, column) || mentions(right, table, column),
+        ConditionExpression::NegationOp(ref inner) | ConditionExpression::Bracketed(ref inner) => {
+            mentions(inner, table, column)
+        }
+        ConditionExpression::Base(ConditionBase::Field(ref f)) => {
+            f.name == column && f.table.as_ref().map(|t| t == table).unwrap_or(false)
+        }
+        ConditionExpression::Base(_) => false,
+    }
+}
+
+fn hide_in_select(
+    mut sq: SelectStatement,
+    soft_deleted: &HashMap<String, String>,
+) -> SelectStatement {
+    // the rows of tables that are left joined in are not filtered, since that would drop the rows
+    // they are joined to as well
+    let mut tables: Vec<Table> = sq.tables.clone();
+    for jc in &sq.join {
+        if jc.operator == JoinOperator::LeftJoin || jc.operator == JoinOperator::LeftOuterJoin {
+            continue;
+        }
+        match jc.right {
+            JoinRightSide::Table(ref t) => tables.push(t.clone()),
+            JoinRightSide::Tables(ref ts) => tables.extend(ts.iter().cloned()),
+            _ => {}
+        }
+    }
+
+    for t in tables {
+        let column = match soft_deleted.get(&t.name) {
+            Some(column) => column,
+            None => continue,
+        };
+        // queries that ask about the column themselves see the rows that are marked as deleted
+        if let Some(ref wc) = sq.where_clause {
+            if mentions(wc, &t.name, column) {
+                continue;
+            }
+        }
+
+        let live = ConditionExpression::ComparisonOp(ConditionTree {
+            operator: Operator::Equal,
+            left: Box::new(ConditionExpression::Base(ConditionBase::Field(Column {
+                name: column.clone(),
+                alias: None,
+                table: Some(t.name.clone()),
+                function: None,
+            }))),
+            right: Box::new(ConditionExpression::Base(ConditionBase::Literal(
+                Literal::Null,
+            ))),
+        });
+        sq.where_clause = Some(match sq.where_clause.take() {
+            None => live,
+            Some(wc) => ConditionExpression::LogicalOp(ConditionTree {
+                operator: Operator::And,
+                left: Box::new(wc),
+                right: Box::new(live),
+            }),
+        });
+    }
+    sq
+}
+
+impl SoftDeleteFiltering for SqlQuery {
+    fn hide_soft_deleted(self, soft_deleted: &HashMap<String, String>) -> SqlQuery {
+        if soft_deleted.is_empty() {
+            return self;
+        }
+        match self {
+            SqlQuery::Select(sq) => SqlQuery::Select(hide_in_select(sq, soft_deleted)),
+            SqlQuery::CompoundSelect(mut csq) => {
+                csq.selects = csq
+                    .selects
+                    .into_iter()
+                    .map(|(op, sq)| (op, hide_in_select(sq, soft_deleted)))
+                    .collect();
+                SqlQuery::CompoundSelect(csq)
+            }
+            q => q,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom_sql::parser::parse_query;
+
+    fn soft_deleted() -> HashMap<String, String> {
+        let mut soft_deleted = HashMap::new();
+        soft_deleted.insert("posts".to_owned(), "deleted_at".to_owned());
+        soft_deleted
+    }
+
+    #[test]
+    fn it_hides_soft_deleted_rows() {
+        let q = parse_query("SELECT posts.id FROM posts WHERE posts.author = ?;").unwrap();
+        let expected = parse_query(
+            "SELECT posts.id FROM posts WHERE posts.author = ? AND posts.deleted_at = NULL;",
+        )
+        .unwrap();
+        assert_eq!(q.hide_soft_deleted(&soft_deleted()), expected);
+    }
+
+    #[test]
+    fn it_leaves_queries_about_deletes_alone() {
+        let q = parse_query("SELECT posts.id FROM posts WHERE posts.deleted_at != NULL;").unwrap();
+        assert_eq!(q.clone().hide_soft_deleted(&soft_deleted()), q);
+        let q = parse_query("SELECT users.id FROM users WHERE users.id = ?;").unwrap();
+        assert_eq!(q.clone().hide_soft_deleted(&soft_deleted()), q);
+    }
+}
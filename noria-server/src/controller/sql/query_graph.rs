@@ -188,6 +188,18 @@ impl QueryGraph {
             })
     }
 
+    /// Make the joins of this query in the order that brings in the tables in `tables` one after
+    /// another. Joins with tables that are not listed are made last.
+    pub fn order_joins(&mut self, tables: &[String]) -> Result<(), String> {
+        if let Some(t) = tables.iter().find(|t| !self.relations.contains_key(*t)) {
+            return Err(format!("cannot order joins by {}, which is not joined", t));
+        }
+        let position = |t: &str| tables.iter().position(|o| o == t).unwrap_or(tables.len());
+        self.join_order
+            .sort_by_key(|j| position(&j.src).max(position(&j.dst)));
+        Ok(())
+    }
+
     pub fn exact_hash(&self) -> u64 {
         use std::collections::hash_map::DefaultHasher;
 
@@ -2,10 +2,13 @@ use crate::controller::security::SecurityConfig;
 use crate::controller::sql::query_graph::{to_query_graph, QueryGraph};
 use crate::controller::sql::{QueryFlowParts, SqlIncorporator};
 use crate::controller::Migration;
+use dataflow::ops::project::ColumnMask;
 use dataflow::prelude::DataType;
 use nom_sql::parser as sql_parser;
 use nom_sql::SqlQuery;
+use petgraph::graph::NodeIndex;
 use std::collections::HashMap;
+use std::mem;
 
 #[derive(Clone, Debug)]
 pub(super) struct Universe {
@@ -14,6 +17,8 @@ pub(super) struct Universe {
     pub(super) member_of: HashMap<String, Vec<DataType>>,
     pub(super) row_policies: HashMap<String, Vec<QueryGraph>>,
     pub(super) rewrite_policies: HashMap<String, Vec<RewritePolicy>>,
+    /// Per table, the columns that must be redacted before reaching this universe's readers.
+    pub(super) mask_policies: HashMap<String, Vec<(String, ColumnMask)>>,
 }
 
 impl Default for Universe {
@@ -24,6 +29,7 @@ impl Default for Universe {
             member_of: HashMap::default(),
             row_policies: HashMap::default(),
             rewrite_policies: HashMap::default(),
+            mask_policies: HashMap::default(),
         }
     }
 }
@@ -53,6 +59,119 @@ pub(in crate::controller) trait Multiverse {
         fields: &mut Vec<String>,
         mig: &mut Migration,
     ) -> QueryFlowParts;
+
+    /// Install a query in the universe that `mig` operates in, subject to the universe's quota.
+    fn add_universe_query(
+        &mut self,
+        query: SqlQuery,
+        name: Option<String>,
+        is_leaf: bool,
+        mig: &mut Migration,
+    ) -> Result<QueryFlowParts, String>;
+
+    /// Install a query that is added after universes have been created in each of those
+    /// universes, subject to that universe's policies.
+    ///
+    /// Without this, clients of existing universes could only read the new query through its
+    /// global, unfiltered view.
+    fn add_to_universes(
+        &mut self,
+        name: Option<String>,
+        query: SqlQuery,
+        is_leaf: bool,
+        mig: &mut Migration,
+    ) -> Result<Vec<QueryFlowParts>, String>;
+}
+
+impl SqlIncorporator {
+    /// Record that user `uid` joined or left group `gid` of kind `group`, so that queries later
+    /// installed in the user's universe include exactly the group universes they are a member of.
+    ///
+    /// Returns `false` if no universe has been created for the user yet.
+    pub(in crate::controller) fn update_group_membership(
+        &mut self,
+        group: &str,
+        uid: &DataType,
+        gid: &DataType,
+        member: bool,
+    ) -> bool {
+        let universe = self
+            .prepared_universes
+            .iter_mut()
+            .map(|&mut (_, ref mut u)| u)
+            .find(|u| u.from_group.is_none() && u.id == *uid);
+
+        match universe {
+            Some(universe) => {
+                let gids = universe
+                    .member_of
+                    .entry(group.to_owned())
+                    .or_insert_with(Vec::new);
+                gids.retain(|g| g != gid);
+                if member {
+                    gids.push(gid.clone());
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget the universe that `mig` operates in, and remove the queries installed in it.
+    ///
+    /// Returns the leaves of the removed queries that no other query uses. The universe's context
+    /// base table is left in place.
+    pub(in crate::controller) fn remove_universe(&mut self, mig: &Migration) -> Vec<NodeIndex> {
+        let (id, group) = mig.universe();
+        let universe = (id.clone(), group.clone());
+
+        self.prepared_universes
+            .retain(|&(ref context, _)| context != mig.context());
+        if let Some(ids) = self.universes.get_mut(&group) {
+            ids.retain(|u| *u != universe);
+        }
+        self.universes.retain(|_, ids| !ids.is_empty());
+        self.universe_queries.remove(&universe_name(&id, &group));
+
+        let mut names: Vec<_> = self
+            .leaf_addresses
+            .keys()
+            .filter(|n| match self.named_queries.get(*n) {
+                Some(h) => self.mir_queries.contains_key(&(*h, universe.clone())),
+                None => false,
+            })
+            .cloned()
+            .collect();
+        names.sort();
+        names
+            .into_iter()
+            .filter_map(|n| self.remove_query(&n, mig))
+            .collect()
+    }
+
+    fn over_query_quota(&self, universe: &str) -> bool {
+        match self.max_universe_queries {
+            Some(max) => self.universe_queries.get(universe).cloned().unwrap_or(0) >= max,
+            None => false,
+        }
+    }
+}
+
+/// A name that identifies the given universe, such as `u1` for the universe of user 1.
+pub(in crate::controller) fn universe_name(id: &DataType, group: &Option<DataType>) -> String {
+    match *group {
+        Some(ref g) => format!("{}{}", g.to_string(), id.to_string()),
+        None => format!("u{}", id.to_string()),
+    }
+}
+
+/// The name under which the query `name` is installed in the given universe.
+pub(in crate::controller) fn universe_query_name(
+    name: &str,
+    id: &DataType,
+    group: &Option<DataType>,
+) -> String {
+    format!("{}_{}", name, universe_name(id, group))
 }
 
 impl Multiverse for SqlIncorporator {
@@ -73,6 +192,7 @@ impl Multiverse for SqlIncorporator {
             member_of: universe_groups,
             row_policies: HashMap::new(),
             rewrite_policies: HashMap::new(),
+            mask_policies: HashMap::new(),
         };
 
         // Create the UserContext base node.
@@ -105,6 +225,15 @@ impl Multiverse for SqlIncorporator {
         // e.g. if they reference UserContext.
         let mut row_policies_qg: HashMap<String, Vec<QueryGraph>> = HashMap::new();
         for policy in universe_policies {
+            if policy.is_mask_policy() {
+                universe
+                    .mask_policies
+                    .entry(policy.table())
+                    .or_insert_with(Vec::new)
+                    .push((policy.column(), policy.mask()));
+                continue;
+            }
+
             if !policy.is_row_policy() {
                 let qfp = self
                     .add_parsed_query(policy.predicate(), None, false, mig)
@@ -153,6 +282,8 @@ impl Multiverse for SqlIncorporator {
         let e = self.universes.entry(group.clone()).or_insert_with(Vec::new);
         e.push((id, group));
 
+        self.prepared_universes
+            .push((mig.context().clone(), universe.clone()));
         self.mir_converter.set_universe(universe);
 
         Ok(qfps)
@@ -184,4 +315,67 @@ impl Multiverse for SqlIncorporator {
         self.add_parsed_query(parsed_query, Some(name), false, mig)
             .unwrap()
     }
+
+    fn add_universe_query(
+        &mut self,
+        query: SqlQuery,
+        name: Option<String>,
+        is_leaf: bool,
+        mig: &mut Migration,
+    ) -> Result<QueryFlowParts, String> {
+        let (id, group) = mig.universe();
+        let universe = universe_name(&id, &group);
+        if self.over_query_quota(&universe) {
+            return Err(format!(
+                "universe {} has reached its quota of {} queries",
+                universe,
+                self.max_universe_queries.unwrap()
+            ));
+        }
+
+        let qfp = self.add_parsed_query(query, name, is_leaf, mig)?;
+        *self.universe_queries.entry(universe).or_insert(0) += 1;
+        Ok(qfp)
+    }
+
+    fn add_to_universes(
+        &mut self,
+        name: Option<String>,
+        query: SqlQuery,
+        is_leaf: bool,
+        mig: &mut Migration,
+    ) -> Result<Vec<QueryFlowParts>, String> {
+        // user universes union in the results of their groups' universes, so the group universe
+        // versions of the query must exist first.
+        let mut universes = self.prepared_universes.clone();
+        universes.sort_by_key(|&(_, ref u)| u.from_group.is_none());
+
+        let mut qfps = Vec::new();
+        for (context, universe) in universes {
+            // the migration's context determines which universe the query is added to
+            let context = mem::replace(&mut mig.context, context);
+            self.mir_converter.set_universe(universe);
+
+            let (id, group) = mig.universe();
+            let name = name.as_ref().map(|n| universe_query_name(n, &id, &group));
+            let is_leaf = if group.is_some() { false } else { is_leaf };
+            let qfp = self.add_universe_query(query.clone(), name, is_leaf, mig);
+
+            mig.context = context;
+            match qfp {
+                Ok(qfp) => qfps.push(qfp),
+                Err(ref e) if self.over_query_quota(&universe_name(&id, &group)) => {
+                    // the rest of the migration shouldn't fail just because one universe is full
+                    warn!(self.log, "not installing query in universe: {}", e);
+                }
+                Err(e) => {
+                    self.mir_converter.clear_universe();
+                    return Err(e);
+                }
+            }
+        }
+
+        self.mir_converter.clear_universe();
+        Ok(qfps)
+    }
 }
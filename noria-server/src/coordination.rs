@@ -26,6 +26,12 @@ pub enum CoordinationPayload {
         read_listen_addr: SocketAddr,
         /// Which log files are stored locally on the worker.
         log_files: Vec<String>,
+        /// The labels the worker was started with, which decide what may be placed on it.
+        labels: HashMap<String, String>,
+        /// How much the worker can take on compared to other workers.
+        capacity: Capacity,
+        /// Whether the worker only runs domains that hold nothing but readers.
+        reader_only: bool,
     },
     /// Worker going offline.
     Deregister,
@@ -39,6 +45,35 @@ pub enum CoordinationPayload {
     DomainBooted(DomainDescriptor),
     /// Create a new security universe.
     CreateUniverse(HashMap<String, DataType>),
+    /// Change the settings of a running worker.
+    UpdateConfig {
+        /// The number of bytes of state to keep below, or 0 for no limit.
+        memory_limit: usize,
+    },
+    /// Inject a fault into the domains on a worker.
+    #[cfg(feature = "fault-injection")]
+    InjectFault(crate::Fault),
+    /// Remove all faults injected into the domains on a worker.
+    #[cfg(feature = "fault-injection")]
+    ClearFaults,
+}
+
+/// The resources a worker has for running domains.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Capacity {
+    /// The number of cores the worker may use.
+    pub cores: usize,
+    /// The number of bytes of memory the worker may use, or 0 if unknown.
+    pub memory: usize,
+}
+
+impl Default for Capacity {
+    fn default() -> Self {
+        Capacity {
+            cores: 1,
+            memory: 0,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
@@ -0,0 +1,180 @@
+//! Injecting faults into the dataflow, so that tests can exercise recovery and replay logic.
+//!
+//! Faults can only be injected when the `fault-injection` feature is enabled. Without it, the
+//! hooks in this module never report a fault.
+
+#[cfg(feature = "fault-injection")]
+use futures::task::{self, Task};
+use noria::internal::DomainIndex;
+#[cfg(feature = "fault-injection")]
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A fault to inject into the dataflow.
+///
+/// Domains are given by their index, and a fault applies to every shard of the domains it names.
+/// A domain of `None` matches any domain. Faults on packets only affect packets that carry data,
+/// that is, regular updates and replay pieces.
+#[cfg(feature = "fault-injection")]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[allow(missing_docs)]
+pub enum Fault {
+    /// Drop packets that `from` sends to `to`.
+    Drop {
+        from: Option<DomainIndex>,
+        to: Option<DomainIndex>,
+    },
+    /// Hold back packets that `from` sends to `to` for the given time, so that later packets can
+    /// overtake them.
+    Delay {
+        from: Option<DomainIndex>,
+        to: Option<DomainIndex>,
+        by: Duration,
+    },
+    /// Deliver the packets that `from` sends to `to` twice.
+    Duplicate {
+        from: Option<DomainIndex>,
+        to: Option<DomainIndex>,
+    },
+    /// Make `domain` fail once it has processed another `after` packets.
+    Crash { domain: DomainIndex, after: usize },
+    /// Withhold the acknowledgements of writes to base tables in `domain`.
+    StallAcks { domain: Option<DomainIndex> },
+}
+
+#[cfg(feature = "fault-injection")]
+fn matches(pattern: Option<DomainIndex>, domain: DomainIndex) -> bool {
+    pattern.map(|p| p == domain).unwrap_or(true)
+}
+
+/// What to do with a packet on its way to another domain.
+#[cfg_attr(not(feature = "fault-injection"), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+crate enum Delivery {
+    Deliver,
+    Drop,
+    Duplicate,
+    Delay(Duration),
+}
+
+/// The faults that are currently injected into the domains on a worker.
+#[derive(Default)]
+crate struct Faults {
+    #[cfg(feature = "fault-injection")]
+    inner: Mutex<(Vec<Fault>, Vec<Task>)>,
+}
+
+#[cfg_attr(not(feature = "fault-injection"), allow(unused_variables))]
+impl Faults {
+    /// Inject `fault`, in addition to those that are already injected.
+    #[cfg(feature = "fault-injection")]
+    crate fn inject(&self, fault: Fault) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.0.push(fault);
+        inner.1.drain(..).for_each(|t| t.notify());
+    }
+
+    /// Remove all injected faults.
+    #[cfg(feature = "fault-injection")]
+    crate fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.0.clear();
+        inner.1.drain(..).for_each(|t| t.notify());
+    }
+
+    /// What should happen to a data packet that `from` sends to `to`.
+    crate fn on_send(&self, from: DomainIndex, to: DomainIndex) -> Delivery {
+        #[cfg(feature = "fault-injection")]
+        {
+            for fault in &self.inner.lock().unwrap().0 {
+                match *fault {
+                    Fault::Drop { from: f, to: t } if matches(f, from) && matches(t, to) => {
+                        return Delivery::Drop;
+                    }
+                    Fault::Delay { from: f, to: t, by } if matches(f, from) && matches(t, to) => {
+                        return Delivery::Delay(by);
+                    }
+                    Fault::Duplicate { from: f, to: t } if matches(f, from) && matches(t, to) => {
+                        return Delivery::Duplicate;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Delivery::Deliver
+    }
+
+    /// Note that `domain` is about to process a packet, and return whether it should crash
+    /// instead.
+    crate fn crash(&self, domain: DomainIndex) -> bool {
+        #[cfg(feature = "fault-injection")]
+        {
+            for fault in &mut self.inner.lock().unwrap().0 {
+                if let Fault::Crash {
+                    domain: d,
+                    ref mut after,
+                } = *fault
+                {
+                    if d == domain {
+                        if *after == 0 {
+                            return true;
+                        }
+                        *after -= 1;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Whether `domain` should withhold its acknowledgements for now.
+    ///
+    /// If it should, the current task is notified when the injected faults change.
+    crate fn acks_stalled(&self, domain: DomainIndex) -> bool {
+        #[cfg(feature = "fault-injection")]
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let stalled = inner.0.iter().any(|fault| match *fault {
+                Fault::StallAcks { domain: d } => matches(d, domain),
+                _ => false,
+            });
+            if stalled {
+                inner.1.push(task::current());
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(all(test, feature = "fault-injection"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faults_match_domains() {
+        let a = DomainIndex::new(0);
+        let b = DomainIndex::new(1);
+        let faults = Faults::default();
+        assert_eq!(faults.on_send(a, b), Delivery::Deliver);
+
+        faults.inject(Fault::Drop {
+            from: Some(a),
+            to: None,
+        });
+        assert_eq!(faults.on_send(a, b), Delivery::Drop);
+        assert_eq!(faults.on_send(b, a), Delivery::Deliver);
+
+        faults.inject(Fault::Crash {
+            domain: b,
+            after: 1,
+        });
+        assert!(!faults.crash(a));
+        assert!(!faults.crash(b));
+        assert!(faults.crash(b));
+
+        faults.clear();
+        assert_eq!(faults.on_send(a, b), Delivery::Deliver);
+        assert!(!faults.crash(b));
+    }
+}
@@ -1,5 +1,6 @@
 #[cfg(test)]
 use crate::controller::migrate::Migration;
+use crate::controller::{GroupMembershipUpdate, MembershipWrite};
 use crate::startup::Event;
 use dataflow::prelude::*;
 use noria::consensus::Authority;
@@ -37,11 +38,13 @@ impl<A: Authority> DerefMut for Handle<A> {
 impl<A: Authority + 'static> Handle<A> {
     pub(super) fn new(
         authority: Arc<A>,
+        token: Option<String>,
+        tls: Option<Arc<rustls::ClientConfig>>,
         event_tx: futures::sync::mpsc::UnboundedSender<Event>,
         kill: Trigger,
         io: tokio_io_pool::Runtime,
     ) -> impl Future<Item = Self, Error = failure::Error> {
-        ControllerHandle::make(authority).map(move |c| Handle {
+        ControllerHandle::make_with_tls(authority, token, tls).map(move |c| Handle {
             c: Some(c),
             event_tx: Some(event_tx),
             kill: Some(kill),
@@ -99,6 +102,19 @@ impl<A: Authority + 'static> Handle<A> {
         }
     }
 
+    /// Make the next migration fail just before it sets up its materializations, by panicking if
+    /// `panic` is set and with an error otherwise.
+    #[cfg(test)]
+    crate fn fail_next_migration(&mut self, panic: bool) {
+        let (tx, rx) = futures::sync::oneshot::channel();
+        self.event_tx
+            .clone()
+            .unwrap()
+            .unbounded_send(Event::FailNextMigration(panic, tx))
+            .unwrap();
+        rx.wait().unwrap();
+    }
+
     /// Install a new set of policies on the controller.
     #[must_use]
     pub fn set_security_config(
@@ -145,6 +161,137 @@ impl<A: Authority + 'static> Handle<A> {
         })
     }
 
+    /// Remove the security universe with the given context, and the queries installed in it.
+    #[must_use]
+    pub fn remove_universe(
+        &mut self,
+        context: HashMap<String, DataType>,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.rpc(
+            "remove_universe",
+            &context,
+            "failed to remove security universe",
+        )
+    }
+
+    /// Inject `fault` into the domains on every worker.
+    #[cfg(feature = "fault-injection")]
+    #[must_use]
+    pub fn inject_fault(
+        &mut self,
+        fault: crate::Fault,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.rpc("inject_fault", fault, "failed to inject fault")
+    }
+
+    /// Remove all faults injected into domains.
+    #[cfg(feature = "fault-injection")]
+    #[must_use]
+    pub fn clear_faults(&mut self) -> impl Future<Item = (), Error = failure::Error> {
+        self.rpc("clear_faults", (), "failed to clear faults")
+    }
+
+    /// Add user `uid` to the security group `gid` of kind `group`.
+    ///
+    /// This writes the membership to the base table that the group's membership query reads
+    /// from, so the group's views are updated incrementally rather than reinstalled.
+    #[must_use]
+    pub fn add_group_member(
+        &mut self,
+        group: &str,
+        uid: DataType,
+        gid: DataType,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.update_group_membership(group, uid, gid, true)
+    }
+
+    /// Remove user `uid` from the security group `gid` of kind `group`.
+    ///
+    /// This fails if the user is not a member of that group, rather than deleting whatever row
+    /// of the membership base table has the same key.
+    #[must_use]
+    pub fn remove_group_member(
+        &mut self,
+        group: &str,
+        uid: DataType,
+        gid: DataType,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.update_group_membership(group, uid, gid, false)
+    }
+
+    fn update_group_membership(
+        &mut self,
+        group: &str,
+        uid: DataType,
+        gid: DataType,
+        member: bool,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        let mut c = self.c.clone().unwrap();
+        let update = GroupMembershipUpdate {
+            group: group.to_owned(),
+            uid,
+            gid,
+            member,
+        };
+
+        self.rpc::<_, MembershipWrite>(
+            "membership_write",
+            &update,
+            "failed to update group membership",
+        )
+        .and_then(move |write| {
+            let MembershipWrite {
+                table: base,
+                row,
+                key,
+                fields: (u, g),
+            } = write;
+
+            // a row is only deleted if it is the one that makes the user a member of the group,
+            // and not some other row with the same key
+            let check = if member {
+                future::Either::A(future::ok(()))
+            } else {
+                let (uid, gid) = (update.uid.clone(), update.gid.clone());
+                future::Either::B(
+                    c.view(&update.group)
+                        .and_then(|view| {
+                            view.lookup(&[0.into()], true).map_err(|e| {
+                                format_err!("failed to look up group membership: {:?}", e)
+                            })
+                        })
+                        .and_then(move |(_, rows)| {
+                            if rows.iter().any(|r| r[u] == uid && r[g] == gid) {
+                                Ok(())
+                            } else {
+                                Err(format_err!("user {} is not a member of group {}", uid, gid))
+                            }
+                        }),
+                )
+            };
+
+            let mut ctrl = c.clone();
+            check
+                .and_then(move |()| c.table(&base))
+                .and_then(move |table| {
+                    if member {
+                        future::Either::A(table.insert(row))
+                    } else {
+                        future::Either::B(table.delete(key))
+                    }
+                    .map_err(|e| format_err!("failed to write group membership: {:?}", e))
+                })
+                // the recipe only learns of the change once the write has been acknowledged
+                .and_then(move |_| {
+                    ctrl.rpc::<_, ()>(
+                        "group_membership",
+                        update,
+                        "failed to update group membership",
+                    )
+                })
+        })
+    }
+
     /// Inform the local instance that it should exit.
     pub fn shutdown(&mut self) {
         if let Some(io) = self.iopool.take() {
@@ -223,6 +370,16 @@ impl<A: Authority> SyncHandle<A> {
         self.on_worker(move |w| -> Result<_, ()> { Ok(w.migrate(f)) })
             .unwrap()
     }
+
+    /// See `Handle::fail_next_migration`.
+    #[cfg(test)]
+    crate fn fail_next_migration(&mut self, panic: bool) {
+        self.on_worker(move |w| -> Result<_, ()> {
+            w.fail_next_migration(panic);
+            Ok(())
+        })
+        .unwrap()
+    }
 }
 
 impl<A: Authority> Deref for SyncHandle<A> {
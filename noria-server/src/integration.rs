@@ -1,16 +1,19 @@
 use crate::controller::recipe::Recipe;
 use crate::controller::sql::SqlIncorporator;
 use crate::{Builder, Handle, SyncHandle};
-use dataflow::node::special::Base;
+use dataflow::node::special::{Base, Cast};
 use dataflow::ops::grouped::aggregate::Aggregation;
 use dataflow::ops::identity::Identity;
 use dataflow::ops::join::JoinSource::*;
 use dataflow::ops::join::{Join, JoinSource, JoinType};
+use dataflow::ops::positional::Position;
 use dataflow::ops::project::Project;
 use dataflow::ops::union::Union;
-use dataflow::{DurabilityMode, PersistenceParameters};
+use dataflow::{DurabilityMode, Overflow, PersistenceParameters};
 use futures::Future;
+use noria::cluster::{MigrationStep, NewWorker, PlannedWorker, SubmittedMigration, WorkerChange};
 use noria::consensus::{Authority, LocalAuthority};
+use noria::prepared::Executed;
 use noria::DataType;
 
 use std::collections::HashMap;
@@ -488,6 +491,41 @@ fn it_works_deletion() {
     );
 }
 
+#[test]
+fn it_works_with_positional_values() {
+    let mut g = start_simple_unsharded("it_works_with_positional_values");
+    g.migrate(|mig| {
+        let a = mig.add_base(
+            "a",
+            &["id", "entity", "ts", "status"],
+            Base::new(vec![]).with_key(vec![0]),
+        );
+        let c = mig.add_ingredient("c", &["entity", "status"], Position::LAST.of(a, 3, 2, &[1]));
+        mig.maintain_anonymous(c, &[0]);
+    });
+
+    let mut cq = g.view("c").unwrap().into_sync();
+    let mut muta = g.table("a").unwrap().into_sync();
+
+    muta.insert(vec![1.into(), 1.into(), 10.into(), "open".into()])
+        .unwrap();
+    muta.insert(vec![2.into(), 1.into(), 20.into(), "closed".into()])
+        .unwrap();
+    sleep();
+    assert_eq!(
+        cq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "closed".into()]]
+    );
+
+    // removing the latest status brings back the one before it
+    muta.delete(vec![2.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        cq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "open".into()]]
+    );
+}
+
 #[test]
 fn it_works_with_sql_recipe() {
     let mut g = start_simple("it_works_with_sql_recipe");
@@ -723,6 +761,479 @@ fn it_works_with_arithmetic_aliases() {
     assert_eq!(result[0][1], (price / 100).into());
 }
 
+#[test]
+fn bases_can_be_renamed_and_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let prefix = dir
+        .path()
+        .join("bases_can_be_renamed_and_dropped")
+        .to_string_lossy()
+        .into_owned();
+    let mut b = Builder::default();
+    b.set_sharding(None);
+    b.set_persistence(PersistenceParameters::new(
+        DurabilityMode::Permanent,
+        Duration::from_millis(1),
+        Some(prefix.clone()),
+        1,
+    ));
+    let mut g = b.start_simple().unwrap();
+    let state = |name: &str| std::path::PathBuf::from(format!("{}-{}-0.db", prefix, name));
+
+    let base =
+        g.migrate(|mig| mig.add_base("Old", &["id", "x"], Base::new(vec![]).with_key(vec![0])));
+    let mut old = g.table("Old").unwrap().into_sync();
+    old.insert(vec![1.into(), 2.into()]).unwrap();
+    sleep();
+    assert!(state("Old").exists());
+
+    g.migrate(move |mig| mig.rename_base(base, "New"));
+    assert!(g.table("Old").is_err());
+    assert!(!state("Old").exists());
+    assert!(state("New").exists());
+
+    // the rows written under the old name are still there
+    let view = g.migrate(move |mig| {
+        let v = mig.add_ingredient("v", &["id", "x"], Identity::new(base));
+        mig.maintain_anonymous(v, &[0]);
+        v
+    });
+    let mut v = g.view("v").unwrap().into_sync();
+    assert_eq!(
+        v.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+
+    g.migrate(move |mig| {
+        mig.remove_node(view);
+        mig.drop_base(base);
+    });
+    sleep();
+    assert!(g.inputs().unwrap().is_empty());
+    assert!(g.outputs().unwrap().is_empty());
+    assert!(!state("New").exists());
+}
+
+#[test]
+fn base_columns_can_be_renamed() {
+    let mut g = start_simple("base_columns_can_be_renamed");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT id, price FROM Car WHERE id = ?;",
+    )
+    .unwrap();
+    let mut car = g.table("Car").unwrap().into_sync();
+    car.insert(vec![1.into(), 10.into()]).unwrap();
+    sleep();
+
+    let base = g.inputs().unwrap()["Car"];
+    g.migrate(move |mig| mig.rename_column(base, 1, "cost"));
+    assert_eq!(g.table("Car").unwrap().columns(), &["id", "cost"]);
+
+    // the view passes the column on, so it follows along without losing any rows
+    let mut prices = g.view("CarPrice").unwrap().into_sync();
+    assert_eq!(prices.columns(), &["id", "cost"]);
+    assert_eq!(
+        prices.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 10.into()]]
+    );
+
+    // and queries added later know the column by its new name
+    g.extend_recipe("QUERY CarCost: SELECT cost FROM Car WHERE id = ?;")
+        .unwrap();
+    sleep();
+    let mut costs = g.view("CarCost").unwrap().into_sync();
+    assert_eq!(
+        costs.lookup(&[1.into()], true).unwrap(),
+        vec![vec![10.into()]]
+    );
+}
+
+#[test]
+fn base_column_types_can_be_changed() {
+    use nom_sql::SqlType;
+
+    let mut g = start_simple("base_column_types_can_be_changed");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price varchar(10), PRIMARY KEY(id));
+         QUERY CarPrice: SELECT id, price FROM Car WHERE id = ?;",
+    )
+    .unwrap();
+    let mut car = g.table("Car").unwrap().into_sync();
+    car.insert(vec![1.into(), "10".into()]).unwrap();
+    car.insert(vec![2.into(), "cheap".into()]).unwrap();
+    sleep();
+
+    let base = g.inputs().unwrap()["Car"];
+    g.migrate(move |mig| {
+        mig.alter_column_type(base, 1, SqlType::Int(32), Cast::CoerceOr(0.into()))
+    });
+    sleep();
+    let car = g.table("Car").unwrap();
+    assert_eq!(car.schema().unwrap().fields[1].sql_type, SqlType::Int(32));
+
+    // the rows that were there are converted, and the view sees them change
+    let mut prices = g.view("CarPrice").unwrap().into_sync();
+    assert_eq!(
+        prices.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 10.into()]]
+    );
+    assert_eq!(
+        prices.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), 0.into()]]
+    );
+}
+
+#[test]
+fn deduplicated_tables_apply_each_event_once() {
+    let mut g = start_simple("deduplicated_tables_apply_each_event_once");
+    g.install_recipe(
+        "CREATE TABLE events (aid int, part int, off bigint) DEDUP ON part BY off WINDOW 10;
+         QUERY Events: SELECT aid, COUNT(off) AS n FROM events WHERE aid = ? GROUP BY aid;",
+    )
+    .unwrap();
+    let mut events = g.table("events").unwrap().into_sync();
+    let event = |part: i32, off: i64| vec![1.into(), part.into(), off.into()];
+    events
+        .perform_all(vec![event(0, 1), event(0, 2), event(1, 1)])
+        .unwrap();
+    sleep();
+
+    // the stream is replayed from an earlier offset, as it would be after a crash
+    events
+        .perform_all(vec![event(0, 2), event(1, 1), event(1, 2)])
+        .unwrap();
+    sleep();
+
+    let mut counts = g.view("Events").unwrap().into_sync();
+    assert_eq!(
+        counts.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 4.into()]]
+    );
+}
+
+#[test]
+fn submitted_migrations_are_applied_in_order() {
+    let mut g = start_simple("submitted_migrations_are_applied_in_order");
+    let article = g
+        .submit_migration("CREATE TABLE Article (aid int, title varchar(255), PRIMARY KEY(aid));")
+        .unwrap();
+    let vote = g
+        .submit_migration("CREATE TABLE Vote (aid int, uid int);")
+        .unwrap();
+    let titles = g
+        .submit_migration("QUERY Titles: SELECT aid, title FROM Article WHERE aid = ?;")
+        .unwrap();
+    assert!(g.submit_migration("CREATE TABLE").is_err());
+
+    let done = |g: &mut SyncHandle<LocalAuthority>, ticket| loop {
+        match g.submitted_migration(ticket).unwrap() {
+            SubmittedMigration::Queued(..) => sleep(),
+            SubmittedMigration::Done(_, batch) => return batch,
+            SubmittedMigration::Failed(e) => panic!("migration {} failed: {}", ticket, e),
+        }
+    };
+    // the query reads from a table that is created ahead of it, so it cannot go along with it
+    assert!(!done(&mut g, article).contains(&titles));
+    done(&mut g, vote);
+    assert!(!done(&mut g, titles).contains(&article));
+    assert!(g.migrations().unwrap().queued.is_empty());
+
+    let mut article = g.table("Article").unwrap().into_sync();
+    article.insert(vec![1.into(), "a".into()]).unwrap();
+    sleep();
+    let mut titles = g.view("Titles").unwrap().into_sync();
+    assert_eq!(
+        titles.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "a".into()]]
+    );
+}
+
+#[test]
+fn failed_submitted_migrations_do_not_fail_others() {
+    let mut g = start_simple("failed_submitted_migrations_do_not_fail_others");
+    let bad = g
+        .submit_migration("QUERY Titles: SELECT aid, title FROM Article WHERE aid = ?;")
+        .unwrap();
+    let vote = g
+        .submit_migration("CREATE TABLE Vote (aid int, uid int);")
+        .unwrap();
+
+    let status = |g: &mut SyncHandle<LocalAuthority>, ticket| loop {
+        match g.submitted_migration(ticket).unwrap() {
+            SubmittedMigration::Queued(..) => sleep(),
+            s => return s,
+        }
+    };
+    match status(&mut g, bad) {
+        SubmittedMigration::Failed(..) => {}
+        s => panic!("unexpected status {:?}", s),
+    }
+    match status(&mut g, vote) {
+        SubmittedMigration::Done(..) => {}
+        s => panic!("unexpected status {:?}", s),
+    }
+    assert!(g.table("Vote").is_ok());
+}
+
+#[test]
+fn migration_progress_is_reported() {
+    let mut g = start_simple("migration_progress_is_reported");
+    g.install_recipe("CREATE TABLE Article (aid int, title varchar(255), PRIMARY KEY(aid));")
+        .unwrap();
+    let mut article = g.table("Article").unwrap().into_sync();
+    article.insert(vec![1.into(), "a".into()]).unwrap();
+    article.insert(vec![2.into(), "b".into()]).unwrap();
+    sleep();
+
+    let ticket = g
+        .submit_migration("QUERY Titles: SELECT aid, title FROM Article;")
+        .unwrap();
+    let progress = loop {
+        match g.migration_progress(Some(ticket)).unwrap() {
+            Some(ref p) if p.step != MigrationStep::Done => sleep(),
+            Some(p) => break p,
+            None => panic!("no progress for migration {}", ticket),
+        }
+    };
+    assert!(progress.nodes_added > 0);
+    assert_eq!(progress.nodes_ready, progress.nodes_to_ready);
+    assert!(progress.bytes_replayed > 0);
+    assert_eq!(progress.eta(), Some(Duration::from_secs(0)));
+    assert_eq!(g.migration_progress(None).unwrap(), Some(progress));
+    assert_eq!(g.migration_progress(Some(ticket + 1)).unwrap(), None);
+}
+
+#[test]
+fn intersect_and_except_count_rows() {
+    let mut g = start_simple("intersect_and_except_count_rows");
+    g.install_recipe(
+        "CREATE TABLE Morning (uid int, name varchar(40));
+         CREATE TABLE Evening (uid int, name varchar(40), PRIMARY KEY(uid));
+         VIEW both_shifts: \
+           (SELECT Morning.uid AS uid, Morning.name AS name FROM Morning) \
+           INTERSECT \
+           (SELECT Evening.uid AS uid, Evening.name AS name FROM Evening);
+         VIEW morning_only: \
+           (SELECT Morning.uid AS uid, Morning.name AS name FROM Morning) \
+           EXCEPT \
+           (SELECT Evening.uid AS uid, Evening.name AS name FROM Evening);
+         QUERY Both: SELECT uid, name FROM both_shifts WHERE uid = ?;
+         QUERY MorningOnly: SELECT uid, name FROM morning_only WHERE uid = ?;",
+    )
+    .unwrap();
+    let mut morning = g.table("Morning").unwrap().into_sync();
+    let mut evening = g.table("Evening").unwrap().into_sync();
+    morning.insert(vec![1.into(), "a".into()]).unwrap();
+    morning.insert(vec![2.into(), "b".into()]).unwrap();
+    morning.insert(vec![2.into(), "b".into()]).unwrap();
+    evening.insert(vec![2.into(), "b".into()]).unwrap();
+    evening.insert(vec![3.into(), "c".into()]).unwrap();
+    sleep();
+
+    // rows that either side has more than once still show up once
+    let mut both = g.view("Both").unwrap().into_sync();
+    let mut morning_only = g.view("MorningOnly").unwrap().into_sync();
+    assert_eq!(
+        both.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), "b".into()]]
+    );
+    assert!(both.lookup(&[1.into()], true).unwrap().is_empty());
+    assert!(both.lookup(&[3.into()], true).unwrap().is_empty());
+    assert_eq!(
+        morning_only.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "a".into()]]
+    );
+    assert!(morning_only.lookup(&[2.into()], true).unwrap().is_empty());
+
+    // retracting the row from one side moves it from one result to the other
+    evening.delete(vec![2.into()]).unwrap();
+    sleep();
+    assert!(both.lookup(&[2.into()], true).unwrap().is_empty());
+    assert_eq!(
+        morning_only.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), "b".into()]]
+    );
+}
+
+#[test]
+fn recursive_views_follow_edges() {
+    let mut g = start_simple("recursive_views_follow_edges");
+    g.install_recipe(
+        "CREATE TABLE follows (follower int, followee int);
+         VIEW fof: SELECT follower, followee FROM follows WITH RECURSIVE DEPTH 2;
+         QUERY Reached: SELECT followee, depth FROM fof WHERE follower = ?;",
+    )
+    .unwrap();
+    let mut follows = g.table("follows").unwrap().into_sync();
+    follows.insert(vec![1.into(), 2.into()]).unwrap();
+    follows.insert(vec![2.into(), 3.into()]).unwrap();
+    follows.insert(vec![3.into(), 4.into()]).unwrap();
+    sleep();
+
+    let mut reached = g.view("Reached").unwrap().into_sync();
+    let mut lookup = |follower: i32| {
+        let mut rows = reached.lookup(&[follower.into()], true).unwrap();
+        rows.sort();
+        rows
+    };
+    // 4 is three steps away from 1
+    assert_eq!(
+        lookup(1),
+        vec![vec![2.into(), 1.into()], vec![3.into(), 2.into()]]
+    );
+
+    // a shortcut brings it within reach
+    follows.insert(vec![1.into(), 3.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        lookup(1),
+        vec![
+            vec![2.into(), 1.into()],
+            vec![3.into(), 1.into()],
+            vec![4.into(), 2.into()],
+        ]
+    );
+}
+
+#[test]
+fn approximate_distinct_counts() {
+    let mut g = start_simple("approximate_distinct_counts");
+    g.install_recipe(
+        "CREATE TABLE visits (id int, page int, uid int, PRIMARY KEY(id));
+         QUERY Visitors: SELECT page, APPROX_COUNT_DISTINCT(uid) AS visitors FROM visits \
+         WHERE page = ? GROUP BY page;",
+    )
+    .unwrap();
+    let mut visits = g.table("visits").unwrap().into_sync();
+    visits.insert(vec![1.into(), 1.into(), 10.into()]).unwrap();
+    visits.insert(vec![2.into(), 1.into(), 20.into()]).unwrap();
+    visits.insert(vec![3.into(), 1.into(), 10.into()]).unwrap();
+    visits.insert(vec![4.into(), 2.into(), 10.into()]).unwrap();
+    sleep();
+
+    let mut visitors = g.view("Visitors").unwrap().into_sync();
+    let mut lookup = |page: i32| visitors.lookup(&[page.into()], true).unwrap();
+    // a second visit by the same user is not counted again
+    assert_eq!(lookup(1), vec![vec![1.into(), 2.into()]]);
+    assert_eq!(lookup(2), vec![vec![2.into(), 1.into()]]);
+
+    // the estimate only drops once every visit by a user is gone
+    visits.delete(vec![1.into()]).unwrap();
+    sleep();
+    assert_eq!(lookup(1), vec![vec![1.into(), 2.into()]]);
+    visits.delete(vec![3.into()]).unwrap();
+    sleep();
+    assert_eq!(lookup(1), vec![vec![1.into(), 1.into()]]);
+}
+
+#[test]
+fn views_keep_the_first_rows_of_each_group() {
+    let mut g = start_simple("views_keep_the_first_rows_of_each_group");
+    g.install_recipe(
+        "CREATE TABLE comments (id int, story int, ts int, PRIMARY KEY(id));
+         VIEW recent: SELECT story, id, ts FROM comments \
+         QUALIFY ROW_NUMBER() OVER (PARTITION BY story ORDER BY ts DESC) <= 2;
+         QUERY Recent: SELECT id, ts FROM recent WHERE story = ?;",
+    )
+    .unwrap();
+    let mut comments = g.table("comments").unwrap().into_sync();
+    for &(id, story, ts) in &[(1, 1, 10), (2, 1, 30), (3, 2, 5), (4, 1, 20)] {
+        comments
+            .insert(vec![id.into(), story.into(), ts.into()])
+            .unwrap();
+    }
+    sleep();
+
+    let mut recent = g.view("Recent").unwrap().into_sync();
+    let mut lookup = |story: i32| {
+        let mut rows = recent.lookup(&[story.into()], true).unwrap();
+        rows.sort();
+        rows
+    };
+    assert_eq!(
+        lookup(1),
+        vec![vec![2.into(), 30.into()], vec![4.into(), 20.into()]]
+    );
+    assert_eq!(lookup(2), vec![vec![3.into(), 5.into()]]);
+
+    // the next most recent comment takes the place of one that is deleted
+    comments.delete(vec![2.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        lookup(1),
+        vec![vec![1.into(), 10.into()], vec![4.into(), 20.into()]]
+    );
+}
+
+#[test]
+fn migrations_that_time_out_are_undone() {
+    let mut builder = Builder::default();
+    builder.set_persistence(get_persistence_params(
+        "migrations_that_time_out_are_undone",
+    ));
+    // no migration that has to wait for the domains to set up its views finishes in time
+    builder.set_migration_timeout(Duration::from_nanos(1));
+    let mut g = builder.start_simple().unwrap();
+
+    let r = g.install_recipe(
+        "CREATE TABLE Article (aid int, title varchar(255), PRIMARY KEY(aid));
+         QUERY ArticleTitle: SELECT title FROM Article WHERE aid = ?;",
+    );
+    assert!(r.is_err());
+    assert!(g.inputs().unwrap().is_empty());
+    assert!(g.outputs().unwrap().is_empty());
+    // the graph is as it was before, rather than holding on to the nodes that were removed
+    assert!(!g.graphviz().unwrap().contains("Article"));
+}
+
+fn check_failed_migration_is_undone(prefix: &str, panic: bool) {
+    let mut g = start_simple(prefix);
+    g.install_recipe(
+        "CREATE TABLE Article (aid int, title varchar(255), PRIMARY KEY(aid));
+         QUERY ArticleTitle: SELECT title FROM Article WHERE aid = ?;",
+    )
+    .unwrap();
+    let mut article = g.table("Article").unwrap().into_sync();
+    article.insert(vec![1.into(), "a".into()]).unwrap();
+    sleep();
+    let graph = g.graphviz().unwrap();
+    let outputs = g.outputs().unwrap();
+
+    let q = "QUERY ArticleByTitle: SELECT aid FROM Article WHERE title = ?;";
+    g.fail_next_migration(panic);
+    assert!(g.extend_recipe(q).is_err());
+    assert_eq!(g.graphviz().unwrap(), graph);
+    assert_eq!(g.outputs().unwrap(), outputs);
+    assert!(g.view("ArticleByTitle").is_err());
+
+    // the domains that were there before no longer send to the nodes the migration added
+    article.insert(vec![2.into(), "a".into()]).unwrap();
+    sleep();
+    let mut title = g.view("ArticleTitle").unwrap().into_sync();
+    assert_eq!(
+        title.lookup(&[2.into()], true).unwrap(),
+        vec![vec!["a".into()]]
+    );
+
+    // the recipe and the materializations were put back too, so the query can be added again
+    g.extend_recipe(q).unwrap();
+    let mut by_title = g.view("ArticleByTitle").unwrap().into_sync();
+    let mut aids = by_title.lookup(&["a".into()], true).unwrap();
+    aids.sort();
+    assert_eq!(aids, vec![vec![1.into()], vec![2.into()]]);
+}
+
+#[test]
+fn migrations_that_fail_are_undone() {
+    check_failed_migration_is_undone("migrations_that_fail_are_undone", false);
+}
+
+#[test]
+fn migrations_that_panic_are_undone() {
+    check_failed_migration_is_undone("migrations_that_panic_are_undone", true);
+}
+
 #[test]
 fn it_recovers_persisted_bases() {
     let authority = Arc::new(LocalAuthority::new());
@@ -771,6 +1282,36 @@ fn it_recovers_persisted_bases() {
     }
 }
 
+#[test]
+fn recovery_rebuilds_the_recorded_graph() {
+    let authority = Arc::new(LocalAuthority::new());
+    let persistence_params = get_persistence_params("recovery_rebuilds_the_recorded_graph");
+
+    let recorded = {
+        let mut g = Builder::default();
+        g.set_persistence(persistence_params.clone());
+        let mut g = wrap_sync(g.start(authority.clone()));
+        g.install_recipe("CREATE TABLE Car (id int, price int, PRIMARY KEY(id));")
+            .unwrap();
+        g.extend_recipe("QUERY CarPrice: SELECT price FROM Car WHERE id = ?;")
+            .unwrap();
+        g.extend_recipe("QUERY CarByPrice: SELECT id FROM Car WHERE price = ?;")
+            .unwrap();
+        // the nodes the rolled back query had keep their indices from being handed out again
+        g.rollback_migration(2).unwrap();
+        g.extend_recipe("QUERY Cheap: SELECT id, price FROM Car WHERE price < 100;")
+            .unwrap();
+        g.graphviz().unwrap()
+    };
+
+    let mut g = Builder::default();
+    g.set_persistence(persistence_params);
+    let mut g = wrap_sync(g.start(authority.clone()));
+    g.view("Cheap").unwrap();
+    assert!(g.view("CarByPrice").is_err());
+    assert_eq!(g.graphviz().unwrap(), recorded);
+}
+
 #[test]
 fn mutator_churn() {
     let mut g = start_simple("mutator_churn");
@@ -1589,20 +2130,69 @@ fn full_aggregation_with_bogokey() {
 }
 
 #[test]
-fn materialization_frontier() {
-    // set up graph
-    let mut g = start_simple_unsharded("materialization_frontier");
-    g.migrate(|mig| {
-        // migrate
+fn parallel_full_replay() {
+    let mut g = Builder::default();
+    g.disable_partial();
+    g.set_full_replay_threads(4);
+    g.set_persistence(get_persistence_params("parallel_full_replay"));
+    let mut g = g.start_simple().unwrap();
+    let base = g.migrate(|mig| {
+        mig.add_base(
+            "base",
+            &["id", "x"],
+            Base::new(vec![0.into(), 0.into()]).with_key(vec![0]),
+        )
+    });
 
-        // add article base node
-        let article = mig.add_base("article", &["id", "title"], Base::default());
+    // enough rows that the state is split across several chunkers
+    let n = 2000;
+    let mut mutb = g.table("base").unwrap().into_sync();
+    mutb.perform_all((0..n).map(|i| vec![i.into(), (i % 10).into()]))
+        .unwrap();
+    sleep();
 
-        // add vote base table
-        let vote = mig.add_base(
-            "vote",
-            &["user", "id"],
-            Base::default().with_key(vec![0, 1]),
+    // a new, fully materialized view must see every row from all partitions
+    g.migrate(move |mig| {
+        let agg = mig.add_ingredient(
+            "agg",
+            &["x", "count"],
+            Aggregation::COUNT.over(base, 0, &[1]),
+        );
+        mig.maintain_anonymous(agg, &[0]);
+    });
+
+    let mut aggq = g.view("agg").unwrap().into_sync();
+    for x in 0..10 {
+        assert_eq!(
+            aggq.lookup(&[x.into()], true).unwrap(),
+            vec![vec![x.into(), (n / 10).into()]]
+        );
+    }
+
+    // and it should keep up with writes that arrive after the replay finished
+    mutb.insert(vec![n.into(), 0.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        aggq.lookup(&[0.into()], true).unwrap(),
+        vec![vec![0.into(), (n / 10 + 1).into()]]
+    );
+}
+
+#[test]
+fn materialization_frontier() {
+    // set up graph
+    let mut g = start_simple_unsharded("materialization_frontier");
+    g.migrate(|mig| {
+        // migrate
+
+        // add article base node
+        let article = mig.add_base("article", &["id", "title"], Base::default());
+
+        // add vote base table
+        let vote = mig.add_base(
+            "vote",
+            &["user", "id"],
+            Base::default().with_key(vec![0, 1]),
         );
 
         // add vote count
@@ -2514,3 +3104,2483 @@ fn correct_nested_view_schema() {
     ];
     assert_eq!(q.schema(), Some(&expected_schema[..]));
 }
+
+#[test]
+fn policies_apply_to_queries_added_after_universe() {
+    let mut g = start_simple_unsharded("policies_apply_to_queries_added_after_universe");
+    let config = r#"{
+        "policies": [{ "table": "Post", "predicate": "WHERE Post.author = UserContext.id" }]
+    }"#;
+    g.on_worker(|w| w.set_security_config(config.to_owned()))
+        .unwrap();
+    g.install_recipe("CREATE TABLE Post (id int, author int, PRIMARY KEY(id));")
+        .unwrap();
+
+    let mut context = HashMap::new();
+    context.insert("id".to_owned(), 1.into());
+    g.on_worker(move |w| w.create_universe(context)).unwrap();
+
+    // this query is added by a migration that happens after the universe was created
+    g.extend_recipe("QUERY PostsById: SELECT id, author FROM Post WHERE id = ?;")
+        .unwrap();
+
+    let mut post = g.table("Post").unwrap().into_sync();
+    post.insert(vec![1.into(), 1.into()]).unwrap();
+    post.insert(vec![2.into(), 2.into()]).unwrap();
+    sleep();
+
+    // the global view is unaffected by policies
+    let mut global = g.view("PostsById").unwrap().into_sync();
+    assert_eq!(global.lookup(&[2.into()], true).unwrap().len(), 1);
+
+    // but the universe's view of the new query only exposes the universe's own posts
+    let mut mine = g.view("PostsById_u1").unwrap().into_sync();
+    assert_eq!(
+        mine.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+    assert!(mine.lookup(&[2.into()], true).unwrap().is_empty());
+}
+
+#[test]
+fn masked_columns_are_redacted_for_universes() {
+    let mut g = start_simple_unsharded("masked_columns_are_redacted_for_universes");
+    let config = r#"{
+        "policies": [{ "action": "mask", "table": "User", "column": "email", "mask": "null" }]
+    }"#;
+    g.on_worker(|w| w.set_security_config(config.to_owned()))
+        .unwrap();
+    g.install_recipe(
+        "CREATE TABLE User (id int, email varchar(255), PRIMARY KEY(id));
+         QUERY UserById: SELECT id, email FROM User WHERE id = ?;
+         QUERY AddressById: SELECT id, email AS address FROM User WHERE id = ?;
+         QUERY UserByEmail: SELECT id, email FROM User WHERE email = ?;
+         Emails: SELECT id, email FROM User;
+         QUERY EmailById: SELECT id, email FROM Emails WHERE id = ?;",
+    )
+    .unwrap();
+
+    let mut context = HashMap::new();
+    context.insert("id".to_owned(), 1.into());
+    g.on_worker(move |w| w.create_universe(context)).unwrap();
+
+    let mut user = g.table("User").unwrap().into_sync();
+    user.insert(vec![1.into(), "alice@example.com".into()])
+        .unwrap();
+    sleep();
+
+    let mut global = g.view("UserById").unwrap().into_sync();
+    assert_eq!(
+        global.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "alice@example.com".into()]]
+    );
+
+    let mut mine = g.view("UserById_u1").unwrap().into_sync();
+    assert_eq!(
+        mine.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), DataType::None]]
+    );
+
+    // the column is masked under an alias, and when it is read through a view
+    for view in &["AddressById_u1", "EmailById_u1"] {
+        let mut mine = g.view(view).unwrap().into_sync();
+        assert_eq!(
+            mine.lookup(&[1.into()], true).unwrap(),
+            vec![vec![1.into(), DataType::None]]
+        );
+    }
+
+    // and when it is a parameter, which the view can still be looked up by
+    let mut mine = g.view("UserByEmail_u1").unwrap().into_sync();
+    assert_eq!(
+        mine.lookup(&["alice@example.com".into()], true).unwrap(),
+        vec![vec![1.into(), DataType::None]]
+    );
+}
+
+#[test]
+fn universes_are_limited_to_their_query_quota() {
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params(
+        "universes_are_limited_to_their_query_quota",
+    ));
+    builder.set_universe_quota(Some(2), None);
+    let mut g = builder.start_simple().unwrap();
+
+    let config = r#"{
+        "policies": [{ "table": "Post", "predicate": "WHERE Post.author = UserContext.id" }]
+    }"#;
+    g.on_worker(|w| w.set_security_config(config.to_owned()))
+        .unwrap();
+    g.install_recipe(
+        "CREATE TABLE Post (id int, author int, PRIMARY KEY(id));
+         QUERY PostsById: SELECT id, author FROM Post WHERE id = ?;
+         QUERY PostsByAuthor: SELECT id, author FROM Post WHERE author = ?;",
+    )
+    .unwrap();
+
+    let mut context = HashMap::new();
+    context.insert("id".to_owned(), 1.into());
+    g.on_worker(move |w| w.create_universe(context)).unwrap();
+    assert!(g.view("PostsById_u1").is_ok());
+    assert!(g.view("PostsByAuthor_u1").is_ok());
+
+    // the global query is still installed, but the universe has no room left for it
+    g.extend_recipe("QUERY AllPosts: SELECT id, author FROM Post WHERE id > ?;")
+        .unwrap();
+    assert!(g.view("AllPosts").is_ok());
+    assert!(g.view("AllPosts_u1").is_err());
+
+    let usage = g.universe_usage().unwrap();
+    assert!(usage["u1"].0 > 0);
+}
+
+#[test]
+fn removed_universes_get_no_new_queries() {
+    let mut g = start_simple_unsharded("removed_universes_get_no_new_queries");
+    let config = r#"{
+        "policies": [{ "table": "Post", "predicate": "WHERE Post.author = UserContext.id" }]
+    }"#;
+    g.on_worker(|w| w.set_security_config(config.to_owned()))
+        .unwrap();
+    g.install_recipe(
+        "CREATE TABLE Post (id int, author int, PRIMARY KEY(id));
+         QUERY PostsById: SELECT id, author FROM Post WHERE id = ?;",
+    )
+    .unwrap();
+
+    for id in 1..3 {
+        let mut context = HashMap::new();
+        context.insert("id".to_owned(), id.into());
+        g.on_worker(move |w| w.create_universe(context)).unwrap();
+    }
+    assert!(g.view("PostsById_u1").is_ok());
+
+    let mut context = HashMap::new();
+    context.insert("id".to_owned(), 1.into());
+    g.on_worker(move |w| w.remove_universe(context)).unwrap();
+    assert!(g.view("PostsById_u1").is_err());
+    assert!(g.view("PostsById_u2").is_ok());
+
+    g.extend_recipe("QUERY AllPosts: SELECT id, author FROM Post WHERE id > ?;")
+        .unwrap();
+    assert!(g.view("AllPosts_u1").is_err());
+    assert!(g.view("AllPosts_u2").is_ok());
+}
+
+#[test]
+fn group_members_can_be_added_and_removed() {
+    let mut g = start_simple_unsharded("group_members_can_be_added_and_removed");
+    let config = r#"{
+        "groups": [{
+            "name": "ta",
+            "membership": "select r_uid as uid, r_cid as gid FROM Role WHERE r_role = 1",
+            "policies": [{ "table": "Post", "predicate": "WHERE Post.class = GroupContext.id" }]
+        }],
+        "policies": []
+    }"#;
+    g.on_worker(|w| w.set_security_config(config.to_owned()))
+        .unwrap();
+    g.install_recipe(
+        "CREATE TABLE Role (r_uid int, r_cid int, r_role int, PRIMARY KEY(r_uid));
+         CREATE TABLE Post (id int, class int, PRIMARY KEY(id));",
+    )
+    .unwrap();
+
+    g.on_worker(|w| w.add_group_member("ta", 1.into(), 10.into()))
+        .unwrap();
+    sleep();
+
+    // the membership query has no parameters, so its view is keyed by a constant
+    let mut members = g.view("ta").unwrap().into_sync();
+    assert_eq!(
+        members.lookup(&[0.into()], true).unwrap(),
+        vec![vec![1.into(), 10.into(), 0.into()]]
+    );
+
+    // removing the user from a group they aren't in leaves their row alone
+    assert!(g
+        .on_worker(|w| w.remove_group_member("ta", 1.into(), 11.into()))
+        .is_err());
+    sleep();
+    assert_eq!(members.lookup(&[0.into()], true).unwrap().len(), 1);
+
+    g.on_worker(|w| w.remove_group_member("ta", 1.into(), 10.into()))
+        .unwrap();
+    sleep();
+    assert!(members.lookup(&[0.into()], true).unwrap().is_empty());
+
+    // groups that aren't in the security configuration can't be changed
+    assert!(g
+        .on_worker(|w| w.add_group_member("student", 1.into(), 10.into()))
+        .is_err());
+}
+
+#[test]
+fn writes_beyond_rate_limit_are_refused() {
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params(
+        "writes_beyond_rate_limit_are_refused",
+    ));
+    builder.set_rate_limits(None, Some(2), None);
+    let mut g = builder.start_simple().unwrap();
+    g.install_recipe("CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));")
+        .unwrap();
+
+    let mut article = g.table("Article").unwrap().into_sync();
+    article.insert(vec![1.into(), "a".into()]).unwrap();
+    article.insert(vec![2.into(), "b".into()]).unwrap();
+    match article.insert(vec![3.into(), "c".into()]) {
+        Err(noria::error::TableError::RateLimited) => {}
+        r => panic!("expected write to be refused, got {:?}", r),
+    }
+
+    // the client may write again once its allowance has been replenished
+    thread::sleep(Duration::from_secs(1));
+    article.insert(vec![3.into(), "c".into()]).unwrap();
+}
+
+#[test]
+fn domains_authenticate_with_shared_secret() {
+    let mut builder = Builder::default();
+    builder.set_persistence(get_persistence_params(
+        "domains_authenticate_with_shared_secret",
+    ));
+    builder.set_domain_secret("hunter2");
+    let mut g = builder.start_simple().unwrap();
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         CREATE TABLE Vote (article_id int, user int);
+         QUERY Voters: SELECT Article.id, Vote.user FROM Article \
+            JOIN Vote ON (Article.id = Vote.article_id) WHERE Article.id = ?;",
+    )
+    .unwrap();
+
+    let mut article = g.table("Article").unwrap().into_sync();
+    let mut vote = g.table("Vote").unwrap().into_sync();
+    let mut voters = g.view("Voters").unwrap().into_sync();
+    article.insert(vec![1.into(), "a".into()]).unwrap();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+
+    assert_eq!(
+        voters.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 7.into()]]
+    );
+}
+
+#[test]
+fn reads_are_audited_by_universe() {
+    let dir = tempfile::tempdir().unwrap();
+    let trail = dir.path().join("audit.log");
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(get_persistence_params("reads_are_audited_by_universe"));
+    builder.set_read_audit(&trail, 1.0);
+    let mut g = builder.start_simple().unwrap();
+
+    let config = r#"{
+        "policies": [{ "table": "Post", "predicate": "WHERE Post.author = UserContext.id" }]
+    }"#;
+    g.on_worker(|w| w.set_security_config(config.to_owned()))
+        .unwrap();
+    g.install_recipe(
+        "CREATE TABLE Post (id int, author int, PRIMARY KEY(id));
+         QUERY PostsById: SELECT id, author FROM Post WHERE id = ?;",
+    )
+    .unwrap();
+
+    let mut context = HashMap::new();
+    context.insert("id".to_owned(), 1.into());
+    g.on_worker(move |w| w.create_universe(context)).unwrap();
+
+    let mut post = g.table("Post").unwrap().into_sync();
+    post.insert(vec![42.into(), 1.into()]).unwrap();
+    sleep();
+
+    let mut global = g.view("PostsById").unwrap().into_sync();
+    let mut user = g.view("PostsById_u1").unwrap().into_sync();
+    global.lookup(&[42.into()], true).unwrap();
+    user.lookup(&[42.into()], true).unwrap();
+    sleep();
+
+    let records: Vec<serde_json::Value> = std::fs::read_to_string(&trail)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert!(records
+        .iter()
+        .any(|r| r["view"] == "PostsById" && r["universe"].is_null() && r["keys"][0][0] == 42));
+    assert!(records
+        .iter()
+        .any(|r| r["view"] == "PostsById_u1" && r["universe"] == "u1" && r["keys"][0][0] == 42));
+}
+
+/// Simulate two workers and a reader-only one, seeded with `seed`, through writes, a migration,
+/// and the failure of the reader-only worker, and return what ran, in the order that it ran.
+fn simulate_votes(seed: u64) -> Vec<String> {
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    let mut sim = builder.simulate(seed);
+    sim.add_worker(false);
+    sim.add_worker(false);
+    sim.add_worker(true);
+    sim.settle();
+
+    sim.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         CREATE TABLE Vote (article_id int, user int);
+         QUERY Voters: SELECT Article.id, Vote.user FROM Article \
+            JOIN Vote ON (Article.id = Vote.article_id) WHERE Article.id = ?;",
+    )
+    .unwrap();
+    sim.insert("Article", vec![1.into(), "a".into()]).unwrap();
+    sim.insert("Vote", vec![1.into(), 7.into()]).unwrap();
+
+    // the migration runs while the writes above are still making their way through
+    sim.extend_recipe("QUERY Votes: SELECT user FROM Vote WHERE article_id = ?;")
+        .unwrap();
+    sim.insert("Vote", vec![1.into(), 8.into()]).unwrap();
+
+    // the worker with the readers fails amid more writes, and the controller moves its readers
+    // once it notices
+    sim.crash_worker(2, 1_000);
+    sim.insert("Vote", vec![1.into(), 9.into()]).unwrap();
+    sim.run_for(Duration::from_secs(60));
+
+    let mut voters = sim.lookup("Voters", &[1.into()]).unwrap();
+    voters.sort();
+    assert_eq!(
+        voters,
+        vec![
+            vec![1.into(), 7.into()],
+            vec![1.into(), 8.into()],
+            vec![1.into(), 9.into()],
+        ]
+    );
+    assert_eq!(sim.lookup("Votes", &[1.into()]).unwrap().len(), 3);
+
+    sim.trace()
+}
+
+#[test]
+fn simulations_are_reproducible() {
+    for seed in 0..3 {
+        let trace = simulate_votes(seed);
+        assert!(trace.iter().any(|t| t == "crash w2"), "seed {}", seed);
+
+        // the same seed interleaves the whole deployment the same way every time
+        assert_eq!(trace, simulate_votes(seed), "seed {}", seed);
+    }
+}
+
+#[test]
+#[cfg(feature = "fault-injection")]
+fn replays_recover_from_dropped_updates() {
+    use crate::Fault;
+
+    let mut g = start_simple_unsharded("replays_recover_from_dropped_updates");
+    g.install_recipe(
+        "CREATE TABLE Vote (article_id int, user int);
+         QUERY Votes: SELECT user FROM Vote WHERE article_id = ?;",
+    )
+    .unwrap();
+    let mut vote = g.table("Vote").unwrap().into_sync();
+    let mut votes = g.view("Votes").unwrap().into_sync();
+    assert!(votes.lookup(&[1.into()], true).unwrap().is_empty());
+
+    // duplicated updates show up twice in materialized keys
+    g.on_worker(|w| {
+        w.inject_fault(Fault::Duplicate {
+            from: None,
+            to: None,
+        })
+    })
+    .unwrap();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+    assert_eq!(votes.lookup(&[1.into()], true).unwrap().len(), 2);
+
+    // updates that are lost on the way to the view are recovered by replaying from the base
+    g.on_worker(|w| w.clear_faults()).unwrap();
+    g.on_worker(|w| {
+        w.inject_fault(Fault::Drop {
+            from: None,
+            to: None,
+        })
+    })
+    .unwrap();
+    vote.insert(vec![2.into(), 8.into()]).unwrap();
+    sleep();
+    g.on_worker(|w| w.clear_faults()).unwrap();
+    assert_eq!(
+        votes.lookup(&[2.into()], true).unwrap(),
+        vec![vec![8.into()]]
+    );
+}
+
+#[test]
+#[cfg(feature = "oracle")]
+fn views_agree_with_oracle() {
+    use crate::oracle::Oracle;
+
+    let recipe = "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+                  CREATE TABLE Vote (article_id int, user int);
+                  QUERY Voters: SELECT Article.title, Vote.user FROM Article \
+                     JOIN Vote ON (Article.id = Vote.article_id) WHERE Article.id = ?;";
+    let mut g = start_simple("views_agree_with_oracle");
+    g.install_recipe(recipe).unwrap();
+
+    let oracle = Oracle::new(recipe).unwrap();
+    let mut article = oracle
+        .table(g.table("Article").unwrap().into_sync())
+        .unwrap();
+    let mut vote = oracle.table(g.table("Vote").unwrap().into_sync()).unwrap();
+    oracle
+        .watch("Voters", g.view("Voters").unwrap().into_sync())
+        .unwrap();
+
+    article.insert(vec![1.into(), "a".into()]).unwrap();
+    article.insert(vec![2.into(), "b".into()]).unwrap();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    vote.insert(vec![2.into(), 8.into()]).unwrap();
+    sleep();
+    assert_eq!(oracle.lookup("Voters", &[1.into()], true).unwrap().len(), 1);
+    assert_eq!(oracle.lookup("Voters", &[2.into()], true).unwrap().len(), 1);
+
+    article.delete(vec![2.into()]).unwrap();
+    vote.insert(vec![1.into(), 9.into()]).unwrap();
+    sleep();
+    assert_eq!(oracle.check().unwrap(), vec![]);
+
+    // writes that bypass the oracle make the view diverge from it
+    let mut unmirrored = g.table("Vote").unwrap().into_sync();
+    unmirrored.insert(vec![1.into(), 10.into()]).unwrap();
+    sleep();
+    let divergences = oracle.check().unwrap();
+    assert_eq!(divergences.len(), 1);
+    assert_eq!(divergences[0].key, vec![1.into()]);
+    assert_eq!(
+        divergences[0].actual.len(),
+        divergences[0].expected.len() + 1
+    );
+}
+
+#[test]
+fn replay_reproduces_recorded_workload() {
+    use noria::recording::{self, Recorder};
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let recipe = "CREATE TABLE Vote (article_id int, user int);
+                  QUERY Votes: SELECT article_id, COUNT(user) AS votes FROM Vote \
+                     WHERE article_id = ? GROUP BY article_id;";
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("workload.jsonl");
+
+    let mut g = start_simple("replay_reproduces_recorded_workload");
+    g.install_recipe(recipe).unwrap();
+    let recorder = Recorder::create(&path).unwrap();
+    g.handle().record_to(recorder.clone());
+    let mut vote = g.table("Vote").unwrap().into_sync();
+    let mut votes = g.view("Votes").unwrap().into_sync();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    vote.insert(vec![1.into(), 8.into()]).unwrap();
+    vote.insert(vec![2.into(), 8.into()]).unwrap();
+    sleep();
+    let expected = votes.lookup(&[1.into()], true).unwrap();
+    recorder.flush().unwrap();
+
+    let mut replica = start_simple("replay_reproduces_recorded_workload_replica");
+    replica.install_recipe(recipe).unwrap();
+    let recording = BufReader::new(File::open(&path).unwrap());
+    let replayed = recording::replay(&mut *replica, recording, None).unwrap();
+    assert_eq!(replayed.writes, 3);
+    assert_eq!(replayed.lookups, 1);
+    assert_eq!(replayed.failed, 0);
+
+    sleep();
+    let mut votes = replica.view("Votes").unwrap().into_sync();
+    assert_eq!(votes.lookup(&[1.into()], true).unwrap(), expected);
+    assert_eq!(expected, vec![vec![1.into(), 2.into()]]);
+
+    // a view that the deployment doesn't have fails the lookups on it, but not the replay
+    let mut partial = start_simple("replay_reproduces_recorded_workload_partial");
+    partial
+        .install_recipe("CREATE TABLE Vote (article_id int, user int);")
+        .unwrap();
+    let recording = BufReader::new(File::open(&path).unwrap());
+    let replayed = recording::replay(&mut *partial, recording, None).unwrap();
+    assert_eq!(replayed.writes, 3);
+    assert_eq!(replayed.lookups, 1);
+    assert_eq!(replayed.failed, 1);
+}
+
+#[test]
+fn shadow_migration() {
+    let mut g = start_simple("shadow_migration");
+    g.install_recipe(
+        "CREATE TABLE Vote (article_id int, user int);
+         QUERY Votes: SELECT article_id, COUNT(user) AS votes FROM Vote \
+            WHERE article_id = ? GROUP BY article_id;",
+    )
+    .unwrap();
+    let proposed = "CREATE TABLE Vote (article_id int, user int);
+                    QUERY Votes: SELECT article_id, user FROM Vote WHERE article_id = ?;";
+
+    let mut vote = g.table("Vote").unwrap().into_sync();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    g.shadow_recipe(proposed, Duration::from_secs(60)).unwrap();
+    assert!(g.extend_recipe("QUERY All: SELECT * FROM Vote;").is_err());
+
+    // the shadow query is fed by live writes, but is not listed as a view
+    vote.insert(vec![1.into(), 8.into()]).unwrap();
+    vote.insert(vec![2.into(), 8.into()]).unwrap();
+    sleep();
+    assert_eq!(g.outputs().unwrap().len(), 1);
+    let report = g.shadow_report().unwrap();
+    assert!(!report.ready);
+    assert!(report.nodes > 0);
+    assert_eq!(report.queries.len(), 1);
+
+    let diffs = g
+        .compare_shadow("Votes", &[vec![1.into()], vec![3.into()]])
+        .unwrap();
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].key, vec![1.into()]);
+    assert_eq!(diffs[0].live, vec![vec![1.into(), 2.into()]]);
+    assert_eq!(
+        diffs[0].shadow,
+        vec![vec![1.into(), 7.into()], vec![1.into(), 8.into()]]
+    );
+
+    // the shadow can't be promoted before it has soaked, but can be discarded
+    assert!(g.promote_shadow().is_err());
+    g.discard_shadow().unwrap();
+    assert!(g.shadow_report().is_err());
+
+    g.shadow_recipe(proposed, Duration::from_millis(0)).unwrap();
+    g.promote_shadow().unwrap();
+    let mut votes = g.view("Votes").unwrap().into_sync();
+    assert_eq!(
+        votes.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), 8.into()]]
+    );
+}
+
+#[test]
+fn only_changed_queries_are_compared_with_their_shadow() {
+    let mut g = start_simple("only_changed_queries_are_compared_with_their_shadow");
+    g.install_recipe(
+        "CREATE TABLE Vote (article_id int, user int);
+         QUERY Votes: SELECT article_id, user FROM Vote WHERE article_id = ?;",
+    )
+    .unwrap();
+    g.shadow_recipe(
+        "CREATE TABLE Vote (article_id int, user int);
+         QUERY Votes: SELECT article_id, user FROM Vote WHERE user = ?;
+         QUERY Voters: SELECT user FROM Vote WHERE article_id = ?;",
+        Duration::from_secs(60),
+    )
+    .unwrap();
+
+    let report = g.shadow_report().unwrap();
+    assert_eq!(report.queries.len(), 2);
+    assert_eq!(report.added, vec!["Voters".to_owned()]);
+    assert!(g.compare_shadow("Votes", &[vec![1.into()]]).is_ok());
+    let e = g.compare_shadow("Voters", &[vec![1.into()]]).unwrap_err();
+    assert!(e.to_string().contains("no live version"));
+}
+
+#[test]
+fn recipes_can_be_planned_without_changing_the_graph() {
+    let mut g = start_simple("recipes_can_be_planned_without_changing_the_graph");
+    g.install_recipe(
+        "CREATE TABLE Vote (article_id int, user int);
+         QUERY Votes: SELECT article_id, user FROM Vote WHERE article_id = ?;",
+    )
+    .unwrap();
+    let mut vote = g.table("Vote").unwrap().into_sync();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+
+    let count = "QUERY VoteCount: SELECT article_id, COUNT(user) AS votes FROM Vote \
+                 WHERE article_id = ? GROUP BY article_id;";
+    let plan = g.plan_recipe(count).unwrap();
+    assert!(!plan.new_domains.is_empty());
+    assert!(!plan.replay_paths.is_empty());
+    assert_eq!(g.outputs().unwrap().len(), 1);
+    assert!(g.view("VoteCount").is_err());
+
+    // the planned nodes are what extending the recipe adds
+    let added = g.extend_recipe(count).unwrap();
+    let leaf = added.new_nodes["VoteCount"];
+    assert!(plan.nodes.iter().any(|n| n.node == leaf));
+    sleep();
+    let mut counts = g.view("VoteCount").unwrap().into_sync();
+    assert_eq!(
+        counts.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+}
+
+#[test]
+fn recipe_changes_report_what_they_changed() {
+    let mut g = start_simple("recipe_changes_report_what_they_changed");
+    let installed = g
+        .install_recipe(
+            "CREATE TABLE Vote (article_id int, user int);
+             QUERY Votes: SELECT article_id, user FROM Vote WHERE article_id = ?;",
+        )
+        .unwrap()
+        .migration
+        .unwrap();
+    assert!(!installed.new_domains.is_empty());
+    let votes = &installed.readers[0];
+    assert_eq!((&votes.name[..], &votes.key[..]), ("Votes", &[0][..]));
+    let mut vote = g.table("Vote").unwrap().into_sync();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+
+    // the count is built on the base table that is already there, and is filled by a replay
+    let extended = g
+        .extend_recipe(
+            "QUERY VoteCount: SELECT article_id, COUNT(user) AS votes FROM Vote \
+             WHERE article_id = ? GROUP BY article_id;",
+        )
+        .unwrap();
+    let result = extended.migration.unwrap();
+    let leaf = extended.new_nodes["VoteCount"];
+    assert!(result.nodes.iter().any(|n| n.node == leaf));
+    assert!(result
+        .nodes
+        .iter()
+        .all(|n| installed.nodes.iter().all(|m| m.node != n.node)));
+    assert!(!result.reused.is_empty());
+    assert!(result
+        .reused
+        .iter()
+        .all(|&ni| installed.nodes.iter().any(|n| n.node == ni)));
+    assert_eq!(result.readers.len(), 1);
+    assert!(!result.swaps.is_empty());
+    assert!(result.removed.is_empty());
+    assert_eq!(result.progress.step, MigrationStep::Done);
+    assert!(result.progress.nodes_ready > 0);
+}
+
+#[test]
+fn recipe_changes_report_what_queries_share() {
+    let mut g = start_simple("recipe_changes_report_what_queries_share");
+    let installed = g
+        .install_recipe(
+            "CREATE TABLE Visit (page int, user int);
+             QUERY Visits: SELECT page, user FROM Visit WHERE page = ?;",
+        )
+        .unwrap();
+    let visit = installed.new_nodes["Visit"];
+    assert!(!installed.reuse.contains_key("Visit"));
+    assert!(installed.reuse["Visits"].reused.contains(&visit));
+    assert!(!installed.reuse["Visits"].added.is_empty());
+
+    // an estimate is built anew, and says why
+    let extended = g
+        .extend_recipe(
+            "QUERY Visitors: SELECT page, APPROX_COUNT_DISTINCT(user) AS n FROM Visit \
+             WHERE page = ? GROUP BY page;",
+        )
+        .unwrap();
+    let visitors = &extended.reuse["Visitors"];
+    assert!(visitors.reused.contains(&visit));
+    assert!(!visitors.added.is_empty());
+    assert!(visitors.rejected[0].contains("estimates counts"));
+}
+
+#[test]
+fn migrations_can_be_rolled_back() {
+    let mut g = start_simple("migrations_can_be_rolled_back");
+    // installing the recipe brings it to version 1
+    g.install_recipe(
+        "CREATE TABLE Vote (article_id int, user int);
+         QUERY Votes: SELECT article_id, user FROM Vote WHERE article_id = ?;",
+    )
+    .unwrap();
+    g.extend_recipe(
+        "QUERY VoteCount: SELECT article_id, COUNT(user) AS votes FROM Vote \
+            WHERE article_id = ? GROUP BY article_id;",
+    )
+    .unwrap();
+    assert_eq!(g.outputs().unwrap().len(), 2);
+    assert!(g.rollback_migration(2).is_err());
+
+    let nodes = g.rollback_migration(1).unwrap().new_nodes;
+    assert!(nodes.is_empty());
+    assert_eq!(g.outputs().unwrap().len(), 1);
+    assert!(g.view("VoteCount").is_err());
+
+    // the queries that are left still see writes
+    let mut vote = g.table("Vote").unwrap().into_sync();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+    let mut votes = g.view("Votes").unwrap().into_sync();
+    assert_eq!(
+        votes.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 7.into()]]
+    );
+}
+
+#[test]
+fn queries_can_be_removed_one_at_a_time() {
+    let mut g = start_simple("queries_can_be_removed_one_at_a_time");
+    g.install_recipe(
+        "CREATE TABLE Vote (article_id int, user int);
+         QUERY Votes: SELECT article_id, user FROM Vote WHERE article_id = ?;",
+    )
+    .unwrap();
+    g.extend_recipe(
+        "QUERY VoteCount: SELECT article_id, COUNT(user) AS votes FROM Vote \
+            WHERE article_id = ? GROUP BY article_id;",
+    )
+    .unwrap();
+    assert_eq!(g.outputs().unwrap().len(), 2);
+    assert!(g.remove_query("Vote").is_err());
+    assert!(g.remove_query("NoSuchQuery").is_err());
+
+    g.remove_query("VoteCount").unwrap();
+    assert_eq!(g.outputs().unwrap().len(), 1);
+    assert!(g.view("VoteCount").is_err());
+
+    let mut vote = g.table("Vote").unwrap().into_sync();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+    let mut votes = g.view("Votes").unwrap().into_sync();
+    assert_eq!(
+        votes.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 7.into()]]
+    );
+
+    // rolling back to before the removal brings the query back
+    g.rollback_migration(2).unwrap();
+    sleep();
+    let mut counts = g.view("VoteCount").unwrap().into_sync();
+    assert_eq!(
+        counts.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+}
+
+#[test]
+fn graph_invariants_hold_across_migrations() {
+    let mut g = start_simple("graph_invariants_hold_across_migrations");
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         CREATE TABLE Vote (article_id int, user int);
+         QUERY ArticleWithVoteCount: SELECT Article.id, title, VoteCount.votes AS votes \
+            FROM Article \
+            LEFT JOIN (SELECT Vote.article_id, COUNT(user) AS votes \
+                       FROM Vote GROUP BY Vote.article_id) AS VoteCount \
+            ON (Article.id = VoteCount.article_id) WHERE Article.id = ?;",
+    )
+    .unwrap();
+    assert_eq!(g.check_invariants().unwrap(), vec![]);
+
+    // a query that shards the votes differently, and one that is later removed again
+    g.extend_recipe(
+        "QUERY VotesByUser: SELECT user, COUNT(article_id) AS votes FROM Vote \
+            WHERE user = ? GROUP BY user;
+         QUERY Titles: SELECT title FROM Article WHERE id = ?;",
+    )
+    .unwrap();
+    assert_eq!(g.check_invariants().unwrap(), vec![]);
+
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         CREATE TABLE Vote (article_id int, user int);
+         QUERY ArticleWithVoteCount: SELECT Article.id, title, VoteCount.votes AS votes \
+            FROM Article \
+            LEFT JOIN (SELECT Vote.article_id, COUNT(user) AS votes \
+                       FROM Vote GROUP BY Vote.article_id) AS VoteCount \
+            ON (Article.id = VoteCount.article_id) WHERE Article.id = ?;
+         QUERY VotesByUser: SELECT user, COUNT(article_id) AS votes FROM Vote \
+            WHERE user = ? GROUP BY user;",
+    )
+    .unwrap();
+    assert_eq!(g.check_invariants().unwrap(), vec![]);
+}
+
+#[test]
+fn probes_reach_every_view() {
+    let mut g = start_simple("probes_reach_every_view");
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         CREATE TABLE Vote (article_id int, user int);
+         QUERY Votes: SELECT article_id, COUNT(user) AS votes FROM Vote \
+            WHERE article_id = ? GROUP BY article_id;
+         QUERY ArticleWithVoteCount: SELECT Article.id, title, VoteCount.votes AS votes \
+            FROM Article \
+            LEFT JOIN (SELECT Vote.article_id, COUNT(user) AS votes \
+                       FROM Vote GROUP BY Vote.article_id) AS VoteCount \
+            ON (Article.id = VoteCount.article_id) WHERE Article.id = ?;",
+    )
+    .unwrap();
+    let mut vote = g.table("Vote").unwrap().into_sync();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+
+    let latencies = g.propagation_latencies(Duration::from_secs(10)).unwrap();
+    assert!(latencies.contains_key("Votes"));
+    assert!(latencies.contains_key("ArticleWithVoteCount"));
+
+    // probes do not show up in the views
+    let mut votes = g.view("Votes").unwrap().into_sync();
+    assert_eq!(
+        votes.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+}
+
+#[test]
+fn domains_dump_captured_packets() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut params = get_persistence_params("domains_dump_captured_packets");
+    params.log_dir = Some(dir.path().to_path_buf());
+    let mut builder = Builder::default();
+    builder.set_sharding(None);
+    builder.set_persistence(params);
+    builder.set_packet_capture(16);
+    let mut g = builder.start_simple().unwrap();
+    g.install_recipe(
+        "CREATE TABLE Vote (article_id int, user int);
+         QUERY Votes: SELECT article_id, COUNT(user) AS votes FROM Vote \
+            WHERE article_id = ? GROUP BY article_id;",
+    )
+    .unwrap();
+    let mut vote = g.table("Vote").unwrap().into_sync();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+
+    g.dump_packets().unwrap();
+    let dumped: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.to_string_lossy().ends_with("-packets.json"))
+        .map(|p| std::fs::read_to_string(p).unwrap())
+        .collect();
+    assert!(!dumped.is_empty());
+    assert!(dumped.iter().any(|d| d.contains("Packet::Input")));
+}
+
+#[test]
+fn views_report_their_freshness() {
+    let mut g = start_simple("views_report_their_freshness");
+    g.install_recipe(
+        "CREATE TABLE Vote (article_id int, user int);
+         QUERY Votes: SELECT article_id, COUNT(user) AS votes FROM Vote \
+            WHERE article_id = ? GROUP BY article_id;",
+    )
+    .unwrap();
+    let vote_node = g.inputs().unwrap()["Vote"];
+    let mut votes = g.view("Votes").unwrap().into_sync();
+    let mut vote = g.table("Vote").unwrap().into_sync();
+    assert!(votes.freshness().unwrap().is_empty());
+
+    // writes only count once they reach the view, so make sure the key isn't a hole
+    assert!(votes.lookup(&[1.into()], true).unwrap().is_empty());
+    let before = std::time::SystemTime::now();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+
+    let freshness = votes.freshness().unwrap();
+    assert_eq!(freshness.len(), 1);
+    assert!(freshness[&vote_node] >= before);
+
+    let stats = g.statistics().unwrap();
+    assert!(stats
+        .values()
+        .flat_map(|(_, nodes)| nodes.values())
+        .any(|n| n.freshness.get(&vote_node) == Some(&freshness[&vote_node])));
+}
+
+#[test]
+fn reads_at_a_snapshot_see_earlier_writes() {
+    let mut g = start_simple("reads_at_a_snapshot_see_earlier_writes");
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         CREATE TABLE Vote (article_id int, user int);
+         QUERY Votes: SELECT article_id, COUNT(user) AS votes FROM Vote \
+            WHERE article_id = ? GROUP BY article_id;
+         QUERY ArticleWithVoteCount: SELECT Article.id, title, VoteCount.votes AS votes \
+            FROM Article \
+            LEFT JOIN (SELECT Vote.article_id, COUNT(user) AS votes \
+                       FROM Vote GROUP BY Vote.article_id) AS VoteCount \
+            ON (Article.id = VoteCount.article_id) WHERE Article.id = ?;",
+    )
+    .unwrap();
+    let mut article = g.table("Article").unwrap().into_sync();
+    let mut vote = g.table("Vote").unwrap().into_sync();
+    let mut votes = g.view("Votes").unwrap().into_sync();
+    let mut awvc = g.view("ArticleWithVoteCount").unwrap().into_sync();
+
+    article.insert(vec![1.into(), "Article".into()]).unwrap();
+    vote.insert(vec![1.into(), 7.into()]).unwrap();
+    vote.insert(vec![1.into(), 8.into()]).unwrap();
+
+    // no sleep: the snapshot makes the reads wait for the writes
+    let snapshot = g.snapshot().unwrap();
+    votes.set_snapshot(Some(snapshot));
+    awvc.set_snapshot(Some(snapshot));
+    assert_eq!(
+        votes.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+    assert_eq!(
+        awvc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "Article".into(), 2.into()]]
+    );
+
+    // later snapshots are ordered after earlier ones
+    assert!(g.snapshot().unwrap() > snapshot);
+}
+
+#[test]
+fn references_between_tables_are_enforced() {
+    let mut g = start_simple("references_between_tables_are_enforced");
+    g.install_recipe(
+        "CREATE TABLE users (id int, name varchar(255), PRIMARY KEY(id));
+         CREATE TABLE orders (id int, user_id int REFERENCES users(id) ON DELETE CASCADE, \
+            PRIMARY KEY(id));
+         QUERY UserOrders: SELECT id, user_id FROM orders WHERE user_id = ?;",
+    )
+    .unwrap();
+    let mut users = g.table("users").unwrap().into_sync();
+    let mut orders = g.table("orders").unwrap().into_sync();
+    let mut user_orders = g.view("UserOrders").unwrap().into_sync();
+
+    users.insert(vec![1.into(), "alice".into()]).unwrap();
+    sleep();
+    orders.insert(vec![1.into(), 1.into()]).unwrap();
+    // there is no user 2 to refer to
+    match orders.insert(vec![2.into(), 2.into()]) {
+        Err(noria::error::TableError::ConstraintViolation(ref vs)) => match vs[..] {
+            [(0, noria::error::ConstraintViolation::Reference(ref v))] => assert_eq!(
+                *v,
+                noria::error::ReferenceViolation::Missing {
+                    column: 1,
+                    value: 2.into(),
+                }
+            ),
+            _ => panic!("unexpected violations: {:?}", vs),
+        },
+        r => panic!("dangling reference was not refused: {:?}", r),
+    }
+    sleep();
+    assert_eq!(
+        user_orders.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+    assert!(user_orders.lookup(&[2.into()], true).unwrap().is_empty());
+
+    // deleting the user takes their orders with them
+    users.delete(vec![1.into()]).unwrap();
+    sleep();
+    assert!(user_orders.lookup(&[1.into()], true).unwrap().is_empty());
+}
+
+#[test]
+fn deletes_of_referenced_rows_are_refused() {
+    let mut g = start_simple("deletes_of_referenced_rows_are_refused");
+    g.install_recipe(
+        "CREATE TABLE users (id int, name varchar(255), PRIMARY KEY(id));
+         CREATE TABLE orders (id int, user_id int REFERENCES users(id), PRIMARY KEY(id));
+         QUERY UserById: SELECT id, name FROM users WHERE id = ?;",
+    )
+    .unwrap();
+    let mut users = g.table("users").unwrap().into_sync();
+    let mut orders = g.table("orders").unwrap().into_sync();
+    let mut user_by_id = g.view("UserById").unwrap().into_sync();
+
+    users.insert(vec![1.into(), "alice".into()]).unwrap();
+    sleep();
+    orders.insert(vec![1.into(), 1.into()]).unwrap();
+    sleep();
+    match users.delete(vec![1.into()]) {
+        Err(noria::error::TableError::ConstraintViolation(ref vs)) => match vs[..] {
+            [(0, noria::error::ConstraintViolation::Reference(ref v))] => assert_eq!(
+                *v,
+                noria::error::ReferenceViolation::Referenced { key: 1.into() }
+            ),
+            _ => panic!("unexpected violations: {:?}", vs),
+        },
+        r => panic!("delete of a referenced row was not refused: {:?}", r),
+    }
+    sleep();
+    assert_eq!(
+        user_by_id.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "alice".into()]]
+    );
+}
+
+#[test]
+fn write_groups_are_seen_all_at_once() {
+    let mut g = start_simple("write_groups_are_seen_all_at_once");
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         CREATE TABLE Vote (article_id int, user int);
+         QUERY ArticleWithVoteCount: SELECT Article.id, title, VoteCount.votes AS votes \
+            FROM Article \
+            LEFT JOIN (SELECT Vote.article_id, COUNT(user) AS votes \
+                       FROM Vote GROUP BY Vote.article_id) AS VoteCount \
+            ON (Article.id = VoteCount.article_id) WHERE Article.id = ?;",
+    )
+    .unwrap();
+    let article = g.table("Article").unwrap();
+    let vote = g.table("Vote").unwrap();
+    let mut awvc = g.view("ArticleWithVoteCount").unwrap().into_sync();
+
+    let group = article
+        .write_group(vec![vec![DataType::from(1), "Article".into()]])
+        .and(
+            &vote,
+            vec![
+                vec![DataType::from(1), 7.into()],
+                vec![DataType::from(1), 8.into()],
+            ],
+        );
+    g.write_group(group).unwrap();
+
+    let snapshot = g.snapshot().unwrap();
+    awvc.set_snapshot(Some(snapshot));
+    assert_eq!(
+        awvc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "Article".into(), 2.into()]]
+    );
+}
+
+#[test]
+fn write_groups_are_not_seen_while_open() {
+    let mut g = start_simple("write_groups_are_not_seen_while_open");
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;",
+    )
+    .unwrap();
+    let mut article = g.table("Article").unwrap().into_sync();
+    let mut by_id = g.view("ArticleById").unwrap().into_sync();
+
+    let open = g
+        .handle()
+        .rpc::<_, u64>("open_write_group", (), "failed to open write group");
+    let id = g.run(open).unwrap();
+    sleep();
+
+    // views hold back every write while a group is open, so none of a group shows up early
+    article.insert(vec![1.into(), "Article".into()]).unwrap();
+    sleep();
+    assert!(by_id.lookup(&[1.into()], true).unwrap().is_empty());
+
+    let close = g.handle().rpc::<_, ()>(
+        "close_write_group",
+        (id, false),
+        "failed to close write group",
+    );
+    g.run(close).unwrap();
+    sleep();
+    assert_eq!(
+        by_id.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "Article".into()]]
+    );
+}
+
+#[test]
+fn write_groups_with_refused_writes_apply_nothing() {
+    let mut g = start_simple("write_groups_with_refused_writes_apply_nothing");
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         CREATE TABLE Vote (article_id int, user int CHECK (user > 0));
+         QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;
+         QUERY VotesByArticle: SELECT article_id, user FROM Vote WHERE article_id = ?;",
+    )
+    .unwrap();
+    let article = g.table("Article").unwrap();
+    let vote = g.table("Vote").unwrap();
+    let mut by_id = g.view("ArticleById").unwrap().into_sync();
+    let mut votes = g.view("VotesByArticle").unwrap().into_sync();
+
+    let group = article
+        .write_group(vec![vec![DataType::from(1), "Article".into()]])
+        .and(
+            &vote,
+            vec![
+                vec![DataType::from(1), 7.into()],
+                vec![DataType::from(1), 0.into()],
+            ],
+        );
+    assert!(g.write_group(group).is_err());
+
+    let snapshot = g.snapshot().unwrap();
+    by_id.set_snapshot(Some(snapshot));
+    votes.set_snapshot(Some(snapshot));
+    assert!(by_id.lookup(&[1.into()], true).unwrap().is_empty());
+    assert!(votes.lookup(&[1.into()], true).unwrap().is_empty());
+}
+
+#[test]
+fn views_export_a_consistent_cut() {
+    let mut b = Builder::default();
+    b.disable_partial();
+    b.set_sharding(Some(2));
+    b.set_persistence(get_persistence_params("views_export_a_consistent_cut"));
+    let mut g = b.start_simple().unwrap();
+    g.install_recipe(
+        "CREATE TABLE comments (id int, story int, PRIMARY KEY(id));
+         QUERY ByStory: SELECT id, story FROM comments WHERE story = ?;",
+    )
+    .unwrap();
+    let mut comments = g.table("comments").unwrap().into_sync();
+    for id in 0..8 {
+        comments.insert(vec![id.into(), (id % 4).into()]).unwrap();
+    }
+
+    // no sleep: the export waits for the writes to reach every shard
+    let mut rows = g.export_view("ByStory").unwrap();
+    rows.sort();
+    let mut expected: Vec<Vec<DataType>> =
+        (0..8).map(|id| vec![id.into(), (id % 4).into()]).collect();
+    expected.sort();
+    assert_eq!(rows, expected);
+
+    comments.delete(vec![0.into()]).unwrap();
+    assert_eq!(g.export_view("ByStory").unwrap().len(), 7);
+    assert!(g.export_view("NoSuchView").is_err());
+}
+
+#[test]
+fn write_groups_go_straight_to_sharded_tables() {
+    let mut b = Builder::default();
+    b.disable_partial();
+    b.set_sharding(Some(2));
+    b.set_persistence(get_persistence_params(
+        "write_groups_go_straight_to_sharded_tables",
+    ));
+    let mut g = b.start_simple().unwrap();
+    g.install_recipe(
+        "CREATE TABLE comments (id int, story int, PRIMARY KEY(id));
+         QUERY ByStory: SELECT id, story FROM comments WHERE story = ?;",
+    )
+    .unwrap();
+    let comments = g.table("comments").unwrap();
+
+    let group = comments.write_group((0..8).map(|id| vec![id.into(), (id % 4).into()]));
+    g.write_group(group).unwrap();
+    assert_eq!(g.export_view("ByStory").unwrap().len(), 8);
+
+    // the group has ended on every shard, so it holds back no later writes
+    let mut comments = comments.into_sync();
+    comments.insert(vec![9.into(), 1.into()]).unwrap();
+    assert_eq!(g.export_view("ByStory").unwrap().len(), 9);
+}
+
+#[test]
+fn unique_columns_are_enforced() {
+    let mut g = start_simple("unique_columns_are_enforced");
+    g.install_recipe(
+        "CREATE TABLE users (id int, email varchar(255) UNIQUE, PRIMARY KEY(id));
+         QUERY UserByEmail: SELECT id FROM users WHERE email = ?;",
+    )
+    .unwrap();
+    let mut users = g.table("users").unwrap().into_sync();
+    let mut by_email = g.view("UserByEmail").unwrap().into_sync();
+
+    users.insert(vec![1.into(), "a@b.c".into()]).unwrap();
+    sleep();
+    match users.insert(vec![2.into(), "a@b.c".into()]) {
+        Err(noria::error::TableError::ConstraintViolation(ref vs)) => match vs[..] {
+            [(0, noria::error::ConstraintViolation::Unique(ref v))] => {
+                assert_eq!(v.columns, vec![1])
+            }
+            _ => panic!("unexpected violations: {:?}", vs),
+        },
+        r => panic!("duplicate email was not refused: {:?}", r),
+    }
+    // a different email is fine
+    users.insert(vec![3.into(), "d@e.f".into()]).unwrap();
+    sleep();
+    assert_eq!(
+        by_email.lookup(&["a@b.c".into()], true).unwrap(),
+        vec![vec![1.into()]]
+    );
+}
+
+#[test]
+fn checks_are_enforced() {
+    use noria::Modification;
+
+    let mut g = start_simple("checks_are_enforced");
+    g.install_recipe(
+        "CREATE TABLE items (id int, price int CHECK (price > 0), status varchar(8), \
+            PRIMARY KEY(id), CONSTRAINT known CHECK (status IN ('open', 'closed')));
+         QUERY Items: SELECT id, price FROM items WHERE status = ?;",
+    )
+    .unwrap();
+    let mut items = g.table("items").unwrap().into_sync();
+    let mut open = g.view("Items").unwrap().into_sync();
+
+    items
+        .insert(vec![1.into(), 10.into(), "open".into()])
+        .unwrap();
+    match items.insert(vec![2.into(), 0.into(), "open".into()]) {
+        Err(noria::error::TableError::ConstraintViolation(ref vs)) => match vs[..] {
+            [(0, noria::error::ConstraintViolation::Check(ref v))] => {
+                assert_eq!(v.check, "price > 0")
+            }
+            _ => panic!("unexpected violations: {:?}", vs),
+        },
+        r => panic!("non-positive price was not refused: {:?}", r),
+    }
+    assert!(items
+        .insert(vec![3.into(), 10.into(), "lost".into()])
+        .is_err());
+    // updates must leave rows that meet the checks too
+    assert!(items
+        .update(vec![1.into()], vec![(1, Modification::Set((-1).into()))])
+        .is_err());
+    sleep();
+
+    assert_eq!(
+        open.lookup(&["open".into()], true).unwrap(),
+        vec![vec![1.into(), 10.into()]]
+    );
+}
+
+#[test]
+fn writes_report_what_became_of_each_row() {
+    use noria::error::{ConstraintViolation, RowError};
+
+    let mut g = start_simple("writes_report_what_became_of_each_row");
+    g.install_recipe(
+        "CREATE TABLE users (id int, email varchar(255) UNIQUE, PRIMARY KEY(id));
+         QUERY Users: SELECT id, email FROM users WHERE id = ?;",
+    )
+    .unwrap();
+    let mut users = g.table("users").unwrap().into_sync();
+    let mut by_id = g.view("Users").unwrap().into_sync();
+
+    users.insert(vec![1.into(), "a@b.c".into()]).unwrap();
+    sleep();
+    let results = users
+        .perform_each(vec![
+            vec![2.into(), "d@e.f".into()],
+            vec![3.into(), "a@b.c".into()],
+            vec![4.into()],
+            vec![5.into(), 42.into()],
+            vec![6.into(), "g@h.i".into()],
+        ])
+        .unwrap();
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[0], Ok(()));
+    match results[1] {
+        Err(RowError::ConstraintViolation(ConstraintViolation::Unique(ref v))) => {
+            assert_eq!(v.columns, vec![1])
+        }
+        ref r => panic!("duplicate email was not refused: {:?}", r),
+    }
+    assert_eq!(results[2], Err(RowError::WrongColumnCount(2, 1)));
+    match results[3] {
+        Err(RowError::WrongType(ref v)) => assert_eq!(v.column, 1),
+        ref r => panic!("number for an email was not refused: {:?}", r),
+    }
+    assert_eq!(results[4], Ok(()));
+    sleep();
+
+    // the rows that were fine were written all the same
+    for &(id, found) in &[(2, true), (3, false), (5, false), (6, true)] {
+        let rows = by_id.lookup(&[id.into()], true).unwrap();
+        assert_eq!(rows.len(), found as usize, "row {}", id);
+    }
+}
+
+#[test]
+fn mistyped_values_are_handled_as_tables_ask() {
+    use noria::error::{ConstraintViolation, TableError};
+
+    let mut g = start_simple("mistyped_values_are_handled_as_tables_ask");
+    g.install_recipe(
+        "CREATE TABLE exact (id int, n int, PRIMARY KEY(id)) ON TYPE MISMATCH REJECT;
+         CREATE TABLE loose (id int, n int, PRIMARY KEY(id)) ON TYPE MISMATCH COERCE;
+         QUERY Exact: SELECT id, n FROM exact WHERE id = ?;
+         QUERY Loose: SELECT id, n FROM loose WHERE id = ?;",
+    )
+    .unwrap();
+    let mut exact = g.table("exact").unwrap().into_sync();
+    let mut loose = g.table("loose").unwrap().into_sync();
+    let mut exact_view = g.view("Exact").unwrap().into_sync();
+    let mut loose_view = g.view("Loose").unwrap().into_sync();
+
+    match exact.insert(vec![1.into(), "2".into()]) {
+        Err(TableError::ConstraintViolation(ref vs)) => match vs[..] {
+            [(0, ConstraintViolation::Type(ref v))] => assert_eq!(v.column, 1),
+            _ => panic!("unexpected violations: {:?}", vs),
+        },
+        r => panic!("text for an int was not refused: {:?}", r),
+    }
+    loose.insert(vec![1.into(), "2".into()]).unwrap();
+    sleep();
+
+    assert!(exact_view.lookup(&[1.into()], true).unwrap().is_empty());
+    assert_eq!(
+        loose_view.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+}
+
+#[test]
+fn writes_report_mistyped_rows_only_where_tables_refuse_them() {
+    use noria::error::RowError;
+
+    let mut g = start_simple("writes_report_mistyped_rows_only_where_tables_refuse_them");
+    g.install_recipe(
+        "CREATE TABLE strict (id int, n int, PRIMARY KEY(id)) ON TYPE MISMATCH REJECT;
+         CREATE TABLE lenient (id int, n int, PRIMARY KEY(id)) ON TYPE MISMATCH LOG;
+         QUERY Strict: SELECT id, n FROM strict WHERE id = ?;
+         QUERY Lenient: SELECT id, n FROM lenient WHERE id = ?;",
+    )
+    .unwrap();
+    let mut strict = g.table("strict").unwrap().into_sync();
+    let mut lenient = g.table("lenient").unwrap().into_sync();
+    let mut strict_view = g.view("Strict").unwrap().into_sync();
+    let mut lenient_view = g.view("Lenient").unwrap().into_sync();
+
+    let rows = vec![vec![1.into(), 1.into()], vec![2.into(), "two".into()]];
+    let results = strict.perform_each(rows.clone()).unwrap();
+    assert_eq!(results[0], Ok(()));
+    match results[1] {
+        Err(RowError::WrongType(ref v)) => assert_eq!(v.column, 1),
+        ref r => panic!("text for an int was not refused: {:?}", r),
+    }
+    assert_eq!(lenient.perform_each(rows).unwrap(), vec![Ok(()), Ok(())]);
+    sleep();
+
+    assert!(strict_view.lookup(&[2.into()], true).unwrap().is_empty());
+    assert_eq!(
+        lenient_view.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), "two".into()]]
+    );
+}
+
+#[test]
+fn generated_columns_are_computed() {
+    use noria::Modification;
+
+    let mut g = start_simple("generated_columns_are_computed");
+    g.install_recipe(
+        "CREATE TABLE lines (id int, price int, qty int, total int AS (price * qty), \
+            PRIMARY KEY(id));
+         QUERY LinesByTotal: SELECT id FROM lines WHERE total = ?;",
+    )
+    .unwrap();
+    let mut lines = g.table("lines").unwrap().into_sync();
+    let mut by_total = g.view("LinesByTotal").unwrap().into_sync();
+
+    // whatever is written to the generated column is replaced
+    lines
+        .insert(vec![1.into(), 3.into(), 4.into(), DataType::None])
+        .unwrap();
+    sleep();
+    assert_eq!(
+        by_total.lookup(&[12.into()], true).unwrap(),
+        vec![vec![1.into()]]
+    );
+
+    lines
+        .update(vec![1.into()], vec![(2, Modification::Set(5.into()))])
+        .unwrap();
+    sleep();
+    assert!(by_total.lookup(&[12.into()], true).unwrap().is_empty());
+    assert_eq!(
+        by_total.lookup(&[15.into()], true).unwrap(),
+        vec![vec![1.into()]]
+    );
+}
+
+#[test]
+fn computed_defaults_are_filled_in() {
+    let mut g = start_simple("computed_defaults_are_filled_in");
+    g.install_recipe(
+        "CREATE TABLE posts (id int AUTO_INCREMENT, token varchar(36) DEFAULT UUID(), \
+            title varchar(255), PRIMARY KEY(id));
+         QUERY Post: SELECT id, token, title FROM posts WHERE id = ?;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut post = g.view("Post").unwrap().into_sync();
+
+    posts
+        .insert(vec![DataType::None, DataType::None, "first".into()])
+        .unwrap();
+    posts
+        .insert(vec![DataType::None, DataType::None, "second".into()])
+        .unwrap();
+    sleep();
+
+    let first = post.lookup(&[1.into()], true).unwrap();
+    let second = post.lookup(&[2.into()], true).unwrap();
+    assert_eq!(first.len(), 1);
+    assert_eq!(second.len(), 1);
+    assert_eq!(first[0][2], "first".into());
+    assert_eq!(second[0][2], "second".into());
+    let token: String = first[0][1].clone().into();
+    assert_eq!(token.len(), 36);
+    assert_ne!(first[0][1], second[0][1]);
+}
+
+#[test]
+fn soft_deleted_rows_are_hidden() {
+    let mut g = start_simple("soft_deleted_rows_are_hidden");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, title varchar(255), deleted_at datetime SOFT DELETE, \
+            PRIMARY KEY(id));
+         QUERY Post: SELECT id, title FROM posts WHERE id = ?;
+         QUERY Deleted: SELECT id, deleted_at FROM posts WHERE deleted_at != NULL;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut post = g.view("Post").unwrap().into_sync();
+    let mut deleted = g.view("Deleted").unwrap().into_sync();
+
+    posts
+        .insert(vec![1.into(), "first".into(), DataType::None])
+        .unwrap();
+    posts
+        .insert(vec![2.into(), "second".into(), DataType::None])
+        .unwrap();
+    sleep();
+    assert_eq!(post.lookup(&[1.into()], true).unwrap().len(), 1);
+    assert!(deleted.lookup(&[0.into()], true).unwrap().is_empty());
+
+    posts.delete(vec![1.into()]).unwrap();
+    sleep();
+
+    // the row is gone from the view, but still in the table
+    assert!(post.lookup(&[1.into()], true).unwrap().is_empty());
+    assert_eq!(post.lookup(&[2.into()], true).unwrap().len(), 1);
+    let rows = deleted.lookup(&[0.into()], true).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0][0], 1.into());
+    assert!(!rows[0][1].is_none());
+}
+
+#[test]
+fn views_keep_their_order() {
+    let mut g = start_simple("views_keep_their_order");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, score int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id, score FROM posts WHERE author = ? ORDER BY score DESC;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+
+    for &(id, score) in &[(1, 5), (2, 9), (3, 1), (4, 7)] {
+        posts
+            .insert(vec![id.into(), 1.into(), score.into()])
+            .unwrap();
+    }
+    sleep();
+
+    let rows = by_author.lookup(&[1.into()], true).unwrap();
+    let ids: Vec<DataType> = rows.into_iter().map(|r| r[0].clone()).collect();
+    assert_eq!(ids, vec![2.into(), 4.into(), 1.into(), 3.into()]);
+}
+
+#[test]
+fn distinct_views_drop_duplicate_rows() {
+    let mut g = start_simple("distinct_views_drop_duplicate_rows");
+    g.install_recipe(
+        "CREATE TABLE votes (id int, post int, user int, PRIMARY KEY(id));
+         QUERY Voters: SELECT DISTINCT user FROM votes WHERE post = ?;",
+    )
+    .unwrap();
+    let mut votes = g.table("votes").unwrap().into_sync();
+    let mut voters = g.view("Voters").unwrap().into_sync();
+
+    for &(id, user) in &[(1, 7), (2, 7), (3, 8)] {
+        votes
+            .insert(vec![id.into(), 1.into(), user.into()])
+            .unwrap();
+    }
+    sleep();
+    assert_eq!(voters.lookup(&[1.into()], true).unwrap().len(), 2);
+
+    // the user is still there as long as one of their votes is
+    votes.delete(vec![1.into()]).unwrap();
+    sleep();
+    assert_eq!(voters.lookup(&[1.into()], true).unwrap().len(), 2);
+
+    votes.delete(vec![2.into()]).unwrap();
+    sleep();
+    let rows = voters.lookup(&[1.into()], true).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0][0], 8.into());
+}
+
+#[test]
+fn config_changes_while_running() {
+    use noria::ConfigUpdate;
+
+    let mut g = start_simple("config_changes_while_running");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+
+    // nothing is applied unless all of it is valid
+    assert!(g
+        .update_config(ConfigUpdate {
+            concurrent_replays: Some(0),
+            replay_batch_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        })
+        .is_err());
+    g.update_config(ConfigUpdate {
+        memory_limit: Some(0),
+        replay_batch_timeout: Some(Duration::from_millis(1)),
+        concurrent_replays: Some(1),
+    })
+    .unwrap();
+
+    // replays still go through with the new limits
+    for i in 0..10 {
+        posts.insert(vec![i.into(), (i % 5).into()]).unwrap();
+    }
+    sleep();
+    for author in 0..5 {
+        assert_eq!(by_author.lookup(&[author.into()], true).unwrap().len(), 2);
+    }
+}
+
+#[test]
+fn placement_follows_worker_labels() {
+    let mut builder = Builder::default();
+    builder.add_label("disk", "ssd");
+    builder.set_persistence(get_persistence_params("placement_follows_worker_labels"));
+    let mut g = builder.start_simple().unwrap();
+
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id)) PLACE ON disk = ssd;
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ? SPREAD ACROSS zone;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+    posts.insert(vec![1.into(), 2.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        by_author.lookup(&[2.into()], true).unwrap(),
+        vec![vec![1.into()]]
+    );
+
+    // nothing can be placed where no worker is
+    assert!(g
+        .extend_recipe("QUERY Slow: SELECT id FROM posts WHERE id = ? PLACE ON disk = hdd;")
+        .is_err());
+}
+
+#[test]
+fn reader_only_workers_serve_reads() {
+    let authority = Arc::new(LocalAuthority::new());
+    let mut b = Builder::default();
+    b.set_quorum(2);
+    b.set_persistence(get_persistence_params("reader_only_workers_serve_reads"));
+    let mut g = wrap_sync(b.start(authority.clone()));
+    let mut b = Builder::default();
+    b.set_quorum(2);
+    b.set_reader_only();
+    b.set_persistence(get_persistence_params("reader_only_workers_serve_reads_r"));
+    let _readers = wrap_sync(b.start(authority.clone()));
+
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+    posts.insert(vec![1.into(), 2.into()]).unwrap();
+    posts.insert(vec![2.into(), 2.into()]).unwrap();
+    sleep();
+
+    // the reader is in a domain of its own on the other worker, and still sees every write
+    assert_eq!(by_author.lookup(&[2.into()], true).unwrap().len(), 2);
+}
+
+#[test]
+fn draining_moves_queries_off_worker() {
+    let authority = Arc::new(LocalAuthority::new());
+    let mut b = Builder::default();
+    b.set_quorum(2);
+    b.set_persistence(get_persistence_params("draining_moves_queries_off_worker"));
+    let mut g = wrap_sync(b.start(authority.clone()));
+    let mut b = Builder::default();
+    b.set_quorum(2);
+    b.set_persistence(get_persistence_params("draining_moves_queries_other"));
+    let _other = wrap_sync(b.start(authority.clone()));
+
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    posts.insert(vec![1.into(), 2.into()]).unwrap();
+    posts.insert(vec![2.into(), 2.into()]).unwrap();
+    sleep();
+
+    let catalog = g.catalog().unwrap();
+    assert_eq!(catalog.tables["posts"].columns, vec!["id", "author"]);
+    assert!(catalog.views.contains_key("ByAuthor"));
+    let workers = g.workers().unwrap();
+    assert_eq!(workers.len(), 2);
+    assert!(workers.iter().all(|w| w.healthy && !w.draining));
+
+    // drain the worker that does not hold the base table, since its state cannot be moved
+    let base = g
+        .domains()
+        .unwrap()
+        .into_iter()
+        .find(|d| d.nodes.contains(&catalog.tables["posts"].node))
+        .unwrap();
+    let drained = workers
+        .iter()
+        .map(|w| w.addr)
+        .find(|&w| w != base.shards[0])
+        .unwrap();
+    let report = g.drain_worker(drained).unwrap();
+    assert!(report.remaining.is_empty());
+    assert!(g
+        .domains()
+        .unwrap()
+        .iter()
+        .all(|d| !d.shards.contains(&drained)));
+    assert!(g
+        .workers()
+        .unwrap()
+        .iter()
+        .any(|w| w.addr == drained && w.draining));
+
+    // the last worker that can run domains cannot be drained too
+    assert!(g.drain_worker(base.shards[0]).is_err());
+
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+    assert_eq!(by_author.lookup(&[2.into()], true).unwrap().len(), 2);
+}
+
+#[test]
+fn worker_changes_are_planned_without_moving_anything() {
+    let mut g = start_simple("worker_changes_are_planned_without_moving_anything");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    posts.insert(vec![1.into(), 2.into()]).unwrap();
+    sleep();
+
+    let worker = g.workers().unwrap()[0].addr;
+    let domains = g.domains().unwrap();
+    let node = g.catalog().unwrap().tables["posts"].node;
+    let base = domains.iter().find(|d| d.nodes.contains(&node)).unwrap();
+
+    // adding a worker moves nothing unless domains are rebalanced
+    let mut change = WorkerChange {
+        add: vec![NewWorker {
+            cores: 4,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let plan = g.plan_workers(change.clone()).unwrap();
+    assert_eq!(plan.domains.len(), domains.len());
+    assert!(plan.domains.iter().all(|d| d.moved.is_empty()));
+    assert_eq!(plan.moved_bytes, 0);
+
+    // removing the only worker moves everything but the base table to the new one
+    change.remove = vec![worker];
+    let plan = g.plan_workers(change).unwrap();
+    assert_eq!(plan.stuck, vec![base.domain]);
+    for d in &plan.domains {
+        if d.domain == base.domain {
+            assert_eq!(d.shards, vec![PlannedWorker::Existing(worker)]);
+        } else {
+            assert_eq!(d.shards, vec![PlannedWorker::New(0)]);
+            assert_eq!(d.moved, vec![0]);
+        }
+    }
+
+    // nothing actually moved
+    assert_eq!(g.domains().unwrap(), domains);
+    assert!(g.plan_workers(WorkerChange::default()).is_ok());
+    let gone = WorkerChange {
+        remove: vec![worker],
+        ..Default::default()
+    };
+    assert!(g.plan_workers(gone).is_err());
+}
+
+#[test]
+fn replay_paths_can_be_listed() {
+    let mut g = start_simple("replay_paths_can_be_listed");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;",
+    )
+    .unwrap();
+    let posts = g.catalog().unwrap().tables["posts"].node;
+
+    let paths = g.replay_paths("ByAuthor").unwrap();
+    assert!(!paths.is_empty());
+    // misses in the view are filled from the base table
+    let path = paths.iter().find(|p| p.partial).unwrap();
+    assert_eq!(path.index, vec![1]);
+    assert_eq!(path.segments[0].nodes[0], posts);
+    assert_eq!(
+        path.segments.last().unwrap().nodes.last(),
+        Some(&path.target)
+    );
+
+    assert!(g.replay_paths("Missing").is_err());
+}
+
+#[test]
+fn views_can_be_weighted_for_eviction() {
+    let mut g = start_simple("views_can_be_weighted_for_eviction");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+
+    assert!(g.set_eviction_weight("ByAuthor", 0).is_err());
+    assert!(g.set_eviction_weight("Missing", 4).is_err());
+    g.set_eviction_weight("ByAuthor", 4).unwrap();
+
+    // the weight is handed on to the domains of later migrations
+    g.extend_recipe("QUERY ById: SELECT author FROM posts WHERE id = ?;")
+        .unwrap();
+    g.set_eviction_weight("ById", 2).unwrap();
+
+    posts.insert(vec![1.into(), 2.into()]).unwrap();
+    sleep();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+    assert_eq!(
+        by_author.lookup(&[2.into()], true).unwrap(),
+        vec![vec![1.into()]]
+    );
+    g.set_eviction_weight("ByAuthor", 1).unwrap();
+}
+
+#[test]
+fn views_can_publish_writes_less_often() {
+    let mut g = start_simple("views_can_publish_writes_less_often");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+    assert!(g
+        .set_publish_interval("Missing", Some(Duration::from_millis(10)))
+        .is_err());
+
+    // fill the key, so that writes to it are applied rather than dropped
+    assert!(by_author.lookup(&[1.into()], true).unwrap().is_empty());
+    g.set_publish_interval("ByAuthor", Some(Duration::from_secs(60)))
+        .unwrap();
+    posts.insert(vec![1.into(), 1.into()]).unwrap();
+    sleep();
+    assert!(by_author.lookup(&[1.into()], true).unwrap().is_empty());
+
+    // writes that are held on to are made visible once the view stops holding on to them
+    g.set_publish_interval("ByAuthor", None).unwrap();
+    assert_eq!(
+        by_author.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into()]]
+    );
+
+    g.set_publish_interval("ByAuthor", Some(Duration::from_millis(10)))
+        .unwrap();
+    posts.insert(vec![2.into(), 1.into()]).unwrap();
+    sleep();
+    assert_eq!(by_author.lookup(&[1.into()], true).unwrap().len(), 2);
+}
+
+#[test]
+fn views_can_be_awaited() {
+    let mut g = start_simple("views_can_be_awaited");
+    g.install_recipe("CREATE TABLE posts (id int, author int, PRIMARY KEY(id));")
+        .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    posts.insert(vec![1.into(), 1.into()]).unwrap();
+    posts.insert(vec![2.into(), 1.into()]).unwrap();
+    sleep();
+
+    // a view added over existing state can serve it as soon as it is ready
+    g.extend_recipe("QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;")
+        .unwrap();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+    by_author.await_ready().unwrap();
+    assert_eq!(by_author.lookup(&[1.into()], true).unwrap().len(), 2);
+
+    // and stays ready
+    by_author.await_ready().unwrap();
+    assert_eq!(by_author.lookup(&[2.into()], true).unwrap().len(), 0);
+}
+
+#[test]
+fn exploding_operators_are_suspended() {
+    let mut g = Builder::default();
+    g.set_sharding(None);
+    g.disable_partial();
+    g.set_max_fanout(10);
+    g.set_persistence(get_persistence_params("exploding_operators_are_suspended"));
+    let mut g = g.start_simple().unwrap();
+    g.install_recipe(
+        "CREATE TABLE a (id int, k int, PRIMARY KEY(id));
+         CREATE TABLE b (id int, k int, PRIMARY KEY(id));
+         QUERY Pairs: SELECT a.id, b.id FROM a JOIN b ON (a.k = b.k);
+         QUERY ByK: SELECT id FROM b WHERE k = ?;",
+    )
+    .unwrap();
+    let mut a = g.table("a").unwrap().into_sync();
+    let mut b = g.table("b").unwrap().into_sync();
+    assert!(g.suspended_views().unwrap().is_empty());
+
+    b.perform_all((0..20).map(|id| vec![id.into(), 1.into()]))
+        .unwrap();
+    sleep();
+    assert!(g.suspended_views().unwrap().is_empty());
+
+    // one row of a joins with every row of b
+    a.insert(vec![1.into(), 1.into()]).unwrap();
+    sleep();
+    assert_eq!(g.suspended_views().unwrap(), vec!["Pairs".to_owned()]);
+
+    // views that do not read from the join carry on
+    b.insert(vec![20.into(), 1.into()]).unwrap();
+    sleep();
+    let mut by_k = g.view("ByK").unwrap().into_sync();
+    assert_eq!(by_k.lookup(&[1.into()], true).unwrap().len(), 21);
+}
+
+#[test]
+fn lookups_stream_in_chunks() {
+    let mut g = start_simple("lookups_stream_in_chunks");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, score int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id, score FROM posts WHERE author = ? ORDER BY score DESC;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let by_author = g.view("ByAuthor").unwrap().into_sync();
+
+    for id in 0..10 {
+        posts
+            .insert(vec![id.into(), 1.into(), (id * 10).into()])
+            .unwrap();
+    }
+    sleep();
+
+    // rows come in the order of the view, however many chunks they are split into
+    let ids: Vec<DataType> = by_author
+        .lookup_stream(&[1.into()], 3, true)
+        .map(|r| r.unwrap()[0].clone())
+        .collect();
+    let expected: Vec<DataType> = (0..10).rev().map(DataType::from).collect();
+    assert_eq!(ids, expected);
+
+    assert_eq!(by_author.lookup_stream(&[2.into()], 3, true).count(), 0);
+}
+
+#[test]
+fn views_count_rows_for_key() {
+    let mut g = start_simple("views_count_rows_for_key");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+
+    for id in 0..7 {
+        posts.insert(vec![id.into(), (id % 2).into()]).unwrap();
+    }
+    sleep();
+
+    assert_eq!(by_author.count(&[0.into()], true).unwrap(), 4);
+    assert_eq!(by_author.count(&[1.into()], true).unwrap(), 3);
+    assert_eq!(by_author.count(&[2.into()], true).unwrap(), 0);
+}
+
+#[test]
+fn lookups_filter_and_project_at_reader() {
+    use noria::Predicate;
+
+    let mut g = start_simple("lookups_filter_and_project_at_reader");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, score int, title text, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id, score, title FROM posts WHERE author = ? ORDER BY score DESC;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+
+    for &(id, score) in &[(1, 5), (2, 9), (3, 1), (4, 7)] {
+        posts
+            .insert(vec![id.into(), 1.into(), score.into(), "title".into()])
+            .unwrap();
+    }
+    sleep();
+
+    // only the titles and ids of the rows that meet the predicate come back, in the view's order
+    let above = [Predicate::Greater(1, 4.into())];
+    let rows = by_author
+        .lookup_filtered(&[1.into()], &above, &[2, 0], true)
+        .unwrap();
+    let expected: Vec<Vec<DataType>> = vec![
+        vec!["title".into(), 2.into()],
+        vec!["title".into(), 4.into()],
+        vec!["title".into(), 1.into()],
+    ];
+    assert_eq!(rows, expected);
+
+    let rows = by_author
+        .lookup_filtered(&[1.into()], &[Predicate::Equal(0, 7.into())], &[0], true)
+        .unwrap();
+    assert!(rows.is_empty());
+}
+
+#[test]
+fn views_page_through_results() {
+    let mut g = start_simple("views_page_through_results");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, score int, PRIMARY KEY(id));
+         QUERY Scores: SELECT score FROM posts WHERE author = ? ORDER BY score DESC;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut scores = g.view("Scores").unwrap().into_sync();
+
+    for &(id, score) in &[(1, 5), (2, 1), (3, 5), (4, 3), (5, 5)] {
+        posts
+            .insert(vec![id.into(), 1.into(), score.into()])
+            .unwrap();
+    }
+    sleep();
+
+    // rows that are the same are neither skipped nor repeated across pages
+    let (mut read, mut cursor) = scores.lookup_page(&[1.into()], None, 2, true).unwrap();
+    while let Some(after) = cursor.take() {
+        let (page, next) = scores
+            .lookup_page(&[1.into()], Some(after), 2, true)
+            .unwrap();
+        assert!(page.len() <= 2);
+        read.extend(page);
+        cursor = next;
+    }
+    let read: Vec<DataType> = read.into_iter().map(|r| r[0].clone()).collect();
+    let expected: Vec<DataType> = vec![5.into(), 5.into(), 5.into(), 3.into(), 1.into()];
+    assert_eq!(read, expected);
+
+    // a page that holds the rest of the rows says there is nothing after it
+    let (page, cursor) = scores.lookup_page(&[1.into()], None, 5, true).unwrap();
+    assert_eq!(page.len(), 5);
+    assert_eq!(cursor, None);
+}
+
+#[test]
+fn concurrent_lookups_of_a_key_are_shared() {
+    let mut g = start_simple("concurrent_lookups_of_a_key_are_shared");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let by_author = g.view("ByAuthor").unwrap();
+    posts.insert(vec![1.into(), 2.into()]).unwrap();
+    posts.insert(vec![2.into(), 2.into()]).unwrap();
+    sleep();
+
+    // every lookup gets every row, whichever of them sent the request
+    let lookups = (0..4).map(|_| by_author.clone().lookup(&[2.into()], true));
+    let results = futures::future::join_all(lookups).wait().unwrap();
+    assert!(results.iter().all(|(_, rows)| rows.len() == 2));
+
+    // and the key can be looked up again once they are done
+    let (_, rows) = by_author.lookup(&[2.into()], true).wait().unwrap();
+    assert_eq!(rows.len(), 2);
+}
+
+#[test]
+fn speculative_lookups_are_corrected_once_replayed() {
+    let mut g = start_simple("speculative_lookups_are_corrected_once_replayed");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+    posts.insert(vec![1.into(), 1.into()]).unwrap();
+    posts.insert(vec![2.into(), 1.into()]).unwrap();
+    sleep();
+
+    // the key has yet to be replayed, so the rows come later
+    let (rows, correction) = by_author.lookup_speculative(&[1.into()]).unwrap();
+    assert!(rows.is_empty());
+    assert_eq!(correction.expect("key missed").wait().unwrap().len(), 2);
+
+    // and once it has been, they are all there right away
+    let (rows, correction) = by_author.lookup_speculative(&[1.into()]).unwrap();
+    assert_eq!(rows.len(), 2);
+    assert!(correction.is_none());
+}
+
+#[test]
+fn lookups_remember_misses_until_told_otherwise() {
+    let mut g = start_simple("lookups_remember_misses_until_told_otherwise");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+    by_author.cache_misses(16, Duration::from_secs(60));
+
+    assert!(by_author.lookup(&[1.into()], true).unwrap().is_empty());
+    posts.insert(vec![1.into(), 1.into()]).unwrap();
+    sleep();
+
+    // the miss is remembered until another lookup hears that the key was filled
+    assert!(by_author.lookup(&[1.into()], true).unwrap().is_empty());
+    assert!(by_author.lookup(&[2.into()], true).unwrap().is_empty());
+    assert_eq!(by_author.lookup(&[1.into()], true).unwrap().len(), 1);
+
+    // and only for as long as it may be
+    let ttl = Duration::from_millis(100);
+    by_author.cache_misses(16, ttl);
+    assert!(by_author.lookup(&[3.into()], true).unwrap().is_empty());
+    posts.insert(vec![2.into(), 3.into()]).unwrap();
+    sleep();
+    thread::sleep(ttl);
+    assert_eq!(by_author.lookup(&[3.into()], true).unwrap().len(), 1);
+}
+
+#[test]
+fn writes_wait_for_the_ack_they_ask_for() {
+    let mut g = start_simple("writes_wait_for_the_ack_they_ask_for");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+
+    // a write that waits for the views is seen by the read that follows it
+    posts.set_ack(noria::Ack::Views);
+    posts.insert(vec![1.into(), 1.into()]).unwrap();
+    assert_eq!(
+        by_author.lookup(&[1.into()], true).unwrap(),
+        vec![vec![DataType::from(1)]]
+    );
+
+    // writes that aren't waited for make it all the same
+    posts.set_ack(noria::Ack::None);
+    posts.insert(vec![2.into(), 1.into()]).unwrap();
+    posts.set_ack(noria::Ack::Durable);
+    posts.insert(vec![3.into(), 1.into()]).unwrap();
+    sleep();
+    assert_eq!(by_author.lookup(&[1.into()], true).unwrap().len(), 3);
+}
+
+#[test]
+fn queries_follow_planner_hints() {
+    let mut g = start_simple("queries_follow_planner_hints");
+    g.install_recipe(
+        "CREATE TABLE users (id int, name varchar(40), PRIMARY KEY(id));
+         CREATE TABLE posts (id int, author int, title varchar(40), PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT users.name, posts.title FROM posts, users \
+             WHERE posts.author = users.id AND users.id = ? \
+             WITH HINTS join_order = users posts AND materialize = full AND sharding = none;",
+    )
+    .unwrap();
+    let mut users = g.table("users").unwrap().into_sync();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+    users.insert(vec![1.into(), "alice".into()]).unwrap();
+    posts
+        .insert(vec![1.into(), 1.into(), "hello".into()])
+        .unwrap();
+    posts
+        .insert(vec![2.into(), 2.into(), "bye".into()])
+        .unwrap();
+    sleep();
+    assert_eq!(
+        by_author.lookup(&[1.into()], true).unwrap(),
+        vec![vec!["alice".into(), "hello".into()]]
+    );
+
+    // joins can only be ordered by the tables a query joins
+    assert!(g
+        .extend_recipe(
+            "QUERY Bad: SELECT posts.title FROM posts, users WHERE posts.author = users.id \
+             WITH HINTS join_order = users votes;"
+        )
+        .is_err());
+}
+
+#[test]
+fn queries_can_be_looked_up_by_several_keys() {
+    let mut g = start_simple_unsharded("queries_can_be_looked_up_by_several_keys");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, title varchar(40), PRIMARY KEY(id));
+         QUERY Posts: SELECT id, author, title FROM posts WHERE id = ? \
+             WITH HINTS index = PostsByAuthor author AND index = PostsByTitle author title;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_id = g.view("Posts").unwrap().into_sync();
+    let mut by_author = g.view("PostsByAuthor").unwrap().into_sync();
+    let mut by_title = g.view("PostsByTitle").unwrap().into_sync();
+    posts
+        .insert(vec![1.into(), 1.into(), "hello".into()])
+        .unwrap();
+    posts
+        .insert(vec![2.into(), 1.into(), "bye".into()])
+        .unwrap();
+    sleep();
+
+    // each view has its own key, and they all see the same rows
+    assert_eq!(
+        by_id.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), 1.into(), "bye".into()]]
+    );
+    assert_eq!(by_author.lookup(&[1.into()], true).unwrap().len(), 2);
+    assert_eq!(
+        by_title.lookup(&[1.into(), "hello".into()], true).unwrap(),
+        vec![vec![1.into(), 1.into(), "hello".into()]]
+    );
+
+    // the views go away with the query
+    g.remove_query("Posts").unwrap();
+    assert!(g.view("PostsByAuthor").is_err());
+    assert!(g
+        .extend_recipe("QUERY Bad: SELECT id FROM posts WITH HINTS index = BadByTitle title;")
+        .is_err());
+}
+
+#[test]
+fn prepared_statements_go_to_views_and_tables() {
+    let mut g = start_simple("prepared_statements_go_to_views_and_tables");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, title varchar(40), PRIMARY KEY(id));",
+    )
+    .unwrap();
+
+    let insert = g
+        .prepare("INSERT INTO posts (id, author, title) VALUES (?, ?, ?)")
+        .unwrap();
+    assert_eq!(insert.parameters().len(), 3);
+    assert_eq!(
+        insert.parameters()[1].sql_type,
+        Some(nom_sql::SqlType::Int(32))
+    );
+    let by_author = g
+        .prepare("SELECT id, title FROM posts WHERE author = ?")
+        .unwrap();
+    assert_eq!(by_author.parameters()[0].column, "author");
+
+    // parameters are converted to the types of their columns
+    let written = g.execute(&insert, &[1.into(), "2".into(), "hello".into()]);
+    assert_eq!(written.unwrap(), Executed::Written);
+    g.execute(&insert, &[2.into(), 2.into(), "bye".into()])
+        .unwrap();
+    assert!(g.execute(&insert, &[3.into()]).is_err());
+
+    let update = g
+        .prepare("UPDATE posts SET title = ? WHERE id = ?")
+        .unwrap();
+    g.execute(&update, &["hi".into(), 1.into()]).unwrap();
+    let delete = g.prepare("DELETE FROM posts WHERE id = ?").unwrap();
+    g.execute(&delete, &[2.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        g.execute(&by_author, &[2.into()]).unwrap(),
+        Executed::Rows(vec![vec![1.into(), "hi".into()]])
+    );
+
+    // the query that was added for a SELECT is found when it is prepared again
+    g.prepare("SELECT id, title FROM posts WHERE author = ?")
+        .unwrap();
+    assert_eq!(g.outputs().unwrap().len(), 1);
+
+    // writes must pick out one row by its primary key
+    assert!(g.prepare("DELETE FROM posts WHERE author = ?").is_err());
+}
+
+#[test]
+fn query_metadata_is_listed_with_its_nodes() {
+    let mut g = start_simple("query_metadata_is_listed_with_its_nodes");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ? \
+             WITH METADATA owner = ads AND tags = billing hot;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+    assert!(by_author.lookup(&[1.into()], true).unwrap().is_empty());
+    posts.insert(vec![1.into(), 1.into()]).unwrap();
+    sleep();
+    assert_eq!(by_author.lookup(&[1.into()], true).unwrap().len(), 1);
+
+    let view = &g.catalog().unwrap().views["ByAuthor"];
+    assert_eq!(view.owner, Some("ads".to_owned()));
+    assert_eq!(view.tags, vec!["billing", "hot"]);
+    let stats = g.statistics().unwrap();
+    assert!(stats
+        .values()
+        .flat_map(|(_, nodes)| nodes.values())
+        .any(|n| n.owners == vec!["ads"] && n.tags == vec!["billing", "hot"]));
+    assert!(g.graphviz().unwrap().contains("owner: ads"));
+
+    // adding the query again only changes its metadata
+    g.extend_recipe(
+        "QUERY ByAuthor: SELECT id FROM posts WHERE author = ? WITH METADATA owner = search;",
+    )
+    .unwrap();
+    let view = &g.catalog().unwrap().views["ByAuthor"];
+    assert_eq!(view.owner, Some("search".to_owned()));
+    assert!(view.tags.is_empty());
+    assert_eq!(by_author.lookup(&[1.into()], true).unwrap().len(), 1);
+}
+
+#[test]
+fn statistics_charge_queries_for_what_they_use() {
+    let mut g = start_simple("statistics_charge_queries_for_what_they_use");
+    g.install_recipe(
+        "CREATE TABLE posts (id int, author int, PRIMARY KEY(id));
+         QUERY ByAuthor: SELECT id FROM posts WHERE author = ?;
+         QUERY PostCount: SELECT author, COUNT(id) AS n FROM posts \
+             WHERE author = ? GROUP BY author;",
+    )
+    .unwrap();
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+    let mut post_count = g.view("PostCount").unwrap().into_sync();
+    assert!(by_author.lookup(&[1.into()], true).unwrap().is_empty());
+    assert!(post_count.lookup(&[1.into()], true).unwrap().is_empty());
+    for id in 0..10 {
+        posts.insert(vec![id.into(), 1.into()]).unwrap();
+    }
+    sleep();
+    assert_eq!(by_author.lookup(&[1.into()], true).unwrap().len(), 10);
+
+    // both queries are charged for the table they share, and for the nodes of their own
+    let stats = g.statistics().unwrap();
+    assert_eq!(stats.queries.len(), 2);
+    for name in &["ByAuthor", "PostCount"] {
+        let usage = &stats.queries[*name];
+        assert!(usage.cpu_time > 0, "{}: {:?}", name, usage);
+        assert!(usage.state_bytes > 0, "{}: {:?}", name, usage);
+        assert!(usage.network_bytes > 0, "{}: {:?}", name, usage);
+    }
+}
+
+#[test]
+fn undeclared_tables_are_inferred_from_queries() {
+    let mut builder = Builder::default();
+    builder.set_persistence(get_persistence_params(
+        "undeclared_tables_are_inferred_from_queries",
+    ));
+    builder.enable_table_inference();
+    let mut g = builder.start_simple().unwrap();
+    g.install_recipe("QUERY ByAuthor: SELECT id, title FROM posts WHERE author = ? AND id > 0;")
+        .unwrap();
+    let catalog = g.catalog().unwrap();
+    assert_eq!(
+        catalog.tables["posts"].columns,
+        vec!["id", "title", "author"]
+    );
+
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut by_author = g.view("ByAuthor").unwrap().into_sync();
+    posts
+        .insert(vec![1.into(), "hello".into(), "alice".into()])
+        .unwrap();
+    sleep();
+    assert_eq!(
+        by_author.lookup(&["alice".into()], true).unwrap(),
+        vec![vec![1.into(), "hello".into()]]
+    );
+
+    // tables that were inferred once are declared from then on
+    g.extend_recipe("QUERY Titles: SELECT title FROM posts;")
+        .unwrap();
+    assert!(g
+        .extend_recipe("QUERY Bad: SELECT title FROM posts, votes;")
+        .is_err());
+}
+
+#[test]
+fn query_optimizers_see_queries_before_they_are_added() {
+    use crate::optimizer::{MirQuery, QueryOptimizer, SelectStatement};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct Recording(Mutex<Vec<String>>);
+    impl QueryOptimizer for Recording {
+        fn rewrite_query(&self, name: &str, _: &SelectStatement) -> Option<SelectStatement> {
+            self.0.lock().unwrap().push(format!("query {}", name));
+            None
+        }
+        fn rewrite_mir(&self, name: &str, mir: &MirQuery) -> Option<MirQuery> {
+            self.0.lock().unwrap().push(format!("mir {}", name));
+            Some(mir.clone())
+        }
+    }
+
+    let optimizer = Arc::new(Recording::default());
+    let mut builder = Builder::default();
+    builder.set_persistence(get_persistence_params(
+        "query_optimizers_see_queries_before_they_are_added",
+    ));
+    builder.set_query_optimizer(optimizer.clone());
+    let mut g = builder.start_simple().unwrap();
+    g.install_recipe(
+        "CREATE TABLE posts (id int, title varchar(40), PRIMARY KEY(id));
+         QUERY Titles: SELECT title FROM posts WHERE id = ?;",
+    )
+    .unwrap();
+    assert_eq!(
+        *optimizer.0.lock().unwrap(),
+        vec!["query Titles", "mir Titles"]
+    );
+
+    // the rewritten plan is the one that is lowered
+    let mut posts = g.table("posts").unwrap().into_sync();
+    let mut titles = g.view("Titles").unwrap().into_sync();
+    posts.insert(vec![1.into(), "hello".into()]).unwrap();
+    sleep();
+    assert_eq!(
+        titles.lookup(&[1.into()], true).unwrap(),
+        vec![vec!["hello".into()]]
+    );
+}
+
+#[test]
+fn spilled_queues_still_deliver_every_write() {
+    let mut builder = Builder::default();
+    builder.set_persistence(get_persistence_params(
+        "spilled_queues_still_deliver_every_write",
+    ));
+    builder.set_queue_limit(1, Overflow::Spill);
+    let mut g = builder.start_simple().unwrap();
+    g.install_recipe(
+        "CREATE TABLE votes (aid int, uid int);
+         QUERY VoteCount: SELECT aid, COUNT(uid) AS votes FROM votes WHERE aid = ? GROUP BY aid;",
+    )
+    .unwrap();
+
+    let mut votes = g.table("votes").unwrap().into_sync();
+    let mut count = g.view("VoteCount").unwrap().into_sync();
+    votes
+        .perform_all((0..500).map(|uid| vec![1.into(), uid.into()]))
+        .unwrap();
+    sleep();
+    assert_eq!(
+        count.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 500.into()]]
+    );
+
+    // everything has been sent on by now
+    let stats = g.statistics().unwrap();
+    assert!(stats.values().all(|(domain, _)| domain.queue_depth == 0));
+}
+
+#[test]
+fn sharded_aggregations_are_pre_aggregated() {
+    let mut g = start_simple("sharded_aggregations_are_pre_aggregated");
+    g.install_recipe(
+        "CREATE TABLE votes (id int, aid int, PRIMARY KEY(id));
+         QUERY VoteCount: SELECT aid, COUNT(id) AS votes FROM votes WHERE aid = ? GROUP BY aid;",
+    )
+    .unwrap();
+    // votes are sharded by id, so each shard counts its votes before they are shuffled by aid
+    assert!(g.graphviz().unwrap().contains("∂"));
+
+    let mut votes = g.table("votes").unwrap().into_sync();
+    let mut count = g.view("VoteCount").unwrap().into_sync();
+    votes
+        .perform_all((0..90).map(|id| vec![id.into(), (id % 3).into()]))
+        .unwrap();
+    votes.delete(vec![0.into()]).unwrap();
+    votes.delete(vec![3.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        count.lookup(&[0.into()], true).unwrap(),
+        vec![vec![0.into(), 28.into()]]
+    );
+    assert_eq!(
+        count.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 30.into()]]
+    );
+}
+
+#[test]
+fn joins_over_spilled_state_still_find_rows() {
+    // the tables are kept in memory, so their state is what the join looks rows up in
+    let mut builder = Builder::default();
+    builder.disable_partial();
+    builder.set_join_state_limit(4096);
+    let mut g = builder.start_simple().unwrap();
+    g.install_recipe(
+        "CREATE TABLE article (id int, title varchar(255), PRIMARY KEY(id));
+         CREATE TABLE vote (aid int, uid int);
+         QUERY ArticleVotes: SELECT article.id, article.title, vote.uid \
+                             FROM article JOIN vote ON (article.id = vote.aid) \
+                             WHERE article.id = ?;",
+    )
+    .unwrap();
+
+    let mut article = g.table("article").unwrap().into_sync();
+    let mut vote = g.table("vote").unwrap().into_sync();
+    let mut votes = g.view("ArticleVotes").unwrap().into_sync();
+    article
+        .perform_all((0..500).map(|id| vec![id.into(), format!("Article #{}", id).into()]))
+        .unwrap();
+    vote.perform_all((0..500).map(|id| vec![id.into(), (id * 2).into()]))
+        .unwrap();
+    sleep();
+
+    for id in &[0, 123, 499] {
+        assert_eq!(
+            votes.lookup(&[(*id).into()], true).unwrap(),
+            vec![vec![
+                (*id).into(),
+                format!("Article #{}", id).into(),
+                (*id * 2).into()
+            ]]
+        );
+    }
+}
@@ -371,11 +371,19 @@ extern crate serde_derive;
 #[macro_use]
 extern crate slog;
 
+mod auth;
 mod builder;
 mod controller;
 mod coordination;
+mod faults;
 mod handle;
+#[cfg(feature = "oracle")]
+pub mod optimizer;
+pub mod oracle;
+pub mod secrets;
+mod sim;
 mod startup;
+mod tls;
 mod worker;
 
 #[cfg(test)]
@@ -390,15 +398,23 @@ pub enum ReuseConfigType {
     NoReuse,
 }
 
+pub use crate::auth::Role;
 pub use crate::builder::Builder;
+#[cfg(feature = "fault-injection")]
+pub use crate::faults::Fault;
 pub use crate::handle::{Handle, SyncHandle};
+pub use crate::sim::Simulation;
 pub use controller::migrate::materialization::FrontierStrategy;
-pub use dataflow::{DurabilityMode, PersistenceParameters};
+pub use dataflow::{DurabilityMode, Overflow, PersistenceParameters};
 pub use noria::consensus::LocalAuthority;
 pub use noria::*;
 pub use petgraph::graph::NodeIndex;
 
+use crate::auth::AuthConfig;
+use crate::tls::{Certificates, TlsConfig};
 use dataflow::DomainConfig;
+use noria::channel::Connection;
+use std::path::PathBuf;
 use std::time;
 
 pub(crate) fn block_on<F, T>(f: F) -> T
@@ -410,7 +426,68 @@ where
     let mut wrap = Some(f);
     future::poll_fn(|| blocking(|| wrap.take().unwrap()()))
         .wait()
-        .unwrap()
+        .unwrap_or_else(|_| {
+            // not on a runtime thread pool, such as in a simulation
+            wrap.take().unwrap()()
+        })
+}
+
+/// Set up a new connection as connections must be: encrypted with the current certificate in
+/// `certs`, and having proven that it knows `key` and been proven the same, if they are set.
+///
+/// Resolves to the connection if that worked out, and to `None` otherwise.
+pub(crate) fn secure(
+    certs: Option<&Certificates>,
+    key: Option<&[u8]>,
+    stream: tokio::net::TcpStream,
+    log: &slog::Logger,
+) -> Box<dyn futures::Future<Item = Option<Connection<tokio::net::TcpStream>>, Error = ()> + Send> {
+    use tokio::prelude::*;
+    let peer = stream.peer_addr().ok();
+    let log = log.clone();
+    let key = key.map(<[u8]>::to_vec);
+    let handshake = noria::tls::handshake(tls::accept(certs, stream))
+        .and_then(move |stream| noria::channel::auth::accept(stream, key));
+    Box::new(handshake.then(move |r| match r {
+        Ok(stream) => Ok(Some(stream)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            warn!(log, "rejecting connection with bad credentials"; "from" => ?peer);
+            Ok(None)
+        }
+        Err(e) => {
+            warn!(log, "connection failed to authenticate: {:?}", e; "from" => ?peer);
+            Ok(None)
+        }
+    }))
+}
+
+/// Limits on the resources that a single security universe may use.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+crate struct UniverseQuota {
+    /// The maximum number of queries that may be installed in a universe.
+    crate max_queries: Option<usize>,
+    /// The maximum number of bytes of state that a universe's own nodes may hold.
+    crate max_memory: Option<u64>,
+}
+
+/// Limits on the load that a single client may place on each worker.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+crate struct RateLimits {
+    /// The number of reads per second a client may issue to a worker.
+    crate reads_per_sec: Option<u32>,
+    /// The number of writes per second a client may issue to a domain.
+    crate writes_per_sec: Option<u32>,
+    /// The number of reads a client may have waiting for a replay on a worker at any one time.
+    crate concurrent_replays: Option<usize>,
+}
+
+/// Where to record reads from views, and how many of them to record.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+crate struct AuditConfig {
+    /// The file that reads are appended to, or `-` for standard output.
+    crate path: PathBuf,
+    /// The fraction of reads that are recorded.
+    crate sample: f64,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq)]
@@ -425,6 +502,19 @@ crate struct Config {
     crate quorum: usize,
     crate reuse: ReuseConfigType,
     crate threads: Option<usize>,
+    #[serde(skip)]
+    crate auth: Option<AuthConfig>,
+    crate tls: Option<TlsConfig>,
+    crate universe_quota: UniverseQuota,
+    crate rate_limits: RateLimits,
+    crate audit: Option<AuditConfig>,
+    /// Whether queries may read from base tables that the recipe does not declare.
+    crate infer_tables: bool,
+    /// How long a migration may take before it is aborted, if there is a limit.
+    crate migration_timeout: Option<time::Duration>,
+    /// Secret that connections between domains must authenticate with.
+    #[serde(skip)]
+    crate domain_secret: Option<String>,
 }
 impl Default for Config {
     fn default() -> Self {
@@ -438,6 +528,12 @@ impl Default for Config {
             domain_config: DomainConfig {
                 concurrent_replays: 512,
                 replay_batch_timeout: time::Duration::new(0, 100_000),
+                full_replay_threads: 4,
+                capture_packets: 0,
+                max_fanout: None,
+                max_queue: None,
+                overflow: Default::default(),
+                max_join_state: None,
             },
             persistence: Default::default(),
             heartbeat_every: time::Duration::from_secs(1),
@@ -448,10 +544,29 @@ impl Default for Config {
             threads: Some(2),
             #[cfg(not(any(debug_assertions, test)))]
             threads: None,
+            auth: None,
+            tls: None,
+            universe_quota: Default::default(),
+            rate_limits: Default::default(),
+            audit: None,
+            infer_tables: false,
+            migration_timeout: None,
+            domain_secret: None,
         }
     }
 }
 
+impl Config {
+    /// Take the secrets in this configuration from `other`.
+    ///
+    /// Secrets are never written to the authority, so a configuration read back from it has to
+    /// get them from the local configuration instead.
+    crate fn restore_secrets(&mut self, other: &Config) {
+        self.auth = other.auth.clone();
+        self.domain_secret = other.domain_secret.clone();
+    }
+}
+
 /// Just give me a damn terminal logger
 pub fn logger_pls() -> slog::Logger {
     use slog::Drain;
@@ -3,8 +3,9 @@ extern crate clap;
 extern crate noria_server;
 extern crate slog;
 
-use noria_server::{Builder, ReuseConfigType, ZookeeperAuthority};
-use std::path::PathBuf;
+use noria_server::secrets::{self, CommandSecrets, EnvSecrets, SecretsProvider};
+use noria_server::{Builder, ReuseConfigType, Role, ZookeeperAuthority};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -49,6 +50,12 @@ fn main() {
                 .default_value("100000")
                 .help("Time to wait before processing a merged packet, in nanoseconds."),
         )
+        .arg(
+            Arg::with_name("background-sync")
+                .long("background-sync")
+                .takes_value(true)
+                .help("Sync base table writes to disk from a background thread this often [in milliseconds], rather than before processing them."),
+        )
         .arg(
             Arg::with_name("log-dir")
                 .long("log-dir")
@@ -79,6 +86,25 @@ fn main() {
                 .requires("memory")
                 .help("Frequency at which to check the state size against the memory limit [in milliseconds]."),
         )
+        .arg(
+            Arg::with_name("cores")
+                .long("cores")
+                .takes_value(true)
+                .help("Number of cores this worker has, which decides its share of the domains [default: 1]."),
+        )
+        .arg(
+            Arg::with_name("reader-only")
+                .long("reader-only")
+                .help("Only run readers on this worker."),
+        )
+        .arg(
+            Arg::with_name("label")
+                .long("label")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Label this worker for placement constraints in the recipe [as LABEL=VALUE]."),
+        )
         .arg(
             Arg::with_name("noreuse")
                 .long("no-reuse")
@@ -104,6 +130,97 @@ fn main() {
                 .default_value("0")
                 .help("Shard the graph this many ways (0 = disable sharding)."),
         )
+        .arg(
+            Arg::with_name("api-token")
+                .long("api-token")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Require clients to authenticate, and accept this token [as ROLE:TOKEN, where ROLE is read, write, or admin]."),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .takes_value(true)
+                .requires_all(&["tls-key", "tls-name"])
+                .help("Encrypt the external API, reads, writes, and traffic between workers with TLS using this PEM certificate chain."),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .takes_value(true)
+                .requires("tls-cert")
+                .help("PEM private key for the certificate given with --tls-cert."),
+        )
+        .arg(
+            Arg::with_name("tls-name")
+                .long("tls-name")
+                .takes_value(true)
+                .requires("tls-cert")
+                .help("DNS name that the certificate given with --tls-cert is issued for, which connections to workers verify it against."),
+        )
+        .arg(
+            Arg::with_name("tls-ca")
+                .long("tls-ca")
+                .takes_value(true)
+                .requires("tls-cert")
+                .help("Additional PEM root certificate to trust when connecting to the API or to other workers."),
+        )
+        .arg(
+            Arg::with_name("read-rate-limit")
+                .long("read-rate-limit")
+                .takes_value(true)
+                .help("Number of reads per second each client may issue to a worker."),
+        )
+        .arg(
+            Arg::with_name("write-rate-limit")
+                .long("write-rate-limit")
+                .takes_value(true)
+                .help("Number of writes per second each client may issue to a domain."),
+        )
+        .arg(
+            Arg::with_name("replay-limit")
+                .long("replay-limit")
+                .takes_value(true)
+                .help("Number of reads each client may have waiting on replays on a worker."),
+        )
+        .arg(
+            Arg::with_name("domain-secret")
+                .long("domain-secret")
+                .takes_value(true)
+                .help("Shared secret that connections within the deployment, and from clients to views and tables, must authenticate with."),
+        )
+        .arg(
+            Arg::with_name("secrets-command")
+                .long("secrets-command")
+                .takes_value(true)
+                .help("Fetch secrets by running this command with the name of each secret, rather than from NORIA_* environment variables."),
+        )
+        .arg(
+            Arg::with_name("secrets-refresh")
+                .long("secrets-refresh")
+                .takes_value(true)
+                .help("Fetch rotated API tokens this often [in seconds]."),
+        )
+        .arg(
+            Arg::with_name("audit-log")
+                .long("audit-log")
+                .takes_value(true)
+                .help("Record reads from views as JSON lines in this file [- for standard output]."),
+        )
+        .arg(
+            Arg::with_name("audit-sample")
+                .long("audit-sample")
+                .takes_value(true)
+                .default_value("1.0")
+                .help("Fraction of reads to record in the audit log."),
+        )
+        .arg(
+            Arg::with_name("capture-packets")
+                .long("capture-packets")
+                .takes_value(true)
+                .help("Keep this many of the last packets each domain handled, and write them to the log directory if it panics."),
+        )
         .arg(
             Arg::with_name("verbose")
                 .short("v")
@@ -117,7 +234,18 @@ fn main() {
 
     let durability = matches.value_of("durability").unwrap();
     let listen_addr = matches.value_of("address").unwrap().parse().unwrap();
-    let zookeeper_addr = matches.value_of("zookeeper").unwrap();
+    let secrets: Arc<dyn SecretsProvider> = match matches.value_of("secrets-command") {
+        Some(command) => Arc::new(CommandSecrets::new(command)),
+        None => Arc::new(EnvSecrets),
+    };
+    let zookeeper_addr = match secrets.get(secrets::ZOOKEEPER) {
+        Ok(addr) => addr.unwrap_or_else(|| matches.value_of("zookeeper").unwrap().to_owned()),
+        Err(e) => clap::Error::with_description(
+            &format!("could not fetch the {} secret: {}", secrets::ZOOKEEPER, e),
+            clap::ErrorKind::ValueValidation,
+        )
+        .exit(),
+    };
     let memory = value_t_or_exit!(matches, "memory", usize);
     let memory_check_freq = value_t_or_exit!(matches, "memory_check_freq", u64);
     let quorum = value_t_or_exit!(matches, "quorum", usize);
@@ -131,7 +259,7 @@ fn main() {
     let deployment_name = matches.value_of("deployment").unwrap();
 
     let mut authority =
-        ZookeeperAuthority::new(&format!("{}/{}", zookeeper_addr, deployment_name)).unwrap();
+        ZookeeperAuthority::for_deployment(&zookeeper_addr, deployment_name).unwrap();
     let mut builder = Builder::default();
     builder.set_listen_addr(listen_addr);
     if memory > 0 {
@@ -145,6 +273,79 @@ fn main() {
     if matches.is_present("noreuse") {
         builder.set_reuse(ReuseConfigType::NoReuse);
     }
+    if matches.is_present("reader-only") {
+        builder.set_reader_only();
+    }
+    if matches.is_present("cores") {
+        let cores = value_t_or_exit!(matches, "cores", usize);
+        builder.set_capacity(cores, memory);
+    }
+    if let Some(labels) = matches.values_of("label") {
+        for l in labels {
+            let mut parts = l.splitn(2, '=');
+            let label = parts.next().unwrap();
+            let value = parts.next().expect("labels must be given as LABEL=VALUE");
+            builder.add_label(label, value);
+        }
+    }
+    if let Some(tokens) = matches.values_of("api-token") {
+        for t in tokens {
+            let mut parts = t.splitn(2, ':');
+            let role = parts.next().unwrap().parse::<Role>();
+            let (role, token) = match (role, parts.next()) {
+                (Ok(role), Some(token)) => (role, token),
+                (Err(e), _) => clap::Error::with_description(
+                    &format!("invalid api token: {}", e),
+                    clap::ErrorKind::ValueValidation,
+                )
+                .exit(),
+                (_, None) => clap::Error::with_description(
+                    "api tokens must be given as ROLE:TOKEN",
+                    clap::ErrorKind::ValueValidation,
+                )
+                .exit(),
+            };
+            builder.add_api_token(token, role);
+        }
+    }
+    if let Some(cert) = matches.value_of("tls-cert") {
+        builder.set_tls(
+            matches.value_of("tls-name").unwrap(),
+            Path::new(cert),
+            Path::new(matches.value_of("tls-key").unwrap()),
+            matches.value_of("tls-ca").map(Path::new),
+        );
+    }
+    builder.set_rate_limits(
+        matches
+            .value_of("read-rate-limit")
+            .map(|_| value_t_or_exit!(matches, "read-rate-limit", u32)),
+        matches
+            .value_of("write-rate-limit")
+            .map(|_| value_t_or_exit!(matches, "write-rate-limit", u32)),
+        matches
+            .value_of("replay-limit")
+            .map(|_| value_t_or_exit!(matches, "replay-limit", usize)),
+    );
+    if let Some(secret) = matches.value_of("domain-secret") {
+        builder.set_domain_secret(secret);
+    }
+    builder.set_secrets_provider(
+        secrets,
+        matches
+            .value_of("secrets-refresh")
+            .map(|_| Duration::from_secs(value_t_or_exit!(matches, "secrets-refresh", u64))),
+    );
+    if let Some(path) = matches.value_of("audit-log") {
+        builder.set_read_audit(
+            Path::new(path),
+            value_t_or_exit!(matches, "audit-sample", f64),
+        );
+    }
+
+    if matches.is_present("capture-packets") {
+        builder.set_packet_capture(value_t_or_exit!(matches, "capture-packets", usize));
+    }
 
     let mut persistence_params = noria_server::PersistenceParameters::new(
         match durability {
@@ -157,6 +358,10 @@ fn main() {
         Some(deployment_name.to_string()),
         persistence_threads,
     );
+    if matches.is_present("background-sync") {
+        let every = value_t_or_exit!(matches, "background-sync", u64);
+        persistence_params.background_sync = Some(Duration::from_millis(every));
+    }
     persistence_params.log_dir = matches
         .value_of("log-dir")
         .and_then(|p| Some(PathBuf::from(p)));
@@ -0,0 +1,36 @@
+//! Letting an external optimizer rewrite the plans that the controller makes for queries.
+//!
+//! A [`QueryOptimizer`] is shown each `SELECT` query that the controller adds, first as SQL before
+//! the controller looks for existing queries to build it on, and then, if it is planned from
+//! scratch, as MIR, the intermediate representation that is lowered into dataflow nodes. It may
+//! replace either one, so that optimization strategies can be tried out without changing the
+//! controller.
+
+use std::fmt;
+
+pub use mir::node::{MirNode, MirNodeType};
+pub use mir::query::MirQuery;
+pub use mir::MirNodeRef;
+pub use nom_sql::SelectStatement;
+
+/// An optimizer that may rewrite the plans for queries before they are added to the dataflow.
+///
+/// Both methods leave their input as it is by default.
+pub trait QueryOptimizer: fmt::Debug + Send + Sync {
+    /// Rewrite the query that is about to be added under the name `name`.
+    ///
+    /// The query has already been through the controller's own rewrites, so every column names
+    /// its table and `*` has been expanded into the columns it stands for. The rewritten query
+    /// must be in the same form.
+    fn rewrite_query(&self, _name: &str, _query: &SelectStatement) -> Option<SelectStatement> {
+        None
+    }
+
+    /// Rewrite the MIR that the controller planned for the query `name`, after its own MIR
+    /// optimizations.
+    ///
+    /// The rewritten MIR is lowered as it is, so it must compute the same query.
+    fn rewrite_mir(&self, _name: &str, _mir: &MirQuery) -> Option<MirQuery> {
+        None
+    }
+}
@@ -0,0 +1,341 @@
+//! Checking the contents of views against a reference SQL engine.
+//!
+//! An [`Oracle`] mirrors writes to base tables into an embedded SQLite database, and compares the
+//! results that views return for the keys that have been read from them with the results of the
+//! equivalent SQL queries. Since Noria is only eventually consistent, a key whose results differ
+//! may just be waiting for updates to propagate; [`Oracle::check_every`] therefore only reports
+//! keys that still differ, in the same way, a full check later.
+
+use crate::controller::recipe::Recipe;
+use nom_sql::{ColumnConstraint, CreateTableStatement, SqlQuery, TableKey};
+use noria::{DataType, SyncTable, SyncView};
+use rusqlite::types::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// A key for which a view disagrees with the reference engine.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    /// The view that disagrees.
+    pub view: String,
+    /// The key whose results differ.
+    pub key: Vec<DataType>,
+    /// The rows that the reference engine returns for the key, in sorted order.
+    pub expected: Vec<Vec<DataType>>,
+    /// The rows that the view returns for the key, in sorted order.
+    pub actual: Vec<Vec<DataType>>,
+}
+
+struct Watched {
+    view: SyncView,
+    keys: HashSet<Vec<DataType>>,
+}
+
+struct Inner {
+    db: Mutex<rusqlite::Connection>,
+    /// The SQL for each named query in the recipe.
+    queries: HashMap<String, String>,
+    /// The schema of each table in the recipe.
+    tables: HashMap<String, CreateTableStatement>,
+    views: Mutex<HashMap<String, Watched>>,
+}
+
+/// Mirrors writes into an embedded SQLite database, and checks views against it.
+///
+/// Only writes made through the tables returned by [`Oracle::table`] are mirrored, and only keys
+/// read through [`Oracle::lookup`] are checked. For partially materialized views, those are the
+/// keys that the view holds results for.
+#[derive(Clone)]
+pub struct Oracle {
+    inner: Arc<Inner>,
+}
+
+impl Oracle {
+    /// Create an oracle for the tables and queries in `recipe`.
+    ///
+    /// The recipe should be the same as the one installed in Noria.
+    pub fn new(recipe: &str) -> Result<Self, failure::Error> {
+        let recipe = Recipe::from_str(recipe, None).map_err(failure::err_msg)?;
+        let db = rusqlite::Connection::open_in_memory()?;
+
+        let mut queries = HashMap::new();
+        let mut tables = HashMap::new();
+        for (name, q) in recipe.expressions() {
+            match *q {
+                SqlQuery::CreateTable(ref ctq) => {
+                    db.execute(&q.to_string(), rusqlite::NO_PARAMS)?;
+                    tables.insert(ctq.table.name.clone(), ctq.clone());
+                }
+                SqlQuery::Select(..) | SqlQuery::CompoundSelect(..) => {
+                    if let Some(name) = name {
+                        queries.insert(name.clone(), q.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Oracle {
+            inner: Arc::new(Inner {
+                db: Mutex::new(db),
+                queries,
+                tables,
+                views: Default::default(),
+            }),
+        })
+    }
+
+    /// Mirror writes made through `table` into the reference engine.
+    pub fn table(&self, table: SyncTable) -> Result<MirroredTable, failure::Error> {
+        let schema = match self.inner.tables.get(table.table_name()) {
+            Some(schema) => schema,
+            None => bail!("table {} is not in the recipe", table.table_name()),
+        };
+
+        let name = &schema.table.name;
+        let insert = format!(
+            "INSERT INTO {} VALUES ({})",
+            name,
+            vec!["?"; schema.fields.len()].join(", ")
+        );
+        let delete = primary_key(schema).map(|key| {
+            let key: Vec<_> = key.iter().map(|c| format!("{} = ?", c)).collect();
+            format!("DELETE FROM {} WHERE {}", name, key.join(" AND "))
+        });
+
+        Ok(MirroredTable {
+            oracle: self.clone(),
+            table,
+            insert,
+            delete,
+        })
+    }
+
+    /// Check the keys that are read from the view for the query `name` through this oracle.
+    pub fn watch(&self, name: &str, view: SyncView) -> Result<(), failure::Error> {
+        if !self.inner.queries.contains_key(name) {
+            bail!("query {} is not in the recipe", name);
+        }
+
+        self.inner.views.lock().unwrap().insert(
+            name.to_owned(),
+            Watched {
+                view,
+                keys: HashSet::new(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Look up `key` in the watched view for the query `name`, and remember to check it later.
+    pub fn lookup(
+        &self,
+        name: &str,
+        key: &[DataType],
+        block: bool,
+    ) -> Result<Vec<Vec<DataType>>, failure::Error> {
+        let mut views = self.inner.views.lock().unwrap();
+        let watched = match views.get_mut(name) {
+            Some(watched) => watched,
+            None => bail!("view {} is not watched", name),
+        };
+
+        let rows = watched
+            .view
+            .lookup(key, block)
+            .map_err(|e| format_err!("lookup in {} failed: {:?}", name, e))?;
+        watched.keys.insert(key.to_vec());
+        Ok(rows)
+    }
+
+    /// Compare every key read so far with the reference engine, and return those that differ.
+    pub fn check(&self) -> Result<Vec<Divergence>, failure::Error> {
+        let mut views = self.inner.views.lock().unwrap();
+        let db = self.inner.db.lock().unwrap();
+
+        let mut divergences = Vec::new();
+        for (name, watched) in views.iter_mut() {
+            let mut stmt = db.prepare(&self.inner.queries[name])?;
+            let width = stmt.column_count();
+            for key in &watched.keys {
+                let params: Vec<_> = key.iter().map(to_sql).collect();
+                let mut expected = Vec::new();
+                let mut rows = stmt.query(&params)?;
+                while let Some(row) = rows.next() {
+                    let row = row?;
+                    let row: Result<Vec<_>, _> =
+                        (0..width).map(|i| from_sql(row.get_checked(i)?)).collect();
+                    expected.push(row?);
+                }
+
+                let mut actual = watched
+                    .view
+                    .lookup(key, true)
+                    .map_err(|e| format_err!("lookup in {} failed: {:?}", name, e))?;
+                // views may carry hidden columns, such as their key, after the query's columns
+                for row in &mut actual {
+                    row.truncate(width);
+                }
+
+                expected.sort();
+                actual.sort();
+                if expected != actual {
+                    divergences.push(Divergence {
+                        view: name.clone(),
+                        key: key.clone(),
+                        expected,
+                        actual,
+                    });
+                }
+            }
+        }
+        Ok(divergences)
+    }
+
+    /// Check views against the reference engine every `every`, and log any divergent keys.
+    ///
+    /// Checking stops once all handles to the oracle have been dropped.
+    pub fn check_every(&self, every: Duration, log: slog::Logger) {
+        let inner = Arc::downgrade(&self.inner);
+        thread::Builder::new()
+            .name("oracle".to_owned())
+            .spawn(move || {
+                let mut suspects = Vec::new();
+                loop {
+                    thread::sleep(every);
+                    let oracle = match Weak::upgrade(&inner) {
+                        Some(inner) => Oracle { inner },
+                        None => break,
+                    };
+
+                    match oracle.check() {
+                        Ok(divergences) => {
+                            for d in &divergences {
+                                if suspects.contains(d) {
+                                    error!(log, "view diverged from oracle";
+                                           "view" => &d.view,
+                                           "key" => ?d.key,
+                                           "expected" => ?d.expected,
+                                           "actual" => ?d.actual);
+                                }
+                            }
+                            suspects = divergences;
+                        }
+                        Err(e) => warn!(log, "failed to check views: {:?}", e),
+                    }
+                }
+            })
+            .unwrap();
+    }
+}
+
+/// A base table whose writes are mirrored into an [`Oracle`].
+pub struct MirroredTable {
+    oracle: Oracle,
+    table: SyncTable,
+    insert: String,
+    delete: Option<String>,
+}
+
+impl MirroredTable {
+    /// Insert `row` into the table.
+    pub fn insert(&mut self, row: Vec<DataType>) -> Result<(), failure::Error> {
+        self.table
+            .insert(row.clone())
+            .map_err(|e| format_err!("insert failed: {:?}", e))?;
+        self.mirror(&self.insert, &row)
+    }
+
+    /// Delete the row with primary key `key` from the table.
+    pub fn delete(&mut self, key: Vec<DataType>) -> Result<(), failure::Error> {
+        let delete = match self.delete {
+            Some(ref delete) => delete.clone(),
+            None => bail!("table {} has no primary key", self.table.table_name()),
+        };
+        self.table
+            .delete(key.clone())
+            .map_err(|e| format_err!("delete failed: {:?}", e))?;
+        self.mirror(&delete, &key)
+    }
+
+    fn mirror(&self, sql: &str, values: &[DataType]) -> Result<(), failure::Error> {
+        let values: Vec<_> = values.iter().map(to_sql).collect();
+        self.oracle.inner.db.lock().unwrap().execute(sql, &values)?;
+        Ok(())
+    }
+}
+
+/// The names of the columns that make up the primary key of a table, if it has one.
+crate fn primary_key(schema: &CreateTableStatement) -> Option<Vec<String>> {
+    let key = schema.keys.iter().flatten().find_map(|k| match *k {
+        TableKey::PrimaryKey(ref cols) => Some(cols.iter().map(|c| c.name.clone()).collect()),
+        _ => None,
+    });
+    key.or_else(|| {
+        let cols: Vec<_> = schema
+            .fields
+            .iter()
+            .filter(|cs| cs.constraints.contains(&ColumnConstraint::PrimaryKey))
+            .map(|cs| cs.column.name.clone())
+            .collect();
+        if cols.is_empty() {
+            None
+        } else {
+            Some(cols)
+        }
+    })
+}
+
+fn to_sql(v: &DataType) -> Value {
+    match *v {
+        DataType::None => Value::Null,
+        DataType::Int(..) | DataType::BigInt(..) => Value::Integer(v.into()),
+        DataType::Real(..) => Value::Real(v.into()),
+        DataType::Text(..) | DataType::TinyText(..) => Value::Text(v.into()),
+        DataType::Timestamp(ts) => Value::Text(ts.format("%Y-%m-%d %H:%M:%S").to_string()),
+    }
+}
+
+fn from_sql(v: Value) -> Result<DataType, failure::Error> {
+    Ok(match v {
+        Value::Null => DataType::None,
+        Value::Integer(i) => i.into(),
+        Value::Real(f) => f.into(),
+        Value::Text(s) => s.into(),
+        Value::Blob(..) => bail!("blobs are not supported"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip() {
+        for v in vec![
+            DataType::None,
+            DataType::from(42),
+            DataType::from(-7i64),
+            DataType::from(1.5),
+            DataType::from("text"),
+        ] {
+            assert_eq!(from_sql(to_sql(&v)).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn finds_primary_keys() {
+        let oracle = Oracle::new(
+            "CREATE TABLE a (id int, x int, PRIMARY KEY(id));
+             CREATE TABLE b (id int PRIMARY KEY, x int);
+             CREATE TABLE c (id int, x int);",
+        )
+        .unwrap();
+        let key = |t: &str| primary_key(&oracle.inner.tables[t]);
+        assert_eq!(key("a"), Some(vec!["id".to_owned()]));
+        assert_eq!(key("b"), Some(vec!["id".to_owned()]));
+        assert_eq!(key("c"), None);
+    }
+}
@@ -0,0 +1,233 @@
+//! Fetching secrets, such as API tokens, from outside the server's command line.
+//!
+//! Secrets given on the command line are visible to anyone who can list the processes on a host,
+//! and can only be changed by restarting the server. A [`SecretsProvider`] can instead supply them
+//! from the environment or from an external secrets store, and is asked again for API tokens
+//! periodically so that they can be rotated.
+
+use crate::auth::AuthConfig;
+use crate::Config;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// The name of the secret that holds API tokens, given as `ROLE:TOKEN` separated by commas or
+/// whitespace.
+pub const API_TOKENS: &str = "api-tokens";
+/// The name of the secret that connections between domains authenticate with.
+pub const DOMAIN_SECRET: &str = "domain-secret";
+/// The name of the secret that holds the ZooKeeper connection info.
+pub const ZOOKEEPER: &str = "zookeeper";
+
+/// A source of the secrets a server needs, so that they don't have to be given on the command
+/// line.
+pub trait SecretsProvider: Send + Sync {
+    /// Look up the current value of the secret called `name`, if it is set.
+    fn get(&self, name: &str) -> Result<Option<String>, failure::Error>;
+}
+
+/// Reads secrets from the environment.
+///
+/// The secret called `domain-secret` is read from the `NORIA_DOMAIN_SECRET` variable, and so on.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EnvSecrets;
+
+impl SecretsProvider for EnvSecrets {
+    fn get(&self, name: &str) -> Result<Option<String>, failure::Error> {
+        let var = format!("NORIA_{}", name.to_uppercase().replace('-', "_"));
+        Ok(std::env::var(var).ok())
+    }
+}
+
+/// Fetches secrets by running an external command, such as a wrapper around a key management
+/// service.
+///
+/// The command is given the name of a secret as its only argument, and should print the secret's
+/// value. If the secret is not set, the command should print nothing. A command that exits with
+/// an error is taken to mean that the secret could not be fetched.
+#[derive(Clone, Debug)]
+pub struct CommandSecrets {
+    command: PathBuf,
+}
+
+impl CommandSecrets {
+    /// Fetch secrets using the given command.
+    pub fn new<P: Into<PathBuf>>(command: P) -> Self {
+        CommandSecrets {
+            command: command.into(),
+        }
+    }
+}
+
+impl SecretsProvider for CommandSecrets {
+    fn get(&self, name: &str) -> Result<Option<String>, failure::Error> {
+        let out = Command::new(&self.command).arg(name).output()?;
+        if !out.status.success() {
+            bail!(
+                "{} failed to fetch {}: {}",
+                self.command.display(),
+                name,
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+
+        let secret = String::from_utf8(out.stdout)?;
+        let secret = secret.trim();
+        if secret.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(secret.to_owned()))
+        }
+    }
+}
+
+/// A provider along with how often to fetch rotated secrets from it.
+#[derive(Clone)]
+crate struct Secrets {
+    provider: Arc<dyn SecretsProvider>,
+    refresh_every: Option<Duration>,
+}
+
+impl Secrets {
+    crate fn new(provider: Arc<dyn SecretsProvider>, refresh_every: Option<Duration>) -> Self {
+        Secrets {
+            provider,
+            refresh_every,
+        }
+    }
+
+    /// Add the secrets that are currently set to `config`.
+    ///
+    /// API tokens are accepted in addition to those already in `config`, while the domain secret
+    /// replaces any that is already there.
+    crate fn apply(&self, config: &mut Config) -> Result<(), failure::Error> {
+        if let Some(tokens) = self.provider.get(API_TOKENS)? {
+            config
+                .auth
+                .get_or_insert_with(Default::default)
+                .extend(AuthConfig::parse(&tokens)?);
+        }
+        if let Some(secret) = self.provider.get(DOMAIN_SECRET)? {
+            config.domain_secret = Some(secret);
+        }
+        Ok(())
+    }
+
+    /// Periodically replace the tokens in `auth` with those in `base` and those currently given
+    /// by the provider.
+    ///
+    /// This stops once `auth` is dropped. If the tokens cannot be fetched, the current ones are
+    /// kept.
+    crate fn rotate(&self, base: AuthConfig, auth: Weak<RwLock<AuthConfig>>, log: slog::Logger) {
+        let every = match self.refresh_every {
+            Some(every) => every,
+            None => return,
+        };
+
+        let provider = self.provider.clone();
+        thread::Builder::new()
+            .name("srv-secrets".to_owned())
+            .spawn(move || loop {
+                thread::sleep(every);
+                let auth = match auth.upgrade() {
+                    Some(auth) => auth,
+                    None => break,
+                };
+
+                let tokens = provider.get(API_TOKENS).and_then(|tokens| {
+                    tokens
+                        .map(|tokens| AuthConfig::parse(&tokens))
+                        .unwrap_or_else(|| Ok(Default::default()))
+                });
+                match tokens {
+                    Ok(tokens) => {
+                        let mut new = base.clone();
+                        new.extend(tokens);
+                        let mut auth = auth.write().unwrap();
+                        if *auth != new {
+                            info!(log, "rotated api tokens");
+                            *auth = new;
+                        }
+                    }
+                    Err(e) => {
+                        warn!(log, "failed to fetch api tokens: {:?}", e);
+                    }
+                }
+            })
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Fixed(Mutex<HashMap<&'static str, String>>);
+
+    impl Fixed {
+        fn set(&self, name: &'static str, value: &str) {
+            self.0.lock().unwrap().insert(name, value.to_owned());
+        }
+    }
+
+    impl SecretsProvider for Fixed {
+        fn get(&self, name: &str) -> Result<Option<String>, failure::Error> {
+            Ok(self.0.lock().unwrap().get(name).cloned())
+        }
+    }
+
+    #[test]
+    fn secrets_are_applied() {
+        let provider = Fixed::default();
+        provider.set(API_TOKENS, "admin:b");
+        provider.set(DOMAIN_SECRET, "s");
+
+        let mut config = Config::default();
+        let mut auth = AuthConfig::default();
+        auth.add_token("a".to_owned(), crate::Role::Read);
+        config.auth = Some(auth);
+        config.domain_secret = Some("old".to_owned());
+
+        Secrets::new(Arc::new(provider), None)
+            .apply(&mut config)
+            .unwrap();
+        assert_eq!(
+            config.auth,
+            Some(AuthConfig::parse("read:a admin:b").unwrap())
+        );
+        assert_eq!(config.domain_secret, Some("s".to_owned()));
+    }
+
+    #[test]
+    fn tokens_are_rotated() {
+        let provider = Arc::new(Fixed::default());
+        provider.set(API_TOKENS, "admin:b");
+
+        let base = AuthConfig::parse("read:a").unwrap();
+        let auth = Arc::new(RwLock::new(AuthConfig::default()));
+        let secrets = Secrets::new(provider.clone(), Some(Duration::from_millis(10)));
+        secrets.rotate(
+            base,
+            Arc::downgrade(&auth),
+            slog::Logger::root(slog::Discard, o!()),
+        );
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            *auth.read().unwrap(),
+            AuthConfig::parse("read:a admin:b").unwrap()
+        );
+
+        provider.set(API_TOKENS, "admin:c");
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            *auth.read().unwrap(),
+            AuthConfig::parse("read:a admin:c").unwrap()
+        );
+    }
+}
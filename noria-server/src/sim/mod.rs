@@ -0,0 +1,227 @@
+//! Deterministic simulation of a whole deployment in a single process.
+//!
+//! A simulation runs the controller, the workers and their domains, and the network between them
+//! as tasks on the calling thread. A scheduler seeded by the test decides which task runs next,
+//! messages between nodes are delivered by tasks of their own, timers only fire when the scheduler
+//! moves its virtual clock forward, and nodes fail at steps the seed picks. Given the same seed and
+//! the same calls, a simulation therefore runs the same way every time, which makes ordering bugs
+//! between the parts of a deployment reproducible.
+
+use crate::startup::Event;
+use crate::Config;
+use dataflow::prelude::Packet;
+use futures::sync::mpsc::UnboundedSender;
+use hyper::Method;
+use noria::builders::{TableBuilder, ViewBuilder};
+use noria::consensus::Epoch;
+use noria::internal::LocalOrNot;
+use noria::{ActivationResult, DataType, Input, TableOperation};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use slog;
+use std::time::Duration;
+
+mod network;
+mod scheduler;
+
+crate use self::network::{controller_addr, read_addr, source_addr, worker_addr, Network};
+crate use self::scheduler::{Delay, Node, Scheduler};
+
+/// The most tasks a simulation runs before it gives up on ever being done.
+const MAX_STEPS: usize = 10_000_000;
+
+/// A deployment whose controller, workers, and network all run on the calling thread, in an order
+/// that a seed decides.
+///
+/// Each operation runs the deployment until it is done. Whatever else is going on, such as writes
+/// that were sent earlier or a worker failing, is interleaved with it as the seed says. Nodes
+/// still iterate over hash maps in whatever order the maps happen to be in, so the order in which
+/// a node sends several messages on one link at once may differ between runs.
+pub struct Simulation {
+    scheduler: Scheduler,
+    network: Network,
+    controller: UnboundedSender<Event>,
+    epoch: Epoch,
+    config: Config,
+    workers: usize,
+    log: slog::Logger,
+}
+
+impl Simulation {
+    crate fn new(config: Config, seed: u64, log: slog::Logger) -> Self {
+        let scheduler = Scheduler::new(seed, MAX_STEPS);
+        let network = Network::new(scheduler.clone());
+        let log = log.new(o!("seed" => seed));
+        let (controller, epoch) = scheduler.enter(|| {
+            crate::controller::sim::start(&scheduler, &network, config.clone(), log.clone())
+        });
+        Simulation {
+            scheduler,
+            network,
+            controller,
+            epoch,
+            config,
+            workers: 0,
+            log,
+        }
+    }
+
+    /// Add a worker that registers with the controller, and return its index.
+    ///
+    /// If `reader_only` is set, the worker only runs domains that hold nothing but readers.
+    pub fn add_worker(&mut self, reader_only: bool) -> usize {
+        let i = self.workers;
+        self.workers += 1;
+        let log = self.log.new(o!("worker" => i));
+        let (scheduler, network) = (&self.scheduler, &self.network);
+        let (config, epoch) = (&self.config, self.epoch);
+        scheduler.enter(|| {
+            crate::worker::sim::start(scheduler, network, (i, reader_only), config, epoch, log)
+        });
+        i
+    }
+
+    /// Make worker `i` fail at a point that the seed picks within the next `steps` tasks that run.
+    ///
+    /// The worker's domains and readers are lost, and it stops sending heartbeats, so the
+    /// controller eventually moves what ran on it to the other workers.
+    pub fn crash_worker(&mut self, i: usize, steps: usize) {
+        assert!(i < self.workers, "no simulated worker {}", i);
+        self.scheduler.crash_within(Node::Worker(i), steps);
+    }
+
+    /// Run the deployment until nothing is left to do but wait for heartbeats.
+    pub fn settle(&mut self) {
+        let scheduler = &self.scheduler;
+        scheduler.enter(|| scheduler.settle());
+    }
+
+    /// Run the deployment while `d` passes on its clock.
+    pub fn run_for(&mut self, d: Duration) {
+        let scheduler = &self.scheduler;
+        scheduler.enter(|| scheduler.run_for(d));
+    }
+
+    /// What ran, and which nodes failed, in order.
+    ///
+    /// Each task is named by the node it ran on, followed by what it does. Two simulations with
+    /// the same seed and the same calls have the same trace.
+    pub fn trace(&self) -> Vec<String> {
+        self.scheduler.trace()
+    }
+
+    /// Install the given recipe, as `SyncHandle::install_recipe` does.
+    pub fn install_recipe(&mut self, recipe: &str) -> Result<ActivationResult, failure::Error> {
+        self.rpc("install_recipe", recipe)
+    }
+
+    /// Extend the installed recipe, as `SyncHandle::extend_recipe` does.
+    pub fn extend_recipe(&mut self, addition: &str) -> Result<ActivationResult, failure::Error> {
+        self.rpc("extend_recipe", addition)
+    }
+
+    /// Send a write of `row` to the base table `table`.
+    ///
+    /// The write is only sent. It is processed as the deployment runs, along with whatever else is
+    /// going on.
+    pub fn insert(&mut self, table: &str, row: Vec<DataType>) -> Result<(), failure::Error> {
+        let builder: Option<TableBuilder> = self.rpc("table_builder", table)?;
+        let builder = builder.ok_or_else(|| format_err!("no table named {}", table))?;
+        let shard = match builder.txs.len() {
+            1 => 0,
+            n => noria::shard_by(&row[builder.key[0]], n),
+        };
+        let domain = self
+            .network
+            .domain_at(&builder.txs[shard])
+            .ok_or_else(|| format_err!("table {} is not reachable", table))?;
+
+        let mut tx = self
+            .network
+            .coordinator()
+            .builder_for(&domain)
+            .unwrap()
+            .build_sync()?;
+        let input = Input {
+            dst: builder.addr,
+            data: vec![TableOperation::Insert(row)],
+            tracer: None,
+            durable: false,
+            group: None,
+        };
+        tx.send(Box::new(Packet::Input {
+            inner: LocalOrNot::new(input),
+            src: None,
+            senders: vec![],
+        }))?;
+        Ok(())
+    }
+
+    /// Read the rows for `key` from the view `view`, running the deployment until they are there.
+    pub fn lookup(
+        &mut self,
+        view: &str,
+        key: &[DataType],
+    ) -> Result<Vec<Vec<DataType>>, failure::Error> {
+        let builder: Option<ViewBuilder> = self.rpc("view_builder", view)?;
+        let builder = builder.ok_or_else(|| format_err!("no view named {}", view))?;
+        let shard = match builder.shards.len() {
+            1 => 0,
+            n => noria::shard_by(&key[0], n),
+        };
+        let readers = self
+            .network
+            .readers_at(&builder.shards[shard])
+            .ok_or_else(|| format_err!("view {} is not reachable", view))?;
+        let target = (builder.node, shard);
+
+        let scheduler = &self.scheduler;
+        let mut triggered = false;
+        Ok(scheduler.enter(|| loop {
+            if let Some(reader) = readers.lock().unwrap().get(&target) {
+                match reader.try_find_and(key, |rows| rows.to_vec()) {
+                    Ok((Some(rows), _)) => break rows,
+                    Ok((None, _)) if !triggered => triggered = reader.trigger(key),
+                    // the view is not ready yet, or the key is still being replayed
+                    _ => {}
+                }
+            }
+            scheduler.wait();
+        }))
+    }
+
+    /// Make a request to the controller's external API, and run the deployment until it replies.
+    fn rpc<Q: Serialize, R: DeserializeOwned>(
+        &mut self,
+        path: &str,
+        request: Q,
+    ) -> Result<R, failure::Error> {
+        let body = serde_json::to_vec(&request)?;
+        let (tx, mut rx) = futures::sync::oneshot::channel();
+        let path = format!("/{}", path);
+        self.controller
+            .unbounded_send(Event::ExternalRequest(Method::POST, path, None, body, tx))
+            .map_err(|_| format_err!("simulated controller went away"))?;
+
+        let scheduler = &self.scheduler;
+        let reply = scheduler.enter(|| loop {
+            match rx.try_recv() {
+                Ok(Some(reply)) => break Ok(reply),
+                Ok(None) => scheduler.wait(),
+                Err(e) => break Err(e),
+            }
+        });
+        match reply.map_err(|_| format_err!("simulated controller went away"))? {
+            Ok(Ok(reply)) => Ok(serde_json::from_str(&reply)?),
+            Ok(Err(e)) => Err(format_err!("{}", e)),
+            Err(status) => Err(format_err!("simulated controller replied with {}", status)),
+        }
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        self.scheduler.shutdown();
+    }
+}
@@ -0,0 +1,221 @@
+//! The network of a simulated deployment, made of in-memory links between made-up addresses.
+
+use super::scheduler::{Node, Scheduler};
+use crate::coordination::CoordinationMessage;
+use crate::startup::Event;
+use dataflow::payload::ControlReplyPacket;
+use dataflow::prelude::{ChannelCoordinator, Packet};
+use dataflow::Readers;
+use noria::internal::DomainIndex;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::prelude::*;
+
+/// The made-up address that messages from the controller come from.
+crate fn controller_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 254)), 6000)
+}
+
+/// The made-up address that worker `i` listens on for messages from the controller.
+crate fn worker_addr(i: usize) -> SocketAddr {
+    SocketAddr::new(worker_ip(i), 4000)
+}
+
+/// The made-up address that worker `i` serves reads on.
+crate fn read_addr(i: usize) -> SocketAddr {
+    SocketAddr::new(worker_ip(i), 4001)
+}
+
+/// The made-up address that messages from worker `i` to the controller come from.
+crate fn source_addr(i: usize) -> SocketAddr {
+    SocketAddr::new(worker_ip(i), 4002)
+}
+
+fn worker_ip(i: usize) -> IpAddr {
+    assert!(i < 250, "too many simulated workers");
+    IpAddr::V4(Ipv4Addr::new(10, 0, 0, i as u8 + 1))
+}
+
+/// A sender whose messages are delivered by a task of their own, so that when they arrive is up
+/// to the scheduler.
+///
+/// Messages on a link arrive in the order they were sent, and are handed to `deliver`. Messages
+/// for a node that has failed are lost.
+crate fn link<T, F>(
+    scheduler: &Scheduler,
+    label: &str,
+    deliver: F,
+) -> tokio_sync::mpsc::UnboundedSender<T>
+where
+    T: Send + 'static,
+    F: FnMut(T) + Send + 'static,
+{
+    let (tx, rx) = tokio_sync::mpsc::unbounded_channel();
+    scheduler.spawn(Node::Network, label, Link { rx, deliver });
+    tx
+}
+
+struct Link<T, F> {
+    rx: tokio_sync::mpsc::UnboundedReceiver<T>,
+    deliver: F,
+}
+
+impl<T, F: FnMut(T)> Future for Link<T, F> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        // one message at a time, so that messages on different links can arrive in any order
+        match self.rx.poll().map_err(|_| ())? {
+            Async::Ready(Some(m)) => {
+                (self.deliver)(m);
+                task::current().notify();
+                Ok(Async::NotReady)
+            }
+            Async::Ready(None) => Ok(Async::Ready(())),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Hosts {
+    /// Where messages to each worker are delivered.
+    workers: HashMap<SocketAddr, tokio_sync::mpsc::UnboundedSender<CoordinationMessage>>,
+    /// The readers each worker serves reads from.
+    readers: HashMap<SocketAddr, Readers>,
+    /// The domain shard at each address.
+    domains: HashMap<SocketAddr, (DomainIndex, usize)>,
+    /// How many domain shards have been given an address on each worker.
+    booted: HashMap<usize, u16>,
+    controller: Option<futures::sync::mpsc::UnboundedSender<Event>>,
+    replies: Option<futures::sync::mpsc::UnboundedSender<ControlReplyPacket>>,
+}
+
+/// What can be reached at each address of a simulated deployment.
+#[derive(Clone)]
+crate struct Network {
+    scheduler: Scheduler,
+    hosts: Arc<Mutex<Hosts>>,
+    /// The channels to every domain, which all nodes share.
+    coordinator: Arc<ChannelCoordinator>,
+}
+
+impl Network {
+    crate fn new(scheduler: Scheduler) -> Self {
+        Network {
+            scheduler,
+            hosts: Default::default(),
+            coordinator: Arc::new(ChannelCoordinator::new()),
+        }
+    }
+
+    crate fn coordinator(&self) -> Arc<ChannelCoordinator> {
+        self.coordinator.clone()
+    }
+
+    /// Deliver messages for the controller to `inbox`, and replies from domains to `replies`.
+    crate fn listen_controller(
+        &self,
+        inbox: futures::sync::mpsc::UnboundedSender<Event>,
+        replies: futures::sync::mpsc::UnboundedSender<ControlReplyPacket>,
+    ) {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.controller = Some(inbox);
+        hosts.replies = Some(replies);
+    }
+
+    /// Deliver messages for worker `i` to `inbox`, and serve reads from `readers`.
+    crate fn listen_worker(
+        &self,
+        i: usize,
+        inbox: tokio_sync::mpsc::UnboundedSender<CoordinationMessage>,
+        readers: Readers,
+    ) {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.workers.insert(worker_addr(i), inbox);
+        hosts.readers.insert(read_addr(i), readers);
+    }
+
+    /// Connect to the worker at `addr`.
+    crate fn connect_worker(
+        &self,
+        addr: &SocketAddr,
+    ) -> Option<tokio_sync::mpsc::UnboundedSender<CoordinationMessage>> {
+        let mut inbox = self.hosts.lock().unwrap().workers.get(addr)?.clone();
+        let label = format!("ctrl->{}", addr.ip());
+        Some(link(&self.scheduler, &label, move |m| {
+            // the worker may have failed
+            let _ = inbox.try_send(m);
+        }))
+    }
+
+    /// Connect worker `i` to the controller.
+    crate fn connect_controller(
+        &self,
+        i: usize,
+    ) -> tokio_sync::mpsc::UnboundedSender<CoordinationMessage> {
+        let inbox = self.hosts.lock().unwrap().controller.clone();
+        let inbox = inbox.expect("simulated controller is not listening");
+        link(&self.scheduler, &format!("w{}->ctrl", i), move |m| {
+            let _ = inbox.unbounded_send(Event::InternalMessage(m));
+        })
+    }
+
+    /// Connect the domain shard `(idx, shard)` to the controller, to send it replies on.
+    crate fn connect_replies(
+        &self,
+        (idx, shard): (DomainIndex, usize),
+    ) -> tokio_sync::mpsc::UnboundedSender<ControlReplyPacket> {
+        let replies = self.hosts.lock().unwrap().replies.clone();
+        let replies = replies.expect("simulated controller is not listening");
+        let label = format!("d{}.{}->ctrl", idx.index(), shard);
+        link(&self.scheduler, &label, move |m| {
+            let _ = replies.unbounded_send(m);
+        })
+    }
+
+    /// Give the domain shard `(idx, shard)` on worker `i` an address, and connect other domains to
+    /// it through `inbox`.
+    crate fn listen_domain(
+        &self,
+        i: usize,
+        (idx, shard): (DomainIndex, usize),
+        mut inbox: tokio_sync::mpsc::UnboundedSender<Box<Packet>>,
+    ) -> SocketAddr {
+        let addr = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let n = hosts.booted.entry(i).or_insert(0);
+            *n += 1;
+            let addr = SocketAddr::new(worker_ip(i), 5000 + *n);
+            hosts.domains.insert(addr, (idx, shard));
+            addr
+        };
+
+        let label = format!("d{}.{}", idx.index(), shard);
+        let tx = link(&self.scheduler, &label, move |p| {
+            let _ = inbox.try_send(p);
+        });
+        self.coordinator.insert_local((idx, shard), tx);
+        self.coordinator.insert_remote((idx, shard), addr);
+        addr
+    }
+
+    /// The domain shard at `addr`.
+    crate fn domain_at(&self, addr: &SocketAddr) -> Option<(DomainIndex, usize)> {
+        self.hosts.lock().unwrap().domains.get(addr).cloned()
+    }
+
+    /// The readers that are served at `addr`.
+    crate fn readers_at(&self, addr: &SocketAddr) -> Option<Readers> {
+        self.hosts.lock().unwrap().readers.get(addr).cloned()
+    }
+
+    /// Forget what worker `i` serves, once it has failed.
+    crate fn remove_worker(&self, i: usize) {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.workers.remove(&worker_addr(i));
+        hosts.readers.remove(&read_addr(i));
+    }
+}
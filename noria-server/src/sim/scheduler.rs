@@ -0,0 +1,549 @@
+//! A scheduler that runs every task of a simulated deployment, one at a time, on one thread.
+
+use dataflow::{Clock, VirtualClock};
+use futures::executor::{self, Notify, NotifyHandle, Spawn};
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::prelude::*;
+
+type Task = Spawn<Box<dyn Future<Item = (), Error = ()> + Send>>;
+
+/// A part of a simulated deployment that fails as a whole.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+crate enum Node {
+    /// The links between the other nodes, which never fail.
+    Network,
+    Controller,
+    Worker(usize),
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Node::Network => write!(f, "net"),
+            Node::Controller => write!(f, "ctrl"),
+            Node::Worker(i) => write!(f, "w{}", i),
+        }
+    }
+}
+
+/// The tasks that have been woken up since they were last run.
+#[derive(Default)]
+struct Woken(Mutex<BTreeSet<usize>>);
+
+impl Notify for Woken {
+    fn notify(&self, id: usize) {
+        // tasks may be dropped, and wake others, while unwinding from a panic in the scheduler
+        let mut woken = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        woken.insert(id);
+    }
+}
+
+struct Entry {
+    name: String,
+    node: Node,
+    /// The task, unless it is running right now.
+    task: Option<Task>,
+}
+
+struct Core {
+    rng: XorShiftRng,
+    tasks: HashMap<usize, Entry>,
+    next_id: usize,
+    /// The pending timers by when they expire, with the task each wakes and whether it repeats.
+    timers: BTreeMap<(Instant, usize), (usize, bool)>,
+    next_timer: usize,
+    /// The tasks that are running, innermost last.
+    running: Vec<usize>,
+    steps: usize,
+    max_steps: usize,
+    /// What ran, and what failed, in order.
+    trace: Vec<String>,
+    crashed: HashSet<Node>,
+    /// Nodes that fail once the given number of steps have run.
+    failures: Vec<(usize, Node)>,
+    on_crash: HashMap<Node, Vec<Box<dyn FnOnce() + Send>>>,
+}
+
+struct Inner {
+    core: Mutex<Core>,
+    woken: Arc<Woken>,
+    clock: VirtualClock,
+}
+
+/// Runs tasks one at a time, in an order chosen by a seeded random number generator.
+///
+/// Whenever more than one task can make progress, the next one to run is picked at random, and
+/// time only passes when a timer is made to expire. Given the same seed and the same calls, tasks
+/// therefore interleave the same way on every run.
+#[derive(Clone)]
+crate struct Scheduler(Arc<Inner>);
+
+impl Scheduler {
+    /// A scheduler that picks tasks according to `seed`, and panics if it runs more than
+    /// `max_steps` of them.
+    crate fn new(seed: u64, max_steps: usize) -> Self {
+        let mut s = [0; 16];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = (seed >> (8 * (i % 8))) as u8;
+        }
+
+        Scheduler(Arc::new(Inner {
+            core: Mutex::new(Core {
+                rng: XorShiftRng::from_seed(s),
+                tasks: HashMap::new(),
+                next_id: 0,
+                timers: BTreeMap::new(),
+                next_timer: 0,
+                running: Vec::new(),
+                steps: 0,
+                max_steps,
+                trace: Vec::new(),
+                crashed: HashSet::new(),
+                failures: Vec::new(),
+                on_crash: HashMap::new(),
+            }),
+            woken: Default::default(),
+            clock: VirtualClock::new(),
+        }))
+    }
+
+    fn core(&self) -> MutexGuard<Core> {
+        self.0.core.lock().unwrap()
+    }
+
+    /// The clock that only moves when a timer expires.
+    crate fn clock(&self) -> &VirtualClock {
+        &self.0.clock
+    }
+
+    /// What ran, and what failed, so far.
+    crate fn trace(&self) -> Vec<String> {
+        self.core().trace.clone()
+    }
+
+    /// Run `f` on `node`, unless `node` has failed.
+    ///
+    /// The task is named `label` in the trace, prefixed with the node.
+    crate fn spawn<F>(&self, node: Node, label: &str, f: F)
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        let name = format!("{}/{}", node, label);
+        self.insert(node, name, Box::new(f));
+    }
+
+    /// Run `f` on the node of the task that is running, naming it after that task.
+    fn spawn_here(&self, f: Box<dyn Future<Item = (), Error = ()> + Send>) {
+        let (node, name) = {
+            let core = self.core();
+            match core.running.last().map(|id| &core.tasks[id]) {
+                Some(parent) => (parent.node, format!("{}+{}", parent.name, core.next_id)),
+                None => (Node::Network, format!("{}/{}", Node::Network, core.next_id)),
+            }
+        };
+        self.insert(node, name, f);
+    }
+
+    fn insert(&self, node: Node, name: String, f: Box<dyn Future<Item = (), Error = ()> + Send>) {
+        let task = executor::spawn(f);
+        let rejected = {
+            let mut core = self.core();
+            if core.crashed.contains(&node) {
+                Some(task)
+            } else {
+                // new tasks start out woken, so that they are run at least once
+                let id = core.next_id;
+                core.next_id += 1;
+                let task = Some(task);
+                core.tasks.insert(id, Entry { name, node, task });
+                self.0.woken.notify(id);
+                None
+            }
+        };
+        // the task may hold timers, which need the lock to cancel
+        drop(rejected);
+    }
+
+    /// Run `hook` when `node` fails.
+    crate fn on_crash<F: FnOnce() + Send + 'static>(&self, node: Node, hook: F) {
+        self.core()
+            .on_crash
+            .entry(node)
+            .or_insert_with(Vec::new)
+            .push(Box::new(hook));
+    }
+
+    /// Make `node` fail at a point chosen by the seed within the next `within` steps.
+    crate fn crash_within(&self, node: Node, within: usize) {
+        let mut core = self.core();
+        let at = core.steps + core.rng.gen_range(0, within.max(1));
+        core.failures.push((at, node));
+    }
+
+    /// Make `node` fail now: its tasks and timers are dropped, and nothing is delivered to it.
+    fn crash(&self, node: Node) {
+        let (tasks, hooks) = {
+            let mut core = self.core();
+            if !core.crashed.insert(node) {
+                return;
+            }
+            core.trace.push(format!("crash {}", node));
+            let ids: HashSet<usize> = core
+                .tasks
+                .iter()
+                .filter(|&(_, e)| e.node == node)
+                .map(|(&id, _)| id)
+                .collect();
+            let tasks: Vec<_> = ids.iter().filter_map(|id| core.tasks.remove(id)).collect();
+            core.timers.retain(|_, &mut (id, _)| !ids.contains(&id));
+            (tasks, core.on_crash.remove(&node).unwrap_or_default())
+        };
+        drop(tasks);
+        for hook in hooks {
+            hook();
+        }
+    }
+
+    /// Make the first of the failures that are due, or of any if `all`, happen.
+    fn strike(&self, all: bool) -> bool {
+        let node = {
+            let mut core = self.core();
+            let steps = core.steps;
+            match core.failures.iter().position(|&(at, _)| all || at <= steps) {
+                Some(i) => core.failures.remove(i).1,
+                None => return false,
+            }
+        };
+        self.crash(node);
+        true
+    }
+
+    /// Run one of the tasks that can make progress, if there are any.
+    crate fn step(&self) -> bool {
+        self.strike(false);
+
+        let picked = {
+            let mut core = self.core();
+            let core = &mut *core;
+            let mut woken = self.0.woken.0.lock().unwrap();
+            // tasks that are gone will not run again
+            woken.retain(|id| core.tasks.contains_key(id));
+            let ready: Vec<usize> = woken
+                .iter()
+                .cloned()
+                .filter(|id| core.tasks[id].task.is_some())
+                .collect();
+            if ready.is_empty() {
+                return false;
+            }
+
+            core.steps += 1;
+            if core.steps > core.max_steps {
+                Err(core.max_steps)
+            } else {
+                let id = ready[core.rng.gen_range(0, ready.len())];
+                woken.remove(&id);
+                let entry = core.tasks.get_mut(&id).unwrap();
+                core.trace.push(entry.name.clone());
+                core.running.push(id);
+                Ok((id, entry.task.take().unwrap()))
+            }
+        };
+        let (id, mut task) = match picked {
+            Ok(picked) => picked,
+            Err(max) => panic!("simulation did not settle within {} steps", max),
+        };
+
+        let notify = NotifyHandle::from(self.0.woken.clone());
+        let done = match task.poll_future_notify(&notify, id) {
+            Ok(Async::NotReady) => false,
+            Ok(Async::Ready(())) | Err(()) => true,
+        };
+
+        let finished = {
+            let mut core = self.core();
+            core.running.pop();
+            match core.tasks.get_mut(&id) {
+                Some(entry) if !done => {
+                    entry.task = Some(task);
+                    None
+                }
+                // it failed while it ran
+                None => Some(task),
+                Some(_) => {
+                    core.tasks.remove(&id);
+                    Some(task)
+                }
+            }
+        };
+        drop(finished);
+        true
+    }
+
+    /// Make the earliest timer that expires by `until` expire, moving the clock forward to it.
+    ///
+    /// Timers that repeat are only considered if `repeating` is set.
+    fn fire(&self, until: Option<Instant>, repeating: bool) -> bool {
+        let (at, id) = {
+            let mut core = self.core();
+            let next = core
+                .timers
+                .iter()
+                .find(|&(_, &(_, repeats))| repeating || !repeats)
+                .map(|(&key, &(id, _))| (key, id));
+            match next {
+                Some(((at, _), _)) if until.map(|until| at > until).unwrap_or(false) => {
+                    return false;
+                }
+                Some((key, id)) => {
+                    core.timers.remove(&key);
+                    (key.0, id)
+                }
+                None => return false,
+            }
+        };
+
+        let now = self.0.clock.now();
+        if at > now {
+            self.0.clock.advance(at - now);
+        }
+        self.0.woken.notify(id);
+        true
+    }
+
+    /// Run tasks until none can make progress, expiring timers that do not repeat along the way.
+    crate fn settle(&self) {
+        while self.step() || self.strike(true) || self.fire(None, false) {}
+    }
+
+    /// Run tasks while `d` passes, expiring every timer that is due by then.
+    crate fn run_for(&self, d: Duration) {
+        let until = self.0.clock.now() + d;
+        while self.step() || self.strike(true) || self.fire(Some(until), true) {}
+        let now = self.0.clock.now();
+        if until > now {
+            self.0.clock.advance(until - now);
+        }
+    }
+
+    /// Make progress on behalf of a task that cannot continue until other tasks do.
+    crate fn wait(&self) {
+        if !(self.step() || self.strike(true) || self.fire(None, false) || self.fire(None, true)) {
+            panic!("simulation is stuck");
+        }
+    }
+
+    /// Drop every task, and whatever they hold on to, which includes the scheduler itself.
+    crate fn shutdown(&self) {
+        let dropped = {
+            // the scheduler may have panicked
+            let mut core = self.0.core.lock().unwrap_or_else(|e| e.into_inner());
+            core.timers.clear();
+            core.failures.clear();
+            let tasks = mem::replace(&mut core.tasks, HashMap::new());
+            (tasks, mem::replace(&mut core.on_crash, HashMap::new()))
+        };
+        // tasks may hold timers, which need the lock to cancel
+        drop(dropped);
+    }
+
+    /// Run `f` with `tokio::spawn` spawning onto this scheduler.
+    crate fn enter<F: FnOnce() -> R, R>(&self, f: F) -> R {
+        let mut enter = tokio_executor::enter().expect("simulation run from within an executor");
+        let mut executor = SimExecutor(self.clone());
+        tokio_executor::with_default(&mut executor, &mut enter, |_| f())
+    }
+
+    /// A future that is ready once the clock reaches `at`.
+    crate fn delay(&self, at: Instant) -> Delay {
+        Delay {
+            scheduler: self.clone(),
+            at,
+            repeats: false,
+            timer: None,
+        }
+    }
+
+    /// A stream that yields every `every`, starting `every` from now.
+    crate fn interval(&self, every: Duration) -> Interval {
+        let mut delay = self.delay(self.0.clock.now() + every);
+        delay.repeats = true;
+        Interval { delay, every }
+    }
+
+    fn add_timer(&self, at: Instant, repeats: bool) -> (Instant, usize) {
+        let mut core = self.core();
+        let id = *core
+            .running
+            .last()
+            .expect("simulated timer used outside of a simulated task");
+        let key = (at, core.next_timer);
+        core.next_timer += 1;
+        core.timers.insert(key, (id, repeats));
+        key
+    }
+}
+
+struct SimExecutor(Scheduler);
+
+impl tokio_executor::Executor for SimExecutor {
+    fn spawn(
+        &mut self,
+        future: Box<dyn Future<Item = (), Error = ()> + Send>,
+    ) -> Result<(), tokio_executor::SpawnError> {
+        self.0.spawn_here(future);
+        Ok(())
+    }
+}
+
+/// A future that is ready once a scheduler's clock reaches a given time.
+crate struct Delay {
+    scheduler: Scheduler,
+    at: Instant,
+    repeats: bool,
+    /// The timer that wakes the task waiting for us, once there is one.
+    timer: Option<(Instant, usize)>,
+}
+
+impl Future for Delay {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.scheduler.0.clock.now() >= self.at {
+            return Ok(Async::Ready(()));
+        }
+        if self.timer.is_none() {
+            self.timer = Some(self.scheduler.add_timer(self.at, self.repeats));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for Delay {
+    fn drop(&mut self) {
+        if let Some(key) = self.timer.take() {
+            // the scheduler may have panicked
+            if let Ok(mut core) = self.scheduler.0.core.lock() {
+                core.timers.remove(&key);
+            }
+        }
+    }
+}
+
+/// A stream that yields every time a fixed interval of a scheduler's clock passes.
+crate struct Interval {
+    delay: Delay,
+    every: Duration,
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Async::NotReady = self.delay.poll()? {
+            return Ok(Async::NotReady);
+        }
+        let at = self.delay.at;
+        let mut next = self.delay.scheduler.delay(at + self.every);
+        next.repeats = true;
+        self.delay = next;
+        Ok(Async::Ready(Some(at)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A task that runs `steps` times, and then waits for a timer that expires after `timer`.
+    struct Steps {
+        id: usize,
+        steps: usize,
+        timer: Option<Delay>,
+        ran: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Future for Steps {
+        type Item = ();
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<(), ()> {
+            self.ran.lock().unwrap().push(self.id);
+            if self.steps > 0 {
+                self.steps -= 1;
+                task::current().notify();
+                return Ok(Async::NotReady);
+            }
+            match self.timer {
+                Some(ref mut timer) => timer.poll().map_err(|_| ()),
+                None => Ok(Async::Ready(())),
+            }
+        }
+    }
+
+    fn run(seed: u64, timers: &[Option<u64>], crash: Option<usize>) -> (Vec<usize>, Scheduler) {
+        let scheduler = Scheduler::new(seed, 1000);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        for (id, timer) in timers.iter().enumerate() {
+            let at = timer.map(|ms| scheduler.clock().now() + Duration::from_millis(ms));
+            let task = Steps {
+                id,
+                steps: 5,
+                timer: at.map(|at| scheduler.delay(at)),
+                ran: ran.clone(),
+            };
+            scheduler.spawn(Node::Worker(id), "steps", task);
+        }
+        if let Some(node) = crash {
+            scheduler.crash_within(Node::Worker(node), 10);
+        }
+
+        scheduler.settle();
+        let ran = ran.lock().unwrap().clone();
+        (ran, scheduler)
+    }
+
+    #[test]
+    fn schedules_are_reproducible() {
+        let timers = [None, None, None, None];
+        let (a, _) = run(1, &timers, None);
+        assert_eq!(a.len(), 4 * 6);
+        assert_eq!(a, run(1, &timers, None).0);
+        assert_ne!(a, run(2, &timers, None).0);
+    }
+
+    #[test]
+    fn timers_fire_when_idle() {
+        let (ran, scheduler) = run(1, &[Some(20), Some(10), None], None);
+
+        // the tasks with timers finish last, shortest timer first
+        assert_eq!(ran.len(), 3 * 6 + 2);
+        assert_eq!(&ran[ran.len() - 2..], &[1, 0]);
+        assert!(scheduler.trace().iter().all(|t| t.ends_with("/steps")));
+    }
+
+    #[test]
+    fn failures_happen_at_a_seeded_step() {
+        let timers = [None, None, None];
+        let (ran, scheduler) = run(3, &timers, Some(1));
+        let trace = scheduler.trace();
+
+        // the crashed task never runs again
+        let at = trace.iter().position(|t| t == "crash w1").unwrap();
+        assert!(at < 10);
+        assert!(trace[at..].iter().all(|t| t != "w1/steps"));
+        for id in &[0, 2] {
+            assert_eq!(ran.iter().filter(|&r| r == id).count(), 6);
+        }
+        assert_eq!(trace, run(3, &timers, Some(1)).1.trace());
+    }
+}
@@ -1,5 +1,6 @@
+use crate::controller::progress::MigrationTracker;
 use crate::controller::ControllerState;
-use crate::coordination::{CoordinationMessage, CoordinationPayload};
+use crate::coordination::{Capacity, CoordinationMessage, CoordinationPayload};
 use async_bincode::AsyncBincodeReader;
 use futures::sync::mpsc::UnboundedSender;
 use futures::{self, Future, Sink, Stream};
@@ -7,10 +8,12 @@ use hyper::{self, header::CONTENT_TYPE, Method, StatusCode};
 use noria::consensus::Authority;
 use noria::ControllerDescriptor;
 use rand;
+use serde_json;
 use slog;
+use std::collections::HashMap;
 use std::io;
 use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time;
 use stream_cancel::{Valve, Valved};
 use tokio;
@@ -21,7 +24,11 @@ use tokio_io_pool;
 #[cfg(test)]
 use std::boxed::FnBox;
 
+use crate::auth::AuthConfig;
 use crate::handle::Handle;
+use crate::optimizer::QueryOptimizer;
+use crate::secrets::Secrets;
+use crate::tls::Certificates;
 use crate::Config;
 
 #[allow(clippy::large_enum_variant)]
@@ -37,6 +44,10 @@ crate enum Event {
     LeaderChange(ControllerState, ControllerDescriptor),
     WonLeaderElection(ControllerState),
     CampaignError(failure::Error),
+    /// Apply the migrations that clients have submitted to the controller's queue.
+    RunMigrations,
+    /// Evict state from the universes that hold more than their quota allows.
+    EnforceQuotas,
     #[cfg(test)]
     IsReady(futures::sync::oneshot::Sender<bool>),
     #[cfg(test)]
@@ -44,6 +55,10 @@ crate enum Event {
         f: Box<FnBox(&mut crate::controller::migrate::Migration) + Send + 'static>,
         done: futures::sync::oneshot::Sender<()>,
     },
+    /// Make the next migration fail just before it sets up its materializations, by panicking if
+    /// the flag is set and with an error otherwise.
+    #[cfg(test)]
+    FailNextMigration(bool, futures::sync::oneshot::Sender<()>),
 }
 
 use std::fmt;
@@ -55,10 +70,14 @@ impl fmt::Debug for Event {
             Event::LeaderChange(..) => write!(f, "LeaderChange(..)"),
             Event::WonLeaderElection(..) => write!(f, "Won(..)"),
             Event::CampaignError(ref e) => write!(f, "CampaignError({:?})", e),
+            Event::RunMigrations => write!(f, "RunMigrations"),
+            Event::EnforceQuotas => write!(f, "EnforceQuotas"),
             #[cfg(test)]
             Event::IsReady(..) => write!(f, "IsReady"),
             #[cfg(test)]
             Event::ManualMigration { .. } => write!(f, "ManualMigration{{..}}"),
+            #[cfg(test)]
+            Event::FailNextMigration(..) => write!(f, "FailNextMigration"),
         }
     }
 }
@@ -68,9 +87,14 @@ impl fmt::Debug for Event {
 pub(super) fn start_instance<A: Authority + 'static>(
     authority: Arc<A>,
     listen_addr: IpAddr,
-    config: Config,
+    mut config: Config,
     memory_limit: Option<usize>,
     memory_check_frequency: Option<time::Duration>,
+    labels: HashMap<String, String>,
+    capacity: Capacity,
+    reader_only: bool,
+    secrets: Option<Secrets>,
+    optimizer: Option<Arc<dyn QueryOptimizer>>,
     log: slog::Logger,
 ) -> impl Future<Item = Handle<A>, Error = failure::Error> {
     let mut pool = tokio_io_pool::Builder::default();
@@ -83,7 +107,13 @@ pub(super) fn start_instance<A: Authority + 'static>(
     let (trigger, valve) = Valve::new();
     let (tx, rx) = futures::sync::mpsc::unbounded();
 
+    // the tokens that were given to us directly are kept across rotations
+    let base_auth = config.auth.clone();
     let v = try {
+        if let Some(ref secrets) = secrets {
+            secrets.apply(&mut config)?;
+        }
+
         // we'll be listening for a couple of different types of events:
         // first, events from workers
         let wport = tokio::net::TcpListener::bind(&SocketAddr::new(listen_addr, 0))?;
@@ -97,9 +127,19 @@ pub(super) fn start_instance<A: Authority + 'static>(
         // give it its own channel.
         let cport = tokio::net::TcpListener::bind(&SocketAddr::new(listen_addr, 0))?;
         let caddr = cport.local_addr()?;
-        ((wport, waddr), (xport, xaddr), (cport, caddr))
+        // if we use tls, make sure we can load the certificate before we announce ourselves, and
+        // that we, and our own handle, will be able to talk to the rest of the deployment.
+        let tls = match config.tls {
+            Some(ref tls) => Some((
+                Arc::new(Certificates::new(tls.clone(), log.clone())?),
+                Arc::new(tls.client_config()?),
+                tls.connector()?,
+            )),
+            None => None,
+        };
+        ((wport, waddr), (xport, xaddr), (cport, caddr), tls)
     };
-    let ((wport, waddr), (xport, xaddr), (cport, caddr)) = match v {
+    let ((wport, waddr), (xport, xaddr), (cport, caddr), tls) = match v {
         Ok(v) => v,
         Err(e) => return future::Either::A(future::err(e)),
     };
@@ -109,16 +149,44 @@ pub(super) fn start_instance<A: Authority + 'static>(
     // were in a single loop, that could deadlock.
     let (ctrl_tx, ctrl_rx) = futures::sync::mpsc::unbounded();
     let (worker_tx, worker_rx) = futures::sync::mpsc::unbounded();
+    let progress = MigrationTracker::default();
 
     // spawn all of those
-    tokio::spawn(listen_internal(&valve, log.clone(), tx.clone(), wport));
+    let auth = config.auth.clone().map(|auth| Arc::new(RwLock::new(auth)));
+    if let (Some(secrets), Some(auth)) = (secrets, &auth) {
+        secrets.rotate(
+            base_auth.unwrap_or_default(),
+            Arc::downgrade(auth),
+            log.clone(),
+        );
+    }
+
+    let (certs, client_tls, connector) = match tls {
+        Some((certs, client_tls, connector)) => (Some(certs), Some(client_tls), Some(connector)),
+        None => (None, None, None),
+    };
+    let domain_secret = config.domain_secret.clone().map(String::into_bytes);
+    tokio::spawn(listen_internal(
+        &valve,
+        log.clone(),
+        tx.clone(),
+        wport,
+        (certs.clone(), domain_secret.clone()),
+    ));
     let ext_log = log.clone();
     tokio::spawn(
-        listen_external(tx.clone(), valve.wrap(xport.incoming()), authority.clone()).map_err(
-            move |e| {
-                warn!(ext_log, "external request failed: {:?}", e);
-            },
-        ),
+        listen_external(
+            tx.clone(),
+            valve.wrap(xport.incoming()),
+            authority.clone(),
+            auth.clone(),
+            progress.clone(),
+            certs.clone(),
+            log.clone(),
+        )
+        .map_err(move |e| {
+            warn!(ext_log, "external request failed: {:?}", e);
+        }),
     );
 
     // first, a loop that just forwards to the appropriate place
@@ -142,6 +210,11 @@ pub(super) fn start_instance<A: Authority + 'static>(
                         CoordinationPayload::Register { .. } => fw(e, true),
                         CoordinationPayload::Heartbeat => fw(e, true),
                         CoordinationPayload::CreateUniverse(..) => fw(e, true),
+                        CoordinationPayload::UpdateConfig { .. } => fw(e, false),
+                        #[cfg(feature = "fault-injection")]
+                        CoordinationPayload::InjectFault(..) => fw(e, false),
+                        #[cfg(feature = "fault-injection")]
+                        CoordinationPayload::ClearFaults => fw(e, false),
                     },
                     Event::ExternalRequest(..) => fw(e, true),
                     #[cfg(test)]
@@ -149,19 +222,31 @@ pub(super) fn start_instance<A: Authority + 'static>(
                     Event::LeaderChange(..) => fw(e, false),
                     Event::WonLeaderElection(..) => fw(e, true),
                     Event::CampaignError(..) => fw(e, true),
+                    Event::RunMigrations => fw(e, true),
+                    Event::EnforceQuotas => fw(e, true),
                     #[cfg(test)]
                     Event::IsReady(..) => fw(e, true),
+                    #[cfg(test)]
+                    Event::FailNextMigration(..) => fw(e, true),
                 }
                 .map_err(|e| panic!("{:?}", e))
             })
             .map(|_| ()),
     );
 
+    // our own handle should be able to do anything
+    let token = config
+        .auth
+        .as_ref()
+        .and_then(|auth| auth.admin_token())
+        .map(String::from);
+
     let descriptor = ControllerDescriptor {
         external_addr: xaddr,
         worker_addr: waddr,
         domain_addr: caddr,
         nonce: rand::random(),
+        tls: client_tls.is_some(),
     };
     tokio::spawn(crate::controller::main(
         &valve,
@@ -169,9 +254,12 @@ pub(super) fn start_instance<A: Authority + 'static>(
         descriptor,
         ctrl_rx,
         cport,
+        (certs.clone(), connector.clone()),
         log.clone(),
         authority.clone(),
         tx.clone(),
+        optimizer,
+        progress,
     ));
     tokio::spawn(crate::worker::main(
         iopool.handle().clone(),
@@ -180,36 +268,55 @@ pub(super) fn start_instance<A: Authority + 'static>(
         waddr,
         memory_limit,
         memory_check_frequency,
+        (labels, capacity, reader_only),
+        (certs, connector, domain_secret),
         log.clone(),
     ));
 
-    future::Either::B(Handle::new(authority, tx, trigger, iopool))
+    future::Either::B(Handle::new(
+        authority, token, client_tls, tx, trigger, iopool,
+    ))
 }
 
+/// Listen for coordination messages, on connections that are encrypted with `certs` and that
+/// know `secret`, if those are set.
 fn listen_internal(
     valve: &Valve,
     log: slog::Logger,
     event_tx: UnboundedSender<Event>,
     on: tokio::net::TcpListener,
+    (certs, secret): (Option<Arc<Certificates>>, Option<Vec<u8>>),
 ) -> impl Future<Item = (), Error = ()> {
     let valve = valve.clone();
+    let conn_log = log.clone();
     valve
         .wrap(on.incoming())
         .map_err(failure::Error::from)
         .for_each(move |sock| {
-            tokio::spawn(
-                valve
-                    .wrap(AsyncBincodeReader::from(sock))
-                    .map(Event::InternalMessage)
-                    .map_err(failure::Error::from)
-                    .forward(
-                        event_tx
-                            .clone()
-                            .sink_map_err(|_| format_err!("main event loop went away")),
-                    )
-                    .map(|_| ())
-                    .map_err(|e| panic!("{:?}", e)),
+            let valve = valve.clone();
+            let event_tx = event_tx.clone();
+            let handshake = crate::secure(
+                certs.as_ref().map(|c| &**c),
+                secret.as_ref().map(Vec::as_slice),
+                sock,
+                &conn_log,
             );
+            tokio::spawn(handshake.and_then(move |sock| {
+                match sock {
+                    Some(sock) => Either::A(
+                        valve
+                            .wrap(AsyncBincodeReader::from(sock))
+                            .map(Event::InternalMessage)
+                            .map_err(failure::Error::from)
+                            .forward(
+                                event_tx.sink_map_err(|_| format_err!("main event loop went away")),
+                            )
+                            .map(|_| ())
+                            .map_err(|e| panic!("{:?}", e)),
+                    ),
+                    None => Either::B(future::ok(())),
+                }
+            }));
             Ok(())
         })
         .map_err(move |e| {
@@ -217,11 +324,20 @@ fn listen_internal(
         })
 }
 
-struct ExternalServer<A: Authority>(UnboundedSender<Event>, Arc<A>);
+struct ExternalServer<A: Authority>(
+    UnboundedSender<Event>,
+    Arc<A>,
+    Option<Arc<RwLock<AuthConfig>>>,
+    MigrationTracker,
+);
 fn listen_external<A: Authority + 'static>(
     event_tx: UnboundedSender<Event>,
     on: Valved<tokio::net::tcp::Incoming>,
     authority: Arc<A>,
+    auth: Option<Arc<RwLock<AuthConfig>>>,
+    progress: MigrationTracker,
+    tls: Option<Arc<Certificates>>,
+    log: slog::Logger,
 ) -> impl Future<Item = (), Error = hyper::Error> + Send {
     use hyper::{
         service::{NewService, Service},
@@ -230,7 +346,12 @@ fn listen_external<A: Authority + 'static>(
     impl<A: Authority> Clone for ExternalServer<A> {
         // Needed due to #26925
         fn clone(&self) -> Self {
-            ExternalServer(self.0.clone(), self.1.clone())
+            ExternalServer(
+                self.0.clone(),
+                self.1.clone(),
+                self.2.clone(),
+                self.3.clone(),
+            )
         }
     }
     impl<A: Authority> Service for ExternalServer<A> {
@@ -243,6 +364,16 @@ fn listen_external<A: Authority + 'static>(
             let mut res = Response::builder();
             // disable CORS to allow use as API server
             res.header(hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+            if let Some(ref auth) = self.2 {
+                let authorized = auth
+                    .read()
+                    .unwrap()
+                    .authorize(req.headers(), req.uri().path());
+                if let Err(status) = authorized {
+                    res.status(status);
+                    return Box::new(futures::future::ok(res.body(hyper::Body::empty()).unwrap()));
+                }
+            }
             if let Method::GET = *req.method() {
                 match req.uri().path() {
                     "/graph.html" => {
@@ -271,10 +402,24 @@ fn listen_external<A: Authority + 'static>(
             let path = req.uri().path().to_string();
             let query = req.uri().query().map(ToOwned::to_owned);
             let event_tx = self.0.clone();
+            let progress = self.3.clone();
             Box::new(req.into_body().concat2().and_then(move |body| {
                 let body: Vec<u8> = body.iter().cloned().collect();
+                // the controller does not get to requests while it is applying a migration, so
+                // the progress of one is answered here when it is known
+                if method == Method::POST && path == "/migration_progress" {
+                    let known = serde_json::from_slice(&body)
+                        .ok()
+                        .and_then(|ticket| progress.get(ticket));
+                    if let Some(p) = known {
+                        res.header("Content-Type", "application/json; charset=utf-8");
+                        let body = hyper::Body::from(serde_json::to_string(&p).unwrap());
+                        return Either::A(future::ok(res.body(body).unwrap()));
+                    }
+                }
+
                 let (tx, rx) = futures::sync::oneshot::channel();
-                event_tx
+                let forwarded = event_tx
                     .clone()
                     .send(Event::ExternalRequest(method, path, query, body, tx))
                     .map_err(|_| futures::Canceled)
@@ -302,7 +447,8 @@ fn listen_external<A: Authority + 'static>(
                             res.status(StatusCode::NOT_FOUND);
                             Ok(res.body(hyper::Body::empty()).unwrap())
                         }
-                    })
+                    });
+                Either::B(forwarded)
             }))
         }
     }
@@ -318,6 +464,30 @@ fn listen_external<A: Authority + 'static>(
         }
     }
 
-    let service = ExternalServer(event_tx, authority);
-    hyper::server::Server::builder(on).serve(service)
+    let service = ExternalServer(event_tx, authority, auth, progress);
+    match tls {
+        Some(certs) => {
+            // a failed handshake only affects that one connection, so we log it and move on.
+            // we also don't want a single slow client to hold up everyone else's handshakes.
+            let on = on
+                .map(move |sock| {
+                    let log = log.clone();
+                    tokio_rustls::TlsAcceptor::from(certs.current())
+                        .accept(sock)
+                        .then(move |r| -> Result<_, io::Error> {
+                            match r {
+                                Ok(s) => Ok(Some(s)),
+                                Err(e) => {
+                                    debug!(log, "tls handshake failed: {:?}", e);
+                                    Ok(None)
+                                }
+                            }
+                        })
+                })
+                .buffer_unordered(64)
+                .filter_map(|s| s);
+            future::Either::A(hyper::server::Server::builder(on).serve(service))
+        }
+        None => future::Either::B(hyper::server::Server::builder(on).serve(service)),
+    }
 }
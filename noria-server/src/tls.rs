@@ -0,0 +1,112 @@
+use noria::tls::{Connector, TlsStream};
+use rustls::internal::pemfile;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Where to find the certificate and private key used to encrypt connections to the deployment,
+/// and the name that the certificate is issued for.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+crate struct TlsConfig {
+    /// The name that connections within the deployment check the certificate against, since they
+    /// address servers by IP.
+    crate name: String,
+    crate cert: PathBuf,
+    crate key: PathBuf,
+    /// Additional root certificate used by the server's own handle, for self-signed setups.
+    crate ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// A client configuration that trusts the usual web roots, as well as `ca` if it is set.
+    crate fn client_config(&self) -> Result<rustls::ClientConfig, failure::Error> {
+        noria::tls::client_config(self.ca.as_ref().map(PathBuf::as_path))
+    }
+
+    /// What connections within the deployment are encrypted with.
+    crate fn connector(&self) -> Result<Connector, failure::Error> {
+        Connector::new(Arc::new(self.client_config()?), &self.name)
+    }
+}
+
+/// The server's current TLS configuration.
+///
+/// The certificate and key files are checked for modifications whenever a new connection is
+/// accepted, so that certificates can be rotated without restarting the server. Existing
+/// connections keep using the certificate they were established with.
+crate struct Certificates {
+    files: TlsConfig,
+    current: Mutex<(Option<SystemTime>, Arc<rustls::ServerConfig>)>,
+    log: slog::Logger,
+}
+
+impl Certificates {
+    crate fn new(files: TlsConfig, log: slog::Logger) -> Result<Self, failure::Error> {
+        let modified = last_modified(&files);
+        let config = load(&files.cert, &files.key)?;
+        Ok(Certificates {
+            files,
+            current: Mutex::new((modified, Arc::new(config))),
+            log,
+        })
+    }
+
+    crate fn current(&self) -> Arc<rustls::ServerConfig> {
+        let mut current = self.current.lock().unwrap();
+        let modified = last_modified(&self.files);
+        if modified != current.0 {
+            // we only ever replace a working configuration with another working configuration
+            match load(&self.files.cert, &self.files.key) {
+                Ok(config) => {
+                    info!(self.log, "reloaded tls certificate"; "cert" => ?self.files.cert);
+                    *current = (modified, Arc::new(config));
+                }
+                Err(e) => {
+                    warn!(self.log, "failed to reload tls certificate: {:?}", e);
+                }
+            }
+        }
+        current.1.clone()
+    }
+}
+
+/// Encrypt the connection `stream` that a server accepted with the current certificate, if
+/// there is one.
+crate fn accept<S>(certs: Option<&Certificates>, stream: S) -> TlsStream<S> {
+    match certs {
+        Some(certs) => noria::tls::accept(&certs.current(), stream),
+        None => TlsStream::plain(stream),
+    }
+}
+
+fn last_modified(files: &TlsConfig) -> Option<SystemTime> {
+    let cert = fs::metadata(&files.cert).and_then(|m| m.modified()).ok()?;
+    let key = fs::metadata(&files.key).and_then(|m| m.modified()).ok()?;
+    Some(std::cmp::max(cert, key))
+}
+
+fn load(cert: &Path, key: &Path) -> Result<rustls::ServerConfig, failure::Error> {
+    let certs = pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .map_err(|_| format_err!("could not parse certificates in {}", cert.display()))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {}", cert.display());
+    }
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key)?))
+        .map_err(|_| format_err!("could not parse private key in {}", key.display()))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key)?))
+            .map_err(|_| format_err!("could not parse private key in {}", key.display()))?;
+    }
+    let key = keys
+        .pop()
+        .ok_or_else(|| format_err!("no private key found in {}", key.display()))?;
+
+    let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|e| format_err!("invalid certificate: {:?}", e))?;
+    Ok(config)
+}
@@ -0,0 +1,138 @@
+use crate::AuditConfig;
+use dataflow::prelude::DataType;
+use dataflow::SingleReadHandle;
+use std::borrow::Cow;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A read from a view, as it appears in the audit trail.
+#[derive(Debug, Serialize)]
+struct ReadRecord {
+    /// When the read arrived, in milliseconds since the Unix epoch.
+    time: u64,
+    client: IpAddr,
+    view: String,
+    /// The security universe of the view, or `None` for global views.
+    universe: Option<String>,
+    keys: Vec<Vec<serde_json::Value>>,
+}
+
+/// Records which clients read which keys from which views.
+///
+/// Records are written on a separate thread as one JSON object per line, so that operators can
+/// forward them to whatever system collects their audit logs.
+#[derive(Clone)]
+crate struct Auditor {
+    tx: mpsc::Sender<ReadRecord>,
+    sample: f64,
+}
+
+impl Auditor {
+    crate fn new(config: &AuditConfig, log: slog::Logger) -> io::Result<Self> {
+        let out: Box<dyn Write + Send> = if config.path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config.path)?,
+            )
+        };
+
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("srv-audit".to_owned())
+            .spawn(move || {
+                if let Err(e) = write_records(rx, out) {
+                    error!(log, "failed to write audit trail: {:?}", e);
+                }
+            })?;
+
+        Ok(Auditor {
+            tx,
+            sample: config.sample,
+        })
+    }
+
+    /// Record that `client` read `keys` from the view behind `reader`, if this read is sampled.
+    crate fn record(&self, client: IpAddr, reader: &SingleReadHandle, keys: &[Vec<DataType>]) {
+        if self.sample < 1.0 && rand::random::<f64>() >= self.sample {
+            return;
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+            .unwrap_or(0);
+        let record = ReadRecord {
+            time,
+            client,
+            view: reader.view().to_owned(),
+            universe: reader.universe().map(String::from),
+            keys: keys
+                .iter()
+                .map(|key| key.iter().map(to_json).collect())
+                .collect(),
+        };
+
+        // the writer only goes away if the trail can no longer be written, which it has logged
+        let _ = self.tx.send(record);
+    }
+}
+
+fn write_records(rx: mpsc::Receiver<ReadRecord>, out: Box<dyn Write + Send>) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+    while let Ok(record) = rx.recv() {
+        // write out everything that has queued up before flushing
+        let mut next = Some(record);
+        while let Some(record) = next {
+            serde_json::to_writer(&mut out, &record)?;
+            out.write_all(b"\n")?;
+            next = rx.try_recv().ok();
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn to_json(d: &DataType) -> serde_json::Value {
+    match *d {
+        DataType::None => serde_json::Value::Null,
+        DataType::Int(n) => n.into(),
+        DataType::BigInt(n) => n.into(),
+        DataType::Real(i, frac) => (i as f64 + f64::from(frac) * 1e-9).into(),
+        DataType::Text(..) | DataType::TinyText(..) => {
+            let text: Cow<str> = d.into();
+            text.into_owned().into()
+        }
+        DataType::Timestamp(ts) => ts.to_string().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_plain_json() {
+        let keys: Vec<serde_json::Value> = vec![
+            DataType::None,
+            1.into(),
+            "a".into(),
+            DataType::Real(-1, -500_000_000),
+        ]
+        .iter()
+        .map(to_json)
+        .collect();
+        assert_eq!(
+            serde_json::to_string(&keys).unwrap(),
+            r#"[null,1,"a",-1.5]"#
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use crate::RateLimits;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often clients that have not been heard from in a while are forgotten.
+const PRUNE_EVERY: Duration = Duration::from_secs(10);
+
+/// A token bucket that refills at a fixed rate, and holds at most one second's worth of tokens.
+#[derive(Debug, Default)]
+struct Bucket {
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+impl Bucket {
+    fn take(&mut self, rate: u32, now: Instant) -> bool {
+        let rate = f64::from(rate);
+        match self.last {
+            Some(last) => {
+                let elapsed = now.duration_since(last);
+                let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+                self.tokens = (self.tokens + elapsed * rate).min(rate);
+            }
+            None => self.tokens = rate,
+        }
+        self.last = Some(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the bucket has been full for long enough that it is no different from a new one.
+    fn is_full(&self, now: Instant) -> bool {
+        self.last.map_or(true, |last| {
+            now.duration_since(last) >= Duration::from_secs(1)
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Client {
+    reads: Bucket,
+    writes: Bucket,
+    replays: usize,
+}
+
+impl Client {
+    /// Whether forgetting about the client would make no difference to what it is admitted to.
+    fn is_idle(&self, now: Instant) -> bool {
+        self.replays == 0 && self.reads.is_full(now) && self.writes.is_full(now)
+    }
+}
+
+#[derive(Debug)]
+struct Clients {
+    by_ip: HashMap<IpAddr, Client>,
+    pruned: Instant,
+}
+
+/// Tracks the load that each client places on this worker, and decides whether to admit more.
+///
+/// The worker's read and write channels carry no credentials, so clients are told apart by their
+/// IP address.
+#[derive(Clone)]
+crate struct Limiter {
+    limits: RateLimits,
+    clients: Arc<Mutex<Clients>>,
+}
+
+impl Limiter {
+    crate fn new(limits: RateLimits) -> Self {
+        Limiter {
+            limits,
+            clients: Arc::new(Mutex::new(Clients {
+                by_ip: HashMap::new(),
+                pruned: Instant::now(),
+            })),
+        }
+    }
+
+    /// Whether `client` may issue another read.
+    crate fn admit_read(&self, client: IpAddr) -> bool {
+        match self.limits.reads_per_sec {
+            Some(rate) => self.with(client, |c| c.reads.take(rate, Instant::now())),
+            None => true,
+        }
+    }
+
+    /// Whether `client` may issue another write.
+    crate fn admit_write(&self, client: IpAddr) -> bool {
+        match self.limits.writes_per_sec {
+            Some(rate) => self.with(client, |c| c.writes.take(rate, Instant::now())),
+            None => true,
+        }
+    }
+
+    /// Reserve one of the replays that `client` may wait on concurrently.
+    ///
+    /// The reservation is released when the returned guard is dropped.
+    crate fn start_replay(&self, client: IpAddr) -> Option<ReplayGuard> {
+        let max = match self.limits.concurrent_replays {
+            Some(max) => max,
+            None => {
+                return Some(ReplayGuard {
+                    limiter: None,
+                    client,
+                });
+            }
+        };
+
+        let admitted = self.with(client, |c| {
+            if c.replays < max {
+                c.replays += 1;
+                true
+            } else {
+                false
+            }
+        });
+
+        if admitted {
+            Some(ReplayGuard {
+                limiter: Some(self.clone()),
+                client,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn with<F, T>(&self, client: IpAddr, f: F) -> T
+    where
+        F: FnOnce(&mut Client) -> T,
+    {
+        let mut clients = self.clients.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(clients.pruned) >= PRUNE_EVERY {
+            Self::prune(&mut clients, now);
+        }
+        f(clients.by_ip.entry(client).or_default())
+    }
+
+    /// Forget about the clients that are idle at `now`, so that clients that come and go do not
+    /// make the limiter grow without bound.
+    fn prune(clients: &mut Clients, now: Instant) {
+        clients.by_ip.retain(|_, c| !c.is_idle(now));
+        clients.pruned = now;
+    }
+}
+
+/// A replay that a client is waiting on.
+crate struct ReplayGuard {
+    limiter: Option<Limiter>,
+    client: IpAddr,
+}
+
+impl Drop for ReplayGuard {
+    fn drop(&mut self) {
+        if let Some(ref limiter) = self.limiter {
+            limiter.with(self.client, |c| c.replays -= 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn buckets_refill() {
+        let start = Instant::now();
+        let mut b = Bucket::default();
+        assert!(b.take(2, start));
+        assert!(b.take(2, start));
+        assert!(!b.take(2, start));
+        assert!(b.take(2, start + Duration::from_millis(500)));
+        assert!(!b.take(2, start + Duration::from_millis(500)));
+
+        // tokens don't accumulate beyond one second's worth
+        let later = start + Duration::from_secs(10);
+        assert!(b.take(2, later));
+        assert!(b.take(2, later));
+        assert!(!b.take(2, later));
+    }
+
+    #[test]
+    fn limits_are_per_client() {
+        let limiter = Limiter::new(RateLimits {
+            writes_per_sec: Some(1),
+            concurrent_replays: Some(1),
+            ..Default::default()
+        });
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert!(limiter.admit_write(a));
+        assert!(!limiter.admit_write(a));
+        assert!(limiter.admit_write(b));
+        assert!(limiter.admit_read(a));
+
+        let replay = limiter.start_replay(a);
+        assert!(replay.is_some());
+        assert!(limiter.start_replay(a).is_none());
+        assert!(limiter.start_replay(b).is_some());
+        drop(replay);
+        assert!(limiter.start_replay(a).is_some());
+    }
+
+    #[test]
+    fn idle_clients_are_forgotten() {
+        let limiter = Limiter::new(RateLimits {
+            writes_per_sec: Some(1),
+            concurrent_replays: Some(1),
+            ..Default::default()
+        });
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert!(limiter.admit_write(a));
+        let replay = limiter.start_replay(b);
+        assert!(replay.is_some());
+
+        // clients are remembered while they may still be refused
+        let mut clients = limiter.clients.lock().unwrap();
+        Limiter::prune(&mut clients, Instant::now());
+        assert_eq!(clients.by_ip.len(), 2);
+
+        // but not once their allowance has been replenished, unless they are waiting on replays
+        Limiter::prune(&mut clients, Instant::now() + Duration::from_secs(2));
+        assert_eq!(clients.by_ip.len(), 1);
+        assert!(clients.by_ip.contains_key(&b));
+        drop(clients);
+
+        drop(replay);
+        let mut clients = limiter.clients.lock().unwrap();
+        Limiter::prune(&mut clients, Instant::now() + Duration::from_secs(2));
+        assert!(clients.by_ip.is_empty());
+    }
+}
@@ -1,13 +1,16 @@
 use crate::controller::ControllerState;
-use crate::coordination::{CoordinationMessage, CoordinationPayload, DomainDescriptor};
+use crate::coordination::{Capacity, CoordinationMessage, CoordinationPayload, DomainDescriptor};
+use crate::faults::Faults;
 use crate::startup::Event;
+use crate::tls::Certificates;
 use async_bincode::AsyncBincodeWriter;
-use dataflow::{DomainBuilder, Packet};
+use dataflow::{BackgroundSync, DomainBuilder, Packet, StateSize, SystemClock};
 use futures::sync::mpsc::UnboundedSender;
 use futures::{self, Future, Sink, Stream};
 use noria::channel::{self, TcpSender};
 use noria::consensus::Epoch;
 use noria::internal::DomainIndex;
+use noria::tls::Connector;
 use noria::ControllerDescriptor;
 use replica::ReplicaIndex;
 use slog;
@@ -26,8 +29,12 @@ use tokio::prelude::future::Either;
 use tokio::prelude::*;
 use tokio_io_pool;
 
+mod audit;
+mod limits;
 mod readers;
 mod replica;
+crate mod sim; // crate viz for simulations
+mod spill;
 
 type ChannelCoordinator = channel::ChannelCoordinator<ReplicaIndex, Box<Packet>>;
 
@@ -52,10 +59,19 @@ pub(super) fn main(
     waddr: SocketAddr,
     memory_limit: Option<usize>,
     memory_check_frequency: Option<time::Duration>,
+    (labels, capacity, reader_only): (HashMap<String, String>, Capacity, bool),
+    (certs, connector, domain_secret): (
+        Option<Arc<Certificates>>,
+        Option<Connector>,
+        Option<Vec<u8>>,
+    ),
     log: slog::Logger,
 ) -> impl Future<Item = (), Error = ()> {
     // shared df state
-    let coord = Arc::new(ChannelCoordinator::new());
+    let coord = Arc::new(ChannelCoordinator::secured(domain_secret, connector));
+    let faults = Arc::new(Faults::default());
+    // 0 means that there is no limit, which lets the limit be changed while domains run
+    let memory_limit = Arc::new(AtomicUsize::new(memory_limit.unwrap_or(0)));
 
     let mut worker_state = InstanceState::Pining;
     let log = log.clone();
@@ -102,6 +118,22 @@ pub(super) fn main(
                             }
                         }
                     }
+                    CoordinationPayload::UpdateConfig {
+                        memory_limit: limit,
+                    } => {
+                        info!(log, "changing memory limit"; "bytes" => limit);
+                        memory_limit.store(limit, Ordering::Relaxed);
+                    }
+                    #[cfg(feature = "fault-injection")]
+                    CoordinationPayload::InjectFault(fault) => {
+                        warn!(log, "injecting fault"; "fault" => ?fault);
+                        faults.inject(fault);
+                    }
+                    #[cfg(feature = "fault-injection")]
+                    CoordinationPayload::ClearFaults => {
+                        warn!(log, "clearing injected faults");
+                        faults.clear();
+                    }
                     _ => unreachable!(),
                 },
                 Event::LeaderChange(state, descriptor) => {
@@ -143,11 +175,14 @@ pub(super) fn main(
                         valve,
                         &ioh,
                         log.clone(),
-                        (memory_limit, memory_check_frequency),
+                        (memory_limit.clone(), memory_check_frequency),
+                        (labels.clone(), capacity, reader_only),
                         &state,
                         &descriptor,
                         waddr,
                         coord.clone(),
+                        certs.clone(),
+                        faults.clone(),
                         listen_addr,
                         rep_rx,
                     );
@@ -185,18 +220,24 @@ fn listen_df(
     valve: Valve,
     ioh: &tokio_io_pool::Handle,
     log: slog::Logger,
-    (memory_limit, evict_every): (Option<usize>, Option<Duration>),
+    (memory_limit, evict_every): (Arc<AtomicUsize>, Option<Duration>),
+    (labels, capacity, reader_only): (HashMap<String, String>, Capacity, bool),
     state: &ControllerState,
     desc: &ControllerDescriptor,
     waddr: SocketAddr,
     coord: Arc<ChannelCoordinator>,
+    certs: Option<Arc<Certificates>>,
+    faults: Arc<Faults>,
     on: IpAddr,
     replicas: futures::sync::mpsc::UnboundedReceiver<DomainBuilder>,
 ) -> Result<(), failure::Error> {
     // first, try to connect to controller
-    let ctrl = ::std::net::TcpStream::connect(&desc.worker_addr)?;
-    let ctrl = tokio::net::TcpStream::from_std(ctrl, &Default::default())?;
-    let ctrl_addr = ctrl.local_addr()?;
+    let ctrl = crate::block_on(|| -> io::Result<_> {
+        let ctrl = ::std::net::TcpStream::connect(&desc.worker_addr)?;
+        channel::secure_sync(ctrl, coord.tls(), coord.secret())
+    })?;
+    let ctrl = ctrl.map(|s| s.map(|s| tokio::net::TcpStream::from_std(s, &Default::default())))?;
+    let ctrl_addr = ctrl.get_ref().get_ref().local_addr()?;
     info!(log, "connected to controller"; "src" => ?ctrl_addr);
 
     let log_prefix = state.config.persistence.log_prefix.clone();
@@ -212,6 +253,17 @@ fn listen_df(
     // extract important things from state config
     let epoch = state.epoch;
     let heartbeat_every = state.config.heartbeat_every;
+    let limiter = limits::Limiter::new(state.config.rate_limits);
+    // the base tables of all our domains are synced to disk by the same thread
+    let syncer = state
+        .config
+        .persistence
+        .background_sync
+        .map(BackgroundSync::start);
+    let auditor = match state.config.audit {
+        Some(ref audit) => Some(audit::Auditor::new(audit, log.clone())?),
+        None => None,
+    };
 
     let (ctrl_tx, ctrl_rx) = futures::sync::mpsc::unbounded();
 
@@ -240,7 +292,21 @@ fn listen_df(
     );
 
     // also start readers
-    tokio::spawn(readers::listen(&valve, ioh, rport, readers.clone()));
+    tokio::spawn(readers::listen(
+        &valve,
+        ioh,
+        rport,
+        readers.clone(),
+        limiter.clone(),
+        auditor,
+        (
+            certs.clone(),
+            coord
+                .secret()
+                .map(|s| channel::auth::derive(s, channel::auth::READ)),
+        ),
+        log.clone(),
+    ));
 
     // and tell the controller about us
     let timer = valve.wrap(tokio::timer::Interval::new(
@@ -254,6 +320,9 @@ fn listen_df(
                 addr: waddr,
                 read_listen_addr: raddr,
                 log_files,
+                labels,
+                capacity,
+                reader_only,
             })
             .and_then(move |ctrl_tx| {
                 // and start sending heartbeats
@@ -280,6 +349,10 @@ fn listen_df(
         tokio::spawn(
             timer
                 .for_each(move |_| {
+                    let memory_limit = match memory_limit.load(Ordering::Relaxed) {
+                        0 => None,
+                        limit => Some(limit),
+                    };
                     do_eviction(&log, memory_limit, &mut domain_senders, &state_sizes)
                         .map_err(|e| panic!("{:?}", e))
                 })
@@ -299,15 +372,22 @@ fn listen_df(
                     let on = tokio::net::TcpListener::bind(&SocketAddr::new(on, 0))?;
                     let addr = on.local_addr()?;
 
-                    let state_size = Arc::new(AtomicUsize::new(0));
-                    let d = d.build(
-                        log.clone(),
-                        readers.clone(),
-                        coord.clone(),
-                        dcaddr,
-                        &valve,
-                        state_size.clone(),
-                    );
+                    let state_size = Arc::new(StateSize::default());
+                    // the domain authenticates its connection to the controller as it is built
+                    let d = crate::block_on(|| -> io::Result<_> {
+                        let control_reply_tx =
+                            TcpSender::connect_with(&dcaddr, coord.tls(), coord.secret())?;
+                        Ok(d.build(
+                            log.clone(),
+                            readers.clone(),
+                            coord.clone(),
+                            Box::new(control_reply_tx),
+                            &valve,
+                            state_size.clone(),
+                            Arc::new(SystemClock),
+                            syncer.clone(),
+                        ))
+                    })?;
 
                     let (tx, rx) = tokio_sync::mpsc::unbounded_channel();
 
@@ -321,7 +401,7 @@ fn listen_df(
                         state_sizes.lock().unwrap().insert((idx, shard), state_size)
                     });
 
-                    tokio::spawn(replica::Replica::new(
+                    let replica = replica::Replica::new(
                         &valve,
                         d,
                         on,
@@ -329,7 +409,11 @@ fn listen_df(
                         ctrl_tx.clone(),
                         log.clone(),
                         coord.clone(),
-                    ));
+                        certs.clone(),
+                        faults.clone(),
+                        limiter.clone(),
+                    );
+                    tokio::spawn(replica);
 
                     info!(
                         log,
@@ -368,45 +452,48 @@ fn do_eviction(
     log: &slog::Logger,
     memory_limit: Option<usize>,
     domain_senders: &mut HashMap<(DomainIndex, usize), TcpSender<Box<Packet>>>,
-    state_sizes: &Arc<Mutex<HashMap<(DomainIndex, usize), Arc<AtomicUsize>>>>,
+    state_sizes: &Arc<Mutex<HashMap<(DomainIndex, usize), Arc<StateSize>>>>,
 ) -> impl Future<Item = (), Error = ()> {
     use std::cmp;
 
     // 2. add current state sizes (could be out of date, as packet sent below is not
     //    necessarily received immediately)
-    let sizes: Vec<((DomainIndex, usize), usize)> = crate::block_on(|| {
+    let sizes: Vec<((DomainIndex, usize), usize, usize)> = crate::block_on(|| {
         let state_sizes = state_sizes.lock().unwrap();
         state_sizes
             .iter()
             .map(|(ds, sa)| {
-                let size = sa.load(Ordering::Relaxed);
+                let size = sa.bytes.load(Ordering::Relaxed);
+                let evictable = sa.evictable.load(Ordering::Relaxed);
                 trace!(
                     log,
-                    "domain {}.{} state size is {} bytes",
+                    "domain {}.{} state size is {} bytes ({} weighted evictable)",
                     ds.0.index(),
                     ds.1,
-                    size
+                    size,
+                    evictable
                 );
-                (*ds, size)
+                (*ds, size, evictable)
             })
             .collect()
     });
 
     // 3. are we above the limit?
-    let total: usize = sizes.iter().map(|&(_, s)| s).sum();
+    let total: usize = sizes.iter().map(|&(_, s, _)| s).sum();
     match memory_limit {
         None => (),
         Some(limit) => {
             if total >= limit {
-                // evict from the largest domain
-                let largest = sizes.into_iter().max_by_key(|&(_, s)| s).unwrap();
+                // evict from the domain whose largest node, relative to its eviction weight, is
+                // the largest
+                let largest = sizes.into_iter().max_by_key(|&(_, s, e)| (e, s)).unwrap();
                 debug!(
-                        log,
-                        "memory footprint ({} bytes) exceeds limit ({} bytes); evicting from largest domain {}",
-                        total,
-                        limit,
-                        (largest.0).0.index(),
-                    );
+                    log,
+                    "memory footprint ({} bytes) exceeds limit ({} bytes); evicting from domain {}",
+                    total,
+                    limit,
+                    (largest.0).0.index(),
+                );
 
                 let tx = domain_senders.get_mut(&largest.0).unwrap();
                 crate::block_on(|| {
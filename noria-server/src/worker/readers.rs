@@ -1,3 +1,7 @@
+use super::audit::Auditor;
+use super::limits::{Limiter, ReplayGuard};
+use crate::secure;
+use crate::tls::Certificates;
 use async_bincode::AsyncBincodeStream;
 use dataflow::prelude::DataType;
 use dataflow::prelude::*;
@@ -6,10 +10,13 @@ use dataflow::SingleReadHandle;
 use futures::future::{self, Either};
 use futures::try_ready;
 use futures::{self, Future, Stream};
-use noria::{ReadQuery, ReadReply, Tagged};
+use noria::{Cursor, Predicate, ReadQuery, ReadReply, SnapshotToken, Tagged};
 use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::mem;
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::time;
 use stream_cancel::Valve;
 use tokio::prelude::*;
@@ -24,6 +31,10 @@ const RETRY_TIMEOUT_US: u64 = 200;
 /// while, waiting readers will use exponential backoff on this delay if they continue to miss.
 const TRIGGER_TIMEOUT_US: u64 = 50_000;
 
+/// How long a blocking read waits for a view to catch up with the snapshot it is made at, before
+/// it gives up on it.
+const SNAPSHOT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
 thread_local! {
     static READERS: RefCell<HashMap<
         (NodeIndex, usize),
@@ -36,6 +47,10 @@ pub(super) fn listen(
     ioh: &tokio_io_pool::Handle,
     on: tokio::net::TcpListener,
     readers: Readers,
+    limiter: Limiter,
+    auditor: Option<Auditor>,
+    (certs, secret): (Option<Arc<Certificates>>, Option<Vec<u8>>),
+    log: slog::Logger,
 ) -> impl Future<Item = (), Error = ()> {
     ioh.spawn_all(
         valve
@@ -46,19 +61,46 @@ pub(super) fn listen(
                 Ok(None)
             })
             .filter_map(|c| c)
-            .map(move |stream| {
+            .filter_map(|stream| {
+                // the client may have gone away already, in which case there's no one to serve
+                let client = stream.peer_addr().ok()?.ip();
+                Some((stream, client))
+            })
+            .map(move |(stream, client)| {
                 let readers = readers.clone();
-                stream.set_nodelay(true).expect("could not set TCP_NODELAY");
-                server::Server::new(
-                    AsyncBincodeStream::from(stream).for_async(),
-                    service_fn(move |req| handle_message(req, &readers)),
-                )
-                .map_err(|e| {
-                    if let server::Error::Service(()) = e {
-                        // server is shutting down -- no need to report this error
-                    } else {
-                        eprintln!("!!! reader client protocol error: {:?}", e);
-                    }
+                let limiter = limiter.clone();
+                let auditor = auditor.clone();
+                if let Err(e) = stream.set_nodelay(true) {
+                    warn!(log,
+                          "failed to set TCP_NODELAY for new reader connection: {:?}", e;
+                          "from" => ?client);
+                }
+                let handshake = secure(
+                    certs.as_ref().map(|c| &**c),
+                    secret.as_ref().map(Vec::as_slice),
+                    stream,
+                    &log,
+                );
+                handshake.and_then(move |stream| {
+                    let stream = match stream {
+                        Some(stream) => stream,
+                        None => return Either::B(future::ok(())),
+                    };
+                    Either::A(
+                        server::Server::new(
+                            AsyncBincodeStream::from(stream).for_async(),
+                            service_fn(move |req| {
+                                handle_hinted(req, &readers, &limiter, &auditor, client)
+                            }),
+                        )
+                        .map_err(|e| {
+                            if let server::Error::Service(()) = e {
+                                // server is shutting down -- no need to report this error
+                            } else {
+                                eprintln!("!!! reader client protocol error: {:?}", e);
+                            }
+                        }),
+                    )
                 })
             }),
     )
@@ -70,7 +112,8 @@ pub(super) fn listen(
     })
 }
 
-fn dup(rs: &[Vec<DataType>]) -> Vec<Vec<DataType>> {
+/// Copy out the rows `rs` read from `reader`, in the order of its view.
+fn dup(reader: &SingleReadHandle, rs: &[Vec<DataType>]) -> Vec<Vec<DataType>> {
     let mut outer = Vec::with_capacity(rs.len());
     for r in rs {
         let mut inner = Vec::with_capacity(r.len());
@@ -79,20 +122,397 @@ fn dup(rs: &[Vec<DataType>]) -> Vec<Vec<DataType>> {
         }
         outer.push(inner);
     }
+    reader.sort(&mut outer);
     outer
 }
 
+/// Which of the rows that a read finds are sent back.
+#[derive(Clone)]
+enum Rows {
+    /// All of the rows for every key.
+    All,
+    /// Some of the rows for the one key that was read.
+    Chunk { offset: usize, limit: usize },
+    /// None of the rows, only how many there are for the one key that was read.
+    Count,
+    /// The rows for the one key that was read that come after a cursor.
+    Page { after: Option<Cursor>, limit: usize },
+    /// Some of the columns of the rows that meet all of the predicates.
+    Filtered {
+        predicates: Vec<Predicate>,
+        columns: Vec<usize>,
+    },
+    /// All of the rows for the one key that was read, along with whether it hit.
+    Speculative,
+}
+
+/// Copy out the rows `rs` read from `reader` that `rows` asks for, along with how many there are.
+fn copy_rows(
+    reader: &SingleReadHandle,
+    rs: &[Vec<DataType>],
+    rows: &Rows,
+) -> (Vec<Vec<DataType>>, usize) {
+    if reader.is_masked() {
+        // the rows are masked before they are picked, so that cursors and predicates are compared
+        // with the same values that the client sees
+        let mut masked = dup(reader, rs);
+        reader.mask(&mut masked);
+        return pick_rows(reader, &masked, rows);
+    }
+    pick_rows(reader, rs, rows)
+}
+
+/// Copy out the rows in `rs` that `rows` asks for, along with how many there are.
+fn pick_rows(
+    reader: &SingleReadHandle,
+    rs: &[Vec<DataType>],
+    rows: &Rows,
+) -> (Vec<Vec<DataType>>, usize) {
+    match *rows {
+        Rows::All | Rows::Speculative => (dup(reader, rs), rs.len()),
+        Rows::Chunk { offset, limit } => {
+            // only the rows in the chunk are copied, but they are picked in the order of the view
+            let mut sorted: Vec<_> = rs.iter().collect();
+            reader.sort(&mut sorted);
+            let copied = sorted
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .map(|r| r.iter().map(DataType::deep_clone).collect())
+                .collect();
+            (copied, rs.len())
+        }
+        Rows::Count => (Vec::new(), rs.len()),
+        Rows::Page { ref after, limit } => {
+            // rows that the view's order does not tell apart are ordered by their values, so that
+            // they come in the same order on every page
+            let order =
+                |a: &[DataType], b: &[DataType]| reader.compare(a, b).then_with(|| a.cmp(b));
+            let mut rest: Vec<_> = match *after {
+                Some(ref after) => rs
+                    .iter()
+                    .filter(|r| order(r, &after.last) != Ordering::Less)
+                    .collect(),
+                None => rs.iter().collect(),
+            };
+            rest.sort_by(|a, b| order(a, b));
+            // some of the rows equal to the last one on the page before were on that page
+            let skip = match *after {
+                Some(ref after) => rest
+                    .iter()
+                    .take_while(|r| r[..] == after.last[..])
+                    .count()
+                    .min(after.seen),
+                None => 0,
+            };
+            let copied = rest[skip..]
+                .iter()
+                .take(limit)
+                .map(|r| r.iter().map(DataType::deep_clone).collect())
+                .collect();
+            (copied, rest.len() - skip)
+        }
+        Rows::Filtered {
+            ref predicates,
+            ref columns,
+        } => {
+            // rows are sorted before they are cut down, since the view may be ordered by columns
+            // that are left out
+            let mut kept: Vec<_> = rs
+                .iter()
+                .filter(|r| predicates.iter().all(|p| p.matches(r)))
+                .collect();
+            reader.sort(&mut kept);
+            let copied = kept
+                .iter()
+                .map(|r| {
+                    columns
+                        .iter()
+                        .map(|&c| r.get(c).map(DataType::deep_clone).unwrap_or(DataType::None))
+                        .collect()
+                })
+                .collect();
+            (copied, kept.len())
+        }
+    }
+}
+
+/// The reply to a read that found `read`, or an error if the view is not yet ready, along with
+/// `found` rows in total.
+fn rows_reply(read: Result<Vec<Vec<Vec<DataType>>>, ()>, rows: &Rows, found: usize) -> ReadReply {
+    match *rows {
+        Rows::All | Rows::Filtered { .. } => ReadReply::Normal(read),
+        Rows::Chunk { offset, limit } => ReadReply::Chunk(
+            read.map(|mut read| (read.pop().unwrap_or_default(), found > offset + limit)),
+        ),
+        Rows::Count => ReadReply::Count(read.map(|_| found)),
+        Rows::Speculative => {
+            ReadReply::Speculative(read.map(|mut read| (read.pop().unwrap_or_default(), true)))
+        }
+        Rows::Page { ref after, limit } => ReadReply::Page(read.map(|mut read| {
+            let page = read.pop().unwrap_or_default();
+            let next = if found > limit {
+                page.last().map(|last| {
+                    let mut seen = page.iter().rev().take_while(|&r| r == last).count();
+                    match *after {
+                        Some(ref after) if after.last == *last => seen += after.seen,
+                        _ => {}
+                    }
+                    Cursor {
+                        last: last.clone(),
+                        seen,
+                    }
+                })
+            } else {
+                None
+            };
+            (page, next)
+        })),
+    }
+}
+
+/// Whether the reader for `target` reflects every write that came before `snapshot`.
+fn reflects(s: &Readers, target: (NodeIndex, usize), snapshot: SnapshotToken) -> bool {
+    READERS.with(|readers_cache| {
+        let mut readers_cache = readers_cache.borrow_mut();
+        let reader = readers_cache.entry(target).or_insert_with(|| {
+            let readers = s.lock().unwrap();
+            readers.get(&target).unwrap().clone()
+        });
+        reader.reflects(snapshot.0)
+    })
+}
+
+/// Find the rows for `key` in `reader` and pass them to `then`, as they were at `snapshot` if it
+/// is given.
+fn find_and<F, T>(
+    reader: &SingleReadHandle,
+    key: &[DataType],
+    snapshot: Option<SnapshotToken>,
+    then: F,
+) -> Result<(Option<T>, i64), ()>
+where
+    F: FnMut(&[Vec<DataType>]) -> T,
+{
+    match snapshot {
+        Some(snapshot) => reader.try_find_at_and(key, snapshot.0, then),
+        None => reader.try_find_and(key, then),
+    }
+}
+
+/// The generation of the reader for `target`, along with the keys that it has filled since
+/// generation `since`.
+fn filled_since(
+    s: &Readers,
+    target: (NodeIndex, usize),
+    since: u64,
+) -> (u64, Option<Vec<Vec<DataType>>>) {
+    READERS.with(|readers_cache| {
+        let mut readers_cache = readers_cache.borrow_mut();
+        let reader = readers_cache.entry(target).or_insert_with(|| {
+            let readers = s.lock().unwrap();
+            readers.get(&target).unwrap().clone()
+        });
+        reader.filled_since(since)
+    })
+}
+
+/// Handle `m`, and if the client asks, tell it about the keys that were filled since it last heard
+/// from the reader along with the reply.
+fn handle_hinted(
+    m: Tagged<ReadQuery>,
+    s: &Readers,
+    limiter: &Limiter,
+    auditor: &Option<Auditor>,
+    client: IpAddr,
+) -> impl Future<Item = Tagged<ReadReply>, Error = ()> + Send {
+    let tag = m.tag;
+    let (query, since) = match m.v {
+        ReadQuery::Hinted { query, since } => (*query, since),
+        query => {
+            let m = Tagged { tag, v: query };
+            return Either::A(handle_message(m, s, limiter, auditor, client));
+        }
+    };
+    let target = match query {
+        ReadQuery::Normal { target, .. }
+        | ReadQuery::Chunk { target, .. }
+        | ReadQuery::Filtered { target, .. }
+        | ReadQuery::Page { target, .. }
+        | ReadQuery::Count { target, .. }
+        | ReadQuery::Speculative { target, .. }
+        | ReadQuery::Size { target }
+        | ReadQuery::Freshness { target }
+        | ReadQuery::Ready { target }
+        | ReadQuery::Export { target, .. } => target,
+        ReadQuery::Hinted { .. } => unreachable!("hinted reads are not nested"),
+    };
+
+    // the filled keys are found before the read, so that any key the read misses is either filled
+    // in time for the read to see it, or is among the keys filled since the generation replied with
+    let (generation, filled) = filled_since(s, target, since);
+    let m = Tagged { tag, v: query };
+    Either::B(
+        handle_message(m, s, limiter, auditor, client).map(move |reply| Tagged {
+            tag: reply.tag,
+            v: ReadReply::Hinted {
+                reply: box reply.v,
+                generation,
+                filled,
+            },
+        }),
+    )
+}
+
 fn handle_message(
     m: Tagged<ReadQuery>,
     s: &Readers,
+    limiter: &Limiter,
+    auditor: &Option<Auditor>,
+    client: IpAddr,
 ) -> impl Future<Item = Tagged<ReadReply>, Error = ()> + Send {
     let tag = m.tag;
-    match m.v {
+    // chunked, paged, counting, and filtered reads are read like any other, except for which rows
+    // go in the reply
+    let (query, rows) = match m.v {
+        ReadQuery::Chunk {
+            target,
+            key,
+            block,
+            snapshot,
+            offset,
+            limit,
+        } => {
+            let query = ReadQuery::Normal {
+                target,
+                keys: vec![key],
+                block,
+                snapshot,
+            };
+            (query, Rows::Chunk { offset, limit })
+        }
+        ReadQuery::Count {
+            target,
+            key,
+            block,
+            snapshot,
+        } => {
+            let query = ReadQuery::Normal {
+                target,
+                keys: vec![key],
+                block,
+                snapshot,
+            };
+            (query, Rows::Count)
+        }
+        ReadQuery::Page {
+            target,
+            key,
+            block,
+            snapshot,
+            after,
+            limit,
+        } => {
+            let query = ReadQuery::Normal {
+                target,
+                keys: vec![key],
+                block,
+                snapshot,
+            };
+            (query, Rows::Page { after, limit })
+        }
+        ReadQuery::Filtered {
+            target,
+            key,
+            block,
+            snapshot,
+            predicates,
+            columns,
+        } => {
+            let query = ReadQuery::Normal {
+                target,
+                keys: vec![key],
+                block,
+                snapshot,
+            };
+            let rows = Rows::Filtered {
+                predicates,
+                columns,
+            };
+            (query, rows)
+        }
+        ReadQuery::Speculative { target, key } => {
+            // the key is read like a non-blocking read, and the client waits for the replay
+            // itself if it wants the rows that were missing
+            let query = ReadQuery::Normal {
+                target,
+                keys: vec![key],
+                block: false,
+                snapshot: None,
+            };
+            (query, Rows::Speculative)
+        }
+        query => (query, Rows::All),
+    };
+    match query {
+        ReadQuery::Normal { .. } if !limiter.admit_read(client) => {
+            Either::A(Either::A(future::ok(Tagged {
+                tag,
+                v: ReadReply::RateLimited,
+            })))
+        }
+        ReadQuery::Normal {
+            target,
+            keys,
+            block,
+            snapshot: Some(snapshot),
+        } if !reflects(s, target, snapshot) => {
+            // the view hasn't caught up with the snapshot yet, so a blocking read waits for it
+            // before reading, and a non-blocking one finds the view not yet available
+            if !block {
+                return Either::A(Either::A(future::ok(Tagged {
+                    tag,
+                    v: rows_reply(Err(()), &rows, 0),
+                })));
+            }
+            let replay = limiter.start_replay(client);
+            if replay.is_none() {
+                return Either::A(Either::A(future::ok(Tagged {
+                    tag,
+                    v: ReadReply::RateLimited,
+                })));
+            }
+            if let Some(ref auditor) = *auditor {
+                READERS.with(|readers_cache| {
+                    auditor.record(client, &readers_cache.borrow()[&target], &keys)
+                });
+            }
+
+            let retry = time::Duration::from_micros(RETRY_TIMEOUT_US);
+            Either::A(Either::B(BlockingRead {
+                tag,
+                target,
+                read: vec![Vec::new(); keys.len()],
+                keys,
+                truth: s.clone(),
+                retry: tokio_os_timer::Interval::new(retry).unwrap(),
+                trigger_timeout: time::Duration::from_micros(TRIGGER_TIMEOUT_US),
+                next_trigger: time::Instant::now(),
+                snapshot: Some(snapshot),
+                deadline: Some(time::Instant::now() + SNAPSHOT_TIMEOUT),
+                block,
+                rows,
+                found: 0,
+                _replay: replay,
+            }))
+        }
         ReadQuery::Normal {
             target,
             mut keys,
             block,
+            snapshot,
         } => {
+            let mut replay = None;
             let immediate = READERS.with(|readers_cache| {
                 let mut readers_cache = readers_cache.borrow_mut();
                 let reader = readers_cache.entry(target).or_insert_with(|| {
@@ -100,14 +520,20 @@ fn handle_message(
                     readers.get(&target).unwrap().clone()
                 });
 
+                if let Some(ref auditor) = *auditor {
+                    auditor.record(client, reader, &keys);
+                }
+
                 let mut ret = Vec::with_capacity(keys.len());
                 ret.resize(keys.len(), Vec::new());
+                let mut found = 0;
 
                 // first do non-blocking reads for all keys to see if we can return immediately
                 let found = keys
                     .iter_mut()
                     .map(|key| {
-                        let rs = reader.try_find_and(key, dup).map(|r| r.0);
+                        let rs = find_and(reader, key, snapshot, |rs| copy_rows(reader, rs, &rows))
+                            .map(|r| r.0);
                         (key, rs)
                     })
                     .enumerate();
@@ -116,9 +542,10 @@ fn handle_message(
                 let mut replaying = false;
                 for (i, (key, v)) in found {
                     match v {
-                        Ok(Some(rs)) => {
+                        Ok(Some((rs, n))) => {
                             // immediate hit!
                             ret[i] = rs;
+                            found += n;
                             *key = vec![];
                         }
                         Err(()) => {
@@ -137,7 +564,7 @@ fn handle_message(
                 if !ready {
                     return Ok(Tagged {
                         tag,
-                        v: ReadReply::Normal(Err(())),
+                        v: rows_reply(Err(()), &rows, 0),
                     });
                 }
 
@@ -145,10 +572,21 @@ fn handle_message(
                     // we hit on all the keys!
                     return Ok(Tagged {
                         tag,
-                        v: ReadReply::Normal(Ok(ret)),
+                        v: rows_reply(Ok(ret), &rows, found),
                     });
                 }
 
+                if block {
+                    // the client will be waiting for the replay, so it counts towards its limit
+                    replay = limiter.start_replay(client);
+                    if replay.is_none() {
+                        return Ok(Tagged {
+                            tag,
+                            v: ReadReply::RateLimited,
+                        });
+                    }
+                }
+
                 // trigger backfills for all the keys we missed on for later
                 for key in &keys {
                     if !key.is_empty() {
@@ -156,16 +594,23 @@ fn handle_message(
                     }
                 }
 
-                Err((keys, ret))
+                Err((keys, ret, found))
             });
 
             match immediate {
                 Ok(reply) => Either::A(Either::A(future::ok(reply))),
-                Err((keys, ret)) => {
-                    if !block {
+                Err((keys, ret, found)) => {
+                    if let Rows::Speculative = rows {
+                        // the key missed, and its replay has been triggered
+                        let rows = ret.into_iter().next().unwrap_or_default();
+                        Either::A(Either::A(future::ok(Tagged {
+                            tag,
+                            v: ReadReply::Speculative(Ok((rows, false))),
+                        })))
+                    } else if !block {
                         Either::A(Either::A(future::ok(Tagged {
                             tag,
-                            v: ReadReply::Normal(Ok(ret)),
+                            v: rows_reply(Ok(ret), &rows, found),
                         })))
                     } else {
                         let trigger = time::Duration::from_micros(TRIGGER_TIMEOUT_US);
@@ -180,6 +625,12 @@ fn handle_message(
                             retry: tokio_os_timer::Interval::new(retry).unwrap(),
                             trigger_timeout: trigger,
                             next_trigger: now,
+                            snapshot,
+                            deadline: None,
+                            block,
+                            rows,
+                            found,
+                            _replay: replay,
                         }))
                     }
                 }
@@ -201,6 +652,66 @@ fn handle_message(
                 v: ReadReply::Size(size),
             }))
         }
+        ReadQuery::Freshness { target } => {
+            let freshness = READERS.with(|readers_cache| {
+                let mut readers_cache = readers_cache.borrow_mut();
+                let reader = readers_cache.entry(target).or_insert_with(|| {
+                    let readers = s.lock().unwrap();
+                    readers.get(&target).unwrap().clone()
+                });
+
+                reader.freshness()
+            });
+
+            Either::B(future::ok(Tagged {
+                tag,
+                v: ReadReply::Freshness(freshness),
+            }))
+        }
+        ReadQuery::Ready { target } => {
+            let ready = READERS.with(|readers_cache| {
+                let mut readers_cache = readers_cache.borrow_mut();
+                if let Some(reader) = readers_cache.get(&target) {
+                    return reader.is_ready();
+                }
+
+                // the reader may not have been set up on this worker yet
+                let reader = s.lock().unwrap().get(&target).cloned();
+                match reader {
+                    Some(reader) => readers_cache.entry(target).or_insert(reader).is_ready(),
+                    None => false,
+                }
+            });
+
+            Either::B(future::ok(Tagged {
+                tag,
+                v: ReadReply::Ready(ready),
+            }))
+        }
+        ReadQuery::Export { target, id } => {
+            let rows = READERS.with(|readers_cache| {
+                let mut readers_cache = readers_cache.borrow_mut();
+                let reader = readers_cache.entry(target).or_insert_with(|| {
+                    let readers = s.lock().unwrap();
+                    readers.get(&target).unwrap().clone()
+                });
+
+                reader.take_export(id)
+            });
+
+            Either::B(future::ok(Tagged {
+                tag,
+                v: ReadReply::Export(rows),
+            }))
+        }
+        ReadQuery::Chunk { .. }
+        | ReadQuery::Page { .. }
+        | ReadQuery::Count { .. }
+        | ReadQuery::Filtered { .. }
+        | ReadQuery::Speculative { .. } => {
+            unreachable!("special reads are turned into normal reads")
+        }
+        ReadQuery::Hinted { .. } => unreachable!("hinted reads are unwrapped before they are read"),
     }
 }
 
@@ -213,6 +724,17 @@ struct BlockingRead {
     retry: tokio_os_timer::Interval,
     trigger_timeout: time::Duration,
     next_trigger: time::Instant,
+    /// The snapshot that keys are read at, if any.
+    snapshot: Option<SnapshotToken>,
+    /// When to give up on the reader reflecting `snapshot`, if it didn't already.
+    deadline: Option<time::Instant>,
+    /// Whether to wait for keys that miss to be filled in.
+    block: bool,
+    /// Which of the rows that are found to reply with.
+    rows: Rows,
+    /// How many rows have been found so far.
+    found: usize,
+    _replay: Option<ReplayGuard>,
 }
 
 impl Future for BlockingRead {
@@ -225,6 +747,20 @@ impl Future for BlockingRead {
             }))
             .expect("interval stopped yielding");
 
+            if let (Some(snapshot), Some(deadline)) = (self.snapshot, self.deadline) {
+                if !reflects(&self.truth, self.target, snapshot) {
+                    if time::Instant::now() < deadline {
+                        continue;
+                    }
+                    // the view didn't catch up with the snapshot in time
+                    return Ok(Async::Ready(Tagged {
+                        tag: self.tag,
+                        v: rows_reply(Err(()), &self.rows, 0),
+                    }));
+                }
+                self.deadline = None;
+            }
+
             let missing = READERS.with(|readers_cache| {
                 let mut readers_cache = readers_cache.borrow_mut();
                 let s = &self.truth;
@@ -244,13 +780,18 @@ impl Future for BlockingRead {
                         // note that this *does* mean we'll trigger replay multiple times for things
                         // that miss and aren't replayed in time, which is a little sad. but at the
                         // same time, that replay trigger will just be ignored by the target domain.
-                        match reader.try_find_and(key, dup).map(|r| r.0) {
-                            Ok(Some(rs)) => {
+                        let rows = &self.rows;
+                        match find_and(reader, key, self.snapshot, |rs| copy_rows(reader, rs, rows))
+                            .map(|r| r.0)
+                        {
+                            Ok(Some((rs, n))) => {
                                 self.read[i] = rs;
+                                self.found += n;
                                 key.clear();
                             }
                             Err(()) => {
-                                unreachable!("map became not ready?");
+                                // the reader no longer keeps the rows of the snapshot
+                                return Ok(None);
                             }
                             Ok(None) => {
                                 if now > self.next_trigger {
@@ -272,14 +813,25 @@ impl Future for BlockingRead {
                     self.next_trigger = now + self.trigger_timeout;
                 }
 
-                Ok(missing)
+                // a non-blocking read leaves any misses empty once it has triggered their replay
+                Ok(Some(missing && self.block))
             })?;
 
-            if !missing {
-                return Ok(Async::Ready(Tagged {
-                    tag: self.tag,
-                    v: ReadReply::Normal(Ok(mem::replace(&mut self.read, Vec::new()))),
-                }));
+            match missing {
+                Some(true) => {}
+                Some(false) => {
+                    let read = mem::replace(&mut self.read, Vec::new());
+                    return Ok(Async::Ready(Tagged {
+                        tag: self.tag,
+                        v: rows_reply(Ok(read), &self.rows, self.found),
+                    }));
+                }
+                None => {
+                    return Ok(Async::Ready(Tagged {
+                        tag: self.tag,
+                        v: rows_reply(Err(()), &self.rows, 0),
+                    }));
+                }
             }
         }
     }
@@ -1,27 +1,41 @@
 /// Only allow processing this many inputs in a domain before we handle timer events, acks, etc.
 const FORCE_INPUT_YIELD_EVERY: usize = 64;
 
+/// Never coalesce more than this many queued updates into a single packet to a downstream domain.
+const MAX_OUTPUT_BATCH: usize = 256;
+
+use super::limits::Limiter;
+use super::spill::Spill;
 use super::ChannelCoordinator;
 use crate::coordination::CoordinationPayload;
+use crate::faults::{Delivery, Faults};
+use crate::secure;
+use crate::sim::{Delay, Scheduler};
+use crate::tls::Certificates;
 use async_bincode::AsyncDestination;
 use bincode;
 use bufstream::BufStream;
 use dataflow::{
     payload::SourceChannelIdentifier,
     prelude::{DataType, Executor},
-    Domain, Packet, PollEvent, ProcessResult,
+    Clock, Domain, Overflow, Packet, PollEvent, ProcessResult,
 };
 use failure::{self, ResultExt};
 use fnv::{FnvHashMap, FnvHashSet};
 use futures::stream::futures_unordered::FuturesUnordered;
 use futures::{self, Future, Sink, Stream};
-use noria::channel::{DualTcpStream, CONNECTION_FROM_BASE};
+use noria::channel::auth;
+use noria::channel::{Connection, DualTcpStream, CONNECTION_FROM_BASE};
+use noria::error::ConstraintViolation;
 use noria::internal::DomainIndex;
 use noria::internal::LocalOrNot;
-use noria::{Input, Tagged};
+use noria::{Input, Tagged, WriteReply};
 use slog;
+use std::cmp;
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, VecDeque};
 use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use stream_cancel::{Valve, Valved};
 use streamunordered::{StreamUnordered, StreamYield};
@@ -35,30 +49,59 @@ pub(super) struct Replica {
     log: slog::Logger,
 
     coord: Arc<ChannelCoordinator>,
+    /// The certificate that connections to us are encrypted with, if they are.
+    certs: Option<Arc<Certificates>>,
 
     retry: Option<Box<Packet>>,
-    incoming: Valved<tokio::net::tcp::Incoming>,
+    /// Where connections from other workers and clients arrive, unless we are simulated.
+    incoming: Option<Valved<tokio::net::tcp::Incoming>>,
     first_byte: FuturesUnordered<tokio::io::ReadExact<tokio::net::tcp::TcpStream, Vec<u8>>>,
+    /// Connections that have yet to be encrypted, or to prove they know the domain secret, and
+    /// whether they are from a base table.
+    handshakes: FuturesUnordered<
+        Box<
+            dyn Future<Item = Option<(Connection<tokio::net::TcpStream>, bool)>, Error = ()> + Send,
+        >,
+    >,
     locals: tokio_sync::mpsc::UnboundedReceiver<Box<Packet>>,
     inputs: StreamUnordered<
         DualTcpStream<
-            BufStream<tokio::net::TcpStream>,
+            BufStream<Connection<tokio::net::TcpStream>>,
             Box<Packet>,
             Tagged<LocalOrNot<Input>>,
             AsyncDestination,
         >,
     >,
+    /// For each downstream domain: the channel to it, whether it has unflushed packets, and how
+    /// many queued updates we currently allow to be coalesced into a single packet.
     outputs: FnvHashMap<
         ReplicaIndex,
         (
             Box<dyn Sink<SinkItem = Box<Packet>, SinkError = bincode::Error> + Send>,
             bool,
+            usize,
         ),
     >,
 
     outbox: FnvHashMap<ReplicaIndex, VecDeque<Box<Packet>>>,
-    timeout: Option<tokio_os_timer::Delay>,
+    /// Packets to send to downstream domains ahead of the outbox, without injecting faults.
+    bypass: FnvHashMap<ReplicaIndex, VecDeque<Box<Packet>>>,
+    /// Packets that are held back by an injected fault, and where they should go after.
+    delayed: Vec<(tokio_os_timer::Delay, ReplicaIndex, Box<Packet>)>,
+    /// Packets for downstream domains that did not fit in the outbox, and how many of the packets
+    /// in the outbox were queued before them.
+    spilled: FnvHashMap<ReplicaIndex, (Spill, usize)>,
+    /// The number of client writes refused because the outbox was full.
+    shed: u64,
+    faults: Arc<Faults>,
+    timeout: Option<Timeout>,
+    /// The scheduler that runs us and drives our timers, if we are simulated.
+    simulation: Option<Scheduler>,
     oob: OutOfBand,
+
+    /// The client behind each input stream from a base table, and the limits on its writes.
+    clients: FnvHashMap<usize, IpAddr>,
+    limiter: Limiter,
 }
 
 impl Replica {
@@ -70,27 +113,78 @@ impl Replica {
         ctrl_tx: futures::sync::mpsc::UnboundedSender<CoordinationPayload>,
         log: slog::Logger,
         cc: Arc<ChannelCoordinator>,
+        certs: Option<Arc<Certificates>>,
+        faults: Arc<Faults>,
+        limiter: Limiter,
+    ) -> Self {
+        domain.booted(on.local_addr().unwrap());
+        let mut replica = Replica::with(domain, locals, ctrl_tx, log, cc, faults, limiter);
+        replica.certs = certs;
+        replica.incoming = Some(valve.wrap(on.incoming()));
+        replica
+    }
+
+    /// A replica that `scheduler` runs, which appears to be at `addr`, and which only takes input
+    /// from `locals`.
+    pub(super) fn simulated(
+        mut domain: Domain,
+        addr: SocketAddr,
+        locals: tokio_sync::mpsc::UnboundedReceiver<Box<Packet>>,
+        ctrl_tx: futures::sync::mpsc::UnboundedSender<CoordinationPayload>,
+        log: slog::Logger,
+        cc: Arc<ChannelCoordinator>,
+        faults: Arc<Faults>,
+        limiter: Limiter,
+        scheduler: Scheduler,
+    ) -> Self {
+        domain.booted(addr);
+        let mut replica = Replica::with(domain, locals, ctrl_tx, log, cc, faults, limiter);
+        replica.simulation = Some(scheduler);
+        replica
+    }
+
+    fn with(
+        domain: Domain,
+        locals: tokio_sync::mpsc::UnboundedReceiver<Box<Packet>>,
+        ctrl_tx: futures::sync::mpsc::UnboundedSender<CoordinationPayload>,
+        log: slog::Logger,
+        cc: Arc<ChannelCoordinator>,
+        faults: Arc<Faults>,
+        limiter: Limiter,
     ) -> Self {
         let id = domain.id();
         let id = format!("{}.{}", id.0.index(), id.1);
-        domain.booted(on.local_addr().unwrap());
         Replica {
             coord: cc,
+            certs: None,
             domain,
             retry: None,
-            incoming: valve.wrap(on.incoming()),
+            incoming: None,
             first_byte: FuturesUnordered::new(),
+            handshakes: FuturesUnordered::new(),
             locals,
             log: log.new(o! {"id" => id}),
             inputs: Default::default(),
             outputs: Default::default(),
             outbox: Default::default(),
+            bypass: Default::default(),
+            delayed: Vec::new(),
+            spilled: Default::default(),
+            shed: 0,
+            faults,
             oob: OutOfBand::new(ctrl_tx),
             timeout: None,
+            simulation: None,
+            clients: Default::default(),
+            limiter,
         }
     }
 
     fn try_oob(&mut self) -> Result<(), failure::Error> {
+        if self.faults.acks_stalled(self.domain.id().0) {
+            return Ok(());
+        }
+
         let inputs = &mut self.inputs;
         let pending = &mut self.oob.pending;
 
@@ -100,8 +194,8 @@ impl Replica {
             let stream = &mut inputs[streami];
 
             let had = tags.len();
-            tags.retain(|&tag| {
-                match stream.start_send(Tagged { tag, v: () }) {
+            tags.retain(|&(tag, ref v)| {
+                match stream.start_send(Tagged { tag, v: v.clone() }) {
                     Ok(AsyncSink::Ready) => false,
                     Ok(AsyncSink::NotReady(_)) => {
                         // TODO: also break?
@@ -165,30 +259,81 @@ impl Replica {
     fn try_flush(&mut self) -> Result<(), failure::Error> {
         let cc = &self.coord;
         let outputs = &mut self.outputs;
+        let from = self.domain.id().0;
+
+        // packets that do not fit in the outbox wait on disk, behind any that already do
+        if let Some((max, Overflow::Spill)) = self.domain.queue_limit() {
+            for (&ri, ms) in &mut self.outbox {
+                let keep = match self.spilled.get(&ri) {
+                    Some(&(ref spill, before)) if spill.len() != 0 => before,
+                    _ => max,
+                };
+                if ms.len() <= keep {
+                    continue;
+                }
+                let &mut (ref mut spill, _) = match self.spilled.entry(ri) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => e.insert((Spill::new()?, 0)),
+                };
+                for m in ms.drain(keep..) {
+                    spill.push(&m)?;
+                }
+            }
+        }
+
+        // release any packets whose injected delay has passed
+        let mut i = 0;
+        while i < self.delayed.len() {
+            if self.delayed[i].0.poll()?.is_ready() {
+                let (_, ri, m) = self.delayed.swap_remove(i);
+                self.bypass.entry(ri).or_default().push_back(m);
+                self.outbox.entry(ri).or_default();
+            } else {
+                i += 1;
+            }
+        }
 
         // just like in try_oob:
         // first, queue up any additional writes we have to do
         let mut err = Vec::new();
         for (&ri, ms) in &mut self.outbox {
-            if ms.is_empty() {
+            let bypass = self.bypass.entry(ri).or_default();
+            if ms.is_empty() && bypass.is_empty() {
                 continue;
             }
 
-            let &mut (ref mut tx, ref mut pending) = outputs.entry(ri).or_insert_with(|| {
-                while !cc.has(&ri) {}
-                let tx = cc.builder_for(&ri).unwrap().build_async().unwrap();
-                (tx, true)
-            });
+            let &mut (ref mut tx, ref mut pending, ref mut batch) =
+                outputs.entry(ri).or_insert_with(|| {
+                    while !cc.has(&ri) {}
+                    let tx = cc.builder_for(&ri).unwrap().build_async().unwrap();
+                    (tx, true, 1)
+                });
+
+            loop {
+                let m = if let Some(m) = bypass.pop_front() {
+                    m
+                } else if let Some(mut m) = ms.pop_front() {
+                    coalesce(&mut m, ms, *batch);
+                    match inject(&self.faults, from, ri, m, bypass, &mut self.delayed)? {
+                        Some(m) => m,
+                        None => continue,
+                    }
+                } else {
+                    break;
+                };
 
-            while let Some(m) = ms.pop_front() {
                 match tx.start_send(m) {
                     Ok(AsyncSink::Ready) => {
                         // we queued something, so we'll need to send!
                         *pending = true;
                     }
                     Ok(AsyncSink::NotReady(m)) => {
-                        // put back the m we tried to send
-                        ms.push_front(m);
+                        // put back the m we tried to send. it has already been through fault
+                        // injection, so it shouldn't go through it again.
+                        bypass.push_front(m);
+                        // the downstream link can't keep up, so let more updates pile up in
+                        // each packet we send it.
+                        *batch = cmp::min(*batch * 2, MAX_OUTPUT_BATCH);
                         // there's also no use in trying to enqueue more packets
                         break;
                     }
@@ -205,7 +350,8 @@ impl Replica {
         }
 
         // then, try to do any sends that are still pending
-        for &mut (ref mut tx, ref mut pending) in outputs.values_mut() {
+        let outbox = &self.outbox;
+        for (ri, &mut (ref mut tx, ref mut pending, ref mut batch)) in outputs.iter_mut() {
             if !*pending {
                 continue;
             }
@@ -213,8 +359,15 @@ impl Replica {
             match tx.poll_complete() {
                 Ok(Async::Ready(())) => {
                     *pending = false;
+                    if outbox.get(ri).map(VecDeque::is_empty).unwrap_or(true) {
+                        // the link is keeping up with us, so go back towards sending updates
+                        // as soon as they are produced.
+                        *batch = cmp::max(*batch / 2, 1);
+                    }
+                }
+                Ok(Async::NotReady) => {
+                    *batch = cmp::min(*batch * 2, MAX_OUTPUT_BATCH);
                 }
-                Ok(Async::NotReady) => {}
                 Err(e) => err.push(e),
             }
         }
@@ -223,21 +376,47 @@ impl Replica {
             return Err(err.swap_remove(0).into());
         }
 
+        // move spilled packets back into the outbox as it drains
+        let mut refilled = false;
+        let max = self.domain.queue_limit().map(|(max, _)| max).unwrap_or(0);
+        for (ri, &mut (ref mut spill, ref mut before)) in &mut self.spilled {
+            let ms = self.outbox.entry(*ri).or_default();
+            while spill.len() != 0 && ms.len() < max {
+                ms.push_back(spill.pop()?.unwrap());
+                refilled = true;
+            }
+            *before = ms.len();
+        }
+        if refilled {
+            // make sure the refilled packets are sent even if no more input arrives
+            task::current().notify();
+        }
+
         Ok(())
     }
 
+    /// The number of packets waiting to be sent to downstream domains.
+    fn queued(&self) -> usize {
+        self.outbox.values().map(VecDeque::len).sum::<usize>()
+            + self.bypass.values().map(VecDeque::len).sum::<usize>()
+            + self.spilled.values().map(|s| s.0.len()).sum::<usize>()
+            + self.delayed.len()
+    }
+
     fn try_new(&mut self) -> io::Result<bool> {
-        while let Async::Ready(stream) = self.incoming.poll()? {
-            match stream {
-                Some(stream) => {
-                    // we know that any new connection to a domain will first send a one-byte
-                    // token to indicate whether the connection is from a base or not.
-                    debug!(self.log, "accepted new connection"; "from" => ?stream.peer_addr().unwrap());
-                    self.first_byte
-                        .push(tokio::io::read_exact(stream, vec![0; 1]));
-                }
-                None => {
-                    return Ok(false);
+        if let Some(ref mut incoming) = self.incoming {
+            while let Async::Ready(stream) = incoming.poll()? {
+                match stream {
+                    Some(stream) => {
+                        // we know that any new connection to a domain will first send a one-byte
+                        // token to indicate whether the connection is from a base or not.
+                        debug!(self.log, "accepted new connection"; "from" => ?stream.peer_addr().unwrap());
+                        self.first_byte
+                            .push(tokio::io::read_exact(stream, vec![0; 1]));
+                    }
+                    None => {
+                        return Ok(false);
+                    }
                 }
             }
         }
@@ -246,48 +425,195 @@ impl Replica {
             let is_base = tag[0] == CONNECTION_FROM_BASE;
 
             debug!(self.log, "established new connection"; "base" => ?is_base);
-            let slot = self.inputs.stream_slot();
-            let token = slot.token();
             if let Err(e) = stream.set_nodelay(true) {
                 warn!(self.log,
                       "failed to set TCP_NODELAY for new connection: {:?}", e;
                       "from" => ?stream.peer_addr().unwrap());
             }
-            let tcp = if is_base {
-                DualTcpStream::upgrade(BufStream::new(stream), move |Tagged { v: input, tag }| {
-                    Box::new(Packet::Input {
-                        inner: input,
-                        src: Some(SourceChannelIdentifier { token, tag }),
-                        senders: Vec::new(),
-                    })
-                })
-            } else {
-                BufStream::with_capacities(2 * 1024 * 1024, 4 * 1024, stream).into()
+
+            // clients writing to a base table authenticate with a key derived from the secret,
+            // so that they cannot also pose as a domain.
+            let key = match self.coord.secret() {
+                Some(secret) if is_base => Some(auth::derive(secret, auth::WRITE)),
+                Some(secret) => Some(secret.to_vec()),
+                None => None,
             };
-            slot.insert(tcp);
+            let handshake = secure(
+                self.certs.as_ref().map(|c| &**c),
+                key.as_ref().map(Vec::as_slice),
+                stream,
+                &self.log,
+            );
+            self.handshakes.push(Box::new(
+                handshake.map(move |stream| stream.map(|stream| (stream, is_base))),
+            ));
+        }
+
+        while let Ok(Async::Ready(Some(stream))) = self.handshakes.poll() {
+            if let Some((stream, is_base)) = stream {
+                self.accept(stream, is_base);
+            }
         }
         Ok(true)
     }
 
-    fn try_timeout(&mut self) -> Poll<(), io::Error> {
-        if let Some(mut to) = self.timeout.take() {
-            if let Async::Ready(()) = to.poll()? {
-                crate::block_on(|| {
-                    self.domain
-                        .on_event(&mut self.oob, PollEvent::Timeout, &mut self.outbox)
-                });
-                return Ok(Async::Ready(()));
-            } else {
-                self.timeout = Some(to);
+    /// Start reading input from a connection that has been established (and authenticated).
+    fn accept(&mut self, stream: Connection<tokio::net::TcpStream>, is_base: bool) {
+        let slot = self.inputs.stream_slot();
+        let token = slot.token();
+        let tcp = if is_base {
+            if let Ok(peer) = stream.get_ref().get_ref().peer_addr() {
+                self.clients.insert(token, peer.ip());
             }
+            DualTcpStream::upgrade(BufStream::new(stream), move |Tagged { v: input, tag }| {
+                Box::new(Packet::Input {
+                    inner: input,
+                    src: Some(SourceChannelIdentifier { token, tag }),
+                    senders: Vec::new(),
+                })
+            })
+        } else {
+            BufStream::with_capacities(2 * 1024 * 1024, 4 * 1024, stream).into()
+        };
+        slot.insert(tcp);
+    }
+
+    fn try_timeout(&mut self) -> Poll<(), io::Error> {
+        let expired = match self.timeout {
+            Some(Timeout::Clock(ref mut to)) => to.poll()?.is_ready(),
+            Some(Timeout::Simulated(ref mut to)) => to.poll()?.is_ready(),
+            None => false,
+        };
+        if expired {
+            self.timeout = None;
+            crate::block_on(|| {
+                self.domain
+                    .on_event(&mut self.oob, PollEvent::Timeout, &mut self.outbox)
+            });
+            return Ok(Async::Ready(()));
         }
         Ok(Async::NotReady)
     }
 }
 
+/// A point at which the domain wants to be woken up even if no input has arrived.
+enum Timeout {
+    Clock(tokio_os_timer::Delay),
+    /// A timer on the clock of the simulation that runs us.
+    Simulated(Delay),
+}
+
+/// Merge regular updates at the head of `queue` into `m` for as long as they are headed for the
+/// same node, and we haven't yet merged more than `limit` updates.
+///
+/// Only consecutive updates are merged, so the order in which the downstream domain observes
+/// updates is not affected. Updates that started out at different base tables are not merged, so
+/// that readers can tell how fresh they are with respect to each of them.
+fn coalesce(m: &mut Box<Packet>, queue: &mut VecDeque<Box<Packet>>, limit: usize) {
+    let mut merged = 1;
+    while merged < limit {
+        let mergeable = match (&**m, queue.front().map(|p| &**p)) {
+            (
+                &Packet::Message {
+                    link,
+                    tracer: None,
+                    origin,
+                    ..
+                },
+                Some(&Packet::Message {
+                    link: next,
+                    tracer: None,
+                    origin: next_origin,
+                    ..
+                }),
+            ) => link == next && origin.map(|o| o.0) == next_origin.map(|o| o.0),
+            _ => false,
+        };
+        if !mergeable {
+            break;
+        }
+
+        let mut next = queue.pop_front().unwrap();
+        if let (
+            &mut Packet::Message {
+                ref mut data,
+                ref mut origin,
+                ..
+            },
+            &mut Packet::Message {
+                data: ref mut more,
+                origin: later,
+                ..
+            },
+        ) = (&mut **m, &mut *next)
+        {
+            data.append(more);
+            *origin = later;
+        }
+        merged += 1;
+    }
+}
+
+/// Subject `m`, which is headed for `to`, to any faults injected on the link from `from`.
+///
+/// Returns the packet to send now, if any. Copies and delayed packets are queued up on the side.
+fn inject(
+    faults: &Faults,
+    from: DomainIndex,
+    to: ReplicaIndex,
+    m: Box<Packet>,
+    bypass: &mut VecDeque<Box<Packet>>,
+    delayed: &mut Vec<(tokio_os_timer::Delay, ReplicaIndex, Box<Packet>)>,
+) -> io::Result<Option<Box<Packet>>> {
+    match *m {
+        Packet::Message { .. } | Packet::ReplayPiece { .. } => {}
+        _ => return Ok(Some(m)),
+    }
+
+    Ok(match faults.on_send(from, to.0) {
+        Delivery::Deliver => Some(m),
+        Delivery::Drop => None,
+        Delivery::Duplicate => {
+            bypass.push_back(Box::new(m.clone_data()));
+            Some(m)
+        }
+        Delivery::Delay(by) => {
+            delayed.push((tokio_os_timer::Delay::new(by)?, to, m));
+            None
+        }
+    })
+}
+
+/// If `packet` is a write from a client that has exceeded its rate limit, or that arrived while
+/// the domain is `shedding` writes, where to refuse it and why.
+fn refuse(
+    limiter: &Limiter,
+    clients: &FnvHashMap<usize, IpAddr>,
+    streami: usize,
+    packet: &Packet,
+    shedding: bool,
+) -> Option<(SourceChannelIdentifier, WriteReply)> {
+    match *packet {
+        Packet::Input { src: Some(src), .. } => match clients.get(&streami) {
+            Some(_) if shedding => Some((src, WriteReply::Overloaded)),
+            Some(&client) if !limiter.admit_write(client) => Some((src, WriteReply::RateLimited)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether more packets wait in `outbox` for some downstream domain than `limit` allows.
+fn over(
+    outbox: &FnvHashMap<ReplicaIndex, VecDeque<Box<Packet>>>,
+    limit: Option<(usize, Overflow)>,
+) -> bool {
+    limit.map_or(false, |(max, _)| outbox.values().any(|ms| ms.len() > max))
+}
+
 struct OutOfBand {
-    // map from inputi to number of (empty) ACKs
-    back: FnvHashMap<usize, Vec<u32>>,
+    // map from inputi to the tags of, and replies to, the writes we are yet to respond to
+    back: FnvHashMap<usize, Vec<(u32, WriteReply)>>,
     pending: FnvHashSet<usize>,
 
     // for sending messages to the controller
@@ -302,11 +628,29 @@ impl OutOfBand {
             ctrl_tx,
         }
     }
+
+    fn refuse(&mut self, id: SourceChannelIdentifier, reply: WriteReply) {
+        self.back.entry(id.token).or_default().push((id.tag, reply));
+    }
 }
 
 impl Executor for OutOfBand {
     fn ack(&mut self, id: SourceChannelIdentifier) {
-        self.back.entry(id.token).or_default().push(id.tag);
+        self.back
+            .entry(id.token)
+            .or_default()
+            .push((id.tag, WriteReply::Ok));
+    }
+
+    fn reject(
+        &mut self,
+        id: SourceChannelIdentifier,
+        violations: Vec<(usize, ConstraintViolation)>,
+    ) {
+        self.back
+            .entry(id.token)
+            .or_default()
+            .push((id.tag, WriteReply::ConstraintViolation(violations)));
     }
 
     fn create_universe(&mut self, universe: HashMap<String, DataType>) {
@@ -355,15 +699,31 @@ impl Future for Replica {
                 let mut remote_done = false;
                 let mut check_local = true;
                 let readiness = 'ready: loop {
+                    let id = self.domain.id().0;
+                    let limit = self.domain.queue_limit();
+                    let policy = limit.map(|(_, overflow)| overflow);
                     let d = &mut self.domain;
                     let oob = &mut self.oob;
                     let ob = &mut self.outbox;
+                    let faults = &self.faults;
+                    let log = &self.log;
+                    let simulated = self.simulation.is_some();
 
                     macro_rules! process {
                         ($retry:expr, $p:expr, $pp:expr) => {{
+                            if faults.crash(id) {
+                                crit!(log, "crashing domain due to injected fault");
+                                return Err(());
+                            }
                             $retry = Some($p);
                             let retry = &mut $retry;
-                            match tokio_threadpool::blocking(|| $pp(retry.take().unwrap())) {
+                            let processed = if simulated {
+                                // a simulation runs its tasks on the calling thread, not on a pool
+                                Ok(Async::Ready($pp(retry.take().unwrap())))
+                            } else {
+                                tokio_threadpool::blocking(|| $pp(retry.take().unwrap()))
+                            };
+                            match processed {
                                 Ok(Async::Ready(ProcessResult::StopPolling)) => {
                                     // domain got a message to quit
                                     // TODO: should we finish up remaining work?
@@ -391,7 +751,15 @@ impl Future for Replica {
                         ));
                     }
 
+                    let mut blocked = false;
                     for i in 0..FORCE_INPUT_YIELD_EVERY {
+                        let full = over(ob, limit);
+                        if full && policy == Some(Overflow::Block) {
+                            // leave input where it is until downstream domains catch up
+                            blocked = true;
+                            break;
+                        }
+
                         if !local_done && (check_local || remote_done) {
                             match self.locals.poll() {
                                 Ok(Async::Ready(Some(packet))) => process!(
@@ -417,17 +785,40 @@ impl Future for Replica {
 
                         if !remote_done && (!check_local || local_done) {
                             match self.inputs.poll() {
-                                Ok(Async::Ready(Some((StreamYield::Item(packet), _)))) => process!(
-                                    self.retry,
-                                    packet,
-                                    |p| d.on_event(oob, PollEvent::Process(p), ob)
-                                ),
+                                Ok(Async::Ready(Some((StreamYield::Item(packet), streami)))) => {
+                                    let shedding = full && policy == Some(Overflow::Shed);
+                                    match refuse(
+                                        &self.limiter,
+                                        &self.clients,
+                                        streami,
+                                        &packet,
+                                        shedding,
+                                    ) {
+                                        Some((src, reply)) => {
+                                            // local writes are passed by pointer, so we have to
+                                            // free the refused write ourselves
+                                            if let Packet::Input { inner, .. } = *packet {
+                                                drop(unsafe { inner.take() });
+                                            }
+                                            if reply == WriteReply::Overloaded {
+                                                self.shed += 1;
+                                            }
+                                            oob.refuse(src, reply)
+                                        }
+                                        None => process!(self.retry, packet, |p| d.on_event(
+                                            oob,
+                                            PollEvent::Process(p),
+                                            ob
+                                        )),
+                                    }
+                                }
                                 Ok(Async::Ready(Some((
                                     StreamYield::Finished(_stream),
                                     streami,
                                 )))) => {
                                     oob.back.remove(&streami);
                                     oob.pending.remove(&streami);
+                                    self.clients.remove(&streami);
                                     // FIXME: what about if a later flush flushes to this stream?
                                 }
                                 Ok(Async::Ready(None)) => {
@@ -466,6 +857,13 @@ impl Future for Replica {
                     // send acks
                     self.try_oob()?;
 
+                    let queued = self.queued() as u64;
+                    self.domain.note_queue(queued, self.shed);
+
+                    if blocked && !over(&self.outbox, limit) {
+                        // the outbox drained enough to take more input
+                        continue;
+                    }
                     if interrupted {
                         // resume reading from our non-depleted inputs
                         continue;
@@ -483,7 +881,15 @@ impl Future for Replica {
                         if let Some(timeout) = timeout {
                             // tokio-timer has a resolution of 1ms, so we can't use it :'(
                             // TODO: how about we don't create a new timer each time?
-                            self.timeout = Some(tokio_os_timer::Delay::new(timeout).unwrap());
+                            self.timeout = Some(match self.simulation {
+                                Some(ref scheduler) => {
+                                    let at = scheduler.clock().now() + timeout;
+                                    Timeout::Simulated(scheduler.delay(at))
+                                }
+                                None => {
+                                    Timeout::Clock(tokio_os_timer::Delay::new(timeout).unwrap())
+                                }
+                            });
 
                             // we need to poll the timer to ensure we'll get woken up
                             if let Async::Ready(()) =
@@ -514,3 +920,43 @@ impl Future for Replica {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dataflow::prelude::{Link, LocalNodeIndex};
+
+    fn message(dst: u32, v: i32) -> Box<Packet> {
+        Box::new(Packet::Message {
+            link: Link::new(unsafe { LocalNodeIndex::make(0) }, unsafe {
+                LocalNodeIndex::make(dst)
+            }),
+            data: vec![vec![DataType::from(v)]].into(),
+            tracer: None,
+            origin: None,
+        })
+    }
+
+    #[test]
+    fn coalesce_same_link() {
+        let mut queue: VecDeque<_> =
+            vec![message(1, 2), message(1, 3), message(2, 4), message(1, 5)]
+                .into_iter()
+                .collect();
+
+        let mut m = message(1, 1);
+        coalesce(&mut m, &mut queue, 1);
+        assert_eq!(queue.len(), 4);
+
+        // must not merge across the update for a different node
+        coalesce(&mut m, &mut queue, MAX_OUTPUT_BATCH);
+        assert_eq!(queue.len(), 2);
+        match *m {
+            Packet::Message { ref data, .. } => {
+                let data: Vec<_> = data.iter().map(|r| r[0].clone()).collect();
+                assert_eq!(data, vec![1.into(), 2.into(), 3.into()]);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! A worker of a simulated deployment.
+
+use super::limits::Limiter;
+use super::replica::Replica;
+use crate::coordination::{Capacity, CoordinationMessage, CoordinationPayload};
+use crate::faults::Faults;
+use crate::sim::{self, Network, Node, Scheduler};
+use crate::Config;
+use dataflow::{DomainBuilder, Readers, StateSize};
+use futures::sync::mpsc::UnboundedSender;
+use noria::consensus::Epoch;
+use slog;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use stream_cancel::Valve;
+use tokio::prelude::*;
+
+/// What a simulated worker needs to boot domains.
+struct Worker {
+    i: usize,
+    scheduler: Scheduler,
+    network: Network,
+    readers: Readers,
+    valve: Valve,
+    limiter: Limiter,
+    faults: Arc<Faults>,
+    ctrl_tx: UnboundedSender<CoordinationPayload>,
+    log: slog::Logger,
+}
+
+impl Worker {
+    fn boot(&self, d: DomainBuilder) {
+        let key = (d.index, d.shard.unwrap_or(0));
+        let (tx, rx) = tokio_sync::mpsc::unbounded_channel();
+        let addr = self.network.listen_domain(self.i, key, tx);
+
+        let d = d.build(
+            self.log.clone(),
+            self.readers.clone(),
+            self.network.coordinator(),
+            Box::new(self.network.connect_replies(key)),
+            &self.valve,
+            Arc::new(StateSize::default()),
+            Arc::new(self.scheduler.clock().clone()),
+            None,
+        );
+        let replica = Replica::simulated(
+            d,
+            addr,
+            rx,
+            self.ctrl_tx.clone(),
+            self.log.clone(),
+            self.network.coordinator(),
+            self.faults.clone(),
+            self.limiter.clone(),
+            self.scheduler.clone(),
+        );
+
+        let label = format!("d{}.{}", key.0.index(), key.1);
+        self.scheduler.spawn(Node::Worker(self.i), &label, replica);
+    }
+}
+
+/// Start worker `i` of a simulated deployment on `scheduler`, and register it with the controller
+/// that leads in `epoch`.
+crate fn start(
+    scheduler: &Scheduler,
+    network: &Network,
+    (i, reader_only): (usize, bool),
+    config: &Config,
+    epoch: Epoch,
+    log: slog::Logger,
+) {
+    let node = Node::Worker(i);
+    let (inbox_tx, inbox) = tokio_sync::mpsc::unbounded_channel();
+    let readers = Arc::new(Mutex::new(HashMap::new()));
+    network.listen_worker(i, inbox_tx, readers.clone());
+
+    // everything the worker tells the controller goes over one link, as it would over one
+    // connection
+    let (ctrl_tx, ctrl_rx) = futures::sync::mpsc::unbounded();
+    let mut controller = network.connect_controller(i);
+    let source = sim::source_addr(i);
+    let forward = ctrl_rx.for_each(move |payload| {
+        controller
+            .try_send(CoordinationMessage {
+                source,
+                payload,
+                epoch,
+            })
+            .map_err(|_| ())
+    });
+    scheduler.spawn(node, "ctrl", forward);
+
+    ctrl_tx
+        .unbounded_send(CoordinationPayload::Register {
+            addr: sim::worker_addr(i),
+            read_listen_addr: sim::read_addr(i),
+            log_files: vec![],
+            labels: HashMap::new(),
+            capacity: Capacity::default(),
+            reader_only,
+        })
+        .unwrap();
+    let heartbeats = ctrl_tx.clone();
+    let heartbeat = scheduler
+        .interval(config.heartbeat_every)
+        .map_err(|_| ())
+        .for_each(move |_| {
+            heartbeats
+                .unbounded_send(CoordinationPayload::Heartbeat)
+                .map_err(|_| ())
+        });
+    scheduler.spawn(node, "heartbeat", heartbeat);
+
+    // a worker that fails takes its domains and its readers with it
+    let (trigger, valve) = Valve::new();
+    let gone = network.clone();
+    scheduler.on_crash(node, move || {
+        trigger.cancel();
+        gone.remove_worker(i);
+    });
+
+    let worker = Worker {
+        i,
+        scheduler: scheduler.clone(),
+        network: network.clone(),
+        readers,
+        valve,
+        limiter: Limiter::new(config.rate_limits),
+        faults: Arc::new(Faults::default()),
+        ctrl_tx,
+        log,
+    };
+    let main = inbox.map_err(|_| ()).for_each(move |msg| {
+        match msg.payload {
+            CoordinationPayload::AssignDomain(d) => worker.boot(d),
+            CoordinationPayload::DomainBooted(..) => {
+                // every node uses the network's channels to domains, which know of all of them
+            }
+            CoordinationPayload::UpdateConfig { .. } => {
+                // simulated workers do not evict
+            }
+            #[cfg(feature = "fault-injection")]
+            CoordinationPayload::InjectFault(fault) => worker.faults.inject(fault),
+            #[cfg(feature = "fault-injection")]
+            CoordinationPayload::ClearFaults => worker.faults.clear(),
+            CoordinationPayload::RemoveDomain => {
+                unimplemented!();
+            }
+            p => unreachable!("{:?} is not a worker message", p),
+        }
+        Ok(())
+    });
+    scheduler.spawn(node, "main", main);
+}
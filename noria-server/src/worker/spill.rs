@@ -0,0 +1,104 @@
+use bincode;
+use dataflow::Packet;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use tempfile;
+
+/// Packets for a downstream domain that do not fit in memory, kept in a temporary file in the
+/// order they were queued.
+pub(super) struct Spill {
+    file: File,
+    read_at: u64,
+    write_at: u64,
+    len: usize,
+}
+
+impl Spill {
+    pub(super) fn new() -> Result<Self, bincode::Error> {
+        Ok(Spill {
+            file: tempfile::tempfile()?,
+            read_at: 0,
+            write_at: 0,
+            len: 0,
+        })
+    }
+
+    /// The number of packets in the file.
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Add `m` to the back of the queue.
+    pub(super) fn push(&mut self, m: &Packet) -> Result<(), bincode::Error> {
+        let bytes = bincode::serialize(m)?;
+        self.file.seek(SeekFrom::Start(self.write_at))?;
+        self.file.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        self.file.write_all(&bytes)?;
+        self.write_at += 8 + bytes.len() as u64;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Take the packet at the front of the queue, if there is one.
+    pub(super) fn pop(&mut self) -> Result<Option<Box<Packet>>, bincode::Error> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+
+        self.file.seek(SeekFrom::Start(self.read_at))?;
+        let mut len = [0; 8];
+        self.file.read_exact(&mut len)?;
+        let mut bytes = vec![0; u64::from_le_bytes(len) as usize];
+        self.file.read_exact(&mut bytes)?;
+        self.read_at += 8 + bytes.len() as u64;
+        self.len -= 1;
+        if self.len == 0 {
+            // start the file over, so that it does not keep growing
+            self.file.set_len(0)?;
+            self.read_at = 0;
+            self.write_at = 0;
+        }
+        Ok(Some(Box::new(bincode::deserialize(&bytes)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dataflow::prelude::{DataType, Link, LocalNodeIndex};
+
+    fn message(v: i32) -> Packet {
+        Packet::Message {
+            link: Link::new(unsafe { LocalNodeIndex::make(0) }, unsafe {
+                LocalNodeIndex::make(1)
+            }),
+            data: vec![vec![DataType::from(v)]].into(),
+            tracer: None,
+            origin: None,
+        }
+    }
+
+    fn value(m: Box<Packet>) -> DataType {
+        match *m {
+            Packet::Message { ref data, .. } => data.iter().next().unwrap()[0].clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn spilled_packets_come_back_in_order() {
+        let mut spill = Spill::new().unwrap();
+        spill.push(&message(1)).unwrap();
+        spill.push(&message(2)).unwrap();
+        assert_eq!(value(spill.pop().unwrap().unwrap()), 1.into());
+        spill.push(&message(3)).unwrap();
+        assert_eq!(spill.len(), 2);
+        assert_eq!(value(spill.pop().unwrap().unwrap()), 2.into());
+        assert_eq!(value(spill.pop().unwrap().unwrap()), 3.into());
+        assert!(spill.pop().unwrap().is_none());
+
+        // the file is reused once it has been emptied
+        spill.push(&message(4)).unwrap();
+        assert_eq!(value(spill.pop().unwrap().unwrap()), 4.into());
+    }
+}
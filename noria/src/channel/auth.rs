@@ -0,0 +1,505 @@
+//! Mutual authentication, and authentication of every message, for connections within a
+//! deployment.
+//!
+//! When the deployment is configured with a shared secret, both ends of a connection prove to
+//! each other that they know a key before anything else is sent over it. The accepting side sends
+//! a random challenge, the connecting side answers it along with a challenge of its own, and the
+//! accepting side answers that in turn. Both sides then derive a key for each direction from the
+//! key they proved they know and from both challenges. Everything sent after the handshake is
+//! split into frames that carry an HMAC of their contents and of their position in the stream
+//! under the key for their direction, so frames that are forged, altered, replayed, reordered or
+//! sent back to where they came from are rejected.
+//!
+//! Connections between domains, from domains to the controller, and between the controller and
+//! the workers authenticate with the secret itself. Connections from clients to base tables and to
+//! views authenticate with keys derived from the secret for writing and for reading. The
+//! controller hands those keys out along with the tables and views it lets a client use, and they
+//! reveal nothing about the secret.
+//!
+//! Frames are authenticated, but not encrypted. When TLS is configured, they are sent over the
+//! encrypted stream (see the `tls` module).
+
+use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
+use futures::try_ready;
+use ring::constant_time;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac};
+use std::cmp;
+use std::io::{self, Read, Write};
+use tokio::prelude::*;
+
+/// The length of the challenge that each side sends.
+pub const CHALLENGE_LEN: usize = 32;
+
+/// The length of the response to a challenge.
+pub const RESPONSE_LEN: usize = 32;
+
+/// The length of the HMAC that follows every frame.
+const TAG_LEN: usize = 32;
+
+/// The most data that is sent in a single frame.
+const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// How much sealed data may be waiting to be written before writes have to wait for it.
+const MAX_SEALED: usize = 4 * MAX_FRAME_LEN;
+
+/// What the key that clients write to base tables with is derived for.
+pub const WRITE: &[u8] = b"write";
+
+/// What the key that clients read from views with is derived for.
+pub const READ: &[u8] = b"read";
+
+const CONNECTOR_PROOF: &[u8] = b"connector proof";
+const ACCEPTOR_PROOF: &[u8] = b"acceptor proof";
+const CONNECTOR_FRAMES: &[u8] = b"connector frames";
+const ACCEPTOR_FRAMES: &[u8] = b"acceptor frames";
+
+/// Derive the key for `purpose` from the shared `secret`.
+pub fn derive(secret: &[u8], purpose: &[u8]) -> Vec<u8> {
+    mac(secret, &[purpose])
+}
+
+/// Generate a new random challenge.
+pub fn challenge() -> Vec<u8> {
+    let mut c = vec![0; CHALLENGE_LEN];
+    SystemRandom::new()
+        .fill(&mut c)
+        .expect("failed to generate authentication challenge");
+    c
+}
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let key = hmac::SigningKey::new(&digest::SHA256, key);
+    let mut ctx = hmac::SigningContext::with_key(&key);
+    for part in parts {
+        ctx.update(part);
+    }
+    ctx.sign().as_ref().to_vec()
+}
+
+fn rejected() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "peer could not prove that it knows the key",
+    )
+}
+
+/// Check the accepting side's answer to our challenge, and set up the keys for the frames that
+/// follow.
+fn accepted<S>(
+    stream: S,
+    key: &[u8],
+    challenges: &[u8],
+    proof: &[u8],
+) -> io::Result<Authenticated<S>> {
+    let expected = mac(key, &[ACCEPTOR_PROOF, challenges]);
+    if constant_time::verify_slices_are_equal(&expected, proof).is_err() {
+        return Err(rejected());
+    }
+    Ok(Authenticated::new(
+        stream,
+        Some(Keys::new(key, challenges, true)),
+    ))
+}
+
+/// Prove to the accepting side of `stream` that we know `key`, and have it prove the same to us,
+/// if connections to it must authenticate with `key`.
+pub fn connect<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    key: Option<Vec<u8>>,
+) -> impl Future<Item = Authenticated<S>, Error = io::Error> {
+    let key = match key {
+        None => return future::Either::A(future::ok(Authenticated::new(stream, None))),
+        Some(key) => key,
+    };
+    future::Either::B(
+        tokio::io::read_exact(stream, [0u8; CHALLENGE_LEN])
+            .and_then(move |(stream, theirs)| {
+                let ours = challenge();
+                let challenges = [&theirs[..], &ours[..]].concat();
+                let mut answer = ours;
+                answer.extend(mac(&key, &[CONNECTOR_PROOF, &challenges]));
+                tokio::io::write_all(stream, answer)
+                    .and_then(|(stream, _)| tokio::io::flush(stream))
+                    .map(move |stream| (stream, key, challenges))
+            })
+            .and_then(|(stream, key, challenges)| {
+                tokio::io::read_exact(stream, [0u8; RESPONSE_LEN])
+                    .and_then(move |(stream, proof)| accepted(stream, &key, &challenges, &proof))
+            }),
+    )
+}
+
+/// Like [`connect`], but on a blocking `stream`.
+pub fn connect_sync<S: Read + Write>(
+    mut stream: S,
+    key: Option<&[u8]>,
+) -> io::Result<Authenticated<S>> {
+    let key = match key {
+        None => return Ok(Authenticated::new(stream, None)),
+        Some(key) => key,
+    };
+    let mut theirs = [0; CHALLENGE_LEN];
+    stream.read_exact(&mut theirs)?;
+    let ours = challenge();
+    let challenges = [&theirs[..], &ours[..]].concat();
+    stream.write_all(&ours)?;
+    stream.write_all(&mac(key, &[CONNECTOR_PROOF, &challenges]))?;
+    stream.flush()?;
+
+    let mut proof = [0; RESPONSE_LEN];
+    stream.read_exact(&mut proof)?;
+    accepted(stream, key, &challenges, &proof)
+}
+
+/// Have the connecting side of `stream` prove that it knows `key`, and prove the same to it, if
+/// connections must authenticate with `key`.
+///
+/// Fails with `PermissionDenied` if the connecting side does not know the key.
+pub fn accept<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    key: Option<Vec<u8>>,
+) -> impl Future<Item = Authenticated<S>, Error = io::Error> {
+    let key = match key {
+        None => return future::Either::A(future::ok(Authenticated::new(stream, None))),
+        Some(key) => key,
+    };
+    future::Either::B(
+        tokio::io::write_all(stream, challenge())
+            .and_then(|(stream, ours)| tokio::io::flush(stream).map(move |stream| (stream, ours)))
+            .and_then(|(stream, ours)| {
+                tokio::io::read_exact(stream, vec![0u8; CHALLENGE_LEN + RESPONSE_LEN])
+                    .map(move |(stream, answer)| (stream, ours, answer))
+            })
+            .and_then(move |(stream, ours, answer)| {
+                let challenges = [&ours[..], &answer[..CHALLENGE_LEN]].concat();
+                let expected = mac(&key, &[CONNECTOR_PROOF, &challenges]);
+                if constant_time::verify_slices_are_equal(&expected, &answer[CHALLENGE_LEN..])
+                    .is_err()
+                {
+                    return future::Either::A(future::err(rejected()));
+                }
+                let proof = mac(&key, &[ACCEPTOR_PROOF, &challenges]);
+                future::Either::B(
+                    tokio::io::write_all(stream, proof)
+                        .and_then(|(stream, _)| tokio::io::flush(stream))
+                        .map(move |stream| {
+                            Authenticated::new(stream, Some(Keys::new(&key, &challenges, false)))
+                        }),
+                )
+            }),
+    )
+}
+
+/// The keys that frames are sealed with in each direction, and how many frames have been sealed
+/// and opened so far.
+struct Keys {
+    send: hmac::SigningKey,
+    receive: hmac::SigningKey,
+    sent: u64,
+    received: u64,
+}
+
+impl Keys {
+    fn new(key: &[u8], challenges: &[u8], connector: bool) -> Self {
+        let derive = |side| hmac::SigningKey::new(&digest::SHA256, &mac(key, &[side, challenges]));
+        let (send, receive) = if connector {
+            (derive(CONNECTOR_FRAMES), derive(ACCEPTOR_FRAMES))
+        } else {
+            (derive(ACCEPTOR_FRAMES), derive(CONNECTOR_FRAMES))
+        };
+        Keys {
+            send,
+            receive,
+            sent: 0,
+            received: 0,
+        }
+    }
+}
+
+/// The HMAC of the `n`th frame sent in one direction, where `frame` is its header and contents.
+fn tag(key: &hmac::SigningKey, n: u64, frame: &[u8]) -> hmac::Signature {
+    let mut seq = [0; 8];
+    NetworkEndian::write_u64(&mut seq, n);
+    let mut ctx = hmac::SigningContext::with_key(key);
+    ctx.update(&seq);
+    ctx.update(frame);
+    ctx.sign()
+}
+
+/// A connection whose frames are authenticated with the keys agreed on when it was set up.
+///
+/// A connection that was set up without a key passes everything through as is.
+pub struct Authenticated<S> {
+    stream: S,
+    keys: Option<Keys>,
+    /// Data read from the stream that does not make up a whole frame yet.
+    incoming: Vec<u8>,
+    /// The contents of the last frame that was opened, and how much of them has been read.
+    opened: Vec<u8>,
+    read: usize,
+    /// Data that has been written, but not sealed into a frame yet.
+    unsealed: Vec<u8>,
+    /// Frames that have yet to be written to the stream, and how much of them has been.
+    sealed: Vec<u8>,
+    written: usize,
+}
+
+impl<S> Authenticated<S> {
+    fn new(stream: S, keys: Option<Keys>) -> Self {
+        Authenticated {
+            stream,
+            keys,
+            incoming: Vec::new(),
+            opened: Vec::new(),
+            read: 0,
+            unsealed: Vec::new(),
+            sealed: Vec::new(),
+            written: 0,
+        }
+    }
+
+    /// Use `stream` without authenticating anything sent over it.
+    pub fn unauthenticated(stream: S) -> Self {
+        Self::new(stream, None)
+    }
+
+    /// Whether the frames on this connection are authenticated.
+    pub fn is_authenticated(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Move the connection over to another stream, such as an asynchronous version of the same
+    /// socket.
+    ///
+    /// Anything that has been written is flushed to the old stream first. Must not be called
+    /// while there is data that has been received, but not read.
+    pub fn map<S2, F>(mut self, f: F) -> io::Result<Authenticated<S2>>
+    where
+        S: Write,
+        F: FnOnce(S) -> io::Result<S2>,
+    {
+        self.flush()?;
+        assert!(self.incoming.is_empty() && self.read == self.opened.len());
+        Ok(Authenticated::new(f(self.stream)?, self.keys))
+    }
+
+    fn seal(&mut self) {
+        if self.unsealed.is_empty() {
+            return;
+        }
+        let keys = self.keys.as_mut().unwrap();
+        let start = self.sealed.len();
+        self.sealed
+            .write_u32::<NetworkEndian>(self.unsealed.len() as u32)
+            .unwrap();
+        self.sealed.extend_from_slice(&self.unsealed);
+        let tag = tag(&keys.send, keys.sent, &self.sealed[start..]);
+        self.sealed.extend_from_slice(tag.as_ref());
+        keys.sent += 1;
+        self.unsealed.clear();
+    }
+
+    /// Take the next whole frame out of the data read so far, if there is one, and check that it
+    /// is authentic.
+    fn open(&mut self) -> io::Result<bool> {
+        let keys = self.keys.as_mut().unwrap();
+        if self.incoming.len() < 4 {
+            return Ok(false);
+        }
+        let len = NetworkEndian::read_u32(&self.incoming[..4]) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "received a frame that is too long",
+            ));
+        }
+        if self.incoming.len() < 4 + len + TAG_LEN {
+            return Ok(false);
+        }
+
+        let expected = tag(&keys.receive, keys.received, &self.incoming[..4 + len]);
+        let tagged = &self.incoming[4 + len..4 + len + TAG_LEN];
+        if constant_time::verify_slices_are_equal(expected.as_ref(), tagged).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "received a frame that failed authentication",
+            ));
+        }
+        keys.received += 1;
+        self.opened.clear();
+        self.opened.extend_from_slice(&self.incoming[4..4 + len]);
+        self.read = 0;
+        self.incoming.drain(..4 + len + TAG_LEN);
+        Ok(true)
+    }
+}
+
+impl<S: Write> Authenticated<S> {
+    fn write_sealed(&mut self) -> io::Result<()> {
+        while self.written < self.sealed.len() {
+            match self.stream.write(&self.sealed[self.written..])? {
+                0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                n => self.written += n,
+            }
+        }
+        self.sealed.clear();
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl<S: Read> Read for Authenticated<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.keys.is_none() {
+            return self.stream.read(buf);
+        }
+
+        loop {
+            if self.read < self.opened.len() {
+                let n = cmp::min(buf.len(), self.opened.len() - self.read);
+                buf[..n].copy_from_slice(&self.opened[self.read..self.read + n]);
+                self.read += n;
+                return Ok(n);
+            }
+            if self.open()? {
+                continue;
+            }
+
+            let start = self.incoming.len();
+            self.incoming.resize(start + 8 * 1024, 0);
+            match self.stream.read(&mut self.incoming[start..]) {
+                Ok(0) => {
+                    self.incoming.truncate(start);
+                    if start == 0 {
+                        return Ok(0);
+                    }
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                Ok(n) => self.incoming.truncate(start + n),
+                Err(e) => {
+                    self.incoming.truncate(start);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl<S: Write> Write for Authenticated<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.keys.is_none() {
+            return self.stream.write(buf);
+        }
+
+        if self.sealed.len() - self.written >= MAX_SEALED {
+            self.write_sealed()?;
+        }
+        let n = cmp::min(buf.len(), MAX_FRAME_LEN - self.unsealed.len());
+        self.unsealed.extend_from_slice(&buf[..n]);
+        if self.unsealed.len() == MAX_FRAME_LEN {
+            self.seal();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.keys.is_some() {
+            self.seal();
+            self.write_sealed()?;
+        }
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Authenticated<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Authenticated<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_flush());
+        self.stream.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn both_sides_must_know_the_key() {
+        let handshake = |connector: &'static [u8], acceptor: &'static [u8]| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let connecting = thread::spawn(move || {
+                let stream = std::net::TcpStream::connect(addr).unwrap();
+                let mut stream = connect_sync(stream, Some(connector))?;
+                stream.write_all(b"hello")?;
+                stream.flush()
+            });
+
+            let stream = listener.accept().unwrap().0;
+            let stream = tokio::net::TcpStream::from_std(stream, &Default::default()).unwrap();
+            let accepted = accept(stream, Some(acceptor.to_vec()))
+                .and_then(|stream| tokio::io::read_exact(stream, [0u8; 5]))
+                .wait()
+                .map(|(_, hello)| hello);
+            (connecting.join().unwrap(), accepted)
+        };
+
+        let (connected, accepted) = handshake(b"secret", b"secret");
+        assert!(connected.is_ok());
+        assert_eq!(&accepted.unwrap(), b"hello");
+
+        let (connected, accepted) = handshake(b"secret", b"other");
+        assert!(connected.is_err());
+        assert_eq!(
+            accepted.unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+
+        // keys derived for different purposes don't answer for each other
+        let (read, write) = (derive(b"secret", READ), derive(b"secret", WRITE));
+        assert_ne!(read, write);
+    }
+
+    #[test]
+    fn frames_are_authenticated() {
+        let challenges = [challenge(), challenge()].concat();
+        let mut tx = Authenticated::new(Vec::new(), Some(Keys::new(b"k", &challenges, true)));
+        tx.write_all(b"hello").unwrap();
+        tx.flush().unwrap();
+        tx.write_all(b"world").unwrap();
+        tx.flush().unwrap();
+        let frames = tx.get_ref().clone();
+        let first = 4 + 5 + TAG_LEN;
+        assert_eq!(frames.len(), 2 * first);
+
+        let receive = |frames: &[u8], connector| {
+            let keys = Keys::new(b"k", &challenges, connector);
+            let mut rx = Authenticated::new(io::Cursor::new(frames.to_vec()), Some(keys));
+            let mut data = Vec::new();
+            rx.read_to_end(&mut data).map(|_| data)
+        };
+        assert_eq!(receive(&frames, false).unwrap(), b"helloworld");
+
+        // altered
+        let mut altered = frames.clone();
+        altered[5] ^= 1;
+        assert!(receive(&altered, false).is_err());
+        // dropped or replayed
+        assert!(receive(&frames[first..], false).is_err());
+        // sent back to where it came from
+        assert!(receive(&frames, true).is_err());
+        // cut short
+        assert!(receive(&frames[..first + 4], false).is_err());
+    }
+}
@@ -17,6 +17,9 @@ use byteorder::{ByteOrder, NetworkEndian};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio::prelude::*;
 
+use crate::tls::{self, TlsStream};
+
+pub mod auth;
 pub mod rpc;
 pub mod tcp;
 
@@ -25,6 +28,37 @@ pub use self::tcp::{channel, DualTcpStream, TcpReceiver, TcpSender};
 pub const CONNECTION_FROM_BASE: u8 = 1;
 pub const CONNECTION_FROM_DOMAIN: u8 = 2;
 
+/// A connection within a deployment, encrypted and authenticated as the deployment requires.
+pub type Connection<S> = auth::Authenticated<TlsStream<S>>;
+
+/// Set up the connecting side of `stream` as connections within the deployment must be:
+/// encrypted with `tls` and authenticated with `key`, if they are set.
+pub fn secure<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    tls: Option<&tls::Connector>,
+    key: Option<Vec<u8>>,
+) -> impl Future<Item = Connection<S>, Error = io::Error> {
+    let stream = match tls {
+        Some(tls) => tls.connect(stream),
+        None => TlsStream::plain(stream),
+    };
+    auth::connect(stream, key)
+}
+
+/// Like [`secure`], but on a blocking `stream`.
+pub fn secure_sync<S: Read + Write>(
+    stream: S,
+    tls: Option<&tls::Connector>,
+    key: Option<&[u8]>,
+) -> io::Result<Connection<S>> {
+    let mut stream = match tls {
+        Some(tls) => tls.connect(stream),
+        None => TlsStream::plain(stream),
+    };
+    stream.complete_handshake()?;
+    auth::connect_sync(stream, key)
+}
+
 pub struct Remote;
 pub struct MaybeLocal;
 
@@ -33,6 +67,8 @@ pub struct DomainConnectionBuilder<D, T> {
     addr: SocketAddr,
     chan: Option<tokio_sync::mpsc::UnboundedSender<T>>,
     is_for_base: bool,
+    secret: Option<Vec<u8>>,
+    tls: Option<tls::Connector>,
     _marker: D,
 }
 
@@ -43,6 +79,8 @@ impl<T> DomainConnectionBuilder<Remote, T> {
             chan: None,
             addr,
             is_for_base: true,
+            secret: None,
+            tls: None,
             _marker: Remote,
         }
     }
@@ -66,31 +104,35 @@ where
 {
     pub fn build_async(
         self,
-    ) -> io::Result<AsyncBincodeWriter<BufWriter<tokio::net::TcpStream>, T, AsyncDestination>> {
+    ) -> io::Result<
+        AsyncBincodeWriter<BufWriter<Connection<tokio::net::TcpStream>>, T, AsyncDestination>,
+    > {
         // TODO: async
         // we must currently write and call flush, because the remote end (currently) does a
         // synchronous read upon accepting a connection.
         let s = self.build_sync()?.into_inner().into_inner()?;
 
-        tokio::net::TcpStream::from_std(s, &tokio::reactor::Handle::default())
+        s.map(|s| s.map(|s| tokio::net::TcpStream::from_std(s, &tokio::reactor::Handle::default())))
             .map(BufWriter::new)
             .map(AsyncBincodeWriter::from)
             .map(AsyncBincodeWriter::for_async)
     }
 
     pub fn build_sync(self) -> io::Result<TcpSender<T>> {
-        let mut s = TcpSender::connect_from(self.sport, &self.addr)?;
-        {
-            let s = s.get_mut();
-            s.write_all(&[if self.is_for_base {
-                CONNECTION_FROM_BASE
-            } else {
-                CONNECTION_FROM_DOMAIN
-            }])?;
-            s.flush()?;
-        }
+        let mut s = tcp::connect_from(self.sport, &self.addr)?;
+        s.write_all(&[if self.is_for_base {
+            CONNECTION_FROM_BASE
+        } else {
+            CONNECTION_FROM_DOMAIN
+        }])?;
+        s.flush()?;
 
-        Ok(s)
+        let s = secure_sync(
+            s,
+            self.tls.as_ref(),
+            self.secret.as_ref().map(Vec::as_slice),
+        )?;
+        TcpSender::secured(s)
     }
 }
 
@@ -131,6 +173,8 @@ where
                 chan: None,
                 addr: self.addr,
                 is_for_base: false,
+                secret: self.secret,
+                tls: self.tls,
                 _marker: Remote,
             }
             .build_async()
@@ -147,6 +191,8 @@ where
                 chan: None,
                 addr: self.addr,
                 is_for_base: false,
+                secret: self.secret,
+                tls: self.tls,
                 _marker: Remote,
             }
             .build_sync()
@@ -241,6 +287,10 @@ struct ChannelCoordinatorInner<K: Eq + Hash + Clone, T> {
 
 pub struct ChannelCoordinator<K: Eq + Hash + Clone, T> {
     inner: RwLock<ChannelCoordinatorInner<K, T>>,
+    /// The secret that connections between domains must authenticate with, if any.
+    secret: Option<Vec<u8>>,
+    /// How connections between domains are encrypted, if they are.
+    tls: Option<tls::Connector>,
 }
 
 impl<K: Eq + Hash + Clone, T> Default for ChannelCoordinator<K, T> {
@@ -251,14 +301,30 @@ impl<K: Eq + Hash + Clone, T> Default for ChannelCoordinator<K, T> {
 
 impl<K: Eq + Hash + Clone, T> ChannelCoordinator<K, T> {
     pub fn new() -> Self {
+        Self::secured(None, None)
+    }
+
+    /// Make a coordinator whose connections between domains authenticate with `secret`, and are
+    /// encrypted with `tls`.
+    pub fn secured(secret: Option<Vec<u8>>, tls: Option<tls::Connector>) -> Self {
         Self {
             inner: RwLock::new(ChannelCoordinatorInner {
                 addrs: Default::default(),
                 locals: Default::default(),
             }),
+            secret,
+            tls,
         }
     }
 
+    pub fn secret(&self) -> Option<&[u8]> {
+        self.secret.as_ref().map(Vec::as_slice)
+    }
+
+    pub fn tls(&self) -> Option<&tls::Connector> {
+        self.tls.as_ref()
+    }
+
     pub fn insert_remote(&self, key: K, addr: SocketAddr) {
         let mut inner = self.inner.write().unwrap();
         inner.addrs.insert(key, addr);
@@ -304,6 +370,8 @@ impl<K: Eq + Hash + Clone, T> ChannelCoordinator<K, T> {
             addr: *inner.addrs.get(key)?,
             chan: inner.locals.get(key).cloned(),
             is_for_base: false,
+            secret: self.secret.clone(),
+            tls: self.tls.clone(),
             _marker: MaybeLocal,
         })
     }
@@ -4,7 +4,7 @@ use std::io::{self, BufReader, Write};
 use std::marker::PhantomData;
 use std::net::{Ipv4Addr, SocketAddr};
 
-use crate::Tagged;
+use crate::{Tagged, WriteReply};
 use async_bincode::{AsyncBincodeStream, AsyncBincodeWriter, AsyncDestination};
 use bincode;
 use bufstream::BufStream;
@@ -14,7 +14,9 @@ use net2;
 use serde::{Deserialize, Serialize};
 use tokio::prelude::*;
 
-use super::{DeserializeReceiver, NonBlockingWriter, ReceiveError};
+use super::auth::Authenticated;
+use super::{Connection, DeserializeReceiver, NonBlockingWriter, ReceiveError};
+use crate::tls::{self, TlsStream};
 
 #[derive(Debug, Fail)]
 pub enum SendError {
@@ -50,8 +52,21 @@ macro_rules! poisoning_try {
     };
 }
 
+/// Connect to `addr`, from the local port `sport` if one is given.
+pub(crate) fn connect_from(
+    sport: Option<u16>,
+    addr: &SocketAddr,
+) -> Result<std::net::TcpStream, io::Error> {
+    let s = net2::TcpBuilder::new_v4()?
+        .reuse_address(true)?
+        .bind((Ipv4Addr::UNSPECIFIED, sport.unwrap_or(0)))?
+        .connect(addr)?;
+    s.set_nodelay(true)?;
+    Ok(s)
+}
+
 pub struct TcpSender<T> {
-    stream: BufStream<std::net::TcpStream>,
+    stream: BufStream<Connection<std::net::TcpStream>>,
     poisoned: bool,
 
     phantom: PhantomData<T>,
@@ -59,7 +74,12 @@ pub struct TcpSender<T> {
 
 impl<T: Serialize> TcpSender<T> {
     pub fn new(stream: std::net::TcpStream) -> Result<Self, io::Error> {
-        stream.set_nodelay(true).unwrap();
+        Self::secured(Authenticated::unauthenticated(TlsStream::plain(stream)))
+    }
+
+    /// Send over a connection that has already been encrypted and authenticated as needed.
+    pub fn secured(stream: Connection<std::net::TcpStream>) -> Result<Self, io::Error> {
+        stream.get_ref().get_ref().set_nodelay(true).unwrap();
         Ok(Self {
             stream: BufStream::new(stream),
             poisoned: false,
@@ -67,33 +87,35 @@ impl<T: Serialize> TcpSender<T> {
         })
     }
 
-    pub(crate) fn connect_from(sport: Option<u16>, addr: &SocketAddr) -> Result<Self, io::Error> {
-        let s = net2::TcpBuilder::new_v4()?
-            .reuse_address(true)?
-            .bind((Ipv4Addr::UNSPECIFIED, sport.unwrap_or(0)))?
-            .connect(addr)?;
-        s.set_nodelay(true)?;
-        Self::new(s)
+    pub fn connect(addr: &SocketAddr) -> Result<Self, io::Error> {
+        Self::connect_with(addr, None, None)
     }
 
-    pub fn connect(addr: &SocketAddr) -> Result<Self, io::Error> {
-        Self::connect_from(None, addr)
+    /// Connect to `addr`, encrypting the connection with `tls`, and proving that we know `key`,
+    /// if connections to it must.
+    pub fn connect_with(
+        addr: &SocketAddr,
+        tls: Option<&tls::Connector>,
+        key: Option<&[u8]>,
+    ) -> Result<Self, io::Error> {
+        let s = connect_from(None, addr)?;
+        Self::secured(super::secure_sync(s, tls, key)?)
     }
 
-    pub fn get_mut(&mut self) -> &mut BufStream<std::net::TcpStream> {
+    pub fn get_mut(&mut self) -> &mut BufStream<Connection<std::net::TcpStream>> {
         &mut self.stream
     }
 
-    pub(crate) fn into_inner(self) -> BufStream<std::net::TcpStream> {
+    pub(crate) fn into_inner(self) -> BufStream<Connection<std::net::TcpStream>> {
         self.stream
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.stream.get_ref().local_addr()
+        self.stream.get_ref().get_ref().get_ref().local_addr()
     }
 
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-        self.stream.get_ref().peer_addr()
+        self.stream.get_ref().get_ref().get_ref().peer_addr()
     }
 
     /// Send a message on this channel. Ownership isn't actually required, but is taken anyway to
@@ -140,9 +162,9 @@ pub enum RecvError {
 }
 
 pub enum DualTcpStream<S, T, T2, D> {
-    Passthrough(AsyncBincodeStream<S, T, Tagged<()>, D>),
+    Passthrough(AsyncBincodeStream<S, T, Tagged<WriteReply>, D>),
     Upgrade(
-        AsyncBincodeStream<S, T2, Tagged<()>, D>,
+        AsyncBincodeStream<S, T2, Tagged<WriteReply>, D>,
         Box<FnMut(T2) -> T + Send + Sync>,
     ),
 }
@@ -155,7 +177,7 @@ impl<S, T, T2> From<S> for DualTcpStream<S, T, T2, AsyncDestination> {
 
 impl<S, T, T2> DualTcpStream<S, T, T2, AsyncDestination> {
     pub fn upgrade<F: 'static + FnMut(T2) -> T + Send + Sync>(stream: S, f: F) -> Self {
-        let s: AsyncBincodeStream<S, T2, Tagged<()>, AsyncDestination> =
+        let s: AsyncBincodeStream<S, T2, Tagged<WriteReply>, AsyncDestination> =
             AsyncBincodeStream::from(stream).for_async();
         DualTcpStream::Upgrade(s, Box::new(f))
     }
@@ -171,9 +193,10 @@ impl<S, T, T2> DualTcpStream<S, T, T2, AsyncDestination> {
 impl<S, T, T2, D> Sink for DualTcpStream<S, T, T2, D>
 where
     S: AsyncWrite,
-    AsyncBincodeWriter<S, Tagged<()>, D>: Sink<SinkItem = Tagged<()>, SinkError = bincode::Error>,
+    AsyncBincodeWriter<S, Tagged<WriteReply>, D>:
+        Sink<SinkItem = Tagged<WriteReply>, SinkError = bincode::Error>,
 {
-    type SinkItem = Tagged<()>;
+    type SinkItem = Tagged<WriteReply>;
     type SinkError = bincode::Error;
     fn start_send(
         &mut self,
@@ -0,0 +1,355 @@
+//! Types that describe the workers, domains, and catalog of a running deployment.
+//!
+//! See [`ControllerHandle::workers`](crate::ControllerHandle::workers) and friends.
+
+use crate::{ActivationResult, ShadowReport};
+use petgraph::graph::NodeIndex;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// A worker that has registered with the controller.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct WorkerInfo {
+    /// The address the controller reaches the worker at.
+    pub addr: SocketAddr,
+    /// Whether the worker is still sending heartbeats.
+    pub healthy: bool,
+    /// How long ago the worker last sent a heartbeat.
+    pub last_heartbeat: Duration,
+    /// The labels the worker was started with.
+    pub labels: HashMap<String, String>,
+    /// The number of cores the worker may use.
+    pub cores: usize,
+    /// The number of bytes of memory the worker may use, or 0 if unknown.
+    pub memory: usize,
+    /// Whether the worker only runs domains that hold nothing but readers.
+    pub reader_only: bool,
+    /// Whether the worker is being drained, so that no new domains are placed on it.
+    pub draining: bool,
+    /// The number of domain shards the worker runs.
+    pub shards: usize,
+}
+
+/// A domain and the workers its shards run on.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct DomainInfo {
+    /// The index of the domain.
+    pub domain: usize,
+    /// The worker that runs each shard of the domain.
+    pub shards: Vec<SocketAddr>,
+    /// The dataflow nodes in the domain.
+    pub nodes: Vec<NodeIndex>,
+}
+
+/// A base table or a view, as listed in a [`Catalog`].
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct CatalogEntry {
+    /// The dataflow node that holds the table, or that the view reads from.
+    pub node: NodeIndex,
+    /// The names of its columns.
+    pub columns: Vec<String>,
+    /// The owner that the view's query was given, if any.
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// The tags that the view's query was given.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// The base tables and views of a deployment.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Catalog {
+    /// The base tables, by name.
+    pub tables: BTreeMap<String, CatalogEntry>,
+    /// The views, by name.
+    pub views: BTreeMap<String, CatalogEntry>,
+}
+
+/// The migrations that the controller has not yet finished.
+///
+/// Migrations are applied one at a time as they are asked for, so only recovery after a
+/// controller failure, shadow migrations, and migrations submitted to the controller's queue
+/// stay around for long enough to be seen here.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MigrationStatus {
+    /// The version of the recipe that is installed.
+    pub recipe_version: usize,
+    /// The number of recipes that the controller has yet to replay after taking over, if it is
+    /// recovering.
+    pub recovering: Option<usize>,
+    /// The shadow migration that is running, if any.
+    pub shadow: Option<ShadowReport>,
+    /// The tickets of the submitted migrations that are still queued, oldest first.
+    #[serde(default)]
+    pub queued: Vec<u64>,
+}
+
+/// How far a migration that was submitted to the controller's queue has got.
+///
+/// See [`ControllerHandle::submit_migration`](crate::ControllerHandle::submit_migration).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum SubmittedMigration {
+    /// The migration is waiting behind the given number of migrations that were queued before it.
+    Queued(usize),
+    /// The migration was applied, in one go with the other submitted migrations whose tickets are
+    /// given.
+    Done(ActivationResult, Vec<u64>),
+    /// The migration failed.
+    Failed(String),
+}
+
+/// The step a migration is at.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum MigrationStep {
+    /// The migration is waiting behind the given number of migrations that were submitted to the
+    /// controller's queue before it.
+    Queued(usize),
+    /// The new nodes are being assigned to domains.
+    Planning,
+    /// The new domains are being placed on workers and booted.
+    BootingDomains,
+    /// The new nodes are being added to the domains, and the domains are being connected.
+    Connecting,
+    /// The state of the new nodes is being filled by replays.
+    Replaying,
+    /// The migration has finished.
+    Done,
+}
+
+/// How far a migration has got, for showing the progress of a schema change.
+///
+/// See [`MigrationHandle`](crate::MigrationHandle).
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct MigrationProgress {
+    /// The step the migration is at.
+    pub step: MigrationStep,
+    /// The number of nodes the migration adds to the graph.
+    pub nodes_added: usize,
+    /// The number of new domains that have been booted.
+    pub domains_booted: usize,
+    /// The number of new domains the migration boots.
+    pub domains_to_boot: usize,
+    /// The number of new or newly indexed nodes that are ready.
+    pub nodes_ready: usize,
+    /// The number of new or newly indexed nodes the migration readies.
+    pub nodes_to_ready: usize,
+    /// The number of rows that replays have put into the state of new nodes. Readers only count
+    /// towards `bytes_replayed`.
+    pub rows_replayed: usize,
+    /// The size, in bytes, of the state that replays have filled.
+    pub bytes_replayed: u64,
+    /// How long the migration has been running, or took if it is done.
+    pub elapsed: Duration,
+}
+
+impl MigrationProgress {
+    /// Estimate how much longer the migration will take, from how long it took to get this far.
+    ///
+    /// Most of a migration is spent readying nodes, so the estimate assumes that the nodes that
+    /// are left take as long as those that are ready did. There is no estimate before the first
+    /// node is ready.
+    pub fn eta(&self) -> Option<Duration> {
+        match self.step {
+            MigrationStep::Done => Some(Duration::from_secs(0)),
+            MigrationStep::Queued(_) => None,
+            _ if self.nodes_ready == 0 => None,
+            _ => {
+                let left = self.nodes_to_ready.saturating_sub(self.nodes_ready) as u32;
+                Some(self.elapsed / self.nodes_ready as u32 * left)
+            }
+        }
+    }
+}
+
+/// What draining a worker or rebalancing domains did.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct MoveReport {
+    /// The queries that were rebuilt so that their domains could be placed anew.
+    pub moved: Vec<String>,
+    /// The domains that stay where they are, because they hold base tables and their state cannot
+    /// be moved.
+    pub remaining: Vec<usize>,
+}
+
+/// A worker that a deployment does not have yet, for planning with
+/// [`ControllerHandle::plan_workers`](crate::ControllerHandle::plan_workers).
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct NewWorker {
+    /// The labels the worker would be started with.
+    pub labels: HashMap<String, String>,
+    /// The number of cores the worker could use.
+    pub cores: usize,
+    /// The number of bytes of memory the worker could use, or 0 if unknown.
+    pub memory: usize,
+    /// Whether the worker would only run domains that hold nothing but readers.
+    pub reader_only: bool,
+}
+
+/// A change to the workers of a deployment that is to be planned, but not made.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct WorkerChange {
+    /// The workers that would be added.
+    pub add: Vec<NewWorker>,
+    /// The workers that would be removed, once drained.
+    pub remove: Vec<SocketAddr>,
+    /// Whether domains would then be rebalanced, as with
+    /// [`ControllerHandle::rebalance`](crate::ControllerHandle::rebalance).
+    pub rebalance: bool,
+}
+
+/// A worker that a domain shard would run on under a [`PlacementPlan`].
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum PlannedWorker {
+    /// The worker at the given address, which the deployment has already.
+    Existing(SocketAddr),
+    /// The worker at the given index of [`WorkerChange::add`].
+    New(usize),
+}
+
+/// A domain and the workers its shards would run on under a [`PlacementPlan`].
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct PlannedDomain {
+    /// The index of the domain.
+    pub domain: usize,
+    /// The worker that each shard of the domain would run on.
+    pub shards: Vec<PlannedWorker>,
+    /// The shards that would run on a different worker than they do now.
+    pub moved: Vec<usize>,
+    /// The number of bytes of state that the domain holds across all its shards.
+    pub state_bytes: u64,
+}
+
+/// Where domains would end up if the workers of a deployment changed.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct PlacementPlan {
+    /// The domains that still hold nodes, and where their shards would run.
+    pub domains: Vec<PlannedDomain>,
+    /// The number of bytes of state that the shards that would move hold, which would have to be
+    /// rebuilt on the workers they move to.
+    pub moved_bytes: u64,
+    /// The domains that would have to move off a removed worker but cannot, because they hold base
+    /// tables, whose state cannot be moved.
+    pub stuck: Vec<usize>,
+}
+
+/// The stretch of a [`ReplayPath`] that runs through one domain.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ReplayPathSegment {
+    /// The index of the domain.
+    pub domain: usize,
+    /// The dataflow nodes in the domain that the path goes through, in order.
+    pub nodes: Vec<NodeIndex>,
+}
+
+/// A path along which the state of a materialized node is filled from the closest materialization
+/// upstream of it.
+///
+/// See [`ControllerHandle::replay_paths`](crate::ControllerHandle::replay_paths).
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ReplayPath {
+    /// The tag that identifies the path.
+    pub tag: u32,
+    /// The node whose state the path fills.
+    pub target: NodeIndex,
+    /// The columns of the index of the target that the path fills.
+    pub index: Vec<usize>,
+    /// Whether the target is partially materialized, so that a replay runs along the path each
+    /// time a key misses in the index.
+    pub partial: bool,
+    /// The domains the path runs through, starting with the one that holds the materialization
+    /// that replays come from.
+    pub segments: Vec<ReplayPathSegment>,
+}
+
+/// A node that a migration adds, or would add.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct PlannedNode {
+    /// The index of the node.
+    pub node: NodeIndex,
+    /// The name of the node.
+    pub name: String,
+    /// A textual description of the node.
+    pub desc: String,
+    /// The index of the domain the node is in.
+    pub domain: usize,
+}
+
+/// What a migration would change in the running graph if it were carried out.
+///
+/// See [`ControllerHandle::plan_recipe`](crate::ControllerHandle::plan_recipe).
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct MigrationPlan {
+    /// The nodes that would be added, including the nodes that connect them across domains and
+    /// shards.
+    pub nodes: Vec<PlannedNode>,
+    /// The domains that would be created.
+    pub new_domains: Vec<usize>,
+    /// The replay paths that would fill the new materializations, and the new indices of existing
+    /// ones. Their tags are only tentative.
+    pub replay_paths: Vec<ReplayPath>,
+    /// The number of bytes of state that replays would copy to fill new full materializations,
+    /// judging by the size of the materializations they would copy from. Partial materializations
+    /// are filled as they are read, and so are not counted.
+    pub replayed_bytes: u64,
+}
+
+/// Which nodes a query that a recipe added shares with the queries that were there before, and
+/// why it does not share more.
+///
+/// See [`ActivationResult::reuse`](crate::ActivationResult::reuse).
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct QueryReuse {
+    /// The existing nodes that the query was built on, including the base tables it reads.
+    pub reused: Vec<NodeIndex>,
+    /// The nodes that were added for the query.
+    pub added: Vec<NodeIndex>,
+    /// Why the query was not built on existing queries, or on more of them.
+    pub rejected: Vec<String>,
+}
+
+/// A reader that a migration added, through which a view can be read.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct AddedReader {
+    /// The index of the reader.
+    pub node: NodeIndex,
+    /// The name the view is read by.
+    pub name: String,
+    /// The columns that the view is looked up by.
+    pub key: Vec<usize>,
+}
+
+/// A node that reads from one of its parents through a node that a migration placed in between,
+/// such as an ingress node or a sharder.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SwappedParent {
+    /// The node that reads from the parent.
+    pub node: NodeIndex,
+    /// The parent.
+    pub parent: NodeIndex,
+    /// The node that the parent is read through instead.
+    pub instead: NodeIndex,
+}
+
+/// What a migration that was committed changed in the running graph.
+///
+/// See [`ActivationResult::migration`](crate::ActivationResult::migration).
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct MigrationResult {
+    /// The nodes that were added, including the nodes that connect them across domains and
+    /// shards.
+    pub nodes: Vec<PlannedNode>,
+    /// The nodes that were there before the migration, and that the added nodes read from.
+    pub reused: Vec<NodeIndex>,
+    /// The domains that were created.
+    pub new_domains: Vec<usize>,
+    /// The readers that were added.
+    pub readers: Vec<AddedReader>,
+    /// The parents that added nodes read through the nodes that connect domains and shards.
+    pub swaps: Vec<SwappedParent>,
+    /// The views and base tables that were removed.
+    pub removed: Vec<NodeIndex>,
+    /// How far the migration got, which counts the nodes that replays readied, and what they
+    /// copied.
+    pub progress: MigrationProgress,
+}
@@ -11,7 +11,7 @@ use zookeeper::{Acl, CreateMode, KeeperState, Stat, WatchedEvent, Watcher, ZkErr
 
 use super::Authority;
 use super::Epoch;
-use super::CONTROLLER_KEY;
+use super::{CONTROLLER_KEY, STATE_KEY};
 
 struct EventWatcher;
 impl Watcher for EventWatcher {
@@ -64,6 +64,41 @@ impl ZookeeperAuthority {
         })
     }
 
+    /// Create an instance for the deployment named `deployment` in the ZooKeeper ensemble at
+    /// `zookeeper_address`.
+    ///
+    /// Everything a deployment keeps in ZooKeeper is stored under its name, so any number of
+    /// deployments can share an ensemble without seeing each other.
+    pub fn for_deployment(zookeeper_address: &str, deployment: &str) -> Result<Self, Error> {
+        if deployment.is_empty() || deployment.contains('/') {
+            bail!("invalid deployment name \"{}\"", deployment);
+        }
+        Self::new(&format!(
+            "{}/{}",
+            zookeeper_address.trim_end_matches('/'),
+            deployment
+        ))
+    }
+
+    /// The names of the deployments whose state is kept directly below the root of this instance.
+    ///
+    /// This is only meaningful for an instance created with `new` for the address of the ensemble
+    /// itself, rather than with `for_deployment`.
+    pub fn deployments(&self) -> Result<Vec<String>, Error> {
+        let mut deployments = Vec::new();
+        for child in self.zk.get_children("/", false)? {
+            for key in &[CONTROLLER_KEY, STATE_KEY] {
+                let path = format!("/{}{}", child, key);
+                if self.zk.exists(&path, false)?.is_some() {
+                    deployments.push(child);
+                    break;
+                }
+            }
+        }
+        deployments.sort();
+        Ok(deployments)
+    }
+
     /// Enable logging
     pub fn log_with(&mut self, log: slog::Logger) {
         self.log = log;
@@ -231,4 +266,28 @@ mod tests {
         thread::sleep(Duration::from_millis(100));
         assert_eq!(authority.get_leader().unwrap().1, vec![15]);
     }
+
+    #[test]
+    #[allow_fail]
+    fn it_lists_deployments() {
+        let authority =
+            ZookeeperAuthority::for_deployment("127.0.0.1:2181", "consensus_lists_deployments")
+                .unwrap();
+        authority
+            .read_modify_write(STATE_KEY, |_: Option<u32>| -> Result<u32, u32> { Ok(1) })
+            .unwrap()
+            .unwrap();
+
+        let root = ZookeeperAuthority::new("127.0.0.1:2181").unwrap();
+        assert!(root
+            .deployments()
+            .unwrap()
+            .contains(&"consensus_lists_deployments".to_owned()));
+    }
+
+    #[test]
+    fn deployment_names_are_checked() {
+        assert!(ZookeeperAuthority::for_deployment("127.0.0.1:2181", "").is_err());
+        assert!(ZookeeperAuthority::for_deployment("127.0.0.1:2181", "a/b").is_err());
+    }
 }
@@ -1,8 +1,15 @@
+use crate::cluster::{
+    Catalog, DomainInfo, MigrationPlan, MigrationProgress, MigrationStatus, MoveReport,
+    PlacementPlan, ReplayPath, SubmittedMigration, WorkerChange, WorkerInfo,
+};
 use crate::consensus::{self, Authority};
+use crate::debug::invariants::Violation;
 use crate::debug::stats;
-use crate::table::{Table, TableBuilder, TableRpc};
-use crate::view::{View, ViewBuilder, ViewRpc};
-use crate::ActivationResult;
+use crate::prepared::{Executed, PreparedStatement, Route, StatementPlan, Target};
+use crate::recording::Recorder;
+use crate::table::{Propagate, Table, TableBuilder, TableRpc, WriteGroup};
+use crate::view::{SnapshotToken, View, ViewBuilder, ViewRpc};
+use crate::{ActivationResult, ConfigUpdate, DataType, ShadowDiff, ShadowReport};
 #[cfg(debug_assertions)]
 use assert_infrequent;
 use failure::{self, ResultExt};
@@ -13,6 +20,7 @@ use serde_json;
 use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{Duration, Instant};
 use tokio::prelude::*;
 use tower_buffer::Buffer;
@@ -29,11 +37,15 @@ pub struct ControllerDescriptor {
     pub worker_addr: SocketAddr,
     pub domain_addr: SocketAddr,
     pub nonce: u64,
+    /// Whether the external API is served over TLS.
+    #[serde(default)]
+    pub tls: bool,
 }
 
 struct Controller<A> {
     authority: Arc<A>,
-    client: hyper::Client<hyper::client::HttpConnector>,
+    client: hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>,
+    token: Option<String>,
 }
 
 #[derive(Debug)]
@@ -68,6 +80,7 @@ where
     fn call(&mut self, req: ControllerRequest) -> Self::Future {
         let client = self.client.clone();
         let auth = self.authority.clone();
+        let token = self.token.clone();
         let path = req.path;
         let body = req.request;
 
@@ -88,12 +101,15 @@ where
                     Ok(d) => d,
                     Err(e) => return future::Either::A(future::err(e)),
                 };
-                format!("http://{}/{}", descriptor.external_addr, path)
+                let scheme = if descriptor.tls { "https" } else { "http" };
+                format!("{}://{}/{}", scheme, descriptor.external_addr, path)
             };
 
-            let r = hyper::Request::post(&url)
-                .body(hyper::Body::from(body.clone()))
-                .unwrap();
+            let mut r = hyper::Request::post(&url);
+            if let Some(ref token) = token {
+                r.header(hyper::header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let r = r.body(hyper::Body::from(body.clone())).unwrap();
 
             future::Either::B(
                 client
@@ -125,6 +141,14 @@ where
                                 String::from_utf8_lossy(&*body)
                             ))))
                         }
+                        s @ hyper::StatusCode::UNAUTHORIZED | s @ hyper::StatusCode::FORBIDDEN => {
+                            // retrying won't help us here
+                            future::Either::B(future::Either::B(future::err(format_err!(
+                                "rpc call to {} was rejected: {}",
+                                path,
+                                s
+                            ))))
+                        }
                         s => {
                             let url = if s == hyper::StatusCode::SERVICE_UNAVAILABLE {
                                 None
@@ -173,6 +197,32 @@ where
     handle: Buffer<Controller<A>, ControllerRequest>,
     domains: Arc<Mutex<HashMap<(SocketAddr, usize), TableRpc>>>,
     views: Arc<Mutex<HashMap<(SocketAddr, usize), ViewRpc>>>,
+    recorder: Option<Recorder>,
+    /// The configuration that the certificates of workers that use TLS are verified with.
+    tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+/// Wait for every reader in `propagation`, as taken for the base table `table`, to reflect the
+/// snapshot in it.
+fn reflect(
+    views: Arc<Mutex<HashMap<(SocketAddr, usize), ViewRpc>>>,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    table: &str,
+    propagation: Option<(SnapshotToken, Vec<ViewBuilder>)>,
+) -> impl Future<Item = (), Error = failure::Error> + Send {
+    let (snapshot, readers) = match propagation {
+        Some(p) => p,
+        None => {
+            let e = format_err!("no base table named {}", table);
+            return future::Either::A(future::err(e));
+        }
+    };
+    let reflected = readers.into_iter().map(move |r| {
+        r.build(views.clone(), tls.clone())
+            .map_err(failure::Error::from)
+            .and_then(move |view| view.reflect(snapshot).map_err(|e| e.error.into()))
+    });
+    future::Either::B(future::join_all(reflected).map(|_| ()))
 }
 
 impl<A> Clone for ControllerHandle<A>
@@ -184,6 +234,8 @@ where
             handle: self.handle.clone(),
             domains: self.domains.clone(),
             views: self.views.clone(),
+            recorder: self.recorder.clone(),
+            tls: self.tls.clone(),
         }
     }
 }
@@ -197,20 +249,66 @@ impl ControllerHandle<consensus::ZookeeperAuthority> {
             Err(e) => future::Either::B(future::err(e)),
         }
     }
+
+    /// Connect to the deployment named `deployment` among those that share the ZooKeeper ensemble
+    /// at the given address.
+    pub fn from_deployment(
+        zookeeper_address: &str,
+        deployment: &str,
+    ) -> impl Future<Item = Self, Error = failure::Error> {
+        match consensus::ZookeeperAuthority::for_deployment(zookeeper_address, deployment) {
+            Ok(auth) => future::Either::A(ControllerHandle::new(auth)),
+            Err(e) => future::Either::B(future::err(e)),
+        }
+    }
+
+    /// The names of the deployments that share the ZooKeeper ensemble at the given address.
+    pub fn deployments(zookeeper_address: &str) -> Result<Vec<String>, failure::Error> {
+        consensus::ZookeeperAuthority::new(zookeeper_address)?.deployments()
+    }
 }
 
 impl<A: Authority + 'static> ControllerHandle<A> {
     #[doc(hidden)]
     pub fn make(authority: Arc<A>) -> impl Future<Item = Self, Error = failure::Error> {
+        Self::make_with_token(authority, None)
+    }
+
+    #[doc(hidden)]
+    pub fn make_with_token(
+        authority: Arc<A>,
+        token: Option<String>,
+    ) -> impl Future<Item = Self, Error = failure::Error> {
+        Self::make_with_tls(authority, token, None)
+    }
+
+    #[doc(hidden)]
+    pub fn make_with_tls(
+        authority: Arc<A>,
+        token: Option<String>,
+        tls: Option<Arc<rustls::ClientConfig>>,
+    ) -> impl Future<Item = Self, Error = failure::Error> {
         // need to use lazy otherwise current executor won't be known
         future::lazy(move || {
+            let https = match tls {
+                Some(ref tls) => {
+                    let mut http = hyper::client::HttpConnector::new(4);
+                    http.enforce_http(false);
+                    hyper_rustls::HttpsConnector::from((http, tls.clone()))
+                }
+                None => hyper_rustls::HttpsConnector::new(4),
+            };
+
             Ok(ControllerHandle {
                 views: Default::default(),
                 domains: Default::default(),
+                recorder: None,
+                tls,
                 handle: Buffer::new(
                     Controller {
                         authority,
-                        client: hyper::Client::new(),
+                        client: hyper::Client::builder().build(https),
+                        token,
                     },
                     1,
                 ),
@@ -229,6 +327,51 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         Self::make(Arc::new(authority))
     }
 
+    /// Like `ControllerHandle::new`, but authenticates every request to the controller with the
+    /// given API token.
+    ///
+    /// This is needed when the controller has authentication enabled. Which operations are
+    /// permitted depends on the role the token has been assigned by the controller.
+    pub fn new_with_token(
+        authority: A,
+        token: &str,
+    ) -> impl Future<Item = Self, Error = failure::Error> + Send
+    where
+        A: Send + 'static,
+    {
+        Self::make_with_token(Arc::new(authority), Some(token.to_owned()))
+    }
+
+    /// Like `ControllerHandle::new_with_token`, but uses the given TLS configuration to verify
+    /// the certificate of the controller, and of the workers that host views and tables, when the
+    /// deployment uses TLS.
+    ///
+    /// Deployments that use TLS are reachable with any `ControllerHandle`, but this constructor
+    /// is needed if the deployment's certificate is not signed by a well-known certificate
+    /// authority. See [`tls::client_config`](crate::tls::client_config).
+    pub fn new_with_tls(
+        authority: A,
+        token: Option<&str>,
+        tls: rustls::ClientConfig,
+    ) -> impl Future<Item = Self, Error = failure::Error> + Send
+    where
+        A: Send + 'static,
+    {
+        Self::make_with_tls(
+            Arc::new(authority),
+            token.map(ToOwned::to_owned),
+            Some(Arc::new(tls)),
+        )
+    }
+
+    /// Record the writes and lookups issued through tables and views obtained from this handle
+    /// from now on.
+    ///
+    /// See the [`recording`](crate::recording) module.
+    pub fn record_to(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
     /// Enumerate all known base tables.
     ///
     /// These have all been created in response to a `CREATE TABLE` statement in a recipe.
@@ -270,6 +413,8 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         assert_infrequent::at_most(200);
 
         let views = self.views.clone();
+        let tls = self.tls.clone();
+        let recorder = self.recorder.clone();
         let name = name.to_string();
         self.handle
             .call(ControllerRequest::new("view_builder", &name).unwrap())
@@ -277,7 +422,15 @@ impl<A: Authority + 'static> ControllerHandle<A> {
             .and_then(move |body: hyper::Chunk| {
                 match serde_json::from_slice::<Option<ViewBuilder>>(&body) {
                     Ok(Some(vb)) => {
-                        future::Either::A(vb.build(views).map_err(failure::Error::from))
+                        let name = name.clone();
+                        future::Either::A(vb.build(views, tls).map_err(failure::Error::from).map(
+                            move |mut view| {
+                                if let Some(recorder) = recorder {
+                                    view.record_to(recorder, name);
+                                }
+                                view
+                            },
+                        ))
                     }
                     Ok(None) => {
                         future::Either::B(future::err(failure::err_msg("view does not exist")))
@@ -300,6 +453,9 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         assert_infrequent::at_most(200);
 
         let domains = self.domains.clone();
+        let tls = self.tls.clone();
+        let recorder = self.recorder.clone();
+        let propagate = self.propagate();
         let name = name.to_string();
         self.handle
             .call(ControllerRequest::new("table_builder", &name).unwrap())
@@ -307,9 +463,16 @@ impl<A: Authority + 'static> ControllerHandle<A> {
             .and_then(move |body: hyper::Chunk| {
                 match serde_json::from_slice::<Option<TableBuilder>>(&body) {
                     Ok(Some(tb)) => future::Either::A(
-                        tb.build(domains)
+                        tb.build(domains, tls)
                             .into_future()
-                            .map_err(failure::Error::from),
+                            .map_err(failure::Error::from)
+                            .map(move |mut table| {
+                                if let Some(recorder) = recorder {
+                                    table.record_to(recorder);
+                                }
+                                table.propagate_with(propagate);
+                                table
+                            }),
                     ),
                     Ok(None) => {
                         future::Either::B(future::err(failure::err_msg("view table not exist")))
@@ -320,6 +483,26 @@ impl<A: Authority + 'static> ControllerHandle<A> {
             })
     }
 
+    /// Wait for views to reflect the writes a base table has applied by taking a snapshot, and
+    /// waiting for every view computed from the table to reach it.
+    fn propagate(&self) -> Propagate {
+        let controller = Mutex::new(self.clone());
+        let views = self.views.clone();
+        let tls = self.tls.clone();
+        Arc::new(move |table: &str| {
+            let views = views.clone();
+            let tls = tls.clone();
+            let table = table.to_owned();
+            let propagation = controller.lock().unwrap().rpc(
+                "propagation",
+                &table,
+                "failed to take snapshot of writes",
+            );
+            let reflected = propagation.and_then(move |p| reflect(views, tls, &table, p));
+            Box::new(reflected) as Box<Future<Item = (), Error = failure::Error> + Send>
+        })
+    }
+
     // TODO: we can't use impl Trait here, because it would assume that the returned future is tied
     // to the lifetime of Q, which is not the case. existential types fix this issue.
     #[doc(hidden)]
@@ -353,6 +536,276 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         self.rpc("get_statistics", (), "failed to get stats")
     }
 
+    /// Get the number of nodes, and the bytes of state held by those nodes, that belong to each
+    /// security universe.
+    pub fn universe_usage(
+        &mut self,
+    ) -> impl Future<Item = HashMap<String, (usize, u64)>, Error = failure::Error> + Send {
+        self.rpc("universe_usage", (), "failed to get universe usage")
+    }
+
+    /// Submit a group of writes to several base tables, which views will apply all at once.
+    ///
+    /// The writes go straight to the workers that host the base tables, and the controller only
+    /// marks where the group starts and ends. The base tables hold on to the writes until the
+    /// group ends. If a base table refuses any of them, the group is aborted, none of its writes
+    /// are applied, and the returned future resolves with the error. Otherwise it resolves once
+    /// the group has ended. To wait for the writes to reach the views, take a snapshot afterwards
+    /// and read at it.
+    pub fn write_group(
+        &mut self,
+        group: WriteGroup,
+    ) -> impl Future<Item = (), Error = failure::Error> + Send {
+        let mut close = self.clone();
+        self.rpc::<_, u64>("open_write_group", (), "failed to open write group")
+            .and_then(move |id| {
+                group.send(id).then(move |sent| {
+                    // end the group even if some of it failed, or views would hold back all other
+                    // writes for good
+                    let abort = sent.is_err();
+                    close
+                        .rpc::<_, ()>(
+                            "close_write_group",
+                            (id, abort),
+                            "failed to close write group",
+                        )
+                        .and_then(move |()| sent.map_err(failure::Error::from))
+                })
+            })
+    }
+
+    /// Take a snapshot of the writes that the base tables have processed so far.
+    ///
+    /// Lookups on views that are set to the returned token with `View::set_snapshot` reflect
+    /// every one of those writes and none that came later, so views that are read together at the
+    /// same snapshot agree with each other.
+    pub fn snapshot(&mut self) -> impl Future<Item = SnapshotToken, Error = failure::Error> + Send {
+        self.rpc("snapshot", (), "failed to take snapshot")
+    }
+
+    /// Take a copy of all the rows of the view `name` that reflects the same writes on every one
+    /// of its shards.
+    ///
+    /// Reading each shard of a sharded view in turn may see some shards before a write and others
+    /// after it. Instead, a barrier is sent through the data-flow, each shard keeps its rows as of
+    /// when the barrier reaches it, and the returned future resolves with all of them once every
+    /// shard has. Only views that are fully materialized can be exported.
+    pub fn export_view(
+        &mut self,
+        name: &str,
+    ) -> impl Future<Item = Vec<Vec<DataType>>, Error = failure::Error> + Send {
+        let views = self.views.clone();
+        let tls = self.tls.clone();
+        self.rpc::<_, (u64, ViewBuilder)>("export_view", name, "failed to export view")
+            .and_then(move |(id, vb)| {
+                vb.build(views, tls)
+                    .map_err(failure::Error::from)
+                    .and_then(move |view| view.export(id).map_err(|e| e.error.into()))
+                    .map(|(_, rows)| rows)
+            })
+    }
+
+    /// Inject a latency probe at every base table, and return its identifier.
+    ///
+    /// The probe travels through the data-flow like a write would, but leaves the contents of
+    /// every view unchanged. See [`ControllerHandle::probe_latencies`].
+    pub fn probe(&mut self) -> impl Future<Item = u64, Error = failure::Error> + Send {
+        self.rpc("probe", (), "failed to inject latency probe")
+    }
+
+    /// Get how long the latency probe `id` took to reach each view it has reached.
+    ///
+    /// Latencies are measured from the controller's clock to the clocks of the workers that host
+    /// the views, so they are only as accurate as those clocks are in sync.
+    pub fn probe_latencies(
+        &mut self,
+        id: u64,
+    ) -> impl Future<Item = HashMap<String, Duration>, Error = failure::Error> + Send {
+        self.rpc("probe_latencies", id, "failed to get probe latencies")
+    }
+
+    /// Check that the global invariants of the data-flow graph hold, and return any that do not.
+    ///
+    /// This is cheap, and does not involve the domains, but only reflects what the controller
+    /// believes the graph looks like.
+    pub fn check_invariants(
+        &mut self,
+    ) -> impl Future<Item = Vec<Violation>, Error = failure::Error> + Send {
+        self.rpc("check_invariants", (), "failed to check invariants")
+    }
+
+    /// Have every domain write the packets it last handled to disk.
+    ///
+    /// This does nothing unless the server was started with packet capture enabled.
+    pub fn dump_packets(&mut self) -> impl Future<Item = (), Error = failure::Error> + Send {
+        self.rpc("dump_packets", (), "failed to dump captured packets")
+    }
+
+    /// Change settings of the running deployment without restarting it.
+    ///
+    /// The new settings are checked before any of them are applied, and are in effect on every
+    /// worker and domain once this resolves.
+    pub fn update_config(
+        &mut self,
+        update: ConfigUpdate,
+    ) -> impl Future<Item = (), Error = failure::Error> + Send {
+        self.rpc("update_config", update, "failed to update configuration")
+    }
+
+    /// List the workers that have registered with the controller.
+    pub fn workers(
+        &mut self,
+    ) -> impl Future<Item = Vec<WorkerInfo>, Error = failure::Error> + Send {
+        self.rpc("workers", (), "failed to list workers")
+    }
+
+    /// List the domains of the dataflow graph, along with the workers their shards run on.
+    pub fn domains(
+        &mut self,
+    ) -> impl Future<Item = Vec<DomainInfo>, Error = failure::Error> + Send {
+        self.rpc("domains", (), "failed to list domains")
+    }
+
+    /// List the base tables and views, along with their columns.
+    pub fn catalog(&mut self) -> impl Future<Item = Catalog, Error = failure::Error> + Send {
+        self.rpc("catalog", (), "failed to get catalog")
+    }
+
+    /// Get the migrations that the controller has not yet finished.
+    pub fn migrations(
+        &mut self,
+    ) -> impl Future<Item = MigrationStatus, Error = failure::Error> + Send {
+        self.rpc("migrations", (), "failed to get migration status")
+    }
+
+    /// List the views that have stopped changing because an operator they read from emitted far
+    /// more records than it was given, such as a join that is missing its join condition.
+    ///
+    /// Operators are only suspended if the deployment was started with a maximum fanout. The
+    /// views stay as they were when the operator was suspended until their queries are removed.
+    pub fn suspended_views(
+        &mut self,
+    ) -> impl Future<Item = Vec<String>, Error = failure::Error> + Send {
+        self.rpc("suspended_views", (), "failed to list suspended views")
+    }
+
+    /// Stop placing domains on the worker at `worker`, and move the queries that run there
+    /// elsewhere.
+    ///
+    /// Domains that hold base tables stay on the worker, since their state cannot be moved.
+    pub fn drain_worker(
+        &mut self,
+        worker: SocketAddr,
+    ) -> impl Future<Item = MoveReport, Error = failure::Error> + Send {
+        self.rpc("drain_worker", worker, "failed to drain worker")
+    }
+
+    /// Move the queries that run on workers with more than their share of domain shards, so that
+    /// the shards are spread across the workers in proportion to their capacity again.
+    pub fn rebalance(&mut self) -> impl Future<Item = MoveReport, Error = failure::Error> + Send {
+        self.rpc("rebalance", (), "failed to rebalance domains")
+    }
+
+    /// Work out where domains would be placed if the workers changed as `change` says, along with
+    /// how much state would have to move, without changing anything.
+    pub fn plan_workers(
+        &mut self,
+        change: WorkerChange,
+    ) -> impl Future<Item = PlacementPlan, Error = failure::Error> + Send {
+        self.rpc("plan_workers", change, "failed to plan worker change")
+    }
+
+    /// List the replay paths that fill the state of the view `name`, and of the nodes that it
+    /// reads from.
+    ///
+    /// A miss in a partially materialized view is filled by a replay along its paths, and each
+    /// miss along the way in turn by a replay along the paths of the node that missed. Paths that
+    /// cross many domains make for slow misses.
+    pub fn replay_paths(
+        &mut self,
+        name: &str,
+    ) -> impl Future<Item = Vec<ReplayPath>, Error = failure::Error> + Send {
+        self.rpc("replay_paths", name, "failed to get replay paths")
+    }
+
+    /// Have workers that are over their memory limit keep `weight` times as much of the state of
+    /// the view `name` as they do of other views before they evict from it.
+    ///
+    /// Views start out with a weight of 1. The weight sticks to the view's name, so it carries
+    /// over to the view's reader if the recipe changes it.
+    pub fn set_eviction_weight(
+        &mut self,
+        name: &str,
+        weight: u32,
+    ) -> impl Future<Item = (), Error = failure::Error> + Send {
+        self.rpc(
+            "set_eviction_weight",
+            (name, weight),
+            "failed to set eviction weight",
+        )
+    }
+
+    /// Have the view `name` hold on to the writes that reach it for up to `every` before it makes
+    /// them visible to lookups, or make them visible right away if `every` is `None`.
+    ///
+    /// Making writes visible has a cost that is paid once for every batch of writes, however
+    /// small, so a view that is written to often can take writes faster if it makes them visible
+    /// less often, at the cost of lookups seeing them later. Views make writes visible right away
+    /// unless told otherwise. The interval sticks to the view's name, so it carries over to the
+    /// view's reader if the recipe changes it.
+    pub fn set_publish_interval(
+        &mut self,
+        name: &str,
+        every: Option<Duration>,
+    ) -> impl Future<Item = (), Error = failure::Error> + Send {
+        self.rpc(
+            "set_publish_interval",
+            (name, every),
+            "failed to set publish interval",
+        )
+    }
+
+    /// Have the controller parse and plan the statement `sql`, so that it can be executed many
+    /// times with [`ControllerHandle::execute`] without being parsed again.
+    ///
+    /// A `SELECT` whose query is not in the recipe yet is added to it under a name of its own. Its
+    /// `?` parameters must be compared with the columns that its view is keyed by.
+    ///
+    /// Since preparing a statement can change the recipe, it needs an admin token on deployments
+    /// that require authentication, even for statements that only read or write.
+    pub fn prepare(
+        &mut self,
+        sql: &str,
+    ) -> impl Future<Item = PreparedStatement, Error = failure::Error> + Send {
+        let mut handle = self.clone();
+        self.rpc("prepare", sql, "failed to prepare statement")
+            .and_then(move |plan: StatementPlan| match plan.route {
+                Route::Lookup { ref view, .. } => future::Either::A(
+                    handle
+                        .view(view)
+                        .map(move |view| PreparedStatement::new(plan, Target::View(view))),
+                ),
+                Route::Insert { ref table, .. }
+                | Route::Update { ref table, .. }
+                | Route::Delete { ref table, .. } => future::Either::B(
+                    handle
+                        .table(table)
+                        .map(move |table| PreparedStatement::new(plan, Target::Table(table))),
+                ),
+            })
+    }
+
+    /// Execute the prepared `statement` with the values in `params` for its parameters, in order.
+    ///
+    /// Parameters are converted to the types of the columns they are compared with or written to.
+    pub fn execute(
+        &mut self,
+        statement: &PreparedStatement,
+        params: &[DataType],
+    ) -> impl Future<Item = Executed, Error = failure::Error> + Send {
+        statement.execute(params)
+    }
+
     /// Flush all partial state, evicting all rows present.
     pub fn flush_partial(&mut self) -> impl Future<Item = (), Error = failure::Error> + Send {
         self.rpc("flush_partial", (), "failed to flush partial")
@@ -366,6 +819,69 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         self.rpc("extend_recipe", recipe_addition, "failed to extend recipe")
     }
 
+    /// Queue an extension of the recipe to be applied once the migrations submitted before it that
+    /// it conflicts with have been, and return a ticket to follow its progress with.
+    ///
+    /// Queued migrations that neither define nor read from the tables and queries that others
+    /// define are applied in one go. See [`ControllerHandle::submitted_migration`].
+    pub fn submit_migration(
+        &mut self,
+        recipe_addition: &str,
+    ) -> impl Future<Item = u64, Error = failure::Error> + Send {
+        self.rpc(
+            "submit_migration",
+            recipe_addition,
+            "failed to submit migration",
+        )
+    }
+
+    /// Get how far the migration that was given `ticket` when it was submitted has got.
+    pub fn submitted_migration(
+        &mut self,
+        ticket: u64,
+    ) -> impl Future<Item = SubmittedMigration, Error = failure::Error> + Send {
+        self.rpc(
+            "submitted_migration",
+            ticket,
+            "failed to get migration status",
+        )
+    }
+
+    /// Get how far the steps of a migration have got.
+    ///
+    /// With a `ticket`, this is the migration that was given it when it was submitted, or the
+    /// batch of submitted migrations that it is applied in one go with. Without one, it is the
+    /// migration the controller is applying now, or the last one it applied. The controller
+    /// answers this while a migration is running, so it can be polled to show how far a schema
+    /// change has got. Returns `None` if there is no such migration.
+    pub fn migration_progress(
+        &mut self,
+        ticket: Option<u64>,
+    ) -> impl Future<Item = Option<MigrationProgress>, Error = failure::Error> + Send {
+        self.rpc(
+            "migration_progress",
+            ticket,
+            "failed to get migration progress",
+        )
+    }
+
+    /// Get a handle to follow the submitted migration that was given `ticket`.
+    pub fn migration(&self, ticket: u64) -> MigrationHandle<A> {
+        MigrationHandle {
+            handle: self.clone(),
+            ticket,
+        }
+    }
+
+    /// Work out what extending the recipe with `recipe_addition` would change in the running
+    /// graph, without changing it.
+    pub fn plan_recipe(
+        &mut self,
+        recipe_addition: &str,
+    ) -> impl Future<Item = MigrationPlan, Error = failure::Error> + Send {
+        self.rpc("plan_recipe", recipe_addition, "failed to plan recipe")
+    }
+
     /// Replace the existing recipe with this one.
     pub fn install_recipe(
         &mut self,
@@ -374,6 +890,74 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         self.rpc("install_recipe", new_recipe, "failed to install recipe")
     }
 
+    /// Undo the changes made to the recipe since it was at `version`.
+    ///
+    /// The nodes, readers, and domains added since then are torn down, and the queries removed
+    /// since then are added back. Only versions that the recipe has been at since it was last
+    /// installed can be rolled back to.
+    pub fn rollback_migration(
+        &mut self,
+        version: usize,
+    ) -> impl Future<Item = ActivationResult, Error = failure::Error> + Send {
+        self.rpc(
+            "rollback_migration",
+            version,
+            "failed to roll back migration",
+        )
+    }
+
+    /// Remove the query `name` from the recipe.
+    ///
+    /// Its reader, and any operators and domains that no other query uses, are torn down.
+    pub fn remove_query(
+        &mut self,
+        name: &str,
+    ) -> impl Future<Item = ActivationResult, Error = failure::Error> + Send {
+        self.rpc("remove_query", name, "failed to remove query")
+    }
+
+    /// Try out the recipe `proposed` in the shadow of the existing one.
+    ///
+    /// The queries that `proposed` adds or changes are installed next to the live queries under
+    /// different names, so that they process the same writes without serving reads. Once they
+    /// have run for `soak`, the shadow can be promoted, which installs `proposed` in place of the
+    /// existing recipe. Until the shadow is promoted or discarded, the recipe cannot be changed
+    /// in other ways.
+    pub fn shadow_recipe(
+        &mut self,
+        proposed: &str,
+        soak: Duration,
+    ) -> impl Future<Item = ActivationResult, Error = failure::Error> + Send {
+        self.rpc(
+            "shadow_recipe",
+            (proposed, soak),
+            "failed to start shadow migration",
+        )
+    }
+
+    /// Get the cost of the running shadow migration.
+    pub fn shadow_report(
+        &mut self,
+    ) -> impl Future<Item = ShadowReport, Error = failure::Error> + Send {
+        self.rpc("shadow_report", (), "failed to get shadow report")
+    }
+
+    /// Install the recipe that is running in the shadow in place of the existing one.
+    ///
+    /// Fails if the shadow has not yet soaked for as long as was asked for.
+    pub fn promote_shadow(
+        &mut self,
+    ) -> impl Future<Item = ActivationResult, Error = failure::Error> + Send {
+        self.rpc("promote_shadow", (), "failed to promote shadow")
+    }
+
+    /// Remove the shadow queries, leaving the existing recipe as it was.
+    pub fn discard_shadow(
+        &mut self,
+    ) -> impl Future<Item = ActivationResult, Error = failure::Error> + Send {
+        self.rpc("discard_shadow", (), "failed to discard shadow")
+    }
+
     /// Fetch a graphviz description of the dataflow graph.
     pub fn graphviz(&mut self) -> impl Future<Item = String, Error = failure::Error> + Send {
         self.rpc("graphviz", (), "failed to fetch graphviz output")
@@ -413,6 +997,45 @@ impl<A: Authority + 'static> ControllerHandle<A> {
     }
 }
 
+/// A handle to a migration that was submitted to the controller's queue.
+///
+/// See [`ControllerHandle::migration`].
+pub struct MigrationHandle<A>
+where
+    A: 'static + Authority,
+{
+    handle: ControllerHandle<A>,
+    ticket: u64,
+}
+
+impl<A: Authority + 'static> MigrationHandle<A> {
+    /// The ticket the migration was given when it was submitted.
+    pub fn ticket(&self) -> u64 {
+        self.ticket
+    }
+
+    /// Get how far the steps of the migration have got.
+    ///
+    /// See [`ControllerHandle::migration_progress`].
+    pub fn progress(
+        &mut self,
+    ) -> impl Future<Item = MigrationProgress, Error = failure::Error> + Send {
+        let ticket = self.ticket;
+        self.handle
+            .migration_progress(Some(ticket))
+            .and_then(move |p| p.ok_or_else(|| format_err!("no migration with ticket {}", ticket)))
+    }
+
+    /// Get whether the migration is queued, was applied, or failed.
+    ///
+    /// See [`ControllerHandle::submitted_migration`].
+    pub fn status(
+        &mut self,
+    ) -> impl Future<Item = SubmittedMigration, Error = failure::Error> + Send {
+        self.handle.submitted_migration(self.ticket)
+    }
+}
+
 /// A synchronous handle to a Noria controller.
 ///
 /// This handle lets you interact with a Noria controller without thinking about asynchrony.
@@ -449,6 +1072,19 @@ where
             executor,
         )
     }
+
+    /// Connect to the deployment named `deployment` among those that share the ZooKeeper ensemble
+    /// at the given address.
+    pub fn from_deployment(
+        zookeeper_address: &str,
+        deployment: &str,
+        executor: E,
+    ) -> Result<Self, failure::Error> {
+        Self::new(
+            consensus::ZookeeperAuthority::for_deployment(zookeeper_address, deployment)?,
+            executor,
+        )
+    }
 }
 
 impl<A, E> SyncControllerHandle<A, E>
@@ -460,11 +1096,28 @@ where
     /// stored in the given `authority`.
     ///
     /// You *probably* want to use `SyncControllerHandle::from_zk` instead.
-    pub fn new(authority: A, mut executor: E) -> Result<Self, failure::Error>
+    pub fn new(authority: A, executor: E) -> Result<Self, failure::Error>
     where
         A: Send + 'static,
     {
-        let fut = ControllerHandle::new(authority);
+        Self::start(ControllerHandle::new(authority), executor)
+    }
+
+    /// Like `SyncControllerHandle::new`, but authenticates every request to the controller with
+    /// the given API token.
+    ///
+    /// See [`ControllerHandle::new_with_token`].
+    pub fn new_with_token(authority: A, token: &str, executor: E) -> Result<Self, failure::Error>
+    where
+        A: Send + 'static,
+    {
+        Self::start(ControllerHandle::new_with_token(authority, token), executor)
+    }
+
+    fn start<F>(fut: F, mut executor: E) -> Result<Self, failure::Error>
+    where
+        F: Future<Item = ControllerHandle<A>, Error = failure::Error> + Send + 'static,
+    {
         let (tx, rx) = futures::sync::oneshot::channel();
         executor
             .spawn(Box::new(
@@ -507,6 +1160,198 @@ where
         self.run(fut)
     }
 
+    /// Get the resources used by each security universe.
+    ///
+    /// See [`ControllerHandle::universe_usage`].
+    pub fn universe_usage(&mut self) -> Result<HashMap<String, (usize, u64)>, failure::Error> {
+        let fut = self.handle.universe_usage();
+        self.run(fut)
+    }
+
+    /// Measure how long it takes for writes to propagate from the base tables to each view.
+    ///
+    /// Injects a latency probe, and waits for up to `timeout` for it to reach every view. Views
+    /// that the probe has not reached by then are left out. See
+    /// [`ControllerHandle::probe_latencies`].
+    pub fn propagation_latencies(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<HashMap<String, Duration>, failure::Error> {
+        let views = self.outputs()?;
+        let fut = self.handle.probe();
+        let id = self.run(fut)?;
+        let start = Instant::now();
+        loop {
+            let fut = self.handle.probe_latencies(id);
+            let latencies = self.run(fut)?;
+            if views.keys().all(|v| latencies.contains_key(v)) || start.elapsed() >= timeout {
+                return Ok(latencies);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Submit a group of writes to several base tables, which views will apply all at once.
+    ///
+    /// See [`ControllerHandle::write_group`].
+    pub fn write_group(&mut self, group: WriteGroup) -> Result<(), failure::Error> {
+        let fut = self.handle.write_group(group);
+        self.run(fut)
+    }
+
+    /// Take a snapshot of the writes that the base tables have processed so far.
+    ///
+    /// See [`ControllerHandle::snapshot`].
+    pub fn snapshot(&mut self) -> Result<SnapshotToken, failure::Error> {
+        let fut = self.handle.snapshot();
+        self.run(fut)
+    }
+
+    /// Take a copy of all the rows of a view that reflects the same writes on every shard.
+    ///
+    /// See [`ControllerHandle::export_view`].
+    pub fn export_view(&mut self, name: &str) -> Result<Vec<Vec<DataType>>, failure::Error> {
+        let fut = self.handle.export_view(name);
+        self.run(fut)
+    }
+
+    /// Check that the global invariants of the data-flow graph hold.
+    ///
+    /// See [`ControllerHandle::check_invariants`].
+    pub fn check_invariants(&mut self) -> Result<Vec<Violation>, failure::Error> {
+        let fut = self.handle.check_invariants();
+        self.run(fut)
+    }
+
+    /// Have every domain write the packets it last handled to disk.
+    ///
+    /// See [`ControllerHandle::dump_packets`].
+    pub fn dump_packets(&mut self) -> Result<(), failure::Error> {
+        let fut = self.handle.dump_packets();
+        self.run(fut)
+    }
+
+    /// Change settings of the running deployment without restarting it.
+    ///
+    /// See [`ControllerHandle::update_config`].
+    pub fn update_config(&mut self, update: ConfigUpdate) -> Result<(), failure::Error> {
+        let fut = self.handle.update_config(update);
+        self.run(fut)
+    }
+
+    /// List the workers that have registered with the controller.
+    ///
+    /// See [`ControllerHandle::workers`].
+    pub fn workers(&mut self) -> Result<Vec<WorkerInfo>, failure::Error> {
+        let fut = self.handle.workers();
+        self.run(fut)
+    }
+
+    /// List the domains of the dataflow graph, along with the workers their shards run on.
+    ///
+    /// See [`ControllerHandle::domains`].
+    pub fn domains(&mut self) -> Result<Vec<DomainInfo>, failure::Error> {
+        let fut = self.handle.domains();
+        self.run(fut)
+    }
+
+    /// List the base tables and views, along with their columns.
+    ///
+    /// See [`ControllerHandle::catalog`].
+    pub fn catalog(&mut self) -> Result<Catalog, failure::Error> {
+        let fut = self.handle.catalog();
+        self.run(fut)
+    }
+
+    /// Get the migrations that the controller has not yet finished.
+    ///
+    /// See [`ControllerHandle::migrations`].
+    pub fn migrations(&mut self) -> Result<MigrationStatus, failure::Error> {
+        let fut = self.handle.migrations();
+        self.run(fut)
+    }
+
+    /// List the views that have stopped changing because an operator they read from was suspended.
+    ///
+    /// See [`ControllerHandle::suspended_views`].
+    pub fn suspended_views(&mut self) -> Result<Vec<String>, failure::Error> {
+        let fut = self.handle.suspended_views();
+        self.run(fut)
+    }
+
+    /// Stop placing domains on a worker, and move the queries that run there elsewhere.
+    ///
+    /// See [`ControllerHandle::drain_worker`].
+    pub fn drain_worker(&mut self, worker: SocketAddr) -> Result<MoveReport, failure::Error> {
+        let fut = self.handle.drain_worker(worker);
+        self.run(fut)
+    }
+
+    /// Spread domain shards across the workers in proportion to their capacity again.
+    ///
+    /// See [`ControllerHandle::rebalance`].
+    pub fn rebalance(&mut self) -> Result<MoveReport, failure::Error> {
+        let fut = self.handle.rebalance();
+        self.run(fut)
+    }
+
+    /// List the replay paths that fill the state of a view, and of the nodes that it reads from.
+    ///
+    /// See [`ControllerHandle::replay_paths`].
+    pub fn replay_paths(&mut self, name: &str) -> Result<Vec<ReplayPath>, failure::Error> {
+        let fut = self.handle.replay_paths(name);
+        self.run(fut)
+    }
+
+    /// Have workers keep more or less of the state of a view than of others when they evict.
+    ///
+    /// See [`ControllerHandle::set_eviction_weight`].
+    pub fn set_eviction_weight(&mut self, name: &str, weight: u32) -> Result<(), failure::Error> {
+        let fut = self.handle.set_eviction_weight(name, weight);
+        self.run(fut)
+    }
+
+    /// Have a view make the writes that reach it visible less often, so that it takes writes
+    /// faster.
+    ///
+    /// See [`ControllerHandle::set_publish_interval`].
+    pub fn set_publish_interval(
+        &mut self,
+        name: &str,
+        every: Option<Duration>,
+    ) -> Result<(), failure::Error> {
+        let fut = self.handle.set_publish_interval(name, every);
+        self.run(fut)
+    }
+
+    /// Work out where domains would be placed if the workers changed, without changing anything.
+    ///
+    /// See [`ControllerHandle::plan_workers`].
+    pub fn plan_workers(&mut self, change: WorkerChange) -> Result<PlacementPlan, failure::Error> {
+        let fut = self.handle.plan_workers(change);
+        self.run(fut)
+    }
+
+    /// Parse and plan a statement, so that it can be executed many times.
+    ///
+    /// See [`ControllerHandle::prepare`].
+    pub fn prepare(&mut self, sql: &str) -> Result<PreparedStatement, failure::Error> {
+        let fut = self.handle.prepare(sql);
+        self.run(fut)
+    }
+
+    /// Execute a prepared statement.
+    ///
+    /// See [`ControllerHandle::execute`].
+    pub fn execute(
+        &mut self,
+        statement: &PreparedStatement,
+        params: &[DataType],
+    ) -> Result<Executed, failure::Error> {
+        let fut = self.handle.execute(statement, params);
+        self.run(fut)
+    }
+
     /// Enumerate all known base tables.
     ///
     /// See [`ControllerHandle::inputs`].
@@ -561,6 +1406,144 @@ where
         self.run(fut)
     }
 
+    /// Queue an extension of the recipe.
+    ///
+    /// See [`ControllerHandle::submit_migration`].
+    pub fn submit_migration<S: AsRef<str>>(&mut self, r: S) -> Result<u64, failure::Error> {
+        let fut = self.handle.submit_migration(r.as_ref());
+        self.run(fut)
+    }
+
+    /// Get how far a submitted migration has got.
+    ///
+    /// See [`ControllerHandle::submitted_migration`].
+    pub fn submitted_migration(
+        &mut self,
+        ticket: u64,
+    ) -> Result<SubmittedMigration, failure::Error> {
+        let fut = self.handle.submitted_migration(ticket);
+        self.run(fut)
+    }
+
+    /// Get how far the steps of a migration have got.
+    ///
+    /// See [`ControllerHandle::migration_progress`].
+    pub fn migration_progress(
+        &mut self,
+        ticket: Option<u64>,
+    ) -> Result<Option<MigrationProgress>, failure::Error> {
+        let fut = self.handle.migration_progress(ticket);
+        self.run(fut)
+    }
+
+    /// Work out what extending the recipe would change, without changing it.
+    ///
+    /// See [`ControllerHandle::plan_recipe`].
+    pub fn plan_recipe<S: AsRef<str>>(&mut self, r: S) -> Result<MigrationPlan, failure::Error> {
+        let fut = self.handle.plan_recipe(r.as_ref());
+        self.run(fut)
+    }
+
+    /// Undo the changes made to the recipe since it was at `version`.
+    ///
+    /// See [`ControllerHandle::rollback_migration`].
+    pub fn rollback_migration(
+        &mut self,
+        version: usize,
+    ) -> Result<ActivationResult, failure::Error> {
+        let fut = self.handle.rollback_migration(version);
+        self.run(fut)
+    }
+
+    /// Remove a query from the recipe.
+    ///
+    /// See [`ControllerHandle::remove_query`].
+    pub fn remove_query<S: AsRef<str>>(
+        &mut self,
+        name: S,
+    ) -> Result<ActivationResult, failure::Error> {
+        let fut = self.handle.remove_query(name.as_ref());
+        self.run(fut)
+    }
+
+    /// Try out a recipe in the shadow of the existing one.
+    ///
+    /// See [`ControllerHandle::shadow_recipe`].
+    pub fn shadow_recipe<S: AsRef<str>>(
+        &mut self,
+        proposed: S,
+        soak: Duration,
+    ) -> Result<ActivationResult, failure::Error> {
+        let fut = self.handle.shadow_recipe(proposed.as_ref(), soak);
+        self.run(fut)
+    }
+
+    /// Get the cost of the running shadow migration.
+    ///
+    /// See [`ControllerHandle::shadow_report`].
+    pub fn shadow_report(&mut self) -> Result<ShadowReport, failure::Error> {
+        let fut = self.handle.shadow_report();
+        self.run(fut)
+    }
+
+    /// Compare the results of the shadow version of `query` with those of the live one for each
+    /// of `keys`, and return the keys for which they differ.
+    ///
+    /// Only queries that the shadowed recipe changes, rather than adds, can be compared.
+    pub fn compare_shadow(
+        &mut self,
+        query: &str,
+        keys: &[Vec<DataType>],
+    ) -> Result<Vec<ShadowDiff>, failure::Error> {
+        let report = self.shadow_report()?;
+        let shadow = match report.queries.get(query) {
+            Some(_) if report.added.iter().any(|q| q == query) => bail!(
+                "query {} is added by the shadow migration, and has no live version",
+                query
+            ),
+            Some(shadow) => shadow,
+            None => bail!("query {} is not changed by the shadow migration", query),
+        };
+        let mut live = self.view(query)?.into_sync();
+        let mut shadow = self.view(shadow)?.into_sync();
+
+        let mut diffs = Vec::new();
+        for key in keys {
+            let mut l = live
+                .lookup(key, true)
+                .map_err(|e| format_err!("lookup in {} failed: {:?}", query, e))?;
+            let mut s = shadow
+                .lookup(key, true)
+                .map_err(|e| format_err!("shadow lookup in {} failed: {:?}", query, e))?;
+            l.sort();
+            s.sort();
+            if l != s {
+                diffs.push(ShadowDiff {
+                    key: key.clone(),
+                    live: l,
+                    shadow: s,
+                });
+            }
+        }
+        Ok(diffs)
+    }
+
+    /// Install the recipe that is running in the shadow in place of the existing one.
+    ///
+    /// See [`ControllerHandle::promote_shadow`].
+    pub fn promote_shadow(&mut self) -> Result<ActivationResult, failure::Error> {
+        let fut = self.handle.promote_shadow();
+        self.run(fut)
+    }
+
+    /// Remove the shadow queries, leaving the existing recipe as it was.
+    ///
+    /// See [`ControllerHandle::discard_shadow`].
+    pub fn discard_shadow(&mut self) -> Result<ActivationResult, failure::Error> {
+        let fut = self.handle.discard_shadow();
+        self.run(fut)
+    }
+
     /// Fetch a graphviz description of the dataflow graph.
     ///
     /// See [`ControllerHandle::graphviz`].
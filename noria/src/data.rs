@@ -2,7 +2,7 @@ use arccstr::ArcCStr;
 
 use chrono::{self, NaiveDateTime};
 
-use nom_sql::Literal;
+use nom_sql::{Literal, SqlType};
 
 use std::fmt;
 use std::hash::{Hash, Hasher};
@@ -135,6 +135,90 @@ impl DataType {
             _ => false,
         }
     }
+
+    /// Checks if a column declared as `ty` can hold this value as it is.
+    pub fn fits(&self, ty: &SqlType) -> bool {
+        match *self {
+            DataType::None => true,
+            DataType::Int(_) | DataType::BigInt(_) => match *ty {
+                SqlType::Bool | SqlType::Tinyint(_) | SqlType::Int(_) | SqlType::Bigint(_) => true,
+                SqlType::Double | SqlType::Float | SqlType::Real | SqlType::Decimal(..) => true,
+                _ => false,
+            },
+            DataType::Real(..) => match *ty {
+                SqlType::Double | SqlType::Float | SqlType::Real | SqlType::Decimal(..) => true,
+                _ => false,
+            },
+            DataType::Text(_) | DataType::TinyText(_) => match *ty {
+                SqlType::Char(_) | SqlType::Varchar(_) | SqlType::Enum(_) => true,
+                SqlType::Tinytext | SqlType::Text | SqlType::Mediumtext | SqlType::Longtext => true,
+                SqlType::Tinyblob | SqlType::Blob | SqlType::Mediumblob | SqlType::Longblob => true,
+                SqlType::Binary(_) | SqlType::Varbinary(_) => true,
+                _ => false,
+            },
+            DataType::Timestamp(_) => match *ty {
+                SqlType::Date | SqlType::DateTime | SqlType::Timestamp => true,
+                _ => false,
+            },
+        }
+    }
+
+    /// Convert this value into one that a column declared as `ty` can hold, losing precision if
+    /// need be. Values that cannot be converted at all become `DataType::None`.
+    pub fn coerce_to(&self, ty: &SqlType) -> DataType {
+        if self.fits(ty) {
+            return self.clone();
+        }
+
+        let text: Option<Cow<str>> = if self.is_string() {
+            Some(self.into())
+        } else {
+            None
+        };
+        let text = text.as_ref().map(|t| t.trim());
+        match *ty {
+            SqlType::Bool | SqlType::Tinyint(_) | SqlType::Int(_) | SqlType::Bigint(_) => {
+                match *self {
+                    DataType::Real(i, _) => DataType::from(i),
+                    DataType::Timestamp(ts) => DataType::from(ts.timestamp()),
+                    _ => text
+                        .and_then(|t| {
+                            t.parse::<i64>()
+                                .ok()
+                                .or_else(|| t.parse::<f64>().ok().map(|f| f.trunc() as i64))
+                        })
+                        .map(DataType::from)
+                        .unwrap_or(DataType::None),
+                }
+            }
+            SqlType::Double | SqlType::Float | SqlType::Real | SqlType::Decimal(..) => text
+                .and_then(|t| t.parse::<f64>().ok())
+                .filter(|f| f.is_finite())
+                .map(DataType::from)
+                .unwrap_or(DataType::None),
+            SqlType::Date | SqlType::DateTime | SqlType::Timestamp => {
+                let parsed = text.and_then(|t| {
+                    NaiveDateTime::parse_from_str(t, "%Y-%m-%d %H:%M:%S")
+                        .or_else(|_| {
+                            chrono::NaiveDate::parse_from_str(t, "%Y-%m-%d")
+                                .map(|d| d.and_hms(0, 0, 0))
+                        })
+                        .ok()
+                });
+                let parsed = match *self {
+                    DataType::Int(i) => NaiveDateTime::from_timestamp_opt(i64::from(i), 0),
+                    DataType::BigInt(i) => NaiveDateTime::from_timestamp_opt(i, 0),
+                    _ => parsed,
+                };
+                parsed.map(DataType::Timestamp).unwrap_or(DataType::None)
+            }
+            _ if DataType::from("").fits(ty) => match *self {
+                DataType::Timestamp(ts) => ts.format("%Y-%m-%d %H:%M:%S").to_string().into(),
+                ref v => v.to_string().into(),
+            },
+            _ => DataType::None,
+        }
+    }
 }
 
 impl PartialEq for DataType {
@@ -611,6 +695,28 @@ mod tests {
         assert_eq!(&DataType::BigInt(4) / &DataType::from(2), 2.into());
     }
 
+    #[test]
+    fn coerce_data_types() {
+        use nom_sql::SqlType;
+
+        assert!(DataType::from(1).fits(&SqlType::Bigint(64)));
+        assert!(DataType::None.fits(&SqlType::Text));
+
+        let int = SqlType::Int(32);
+        assert!(!DataType::from("1").fits(&int));
+        assert_eq!(DataType::from(" 12 ").coerce_to(&int), 12.into());
+        assert_eq!(DataType::from("2.5").coerce_to(&int), 2.into());
+        assert_eq!(DataType::from(2.5).coerce_to(&int), 2.into());
+        assert_eq!(DataType::from("two").coerce_to(&int), DataType::None);
+        let real = DataType::from("2.5").coerce_to(&SqlType::Real);
+        assert_eq!(real, (2.5).into());
+        assert_eq!(DataType::from(12).coerce_to(&SqlType::Text), "12".into());
+        assert_eq!(
+            DataType::from("2019-03-01").coerce_to(&SqlType::Timestamp),
+            DataType::Timestamp(chrono::NaiveDate::from_ymd(2019, 3, 1).and_hms(0, 0, 0))
+        );
+    }
+
     #[test]
     #[should_panic(expected = "can't + a TinyText(\"hi\") and Int(5)")]
     fn add_invalid_types() {
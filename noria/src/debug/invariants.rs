@@ -0,0 +1,75 @@
+use crate::internal::*;
+use petgraph::graph::NodeIndex;
+use std::fmt;
+
+/// A global invariant of the data-flow graph that does not hold.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Violation {
+    /// An edge crosses from one domain to another without going from an egress or a sharder node
+    /// to an ingress node.
+    UnroutedEdge {
+        /// The parent end of the edge.
+        from: NodeIndex,
+        /// The child end of the edge.
+        to: NodeIndex,
+    },
+    /// An ingress node is not fed by an egress or a sharder node in another domain.
+    OrphanedIngress(NodeIndex),
+    /// A node is split into a different number of shards than the domain it is in.
+    ShardingMismatch {
+        /// The node.
+        node: NodeIndex,
+        /// The domain the node is in.
+        domain: DomainIndex,
+        /// How many shards the node is split into.
+        shards: usize,
+        /// How many shards the domain is split into.
+        domain_shards: usize,
+    },
+    /// A reader node has no key that lookups can be made by.
+    UnkeyedReader(NodeIndex),
+    /// The controller's record of which local index a node has in its domain disagrees with the
+    /// node itself.
+    RemapMismatch {
+        /// The node.
+        node: NodeIndex,
+        /// The domain the node is in.
+        domain: DomainIndex,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::UnroutedEdge { from, to } => write!(
+                f,
+                "edge from n{} to n{} crosses domains without an egress and ingress",
+                from.index(),
+                to.index()
+            ),
+            Violation::OrphanedIngress(n) => {
+                write!(f, "ingress n{} has no egress or sharder parent", n.index())
+            }
+            Violation::ShardingMismatch {
+                node,
+                domain,
+                shards,
+                domain_shards,
+            } => write!(
+                f,
+                "n{} has {} shards, but its domain {} has {}",
+                node.index(),
+                shards,
+                domain.index(),
+                domain_shards
+            ),
+            Violation::UnkeyedReader(n) => write!(f, "reader n{} has no key", n.index()),
+            Violation::RemapMismatch { node, domain } => write!(
+                f,
+                "local index of n{} disagrees with the remap table of domain {}",
+                node.index(),
+                domain.index()
+            ),
+        }
+    }
+}
@@ -1,3 +1,6 @@
+/// Types related to checking the consistency of the data-flow graph.
+pub mod invariants;
+
 /// Types related to graph statistics.
 pub mod stats;
 
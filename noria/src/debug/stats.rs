@@ -3,6 +3,7 @@ use crate::MaterializationStatus;
 use petgraph::graph::NodeIndex;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
+use std::time::SystemTime;
 
 type DomainMap = HashMap<(DomainIndex, usize), (DomainStats, HashMap<NodeIndex, NodeStats>)>;
 
@@ -17,6 +18,12 @@ pub struct DomainStats {
     pub total_ptime: u64,
     /// Total wall-clock time spent waiting for work in this domain.
     pub wait_time: u64,
+    /// The number of packets waiting to be sent to downstream domains, including any kept on disk.
+    #[serde(default)]
+    pub queue_depth: u64,
+    /// The number of client writes refused because too many packets were waiting.
+    #[serde(default)]
+    pub shed: u64,
 }
 
 /// Statistics about a node.
@@ -34,6 +41,39 @@ pub struct NodeStats {
     pub mem_size: u64,
     /// The materialization type of this node's state.
     pub materialized: MaterializationStatus,
+    /// For a reader, the identifier of the last latency probe to reach it, how many copies of that
+    /// probe have arrived, and how long the slowest of them took to get there from the base tables
+    /// it was injected at.
+    pub probe: Option<(u64, usize, u64)>,
+    /// For a reader, when each base table processed the newest write from it that reads from the
+    /// reader reflect.
+    pub freshness: HashMap<NodeIndex, SystemTime>,
+    /// Whether this node stopped processing updates because it emitted far more records than it
+    /// was given.
+    pub suspended: bool,
+    /// The owners of the named queries that this node computes a part of.
+    #[serde(default)]
+    pub owners: Vec<String>,
+    /// The tags of the named queries that this node computes a part of.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Total bytes of the records this node sent on to other domains.
+    #[serde(default)]
+    pub sent_bytes: u64,
+}
+
+/// The resources that a query has used, for charging them back to whoever runs it.
+///
+/// Each node counts towards every query that reads from it, and the resources of a node that
+/// several queries read from are split evenly between them. Times are in nanoseconds.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueryUsage {
+    /// Total thread time spent processing in the nodes of the query.
+    pub cpu_time: u64,
+    /// Total memory size of the state of the nodes of the query.
+    pub state_bytes: u64,
+    /// Total bytes of the records that the nodes of the query sent on to other domains.
+    pub network_bytes: u64,
 }
 
 /// Statistics about the Soup data-flow.
@@ -43,6 +83,9 @@ pub struct GraphStats {
     #[serde(deserialize_with = "deserialize_domainmap")]
     #[doc(hidden)]
     pub domains: DomainMap,
+    /// The resources that each query has used, by the name of the query.
+    #[serde(default)]
+    pub queries: HashMap<String, QueryUsage>,
 }
 
 use std::ops::Deref;
@@ -0,0 +1,628 @@
+//! Writing the messages of a stream, such as a Kafka topic fed by change data capture, to a base
+//! table.
+//!
+//! Messages are expected in the wire format of a Confluent Schema Registry: a zero byte, the ID of
+//! the schema the message was written with as a big-endian `u32`, and the Avro encoding of a
+//! record of that schema. An [`Ingest`] fetches each schema from the registry the first time it
+//! sees it, and matches the fields of records up with the columns of the base table by name.
+//! Columns that a record has no field for are left NULL. When a schema has fields that the table
+//! has no columns for, and they are nullable or have a default, the table gains columns for them
+//! before rows of that schema are written. Drift that the table cannot follow, such as a field that
+//! is added but is neither, or a field whose type its column cannot hold, does not stop the stream:
+//! it is logged and kept in [`Ingest::drift`], and those fields are left out of the rows that are
+//! written. Only Avro records with fields of primitive types, or unions of `null` and one
+//! primitive type, are understood. Protobuf-encoded messages are not.
+//!
+//! Consuming the stream is up to the caller, which hands batches of messages to
+//! [`Ingest::write`]. For messages to be applied exactly once, the caller instead hands them over
+//! along with where they are in the stream, with [`Ingest::write_from`], to a table that is
+//! declared with `DEDUP ON partition BY offset WINDOW n`. The partition and offset of each message
+//! are then kept in the table's rows, and are as durable as the rows are, so after a crash the
+//! caller may resume from any offset it has committed and the table drops the messages it has
+//! already applied.
+
+use crate::consensus::Authority;
+use crate::data::{DataType, TableOperation};
+use crate::table::RowError;
+use crate::{SyncControllerHandle, SyncTable};
+use failure::{self, ResultExt};
+use nom_sql::{Column, ColumnConstraint, ColumnSpecification, Literal, SqlType};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use tokio::prelude::*;
+
+/// A primitive Avro type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AvroType {
+    /// A single byte that is 0 or 1.
+    Boolean,
+    /// A 32-bit integer.
+    Int,
+    /// A 64-bit integer.
+    Long,
+    /// A 32-bit floating point number.
+    Float,
+    /// A 64-bit floating point number.
+    Double,
+    /// A sequence of bytes.
+    Bytes,
+    /// A UTF-8 string.
+    String,
+}
+
+impl AvroType {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "boolean" => AvroType::Boolean,
+            "int" => AvroType::Int,
+            "long" => AvroType::Long,
+            "float" => AvroType::Float,
+            "double" => AvroType::Double,
+            "bytes" => AvroType::Bytes,
+            "string" => AvroType::String,
+            _ => return None,
+        })
+    }
+
+    /// The type of the column that holds values of this type.
+    pub fn sql_type(self) -> SqlType {
+        match self {
+            AvroType::Boolean => SqlType::Bool,
+            AvroType::Int => SqlType::Int(32),
+            AvroType::Long => SqlType::Bigint(64),
+            AvroType::Float => SqlType::Float,
+            AvroType::Double => SqlType::Double,
+            AvroType::Bytes => SqlType::Blob,
+            AvroType::String => SqlType::Text,
+        }
+    }
+
+    /// Whether a column declared as `column` can hold values of this type.
+    pub fn fits(self, column: &SqlType) -> bool {
+        let value = match self {
+            AvroType::Boolean | AvroType::Int | AvroType::Long => DataType::from(0),
+            AvroType::Float | AvroType::Double => DataType::from(0.0),
+            AvroType::Bytes | AvroType::String => DataType::from(""),
+        };
+        value.fits(column)
+    }
+}
+
+/// A field of an Avro record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AvroField {
+    /// The name of the field.
+    pub name: String,
+    /// The type of its values.
+    pub ty: AvroType,
+    /// The branch of the field's union that is `null`, if the field is nullable.
+    pub null_branch: Option<i64>,
+    /// The value that readers give the field when the record they read lacks it.
+    pub default: Option<Literal>,
+}
+
+/// An Avro record schema.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AvroSchema {
+    /// The name of the record.
+    pub name: String,
+    /// Its fields, in the order they are encoded in.
+    pub fields: Vec<AvroField>,
+}
+
+impl AvroSchema {
+    /// Parse the JSON definition of a record schema.
+    pub fn parse(definition: &str) -> Result<Self, failure::Error> {
+        let schema: serde_json::Value = serde_json::from_str(definition)?;
+        if schema["type"] != "record" {
+            bail!("only record schemas are supported: {}", definition);
+        }
+        let fields = schema["fields"]
+            .as_array()
+            .ok_or_else(|| format_err!("record without fields: {}", definition))?;
+        let fields = fields
+            .iter()
+            .map(|f| {
+                let name = f["name"]
+                    .as_str()
+                    .ok_or_else(|| format_err!("field without a name: {}", f))?;
+                let unsupported = || format_err!("unsupported type for field {}", name);
+                let (ty, null_branch) = match f["type"] {
+                    serde_json::Value::String(ref ty) => (ty.as_str(), None),
+                    serde_json::Value::Array(ref union) => match &union[..] {
+                        [serde_json::Value::String(a), serde_json::Value::String(b)] => {
+                            match (a.as_str(), b.as_str()) {
+                                ("null", ty) => (ty, Some(0)),
+                                (ty, "null") => (ty, Some(1)),
+                                _ => return Err(unsupported()),
+                            }
+                        }
+                        _ => return Err(unsupported()),
+                    },
+                    _ => return Err(unsupported()),
+                };
+                let default = match f.get("default") {
+                    None => None,
+                    Some(&serde_json::Value::Null) => Some(Literal::Null),
+                    Some(&serde_json::Value::Bool(b)) => Some(Literal::Integer(i64::from(b))),
+                    Some(&serde_json::Value::String(ref s)) => Some(Literal::String(s.clone())),
+                    // defaults that are not integers are left out
+                    Some(d) => d.as_i64().map(Literal::Integer),
+                };
+                Ok(AvroField {
+                    name: name.to_owned(),
+                    ty: AvroType::parse(ty).ok_or_else(unsupported)?,
+                    null_branch,
+                    default,
+                })
+            })
+            .collect::<Result<_, failure::Error>>()?;
+
+        Ok(AvroSchema {
+            name: schema["name"].as_str().unwrap_or("").to_owned(),
+            fields,
+        })
+    }
+
+    /// Decode a record of this schema from its Avro binary encoding, with a value for each field.
+    pub fn decode(&self, mut bytes: &[u8]) -> Result<Vec<DataType>, failure::Error> {
+        fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8], failure::Error> {
+            if bytes.len() < n {
+                bail!("record ends early");
+            }
+            let (taken, rest) = bytes.split_at(n);
+            *bytes = rest;
+            Ok(taken)
+        }
+        fn long(bytes: &mut &[u8]) -> Result<i64, failure::Error> {
+            // zig-zag encoded, seven bits at a time
+            let mut v = 0u64;
+            for shift in (0..64).step_by(7) {
+                let b = take(bytes, 1)?[0];
+                v |= u64::from(b & 0x7f) << shift;
+                if b & 0x80 == 0 {
+                    return Ok((v >> 1) as i64 ^ -((v & 1) as i64));
+                }
+            }
+            bail!("integer is too long")
+        }
+        fn real(f: f64) -> DataType {
+            if f.is_finite() {
+                DataType::from(f)
+            } else {
+                DataType::None
+            }
+        }
+
+        let mut row = Vec::with_capacity(self.fields.len());
+        for f in &self.fields {
+            if let Some(null) = f.null_branch {
+                if long(&mut bytes)? == null {
+                    row.push(DataType::None);
+                    continue;
+                }
+            }
+            row.push(match f.ty {
+                AvroType::Boolean => DataType::from(i32::from(take(&mut bytes, 1)?[0] != 0)),
+                AvroType::Int | AvroType::Long => DataType::from(long(&mut bytes)?),
+                AvroType::Float => {
+                    let mut b = [0; 4];
+                    b.copy_from_slice(take(&mut bytes, 4)?);
+                    real(f64::from(f32::from_bits(u32::from_le_bytes(b))))
+                }
+                AvroType::Double => {
+                    let mut b = [0; 8];
+                    b.copy_from_slice(take(&mut bytes, 8)?);
+                    real(f64::from_bits(u64::from_le_bytes(b)))
+                }
+                AvroType::Bytes | AvroType::String => {
+                    let len = long(&mut bytes)?;
+                    let s = take(&mut bytes, len.max(0) as usize)?;
+                    DataType::from(&*String::from_utf8_lossy(s))
+                }
+            });
+        }
+        Ok(row)
+    }
+}
+
+/// A change to the schema of a stream that the base table it is written to cannot follow.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Drift {
+    /// A schema adds a field that is neither nullable nor has a default, so the rows that are
+    /// already in the table could not be given a value for it. The table gains no column for it.
+    Required {
+        /// The ID of the schema.
+        schema: u32,
+        /// The name of the field.
+        field: String,
+    },
+    /// A schema gives a field a type that the column of the same name cannot hold. The column is
+    /// left NULL in rows of that schema.
+    Type {
+        /// The ID of the schema.
+        schema: u32,
+        /// The name of the field.
+        field: String,
+        /// The type the schema gives the field.
+        ty: AvroType,
+        /// The type of the column.
+        column: SqlType,
+    },
+}
+
+impl Drift {
+    /// The ID of the schema that drifted.
+    pub fn schema(&self) -> u32 {
+        match *self {
+            Drift::Required { schema, .. } | Drift::Type { schema, .. } => schema,
+        }
+    }
+
+    /// The name of the field that the table cannot follow.
+    pub fn field(&self) -> &str {
+        match *self {
+            Drift::Required { ref field, .. } | Drift::Type { ref field, .. } => field,
+        }
+    }
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Drift::Required { schema, ref field } => write!(
+                f,
+                "schema {} adds field {}, which is neither nullable nor has a default",
+                schema, field
+            ),
+            Drift::Type {
+                schema,
+                ref field,
+                ty,
+                ref column,
+            } => write!(
+                f,
+                "schema {} gives field {} type {:?}, which its column of type {} cannot hold",
+                schema, field, ty, column
+            ),
+        }
+    }
+}
+
+/// Compare the schema with ID `id` to the `columns` of the table it is written to. Returns the
+/// fields that the table can gain columns for, and how the schema drifted where the table cannot
+/// follow.
+fn compare<'a>(
+    id: u32,
+    schema: &'a AvroSchema,
+    columns: &[ColumnSpecification],
+) -> (Vec<&'a AvroField>, Vec<Drift>) {
+    let mut new = Vec::new();
+    let mut drift = Vec::new();
+    for f in &schema.fields {
+        match columns.iter().find(|c| c.column.name == f.name) {
+            Some(c) if f.ty.fits(&c.sql_type) => {}
+            Some(c) => drift.push(Drift::Type {
+                schema: id,
+                field: f.name.clone(),
+                ty: f.ty,
+                column: c.sql_type.clone(),
+            }),
+            None if f.default.is_some() || f.null_branch.is_some() => new.push(f),
+            None => drift.push(Drift::Required {
+                schema: id,
+                field: f.name.clone(),
+            }),
+        }
+    }
+    (new, drift)
+}
+
+/// Split a message in the wire format of a Confluent Schema Registry into the ID of the schema it
+/// was written with and the encoded record.
+pub fn split_message(message: &[u8]) -> Result<(u32, &[u8]), failure::Error> {
+    if message.len() < 5 || message[0] != 0 {
+        bail!("message is not in the schema registry's wire format");
+    }
+    let mut id = [0; 4];
+    id.copy_from_slice(&message[1..5]);
+    Ok((u32::from_be_bytes(id), &message[5..]))
+}
+
+/// A client of a Confluent Schema Registry that keeps the schemas it has fetched.
+#[derive(Clone, Debug)]
+pub struct SchemaRegistry {
+    url: String,
+    client: hyper::Client<hyper::client::HttpConnector>,
+    schemas: HashMap<u32, Arc<AvroSchema>>,
+}
+
+impl SchemaRegistry {
+    /// Fetch schemas from the registry at `url`, such as `http://registry:8081`.
+    pub fn new(url: &str) -> Self {
+        SchemaRegistry {
+            url: url.trim_end_matches('/').to_owned(),
+            client: hyper::Client::new(),
+            schemas: HashMap::new(),
+        }
+    }
+
+    fn fetch(&self, id: u32) -> impl Future<Item = AvroSchema, Error = failure::Error> + Send {
+        let url = format!("{}/schemas/ids/{}", self.url, id);
+        let url = url.parse::<hyper::Uri>();
+        let client = self.client.clone();
+        future::result(url)
+            .map_err(failure::Error::from)
+            .and_then(move |url| client.get(url).map_err(failure::Error::from))
+            .and_then(move |res| {
+                let status = res.status();
+                res.into_body()
+                    .concat2()
+                    .map_err(failure::Error::from)
+                    .and_then(move |body| {
+                        if !status.is_success() {
+                            bail!("registry has no schema {}: {}", id, status);
+                        }
+                        let body: serde_json::Value = serde_json::from_slice(&body)?;
+                        match body["schemaType"].as_str() {
+                            None | Some("AVRO") => {}
+                            Some(other) => bail!("schema {} is {}, not Avro", id, other),
+                        }
+                        let schema = body["schema"]
+                            .as_str()
+                            .ok_or_else(|| format_err!("registry sent no schema {}", id))?;
+                        AvroSchema::parse(schema)
+                    })
+            })
+    }
+}
+
+/// Writes the messages of a stream to a base table, adding columns to the table as the stream's
+/// schema gains fields.
+pub struct Ingest<A: 'static + Authority, E> {
+    ch: SyncControllerHandle<A, E>,
+    registry: SchemaRegistry,
+    table: SyncTable,
+    positions: Option<(String, String)>,
+    drift: Vec<Drift>,
+    log: slog::Logger,
+}
+
+impl<A, E> Ingest<A, E>
+where
+    A: Authority,
+    E: tokio::executor::Executor,
+{
+    /// Write messages whose schemas are fetched from `registry` to the base table `table`.
+    pub fn new(
+        mut ch: SyncControllerHandle<A, E>,
+        registry: SchemaRegistry,
+        table: &str,
+    ) -> Result<Self, failure::Error> {
+        let table = ch.table(table)?.into_sync();
+        Ok(Ingest {
+            ch,
+            registry,
+            table,
+            positions: None,
+            drift: Vec::new(),
+            log: slog::Logger::root(slog::Discard, o!()),
+        })
+    }
+
+    /// Log drift in the stream's schema to `log`.
+    pub fn log_with(&mut self, log: slog::Logger) {
+        self.log = log;
+    }
+
+    /// The changes to the stream's schema that the table could not follow, in the order they were
+    /// seen.
+    pub fn drift(&self) -> &[Drift] {
+        &self.drift
+    }
+
+    /// Keep the partition and offset of each message that is written with [`Ingest::write_from`]
+    /// in the columns `partition` and `offset` of the table.
+    pub fn with_positions(mut self, partition: &str, offset: &str) -> Self {
+        self.positions = Some((partition.to_owned(), offset.to_owned()));
+        self
+    }
+
+    /// The schema with the given ID, which is fetched from the registry if it has not been seen
+    /// before. The base table first gains columns for any of its fields that are new, and drift
+    /// that it cannot follow is noted.
+    fn schema(&mut self, id: u32) -> Result<Arc<AvroSchema>, failure::Error> {
+        if let Some(schema) = self.registry.schemas.get(&id) {
+            return Ok(schema.clone());
+        }
+        let schema = Arc::new(self.ch.run(self.registry.fetch(id))?);
+
+        let name = self.table.table_name().to_owned();
+        let mut create = self
+            .table
+            .schema()
+            .cloned()
+            .ok_or_else(|| format_err!("{} was not created by a recipe", name))?;
+        let (new, drift) = compare(id, &schema, &create.fields);
+        for d in drift {
+            warn!(self.log, "{} cannot follow its stream: {}", name, d);
+            self.drift.push(d);
+        }
+        if new.is_empty() {
+            self.registry.schemas.insert(id, schema.clone());
+            return Ok(schema);
+        }
+        for f in new {
+            let constraints = match f.default {
+                Some(ref d) => vec![ColumnConstraint::DefaultValue(d.clone())],
+                None => vec![],
+            };
+            create.fields.push(ColumnSpecification::with_constraints(
+                Column::from(&*format!("{}.{}", name, f.name)),
+                f.ty.sql_type(),
+                constraints,
+            ));
+        }
+        self.ch
+            .extend_recipe(format!("{};", create))
+            .context(format!("failed to add columns to {}", name))?;
+        self.table = self.ch.table(&name)?.into_sync();
+        self.registry.schemas.insert(id, schema.clone());
+        Ok(schema)
+    }
+
+    /// Write a row to the base table for each of `messages`, after adding columns for any new
+    /// fields of the schemas they were written with.
+    ///
+    /// The rows are written with [`SyncTable::perform_each`], and what became of each is returned
+    /// in the same way. Messages that cannot be decoded fail the whole batch.
+    pub fn write<I, M>(&mut self, messages: I) -> Result<Vec<Result<(), RowError>>, failure::Error>
+    where
+        I: IntoIterator<Item = M>,
+        M: AsRef<[u8]>,
+    {
+        self.write_at(messages.into_iter().map(|m| (None, m)))
+    }
+
+    /// Like [`Ingest::write`], but for messages that come along with the partition of the stream
+    /// they are from and their offset in it, which are written to the columns given to
+    /// [`Ingest::with_positions`].
+    ///
+    /// Messages that the table has already applied, if it was declared to tell them apart, are
+    /// reported as written.
+    pub fn write_from<I, M>(
+        &mut self,
+        messages: I,
+    ) -> Result<Vec<Result<(), RowError>>, failure::Error>
+    where
+        I: IntoIterator<Item = (i32, i64, M)>,
+        M: AsRef<[u8]>,
+    {
+        if self.positions.is_none() {
+            bail!("no columns were given for the positions of messages");
+        }
+        self.write_at(
+            messages
+                .into_iter()
+                .map(|(partition, offset, m)| (Some((partition, offset)), m)),
+        )
+    }
+
+    fn write_at<I, M>(&mut self, messages: I) -> Result<Vec<Result<(), RowError>>, failure::Error>
+    where
+        I: IntoIterator<Item = (Option<(i32, i64)>, M)>,
+        M: AsRef<[u8]>,
+    {
+        let mut records = Vec::new();
+        for (position, m) in messages {
+            let (id, record) = split_message(m.as_ref())?;
+            let schema = self.schema(id)?;
+            records.push((id, schema.clone(), schema.decode(record)?, position));
+        }
+
+        // the columns may have changed while the schemas were looked at
+        let columns = self.table.columns().to_vec();
+        let positions = self.positions.as_ref();
+        let drift = &self.drift;
+        let rows = records.into_iter().map(|(id, schema, values, position)| {
+            let row = columns.iter().map(|c| {
+                match (positions, position) {
+                    (Some(&(ref p, _)), Some((partition, _))) if p == c => {
+                        return DataType::from(partition);
+                    }
+                    (Some(&(_, ref o)), Some((_, offset))) if o == c => {
+                        return DataType::from(offset);
+                    }
+                    _ => {}
+                }
+                if drift.iter().any(|d| d.schema() == id && d.field() == c) {
+                    return DataType::None;
+                }
+                schema
+                    .fields
+                    .iter()
+                    .position(|f| &f.name == c)
+                    .map(|i| values[i].clone())
+                    .unwrap_or(DataType::None)
+            });
+            TableOperation::Insert(row.collect())
+        });
+        Ok(self.table.perform_each(rows.collect::<Vec<_>>())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_decodes_records() {
+        let schema = AvroSchema::parse(
+            r#"{"type": "record", "name": "user", "fields": [
+                {"name": "id", "type": "long"},
+                {"name": "name", "type": ["null", "string"], "default": null},
+                {"name": "active", "type": "boolean"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(schema.fields[1].null_branch, Some(0));
+        assert_eq!(schema.fields[1].default, Some(Literal::Null));
+
+        // -2 is zig-zag encoded as 3
+        let message = [0, 0, 0, 0, 7, 3, 2, 4, b'a', b'b', 1];
+        let (id, record) = split_message(&message).unwrap();
+        assert_eq!(id, 7);
+        assert_eq!(
+            schema.decode(record).unwrap(),
+            vec![(-2).into(), "ab".into(), 1.into()]
+        );
+        assert_eq!(
+            schema.decode(&[3, 0, 0]).unwrap(),
+            vec![(-2).into(), DataType::None, 0.into()]
+        );
+        assert!(schema.decode(&[3, 2, 4, b'a']).is_err());
+        assert!(split_message(&[1, 0, 0, 0, 7]).is_err());
+    }
+
+    #[test]
+    fn it_follows_drift_it_can() {
+        let schema = AvroSchema::parse(
+            r#"{"type": "record", "name": "user", "fields": [
+                {"name": "id", "type": "int"},
+                {"name": "name", "type": "long"},
+                {"name": "email", "type": ["null", "string"]},
+                {"name": "age", "type": "int", "default": 0},
+                {"name": "karma", "type": "double"}
+            ]}"#,
+        )
+        .unwrap();
+        let columns: Vec<_> = vec![("id", SqlType::Bigint(64)), ("name", SqlType::Text)]
+            .into_iter()
+            .map(|(c, ty)| {
+                let c = Column::from(&*format!("user.{}", c));
+                ColumnSpecification::with_constraints(c, ty, vec![])
+            })
+            .collect();
+
+        let (new, drift) = compare(3, &schema, &columns);
+        let new: Vec<_> = new.into_iter().map(|f| &f.name[..]).collect();
+        assert_eq!(new, vec!["email", "age"]);
+        assert_eq!(
+            drift,
+            vec![
+                Drift::Type {
+                    schema: 3,
+                    field: "name".to_owned(),
+                    ty: AvroType::Long,
+                    column: SqlType::Text,
+                },
+                Drift::Required {
+                    schema: 3,
+                    field: "karma".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(drift[1].field(), "karma");
+    }
+}
@@ -126,6 +126,7 @@ extern crate futures;
 
 use petgraph::graph::NodeIndex;
 use std::collections::HashMap;
+use std::time::Duration;
 use tokio_tower::multiplex;
 
 mod controller;
@@ -135,10 +136,15 @@ mod view;
 
 #[doc(hidden)]
 pub mod channel;
+pub mod cluster;
 #[doc(hidden)]
 pub mod consensus;
+pub mod ingest;
 #[doc(hidden)]
 pub mod internal;
+pub mod prepared;
+pub mod recording;
+pub mod tls;
 
 pub use crate::consensus::ZookeeperAuthority;
 use crate::internal::*;
@@ -153,7 +159,10 @@ pub mod prelude {
 
 /// Noria errors.
 pub mod error {
-    pub use crate::table::TableError;
+    pub use crate::table::{
+        CheckViolation, ConstraintViolation, ReferenceViolation, RowError, TableError,
+        TypeViolation, UniqueViolation,
+    };
     pub use crate::view::ViewError;
 }
 
@@ -188,13 +197,15 @@ impl<T> From<T> for Tagged<T> {
     }
 }
 
-pub use crate::controller::{ControllerDescriptor, ControllerHandle, SyncControllerHandle};
+pub use crate::controller::{
+    ControllerDescriptor, ControllerHandle, MigrationHandle, SyncControllerHandle,
+};
 pub use crate::data::{DataType, Modification, Operation, TableOperation};
-pub use crate::table::{SyncTable, Table};
-pub use crate::view::{SyncView, View};
+pub use crate::table::{Ack, SyncTable, Table, WriteGroup};
+pub use crate::view::{Correction, Cursor, Predicate, SnapshotToken, SyncView, View};
 
 #[doc(hidden)]
-pub use crate::table::Input;
+pub use crate::table::{Input, WriteReply};
 
 #[doc(hidden)]
 pub use crate::view::{ReadQuery, ReadReply};
@@ -219,6 +230,61 @@ pub struct ActivationResult {
     pub expressions_added: usize,
     /// Number of expressions the recipe removed compared to the prior recipe.
     pub expressions_removed: usize,
+    /// What the migration that applied the recipe changed in the running graph.
+    #[serde(default)]
+    pub migration: Option<cluster::MigrationResult>,
+    /// Which existing nodes each query that the recipe added shares, by the name of the query.
+    #[serde(default)]
+    pub reuse: HashMap<String, cluster::QueryReuse>,
+}
+
+/// How a shadow migration has fared so far.
+///
+/// See [`ControllerHandle::shadow_recipe`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ShadowReport {
+    /// Map from the names of the queries that the proposed recipe adds or changes to the names of
+    /// the views that hold their shadow results.
+    pub queries: HashMap<String, String>,
+    /// The names of the queries in `queries` that the proposed recipe adds, and which therefore
+    /// have no live version to compare with.
+    pub added: Vec<String>,
+    /// How long the shadow has been running for.
+    pub soaked: Duration,
+    /// Whether the shadow has soaked for long enough to be promoted.
+    pub ready: bool,
+    /// Number of dataflow nodes that were added for the shadow.
+    pub nodes: usize,
+    /// Bytes of state held by those nodes.
+    pub state_bytes: u64,
+    /// Time spent processing updates in those nodes, in nanoseconds.
+    pub process_time: u64,
+}
+
+/// Settings of a running deployment to change.
+///
+/// See [`ControllerHandle::update_config`]. Settings that are `None` are left as they are.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct ConfigUpdate {
+    /// The number of bytes of state each worker keeps below by evicting, or 0 for no limit.
+    ///
+    /// Only workers that were started with a memory check frequency enforce it.
+    pub memory_limit: Option<usize>,
+    /// How long domains wait for more replay requests to batch with the first one.
+    pub replay_batch_timeout: Option<Duration>,
+    /// How many replay requests each domain may have outstanding at once.
+    pub concurrent_replays: Option<usize>,
+}
+
+/// A key for which the shadow version of a query returns different results from the live one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShadowDiff {
+    /// The key that was looked up.
+    pub key: Vec<DataType>,
+    /// The rows that the live query returns, in sorted order.
+    pub live: Vec<Vec<DataType>>,
+    /// The rows that the shadow query returns, in sorted order.
+    pub shadow: Vec<Vec<DataType>>,
 }
 
 #[doc(hidden)]
@@ -0,0 +1,210 @@
+//! Statements that are parsed and planned once, and then executed many times.
+//!
+//! See [`ControllerHandle::prepare`](crate::ControllerHandle::prepare).
+
+use crate::{DataType, Modification, Table, TableOperation, View};
+use nom_sql::SqlType;
+use tokio::prelude::*;
+
+/// A `?` parameter of a [`PreparedStatement`].
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Parameter {
+    /// The column that the parameter is compared with or written to.
+    pub column: String,
+    /// The type of that column, if it is known.
+    pub sql_type: Option<SqlType>,
+}
+
+/// A value that a prepared statement looks up by or writes.
+#[doc(hidden)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Slot {
+    /// The parameter at this index.
+    Parameter(usize),
+    /// A value given in the statement itself.
+    Value(DataType),
+}
+
+/// What executing a prepared statement does.
+#[doc(hidden)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Route {
+    /// Look up `key` in the view `view`.
+    Lookup { view: String, key: Vec<Slot> },
+    /// Insert `row` into the base table `table`.
+    Insert { table: String, row: Vec<Slot> },
+    /// Set the columns of the row of `table` with the primary key `key` that `set` gives values
+    /// for.
+    Update {
+        table: String,
+        set: Vec<Option<Slot>>,
+        key: Vec<Slot>,
+    },
+    /// Delete the row of `table` with the primary key `key`.
+    Delete { table: String, key: Vec<Slot> },
+}
+
+/// The controller's plan for a prepared statement.
+#[doc(hidden)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct StatementPlan {
+    /// The parameters of the statement, in the order they appear in it.
+    pub parameters: Vec<Parameter>,
+    /// What executing the statement does.
+    pub route: Route,
+}
+
+/// What executing a [`PreparedStatement`] gave.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Executed {
+    /// The rows that a `SELECT` found.
+    Rows(Vec<Vec<DataType>>),
+    /// The write that an `INSERT`, `UPDATE`, or `DELETE` made was applied.
+    Written,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Target {
+    View(View),
+    Table(Table),
+}
+
+/// A statement that the controller has planned, and that can be executed with different
+/// parameters without being parsed again.
+///
+/// `SELECT`s are looked up in the view for their query, which is added to the recipe when it is
+/// prepared if the recipe does not have it yet. `INSERT`s of one row, and `UPDATE`s and `DELETE`s
+/// of the row with a given primary key, are written to their base table.
+#[derive(Clone, Debug)]
+pub struct PreparedStatement {
+    plan: StatementPlan,
+    target: Target,
+}
+
+impl PreparedStatement {
+    pub(crate) fn new(plan: StatementPlan, target: Target) -> Self {
+        PreparedStatement { plan, target }
+    }
+
+    /// The `?` parameters of the statement, in the order they appear in it.
+    pub fn parameters(&self) -> &[Parameter] {
+        &self.plan.parameters
+    }
+
+    /// The columns of the rows that executing the statement gives, which are none for writes.
+    pub fn columns(&self) -> &[String] {
+        match self.target {
+            Target::View(ref view) => view.columns(),
+            Target::Table(_) => &[],
+        }
+    }
+
+    /// Execute the statement with the values in `params` for its parameters.
+    pub(crate) fn execute(
+        &self,
+        params: &[DataType],
+    ) -> impl Future<Item = Executed, Error = failure::Error> + Send {
+        let params = match bind(&self.plan.parameters, params) {
+            Ok(params) => params,
+            Err(e) => return future::Either::A(future::err(e)),
+        };
+        let value = |s: &Slot| match *s {
+            Slot::Parameter(i) => params[i].clone(),
+            Slot::Value(ref v) => v.clone(),
+        };
+        let fill = |slots: &[Slot]| slots.iter().map(&value).collect::<Vec<_>>();
+
+        let table = match self.target {
+            Target::View(ref view) => {
+                let key = match self.plan.route {
+                    Route::Lookup { ref key, .. } => fill(key),
+                    _ => unreachable!("only lookups go to views"),
+                };
+                let lookup = view
+                    .clone()
+                    .lookup(&key, true)
+                    .map(|(_, rows)| Executed::Rows(rows))
+                    .map_err(|e| e.error.into());
+                return future::Either::B(future::Either::A(lookup));
+            }
+            Target::Table(ref table) => table.clone(),
+        };
+        let op = match self.plan.route {
+            Route::Insert { ref row, .. } => TableOperation::Insert(fill(row)),
+            Route::Update {
+                ref set, ref key, ..
+            } => TableOperation::Update {
+                set: set
+                    .iter()
+                    .map(|s| match *s {
+                        Some(ref s) => Modification::Set(value(s)),
+                        None => Modification::None,
+                    })
+                    .collect(),
+                key: fill(key),
+            },
+            Route::Delete { ref key, .. } => TableOperation::Delete { key: fill(key) },
+            Route::Lookup { .. } => unreachable!("lookups go to views"),
+        };
+        let write = table
+            .perform_all(vec![op])
+            .map(|_| Executed::Written)
+            .map_err(|e| e.error.into());
+        future::Either::B(future::Either::B(write))
+    }
+}
+
+/// Check that there is one value in `params` for each of `parameters`, and convert each to the
+/// type of its column.
+fn bind(parameters: &[Parameter], params: &[DataType]) -> Result<Vec<DataType>, failure::Error> {
+    if params.len() != parameters.len() {
+        bail!(
+            "expected {} parameters, got {}",
+            parameters.len(),
+            params.len()
+        );
+    }
+    parameters
+        .iter()
+        .zip(params)
+        .map(|(p, v)| match p.sql_type {
+            Some(ref ty) => {
+                let coerced = v.coerce_to(ty);
+                if coerced.is_none() && !v.is_none() {
+                    bail!("{} is {:?}, but was given {:?}", p.column, ty, v);
+                }
+                Ok(coerced)
+            }
+            None => Ok(v.clone()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_binds_parameters_by_type() {
+        let parameters = vec![
+            Parameter {
+                column: "id".to_owned(),
+                sql_type: Some(SqlType::Int(32)),
+            },
+            Parameter {
+                column: "title".to_owned(),
+                sql_type: None,
+            },
+        ];
+        assert_eq!(
+            bind(&parameters, &["12".into(), "hi".into()]).unwrap(),
+            vec![12.into(), "hi".into()]
+        );
+        assert_eq!(
+            bind(&parameters, &[DataType::None, 1.into()]).unwrap(),
+            vec![DataType::None, 1.into()]
+        );
+        assert!(bind(&parameters, &["twelve".into(), "hi".into()]).is_err());
+        assert!(bind(&parameters, &[12.into()]).is_err());
+    }
+}
@@ -0,0 +1,234 @@
+//! Recording client workloads, and replaying them against a deployment.
+//!
+//! A [`Recorder`] can be attached to a [`ControllerHandle`](crate::ControllerHandle) with
+//! [`ControllerHandle::record_to`](crate::ControllerHandle::record_to). Every write to a `Table`
+//! and every lookup in a `View` obtained from the handle afterwards is then written to the
+//! recording as a line of JSON, along with when it was issued. [`replay`] issues the same
+//! operations, with the same spacing in time or faster, through another handle.
+
+use crate::consensus::Authority;
+use crate::data::{DataType, TableOperation};
+use crate::{SyncControllerHandle, SyncTable, SyncView};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// An operation issued by a client.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Event {
+    /// Operations performed on a base table.
+    Write {
+        /// The name of the table.
+        table: String,
+        /// The operations, as given by the client.
+        ops: Vec<TableOperation>,
+    },
+    /// A lookup of one or more keys in a view.
+    Lookup {
+        /// The name of the view.
+        view: String,
+        /// The keys that were looked up.
+        keys: Vec<Vec<DataType>>,
+        /// Whether the lookup waited for missing keys to be filled.
+        block: bool,
+    },
+}
+
+/// A recorded operation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the operation was issued, relative to the start of the recording.
+    pub at: Duration,
+    /// The operation.
+    pub event: Event,
+}
+
+struct Inner {
+    start: Instant,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+/// A recording of the operations issued through one or more handles.
+///
+/// Recording is best-effort: if writing to the recording fails, the operation itself still goes
+/// ahead.
+#[derive(Clone)]
+pub struct Recorder(Arc<Inner>);
+
+impl Recorder {
+    /// Record to the file at `path`, replacing it if it exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Record to `out`.
+    pub fn new<W: Write + Send + 'static>(out: W) -> Self {
+        Recorder(Arc::new(Inner {
+            start: Instant::now(),
+            out: Mutex::new(Box::new(out)),
+        }))
+    }
+
+    /// Write any buffered entries out.
+    pub fn flush(&self) -> io::Result<()> {
+        self.0.out.lock().unwrap().flush()
+    }
+
+    fn record(&self, event: Event) {
+        let entry = Entry {
+            at: self.0.start.elapsed(),
+            event,
+        };
+        let mut out = self.0.out.lock().unwrap();
+        let _ = serde_json::to_writer(&mut *out, &entry)
+            .map_err(io::Error::from)
+            .and_then(|_| out.write_all(b"\n"));
+    }
+
+    pub(crate) fn write(&self, table: &str, ops: &[TableOperation]) {
+        self.record(Event::Write {
+            table: table.to_owned(),
+            ops: ops.to_vec(),
+        });
+    }
+
+    pub(crate) fn lookup(&self, view: &str, keys: &[Vec<DataType>], block: bool) {
+        self.record(Event::Lookup {
+            view: view.to_owned(),
+            keys: keys.to_vec(),
+            block,
+        });
+    }
+}
+
+/// What happened during a [`replay`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Replayed {
+    /// The number of writes issued.
+    pub writes: usize,
+    /// The number of lookups issued.
+    pub lookups: usize,
+    /// The number of operations that failed, including those on tables or views that don't exist.
+    pub failed: usize,
+    /// The furthest that an operation was issued behind schedule.
+    pub max_lag: Duration,
+}
+
+/// Issue the operations recorded in `recording` through `ch`.
+///
+/// The spacing between operations is divided by `speed`, so a speed of 2 replays a recording in
+/// half the time it took to record it. Without a speed, operations are issued as fast as
+/// possible. Operations are issued one at a time, so if the deployment can't keep up, later
+/// operations fall behind schedule rather than pile up.
+pub fn replay<A, E, R>(
+    ch: &mut SyncControllerHandle<A, E>,
+    recording: R,
+    speed: Option<f64>,
+) -> Result<Replayed, failure::Error>
+where
+    A: Authority,
+    E: tokio::executor::Executor,
+    R: BufRead,
+{
+    let mut tables: HashMap<String, SyncTable> = HashMap::new();
+    let mut views: HashMap<String, SyncView> = HashMap::new();
+    let mut replayed = Replayed::default();
+
+    let start = Instant::now();
+    for line in recording.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let entry: Entry = serde_json::from_str(&line)?;
+
+        if let Some(speed) = speed {
+            let at = entry.at.as_secs() as f64 + f64::from(entry.at.subsec_nanos()) * 1e-9;
+            let at = start + Duration::from_nanos((at / speed * 1e9) as u64);
+            let now = Instant::now();
+            if at > now {
+                thread::sleep(at - now);
+            } else if now - at > replayed.max_lag {
+                replayed.max_lag = now - at;
+            }
+        }
+
+        let ok = match entry.event {
+            Event::Write { table, ops } => {
+                replayed.writes += 1;
+                if !tables.contains_key(&table) {
+                    if let Ok(t) = ch.table(&table) {
+                        tables.insert(table.clone(), t.into_sync());
+                    }
+                }
+                match tables.get_mut(&table) {
+                    Some(t) => t.perform_all(ops).is_ok(),
+                    None => false,
+                }
+            }
+            Event::Lookup { view, keys, block } => {
+                replayed.lookups += 1;
+                if !views.contains_key(&view) {
+                    if let Ok(v) = ch.view(&view) {
+                        views.insert(view.clone(), v.into_sync());
+                    }
+                }
+                match views.get_mut(&view) {
+                    Some(v) => v.multi_lookup(keys, block).is_ok(),
+                    None => false,
+                }
+            }
+        };
+        if !ok {
+            replayed.failed += 1;
+        }
+    }
+
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn entries_are_json_lines() {
+        let out = Shared::default();
+        let recorder = Recorder::new(out.clone());
+        recorder.write("t", &[TableOperation::Insert(vec![1.into(), "a".into()])]);
+        recorder.lookup("v", &[vec![1.into()]], true);
+
+        let out = out.0.lock().unwrap();
+        let entries: Vec<Entry> = out[..]
+            .lines()
+            .map(|l| serde_json::from_str(&l.unwrap()).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].at <= entries[1].at);
+        assert_eq!(
+            entries[1].event,
+            Event::Lookup {
+                view: "v".to_owned(),
+                keys: vec![vec![1.into()]],
+                block: true,
+            }
+        );
+    }
+}
@@ -1,14 +1,17 @@
-use crate::channel::CONNECTION_FROM_BASE;
+use crate::channel::{self, CONNECTION_FROM_BASE};
 use crate::data::*;
 use crate::debug::trace::Tracer;
 use crate::internal::*;
+use crate::recording::Recorder;
+use crate::tls;
 use crate::BoxDynError;
 use crate::LocalOrNot;
 use crate::{Tagged, Tagger};
 use async_bincode::{AsyncBincodeStream, AsyncDestination};
 use futures::stream::futures_unordered::FuturesUnordered;
-use nom_sql::CreateTableStatement;
+use nom_sql::{CreateTableStatement, SqlType};
 use std::collections::HashMap;
+use std::mem;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::{fmt, io};
@@ -21,8 +24,8 @@ use tower_service::Service;
 use vec_map::VecMap;
 
 type Transport = AsyncBincodeStream<
-    tokio::net::tcp::TcpStream,
-    Tagged<()>,
+    channel::Connection<tokio::net::tcp::TcpStream>,
+    Tagged<WriteReply>,
     Tagged<LocalOrNot<Input>>,
     AsyncDestination,
 >;
@@ -30,7 +33,7 @@ type Transport = AsyncBincodeStream<
 #[derive(Debug)]
 #[doc(hidden)]
 // only pub because we use it to figure out the error type for TableError
-pub struct TableEndpoint(SocketAddr);
+pub struct TableEndpoint(SocketAddr, Option<tls::Connector>, Option<Vec<u8>>);
 
 impl Service<()> for TableEndpoint {
     type Response = multiplex::MultiplexTransport<Transport, Tagger>;
@@ -46,6 +49,8 @@ impl Service<()> for TableEndpoint {
     }
 
     fn call(&mut self, _: ()) -> Self::Future {
+        let tls = self.1.clone();
+        let key = self.2.clone();
         tokio::net::TcpStream::connect(&self.0)
             .and_then(|s| {
                 s.set_nodelay(true)?;
@@ -56,6 +61,7 @@ impl Service<()> for TableEndpoint {
                 s.flush().unwrap();
                 s
             })
+            .and_then(move |s| channel::secure(s, tls.as_ref(), key))
             .map(AsyncBincodeStream::from)
             .map(AsyncBincodeStream::for_async)
             .map(|t| multiplex::MultiplexTransport::new(t, Tagger::default()))
@@ -74,6 +80,36 @@ pub(crate) type TableRpc = Buffer<
 
 type E = <TableRpc as Service<Tagged<LocalOrNot<Input>>>>::Error;
 
+/// Waits until every view computed from the named base table reflects the writes that the base
+/// table has applied so far.
+pub(crate) type Propagate =
+    Arc<Fn(&str) -> Box<Future<Item = (), Error = failure::Error> + Send> + Send + Sync>;
+
+/// How far a write to a [`Table`] must get before the operation that made it completes.
+///
+/// See [`Table::set_ack`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ack {
+    /// Complete as soon as the write is on its way to the base table.
+    ///
+    /// Errors are not reported, so writes that the base table refuses are lost without a trace.
+    None,
+    /// Complete once the base table has applied the write.
+    Base,
+    /// Complete once the base table has applied the write, and it is on disk.
+    ///
+    /// Base tables that are only kept in memory treat this like `Ack::Base`.
+    Durable,
+    /// Complete once every view computed from the base table reflects the write.
+    Views,
+}
+
+impl Default for Ack {
+    fn default() -> Self {
+        Ack::Base
+    }
+}
+
 /// A failed [`Table`] operation.
 #[derive(Debug)]
 pub struct AsyncTableError {
@@ -112,6 +148,26 @@ pub enum TableError {
     )]
     WrongKeyColumnCount(usize, usize),
 
+    /// The server refused the write because this client has exceeded its rate limit.
+    #[fail(display = "rate limit exceeded")]
+    RateLimited,
+
+    /// The server refused the write because the domain it was sent to has more packets waiting to
+    /// be sent on than it allows.
+    #[fail(display = "domain overloaded")]
+    Overloaded,
+
+    /// The base table refused some of the operations in the write, because they would have broken
+    /// its constraints. The other operations were applied.
+    ///
+    /// Each violation comes with the index of the operation in the write that it was refused for.
+    #[fail(display = "write would have broken constraints: {:?}", _0)]
+    ConstraintViolation(Vec<(usize, ConstraintViolation)>),
+
+    /// The write was applied, but waiting for the views to reflect it failed.
+    #[fail(display = "failed to wait for views to reflect write: {}", _0)]
+    NotPropagated(failure::Error),
+
     /// The underlying connection to Noria produced an error.
     #[fail(display = "{}", _0)]
     TransportError(#[cause] BoxDynError<<TableRpc as Service<Tagged<LocalOrNot<Input>>>>::Error>),
@@ -123,12 +179,137 @@ impl From<E> for TableError {
     }
 }
 
+/// An operation that a base table refused, because it would have given columns that must be
+/// unique together values that another row already has.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Fail)]
+#[fail(display = "columns {:?} must be unique, but {:?} is taken", columns, value)]
+pub struct UniqueViolation {
+    /// The columns that must be unique together.
+    pub columns: Vec<usize>,
+    /// The values the operation would have given them.
+    pub value: Vec<DataType>,
+}
+
+/// An operation that a base table refused, because the row it would have left behind fails one of
+/// the table's `CHECK` constraints.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Fail)]
+#[fail(display = "{:?} fails CHECK ({})", row, check)]
+pub struct CheckViolation {
+    /// The constraint, as it was declared.
+    pub check: String,
+    /// The row the operation would have left behind.
+    pub row: Vec<DataType>,
+}
+
+/// An operation that a base table refused, because it would have left a reference between base
+/// tables dangling.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Fail)]
+pub enum ReferenceViolation {
+    /// A column would have referred to a row that the table it refers to does not have.
+    #[fail(display = "column {} refers to {:?}, which does not exist", column, value)]
+    Missing {
+        /// The column that holds the reference.
+        column: usize,
+        /// The key the column would have referred to.
+        value: DataType,
+    },
+    /// A row would have been deleted while rows of another table still refer to it.
+    #[fail(display = "{:?} is still referred to", key)]
+    Referenced {
+        /// The primary key of the row.
+        key: DataType,
+    },
+}
+
+/// A constraint of a base table that an operation would have broken.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Fail)]
+pub enum ConstraintViolation {
+    /// Columns that must be unique would have had values that another row already has.
+    #[fail(display = "{}", _0)]
+    Unique(UniqueViolation),
+    /// A row would have failed a `CHECK` constraint.
+    #[fail(display = "{}", _0)]
+    Check(CheckViolation),
+    /// A value would not have had the type of its column.
+    #[fail(display = "{}", _0)]
+    Type(TypeViolation),
+    /// A reference to another base table would have been left dangling.
+    #[fail(display = "{}", _0)]
+    Reference(ReferenceViolation),
+}
+
+/// An operation that a base table refused, because a value in it does not have the type that the
+/// table declares for its column. Only tables that reject such values refuse them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Fail)]
+#[fail(display = "column {} is {:?}, but was given {:?}", column, expected, value)]
+pub struct TypeViolation {
+    /// The index of the column.
+    pub column: usize,
+    /// The type the table declares for the column.
+    pub expected: SqlType,
+    /// The value the operation would have given it.
+    pub value: DataType,
+}
+
+/// Why one of the operations given to [`Table::perform_each`] was not applied.
+#[derive(Clone, Debug, PartialEq, Eq, Fail)]
+pub enum RowError {
+    /// The row has the wrong number of columns.
+    #[fail(
+        display = "wrong number of columns specified: expected {}, got {}",
+        _0, _1
+    )]
+    WrongColumnCount(usize, usize),
+    /// The key has the wrong number of columns.
+    #[fail(
+        display = "wrong number of key columns used: expected {}, got {}",
+        _0, _1
+    )]
+    WrongKeyColumnCount(usize, usize),
+    /// A value does not have the type of its column.
+    #[fail(display = "{}", _0)]
+    WrongType(TypeViolation),
+    /// The base table refused the operation, because it would have broken a constraint.
+    #[fail(display = "{}", _0)]
+    ConstraintViolation(ConstraintViolation),
+}
+
+/// A domain's reply to a write.
+#[doc(hidden)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WriteReply {
+    /// The write was accepted.
+    Ok,
+    /// The write was refused because the client has exceeded its rate limit.
+    RateLimited,
+    /// The write was refused because the domain has too many packets waiting to be sent on.
+    Overloaded,
+    /// Some of the operations in the write were refused because they broke constraints, by index.
+    ConstraintViolation(Vec<(usize, ConstraintViolation)>),
+}
+
+fn check_reply(reply: Tagged<WriteReply>) -> Result<Tagged<()>, TableError> {
+    match reply.v {
+        WriteReply::Ok => Ok(Tagged {
+            tag: reply.tag,
+            v: (),
+        }),
+        WriteReply::RateLimited => Err(TableError::RateLimited),
+        WriteReply::Overloaded => Err(TableError::Overloaded),
+        WriteReply::ConstraintViolation(vs) => Err(TableError::ConstraintViolation(vs)),
+    }
+}
+
 #[doc(hidden)]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Input {
     pub dst: LocalNodeIndex,
     pub data: Vec<TableOperation>,
     pub tracer: Tracer,
+    /// Whether the write must be on disk before it is acknowledged.
+    pub durable: bool,
+    /// The write group that the write is part of, if any.
+    pub group: Option<u64>,
 }
 
 impl fmt::Debug for Input {
@@ -137,6 +318,8 @@ impl fmt::Debug for Input {
             .field("dst", &self.dst)
             .field("data", &self.data)
             .field("tracer", &"_")
+            .field("durable", &self.durable)
+            .field("group", &self.group)
             .finish()
     }
 }
@@ -153,19 +336,28 @@ pub struct TableBuilder {
     pub table_name: String,
     pub columns: Vec<String>,
     pub schema: Option<CreateTableStatement>,
+    /// The key that connections to the base table's domain must authenticate with, if any.
+    pub secret: Option<Vec<u8>>,
+    /// The name that the certificate of the base table's domain is issued for, if it uses TLS.
+    pub tls: Option<String>,
 }
 
 impl TableBuilder {
+    /// Build a `Table` out of a `TableBuilder`, verifying the certificate of the base table's
+    /// domain with `tls` if it uses TLS.
     pub(crate) fn build(
         self,
         rpcs: Arc<Mutex<HashMap<(SocketAddr, usize), TableRpc>>>,
+        tls: Option<Arc<rustls::ClientConfig>>,
     ) -> impl Future<Item = Table, Error = io::Error> + Send {
-        future::join_all(
-            self.txs
-                .clone()
-                .into_iter()
-                .enumerate()
-                .map(move |(shardi, addr)| {
+        let secret = self.secret.clone();
+        let tls = match tls::connector(self.tls.as_ref().map(String::as_str), tls) {
+            Ok(tls) => tls,
+            Err(e) => return future::Either::A(future::err(e)),
+        };
+        future::Either::B(
+            future::join_all(self.txs.clone().into_iter().enumerate().map(
+                move |(shardi, addr)| {
                     use std::collections::hash_map::Entry;
 
                     // one entry per shard so that we can send sharded requests in parallel even if
@@ -181,7 +373,11 @@ impl TableBuilder {
                                     .loaded_above(0.2)
                                     .underutilized_below(0.00001)
                                     .build(
-                                        multiplex::client::Maker::new(TableEndpoint(addr)),
+                                        multiplex::client::Maker::new(TableEndpoint(
+                                            addr,
+                                            tls.clone(),
+                                            secret.clone(),
+                                        )),
                                         (),
                                         choose::RoundRobin::default(),
                                     ),
@@ -191,25 +387,30 @@ impl TableBuilder {
                             Ok((addr, c))
                         }
                     }
-                }),
+                },
+            ))
+            .map(move |shards| {
+                let (addrs, conns) = shards.into_iter().unzip();
+                Table {
+                    node: self.addr,
+                    key: self.key,
+                    key_is_primary: self.key_is_primary,
+                    columns: self.columns,
+                    dropped: self.dropped,
+                    tracer: None,
+                    table_name: self.table_name,
+                    schema: self.schema,
+                    dst_is_local: false,
+                    recorder: None,
+                    ack: Ack::default(),
+                    unacked: Default::default(),
+                    propagate: None,
+
+                    shard_addrs: addrs,
+                    shards: conns,
+                }
+            }),
         )
-        .map(move |shards| {
-            let (addrs, conns) = shards.into_iter().unzip();
-            Table {
-                node: self.addr,
-                key: self.key,
-                key_is_primary: self.key_is_primary,
-                columns: self.columns,
-                dropped: self.dropped,
-                tracer: None,
-                table_name: self.table_name,
-                schema: self.schema,
-                dst_is_local: false,
-
-                shard_addrs: addrs,
-                shards: conns,
-            }
-        })
     }
 }
 
@@ -230,6 +431,13 @@ pub struct Table {
     table_name: String,
     schema: Option<CreateTableStatement>,
     dst_is_local: bool,
+    recorder: Option<Recorder>,
+    /// How far writes must get before operations complete.
+    ack: Ack,
+    /// The replies to writes made with `Ack::None` that have yet to arrive, which are kept around
+    /// since dropping them would call off the writes that have not been sent yet.
+    unacked: Arc<Mutex<Vec<Box<Future<Item = (), Error = ()> + Send>>>>,
+    propagate: Option<Propagate>,
 
     shards: Vec<TableRpc>,
     shard_addrs: Vec<SocketAddr>,
@@ -246,6 +454,7 @@ impl fmt::Debug for Table {
             .field("table_name", &self.table_name)
             .field("schema", &self.schema)
             .field("dst_is_local", &self.dst_is_local)
+            .field("ack", &self.ack)
             .field("shard_addrs", &self.shard_addrs)
             .finish()
     }
@@ -253,11 +462,22 @@ impl fmt::Debug for Table {
 
 impl Service<Input> for Table {
     type Error = TableError;
-    type Response = <TableRpc as Service<Tagged<LocalOrNot<Input>>>>::Response;
+    type Response = Tagged<()>;
     // have to repeat types because https://github.com/rust-lang/rust/issues/57807
     existential type Future: Future<Item = Tagged<()>, Error = TableError>;
 
     fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        // forget about the unacknowledged writes that have made it
+        let mut unacked = self.unacked.lock().unwrap();
+        let mut i = 0;
+        while i < unacked.len() {
+            match unacked[i].poll() {
+                Ok(Async::NotReady) => i += 1,
+                _ => drop(unacked.swap_remove(i)),
+            }
+        }
+        drop(unacked);
+
         for s in &mut self.shards {
             try_ready!(s.poll_ready().map_err(TableError::from));
         }
@@ -266,10 +486,11 @@ impl Service<Input> for Table {
 
     fn call(&mut self, mut i: Input) -> Self::Future {
         i.tracer = self.tracer.take();
+        i.durable = self.ack == Ack::Durable;
 
         // TODO: check each row's .len() against self.columns.len() -> WrongColumnCount
 
-        if self.shards.len() == 1 {
+        let applied = if self.shards.len() == 1 {
             future::Either::A(
                 self.shards[0]
                     .call(
@@ -280,34 +501,14 @@ impl Service<Input> for Table {
                         }
                         .into(),
                     )
-                    .map_err(TableError::from),
+                    .map_err(TableError::from)
+                    .and_then(check_reply),
             )
         } else {
-            if self.key.is_empty() {
-                unreachable!("sharded base without a key?");
-            }
-            if self.key.len() != 1 {
-                // base sharded by complex key
-                unimplemented!();
-            }
-            let key_col = self.key[0];
-
-            let mut shard_writes = vec![Vec::new(); self.shards.len()];
-            for r in i.data.drain(..) {
-                let shard = {
-                    let key = match r {
-                        TableOperation::Insert(ref r) => &r[key_col],
-                        TableOperation::Delete { ref key } => &key[0],
-                        TableOperation::Update { ref key, .. } => &key[0],
-                        TableOperation::InsertOrUpdate { ref row, .. } => &row[key_col],
-                    };
-                    crate::shard_by(key, self.shards.len())
-                };
-                shard_writes[shard].push(r);
-            }
+            let mut shard_writes = self.shard_writes(mem::replace(&mut i.data, Vec::new()));
 
             let mut wait_for = FuturesUnordered::new();
-            for (s, rs) in shard_writes.drain(..).enumerate() {
+            for (s, (indices, rs)) in shard_writes.drain(..).enumerate() {
                 if !rs.is_empty() {
                     let p = if self.dst_is_local {
                         unsafe {
@@ -315,6 +516,8 @@ impl Service<Input> for Table {
                                 dst: i.dst,
                                 tracer: i.tracer.clone(),
                                 data: rs,
+                                durable: i.durable,
+                                group: i.group,
                             })
                         }
                     } else {
@@ -322,10 +525,25 @@ impl Service<Input> for Table {
                             dst: i.dst,
                             tracer: i.tracer.clone(),
                             data: rs,
+                            durable: i.durable,
+                            group: i.group,
                         })
                     };
 
-                    wait_for.push(self.shards[s].call(p.into()));
+                    // refused operations are numbered by their index in the shard's write
+                    wait_for.push(
+                        self.shards[s]
+                            .call(p.into())
+                            .map_err(TableError::from)
+                            .and_then(check_reply)
+                            .map_err(move |e| match e {
+                                TableError::ConstraintViolation(vs) => {
+                                    let vs = vs.into_iter().map(|(i, v)| (indices[i], v));
+                                    TableError::ConstraintViolation(vs.collect())
+                                }
+                                e => e,
+                            }),
+                    );
                 } else {
                     // poll_ready reserves a sender slot which we have to release
                     // we do that by dropping the old handle and replacing it with a clone
@@ -334,12 +552,50 @@ impl Service<Input> for Table {
                 }
             }
 
+            // hear back from every shard, so that all the refused operations are reported
             future::Either::B(
                 wait_for
-                    .for_each(|_| Ok(()))
-                    .map_err(TableError::from)
-                    .map(Tagged::from),
+                    .then(|r| Ok::<_, TableError>(r))
+                    .fold(Vec::new(), |mut refused, r| match r {
+                        Ok(_) => Ok(refused),
+                        Err(TableError::ConstraintViolation(vs)) => {
+                            refused.extend(vs);
+                            Ok(refused)
+                        }
+                        Err(e) => Err(e),
+                    })
+                    .and_then(|mut refused| {
+                        if refused.is_empty() {
+                            Ok(Tagged::from(()))
+                        } else {
+                            refused.sort_by_key(|&(i, _)| i);
+                            Err(TableError::ConstraintViolation(refused))
+                        }
+                    }),
             )
+        };
+
+        match self.ack {
+            Ack::None => {
+                let applied: Box<Future<Item = (), Error = ()> + Send> =
+                    Box::new(applied.map(|_| ()).map_err(|_| ()));
+                self.unacked.lock().unwrap().push(applied);
+                future::Either::A(future::ok(Tagged::from(())))
+            }
+            Ack::Base | Ack::Durable => future::Either::B(future::Either::A(applied)),
+            Ack::Views => {
+                let propagate = self.propagate.clone();
+                let table = self.table_name.clone();
+                future::Either::B(future::Either::B(applied.and_then(move |tag| {
+                    let propagated = match propagate {
+                        Some(propagate) => propagate(&table),
+                        None => Box::new(future::err(failure::err_msg("no controller to ask"))),
+                    };
+                    propagated
+                        .map(move |()| tag)
+                        .map_err(TableError::NotPropagated)
+                })))
+            }
         }
     }
 }
@@ -401,6 +657,15 @@ impl Table {
         self.schema.as_ref()
     }
 
+    /// Get the indices of the columns that make up this base table's primary key, if it has one.
+    pub fn primary_key(&self) -> Option<&[usize]> {
+        if self.key_is_primary {
+            Some(&self.key)
+        } else {
+            None
+        }
+    }
+
     fn inject_dropped_cols(&self, r: &mut TableOperation) {
         use std::mem;
         let ndropped = self.dropped.len();
@@ -468,7 +733,28 @@ impl Table {
         }
     }
 
+    pub(crate) fn record_to(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub(crate) fn propagate_with(&mut self, propagate: Propagate) {
+        self.propagate = Some(propagate);
+    }
+
+    /// Set how far writes made through this handle must get before the operations that made them
+    /// complete. Writes wait for the base table to apply them by default.
+    ///
+    /// Waiting for less lowers the latency of writes, and waiting for more lets the application
+    /// know that, say, a read that follows a write will see it.
+    pub fn set_ack(&mut self, ack: Ack) {
+        self.ack = ack;
+    }
+
     fn prep_records(&self, mut ops: Vec<TableOperation>) -> Input {
+        if let Some(ref recorder) = self.recorder {
+            recorder.write(&self.table_name, &ops);
+        }
+
         for r in &mut ops {
             self.inject_dropped_cols(r);
         }
@@ -477,9 +763,57 @@ impl Table {
             dst: self.node,
             data: ops,
             tracer: None,
+            durable: false,
+            group: None,
         }
     }
 
+    /// Split `ops` up by the shard of the base table that each of them should go to, along with
+    /// the index in `ops` of each.
+    fn shard_writes(&self, ops: Vec<TableOperation>) -> Vec<(Vec<usize>, Vec<TableOperation>)> {
+        if self.shards.len() == 1 {
+            return vec![((0..ops.len()).collect(), ops)];
+        }
+        if self.key.is_empty() {
+            unreachable!("sharded base without a key?");
+        }
+        if self.key.len() != 1 {
+            // base sharded by complex key
+            unimplemented!();
+        }
+        let key_col = self.key[0];
+
+        let mut shard_writes = vec![(Vec::new(), Vec::new()); self.shards.len()];
+        for (i, r) in ops.into_iter().enumerate() {
+            let shard = {
+                let key = match r {
+                    TableOperation::Insert(ref r) => &r[key_col],
+                    TableOperation::Delete { ref key } => &key[0],
+                    TableOperation::Update { ref key, .. } => &key[0],
+                    TableOperation::InsertOrUpdate { ref row, .. } => &row[key_col],
+                };
+                crate::shard_by(key, self.shards.len())
+            };
+            shard_writes[shard].0.push(i);
+            shard_writes[shard].1.push(r);
+        }
+        shard_writes
+    }
+
+    /// Start a group of writes to several base tables that views apply all at once, beginning
+    /// with `ops` to this table.
+    ///
+    /// Add writes to other tables with [`WriteGroup::and`], and submit the group with
+    /// `ControllerHandle::write_group`, which sends the writes straight to the base tables through
+    /// the connections of the `Table`s they were added with.
+    pub fn write_group<I, V>(&self, ops: I) -> WriteGroup
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<TableOperation>,
+    {
+        WriteGroup::default().and(self, ops)
+    }
+
     fn quick_n_dirty<Request>(
         self,
         r: Request,
@@ -522,6 +856,78 @@ impl Table {
         self.quick_n_dirty(i.into_iter().map(Into::into).collect::<Vec<_>>())
     }
 
+    /// Perform multiple operations on this base table, and find out what became of each of them.
+    ///
+    /// Unlike with [`Table::perform_all`], operations that are malformed or that the base table
+    /// refuses do not fail the write as a whole. Instead, there is a result for each operation, in
+    /// the order they were given, that says whether it was applied or why not. Operations with the
+    /// wrong number of columns are not sent at all.
+    pub fn perform_each<I, V>(
+        self,
+        ops: I,
+    ) -> impl Future<Item = (Self, Vec<Result<(), RowError>>), Error = AsyncTableError> + Send
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<TableOperation>,
+    {
+        let mut results = Vec::new();
+        let mut sent = Vec::new();
+        let mut valid = Vec::new();
+        for (i, op) in ops.into_iter().map(Into::into).enumerate() {
+            let checked = self.check_operation(&op);
+            if checked.is_ok() {
+                sent.push(i);
+                valid.push(op);
+            }
+            results.push(checked);
+        }
+        if valid.is_empty() {
+            return future::Either::A(future::ok((self, results)));
+        }
+
+        future::Either::B(self.quick_n_dirty(valid).then(move |r| match r {
+            Ok(table) => Ok((table, results)),
+            Err(AsyncTableError {
+                table: Some(table),
+                error: TableError::ConstraintViolation(vs),
+            }) => {
+                for (i, v) in vs {
+                    results[sent[i]] = Err(match v {
+                        ConstraintViolation::Type(v) => RowError::WrongType(v),
+                        v => RowError::ConstraintViolation(v),
+                    });
+                }
+                Ok((table, results))
+            }
+            Err(e) => Err(e),
+        }))
+    }
+
+    /// Check that `op` has as many columns as this base table.
+    fn check_operation(&self, op: &TableOperation) -> Result<(), RowError> {
+        let (row, key, set) = match *op {
+            TableOperation::Insert(ref row) => (Some(row), None, None),
+            TableOperation::Delete { ref key } => (None, Some(key), None),
+            TableOperation::InsertOrUpdate {
+                ref row,
+                ref update,
+            } => (Some(row), None, Some(update)),
+            TableOperation::Update { ref set, ref key } => (None, Some(key), Some(set)),
+        };
+        let columns = row.map(Vec::len).into_iter().chain(set.map(Vec::len));
+        for got in columns {
+            if got != self.columns.len() {
+                return Err(RowError::WrongColumnCount(self.columns.len(), got));
+            }
+        }
+        match key {
+            Some(key) if key.len() != self.key.len() => {
+                Err(RowError::WrongKeyColumnCount(self.key.len(), key.len()))
+            }
+            _ => Ok(()),
+        }
+    }
+
     /// Delete the row with the given key from this base table.
     pub fn delete<I>(self, key: I) -> impl Future<Item = Self, Error = AsyncTableError> + Send
     where
@@ -631,6 +1037,51 @@ impl Table {
     }
 }
 
+/// Writes to several base tables that views apply all at once.
+///
+/// No view reflects only some of the writes in a group, and if any of them is refused, none are
+/// applied. The writes are not isolated from other writes the way they would be in a
+/// transaction, though. Groups are started with
+/// [`Table::write_group`].
+#[derive(Clone, Debug, Default)]
+pub struct WriteGroup {
+    /// The writes to make, along with the base table they go to.
+    writes: Vec<(Table, Input)>,
+}
+
+impl WriteGroup {
+    /// Add `ops` to `table` to the group.
+    pub fn and<I, V>(mut self, table: &Table, ops: I) -> Self
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<TableOperation>,
+    {
+        let i = table.prep_records(ops.into_iter().map(Into::into).collect());
+        self.writes.push((table.clone(), i));
+        self
+    }
+
+    /// Send the writes as part of write group `id`, straight to the base tables.
+    ///
+    /// Resolves once every base table holds its writes, or has refused them, whatever the tables
+    /// were set to wait for, since the group must not end while any are still on their way.
+    pub(crate) fn send(self, id: u64) -> impl Future<Item = (), Error = TableError> + Send {
+        let writes = self.writes.into_iter().map(move |(mut table, mut input)| {
+            input.group = Some(id);
+            if table.ack != Ack::Durable {
+                table.ack = Ack::Base;
+            }
+            ServiceExt::<Input>::ready(table)
+                .and_then(move |mut table| table.call(input))
+                .then(Ok::<_, ()>)
+        });
+        future::join_all(writes).then(|r| match r {
+            Ok(rs) => rs.into_iter().map(|r| r.map(|_| ())).collect(),
+            Err(()) => unreachable!(),
+        })
+    }
+}
+
 /// A synchronous wrapper around [`Table`] where all methods block (using `wait`) for the operation
 /// to complete before returning.
 #[derive(Clone, Debug)]
@@ -693,6 +1144,28 @@ impl SyncTable {
         sync!(self.perform_all(i))
     }
 
+    /// See [`Table::perform_each`].
+    pub fn perform_each<I, V>(&mut self, ops: I) -> Result<Vec<Result<(), RowError>>, TableError>
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<TableOperation>,
+    {
+        let table = self
+            .0
+            .take()
+            .expect("tried to use Table after its transport has failed");
+        match table.perform_each(ops).wait() {
+            Ok((table, results)) => {
+                self.0 = Some(table);
+                Ok(results)
+            }
+            Err(e) => {
+                self.0 = e.table;
+                Err(e.error)
+            }
+        }
+    }
+
     /// See [`Table::delete`].
     pub fn delete<I>(&mut self, key: I) -> Result<(), TableError>
     where
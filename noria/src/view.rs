@@ -1,4 +1,7 @@
+use crate::channel;
 use crate::data::*;
+use crate::recording::Recorder;
+use crate::tls;
 use crate::BoxDynError;
 use crate::{Tagged, Tagger};
 use async_bincode::{AsyncBincodeStream, AsyncDestination};
@@ -9,6 +12,7 @@ use std::fmt;
 use std::io;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::prelude::*;
 use tokio_tower::multiplex;
 use tower::ServiceExt;
@@ -17,7 +21,7 @@ use tower_buffer::Buffer;
 use tower_service::Service;
 
 type Transport = AsyncBincodeStream<
-    tokio::net::tcp::TcpStream,
+    channel::Connection<tokio::net::tcp::TcpStream>,
     Tagged<ReadReply>,
     Tagged<ReadQuery>,
     AsyncDestination,
@@ -26,7 +30,7 @@ type Transport = AsyncBincodeStream<
 #[derive(Debug)]
 #[doc(hidden)]
 // only pub because we use it to figure out the error type for ViewError
-pub struct ViewEndpoint(SocketAddr);
+pub struct ViewEndpoint(SocketAddr, Option<tls::Connector>, Option<Vec<u8>>);
 
 impl Service<()> for ViewEndpoint {
     type Response = multiplex::MultiplexTransport<Transport, Tagger>;
@@ -42,11 +46,14 @@ impl Service<()> for ViewEndpoint {
     }
 
     fn call(&mut self, _: ()) -> Self::Future {
+        let tls = self.1.clone();
+        let key = self.2.clone();
         tokio::net::TcpStream::connect(&self.0)
             .and_then(|s| {
                 s.set_nodelay(true)?;
                 Ok(s)
             })
+            .and_then(move |s| channel::secure(s, tls.as_ref(), key))
             .map(AsyncBincodeStream::from)
             .map(AsyncBincodeStream::for_async)
             .map(|t| multiplex::MultiplexTransport::new(t, Tagger::default()))
@@ -65,6 +72,114 @@ pub(crate) type ViewRpc = Buffer<
 
 type E = <ViewRpc as Service<Tagged<ReadQuery>>>::Error;
 
+/// How often [`View::await_ready`] asks a shard that cannot serve lookups yet whether it can now,
+/// and how often a shard is asked for an export that has yet to reach it.
+const READY_POLL_EVERY: Duration = Duration::from_millis(10);
+
+/// Lookups that are in flight, by key, whether they block, and the snapshot they must reflect.
+type Inflight = HashMap<
+    (Vec<DataType>, bool, Option<SnapshotToken>),
+    future::Shared<Box<Future<Item = Datas, Error = ViewError> + Send>>,
+>;
+
+/// Keys that blocking lookups found no rows for, which lookups can answer without asking the view
+/// until they expire or the view says that rows were added for them.
+#[derive(Debug)]
+struct Misses {
+    capacity: usize,
+    ttl: Duration,
+    /// The generation of each shard's reader that the misses are known to be current as of.
+    generations: Vec<u64>,
+    /// When each key stops being trusted to have no rows.
+    keys: HashMap<Vec<DataType>, Instant>,
+}
+
+impl Misses {
+    /// Whether `key` is known to have no rows.
+    fn contains(&mut self, key: &[DataType]) -> bool {
+        match self.keys.get(key) {
+            Some(&expires) if expires > Instant::now() => true,
+            Some(_) => {
+                self.keys.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Take note of what `reply` to a lookup of `key` in `shard` says about which keys have rows,
+    /// and return the reply to the lookup itself.
+    fn heard(
+        &mut self,
+        reply: ReadReply,
+        key: Vec<DataType>,
+        shard: usize,
+        block: bool,
+    ) -> ReadReply {
+        match reply {
+            ReadReply::Hinted {
+                reply,
+                generation,
+                filled,
+            } => {
+                self.filled(shard, generation, filled);
+                if let ReadReply::Normal(Ok(ref rows)) = *reply {
+                    // non-blocking lookups find no rows for keys that are yet to be replayed too
+                    if block && rows.iter().all(Vec::is_empty) {
+                        self.insert(key, shard, generation);
+                    }
+                }
+                *reply
+            }
+            reply => reply,
+        }
+    }
+
+    /// Forget the keys that rows were added for, as heard from the reader of `shard` when it was
+    /// at `generation`.
+    fn filled(&mut self, shard: usize, generation: u64, filled: Option<Vec<Vec<DataType>>>) {
+        match filled {
+            Some(keys) => {
+                for key in keys {
+                    self.keys.remove(&key);
+                }
+            }
+            None => self.keys.clear(),
+        }
+        if generation > self.generations[shard] {
+            self.generations[shard] = generation;
+        }
+    }
+
+    /// Remember that `key` had no rows when the reader of `shard` was at `generation`.
+    fn insert(&mut self, key: Vec<DataType>, shard: usize, generation: u64) {
+        // keys filled since then may already have been forgotten
+        if generation < self.generations[shard] {
+            return;
+        }
+        let now = Instant::now();
+        if self.keys.len() >= self.capacity {
+            self.keys.retain(|_, &mut expires| expires > now);
+        }
+        if self.keys.len() >= self.capacity {
+            let oldest = self
+                .keys
+                .iter()
+                .min_by_key(|&(_, &expires)| expires)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.keys.remove(&oldest);
+            }
+        }
+        self.keys.insert(key, now + self.ttl);
+    }
+}
+
+/// The rows for a key that a speculative lookup missed on, once they have been replayed.
+///
+/// See [`View::lookup_speculative`].
+pub type Correction = Box<Future<Item = Datas, Error = ViewError> + Send>;
+
 /// A failed [`View`] operation.
 #[derive(Debug)]
 pub struct AsyncViewError {
@@ -98,6 +213,9 @@ pub enum ViewError {
     /// The given view is not yet available.
     #[fail(display = "the view is not yet available")]
     NotYetAvailable,
+    /// The server refused the read because this client has exceeded its rate limit.
+    #[fail(display = "rate limit exceeded")]
+    RateLimited,
     /// A lower-level error occurred while communicating with Soup.
     #[fail(display = "{}", _0)]
     TransportError(#[cause] BoxDynError<E>),
@@ -120,12 +238,103 @@ pub enum ReadQuery {
         keys: Vec<Vec<DataType>>,
         /// Whether to block if a partial replay is triggered
         block: bool,
+        /// The snapshot the read must reflect, if any
+        snapshot: Option<SnapshotToken>,
+    },
+    /// Read some of the rows for one key of a leaf view
+    Chunk {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// Key to read with
+        key: Vec<DataType>,
+        /// Whether to block if a partial replay is triggered
+        block: bool,
+        /// The snapshot the read must reflect, if any
+        snapshot: Option<SnapshotToken>,
+        /// How many of the key's rows to skip
+        offset: usize,
+        /// How many of the key's rows to read at most
+        limit: usize,
+    },
+    /// Read the rows for one key of a leaf view that meet some predicates, and only some of
+    /// their columns
+    Filtered {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// Key to read with
+        key: Vec<DataType>,
+        /// Whether to block if a partial replay is triggered
+        block: bool,
+        /// The snapshot the read must reflect, if any
+        snapshot: Option<SnapshotToken>,
+        /// The predicates that rows must all meet
+        predicates: Vec<Predicate>,
+        /// The columns to read
+        columns: Vec<usize>,
+    },
+    /// Read a page of the rows for one key of a leaf view
+    Page {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// Key to read with
+        key: Vec<DataType>,
+        /// Whether to block if a partial replay is triggered
+        block: bool,
+        /// The snapshot the read must reflect, if any
+        snapshot: Option<SnapshotToken>,
+        /// Where the previous page ended, if this is not the first page
+        after: Option<Cursor>,
+        /// How many rows to read at most
+        limit: usize,
+    },
+    /// Count the rows for one key of a leaf view
+    Count {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// Key to read with
+        key: Vec<DataType>,
+        /// Whether to block if a partial replay is triggered
+        block: bool,
+        /// The snapshot the read must reflect, if any
+        snapshot: Option<SnapshotToken>,
+    },
+    /// Read the rows for one key of a leaf view without waiting for a replay, and say whether
+    /// the key missed
+    Speculative {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// Key to read with
+        key: Vec<DataType>,
     },
     /// Read the size of a leaf view
     Size {
         /// Where to read from
         target: (NodeIndex, usize),
     },
+    /// Read how fresh a leaf view is
+    Freshness {
+        /// Where to read from
+        target: (NodeIndex, usize),
+    },
+    /// Read whether a leaf view can serve lookups yet
+    Ready {
+        /// Where to read from
+        target: (NodeIndex, usize),
+    },
+    /// Collect the rows a leaf view kept at an export barrier
+    Export {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// The export barrier
+        id: u64,
+    },
+    /// Run a query, and also learn which keys rows were added for since the client last asked
+    Hinted {
+        /// The query to run
+        query: Box<ReadQuery>,
+        /// The generation of the reader that the client last heard of
+        since: u64,
+    },
 }
 
 #[doc(hidden)]
@@ -133,10 +342,102 @@ pub enum ReadQuery {
 pub enum ReadReply {
     /// Errors if view isn't ready yet.
     Normal(Result<Vec<Datas>, ()>),
+    /// Errors if view isn't ready yet, and otherwise says whether the key has more rows.
+    Chunk(Result<(Datas, bool), ()>),
+    /// Errors if view isn't ready yet, and otherwise says where the next page starts, if anywhere.
+    Page(Result<(Datas, Option<Cursor>), ()>),
+    /// Errors if view isn't ready yet.
+    Count(Result<usize, ()>),
+    /// Errors if view isn't ready yet, and otherwise says whether the rows are all there.
+    Speculative(Result<(Datas, bool), ()>),
     /// Read size of view
     Size(usize),
+    /// When each base table processed the newest write from it that the view reflects
+    Freshness(HashMap<NodeIndex, SystemTime>),
+    /// Whether the view can serve lookups yet
+    Ready(bool),
+    /// The rows kept at an export barrier, if it has arrived
+    Export(Option<Datas>),
+    /// The read was refused because the client has exceeded its rate limit.
+    RateLimited,
+    /// The reply to a hinted query, along with the generation of the reader and the keys that rows
+    /// were added for since the generation asked about, or `None` if it lost track of them.
+    Hinted {
+        /// The reply to the query that was run
+        reply: Box<ReadReply>,
+        /// The generation of the reader when the query was run
+        generation: u64,
+        /// The keys that rows were added for
+        filled: Option<Vec<Vec<DataType>>>,
+    },
+}
+
+/// A condition on a column of the rows that a lookup finds.
+///
+/// See [`View::lookup_filtered`]. Columns are given by their index in the view's rows.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Predicate {
+    /// The column is equal to the value.
+    Equal(usize, DataType),
+    /// The column is not equal to the value.
+    NotEqual(usize, DataType),
+    /// The column is less than the value.
+    Less(usize, DataType),
+    /// The column is less than or equal to the value.
+    LessOrEqual(usize, DataType),
+    /// The column is greater than the value.
+    Greater(usize, DataType),
+    /// The column is greater than or equal to the value.
+    GreaterOrEqual(usize, DataType),
+}
+
+impl Predicate {
+    /// Whether `row` meets this predicate.
+    ///
+    /// Rows that do not have the column do not meet it.
+    pub fn matches(&self, row: &[DataType]) -> bool {
+        let (column, value) = match *self {
+            Predicate::Equal(c, ref v)
+            | Predicate::NotEqual(c, ref v)
+            | Predicate::Less(c, ref v)
+            | Predicate::LessOrEqual(c, ref v)
+            | Predicate::Greater(c, ref v)
+            | Predicate::GreaterOrEqual(c, ref v) => (c, v),
+        };
+        let d = match row.get(column) {
+            Some(d) => d,
+            None => return false,
+        };
+        match *self {
+            Predicate::Equal(..) => d == value,
+            Predicate::NotEqual(..) => d != value,
+            Predicate::Less(..) => d < value,
+            Predicate::LessOrEqual(..) => d <= value,
+            Predicate::Greater(..) => d > value,
+            Predicate::GreaterOrEqual(..) => d >= value,
+        }
+    }
+}
+
+/// Where a page of results read with [`View::lookup_page`] ended, so that the next page can be read
+/// from there.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    /// The last row of the page.
+    #[doc(hidden)]
+    pub last: Vec<DataType>,
+    /// How many rows equal to the last one the pages so far have held.
+    #[doc(hidden)]
+    pub seen: usize,
 }
 
+/// A point in the stream of writes to the base tables, which reads from any number of views can
+/// be made to reflect.
+///
+/// Tokens are handed out by `ControllerHandle::snapshot`, and used with `View::set_snapshot`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct SnapshotToken(#[doc(hidden)] pub u64);
+
 #[doc(hidden)]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ViewBuilder {
@@ -144,56 +445,78 @@ pub struct ViewBuilder {
     pub columns: Vec<String>,
     pub schema: Option<Vec<ColumnSpecification>>,
     pub shards: Vec<SocketAddr>,
+    /// The key that connections to the view's shards must authenticate with, if any.
+    pub secret: Option<Vec<u8>>,
+    /// The name that the certificate of the view's shards is issued for, if they use TLS.
+    pub tls: Option<String>,
 }
 
 impl ViewBuilder {
     /// Build a `View` out of a `ViewBuilder`
+    ///
+    /// If the view's shards use TLS, their certificate is verified with `tls`.
     #[doc(hidden)]
     pub fn build(
         &self,
         rpcs: Arc<Mutex<HashMap<(SocketAddr, usize), ViewRpc>>>,
+        tls: Option<Arc<rustls::ClientConfig>>,
     ) -> impl Future<Item = View, Error = io::Error> + Send {
         let node = self.node;
         let columns = self.columns.clone();
         let shards = self.shards.clone();
         let schema = self.schema.clone();
-        future::join_all(shards.into_iter().enumerate().map(move |(shardi, addr)| {
-            use std::collections::hash_map::Entry;
-
-            // one entry per shard so that we can send sharded requests in parallel even if
-            // they happen to be targeting the same machine.
-            let mut rpcs = rpcs.lock().unwrap();
-            match rpcs.entry((addr, shardi)) {
-                Entry::Occupied(e) => Ok((addr, e.get().clone())),
-                Entry::Vacant(h) => {
-                    // TODO: maybe always use the same local port?
-                    let c = Buffer::new(
-                        pool::Builder::new()
-                            .urgency(0.03)
-                            .loaded_above(0.2)
-                            .underutilized_below(0.00001)
-                            .build(
-                                multiplex::client::Maker::new(ViewEndpoint(addr)),
-                                (),
-                                choose::RoundRobin::default(),
-                            ),
-                        1,
-                    );
-                    h.insert(c.clone());
-                    Ok((addr, c))
+        let secret = self.secret.clone();
+        let tls = match tls::connector(self.tls.as_ref().map(String::as_str), tls) {
+            Ok(tls) => tls,
+            Err(e) => return future::Either::A(future::err(e)),
+        };
+        future::Either::B(
+            future::join_all(shards.into_iter().enumerate().map(move |(shardi, addr)| {
+                use std::collections::hash_map::Entry;
+
+                // one entry per shard so that we can send sharded requests in parallel even if
+                // they happen to be targeting the same machine.
+                let mut rpcs = rpcs.lock().unwrap();
+                match rpcs.entry((addr, shardi)) {
+                    Entry::Occupied(e) => Ok((addr, e.get().clone())),
+                    Entry::Vacant(h) => {
+                        // TODO: maybe always use the same local port?
+                        let c = Buffer::new(
+                            pool::Builder::new()
+                                .urgency(0.03)
+                                .loaded_above(0.2)
+                                .underutilized_below(0.00001)
+                                .build(
+                                    multiplex::client::Maker::new(ViewEndpoint(
+                                        addr,
+                                        tls.clone(),
+                                        secret.clone(),
+                                    )),
+                                    (),
+                                    choose::RoundRobin::default(),
+                                ),
+                            1,
+                        );
+                        h.insert(c.clone());
+                        Ok((addr, c))
+                    }
                 }
-            }
-        }))
-        .map(move |shards| {
-            let (addrs, conns) = shards.into_iter().unzip();
-            View {
-                node,
-                schema,
-                columns,
-                shard_addrs: addrs,
-                shards: conns,
-            }
-        })
+            }))
+            .map(move |shards| {
+                let (addrs, conns) = shards.into_iter().unzip();
+                View {
+                    node,
+                    schema,
+                    columns,
+                    shard_addrs: addrs,
+                    shards: conns,
+                    recording: None,
+                    snapshot: None,
+                    inflight: Default::default(),
+                    misses: None,
+                }
+            }),
+        )
     }
 }
 
@@ -209,6 +532,14 @@ pub struct View {
 
     shards: Vec<ViewRpc>,
     shard_addrs: Vec<SocketAddr>,
+    /// Where to record lookups, and the name of the view to record them under.
+    recording: Option<(Recorder, String)>,
+    /// The snapshot that lookups must reflect, if any.
+    snapshot: Option<SnapshotToken>,
+    /// Lookups that are in flight, which lookups of the same key share.
+    inflight: Arc<Mutex<Inflight>>,
+    /// The keys known to have no rows, if lookups remember them.
+    misses: Option<Arc<Mutex<Misses>>>,
 }
 
 impl fmt::Debug for View {
@@ -235,6 +566,10 @@ impl Service<(Vec<Vec<DataType>>, bool)> for View {
     }
 
     fn call(&mut self, (keys, block): (Vec<Vec<DataType>>, bool)) -> Self::Future {
+        if let Some((ref recorder, ref name)) = self.recording {
+            recorder.lookup(name, &keys, block);
+        }
+
         // TODO: optimize for when there's only one shard
         if self.shards.len() == 1 {
             return future::Either::A(
@@ -244,6 +579,7 @@ impl Service<(Vec<Vec<DataType>>, bool)> for View {
                             target: (self.node, 0),
                             keys,
                             block,
+                            snapshot: self.snapshot,
                         }
                         .into(),
                     )
@@ -251,6 +587,7 @@ impl Service<(Vec<Vec<DataType>>, bool)> for View {
                     .and_then(|reply| match reply.v {
                         ReadReply::Normal(Ok(rows)) => Ok(rows),
                         ReadReply::Normal(Err(())) => Err(ViewError::NotYetAvailable),
+                        ReadReply::RateLimited => Err(ViewError::RateLimited),
                         _ => unreachable!(),
                     }),
             );
@@ -264,6 +601,7 @@ impl Service<(Vec<Vec<DataType>>, bool)> for View {
         }
 
         let node = self.node;
+        let snapshot = self.snapshot;
         future::Either::B(
             futures::stream::futures_ordered(
                 self.shards
@@ -288,6 +626,7 @@ impl Service<(Vec<Vec<DataType>>, bool)> for View {
                                     target: (node, shardi),
                                     keys: shard_queries,
                                     block,
+                                    snapshot,
                                 }
                                 .into(),
                             )
@@ -295,6 +634,7 @@ impl Service<(Vec<Vec<DataType>>, bool)> for View {
                             .and_then(|reply| match reply.v {
                                 ReadReply::Normal(Ok(rows)) => Ok(rows),
                                 ReadReply::Normal(Err(())) => Err(ViewError::NotYetAvailable),
+                                ReadReply::RateLimited => Err(ViewError::RateLimited),
                                 _ => unreachable!(),
                             })
                     }),
@@ -306,6 +646,10 @@ impl Service<(Vec<Vec<DataType>>, bool)> for View {
 
 #[allow(clippy::len_without_is_empty)]
 impl View {
+    pub(crate) fn record_to(&mut self, recorder: Recorder, name: String) {
+        self.recording = Some((recorder, name));
+    }
+
     /// Get the list of columns in this view.
     pub fn columns(&self) -> &[String] {
         self.columns.as_slice()
@@ -316,6 +660,41 @@ impl View {
         self.schema.as_ref().map(Vec::as_slice)
     }
 
+    /// Make subsequent lookups reflect every write that came before `snapshot`, and none that
+    /// came after it, or stop doing so.
+    ///
+    /// Blocking lookups wait for the view to catch up with the snapshot, and fail with
+    /// [`ViewError::NotYetAvailable`] if it hasn't in a few seconds. Non-blocking lookups fail
+    /// that way right away. Views only keep the rows of their latest snapshots, so lookups at an
+    /// older snapshot fail too. Keys that the view did not hold at the snapshot, or has evicted
+    /// since, are read as they are now. A snapshot taken while a write group is open is reflected
+    /// once the group ends, along with every write up to then.
+    pub fn set_snapshot(&mut self, snapshot: Option<SnapshotToken>) {
+        self.snapshot = snapshot;
+    }
+
+    /// Have lookups through this `View`, and the clones made of it from now on, remember up to
+    /// `capacity` keys that blocking lookups found no rows for, and answer lookups of them without
+    /// asking the view for at most `ttl`.
+    ///
+    /// This keeps applications that look up keys that do not exist over and over from making a
+    /// partially materialized view replay them each time. Replies to lookups say which keys rows
+    /// have since been added for, and those keys are forgotten, so a key is only answered from the
+    /// cache after rows were added for it until the next lookup of another key finds out. Lookups
+    /// that must reflect a snapshot always ask the view. A `capacity` of 0 turns the cache off.
+    pub fn cache_misses(&mut self, capacity: usize, ttl: Duration) {
+        self.misses = if capacity == 0 {
+            None
+        } else {
+            Some(Arc::new(Mutex::new(Misses {
+                capacity,
+                ttl,
+                generations: vec![0; self.shards.len()],
+                keys: HashMap::new(),
+            })))
+        };
+    }
+
     /// Get the current size of this view.
     ///
     /// Note that you must also continue to poll this `View` for the returned future to resolve.
@@ -347,6 +726,195 @@ impl View {
         })
     }
 
+    /// Wait until every shard of this view can serve lookups.
+    ///
+    /// A view is servable once the migration that added it has completed, which is before the
+    /// recipe change that added it returns. A client that gets hold of the view while that
+    /// migration is still running, such as one other than the client that changed the recipe,
+    /// sees lookups fail with [`ViewError::NotYetAvailable`] until then.
+    ///
+    /// Note that you must also continue to poll this `View` for the returned future to resolve.
+    pub fn await_ready(mut self) -> impl Future<Item = Self, Error = AsyncViewError> + Send {
+        let node = self.node;
+        futures::stream::futures_ordered(self.shards.drain(..).enumerate().map(
+            move |(shardi, shard)| {
+                future::loop_fn(shard, move |shard| {
+                    shard
+                        .ready()
+                        .map_err(AsyncViewError::from)
+                        .and_then(move |mut svc| {
+                            svc.call(
+                                ReadQuery::Ready {
+                                    target: (node, shardi),
+                                }
+                                .into(),
+                            )
+                            .map_err(AsyncViewError::from)
+                            .map(move |reply| match reply.v {
+                                ReadReply::Ready(ready) => (svc, ready),
+                                _ => unreachable!(),
+                            })
+                        })
+                        .and_then(|(svc, ready)| {
+                            if ready {
+                                return future::Either::A(future::ok(future::Loop::Break(svc)));
+                            }
+                            // a timer that fails only makes us ask again sooner
+                            future::Either::B(
+                                tokio::timer::Delay::new(Instant::now() + READY_POLL_EVERY)
+                                    .then(move |_| Ok(future::Loop::Continue(svc))),
+                            )
+                        })
+                })
+            },
+        ))
+        .fold(self, |mut this, svc| {
+            this.shards.push(svc);
+            future::ok::<_, AsyncViewError>(this)
+        })
+    }
+
+    /// Wait until every shard of this view reflects every write that came before `snapshot`.
+    pub(crate) fn reflect(
+        mut self,
+        snapshot: SnapshotToken,
+    ) -> impl Future<Item = Self, Error = AsyncViewError> + Send {
+        let node = self.node;
+        futures::stream::futures_ordered(self.shards.drain(..).enumerate().map(
+            move |(shardi, shard)| {
+                shard
+                    .ready()
+                    .map_err(AsyncViewError::from)
+                    .and_then(move |mut svc| {
+                        // a blocking read of no keys at all replies as soon as the snapshot is
+                        // reflected, or once the shard has given up waiting for it
+                        svc.call(
+                            ReadQuery::Normal {
+                                target: (node, shardi),
+                                keys: Vec::new(),
+                                block: true,
+                                snapshot: Some(snapshot),
+                            }
+                            .into(),
+                        )
+                        .map_err(AsyncViewError::from)
+                        .and_then(move |reply| match reply.v {
+                            ReadReply::RateLimited => Err(AsyncViewError {
+                                view: None,
+                                error: ViewError::RateLimited,
+                            }),
+                            ReadReply::Normal(Err(())) => Err(AsyncViewError {
+                                view: None,
+                                error: ViewError::NotYetAvailable,
+                            }),
+                            _ => Ok(svc),
+                        })
+                    })
+            },
+        ))
+        .fold(self, |mut this, svc| {
+            this.shards.push(svc);
+            future::ok::<_, AsyncViewError>(this)
+        })
+    }
+
+    /// Wait until every shard of this view has kept its rows at export barrier `id`, and collect
+    /// them.
+    pub(crate) fn export(
+        mut self,
+        id: u64,
+    ) -> impl Future<Item = (Self, Datas), Error = AsyncViewError> + Send {
+        let node = self.node;
+        futures::stream::futures_ordered(self.shards.drain(..).enumerate().map(
+            move |(shardi, shard)| {
+                future::loop_fn(shard, move |shard| {
+                    shard
+                        .ready()
+                        .map_err(AsyncViewError::from)
+                        .and_then(move |mut svc| {
+                            svc.call(
+                                ReadQuery::Export {
+                                    target: (node, shardi),
+                                    id,
+                                }
+                                .into(),
+                            )
+                            .map_err(AsyncViewError::from)
+                            .map(move |reply| match reply.v {
+                                ReadReply::Export(rows) => (svc, rows),
+                                _ => unreachable!(),
+                            })
+                        })
+                        .and_then(|(svc, rows)| {
+                            if let Some(rows) = rows {
+                                return future::Either::A(future::ok(future::Loop::Break((
+                                    svc, rows,
+                                ))));
+                            }
+                            // the barrier has yet to reach this shard
+                            future::Either::B(
+                                tokio::timer::Delay::new(Instant::now() + READY_POLL_EVERY)
+                                    .then(move |_| Ok(future::Loop::Continue(svc))),
+                            )
+                        })
+                })
+            },
+        ))
+        .fold((self, Vec::new()), |(mut this, mut acc), (svc, rows)| {
+            this.shards.push(svc);
+            acc.extend(rows);
+            future::ok::<_, AsyncViewError>((this, acc))
+        })
+    }
+
+    /// Get how fresh this view is with respect to each of the base tables it is computed from.
+    ///
+    /// For each base table that has written to this view, this is when that base table processed
+    /// the newest write from it that reads from this view reflect. Base tables are identified as
+    /// in `ControllerHandle::inputs`. If the view is sharded, this is the oldest such time across
+    /// the shards.
+    ///
+    /// Only writes that change the view count, so a view that is rarely affected by writes to a
+    /// table may appear to lag behind it even when it is up to date.
+    ///
+    /// Note that you must also continue to poll this `View` for the returned future to resolve.
+    pub fn freshness(
+        mut self,
+    ) -> impl Future<Item = (Self, HashMap<NodeIndex, SystemTime>), Error = AsyncViewError> + Send
+    {
+        let node = self.node;
+        futures::stream::futures_ordered(self.shards.drain(..).enumerate().map(
+            |(shardi, shard)| {
+                shard
+                    .ready()
+                    .map_err(AsyncViewError::from)
+                    .and_then(move |mut svc| {
+                        svc.call(
+                            ReadQuery::Freshness {
+                                target: (node, shardi),
+                            }
+                            .into(),
+                        )
+                        .map_err(AsyncViewError::from)
+                        .map(move |reply| match reply.v {
+                            ReadReply::Freshness(freshness) => (svc, freshness),
+                            _ => unreachable!(),
+                        })
+                    })
+            },
+        ))
+        .fold((self, HashMap::new()), |(mut this, mut acc), (svc, freshness)| {
+            this.shards.push(svc);
+            for (base, at) in freshness {
+                let oldest = acc.entry(base).or_insert(at);
+                if at < *oldest {
+                    *oldest = at;
+                }
+            }
+            future::ok::<_, AsyncViewError>((this, acc))
+        })
+    }
+
     /// Retrieve the query results for the given parameter values.
     ///
     /// The method will block if the results are not yet available only when `block` is `true`.
@@ -359,7 +927,9 @@ impl View {
     ) -> impl Future<Item = (Self, Vec<Datas>), Error = AsyncViewError> + Send {
         self.ready()
             .map_err(|e| match e {
-                ViewError::NotYetAvailable => unreachable!("can't occur in poll_ready"),
+                ViewError::NotYetAvailable | ViewError::RateLimited => {
+                    unreachable!("can't occur in poll_ready")
+                }
                 ViewError::TransportError(e) => AsyncViewError::from(e),
             })
             .and_then(move |mut svc| {
@@ -376,14 +946,356 @@ impl View {
     /// Retrieve the query results for the given parameter value.
     ///
     /// The method will block if the results are not yet available only when `block` is `true`.
+    ///
+    /// Lookups of a key that is already being looked up through this `View` or one of its clones
+    /// share the result of the lookup in flight instead of sending another request. Keys that are
+    /// known to have no rows are answered without a request if [`View::cache_misses`] was called.
     pub fn lookup(
         self,
         key: &[DataType],
         block: bool,
     ) -> impl Future<Item = (Self, Datas), Error = AsyncViewError> + Send {
-        // TODO: Optimized version of this function?
-        self.multi_lookup(vec![Vec::from(key)], block)
-            .map(|(this, rs)| (this, rs.into_iter().next().unwrap()))
+        let key = Vec::from(key);
+        if let Some((ref recorder, ref name)) = self.recording {
+            recorder.lookup(name, &[key.clone()], block);
+        }
+
+        let misses = match self.snapshot {
+            Some(_) => None,
+            None => self.misses.clone(),
+        };
+        if let Some(ref misses) = misses {
+            if misses.lock().unwrap().contains(&key) {
+                return future::Either::A(future::ok((self, Vec::new())));
+            }
+        }
+
+        let id = (key.clone(), block, self.snapshot);
+        let retry = key.clone();
+        let shared = {
+            let mut inflight = self.inflight.lock().unwrap();
+            let node = self.node;
+            let shard = self.shard_for(&key);
+            let snapshot = self.snapshot;
+            let rpc = &self.shards[shard];
+            let done = self.inflight.clone();
+            inflight
+                .entry(id.clone())
+                .or_insert_with(|| {
+                    let mut query = ReadQuery::Normal {
+                        target: (node, shard),
+                        keys: vec![key.clone()],
+                        block,
+                        snapshot,
+                    };
+                    if let Some(ref misses) = misses {
+                        // ask which keys were filled since, so that they are not answered as misses
+                        query = ReadQuery::Hinted {
+                            query: Box::new(query),
+                            since: misses.lock().unwrap().generations[shard],
+                        };
+                    }
+                    let lookup: Box<Future<Item = Datas, Error = ViewError> + Send> = Box::new(
+                        rpc.clone()
+                            .ready()
+                            .and_then(move |mut svc| svc.call(query.into()))
+                            .then(move |reply| {
+                                done.lock().unwrap().remove(&id);
+                                let mut reply = reply.map_err(ViewError::from)?.v;
+                                if let Some(ref misses) = misses {
+                                    let mut misses = misses.lock().unwrap();
+                                    reply = misses.heard(reply, key, shard, block);
+                                }
+                                match reply {
+                                    ReadReply::Normal(Ok(mut rows)) => {
+                                        Ok(rows.pop().unwrap_or_default())
+                                    }
+                                    ReadReply::Normal(Err(())) => Err(ViewError::NotYetAvailable),
+                                    ReadReply::RateLimited => Err(ViewError::RateLimited),
+                                    _ => unreachable!(),
+                                }
+                            }),
+                    );
+                    lookup.shared()
+                })
+                .clone()
+        };
+
+        future::Either::B(shared.then(move |res| {
+            let error = match res {
+                Ok(rows) => return future::Either::A(future::ok((self, (*rows).clone()))),
+                Err(ref e) => match **e {
+                    ViewError::NotYetAvailable => ViewError::NotYetAvailable,
+                    ViewError::RateLimited => ViewError::RateLimited,
+                    ViewError::TransportError(_) => {
+                        // the error can't be shared, so everyone who waited tries on their own
+                        let lookup = self
+                            .multi_lookup(vec![retry], block)
+                            .map(|(this, rs)| (this, rs.into_iter().next().unwrap()));
+                        return future::Either::B(lookup);
+                    }
+                },
+            };
+            future::Either::A(future::err(AsyncViewError {
+                view: Some(self),
+                error,
+            }))
+        }))
+    }
+
+    /// Retrieve the query results for the given parameter value that meet all of `predicates`,
+    /// with only the given `columns` of each, in that order.
+    ///
+    /// The rows are filtered and cut down where the view is, so rows and columns that are not
+    /// asked for are not sent to the client. The method will block if the results are not yet
+    /// available only when `block` is `true`.
+    pub fn lookup_filtered(
+        self,
+        key: &[DataType],
+        predicates: &[Predicate],
+        columns: &[usize],
+        block: bool,
+    ) -> impl Future<Item = (Self, Datas), Error = AsyncViewError> + Send {
+        let key = Vec::from(key);
+        if let Some((ref recorder, ref name)) = self.recording {
+            recorder.lookup(name, &[key.clone()], block);
+        }
+
+        let shard = self.shard_for(&key);
+        let query = ReadQuery::Filtered {
+            target: (self.node, shard),
+            key,
+            block,
+            snapshot: self.snapshot,
+            predicates: predicates.to_vec(),
+            columns: columns.to_vec(),
+        };
+        self.shards[shard]
+            .clone()
+            .ready()
+            .and_then(move |mut svc| svc.call(query.into()))
+            .map_err(ViewError::from)
+            .then(move |reply| {
+                let error = match reply.map(|reply| reply.v) {
+                    Ok(ReadReply::Normal(Ok(mut rows))) => {
+                        return Ok((self, rows.pop().unwrap_or_default()));
+                    }
+                    Ok(ReadReply::Normal(Err(()))) => ViewError::NotYetAvailable,
+                    Ok(ReadReply::RateLimited) => ViewError::RateLimited,
+                    Ok(_) => unreachable!(),
+                    Err(e) => e,
+                };
+                Err(AsyncViewError {
+                    view: Some(self),
+                    error,
+                })
+            })
+    }
+
+    /// Retrieve up to `limit` of the query results for the given parameter value, in the order of
+    /// the view, starting after `after`, along with where the next page starts if there are more.
+    ///
+    /// Pages pick up right after the last row of the page before, rather than after however many
+    /// rows came before it, so rows that are written or deleted between pages do not make later
+    /// pages skip or repeat rows. The method will block if the results are not yet available only
+    /// when `block` is `true`.
+    pub fn lookup_page(
+        self,
+        key: &[DataType],
+        after: Option<Cursor>,
+        limit: usize,
+        block: bool,
+    ) -> impl Future<Item = (Self, (Datas, Option<Cursor>)), Error = AsyncViewError> + Send {
+        assert_ne!(limit, 0, "pages must hold at least one row");
+        let key = Vec::from(key);
+        if let Some((ref recorder, ref name)) = self.recording {
+            recorder.lookup(name, &[key.clone()], block);
+        }
+
+        let shard = self.shard_for(&key);
+        let query = ReadQuery::Page {
+            target: (self.node, shard),
+            key,
+            block,
+            snapshot: self.snapshot,
+            after,
+            limit,
+        };
+        self.shards[shard]
+            .clone()
+            .ready()
+            .and_then(move |mut svc| svc.call(query.into()))
+            .map_err(ViewError::from)
+            .then(move |reply| {
+                let error = match reply.map(|reply| reply.v) {
+                    Ok(ReadReply::Page(Ok(page))) => return Ok((self, page)),
+                    Ok(ReadReply::Page(Err(()))) => ViewError::NotYetAvailable,
+                    Ok(ReadReply::RateLimited) => ViewError::RateLimited,
+                    Ok(_) => unreachable!(),
+                    Err(e) => e,
+                };
+                Err(AsyncViewError {
+                    view: Some(self),
+                    error,
+                })
+            })
+    }
+
+    /// The shard that holds the results for `key`.
+    fn shard_for(&self, key: &[DataType]) -> usize {
+        if self.shards.len() == 1 {
+            0
+        } else {
+            assert_eq!(key.len(), 1);
+            crate::shard_by(&key[0], self.shards.len())
+        }
+    }
+
+    /// Count the query results for the given parameter value.
+    ///
+    /// The rows are counted where the view is, and are not sent to the client. The method will
+    /// block if the results are not yet available only when `block` is `true`.
+    pub fn count(
+        self,
+        key: &[DataType],
+        block: bool,
+    ) -> impl Future<Item = (Self, usize), Error = AsyncViewError> + Send {
+        let key = Vec::from(key);
+        if let Some((ref recorder, ref name)) = self.recording {
+            recorder.lookup(name, &[key.clone()], block);
+        }
+
+        let shard = self.shard_for(&key);
+        let query = ReadQuery::Count {
+            target: (self.node, shard),
+            key,
+            block,
+            snapshot: self.snapshot,
+        };
+        self.shards[shard]
+            .clone()
+            .ready()
+            .and_then(move |mut svc| svc.call(query.into()))
+            .map_err(ViewError::from)
+            .then(move |reply| {
+                let error = match reply.map(|reply| reply.v) {
+                    Ok(ReadReply::Count(Ok(n))) => return Ok((self, n)),
+                    Ok(ReadReply::Count(Err(()))) => ViewError::NotYetAvailable,
+                    Ok(ReadReply::RateLimited) => ViewError::RateLimited,
+                    Ok(_) => unreachable!(),
+                    Err(e) => e,
+                };
+                Err(AsyncViewError {
+                    view: Some(self),
+                    error,
+                })
+            })
+    }
+
+    /// Retrieve the query results for the given parameter value as they are right now, along with
+    /// a correction if they may not all be there.
+    ///
+    /// The view never waits for a replay to reply. If the key missed in a partially materialized
+    /// view, the rows that are returned are empty, its replay is triggered, and the correction
+    /// resolves to the rows for the key once the replay has completed. If the key hit, the rows
+    /// are complete and there is no correction.
+    pub fn lookup_speculative(
+        self,
+        key: &[DataType],
+    ) -> impl Future<Item = (Self, (Datas, Option<Correction>)), Error = AsyncViewError> + Send
+    {
+        let key = Vec::from(key);
+        if let Some((ref recorder, ref name)) = self.recording {
+            recorder.lookup(name, &[key.clone()], false);
+        }
+
+        let shard = self.shard_for(&key);
+        let query = ReadQuery::Speculative {
+            target: (self.node, shard),
+            key: key.clone(),
+        };
+        self.shards[shard]
+            .clone()
+            .ready()
+            .and_then(move |mut svc| svc.call(query.into()))
+            .map_err(ViewError::from)
+            .then(move |reply| {
+                let error = match reply.map(|reply| reply.v) {
+                    Ok(ReadReply::Speculative(Ok((rows, true)))) => {
+                        return Ok((self, (rows, None)));
+                    }
+                    Ok(ReadReply::Speculative(Ok((rows, false)))) => {
+                        // the replay was triggered by the read, so a blocking lookup only waits
+                        // for it to complete
+                        let correction: Correction = Box::new(
+                            self.clone()
+                                .lookup(&key, true)
+                                .map(|(_, rows)| rows)
+                                .map_err(|e| e.error),
+                        );
+                        return Ok((self, (rows, Some(correction))));
+                    }
+                    Ok(ReadReply::Speculative(Err(()))) => ViewError::NotYetAvailable,
+                    Ok(ReadReply::RateLimited) => ViewError::RateLimited,
+                    Ok(_) => unreachable!(),
+                    Err(e) => e,
+                };
+                Err(AsyncViewError {
+                    view: Some(self),
+                    error,
+                })
+            })
+    }
+
+    /// Retrieve the query results for the given parameter value, `chunk` rows at a time.
+    ///
+    /// Each chunk is only read once the rows of the one before it have all been consumed, so the
+    /// results for keys with many rows are never held in memory all at once. Rows that are written
+    /// or deleted while the results are being read may be missed or returned twice.
+    pub fn lookup_stream(
+        &self,
+        key: &[DataType],
+        chunk: usize,
+        block: bool,
+    ) -> impl Stream<Item = Vec<DataType>, Error = ViewError> + Send {
+        assert_ne!(chunk, 0, "chunks must hold at least one row");
+        let key = Vec::from(key);
+        if let Some((ref recorder, ref name)) = self.recording {
+            recorder.lookup(name, &[key.clone()], block);
+        }
+
+        let shard = self.shard_for(&key);
+        let rpc = self.shards[shard].clone();
+        let target = (self.node, shard);
+        let snapshot = self.snapshot;
+        futures::stream::unfold(Some(0), move |offset| {
+            let offset = offset?;
+            let query = ReadQuery::Chunk {
+                target,
+                key: key.clone(),
+                block,
+                snapshot,
+                offset,
+                limit: chunk,
+            };
+            Some(
+                rpc.clone()
+                    .ready()
+                    .and_then(move |mut svc| svc.call(query.into()))
+                    .map_err(ViewError::from)
+                    .and_then(move |reply| match reply.v {
+                        ReadReply::Chunk(Ok((rows, more))) => {
+                            let next = if more { Some(offset + rows.len()) } else { None };
+                            Ok((rows, next))
+                        }
+                        ReadReply::Chunk(Err(())) => Err(ViewError::NotYetAvailable),
+                        ReadReply::RateLimited => Err(ViewError::RateLimited),
+                        _ => unreachable!(),
+                    }),
+            )
+        })
+        .map(futures::stream::iter_ok)
+        .flatten()
     }
 
     /// Switch to a synchronous interface for this view.
@@ -436,11 +1348,37 @@ impl SyncView {
             .schema()
     }
 
+    /// See [`View::set_snapshot`].
+    pub fn set_snapshot(&mut self, snapshot: Option<SnapshotToken>) {
+        self.0
+            .as_mut()
+            .expect("tried to use View after its transport has failed")
+            .set_snapshot(snapshot)
+    }
+
+    /// See [`View::cache_misses`].
+    pub fn cache_misses(&mut self, capacity: usize, ttl: Duration) {
+        self.0
+            .as_mut()
+            .expect("tried to use View after its transport has failed")
+            .cache_misses(capacity, ttl)
+    }
+
     /// See [`View::len`].
     pub fn len(&mut self) -> Result<usize, ViewError> {
         sync!(self.len())
     }
 
+    /// See [`View::freshness`].
+    pub fn freshness(&mut self) -> Result<HashMap<NodeIndex, SystemTime>, ViewError> {
+        sync!(self.freshness())
+    }
+
+    /// See [`View::await_ready`].
+    pub fn await_ready(&mut self) -> Result<(), ViewError> {
+        sync!(self.await_ready().map(|this| (this, ())))
+    }
+
     /// See [`View::multi_lookup`].
     pub fn multi_lookup(
         &mut self,
@@ -455,6 +1393,57 @@ impl SyncView {
         sync!(self.lookup(key, block))
     }
 
+    /// See [`View::lookup_filtered`].
+    pub fn lookup_filtered(
+        &mut self,
+        key: &[DataType],
+        predicates: &[Predicate],
+        columns: &[usize],
+        block: bool,
+    ) -> Result<Datas, ViewError> {
+        sync!(self.lookup_filtered(key, predicates, columns, block))
+    }
+
+    /// See [`View::lookup_page`].
+    pub fn lookup_page(
+        &mut self,
+        key: &[DataType],
+        after: Option<Cursor>,
+        limit: usize,
+        block: bool,
+    ) -> Result<(Datas, Option<Cursor>), ViewError> {
+        sync!(self.lookup_page(key, after, limit, block))
+    }
+
+    /// See [`View::count`].
+    pub fn count(&mut self, key: &[DataType], block: bool) -> Result<usize, ViewError> {
+        sync!(self.count(key, block))
+    }
+
+    /// See [`View::lookup_speculative`].
+    ///
+    /// The correction, if there is one, can be waited for with [`Future::wait`].
+    pub fn lookup_speculative(
+        &mut self,
+        key: &[DataType],
+    ) -> Result<(Datas, Option<Correction>), ViewError> {
+        sync!(self.lookup_speculative(key))
+    }
+
+    /// See [`View::lookup_stream`].
+    pub fn lookup_stream(
+        &self,
+        key: &[DataType],
+        chunk: usize,
+        block: bool,
+    ) -> impl Iterator<Item = Result<Vec<DataType>, ViewError>> {
+        self.0
+            .as_ref()
+            .expect("tried to use View after its transport has failed")
+            .lookup_stream(key, chunk, block)
+            .wait()
+    }
+
     /// Switch back to an asynchronous interface for this view.
     pub fn into_async(mut self) -> View {
         self.0